@@ -1,5 +1,8 @@
 use anyhow::Context;
 use criterion::{Criterion, criterion_group, criterion_main};
+use nix_diff::drv_cache::DrvCache;
+use nix_diff::store_reader::StoreReader;
+use nix_diff::types::Derivation;
 use nix_diff::{diff::DiffContext, parser};
 use std::hint::black_box;
 use std::process::Command;
@@ -178,8 +181,135 @@ fn benchmark_nixos_diff(c: &mut Criterion) {
                 .unwrap()
         })
     });
+
+    // Comparing a derivation with itself should hit the equality fast path
+    // instead of walking every section.
+    group.bench_function("diff_identical", |b| {
+        let drv1 = parser::parse_derivation(&drv1_path)
+            .with_context(|| format!("Failed to parse derivation: {drv1_path}"))
+            .unwrap();
+
+        b.iter(|| {
+            let mut context = DiffContext::new();
+            context
+                .diff_derivations(
+                    black_box(drv1_path.as_bytes()),
+                    black_box(drv1_path.as_bytes()),
+                    black_box(&drv1),
+                    black_box(&drv1),
+                )
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+/// Two derivations whose 200 sources all differ in content, so every
+/// pair has to be read and compared.
+fn benchmark_source_reads(c: &mut Criterion) {
+    let store = tempfile::tempdir().unwrap();
+    let (mut drv1, mut drv2) = (Derivation::default(), Derivation::default());
+    for i in 0..200 {
+        let p1 = store.path().join(format!("aaaa-source{i}.sh"));
+        let p2 = store.path().join(format!("bbbb-source{i}.sh"));
+        std::fs::write(&p1, format!("echo old {i}\n").repeat(50)).unwrap();
+        std::fs::write(&p2, format!("echo new {i}\n").repeat(50)).unwrap();
+        drv1.input_sources
+            .insert(p1.to_string_lossy().as_bytes().into());
+        drv2.input_sources
+            .insert(p2.to_string_lossy().as_bytes().into());
+    }
+
+    let mut group = c.benchmark_group("source_reads");
+    for jobs in [1, 8] {
+        group.bench_function(format!("jobs_{jobs}"), |b| {
+            b.iter(|| {
+                let mut ctx = DiffContext::with_reader(StoreReader::new([store.path()])).jobs(jobs);
+                black_box(ctx.diff_derivations(b"a", b"b", &drv1, &drv2).unwrap())
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Two derivations with 200 changed inputs, each with a .drv file of its
+/// own on both sides, so every pair has to be read and parsed.
+fn benchmark_input_loads(c: &mut Criterion) {
+    let store = tempfile::tempdir().unwrap();
+    let store_dir = store.path().to_str().unwrap();
+    let (mut drv1, mut drv2) = (Derivation::default(), Derivation::default());
+    for i in 0..200 {
+        for (drv, hash, version) in [(&mut drv1, 'a', 1), (&mut drv2, 'b', 2)] {
+            let hash = hash.to_string().repeat(32);
+            let path = format!("{store_dir}/{hash}-lib{i}.drv");
+            let env: Vec<_> = (0..50)
+                .map(|n| format!(r#"("var{n}","value {n} of lib{i}-{version}")"#))
+                .collect();
+            std::fs::write(
+                &path,
+                format!(
+                    r#"Derive([("out","{store_dir}/{hash}-lib{i}","","")],[],[],"x86_64-linux","/bin/sh",["-c","true"],[{}])"#,
+                    env.join(",")
+                ),
+            )
+            .unwrap();
+            drv.input_derivations
+                .insert(path.into_bytes().into(), [b"out".to_vec()].into());
+        }
+    }
+
+    let mut group = c.benchmark_group("input_loads");
+    for jobs in [1, 8] {
+        group.bench_function(format!("jobs_{jobs}"), |b| {
+            b.iter(|| {
+                let mut ctx = DiffContext::with_reader(StoreReader::new([store.path()])).jobs(jobs);
+                black_box(ctx.diff_derivations(b"a", b"b", &drv1, &drv2).unwrap())
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Two derivations with 200 changed inputs held in memory, each with 200
+/// changed env vars, so the time goes into comparing the pairs rather
+/// than loading them.
+fn benchmark_input_comparisons(c: &mut Criterion) {
+    let (mut drv1, mut drv2) = (Derivation::default(), Derivation::default());
+    let mut inputs = Vec::new();
+    for i in 0..200 {
+        for (drv, hash, version) in [(&mut drv1, 'a', 1), (&mut drv2, 'b', 2)] {
+            let path = format!("/nix/store/{}-lib{i}.drv", hash.to_string().repeat(32));
+            let mut input = Derivation::default();
+            for n in 0..200 {
+                input.env.insert(
+                    format!("var{n}").into_bytes().into(),
+                    format!("value {n} of lib{i}-{version}").into_bytes(),
+                );
+            }
+            drv.input_derivations
+                .insert(path.clone().into_bytes().into(), [b"out".to_vec()].into());
+            inputs.push((path.into_bytes(), input));
+        }
+    }
+    let cache = DrvCache::in_memory(inputs);
+
+    let mut group = c.benchmark_group("input_comparisons");
+    for jobs in [1, 8] {
+        group.bench_function(format!("jobs_{jobs}"), |b| {
+            b.iter(|| {
+                let mut ctx = DiffContext::new().cache(cache.clone()).jobs(jobs);
+                black_box(ctx.diff_derivations(b"a", b"b", &drv1, &drv2).unwrap())
+            })
+        });
+    }
     group.finish();
 }
 
-criterion_group!(benches, benchmark_nixos_diff);
+criterion_group!(
+    benches,
+    benchmark_nixos_diff,
+    benchmark_source_reads,
+    benchmark_input_loads,
+    benchmark_input_comparisons
+);
 criterion_main!(benches);
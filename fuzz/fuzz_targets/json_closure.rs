@@ -0,0 +1,10 @@
+//! `nix derivation show --recursive` dumps, and picking their root.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    if let Ok(closure) = nix_diff::json_closure::parse_closure(data, "/nix/store") {
+        let _ = nix_diff::json_closure::find_root(&closure, &[]);
+    }
+});
@@ -0,0 +1,8 @@
+//! `.narinfo` files from binary caches.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    let _ = nix_diff::binary_cache::NarInfo::parse(data);
+});
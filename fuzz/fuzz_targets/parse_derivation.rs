@@ -0,0 +1,11 @@
+//! ATerm parsing, with the store path of the .drv as the first line:
+//! the store directory and derivation name are derived from it.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    let (path, contents) = data.split_once('\n').unwrap_or(("", data));
+    let _ = nix_diff::parser::parse_derivation_string(contents);
+    let _ = nix_diff::parser::parse_derivation_contents(path, contents);
+});
@@ -0,0 +1,309 @@
+//! Resolve output paths through a binary cache when they are not in the
+//! local store.
+//!
+//! Every cached path has a `<hash>.narinfo` whose `Deriver:` field names the
+//! .drv that produced it. If the cache also holds that .drv (uploaded
+//! uncompressed, as `nix copy --derivation` does for `file://` caches with
+//! `compression=none`) we can fetch it as well; otherwise we at least tell
+//! the user which derivation to go and get.
+
+use crate::http::HttpClient;
+use crate::types::BStr;
+use anyhow::{Context, Result, anyhow, bail};
+use std::path::Path;
+
+/// The subset of a `.narinfo` file we care about.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct NarInfo {
+    pub store_path: String,
+    pub url: String,
+    pub compression: String,
+    pub deriver: Option<String>,
+    pub references: Vec<String>,
+}
+
+impl NarInfo {
+    pub fn parse(input: &str) -> Result<Self> {
+        let mut info = NarInfo {
+            // Nix treats a missing Compression field as bzip2.
+            compression: "bzip2".to_string(),
+            ..Default::default()
+        };
+        for (lineno, line) in input.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once(": ")
+                .ok_or_else(|| anyhow!("malformed narinfo line {}: {line}", lineno + 1))?;
+            match key {
+                "StorePath" => info.store_path = value.to_string(),
+                "URL" => info.url = value.to_string(),
+                "Compression" => info.compression = value.to_string(),
+                // "unknown-deriver" is what Nix writes when it has none.
+                "Deriver" if value != "unknown-deriver" => info.deriver = Some(value.to_string()),
+                "References" => {
+                    info.references = value.split_whitespace().map(str::to_string).collect()
+                }
+                _ => {}
+            }
+        }
+        if info.store_path.is_empty() {
+            bail!("narinfo has no StorePath");
+        }
+        if info.url.is_empty() {
+            bail!("narinfo has no URL");
+        }
+        Ok(info)
+    }
+}
+
+pub struct BinaryCache<'a> {
+    url: String,
+    client: &'a dyn HttpClient,
+}
+
+impl<'a> BinaryCache<'a> {
+    pub fn new(url: &str, client: &'a dyn HttpClient) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            client,
+        }
+    }
+
+    /// Fetch the narinfo for `store_path`, or `None` if the cache lacks it.
+    pub fn narinfo(&self, store_path: &str) -> Result<Option<NarInfo>> {
+        let hash = store_path_hash(store_path)?;
+        let url = format!("{}/{hash}.narinfo", self.url);
+        match self.client.get(&url)? {
+            Some(body) => {
+                let text = String::from_utf8(body)
+                    .with_context(|| format!("narinfo at {url} is not valid UTF-8"))?;
+                NarInfo::parse(&text)
+                    .map(Some)
+                    .with_context(|| format!("Failed to parse narinfo at {url}"))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Look up the full .drv path that produced `store_path`.
+    pub fn deriver(&self, store_path: &str) -> Result<String> {
+        let info = self
+            .narinfo(store_path)?
+            .ok_or_else(|| anyhow!("{store_path} is not in binary cache {}", self.url))?;
+        let deriver = info.deriver.ok_or_else(|| {
+            anyhow!(
+                "binary cache {} knows no deriver for {store_path}",
+                self.url
+            )
+        })?;
+        let store_dir = Path::new(store_path)
+            .parent()
+            .ok_or_else(|| anyhow!("store path has no parent: {store_path}"))?;
+        Ok(store_dir.join(deriver).to_string_lossy().into_owned())
+    }
+
+    /// Download the contents of `drv_path` from the cache.
+    pub fn fetch_derivation(&self, drv_path: &str) -> Result<Vec<u8>> {
+        let info = self.narinfo(drv_path)?.ok_or_else(|| {
+            anyhow!(
+                "binary cache {} does not serve {drv_path}; fetch it with `nix-store --realise {drv_path}` or compare against it directly",
+                self.url
+            )
+        })?;
+        if info.compression != "none" {
+            bail!(
+                "binary cache {} serves {drv_path} with {} compression, which nix-diff cannot unpack; fetch it with `nix-store --realise {drv_path}`",
+                self.url,
+                info.compression
+            );
+        }
+        let url = format!("{}/{}", self.url, info.url);
+        let nar = self
+            .client
+            .get(&url)?
+            .ok_or_else(|| anyhow!("narinfo for {drv_path} points at missing {url}"))?;
+        nar_file_contents(&nar).with_context(|| format!("Failed to unpack NAR from {url}"))
+    }
+}
+
+/// `/nix/store/<hash>-name` → `<hash>`.
+fn store_path_hash(store_path: &str) -> Result<&str> {
+    let base = store_path.rsplit('/').next().unwrap_or(store_path);
+    base.split_once('-')
+        .map(|(hash, _)| hash)
+        .filter(|hash| !hash.is_empty())
+        .ok_or_else(|| anyhow!("not a store path: {store_path}"))
+}
+
+/// Extract the contents of a NAR that holds a single regular file, which is
+/// what a .drv always is.
+fn nar_file_contents(nar: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = NarReader { nar, pos: 0 };
+    reader.expect(b"nix-archive-1")?;
+    reader.expect(b"(")?;
+    reader.expect(b"type")?;
+    reader.expect(b"regular")?;
+    let mut tag = reader.string()?;
+    if tag == b"executable" {
+        reader.expect(b"")?;
+        tag = reader.string()?;
+    }
+    if tag != b"contents" {
+        bail!(
+            "expected NAR contents, found {:?}",
+            String::from_utf8_lossy(tag)
+        );
+    }
+    let contents = reader.string()?.to_vec();
+    reader.expect(b")")?;
+    Ok(contents)
+}
+
+struct NarReader<'a> {
+    nar: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> NarReader<'a> {
+    /// NAR strings are a little-endian u64 length followed by the bytes,
+    /// zero-padded to a multiple of 8.
+    fn string(&mut self) -> Result<&'a [u8]> {
+        let len_bytes = self
+            .nar
+            .get(self.pos..)
+            .and_then(|rest| rest.get(..8))
+            .ok_or_else(|| anyhow!("truncated NAR"))?;
+        let len = usize::try_from(u64::from_le_bytes(len_bytes.try_into().unwrap()))
+            .map_err(|_| anyhow!("NAR string too long"))?;
+        let start = self.pos + 8;
+        let end = start
+            .checked_add(len)
+            .filter(|&end| end <= self.nar.len())
+            .ok_or_else(|| anyhow!("truncated NAR"))?;
+        self.pos = (end + 7) & !7;
+        Ok(&self.nar[start..end])
+    }
+
+    fn expect(&mut self, want: &[u8]) -> Result<()> {
+        let got = self.string()?;
+        if got != want {
+            bail!("expected {:?} in NAR, found {:?}", BStr(want), BStr(got));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const HELLO_NARINFO: &str = include_str!("../tests/fixtures/narinfo/hello.narinfo");
+    const DRV_NARINFO: &str = include_str!("../tests/fixtures/narinfo/hello-drv.narinfo");
+    const HELLO: &str = "/nix/store/0a4zbs3d4shznlmx3s7cqqg8rd8bcx1y-hello-2.12.1";
+
+    struct MockClient(HashMap<String, Vec<u8>>);
+
+    impl HttpClient for MockClient {
+        fn get(&self, url: &str) -> Result<Option<Vec<u8>>> {
+            Ok(self.0.get(url).cloned())
+        }
+    }
+
+    fn nar_string(out: &mut Vec<u8>, s: &[u8]) {
+        out.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        out.extend_from_slice(s);
+        out.resize(out.len().next_multiple_of(8), 0);
+    }
+
+    fn nar_of(contents: &[u8]) -> Vec<u8> {
+        let mut nar = Vec::new();
+        for s in [
+            &b"nix-archive-1"[..],
+            b"(",
+            b"type",
+            b"regular",
+            b"contents",
+        ] {
+            nar_string(&mut nar, s);
+        }
+        nar_string(&mut nar, contents);
+        nar_string(&mut nar, b")");
+        nar
+    }
+
+    #[test]
+    fn parses_narinfo_fixture() {
+        let info = NarInfo::parse(HELLO_NARINFO).unwrap();
+        assert_eq!(info.store_path, HELLO);
+        assert_eq!(info.compression, "xz");
+        assert_eq!(
+            info.deriver.as_deref(),
+            Some("cg3kn2rfrvhfm2sy5hmxxnf4v7pk0b9x-hello-2.12.1.drv")
+        );
+        assert_eq!(info.references.len(), 2);
+    }
+
+    #[test]
+    fn unknown_deriver_is_none_and_missing_fields_error() {
+        let info =
+            NarInfo::parse("StorePath: /nix/store/x-a\nURL: nar/x\nDeriver: unknown-deriver\n")
+                .unwrap();
+        assert_eq!(info.deriver, None);
+        assert!(NarInfo::parse("URL: nar/x\n").is_err());
+        assert!(NarInfo::parse("StorePath /nix/store/x-a\n").is_err());
+    }
+
+    #[test]
+    fn resolves_deriver_and_fetches_uncompressed_drv() {
+        let drv = b"Derive([],[],[],\"x86_64-linux\",\"/bin/sh\",[],[])".to_vec();
+        let client = MockClient(HashMap::from([
+            (
+                "https://cache.example/0a4zbs3d4shznlmx3s7cqqg8rd8bcx1y.narinfo".to_string(),
+                HELLO_NARINFO.as_bytes().to_vec(),
+            ),
+            (
+                "https://cache.example/cg3kn2rfrvhfm2sy5hmxxnf4v7pk0b9x.narinfo".to_string(),
+                DRV_NARINFO.as_bytes().to_vec(),
+            ),
+            (
+                "https://cache.example/nar/cg3kn2rfrvhfm2sy5hmxxnf4v7pk0b9x.nar".to_string(),
+                nar_of(&drv),
+            ),
+        ]));
+        let cache = BinaryCache::new("https://cache.example/", &client);
+
+        let deriver = cache.deriver(HELLO).unwrap();
+        assert_eq!(
+            deriver,
+            "/nix/store/cg3kn2rfrvhfm2sy5hmxxnf4v7pk0b9x-hello-2.12.1.drv"
+        );
+        assert_eq!(cache.fetch_derivation(&deriver).unwrap(), drv);
+    }
+
+    #[test]
+    fn missing_drv_error_names_the_deriver() {
+        let client = MockClient(HashMap::new());
+        let cache = BinaryCache::new("https://cache.example", &client);
+        let drv = "/nix/store/cg3kn2rfrvhfm2sy5hmxxnf4v7pk0b9x-hello-2.12.1.drv";
+        let err = cache.fetch_derivation(drv).unwrap_err().to_string();
+        assert!(err.contains(drv), "deriver path missing from: {err}");
+    }
+
+    #[test]
+    fn rejects_truncated_nar() {
+        let nar = nar_of(b"Derive()");
+        assert!(nar_file_contents(&nar[..nar.len() - 8]).is_err());
+        assert!(nar_file_contents(&nar[..20]).is_err());
+        // Lengths that don't fit a 32-bit usize, or overflow the offset.
+        for len in [u64::MAX, 1 << 32, u64::from(u32::MAX)] {
+            let mut nar = Vec::new();
+            nar_string(&mut nar, b"nix-archive-1");
+            nar.extend_from_slice(&len.to_le_bytes());
+            nar.extend_from_slice(b"(\0\0\0\0\0\0\0");
+            assert!(nar_file_contents(&nar).is_err(), "{len}");
+        }
+    }
+}
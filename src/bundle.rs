@@ -0,0 +1,326 @@
+//! `--bundle DIR`: a self-contained copy of one comparison, for attaching
+//! to a bug report.
+//!
+//! The bundle holds the two .drv files (unless `--redact` is given), the
+//! diff as JSON and as rendered text, the paired contents of changed text
+//! sources and `metadata.json` with the tool version and, unless
+//! `--no-invocation-echo` is given, the rest of the [`Invocation`]. The JSON diff carries both
+//! full derivations of every changed input, so nothing in it needs the
+//! store. `MANIFEST` lists the SHA-256 of every file in `sha256sum`
+//! format, so `sha256sum -c MANIFEST` checks a received bundle.
+
+use crate::invocation::Invocation;
+use anyhow::{Context, Result, bail};
+use harmonia_utils_hash::Sha256;
+use harmonia_utils_hash::fmt::CommonHash;
+use nix_diff::json::render_json;
+use nix_diff::types::{DerivationDiff, InputsDiff, TextDiff};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Sources larger than this (1 MiB) on either side are left out.
+pub const SOURCE_SIZE_CAP: usize = 1 << 20;
+
+/// A bundle directory being filled.
+#[derive(Debug)]
+pub struct Bundle {
+    dir: PathBuf,
+    /// SHA-256 of every file written, by name relative to `dir`.
+    files: BTreeMap<String, Sha256>,
+    /// Things that were left out, with the reason.
+    skipped: Vec<String>,
+    /// Leave out the .drv files, which `--redact` can't mask.
+    redacted: bool,
+}
+
+impl Bundle {
+    /// Start a bundle in `dir`, which must not exist or be empty.
+    pub fn create(dir: &Path) -> Result<Self> {
+        if fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some()) {
+            bail!("{} already exists and is not empty", dir.display());
+        }
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            files: BTreeMap::new(),
+            skipped: Vec::new(),
+            redacted: false,
+        })
+    }
+
+    /// Note that the diff was redacted with `--redact`: the .drv files
+    /// are then left out, as they would still hold what was masked.
+    pub fn redacted(&mut self) {
+        self.redacted = true;
+    }
+
+    /// Write `contents` as `name`, or as `name~2`, `name~3`, ... (before
+    /// the extension) if that is taken. Returns the name used.
+    pub fn add(&mut self, name: &str, contents: &[u8]) -> Result<String> {
+        let name = self.unique_name(name);
+        let path = self.dir.join(&name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        fs::write(&path, contents)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        self.files.insert(name.clone(), Sha256::digest(contents));
+        Ok(name)
+    }
+
+    /// Copy the file at `from` in as `name`.
+    pub fn copy(&mut self, name: &str, from: &Path) -> Result<String> {
+        let contents =
+            fs::read(from).with_context(|| format!("Failed to read {}", from.display()))?;
+        self.add(name, &contents)
+    }
+
+    /// Note that `what` was left out of the bundle.
+    pub fn skip(&mut self, what: String) {
+        self.skipped.push(what);
+    }
+
+    fn unique_name(&self, name: &str) -> String {
+        if !self.files.contains_key(name) {
+            return name.to_string();
+        }
+        let (dir, file) = name.rsplit_once('/').map_or(("", name), |(d, f)| (d, f));
+        // Keep the extension, but not for dotfiles.
+        let (stem, ext) = match file.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{ext}")),
+            _ => (file, String::new()),
+        };
+        (2..)
+            .map(|n| {
+                let file = format!("{stem}~{n}{ext}");
+                if dir.is_empty() {
+                    file
+                } else {
+                    format!("{dir}/{file}")
+                }
+            })
+            .find(|candidate| !self.files.contains_key(candidate))
+            .expect("some suffix is free")
+    }
+
+    /// Add the old and new contents of every changed text source in
+    /// `diff` and the inputs below it, as `sources/<drv>/<source>.old` and
+    /// `.new`. `drv` is the name of the derivation `diff` is for.
+    pub fn add_sources(&mut self, diff: &DerivationDiff, drv: &str) -> Result<()> {
+        for source in diff.sources.iter().flat_map(|s| &s.common) {
+            let name = format!("{drv}/{}", sanitize(&String::from_utf8_lossy(&source.path)));
+            match &source.diff {
+                TextDiff::Text { old, new, .. } => {
+                    if old.len().max(new.len()) > SOURCE_SIZE_CAP {
+                        self.skip(format!("sources/{name}: larger than 1 MiB"));
+                        continue;
+                    }
+                    let old_name = self.add(&format!("sources/{name}.old"), old)?;
+                    // Keep the pair together if the old side was renamed.
+                    let new_name = old_name
+                        .strip_suffix(".old")
+                        .map(|base| format!("{base}.new"));
+                    self.add(&new_name.unwrap_or(format!("sources/{name}.new")), new)?;
+                }
+                TextDiff::Binary => self.skip(format!("sources/{name}: binary")),
+                TextDiff::NotCompared => self.skip(format!("sources/{name}: not compared")),
+                TextDiff::ClosureInfo(_) => {}
+            }
+        }
+        let inputs = diff.inputs.iter().flat_map(InputsDiff::all_changed);
+        for input in inputs {
+            if let Some(derivation) = &input.derivation {
+                self.add_sources(
+                    derivation,
+                    &store_name(&String::from_utf8_lossy(&input.path)),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `MANIFEST` and return the finished bundle.
+    pub fn finish(self) -> Result<Self> {
+        let manifest: String = self
+            .files
+            .iter()
+            .map(|(name, hash)| format!("{}  {name}\n", hash.as_base16().as_bare()))
+            .collect();
+        let path = self.dir.join("MANIFEST");
+        fs::write(&path, manifest)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(self)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The files in the bundle, other than `MANIFEST`.
+    pub fn files(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(String::as_str)
+    }
+
+    pub fn skipped(&self) -> &[String] {
+        &self.skipped
+    }
+}
+
+/// Fill `bundle` with the comparison `diff` of the derivations at
+/// `drv_paths`. `drv_files` are the local files the two derivations were
+/// read from (.drv files, or the dumps of `--from-json-closure`), if any;
+/// `text` is the rendered diff.
+pub fn write_bundle(
+    mut bundle: Bundle,
+    drv_paths: &[String; 2],
+    invocation: Option<&Invocation>,
+    drv_files: [Option<&Path>; 2],
+    diff: &DerivationDiff,
+    text: &[u8],
+) -> Result<Bundle> {
+    for ((side, drv_path), file) in ["old", "new"].iter().zip(drv_paths).zip(drv_files) {
+        match file {
+            Some(_) if bundle.redacted => bundle.skip(format!(
+                "{side}/{}: would not be redacted (--redact)",
+                basename(drv_path)
+            )),
+            Some(file) => {
+                let name = file.file_name().map(|n| n.to_string_lossy());
+                let name = sanitize(&name.unwrap_or_default());
+                bundle.copy(&format!("{side}/{name}"), file)?;
+            }
+            None => bundle.skip(format!("{side}/{}: not a local file", basename(drv_path))),
+        }
+    }
+    bundle.add("diff.json", &render_json(diff, &[], None, None)?)?;
+    bundle.add("diff.txt", text)?;
+    let metadata = match invocation {
+        Some(invocation) => serde_json::to_value(invocation)?,
+        None => serde_json::json!({ "version": env!("CARGO_PKG_VERSION") }),
+    };
+    let mut metadata_json = serde_json::to_vec_pretty(&metadata)?;
+    metadata_json.push(b'\n');
+    bundle.add("metadata.json", &metadata_json)?;
+    bundle.add_sources(diff, &store_name(&drv_paths[1]))?;
+    bundle.finish()
+}
+
+fn basename(path: &str) -> &str {
+    path.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or(path)
+}
+
+/// `hello.drv` from `/nix/store/<hash>-hello.drv`.
+fn store_name(path: &str) -> String {
+    let name = basename(path);
+    let name = match name.split_once('-') {
+        Some((hash, rest)) if hash.len() == 32 => rest,
+        _ => name,
+    };
+    sanitize(name)
+}
+
+/// `name` as a single path component.
+fn sanitize(name: &str) -> String {
+    match name.replace('/', "_") {
+        name if name.is_empty() || name == "." || name == ".." => "_".to_string(),
+        name => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colliding_names_get_a_suffix() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut bundle = Bundle::create(&dir.path().join("b")).unwrap();
+        assert_eq!(bundle.add("old/hello.drv", b"1").unwrap(), "old/hello.drv");
+        assert_eq!(
+            bundle.add("old/hello.drv", b"2").unwrap(),
+            "old/hello~2.drv"
+        );
+        assert_eq!(
+            bundle.add("old/hello.drv", b"3").unwrap(),
+            "old/hello~3.drv"
+        );
+        assert_eq!(bundle.add("README", b"").unwrap(), "README");
+        assert_eq!(bundle.add("README", b"").unwrap(), "README~2");
+        assert_eq!(bundle.add(".env", b"").unwrap(), ".env");
+        assert_eq!(bundle.add(".env", b"").unwrap(), ".env~2");
+        let contents = fs::read(dir.path().join("b/old/hello~2.drv")).unwrap();
+        assert_eq!(contents, b"2");
+    }
+
+    #[test]
+    fn manifest_lists_every_file_with_its_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut bundle = Bundle::create(dir.path()).unwrap();
+        bundle.add("b.txt", b"abc").unwrap();
+        bundle.add("a/x", b"").unwrap();
+        let bundle = bundle.finish().unwrap();
+        assert_eq!(
+            fs::read_to_string(bundle.dir().join("MANIFEST")).unwrap(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  a/x\n\
+             ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  b.txt\n"
+        );
+    }
+
+    #[test]
+    fn refuses_to_fill_a_non_empty_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        Bundle::create(dir.path()).unwrap();
+        fs::write(dir.path().join("important"), "").unwrap();
+        let err = Bundle::create(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("not empty"), "{err}");
+    }
+
+    #[test]
+    fn sources_are_bundled_in_pairs_up_to_the_cap() {
+        use nix_diff::types::{Derivation, SourceDiff, SourcesDiff};
+        let source = |name: &str, diff| SourceDiff {
+            path: name.as_bytes().to_vec(),
+            diff,
+        };
+        let big = vec![b'x'; SOURCE_SIZE_CAP + 1];
+        let diff = DerivationDiff::new(Derivation::default(), Derivation::default()).sources(
+            SourcesDiff {
+                common: vec![
+                    source(
+                        "build.sh",
+                        TextDiff::text(b"old\n".to_vec(), b"new\n".to_vec()),
+                    ),
+                    source("big.txt", TextDiff::text(big, b"small".to_vec())),
+                    source("logo.png", TextDiff::Binary),
+                ],
+                ..Default::default()
+            },
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let mut bundle = Bundle::create(dir.path()).unwrap();
+        bundle.add_sources(&diff, "hello.drv").unwrap();
+        assert_eq!(
+            bundle.files().collect::<Vec<_>>(),
+            [
+                "sources/hello.drv/build.sh.new",
+                "sources/hello.drv/build.sh.old"
+            ]
+        );
+        assert_eq!(
+            bundle.skipped(),
+            [
+                "sources/hello.drv/big.txt: larger than 1 MiB",
+                "sources/hello.drv/logo.png: binary"
+            ]
+        );
+        assert_eq!(
+            fs::read(dir.path().join("sources/hello.drv/build.sh.new")).unwrap(),
+            b"new\n"
+        );
+    }
+}
@@ -0,0 +1,146 @@
+//! Closure size counting.
+//!
+//! "14 inputs changed" reads very differently for a 60-derivation closure
+//! than for a 6,000-derivation one. Counting only needs the `inputDrvs`
+//! keys of every reachable .drv, so nothing is diffed along the way.
+
+use crate::types::{ByteStr, Derivation};
+use std::borrow::Borrow;
+use std::collections::HashSet;
+
+/// Number of distinct derivations in the closure of `root`, including
+/// `root` itself. Inputs that cannot be read are counted but not expanded.
+pub fn closure_size(root: &Derivation) -> usize {
+    closure_size_with(root, |path| {
+        let path = std::str::from_utf8(path).ok()?;
+        crate::parser::parse_derivation(path).ok()
+    })
+}
+
+/// Like [`closure_size`], loading input derivations through `load`.
+pub fn closure_size_with<D: Borrow<Derivation>>(
+    root: &Derivation,
+    load: impl FnMut(&[u8]) -> Option<D>,
+) -> usize {
+    let mut count = 1;
+    walk(root, load, |_| count += 1);
+    count
+}
+
+/// Store paths `root` can see at build time: the outputs of every
+/// derivation in its input closure plus all of their input sources.
+pub fn closure_paths(root: &Derivation) -> HashSet<Vec<u8>> {
+    closure_paths_with(root, |path| {
+        let path = std::str::from_utf8(path).ok()?;
+        crate::parser::parse_derivation(path).ok()
+    })
+}
+
+/// Like [`closure_paths`], loading input derivations through `load`.
+pub fn closure_paths_with<D: Borrow<Derivation>>(
+    root: &Derivation,
+    load: impl FnMut(&[u8]) -> Option<D>,
+) -> HashSet<Vec<u8>> {
+    let mut paths: HashSet<Vec<u8>> = root.input_sources.iter().map(|p| p.to_vec()).collect();
+    walk(root, load, |drv| {
+        if let Some(drv) = drv {
+            paths.extend(
+                drv.outputs
+                    .values()
+                    .filter_map(|o| o.known_path().map(<[u8]>::to_vec)),
+            );
+            paths.extend(drv.input_sources.iter().map(|p| p.to_vec()));
+        }
+    });
+    paths
+}
+
+/// Visit each distinct derivation in the input closure of `root` once.
+/// Inputs that cannot be loaded are visited with `None`.
+fn walk<D: Borrow<Derivation>>(
+    root: &Derivation,
+    mut load: impl FnMut(&[u8]) -> Option<D>,
+    mut visit: impl FnMut(Option<&Derivation>),
+) {
+    let mut seen: HashSet<ByteStr> = HashSet::new();
+    let mut stack: Vec<ByteStr> = root.input_derivations.keys().cloned().collect();
+    while let Some(path) = stack.pop() {
+        if !seen.insert(path.clone()) {
+            continue;
+        }
+        let drv = load(&path);
+        let drv = drv.as_ref().map(Borrow::borrow);
+        visit(drv);
+        if let Some(drv) = drv {
+            stack.extend(
+                drv.input_derivations
+                    .keys()
+                    .filter(|p| !seen.contains(*p))
+                    .cloned(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn drv(inputs: &[&str]) -> Derivation {
+        Derivation {
+            outputs: Default::default(),
+            input_sources: Default::default(),
+            input_derivations: inputs
+                .iter()
+                .map(|p| (ByteStr::from(p.as_bytes()), [b"out".to_vec()].into()))
+                .collect(),
+            platform: Vec::new(),
+            builder: Vec::new(),
+            args: Vec::new(),
+            env: Default::default(),
+        }
+    }
+
+    #[test]
+    fn counts_each_shared_input_once() {
+        // root -> {a, b}; a -> {c}; b -> {c, d}; c -> {d}; "gone" is unreadable.
+        let graph: HashMap<&str, Derivation> = HashMap::from([
+            ("a.drv", drv(&["c.drv"])),
+            ("b.drv", drv(&["c.drv", "d.drv", "gone.drv"])),
+            ("c.drv", drv(&["d.drv"])),
+            ("d.drv", drv(&[])),
+        ]);
+        let load = |p: &[u8]| graph.get(std::str::from_utf8(p).unwrap()).cloned();
+
+        assert_eq!(closure_size_with(&drv(&["a.drv", "b.drv"]), load), 6);
+        assert_eq!(closure_size_with(&drv(&["c.drv"]), load), 3);
+        assert_eq!(closure_size_with(&drv(&[]), load), 1);
+    }
+
+    #[test]
+    fn collects_output_paths_and_sources() {
+        let mut lib = drv(&[]);
+        lib.outputs.insert(
+            b"out".to_vec(),
+            crate::types::Output {
+                path: b"/nix/store/aaa-lib".to_vec(),
+                hash_algorithm: None,
+                hash: None,
+            },
+        );
+        lib.input_sources
+            .insert(b"/nix/store/bbb-patch".as_slice().into());
+        let graph: HashMap<&str, Derivation> = HashMap::from([("lib.drv", lib)]);
+        let load = |p: &[u8]| graph.get(std::str::from_utf8(p).unwrap()).cloned();
+
+        let mut root = drv(&["lib.drv", "gone.drv"]);
+        root.input_sources
+            .insert(b"/nix/store/ccc-builder.sh".as_slice().into());
+        let paths = closure_paths_with(&root, load);
+        for p in ["aaa-lib", "bbb-patch", "ccc-builder.sh"] {
+            assert!(paths.contains(format!("/nix/store/{p}").as_bytes()), "{p}");
+        }
+        assert_eq!(paths.len(), 3);
+    }
+}
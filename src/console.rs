@@ -0,0 +1,115 @@
+//! Where rendered output ends up.
+//!
+//! The renderer's automatic choices (color, hyperlinks, wrapping) depend on
+//! whether stdout is a terminal and how wide it is. They are asked through
+//! [`Console`] once, when the renderer is built, so tests can substitute a
+//! [`FixedConsole`] instead of spawning the binary under a pseudo-terminal.
+//!
+//! Warnings go to stderr through [`warn`], in the style of `error:`.
+
+use crate::types::ColorMode;
+use std::io::{self, IsTerminal, Write};
+use std::sync::OnceLock;
+
+pub trait Console {
+    /// Whether output goes to an interactive terminal.
+    fn is_terminal(&self) -> bool;
+    /// Terminal width in columns, if there is a terminal and it is known.
+    fn width(&self) -> Option<usize>;
+}
+
+/// The process's real stdout.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StdoutConsole;
+
+impl Console for StdoutConsole {
+    fn is_terminal(&self) -> bool {
+        io::stdout().is_terminal()
+    }
+
+    fn width(&self) -> Option<usize> {
+        if !self.is_terminal() {
+            return None;
+        }
+        if let Some(columns) = std::env::var("COLUMNS").ok().and_then(|c| c.parse().ok()) {
+            return Some(columns);
+        }
+        let mut size = libc::winsize {
+            ws_row: 0,
+            ws_col: 0,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        // SAFETY: TIOCGWINSZ only writes a `winsize` into the pointer we pass.
+        let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+        (ok && size.ws_col > 0).then_some(usize::from(size.ws_col))
+    }
+}
+
+/// A console with fixed answers, for tests and for callers that render
+/// somewhere other than stdout.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FixedConsole {
+    pub terminal: bool,
+    pub width: Option<usize>,
+}
+
+impl Console for FixedConsole {
+    fn is_terminal(&self) -> bool {
+        self.terminal
+    }
+
+    fn width(&self) -> Option<usize> {
+        self.width
+    }
+}
+
+/// The `--color` for stderr, once the command line set it.
+static STDERR_COLOR: OnceLock<ColorMode> = OnceLock::new();
+
+/// Color [`warn`]ings according to `mode` from now on.
+pub fn set_stderr_color(mode: ColorMode) {
+    let _ = STDERR_COLOR.set(mode);
+}
+
+/// Whether stderr gets color under `mode`: never with `NO_COLOR` set,
+/// and for `auto` only if it is a terminal.
+pub fn stderr_color(mode: ColorMode) -> bool {
+    let no_color = std::env::var("NO_COLOR").is_ok_and(|v| !v.is_empty());
+    !no_color
+        && match mode {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            ColorMode::Auto => io::stderr().is_terminal(),
+        }
+}
+
+/// Print `message` to stderr after a `warning:` prefix, yellow where an
+/// `error:` would be red.
+pub fn warn(message: impl std::fmt::Display) {
+    let mode = STDERR_COLOR.get().copied().unwrap_or(ColorMode::Auto);
+    let line = format_warning(&message.to_string(), stderr_color(mode));
+    let _ = io::stderr().write_all(line.as_bytes());
+}
+
+fn format_warning(message: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[1;33mwarning:\x1b[0m {message}\n")
+    } else {
+        format!("warning: {message}\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warnings_are_prefixed_like_errors() {
+        assert_eq!(
+            format_warning("source read budget exhausted", false),
+            "warning: source read budget exhausted\n"
+        );
+        assert_eq!(format_warning("x", true), "\x1b[1;33mwarning:\x1b[0m x\n");
+    }
+}
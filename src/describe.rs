@@ -0,0 +1,716 @@
+//! `--format commit-msg`: the diff as a short changelog.
+//!
+//! Each kind of change maps to one phrase ("bump openssl 3.0.12 → 3.0.13",
+//! "add dependency zstd-1.5.5"). Changes to input derivations are prefixed
+//! with the package they belong to. Version bumps come first, then
+//! dependency, source and output changes, then build details, and the list
+//! is capped so it fits in a commit message.
+
+use crate::diff::normalize_store_paths;
+use crate::sanitize;
+use crate::types::*;
+use crate::version;
+use similar::{ChangeTag, TextDiff as SimilarTextDiff};
+use std::collections::BTreeMap;
+
+/// Bullets listed before "and N more changes".
+pub const DEFAULT_MAX_BULLETS: usize = 10;
+
+/// Longest value quoted verbatim; longer ones are only named.
+const MAX_VALUE_LEN: usize = 60;
+
+/// Rule order in the message: the most telling changes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Rule {
+    Name,
+    Dependency,
+    Source,
+    Output,
+    Build,
+    Argument,
+    Env,
+}
+
+/// The changes in `diff` as bullet lines, at most `max_bullets` of them
+/// plus a line counting the rest.
+pub fn commit_message(diff: &DerivationDiff, max_bullets: usize) -> String {
+    let mut bullets = Vec::new();
+    collect(diff, None, &mut bullets);
+    // Stable, so changes of the same kind stay in tree order.
+    bullets.sort_by_key(|(rule, _)| *rule);
+
+    if bullets.is_empty() && diff.original != diff.new {
+        bullets.push((
+            Rule::Build,
+            "rebuild against changed dependencies".to_string(),
+        ));
+    }
+    let mut message = String::new();
+    for (_, bullet) in bullets.iter().take(max_bullets) {
+        message.push_str(&format!("- {bullet}\n"));
+    }
+    match bullets.len().saturating_sub(max_bullets) {
+        0 => {}
+        1 => message.push_str("- and 1 more change\n"),
+        n => message.push_str(&format!("- and {n} more changes\n")),
+    }
+    message
+}
+
+/// Add the phrases for `diff` and the input derivations below it.
+/// `package` names the derivation for nested diffs.
+fn collect(diff: &DerivationDiff, package: Option<&str>, bullets: &mut Vec<(Rule, String)>) {
+    if matches!(diff.outputs, OutputsDiff::AlreadyCompared) {
+        return;
+    }
+    let mut push = |rule, phrase: String| {
+        let bullet = match package {
+            Some(package) if rule != Rule::Name => format!("{package}: {phrase}"),
+            _ => phrase,
+        };
+        bullets.push((rule, bullet));
+    };
+
+    if let Some(name) = &diff.name {
+        for phrase in describe_name(name, &pname(&diff.new)) {
+            push(Rule::Name, phrase);
+        }
+    }
+    if let Some(inputs) = &diff.inputs {
+        for phrase in describe_inputs(inputs) {
+            push(Rule::Dependency, phrase);
+        }
+    }
+    if let Some(sources) = &diff.sources {
+        for phrase in describe_sources(sources) {
+            push(Rule::Source, phrase);
+        }
+    }
+    for phrase in diff
+        .outputs
+        .listed()
+        .into_iter()
+        .filter_map(describe_output)
+    {
+        push(Rule::Output, phrase);
+    }
+    if let Some(platform) = &diff.platform {
+        push(
+            Rule::Build,
+            format!("platform {}", describe_change(platform)),
+        );
+    }
+    if let Some(builder) = &diff.builder {
+        push(Rule::Build, format!("builder {}", describe_change(builder)));
+    }
+    for flag in diff.sandbox.iter().flatten() {
+        push(Rule::Build, describe_sandbox_flag(flag));
+    }
+    if let Some(fetch) = &diff.fetch {
+        if fetch.stale_hash {
+            push(
+                Rule::Source,
+                "fetch: source changed but the output hash did not".to_string(),
+            );
+        }
+        for attr in fetch.attrs.iter().filter(|a| !a.same_digest) {
+            push(Rule::Source, describe_fetch_attr(attr));
+        }
+    }
+    for arg in diff.args.iter().flatten() {
+        if let Some(phrase) = describe_argument(arg) {
+            push(Rule::Argument, phrase);
+        }
+    }
+    for (key, change) in diff.env.iter().flatten() {
+        let Some(change) = change else { continue };
+        let covered = diff.name.as_ref().is_some_and(|name| match key.as_slice() {
+            b"pname" | b"version" => true,
+            b"name" => name.covers_name,
+            _ => false,
+        });
+        let mirrors_output = key.as_slice() == b"builder"
+            || diff.original.outputs.contains_key(key)
+            || diff.new.outputs.contains_key(key);
+        if covered || mirrors_output {
+            continue;
+        }
+        if let Some(phrase) = describe_env(key, change) {
+            push(Rule::Env, phrase);
+        }
+    }
+    for attr in diff.structured_attrs.iter().flatten() {
+        if let Some(phrase) = describe_env(attr.path.as_bytes(), &attr.change) {
+            push(Rule::Env, phrase);
+        }
+    }
+
+    for input in diff.inputs.iter().flat_map(|i| &i.changed) {
+        if let Some(sub) = &input.derivation {
+            let package = pname(&sub.new);
+            let package = if package.is_empty() {
+                pname(&sub.original)
+            } else {
+                package
+            };
+            collect(sub, Some(&package), bullets);
+        }
+    }
+}
+
+/// The package name of `drv`: `pname`, or `name` without its version.
+fn pname(drv: &Derivation) -> String {
+    let pname = match (drv.env.get(&b"pname"[..]), drv.env.get(&b"name"[..])) {
+        (Some(pname), _) => pname.as_slice(),
+        (None, Some(name)) => version::parse_drv_name(name).0,
+        (None, None) => b"",
+    };
+    String::from_utf8_lossy(pname).into_owned()
+}
+
+/// `name-1.0` from `/nix/store/<hash>-name-1.0.drv`.
+fn store_path_name(path: &[u8]) -> String {
+    let file = path.rsplit(|&b| b == b'/').next().unwrap_or(path);
+    let name = file
+        .iter()
+        .position(|&b| b == b'-')
+        .map_or(file, |dash| &file[dash + 1..]);
+    let name = name.strip_suffix(b".drv").unwrap_or(name);
+    String::from_utf8_lossy(name).into_owned()
+}
+
+/// `old → new`, or a shorter note for long or multi-line values.
+fn describe_change(diff: &StringDiff) -> String {
+    match (quotable(&diff.old), quotable(&diff.new)) {
+        (Some(old), Some(new)) => format!("{old} → {new}"),
+        _ => "changed".to_string(),
+    }
+}
+
+/// `value` if it is short enough to quote in a bullet.
+fn quotable(value: &[u8]) -> Option<String> {
+    let escaped = sanitize::escape_unusual(value);
+    let value = String::from_utf8_lossy(&escaped);
+    (value.len() <= MAX_VALUE_LEN && !value.contains('\n')).then(|| value.into_owned())
+}
+
+fn describe_name(name: &NameDiff, pname: &str) -> Vec<String> {
+    let mut phrases = Vec::new();
+    if let Some(rename) = &name.pname {
+        phrases.push(format!(
+            "rename {} → {}",
+            String::from_utf8_lossy(&rename.old),
+            String::from_utf8_lossy(&rename.new)
+        ));
+    }
+    if let Some(version) = &name.version {
+        let verb = if version.downgrade {
+            "downgrade"
+        } else {
+            "bump"
+        };
+        phrases.push(format!(
+            "{verb} {pname} {} → {}",
+            describe_version(&version.old),
+            describe_version(&version.new)
+        ));
+    }
+    phrases
+}
+
+fn describe_version(version: &[u8]) -> String {
+    if version.is_empty() {
+        "(none)".to_string()
+    } else {
+        String::from_utf8_lossy(version).into_owned()
+    }
+}
+
+fn describe_inputs(inputs: &InputsDiff) -> Vec<String> {
+    let added = inputs
+        .added
+        .iter()
+        .map(|p| format!("add dependency {}", store_path_name(&p.0)));
+    let removed = inputs
+        .removed
+        .iter()
+        .map(|p| format!("drop dependency {}", store_path_name(&p.0)));
+    added.chain(removed).collect()
+}
+
+fn describe_sources(sources: &SourcesDiff) -> Vec<String> {
+    let changed = sources
+        .common
+        .iter()
+        .map(|s| format!("update {}", store_path_name(&s.path)));
+    let added = sources
+        .added
+        .iter()
+        .map(|p| format!("add source {}", store_path_name(p)));
+    let removed = sources
+        .removed
+        .iter()
+        .map(|p| format!("drop source {}", store_path_name(p)));
+    changed.chain(added).chain(removed).collect()
+}
+
+fn describe_output(output: &OutputDiff) -> Option<String> {
+    let name = String::from_utf8_lossy(&output.name);
+    match &output.diff {
+        OutputDetailDiff::Added(_) => Some(format!("add output {name}")),
+        OutputDetailDiff::Removed(_) => Some(format!("drop output {name}")),
+        OutputDetailDiff::Changed {
+            fixed_output: Some(FixedOutputTransition::BecameFixedOutput),
+            ..
+        } => Some("become a fixed-output derivation".to_string()),
+        OutputDetailDiff::Changed {
+            fixed_output: Some(FixedOutputTransition::NoLongerFixedOutput),
+            ..
+        } => Some("stop being a fixed-output derivation".to_string()),
+        OutputDetailDiff::Changed { hash: Some(_), .. } => {
+            Some(format!("new fixed-output hash for {name}"))
+        }
+        // Path changes follow from every other change.
+        OutputDetailDiff::Changed { .. } => None,
+    }
+}
+
+fn describe_argument(arg: &ArgumentDiff) -> Option<String> {
+    let quote = |v: &[u8]| quotable(v).map(|v| format!("`{v}`"));
+    Some(match arg {
+        ArgumentDiff::Added { value, .. } => {
+            quote(value).map_or("add an argument".into(), |v| format!("add argument {v}"))
+        }
+        ArgumentDiff::Removed { value, .. } => quote(value)
+            .map_or("remove an argument".into(), |v| {
+                format!("remove argument {v}")
+            }),
+        ArgumentDiff::Changed {
+            store_paths_only: true,
+            ..
+        } => return None,
+        ArgumentDiff::Changed {
+            new_index, diff, ..
+        } => {
+            let StringDiff { old, new } = diff;
+            if old.contains(&b'\n') || new.contains(&b'\n') {
+                return Some(format!("builder script: {}", describe_lines(old, new)));
+            }
+            match quote(old).zip(quote(new)) {
+                Some((old, new)) => format!("argument {old} → {new}"),
+                None => format!("argument {} changed", new_index + 1),
+            }
+        }
+    })
+}
+
+/// The gist of a multi-line change: the line itself if only one changed,
+/// otherwise how many did. Lines that only differ in store paths don't
+/// count.
+fn describe_lines(old: &[u8], new: &[u8]) -> String {
+    let (old, new) = (normalize_store_paths(old), normalize_store_paths(new));
+    let diff = SimilarTextDiff::from_lines(&old[..], &new[..]);
+    let mut lines: BTreeMap<bool, Vec<&[u8]>> = BTreeMap::new();
+    for change in diff.iter_all_changes() {
+        if change.tag() != ChangeTag::Equal {
+            let line = change.value();
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            lines
+                .entry(change.tag() == ChangeTag::Insert)
+                .or_default()
+                .push(line.trim_ascii());
+        }
+    }
+    let quote = |line: &[u8]| quotable(line).map(|l| format!("`{l}`"));
+    let removed = lines.get(&false).map_or(&[][..], Vec::as_slice);
+    let added = lines.get(&true).map_or(&[][..], Vec::as_slice);
+    let single = match (removed, added) {
+        ([], [line]) => quote(line).map(|l| format!("add {l}")),
+        ([line], []) => quote(line).map(|l| format!("remove {l}")),
+        ([old], [new]) => quote(old)
+            .zip(quote(new))
+            .map(|(o, n)| format!("{o} → {n}")),
+        _ => None,
+    };
+    single.unwrap_or_else(|| match removed.len() + added.len() {
+        1 => "1 line changed".to_string(),
+        n => format!("{n} lines changed"),
+    })
+}
+
+fn describe_sandbox_flag(flag: &SandboxFlagDiff) -> String {
+    let [old, new] = flag.shown_values().map(String::from_utf8_lossy);
+    let key = String::from_utf8_lossy(&flag.key);
+    match (quotable(old.as_bytes()), quotable(new.as_bytes())) {
+        (Some(old), Some(new)) => format!("sandbox: {key} {old} → {new}"),
+        _ => format!("sandbox: {key} changed"),
+    }
+}
+
+fn describe_fetch_attr(attr: &FetchAttrDiff) -> String {
+    let key = String::from_utf8_lossy(&attr.key);
+    let shown = |value: &Option<Vec<u8>>| match value {
+        Some(value) => quotable(value),
+        None => Some("unset".to_string()),
+    };
+    match (shown(&attr.old), shown(&attr.new)) {
+        (Some(old), Some(new)) => format!("fetch: {key} {old} → {new}"),
+        _ => format!("fetch: {key} changed"),
+    }
+}
+
+fn describe_env(key: &[u8], change: &EnvVarDiff) -> Option<String> {
+    let key = String::from_utf8_lossy(key);
+    let counts = |added: usize, removed: usize, what: &str| {
+        format!("{key}: {added} {what} added, {removed} removed")
+    };
+    Some(match change {
+        EnvVarDiff::Added(value) => match quotable(value) {
+            Some(value) => format!("set {key}={value}"),
+            None => format!("set {key}"),
+        },
+        EnvVarDiff::Removed(_) => format!("unset {key}"),
+        EnvVarDiff::Changed(diff) => {
+            if change.store_paths_only() {
+                return None;
+            }
+            if diff.old.contains(&b'\n') || diff.new.contains(&b'\n') {
+                format!("{key}: {}", describe_lines(&diff.old, &diff.new))
+            } else {
+                format!("{key} {}", describe_change(diff))
+            }
+        }
+        EnvVarDiff::Requisites(diff) => counts(diff.added.len(), diff.removed.len(), "entries"),
+        EnvVarDiff::ClosureInfo(diff) => {
+            counts(diff.added.len(), diff.removed.len(), "store paths")
+        }
+        EnvVarDiff::StorePaths(diff) => {
+            if change.store_paths_only() {
+                return None;
+            }
+            counts(diff.added.len(), diff.removed.len(), "store paths")
+        }
+        EnvVarDiff::Renamed(renamed) => {
+            format!("rename {} → {key}", String::from_utf8_lossy(&renamed.from))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::sync::Arc;
+
+    fn string_diff(old: &str, new: &str) -> StringDiff {
+        StringDiff {
+            old: old.as_bytes().to_vec(),
+            new: new.as_bytes().to_vec(),
+        }
+    }
+
+    fn derivation_diff() -> DerivationDiff {
+        DerivationDiff::new(Derivation::default(), Derivation::default())
+    }
+
+    #[test]
+    fn versions_are_bumps_or_downgrades() {
+        let name = |old: &str, new: &str, downgrade| NameDiff {
+            pname: None,
+            version: Some(VersionDiff {
+                old: old.as_bytes().to_vec(),
+                new: new.as_bytes().to_vec(),
+                downgrade,
+            }),
+            covers_name: true,
+        };
+        assert_eq!(
+            describe_name(&name("3.0.12", "3.0.13", false), "openssl"),
+            ["bump openssl 3.0.12 → 3.0.13"]
+        );
+        assert_eq!(
+            describe_name(&name("2.0", "1.9", true), "foo"),
+            ["downgrade foo 2.0 → 1.9"]
+        );
+        let renamed = NameDiff {
+            pname: Some(string_diff("hello", "hello-wayland")),
+            version: None,
+            covers_name: true,
+        };
+        assert_eq!(
+            describe_name(&renamed, "hello-wayland"),
+            ["rename hello → hello-wayland"]
+        );
+    }
+
+    #[test]
+    fn dependencies_and_sources_are_named_without_hashes() {
+        let inputs = InputsDiff {
+            added: BTreeSet::from([DerivationPath(
+                b"/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-zstd-1.5.5.drv".to_vec(),
+            )]),
+            removed: BTreeSet::from([DerivationPath(
+                b"/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-xz-5.4.drv".to_vec(),
+            )]),
+            changed: Vec::new(),
+            pruned: Vec::new(),
+            rebuilt: Vec::new(),
+            not_compared: Vec::new(),
+            timed_out: false,
+            depth_limited: false,
+        };
+        assert_eq!(
+            describe_inputs(&inputs),
+            ["add dependency zstd-1.5.5", "drop dependency xz-5.4"]
+        );
+        let sources = SourcesDiff {
+            added: BTreeSet::from([
+                b"/nix/store/cccccccccccccccccccccccccccccccc-fix.patch".to_vec()
+            ]),
+            removed: BTreeSet::new(),
+            common: vec![SourceDiff {
+                path: b"/nix/store/dddddddddddddddddddddddddddddddd-builder.sh".to_vec(),
+                diff: TextDiff::NotCompared,
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            describe_sources(&sources),
+            ["update builder.sh", "add source fix.patch"]
+        );
+    }
+
+    #[test]
+    fn script_changes_quote_a_single_line() {
+        let old = "./configure\nmake\n";
+        assert_eq!(
+            describe_lines(old.as_bytes(), b"./configure\nmake -flto\n"),
+            "`make` → `make -flto`"
+        );
+        assert_eq!(
+            describe_lines(old.as_bytes(), b"./configure\nmake\nmake check\n"),
+            "add `make check`"
+        );
+        assert_eq!(
+            describe_lines(old.as_bytes(), b"autoreconf\n./configure --x\nmake\n"),
+            "3 lines changed"
+        );
+        // Store path churn is not a change.
+        assert_eq!(
+            describe_lines(
+                b"ln -s /nix/store/0c9z6vw9aqk8sw6j6d1x2lxnh2wrrxj0-dep\necho a\n",
+                b"ln -s /nix/store/1a6kqbnvc04rhs8zmbmmhz3x4zjvnmcc-dep\necho b\n"
+            ),
+            "`echo a` → `echo b`"
+        );
+    }
+
+    #[test]
+    fn arguments_are_quoted() {
+        let changed = |old: &str, new: &str| ArgumentDiff::Changed {
+            old_index: 1,
+            new_index: 1,
+            diff: string_diff(old, new),
+            store_paths_only: false,
+        };
+        let added = ArgumentDiff::Added {
+            index: 2,
+            value: b"--lto".to_vec(),
+        };
+        assert_eq!(describe_argument(&added).unwrap(), "add argument `--lto`");
+        let removed = ArgumentDiff::Removed {
+            index: 2,
+            value: b"-O2".to_vec(),
+        };
+        assert_eq!(
+            describe_argument(&removed).unwrap(),
+            "remove argument `-O2`"
+        );
+        assert_eq!(
+            describe_argument(&changed("-O2", "-O3")).unwrap(),
+            "argument `-O2` → `-O3`"
+        );
+        let long = "x".repeat(80);
+        assert_eq!(
+            describe_argument(&changed("-O2", &long)).unwrap(),
+            "argument 2 changed"
+        );
+        let noise = ArgumentDiff::Changed {
+            old_index: 1,
+            new_index: 1,
+            diff: string_diff("a", "b"),
+            store_paths_only: true,
+        };
+        assert_eq!(describe_argument(&noise), None);
+    }
+
+    #[test]
+    fn env_changes() {
+        assert_eq!(
+            describe_env(b"doCheck", &EnvVarDiff::Added(b"1".to_vec())).unwrap(),
+            "set doCheck=1"
+        );
+        assert_eq!(
+            describe_env(b"doCheck", &EnvVarDiff::Removed(b"1".to_vec())).unwrap(),
+            "unset doCheck"
+        );
+        assert_eq!(
+            describe_env(
+                b"configureFlagsArray",
+                &EnvVarDiff::Renamed(RenamedVar {
+                    from: b"configureFlags".to_vec(),
+                    ..Default::default()
+                })
+            )
+            .unwrap(),
+            "rename configureFlags → configureFlagsArray"
+        );
+        assert_eq!(
+            describe_env(
+                b"configureFlags",
+                &EnvVarDiff::Changed(string_diff("--a", "--a --b"))
+            )
+            .unwrap(),
+            "configureFlags --a → --a --b"
+        );
+        assert_eq!(
+            describe_env(
+                b"long",
+                &EnvVarDiff::Changed(string_diff("x", &"y".repeat(80)))
+            )
+            .unwrap(),
+            "long changed"
+        );
+        let churn = string_diff(
+            "/nix/store/0c9z6vw9aqk8sw6j6d1x2lxnh2wrrxj0-dep",
+            "/nix/store/1a6kqbnvc04rhs8zmbmmhz3x4zjvnmcc-dep",
+        );
+        assert_eq!(describe_env(b"dep", &EnvVarDiff::Changed(churn)), None);
+    }
+
+    #[test]
+    fn sandbox_flags() {
+        let flag = |key: &str, old: Option<&str>, new: Option<&str>| {
+            describe_sandbox_flag(&SandboxFlagDiff {
+                key: key.as_bytes().to_vec(),
+                old: old.map(|v| v.as_bytes().to_vec()),
+                new: new.map(|v| v.as_bytes().to_vec()),
+            })
+        };
+        assert_eq!(
+            flag("__noChroot", None, Some("1")),
+            "sandbox: __noChroot unset → true"
+        );
+        assert_eq!(
+            flag("allowSubstitutes", Some("1"), Some("")),
+            "sandbox: allowSubstitutes true → false"
+        );
+        assert_eq!(
+            flag("__sandboxProfile", Some("(allow network*)"), None),
+            "sandbox: __sandboxProfile (allow network*) → unset"
+        );
+        let profile = "(allow file-read*)\n(allow network*)";
+        assert_eq!(
+            flag("__sandboxProfile", None, Some(profile)),
+            "sandbox: __sandboxProfile changed"
+        );
+    }
+
+    #[test]
+    fn outputs() {
+        let out = Output {
+            path: Vec::new(),
+            hash_algorithm: None,
+            hash: None,
+        };
+        let added = OutputDiff {
+            name: b"doc".to_vec(),
+            diff: OutputDetailDiff::Added(out.clone()),
+        };
+        assert_eq!(describe_output(&added).unwrap(), "add output doc");
+        let path_only = OutputDiff {
+            name: b"out".to_vec(),
+            diff: OutputDetailDiff::Changed {
+                old: out.clone(),
+                new: Box::new(out),
+                path: Some(string_diff("a", "b")),
+                hash_algo: None,
+                hash: None,
+                fixed_output: None,
+            },
+        };
+        assert_eq!(describe_output(&path_only), None);
+    }
+
+    #[test]
+    fn message_orders_prefixes_and_caps() {
+        let env = |pairs: &[(&str, &str)]| -> BTreeMap<ByteStr, Vec<u8>> {
+            pairs
+                .iter()
+                .map(|(k, v)| (ByteStr::from(k.as_bytes()), v.as_bytes().to_vec()))
+                .collect()
+        };
+        let mut nested = derivation_diff();
+        Arc::make_mut(&mut nested.new).env = env(&[("name", "openssl-3.0.13")]);
+        nested.name = Some(NameDiff {
+            pname: None,
+            version: Some(VersionDiff {
+                old: b"3.0.12".to_vec(),
+                new: b"3.0.13".to_vec(),
+                downgrade: false,
+            }),
+            covers_name: true,
+        });
+        let mut nested_env = EnvironmentDiff::new();
+        nested_env.insert(
+            b"name".to_vec(),
+            Some(EnvVarDiff::Changed(string_diff("a", "b"))),
+        );
+        nested_env.insert(b"doCheck".to_vec(), Some(EnvVarDiff::Added(b"1".to_vec())));
+        nested.env = Some(nested_env);
+
+        let mut top = derivation_diff();
+        top.platform = Some(string_diff("x86_64-linux", "aarch64-linux"));
+        top.inputs = Some(InputsDiff {
+            added: BTreeSet::from([DerivationPath(
+                b"/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-zstd-1.5.5.drv".to_vec(),
+            )]),
+            removed: BTreeSet::new(),
+            changed: vec![InputDiff {
+                path: b"/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-openssl-3.0.13.drv".to_vec(),
+                outputs: None,
+                derivation: Some(Box::new(nested)),
+                unavailable: None,
+                version: None,
+            }],
+            pruned: Vec::new(),
+            rebuilt: Vec::new(),
+            not_compared: Vec::new(),
+            timed_out: false,
+            depth_limited: false,
+        });
+
+        assert_eq!(
+            commit_message(&top, 10),
+            "- bump openssl 3.0.12 → 3.0.13\n\
+             - add dependency zstd-1.5.5\n\
+             - platform x86_64-linux → aarch64-linux\n\
+             - openssl: set doCheck=1\n"
+        );
+        assert_eq!(
+            commit_message(&top, 2),
+            "- bump openssl 3.0.12 → 3.0.13\n\
+             - add dependency zstd-1.5.5\n\
+             - and 2 more changes\n"
+        );
+
+        // Only rebuilt: still say something.
+        let mut rebuilt = derivation_diff();
+        Arc::make_mut(&mut rebuilt.new).env = env(&[("out", "/nix/store/x")]);
+        assert_eq!(
+            commit_message(&rebuilt, 10),
+            "- rebuild against changed dependencies\n"
+        );
+        assert_eq!(commit_message(&derivation_diff(), 10), "");
+    }
+}
@@ -1,11 +1,160 @@
+use crate::cancel::CancellationToken;
+use crate::digest::{self, Digest};
+use crate::drv_cache::DrvCache;
+use crate::ignore::{EnvFilter, IgnoreRules};
+use crate::intern::{Id, Interner};
+use crate::store_reader::{Budgeted, ReadBudget, StoreReader};
+use crate::structured_attrs;
 use crate::types::*;
-use anyhow::Result;
-use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
-use std::fs;
+use crate::version;
+use anyhow::{Context, Result};
+use std::borrow::Cow;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+/// Env vars restricting what a build output may reference.
+const REQUISITE_VARS: &[&[u8]] = &[
+    b"allowedReferences",
+    b"allowedRequisites",
+    b"disallowedReferences",
+    b"disallowedRequisites",
+];
+
+/// Env vars naming reference graphs for the builder; always compared as
+/// path sets, however short.
+const CLOSURE_INFO_VARS: &[&[u8]] = &[b"exportReferencesGraph"];
+
+/// Other values and source files count as closure info once they list at
+/// least this many distinct store paths, making up at least a third of
+/// their words (registration info also has hashes, sizes and counts).
+const CLOSURE_INFO_MIN_PATHS: usize = 32;
+
+/// Shorter values never count as evidence of a renamed env var; flags like
+/// `1` or `true` match by coincidence.
+const RENAME_MIN_LEN: usize = 8;
 
 #[derive(Default)]
 pub struct DiffContext {
-    already_compared: HashSet<(Vec<u8>, Vec<u8>)>,
+    /// The store paths of the pairs compared so far, as interned ids.
+    already_compared: HashSet<[Id; 2]>,
+    /// The .drv paths of inputs, each stored once however many
+    /// derivations depend on it.
+    paths: Interner,
+    /// Readers for the old and the new side, which differ when the two
+    /// derivations come from different stores.
+    readers: [StoreReader; 2],
+    skip_content_reads: bool,
+    raw_path_lists: bool,
+    /// Threads for reading source and input .drv files; 0 picks one per
+    /// CPU, up to 8.
+    jobs: usize,
+    read_budget: ReadBudget,
+    derivation_limit: DerivationLimit,
+    deadline: Option<Instant>,
+    max_depth: Option<usize>,
+    cancellation: CancellationToken,
+    cache: DrvCache,
+    ignore: IgnoreRules,
+    ignore_env: EnvFilter,
+    counters: DiffCounters,
+}
+
+/// Input derivations a diff loads by default before it stops expanding
+/// inputs; see [`DiffContext::max_derivations`].
+pub const DEFAULT_MAX_DERIVATIONS: usize = 50_000;
+
+/// Input derivations a diff may still load. Atomic like [`ReadBudget`],
+/// so the cap holds however the recursion is split across threads.
+#[derive(Debug)]
+struct DerivationLimit {
+    remaining: AtomicUsize,
+}
+
+impl Default for DerivationLimit {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_DERIVATIONS)
+    }
+}
+
+impl DerivationLimit {
+    fn new(derivations: usize) -> Self {
+        Self {
+            remaining: AtomicUsize::new(derivations),
+        }
+    }
+
+    /// Take `derivations` out of the limit, or return `false` if not
+    /// enough are left.
+    fn take(&self, derivations: usize) -> bool {
+        self.remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(derivations)
+            })
+            .is_ok()
+    }
+
+    fn remaining(&self) -> usize {
+        self.remaining.load(Ordering::Relaxed)
+    }
+}
+
+/// How much work a diff did, for tests and benchmarks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DiffCounters {
+    /// Input .drv files parsed while recursing.
+    pub parsed: usize,
+    /// Parses avoided because both sides' .drv files were byte-identical.
+    pub parses_skipped: usize,
+    /// Derivation pairs found equal up front, skipping the section walk.
+    pub identical: usize,
+    /// Inputs with the same path on both sides, skipped without loading.
+    pub shared: usize,
+}
+
+/// A derivation pair whose inputs were listed, waiting for its changed
+/// inputs to be walked and then for the rest of it to be compared.
+struct Frame {
+    original: Arc<Derivation>,
+    new: Arc<Derivation>,
+    inputs: InputsDiff,
+    pending: VecDeque<PendingInput>,
+    /// Entries of `inputs.changed` moved to `inputs.not_compared`.
+    not_compared: Vec<usize>,
+    /// The entry in the parent's changed inputs this is the diff of.
+    slot: usize,
+    /// Entries of `inputs.changed` whose diff is a frame of its own, with
+    /// the index of that frame among the walked ones.
+    children: Vec<(usize, usize)>,
+}
+
+/// A changed input not compared yet.
+struct PendingInput {
+    /// Its entry in [`InputsDiff::changed`].
+    slot: usize,
+    paths: [Id; 2],
+}
+
+enum Started {
+    /// Nothing was left to compare.
+    Done(Box<DerivationDiff>),
+    Comparing(Box<Frame>),
+}
+
+/// Diff the .drv files `old` and `new` and their inputs with the default
+/// settings, the sides labelled by the paths as given. Its `Display`
+/// prints what `nix-diff --color never --no-closure-count old new` would.
+pub fn diff_files(old: &Path, new: &Path) -> Result<DerivationDiff> {
+    diff_paths(old, new, DiffContext::new())
+}
+
+/// [`diff_files`] with the settings of `options`, e.g. the stores to read
+/// sources from or a [`DiffContext::cancellation`] token.
+pub fn diff_paths(old: &Path, new: &Path, mut options: DiffContext) -> Result<DerivationDiff> {
+    options.diff_files(old, new)
 }
 
 impl DiffContext {
@@ -13,6 +162,123 @@ impl DiffContext {
         Self::default()
     }
 
+    /// Use `reader` for all file content reads (source diffs).
+    pub fn with_reader(reader: StoreReader) -> Self {
+        Self::with_readers(reader.clone(), reader)
+    }
+
+    /// Read the old side's sources and input derivations through `old` and
+    /// the new side's through `new`.
+    pub fn with_readers(old: StoreReader, new: StoreReader) -> Self {
+        Self {
+            readers: [old, new],
+            ..Self::default()
+        }
+    }
+
+    /// Don't read source files: sources whose store paths differ are
+    /// reported as changed with [`TextDiff::NotCompared`]. Input .drv files
+    /// are still read, so the derivation tree is complete.
+    pub fn skip_content_reads(mut self, skip: bool) -> Self {
+        self.skip_content_reads = skip;
+        self
+    }
+
+    /// Compare env vars listing store paths, such as `buildInputs`, as
+    /// plain strings rather than path by path.
+    pub fn raw_path_lists(mut self, raw: bool) -> Self {
+        self.raw_path_lists = raw;
+        self
+    }
+
+    /// Read and parse changed input derivations, then compare the
+    /// derivation pairs and their source files, on up to `jobs` threads.
+    /// The diff is the same for any number.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    /// Keep at most `bytes` of source contents in memory; sources past the
+    /// budget are reported with [`TextDiff::NotCompared`].
+    pub fn read_budget(mut self, bytes: usize) -> Self {
+        self.read_budget = ReadBudget::new(bytes);
+        self
+    }
+
+    /// Load at most `derivations` input derivations, two for each pair
+    /// compared. Past that, changed inputs are not compared, only listed
+    /// in [`InputsDiff::not_compared`]. Guards against comparing two
+    /// unrelated closures of hundreds of thousands of derivations.
+    pub fn max_derivations(mut self, derivations: usize) -> Self {
+        self.derivation_limit = DerivationLimit::new(derivations);
+        self
+    }
+
+    /// Stop comparing inputs once `deadline` has passed: changed inputs
+    /// reached after it are only listed in [`InputsDiff::not_compared`],
+    /// with [`InputsDiff::timed_out`] set, so a diff that runs out of time
+    /// still reports what it compared.
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Compare input derivations only down to `depth` levels below the
+    /// top level, 0 comparing the top level alone. Deeper changed inputs
+    /// are listed without a `derivation`, and their .drv files are not
+    /// read, with [`InputsDiff::depth_limited`] set.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Give up once `token` is cancelled: the diff checks it before each
+    /// derivation and source read and fails with
+    /// [`Cancelled`](crate::cancel::Cancelled).
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    /// Load input derivations through `cache`, sharing parses with other
+    /// contexts and closure walks using it.
+    pub fn cache(mut self, cache: DrvCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Leave what `rules` ignore out of every derivation compared, as if
+    /// it weren't there.
+    pub fn ignore(mut self, rules: IgnoreRules) -> Self {
+        self.ignore = rules;
+        self
+    }
+
+    /// Leave env vars `filter` matches out of the Environment section,
+    /// counting the ones that differ in [`DerivationDiff::ignored_env`].
+    pub fn ignore_env(mut self, filter: EnvFilter) -> Self {
+        self.ignore_env = filter;
+        self
+    }
+
+    pub fn counters(&self) -> DiffCounters {
+        self.counters
+    }
+
+    /// Parse the .drv files `old` and `new` and diff them with these
+    /// settings, the sides labelled by the paths as given.
+    pub fn diff_files(&mut self, old: &Path, new: &Path) -> Result<DerivationDiff> {
+        let [old, new] = [old, new].map(|path| path.to_string_lossy().into_owned());
+        let parse = |path: &str| {
+            crate::parser::parse_derivation(path)
+                .with_context(|| format!("Failed to parse derivation: {path}"))
+        };
+        let (drv1, drv2) = (Arc::new(parse(&old)?), Arc::new(parse(&new)?));
+        let diff = self.diff_shared(old.as_bytes(), new.as_bytes(), drv1, drv2)?;
+        Ok(diff.labels(old.as_bytes(), new.as_bytes()))
+    }
+
     pub fn diff_derivations(
         &mut self,
         path1: &[u8],
@@ -20,42 +286,191 @@ impl DiffContext {
         drv1: &Derivation,
         drv2: &Derivation,
     ) -> Result<DerivationDiff> {
-        let key = (path1.to_vec(), path2.to_vec());
+        let (drv1, drv2) = (Arc::new(drv1.clone()), Arc::new(drv2.clone()));
+        self.diff_shared(path1, path2, drv1, drv2)
+    }
+
+    /// [`diff_derivations`](Self::diff_derivations) of derivations the
+    /// diff can hold on to without copying them.
+    fn diff_shared(
+        &mut self,
+        path1: &[u8],
+        path2: &[u8],
+        drv1: Arc<Derivation>,
+        drv2: Arc<Derivation>,
+    ) -> Result<DerivationDiff> {
+        let mut stack = match self.start(path1, path2, drv1, drv2, 0)? {
+            Started::Done(diff) => return Ok(*diff),
+            Started::Comparing(frame) => vec![frame],
+        };
+        self.preload(&stack[0], 0);
+        // Changed inputs are walked depth first, each one in full before
+        // the next, but on a stack of our own rather than by recursion:
+        // dependency chains (bootstrap → gcc → glibc → …) nest hundreds of
+        // derivations deep. The walk alone decides which pairs are
+        // compared, so it stays in one thread and the diff is the same
+        // however the comparisons are scheduled.
+        let mut walked = Vec::new();
+        loop {
+            let depth = stack.len() - 1;
+            let frame = stack.last_mut().unwrap();
+            if !frame.pending.is_empty() {
+                if let Some(child) = self.compare_input(frame, depth)? {
+                    self.preload(&child, depth + 1);
+                    stack.push(child);
+                }
+                continue;
+            }
+            let frame = stack.pop().unwrap();
+            let slot = frame.slot;
+            walked.push(*frame);
+            match stack.last_mut() {
+                Some(parent) => parent.children.push((slot, walked.len() - 1)),
+                None => return self.compare_walked(walked),
+            }
+        }
+    }
+
+    /// Compare the derivation pairs of `walked` on up to `jobs` threads
+    /// and put their diffs together. Each frame comes after those of its
+    /// inputs, so the last one is the top level.
+    fn compare_walked(&self, walked: Vec<Frame>) -> Result<DerivationDiff> {
+        let threads = self.threads();
+        // Pairs are compared on threads of their own; a lone pair reads its
+        // sources on all of them instead.
+        let source_jobs = if walked.len() > 1 { 1 } else { threads };
+        // Started from the top level down, so its sources come first
+        // under the read budget.
+        let top_down: Vec<_> = walked.iter().rev().collect();
+        let mut compared = in_parallel(threads, &top_down, |frame| {
+            self.compare_pair(&frame.original, &frame.new, source_jobs)
+        });
+        compared.reverse();
+        let mut diffs: Vec<Option<DerivationDiff>> = Vec::with_capacity(walked.len());
+        for (frame, diff) in walked.into_iter().zip(compared) {
+            let mut diff = diff?;
+            let Frame {
+                mut inputs,
+                not_compared,
+                children,
+                ..
+            } = frame;
+            for (slot, child) in children {
+                inputs.changed[slot].derivation = diffs[child].take().map(Box::new);
+            }
+            diff.inputs = finish_inputs(inputs, &not_compared);
+            diffs.push(Some(diff));
+        }
+        Ok(diffs.pop().flatten().expect("the top-level pair is walked"))
+    }
+
+    /// List the inputs of `drv1` and `drv2`, leaving the rest of them to
+    /// [`compare_pair`](Self::compare_pair), or diff them outright if they
+    /// were compared before or are the same. `slot` is the entry in the
+    /// parent's changed inputs the diff goes to.
+    fn start(
+        &mut self,
+        path1: &[u8],
+        path2: &[u8],
+        drv1: Arc<Derivation>,
+        drv2: Arc<Derivation>,
+        slot: usize,
+    ) -> Result<Started> {
+        self.cancellation.check()?;
+        let key = [self.paths.intern(path1), self.paths.intern(path2)];
 
         if self.already_compared.contains(&key) {
-            return Ok(DerivationDiff {
-                original: drv1.clone(),
-                new: drv2.clone(),
-                outputs: OutputsDiff::AlreadyCompared,
-                platform: None,
-                builder: None,
-                args: None,
-                sources: None,
-                inputs: None,
-                env: None,
-            });
+            return Ok(Started::Done(Box::new(
+                DerivationDiff::new(drv1, drv2).outputs(OutputsDiff::AlreadyCompared),
+            )));
         }
 
         self.already_compared.insert(key);
 
+        // Only derivations something was ignored in are copied.
+        let strip = |drv: Arc<Derivation>| match self.ignore.strip(&drv) {
+            Cow::Borrowed(_) => drv,
+            Cow::Owned(stripped) => Arc::new(stripped),
+        };
+        let (drv1, drv2) = (strip(drv1), strip(drv2));
+
+        // The same path is the same derivation; skip the deep comparison.
+        if path1 == path2 || drv1 == drv2 {
+            self.counters.identical += 1;
+            return Ok(Started::Done(Box::new(DerivationDiff::new(drv1, drv2))));
+        }
+
+        let (inputs, pending) = self.list_inputs(&drv1.input_derivations, &drv2.input_derivations);
+
+        Ok(Started::Comparing(Box::new(Frame {
+            original: drv1,
+            new: drv2,
+            inputs,
+            pending,
+            not_compared: Vec::new(),
+            slot,
+            children: Vec::new(),
+        })))
+    }
+
+    /// The diff of `drv1` and `drv2` in everything but their inputs,
+    /// reading paired sources on up to `source_jobs` threads.
+    fn compare_pair(
+        &self,
+        drv1: &Arc<Derivation>,
+        drv2: &Arc<Derivation>,
+        source_jobs: usize,
+    ) -> Result<DerivationDiff> {
+        self.cancellation.check()?;
         let outputs = self.diff_outputs(&drv1.outputs, &drv2.outputs);
         let platform = self.diff_bytes(&drv1.platform, &drv2.platform);
         let builder = self.diff_bytes(&drv1.builder, &drv2.builder);
         let args = self.diff_arguments(&drv1.args, &drv2.args);
-        let sources = self.diff_sources(&drv1.input_sources, &drv2.input_sources)?;
-        let inputs = self.diff_inputs(&drv1.input_derivations, &drv2.input_derivations)?;
-        let env = self.diff_environment(&drv1.env, &drv2.env);
+        let sources = self.diff_sources(&drv1.input_sources, &drv2.input_sources, source_jobs)?;
+        self.cancellation.check()?;
+
+        let sandbox = diff_sandbox_flags(&drv1.env, &drv2.env);
+        let (mut env, ignored_env) = self.diff_environment(&drv1.env, &drv2.env);
+        let structured_attrs = env.as_mut().and_then(|env| diff_json_env(env, drv1, drv2));
+        let fetch = self.diff_fetch(drv1, drv2);
+        if let (Some(env), Some(fetch)) = (&mut env, &fetch) {
+            for attr in &fetch.attrs {
+                env.remove(&attr.key);
+            }
+        }
+        if env.as_ref().is_some_and(BTreeMap::is_empty) {
+            env = None;
+        }
+        if let Some(env) = &mut env {
+            annotate_requisites(env, [drv1, drv2], &self.readers, &self.cache);
+            annotate_closure_info(env, drv1, drv2);
+            if !self.raw_path_lists {
+                annotate_store_paths(env);
+            }
+            detect_renames(env);
+        }
+        let name = diff_name(&drv1.env, &drv2.env);
 
         Ok(DerivationDiff {
-            original: drv1.clone(),
-            new: drv2.clone(),
+            original: Arc::clone(drv1),
+            new: Arc::clone(drv2),
             outputs,
             platform,
             builder,
             args,
             sources,
-            inputs,
+            inputs: None,
             env,
+            name,
+            sandbox,
+            fetch,
+            structured_attrs,
+            ignored_env,
+            closure_size: None,
+            resolution: None,
+            labels: None,
+            output_names: None,
+            flake_inputs: None,
         })
     }
 
@@ -71,10 +486,25 @@ impl DiffContext {
         for name in all_names {
             match (outputs1.get(&name), outputs2.get(&name)) {
                 (Some(o1), Some(o2)) if o1 != o2 => {
-                    let path_diff = self.diff_bytes(&o1.path, &o2.path);
+                    // An output with no path yet has none to compare; its
+                    // kind and hash algorithm tell how it changed.
+                    let path_diff = match (o1.known_path(), o2.known_path()) {
+                        (Some(p1), Some(p2)) => self.diff_bytes(p1, p2),
+                        _ => None,
+                    };
                     let hash_algo_diff =
                         self.diff_optional_bytes(&o1.hash_algorithm, &o2.hash_algorithm);
-                    let hash_diff = self.diff_optional_bytes(&o1.hash, &o2.hash);
+                    // A hash in another encoding is the same hash.
+                    let hash_diff = if digest::same_output_hash(o1, o2) {
+                        None
+                    } else {
+                        self.diff_optional_bytes(&o1.hash, &o2.hash)
+                    };
+                    let fixed_output = match (o1.is_fixed_output(), o2.is_fixed_output()) {
+                        (false, true) => Some(FixedOutputTransition::BecameFixedOutput),
+                        (true, false) => Some(FixedOutputTransition::NoLongerFixedOutput),
+                        _ => None,
+                    };
 
                     diffs.push(OutputDiff {
                         name: name.clone(),
@@ -84,6 +514,7 @@ impl DiffContext {
                             path: path_diff,
                             hash_algo: hash_algo_diff,
                             hash: hash_diff,
+                            fixed_output,
                         },
                     });
                 }
@@ -115,21 +546,59 @@ impl DiffContext {
             return None;
         }
 
-        let mut diffs = Vec::new();
-        let max_len = args1.len().max(args2.len());
-
-        for i in 0..max_len {
-            let arg1 = args1.get(i).map(|s| s.as_slice()).unwrap_or(b"");
-            let arg2 = args2.get(i).map(|s| s.as_slice()).unwrap_or(b"");
+        // Align on the arguments with store path hashes masked, so that a
+        // rebuilt dependency doesn't stop an argument from lining up.
+        let masked1: Vec<_> = args1.iter().map(|a| normalize_store_paths(a)).collect();
+        let masked2: Vec<_> = args2.iter().map(|a| normalize_store_paths(a)).collect();
+        let changed = |o: usize, n: usize| ArgumentDiff::Changed {
+            old_index: o,
+            new_index: n,
+            diff: StringDiff {
+                old: args1[o].clone(),
+                new: args2[n].clone(),
+            },
+            store_paths_only: masked1[o] == masked2[n],
+        };
+        let added = |n: usize| ArgumentDiff::Added {
+            index: n,
+            value: args2[n].clone(),
+        };
+        let removed = |o: usize| ArgumentDiff::Removed {
+            index: o,
+            value: args1[o].clone(),
+        };
 
-            if arg1 != arg2 {
-                diffs.push(ArgumentDiff {
-                    index: i,
-                    diff: StringDiff {
-                        old: arg1.to_vec(),
-                        new: arg2.to_vec(),
-                    },
-                });
+        let mut diffs = Vec::new();
+        for op in similar::capture_diff_slices(similar::Algorithm::Myers, &masked1, &masked2) {
+            match op {
+                similar::DiffOp::Equal {
+                    old_index,
+                    new_index,
+                    len,
+                } => diffs.extend(
+                    (0..len)
+                        .filter(|i| args1[old_index + i] != args2[new_index + i])
+                        .map(|i| changed(old_index + i, new_index + i)),
+                ),
+                similar::DiffOp::Delete {
+                    old_index, old_len, ..
+                } => diffs.extend((old_index..old_index + old_len).map(removed)),
+                similar::DiffOp::Insert {
+                    new_index, new_len, ..
+                } => diffs.extend((new_index..new_index + new_len).map(added)),
+                // Replaced runs are paired up in order; the longer one's
+                // tail was added or removed.
+                similar::DiffOp::Replace {
+                    old_index,
+                    old_len,
+                    new_index,
+                    new_len,
+                } => {
+                    let paired = old_len.min(new_len);
+                    diffs.extend((0..paired).map(|i| changed(old_index + i, new_index + i)));
+                    diffs.extend((old_index + paired..old_index + old_len).map(removed));
+                    diffs.extend((new_index + paired..new_index + new_len).map(added));
+                }
             }
         }
 
@@ -138,8 +607,9 @@ impl DiffContext {
 
     fn diff_sources(
         &self,
-        sources1: &BTreeSet<Vec<u8>>,
-        sources2: &BTreeSet<Vec<u8>>,
+        sources1: &BTreeSet<ByteStr>,
+        sources2: &BTreeSet<ByteStr>,
+        jobs: usize,
     ) -> Result<Option<SourcesDiff>> {
         // Extract name from a store path: /nix/store/hash-name -> name
         fn get_source_name(path: &[u8]) -> &[u8] {
@@ -153,8 +623,8 @@ impl DiffContext {
         }
 
         // Group paths by name so we can pair sources that changed hash
-        let mut by_name1: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>> = BTreeMap::new();
-        let mut by_name2: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>> = BTreeMap::new();
+        let mut by_name1: BTreeMap<Vec<u8>, BTreeSet<ByteStr>> = BTreeMap::new();
+        let mut by_name2: BTreeMap<Vec<u8>, BTreeSet<ByteStr>> = BTreeMap::new();
         for p in sources1 {
             by_name1
                 .entry(get_source_name(p).to_vec())
@@ -173,6 +643,7 @@ impl DiffContext {
         let mut added = BTreeSet::new();
         let mut removed = BTreeSet::new();
         let mut common = Vec::new();
+        let mut pairs = Vec::new();
 
         let empty = BTreeSet::new();
         for name in &all_names {
@@ -184,51 +655,123 @@ impl DiffContext {
 
             let pair_count = only1.len().min(only2.len());
             for i in 0..pair_count {
-                let p1 = &only1[i];
-                let p2 = &only2[i];
-                match (
-                    std::str::from_utf8(p1).ok().and_then(|s| fs::read(s).ok()),
-                    std::str::from_utf8(p2).ok().and_then(|s| fs::read(s).ok()),
-                ) {
-                    (Some(c1), Some(c2)) => {
-                        if c1 != c2 {
-                            common.push(SourceDiff {
-                                path: name.clone(),
-                                diff: self.diff_file_contents(&c1, &c2),
-                            });
-                        }
-                    }
-                    _ => {
-                        // Cannot read — fall back to reporting as added/removed
-                        removed.insert(p1.clone());
-                        added.insert(p2.clone());
-                    }
-                }
+                pairs.push((name, only1[i].clone(), only2[i].clone()));
             }
             for p in &only1[pair_count..] {
-                removed.insert(p.clone());
+                removed.insert(p.to_vec());
             }
             for p in &only2[pair_count..] {
-                added.insert(p.clone());
+                added.insert(p.to_vec());
+            }
+        }
+
+        // Results come back in `pairs` order, i.e. sorted by name, however
+        // the reads were scheduled.
+        for ((name, p1, p2), read) in pairs.iter().zip(self.read_source_pairs(&pairs, jobs)) {
+            match read {
+                SourceRead::Differs(diff) => common.push(SourceDiff {
+                    path: name.to_vec(),
+                    diff,
+                }),
+                SourceRead::Same => {}
+                SourceRead::Unreadable => {
+                    // Cannot read — fall back to reporting as added/removed
+                    removed.insert(p1.to_vec());
+                    added.insert(p2.to_vec());
+                }
             }
         }
 
         if added.is_empty() && removed.is_empty() && common.is_empty() {
             Ok(None)
         } else {
+            let kinds = if self.skip_content_reads {
+                BTreeMap::new()
+            } else {
+                let kind = |side: usize, path: &Vec<u8>| {
+                    let kind = self.readers[side].source_kind(path)?;
+                    Some((path.clone(), kind))
+                };
+                removed
+                    .iter()
+                    .filter_map(|p| kind(0, p))
+                    .chain(added.iter().filter_map(|p| kind(1, p)))
+                    .collect()
+            };
             Ok(Some(SourcesDiff {
                 added,
                 removed,
                 common,
+                kinds,
             }))
         }
     }
 
-    fn diff_inputs(
+    /// Compare the contents of each (name, old path, new path) pair,
+    /// spreading the reads over up to `jobs` threads.
+    fn read_source_pairs(
+        &self,
+        pairs: &[(&Vec<u8>, ByteStr, ByteStr)],
+        jobs: usize,
+    ) -> Vec<SourceRead> {
+        let read_pair = |(_, p1, p2): &(&Vec<u8>, ByteStr, ByteStr)| {
+            // The diff fails once cancelled; what this returns is dropped.
+            if self.cancellation.is_cancelled() {
+                return SourceRead::Unreadable;
+            }
+            if self.skip_content_reads {
+                return SourceRead::Differs(TextDiff::NotCompared);
+            }
+            let budget = &self.read_budget;
+            match (
+                self.readers[0].read_within(p1, budget),
+                self.readers[1].read_within(p2, budget),
+            ) {
+                (Budgeted::Read(c1), Budgeted::Read(c2)) => {
+                    if c1 == c2 {
+                        budget.release(c1.len() + c2.len());
+                        SourceRead::Same
+                    } else if let Some(diff) = closure_info_diff(Some(&c1), Some(&c2), false) {
+                        SourceRead::Differs(TextDiff::ClosureInfo(diff))
+                    } else {
+                        SourceRead::Differs(self.diff_file_contents(&c1, &c2))
+                    }
+                }
+                (one, other) => {
+                    let unreadable = [&one, &other].contains(&&Budgeted::Unreadable);
+                    for contents in [one, other] {
+                        if let Budgeted::Read(c) = contents {
+                            budget.release(c.len());
+                        }
+                    }
+                    if unreadable {
+                        SourceRead::Unreadable
+                    } else {
+                        SourceRead::Differs(TextDiff::NotCompared)
+                    }
+                }
+            }
+        };
+
+        in_parallel(jobs, pairs, read_pair)
+    }
+
+    /// The number of threads `jobs` asks for.
+    fn threads(&self) -> usize {
+        match self.jobs {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get().min(8)),
+            n => n,
+        }
+    }
+
+    /// Diff the input derivations, leaving the pairs that changed to be
+    /// compared: their entries in [`InputsDiff::changed`] are placeholders
+    /// until then.
+    fn list_inputs(
         &mut self,
-        inputs1: &BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>,
-        inputs2: &BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>,
-    ) -> Result<Option<InputsDiff>> {
+        inputs1: &BTreeMap<ByteStr, BTreeSet<Vec<u8>>>,
+        inputs2: &BTreeMap<ByteStr, BTreeSet<Vec<u8>>>,
+    ) -> (InputsDiff, VecDeque<PendingInput>) {
         // Extract derivation name from a path like /nix/store/hash-name.drv -> name.drv
         fn get_derivation_name(path: &[u8]) -> &[u8] {
             if let Some(last_slash) = path.iter().rposition(|&b| b == b'/') {
@@ -243,8 +786,8 @@ impl DiffContext {
         // Build maps from derivation name to paths for both sets. A derivation
         // can have multiple inputs with the same name but different hashes,
         // so we collect all paths per name instead of overwriting.
-        let mut names_to_paths1: HashMap<Vec<u8>, BTreeSet<Vec<u8>>> = HashMap::new();
-        let mut names_to_paths2: HashMap<Vec<u8>, BTreeSet<Vec<u8>>> = HashMap::new();
+        let mut names_to_paths1: HashMap<Vec<u8>, BTreeSet<ByteStr>> = HashMap::new();
+        let mut names_to_paths2: HashMap<Vec<u8>, BTreeSet<ByteStr>> = HashMap::new();
 
         for path in inputs1.keys() {
             let name = get_derivation_name(path).to_vec();
@@ -268,11 +811,10 @@ impl DiffContext {
             .cloned()
             .collect();
 
-        let mut added = BTreeSet::new();
-        let mut removed = BTreeSet::new();
-        let mut changed = Vec::new();
+        let mut diff = InputsDiff::default();
+        let mut pending = VecDeque::new();
 
-        let empty: BTreeSet<Vec<u8>> = BTreeSet::new();
+        let empty: BTreeSet<ByteStr> = BTreeSet::new();
         for name in all_names {
             let paths1 = names_to_paths1.get(&name).unwrap_or(&empty);
             let paths2 = names_to_paths2.get(&name).unwrap_or(&empty);
@@ -291,130 +833,257 @@ impl DiffContext {
             for i in 0..pair_count {
                 let path1 = &only1[i];
                 let path2 = &only2[i];
-                self.push_changed_input(
+                queue_changed_input(
                     &name,
-                    path1,
-                    path2,
-                    &inputs1[path1],
-                    &inputs2[path2],
-                    &mut changed,
-                )?;
+                    [path1, path2],
+                    [&inputs1[path1], &inputs2[path2]],
+                    &mut diff,
+                    &mut pending,
+                    &mut self.paths,
+                );
             }
+
+            // An extra path next to a retained one of the same name that
+            // supplies outputs the retained one does not is that package
+            // being used for another output, e.g. its `dev` output coming
+            // from a different .drv, rather than a new dependency.
+            let retained = |inputs: &BTreeMap<ByteStr, BTreeSet<Vec<u8>>>| -> BTreeSet<Vec<u8>> {
+                common
+                    .iter()
+                    .flat_map(|path| inputs[path].clone())
+                    .collect()
+            };
+            let (retained1, retained2) = (retained(inputs1), retained(inputs2));
+            let mut output_usage = OutputSetDiff {
+                kept: &retained1 & &retained2,
+                ..Default::default()
+            };
             for path1 in &only1[pair_count..] {
-                removed.insert(DerivationPath(path1.clone()));
+                let outputs: BTreeSet<_> = inputs1[path1].difference(&retained2).cloned().collect();
+                if common.is_empty() || outputs.is_empty() {
+                    diff.removed.insert(DerivationPath(path1.to_vec()));
+                } else {
+                    output_usage.removed.extend(outputs);
+                }
             }
             for path2 in &only2[pair_count..] {
-                added.insert(DerivationPath(path2.clone()));
+                let outputs: BTreeSet<_> = inputs2[path2].difference(&retained1).cloned().collect();
+                if common.is_empty() || outputs.is_empty() {
+                    diff.added.insert(DerivationPath(path2.to_vec()));
+                } else {
+                    output_usage.added.extend(outputs);
+                }
+            }
+            if !output_usage.added.is_empty() || !output_usage.removed.is_empty() {
+                diff.changed.push(InputDiff {
+                    path: name.clone(),
+                    outputs: Some(output_usage),
+                    derivation: None,
+                    unavailable: None,
+                    version: None,
+                });
             }
 
-            // Same-path inputs: check for output-set changes
+            // Same-path inputs are the same derivation; only the outputs
+            // used from them can differ.
+            self.counters.shared += common.len();
             for path in &common {
-                let outputs1 = &inputs1[path];
-                let outputs2 = &inputs2[path];
-                if outputs1 != outputs2 {
-                    let added_outputs: BTreeSet<_> =
-                        outputs2.difference(outputs1).cloned().collect();
-                    let removed_outputs: BTreeSet<_> =
-                        outputs1.difference(outputs2).cloned().collect();
-                    if !added_outputs.is_empty() || !removed_outputs.is_empty() {
-                        changed.push(InputDiff {
-                            path: name.clone(),
-                            outputs: Some(OutputSetDiff {
-                                added: added_outputs,
-                                removed: removed_outputs,
-                            }),
-                            derivation: None,
-                        });
-                    }
+                if let Some(outputs) = OutputSetDiff::between(&inputs1[path], &inputs2[path]) {
+                    diff.changed.push(InputDiff {
+                        path: name.clone(),
+                        outputs: Some(outputs),
+                        derivation: None,
+                        unavailable: None,
+                        version: None,
+                    });
                 }
             }
         }
 
-        if added.is_empty() && removed.is_empty() && changed.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(InputsDiff {
-                added,
-                removed,
-                changed,
-            }))
-        }
+        pair_versions(inputs1, inputs2, &mut diff, &mut pending, &mut self.paths);
+        (diff, pending)
     }
 
-    fn push_changed_input(
-        &mut self,
-        name: &[u8],
-        path1: &[u8],
-        path2: &[u8],
-        outputs1: &BTreeSet<Vec<u8>>,
-        outputs2: &BTreeSet<Vec<u8>>,
-        changed: &mut Vec<InputDiff>,
-    ) -> Result<()> {
-        let outputs_diff = if outputs1 != outputs2 {
-            let added_outputs: BTreeSet<_> = outputs2.difference(outputs1).cloned().collect();
-            let removed_outputs: BTreeSet<_> = outputs1.difference(outputs2).cloned().collect();
-            if !added_outputs.is_empty() || !removed_outputs.is_empty() {
-                Some(OutputSetDiff {
-                    added: added_outputs,
-                    removed: removed_outputs,
-                })
-            } else {
-                None
+    /// Read and parse the .drv files of `frame`'s changed inputs on up to
+    /// `jobs` threads, ahead of walking them one at a time. This only
+    /// fills the cache: which inputs are compared, and in what order, is
+    /// up to [`compare_input`](Self::compare_input) as before.
+    fn preload(&mut self, frame: &Frame, depth: usize) {
+        let jobs = self.threads();
+        let late = self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline);
+        if jobs <= 1 || late || self.max_depth.is_some_and(|max| depth >= max) {
+            return;
+        }
+        // Pairs compared before are in the cache already, and don't count
+        // against the limit.
+        let files: Vec<_> = frame
+            .pending
+            .iter()
+            .filter(|input| !self.already_compared.contains(&input.paths))
+            .take(self.derivation_limit.remaining() / 2)
+            .map(|input| {
+                let paths = input.paths.map(|id| &self.paths.resolve(id)[..]);
+                let files = [0, 1].map(|side| self.readers[side].locate(paths[side]));
+                (paths, files)
+            })
+            .collect();
+        if files.len() < 2 {
+            return;
+        }
+        let before = self.cache.stats();
+        in_parallel(jobs, &files, |(paths, [file1, file2])| {
+            if !self.cancellation.is_cancelled() {
+                self.cache.load_pair_at(*paths, [file1, file2]);
             }
-        } else {
-            None
+        });
+        let after = self.cache.stats();
+        self.counters.parsed += after.parsed - before.parsed;
+        self.counters.parses_skipped += after.identical_files - before.identical_files;
+    }
+
+    /// Compare the next pending input of `frame`, whose derivations are
+    /// `depth` levels below the top. Returns the comparison to carry on
+    /// with, if the input's derivations differ.
+    fn compare_input(&mut self, frame: &mut Frame, depth: usize) -> Result<Option<Box<Frame>>> {
+        self.cancellation.check()?;
+        let Some(PendingInput { slot, paths }) = frame.pending.pop_front() else {
+            return Ok(None);
         };
+        let inputs = &mut frame.inputs;
 
-        // Try to load and recursively diff the derivations
-        let derivation_diff =
-            if let (Ok(p1), Ok(p2)) = (std::str::from_utf8(path1), std::str::from_utf8(path2)) {
-                if let (Ok(drv1), Ok(drv2)) = (
-                    crate::parser::parse_derivation(p1),
-                    crate::parser::parse_derivation(p2),
-                ) {
-                    Some(Box::new(self.diff_derivations(path1, path2, &drv1, &drv2)?))
-                } else {
-                    None
-                }
-            } else {
-                None
-            };
+        // Past the limit or the deadline, pairs not compared yet are only
+        // named.
+        let compared = self.already_compared.contains(&paths);
+        let late = self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline);
+        if !compared && self.max_depth.is_some_and(|max| depth >= max) {
+            inputs.depth_limited = true;
+            return Ok(None);
+        }
+        if !compared && (late || !self.derivation_limit.take(2)) {
+            inputs.timed_out |= late;
+            inputs.not_compared.push(inputs.changed[slot].clone());
+            frame.not_compared.push(slot);
+            return Ok(None);
+        }
 
-        changed.push(InputDiff {
-            path: name.to_vec(),
-            outputs: outputs_diff,
-            derivation: derivation_diff,
-        });
-        Ok(())
+        // Try to load and diff the derivations. If only one side is
+        // readable (partial GC), diff it against an empty derivation so its
+        // contents still show up as all-removed or all-added.
+        let [path1, path2] = paths.map(|id| ByteStr::clone(self.paths.resolve(id)));
+        let before = self.cache.stats();
+        let files = [
+            self.readers[0].locate(&path1),
+            self.readers[1].locate(&path2),
+        ];
+        let [drv1, drv2] = self
+            .cache
+            .load_pair_at([&path1, &path2], [&files[0], &files[1]]);
+        let after = self.cache.stats();
+        self.counters.parsed += after.parsed - before.parsed;
+        self.counters.parses_skipped += after.identical_files - before.identical_files;
+        inputs.changed[slot].unavailable = match (&drv1, &drv2) {
+            (Some(_), None) => Some(Side::New),
+            (None, Some(_)) => Some(Side::Old),
+            _ => None,
+        };
+        if drv1.is_none() && drv2.is_none() {
+            return Ok(None);
+        }
+        let started = self.start(
+            &path1,
+            &path2,
+            drv1.unwrap_or_default(),
+            drv2.unwrap_or_default(),
+            slot,
+        )?;
+        match started {
+            Started::Done(diff) => {
+                inputs.changed[slot].derivation = Some(diff);
+                Ok(None)
+            }
+            Started::Comparing(frame) => Ok(Some(frame)),
+        }
     }
 
     fn diff_environment(
         &self,
-        env1: &BTreeMap<Vec<u8>, Vec<u8>>,
-        env2: &BTreeMap<Vec<u8>, Vec<u8>>,
-    ) -> Option<EnvironmentDiff> {
+        env1: &BTreeMap<ByteStr, Vec<u8>>,
+        env2: &BTreeMap<ByteStr, Vec<u8>>,
+    ) -> (Option<EnvironmentDiff>, usize) {
         let mut diffs = BTreeMap::new();
+        let mut ignored = 0;
 
-        let all_keys: BTreeSet<_> = env1.keys().chain(env2.keys()).cloned().collect();
+        // Sandbox flags have their own section.
+        let all_keys: BTreeSet<&ByteStr> = env1
+            .keys()
+            .chain(env2.keys())
+            .filter(|key| !SANDBOX_FLAGS.contains(&&key[..]))
+            .collect();
 
         for key in all_keys {
-            match (env1.get(&key), env2.get(&key)) {
+            let (old, new) = (env1.get(key), env2.get(key));
+            if old != new && self.ignore_env.ignores(key) {
+                ignored += 1;
+                continue;
+            }
+            match (old, new) {
                 (Some(v1), Some(v2)) if v1 != v2 => {
                     if let Some(diff) = self.diff_bytes(v1, v2) {
-                        diffs.insert(key, Some(EnvVarDiff::Changed(diff)));
+                        diffs.insert(key.to_vec(), Some(EnvVarDiff::Changed(diff)));
                     }
                 }
                 (Some(v), None) => {
-                    diffs.insert(key, Some(EnvVarDiff::Removed(v.clone())));
+                    diffs.insert(key.to_vec(), Some(EnvVarDiff::Removed(v.clone())));
                 }
                 (None, Some(v)) => {
-                    diffs.insert(key, Some(EnvVarDiff::Added(v.clone())));
+                    diffs.insert(key.to_vec(), Some(EnvVarDiff::Added(v.clone())));
                 }
                 _ => {}
             }
         }
 
-        if diffs.is_empty() { None } else { Some(diffs) }
+        let diffs = if diffs.is_empty() { None } else { Some(diffs) };
+        (diffs, ignored)
+    }
+
+    /// The [`FETCH_ATTRS`] that differ, if either side is fixed-output.
+    /// Those left out by `--ignore-env` stay left out.
+    fn diff_fetch(&self, drv1: &Derivation, drv2: &Derivation) -> Option<FetchDiff> {
+        let fixed = |drv: &Derivation| drv.outputs.values().any(Output::is_fixed_output);
+        if !fixed(drv1) && !fixed(drv2) {
+            return None;
+        }
+        let digest = |drv: &Derivation| {
+            let hash = drv.env.get(b"outputHash".as_slice())?;
+            let algorithm = drv.env.get(b"outputHashAlgo".as_slice());
+            Digest::parse(hash, algorithm.map(Vec::as_slice))
+        };
+        let same_digest = digest(drv1).is_some_and(|old| digest(drv2) == Some(old));
+        let attrs: Vec<_> = FETCH_ATTRS
+            .iter()
+            .filter(|&&key| !self.ignore_env.ignores(key))
+            .filter_map(|&key| {
+                let (old, new) = (drv1.env.get(key), drv2.env.get(key));
+                (old != new).then(|| FetchAttrDiff {
+                    key: key.to_vec(),
+                    old: old.cloned(),
+                    new: new.cloned(),
+                    same_digest: same_digest && matches!(key, b"outputHash" | b"outputHashAlgo"),
+                })
+            })
+            .collect();
+        if attrs.is_empty() {
+            return None;
+        }
+        let source_changed = attrs
+            .iter()
+            .any(|attr| FETCH_SOURCE_ATTRS.contains(&attr.key.as_slice()));
+        let stale_hash = source_changed && fixed(drv1) && same_fixed_output_hashes(drv1, drv2);
+        Some(FetchDiff { attrs, stale_hash })
     }
 
     fn diff_bytes(&self, s1: &[u8], s2: &[u8]) -> Option<StringDiff> {
@@ -454,74 +1123,787 @@ impl DiffContext {
         }
         // Defer actual diffing to the renderer so it can choose between
         // plain line diff and inline word highlighting.
-        TextDiff::Text {
-            old: content1.to_vec(),
-            new: content2.to_vec(),
-        }
+        TextDiff::text(content1.to_vec(), content2.to_vec())
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    fn ctx() -> DiffContext {
-        DiffContext::new()
+/// `f` applied to each of `items` on up to `jobs` threads, the results
+/// in the order of `items` however the work was scheduled.
+fn in_parallel<T: Sync, R: Send>(jobs: usize, items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let jobs = jobs.min(items.len());
+    if jobs <= 1 {
+        return items.iter().map(f).collect();
     }
 
-    #[test]
-    fn diff_sources_matches_by_name_and_diffs_contents() {
-        // Sources with the same name but different store hashes should be
-        // paired and their file contents compared. Previously the code
-        // iterated the intersection of full paths (always empty when hashes
-        // differ) and read the same file twice.
-        let tmp = tempfile::tempdir().unwrap();
-        let store = tmp.path().join("store");
-        std::fs::create_dir_all(&store).unwrap();
-
-        let p1 = store.join("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-script.sh");
-        let p2 = store.join("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-script.sh");
-        std::fs::write(&p1, b"echo old\n").unwrap();
-        std::fs::write(&p2, b"echo new\n").unwrap();
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, R)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(i) else { break };
+                        done.push((i, f(item)));
+                    }
+                    done
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|w| w.join().expect("worker thread panicked"))
+            .collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
 
-        let s1: BTreeSet<Vec<u8>> = [p1.to_string_lossy().as_bytes().to_vec()].into();
-        let s2: BTreeSet<Vec<u8>> = [p2.to_string_lossy().as_bytes().to_vec()].into();
+/// `inputs` once the diffs of its changed entries are in: those at
+/// `not_compared` left out, the rest sorted by name, and `None` if nothing
+/// is left.
+fn finish_inputs(mut inputs: InputsDiff, not_compared: &[usize]) -> Option<InputsDiff> {
+    if !not_compared.is_empty() {
+        let mut slot = 0;
+        inputs.changed.retain(|_| {
+            slot += 1;
+            !not_compared.contains(&(slot - 1))
+        });
+    }
+    // Stable, so inputs sharing a name keep their order.
+    inputs.changed.sort_by(|a, b| a.path.cmp(&b.path));
+    inputs.not_compared.sort_by(|a, b| a.path.cmp(&b.path));
+    (inputs != InputsDiff::default()).then_some(inputs)
+}
 
-        let diff = ctx().diff_sources(&s1, &s2).unwrap().unwrap();
+/// Add the inputs `paths`, using `outputs` of each, to the changed inputs
+/// in `inputs` under `name`, to be compared later. Returns the entry's
+/// index.
+fn queue_changed_input(
+    name: &[u8],
+    paths: [&[u8]; 2],
+    outputs: [&BTreeSet<Vec<u8>>; 2],
+    inputs: &mut InputsDiff,
+    pending: &mut VecDeque<PendingInput>,
+    interner: &mut Interner,
+) -> usize {
+    let slot = inputs.changed.len();
+    inputs.changed.push(InputDiff {
+        path: name.to_vec(),
+        outputs: OutputSetDiff::between(outputs[0], outputs[1]),
+        derivation: None,
+        unavailable: None,
+        version: None,
+    });
+    pending.push_back(PendingInput {
+        slot,
+        paths: paths.map(|path| interner.intern(path)),
+    });
+    slot
+}
 
-        assert!(diff.added.is_empty(), "expected name-match, not addition");
-        assert!(diff.removed.is_empty(), "expected name-match, not removal");
-        assert_eq!(diff.common.len(), 1, "expected one content diff");
-        match &diff.common[0].diff {
-            TextDiff::Text { old, new } => {
-                assert!(old.starts_with(b"echo old"));
-                assert!(new.starts_with(b"echo new"));
+/// Pair a removed and an added input that are different versions of
+/// the same package, as a version bump. Only unambiguous pairs count:
+/// with two versions of a package on one side, nothing says which one
+/// became which.
+fn pair_versions(
+    inputs1: &BTreeMap<ByteStr, BTreeSet<Vec<u8>>>,
+    inputs2: &BTreeMap<ByteStr, BTreeSet<Vec<u8>>>,
+    diff: &mut InputsDiff,
+    pending: &mut VecDeque<PendingInput>,
+    interner: &mut Interner,
+) {
+    fn by_pname(paths: &BTreeSet<DerivationPath>) -> BTreeMap<&[u8], Vec<&DerivationPath>> {
+        let mut by_pname: BTreeMap<&[u8], Vec<_>> = BTreeMap::new();
+        for path in paths {
+            let (pname, version) = version::parse_drv_path(&path.0);
+            if !version.is_empty() {
+                by_pname.entry(pname).or_default().push(path);
             }
-            _ => panic!("expected text diff"),
         }
+        by_pname
     }
-
-    #[test]
-    fn diff_inputs_handles_duplicate_names() {
-        // Two input derivations can share the same name with different hashes
-        // (e.g., two "source.drv" inputs). The name-based matching must not
-        // silently drop one of them.
-        let mut inputs1: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>> = BTreeMap::new();
-        inputs1.insert(
-            b"/nix/store/aaaa-source.drv".to_vec(),
-            [b"out".to_vec()].into(),
-        );
-        inputs1.insert(
-            b"/nix/store/bbbb-source.drv".to_vec(),
-            [b"out".to_vec()].into(),
+    let (old, new) = (by_pname(&diff.removed), by_pname(&diff.added));
+    let pairs: Vec<(DerivationPath, DerivationPath)> = old
+        .iter()
+        .filter_map(
+            |(pname, old)| match (old.as_slice(), new.get(pname)?.as_slice()) {
+                ([old], [new]) => Some(((*old).clone(), (*new).clone())),
+                _ => None,
+            },
+        )
+        .collect();
+    for (path1, path2) in pairs {
+        diff.removed.remove(&path1);
+        diff.added.remove(&path2);
+        let slot = queue_changed_input(
+            path2.get_name(),
+            [&path1.0, &path2.0],
+            [&inputs1[path1.0.as_slice()], &inputs2[path2.0.as_slice()]],
+            diff,
+            pending,
+            interner,
         );
+        let (_, version1) = version::parse_drv_path(&path1.0);
+        let (_, version2) = version::parse_drv_path(&path2.0);
+        diff.changed[slot].version = Some(VersionDiff {
+            old: version1.to_vec(),
+            new: version2.to_vec(),
+            downgrade: version::compare_versions(version2, version1) == CmpOrdering::Less,
+        });
+    }
+}
 
-        // Second derivation has the same two inputs, unchanged
-        let inputs2 = inputs1.clone();
+/// Env attributes that weaken the build sandbox or make a derivation
+/// impure. Security review looks for these, so they are compared on their
+/// own and left out of the Environment section.
+pub const SANDBOX_FLAGS: &[&[u8]] = &[
+    b"__impure",
+    b"__noChroot",
+    b"__sandboxProfile",
+    b"allowSubstitutes",
+];
 
-        let diff = ctx().diff_inputs(&inputs1, &inputs2).unwrap();
-        // Identical inputs → no diff. With the bug, one input is dropped from
-        // each map and the survivor is compared against itself, still yielding
+/// Env attributes that say what a fixed-output derivation fetches and
+/// what it must hash to, as fetchurl, fetchgit and fetchzip set them. They
+/// are shown on their own, ahead of the other env vars they get lost in.
+pub const FETCH_ATTRS: &[&[u8]] = &[
+    b"url",
+    b"urls",
+    b"rev",
+    b"outputHash",
+    b"outputHashAlgo",
+    b"outputHashMode",
+];
+
+/// The [`FETCH_ATTRS`] that say where the source comes from.
+const FETCH_SOURCE_ATTRS: &[&[u8]] = &[b"url", b"urls", b"rev"];
+
+/// Whether `drv1` and `drv2` have the same fixed outputs, with the same
+/// digests in whatever encoding.
+fn same_fixed_output_hashes(drv1: &Derivation, drv2: &Derivation) -> bool {
+    fn fixed(drv: &Derivation) -> Vec<(&Vec<u8>, &Output)> {
+        (drv.outputs.iter())
+            .filter(|(_, output)| output.is_fixed_output())
+            .collect()
+    }
+    let (fixed1, fixed2) = (fixed(drv1), fixed(drv2));
+    fixed1.len() == fixed2.len()
+        && (fixed1.iter().zip(&fixed2))
+            .all(|((n1, o1), (n2, o2))| n1 == n2 && digest::same_output_hash(o1, o2))
+}
+
+/// The [`SANDBOX_FLAGS`] set, unset or changed between `env1` and `env2`.
+fn diff_sandbox_flags(
+    env1: &BTreeMap<ByteStr, Vec<u8>>,
+    env2: &BTreeMap<ByteStr, Vec<u8>>,
+) -> Option<Vec<SandboxFlagDiff>> {
+    let flags: Vec<_> = SANDBOX_FLAGS
+        .iter()
+        .filter_map(|&key| {
+            let (old, new) = (env1.get(key), env2.get(key));
+            (old != new).then(|| SandboxFlagDiff {
+                key: key.to_vec(),
+                old: old.cloned(),
+                new: new.cloned(),
+            })
+        })
+        .collect();
+    (!flags.is_empty()).then_some(flags)
+}
+
+/// Compare the structured attrs in `__json` attribute by attribute, taking
+/// `__json` out of `env`. It stays there if either side has no `__json`
+/// that parses, or if it was left out by `--ignore-env`.
+fn diff_json_env(
+    env: &mut EnvironmentDiff,
+    drv1: &Derivation,
+    drv2: &Derivation,
+) -> Option<Vec<StructuredAttrDiff>> {
+    env.get(structured_attrs::JSON_KEY)?;
+    let (old, new) = (
+        drv1.env.get(structured_attrs::JSON_KEY)?,
+        drv2.env.get(structured_attrs::JSON_KEY)?,
+    );
+    let attrs = structured_attrs::diff_structured_attrs(old, new)?;
+    env.remove(structured_attrs::JSON_KEY);
+    Some(attrs)
+}
+
+/// Compare package names and versions. `None` unless both sides have a
+/// `name` and the package name or version differs.
+fn diff_name(
+    env1: &BTreeMap<ByteStr, Vec<u8>>,
+    env2: &BTreeMap<ByteStr, Vec<u8>>,
+) -> Option<NameDiff> {
+    fn split(env: &BTreeMap<ByteStr, Vec<u8>>) -> Option<(&[u8], &[u8], bool)> {
+        let name = env.get(b"name".as_slice())?;
+        let (parsed_pname, parsed_version) = version::parse_drv_name(name);
+        let pname = env
+            .get(b"pname".as_slice())
+            .map_or(parsed_pname, Vec::as_slice);
+        let version = env
+            .get(b"version".as_slice())
+            .map_or(parsed_version, Vec::as_slice);
+        let covers_name = (pname, version) == (parsed_pname, parsed_version);
+        Some((pname, version, covers_name))
+    }
+    let (pname1, version1, covers1) = split(env1)?;
+    let (pname2, version2, covers2) = split(env2)?;
+    if (pname1, version1) == (pname2, version2) {
+        return None;
+    }
+
+    Some(NameDiff {
+        pname: (pname1 != pname2).then(|| StringDiff {
+            old: pname1.to_vec(),
+            new: pname2.to_vec(),
+        }),
+        version: (version1 != version2).then(|| VersionDiff {
+            old: version1.to_vec(),
+            new: version2.to_vec(),
+            downgrade: version::compare_versions(version2, version1) == CmpOrdering::Less,
+        }),
+        covers_name: covers1 && covers2,
+    })
+}
+
+/// Outcome of comparing one pair of same-named source files.
+enum SourceRead {
+    Differs(TextDiff),
+    Same,
+    Unreadable,
+}
+
+/// Replace changes to reference restriction vars with entry-wise diffs
+/// that say whether each entry is actually in the closure. The closures are
+/// only walked if such a variable changed.
+fn annotate_requisites(
+    env: &mut EnvironmentDiff,
+    [drv1, drv2]: [&Derivation; 2],
+    readers: &[StoreReader; 2],
+    cache: &DrvCache,
+) {
+    let mut closures = None;
+    for key in REQUISITE_VARS {
+        let Some(Some(var_diff)) = env.get(*key) else {
+            continue;
+        };
+        if matches!(var_diff, EnvVarDiff::Requisites(_)) {
+            continue;
+        }
+        let (old_closure, new_closure) = closures.get_or_insert_with(|| {
+            let [old, new] = [(drv1, &readers[0]), (drv2, &readers[1])].map(|(drv, reader)| {
+                crate::closure::closure_paths_with(drv, |p| cache.load_at(p, &reader.locate(p)))
+            });
+            (old, new)
+        });
+        let old = drv1.env.get(*key).map(Vec::as_slice);
+        let new = drv2.env.get(*key).map(Vec::as_slice);
+        if let Some(diff) = requisites_diff(old, new, old_closure, new_closure) {
+            env.insert(key.to_vec(), Some(EnvVarDiff::Requisites(diff)));
+        }
+    }
+}
+
+/// Replace a removed and an added env var that hold the same value (up to
+/// store path hashes) with a rename. Only values that no other removed or
+/// added var holds qualify, so the pairing is never a guess.
+fn detect_renames(env: &mut EnvironmentDiff) {
+    let mut by_value: HashMap<Cow<'_, [u8]>, [Vec<&[u8]>; 2]> = HashMap::new();
+    for (key, change) in env.iter() {
+        let (value, side) = match change {
+            Some(EnvVarDiff::Removed(value)) => (value, 0),
+            Some(EnvVarDiff::Added(value)) => (value, 1),
+            _ => continue,
+        };
+        if value.len() >= RENAME_MIN_LEN {
+            by_value.entry(normalize_store_paths(value)).or_default()[side].push(key);
+        }
+    }
+    let renames: Vec<(Vec<u8>, Vec<u8>)> = by_value
+        .into_values()
+        .filter_map(|[removed, added]| match (&removed[..], &added[..]) {
+            ([from], [to]) => Some((from.to_vec(), to.to_vec())),
+            _ => None,
+        })
+        .collect();
+    for (from, to) in renames {
+        if let (Some(Some(EnvVarDiff::Removed(old))), Some(Some(EnvVarDiff::Added(new)))) =
+            (env.remove(&from), env.remove(&to))
+        {
+            env.insert(to, Some(EnvVarDiff::Renamed(RenamedVar { from, old, new })));
+        }
+    }
+}
+
+/// Replace changes to env vars holding closure listings with path-set
+/// diffs.
+fn annotate_closure_info(env: &mut EnvironmentDiff, drv1: &Derivation, drv2: &Derivation) {
+    for (key, var_diff) in env.iter_mut() {
+        if !matches!(
+            var_diff,
+            Some(EnvVarDiff::Added(_) | EnvVarDiff::Removed(_) | EnvVarDiff::Changed(_))
+        ) {
+            continue;
+        }
+        let old = drv1.env.get(key.as_slice()).map(Vec::as_slice);
+        let new = drv2.env.get(key.as_slice()).map(Vec::as_slice);
+        let force = CLOSURE_INFO_VARS.contains(&key.as_slice());
+        if let Some(diff) = closure_info_diff(old, new, force) {
+            *var_diff = Some(EnvVarDiff::ClosureInfo(diff));
+        }
+    }
+}
+
+/// Replace changes to env vars that list nothing but store paths, such as
+/// `buildInputs`, with path-by-path diffs. Long listings were already
+/// taken as closure info.
+fn annotate_store_paths(env: &mut EnvironmentDiff) {
+    for var_diff in env.values_mut() {
+        let Some(EnvVarDiff::Changed(StringDiff { old, new })) = var_diff else {
+            continue;
+        };
+        if let Some(diff) = store_paths_diff(old, new) {
+            *var_diff = Some(EnvVarDiff::StorePaths(diff));
+        }
+    }
+}
+
+/// The words of `value` if there are any and all are store paths.
+fn store_path_list(value: &[u8]) -> Option<BTreeSet<&[u8]>> {
+    let paths: BTreeSet<_> = value
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|w| !w.is_empty())
+        .collect();
+    (!paths.is_empty() && paths.iter().all(|p| is_store_path(p))).then_some(paths)
+}
+
+/// Diff two store path lists as sets, pairing a removed and an added path
+/// when nothing but their hash differs. `None` unless both values are
+/// such lists, one of them has several paths, and more than the order
+/// changed; a single path reads fine as a string.
+fn store_paths_diff(old: &[u8], new: &[u8]) -> Option<StorePathsDiff> {
+    let (old_paths, new_paths) = (store_path_list(old)?, store_path_list(new)?);
+    if old_paths.len().max(new_paths.len()) < 2 || old_paths == new_paths {
+        return None;
+    }
+    let mut by_name: BTreeMap<Cow<'_, [u8]>, [Vec<&[u8]>; 2]> = BTreeMap::new();
+    for (side, (paths, others)) in [(&old_paths, &new_paths), (&new_paths, &old_paths)]
+        .into_iter()
+        .enumerate()
+    {
+        for path in paths.difference(others) {
+            by_name.entry(normalize_store_paths(path)).or_default()[side].push(path);
+        }
+    }
+    let mut diff = StorePathsDiff {
+        old: old.to_vec(),
+        new: new.to_vec(),
+        ..Default::default()
+    };
+    for [removed, added] in by_name.into_values() {
+        match (&removed[..], &added[..]) {
+            ([old], [new]) => diff.rebuilt.push(StringDiff {
+                old: old.to_vec(),
+                new: new.to_vec(),
+            }),
+            _ => {
+                diff.removed.extend(removed.iter().map(|p| p.to_vec()));
+                diff.added.extend(added.iter().map(|p| p.to_vec()));
+            }
+        }
+    }
+    diff.removed.sort();
+    diff.added.sort();
+    Some(diff)
+}
+
+const BASE32: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Whether `s` starts with a 32-character nix-base32 hash and a dash, like
+/// the file name of a store path.
+fn starts_with_hash(s: &[u8]) -> bool {
+    s.len() > 32 && s[32] == b'-' && s[..32].iter().all(|c| BASE32.contains(c))
+}
+
+/// Whether `word` is a store path or a path below one: some component
+/// is a 32-character nix-base32 hash followed by a dash.
+fn is_store_path(word: &[u8]) -> bool {
+    word.starts_with(b"/")
+        && word
+            .split(|&b| b == b'/')
+            .any(|component| component.len() > 33 && starts_with_hash(component))
+}
+
+/// `text` with the hash of every store path replaced by a fixed
+/// placeholder, so two texts that only reference different builds of the
+/// same paths compare equal.
+pub fn normalize_store_paths(text: &[u8]) -> Cow<'_, [u8]> {
+    // 'e' is not a base32 digit, so the placeholder can't be a real hash.
+    const PLACEHOLDER: &[u8; 32] = b"eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee";
+    let mut normalized: Option<Vec<u8>> = None;
+    let mut i = 0;
+    while i < text.len() {
+        if text[i] == b'/' && starts_with_hash(&text[i + 1..]) {
+            let out = normalized.get_or_insert_with(|| text.to_vec());
+            out[i + 1..i + 33].copy_from_slice(PLACEHOLDER);
+            i += 33;
+        } else {
+            i += 1;
+        }
+    }
+    normalized.map_or(Cow::Borrowed(text), Cow::Owned)
+}
+
+/// The store paths in `value` if it looks like a closure listing, or all
+/// of them regardless when `force` is set.
+fn closure_info_paths(value: &[u8], force: bool) -> Option<BTreeSet<&[u8]>> {
+    let (mut words, mut path_words) = (0, 0);
+    let mut paths = BTreeSet::new();
+    for word in value
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|w| !w.is_empty())
+    {
+        words += 1;
+        if is_store_path(word) {
+            path_words += 1;
+            paths.insert(word);
+        }
+    }
+    let listing = paths.len() >= CLOSURE_INFO_MIN_PATHS && path_words * 3 >= words;
+    (force || listing).then_some(paths)
+}
+
+/// Diff two closure listings as path sets. `None` if neither side is a
+/// listing (and `force` is unset), or only order and other words changed.
+fn closure_info_diff(
+    old: Option<&[u8]>,
+    new: Option<&[u8]>,
+    force: bool,
+) -> Option<ClosureInfoDiff> {
+    let old_paths = old.map(|v| closure_info_paths(v, force));
+    let new_paths = new.map(|v| closure_info_paths(v, force));
+    // Both sides that exist have to be listings.
+    if matches!(old_paths, Some(None)) || matches!(new_paths, Some(None)) {
+        return None;
+    }
+    let old_paths = old_paths.flatten().unwrap_or_default();
+    let new_paths = new_paths.flatten().unwrap_or_default();
+    let added: Vec<_> = new_paths
+        .difference(&old_paths)
+        .map(|p| p.to_vec())
+        .collect();
+    let removed: Vec<_> = old_paths
+        .difference(&new_paths)
+        .map(|p| p.to_vec())
+        .collect();
+    if added.is_empty() && removed.is_empty() {
+        return None;
+    }
+    Some(ClosureInfoDiff {
+        old: old.map(<[u8]>::to_vec),
+        new: new.map(<[u8]>::to_vec),
+        added,
+        removed,
+    })
+}
+
+/// Diff two reference restriction values as sets. Returns `None` if only
+/// order or whitespace changed.
+fn requisites_diff(
+    old: Option<&[u8]>,
+    new: Option<&[u8]>,
+    old_closure: &HashSet<Vec<u8>>,
+    new_closure: &HashSet<Vec<u8>>,
+) -> Option<RequisitesDiff> {
+    fn entries(value: Option<&[u8]>) -> BTreeSet<&[u8]> {
+        value
+            .unwrap_or_default()
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|e| !e.is_empty())
+            .collect()
+    }
+    fn annotate(entry: &[u8], closure: &HashSet<Vec<u8>>) -> Requisite {
+        Requisite {
+            entry: entry.to_vec(),
+            in_closure: entry.starts_with(b"/").then(|| closure.contains(entry)),
+        }
+    }
+    let (old_entries, new_entries) = (entries(old), entries(new));
+    if old_entries == new_entries {
+        return None;
+    }
+    Some(RequisitesDiff {
+        old: old.map(<[u8]>::to_vec),
+        new: new.map(<[u8]>::to_vec),
+        added: new_entries
+            .difference(&old_entries)
+            .map(|e| annotate(e, new_closure))
+            .collect(),
+        removed: old_entries
+            .difference(&new_entries)
+            .map(|e| annotate(e, old_closure))
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx() -> DiffContext {
+        DiffContext::new()
+    }
+
+    fn path_set<P: AsRef<[u8]>>(paths: impl IntoIterator<Item = P>) -> BTreeSet<ByteStr> {
+        paths
+            .into_iter()
+            .map(|p| ByteStr::from(p.as_ref()))
+            .collect()
+    }
+
+    impl DiffContext {
+        /// The inputs diff of two derivations with nothing but `inputs1`
+        /// and `inputs2`.
+        fn diff_inputs(
+            &mut self,
+            inputs1: &BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>,
+            inputs2: &BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>,
+        ) -> Result<Option<InputsDiff>> {
+            let drv = |inputs: &BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>| Derivation {
+                input_derivations: inputs
+                    .iter()
+                    .map(|(path, outputs)| (ByteStr::from(path.as_slice()), outputs.clone()))
+                    .collect(),
+                ..Default::default()
+            };
+            let diff =
+                self.diff_derivations(b"old.drv", b"new.drv", &drv(inputs1), &drv(inputs2))?;
+            Ok(diff.inputs)
+        }
+    }
+
+    #[test]
+    fn diff_sources_matches_by_name_and_diffs_contents() {
+        // Sources with the same name but different store hashes should be
+        // paired and their file contents compared. Previously the code
+        // iterated the intersection of full paths (always empty when hashes
+        // differ) and read the same file twice.
+        let tmp = tempfile::tempdir().unwrap();
+        let store = tmp.path().join("store");
+        std::fs::create_dir_all(&store).unwrap();
+
+        let p1 = store.join("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-script.sh");
+        let p2 = store.join("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-script.sh");
+        std::fs::write(&p1, b"echo old\n").unwrap();
+        std::fs::write(&p2, b"echo new\n").unwrap();
+
+        let s1 = path_set([p1.to_string_lossy().as_bytes()]);
+        let s2 = path_set([p2.to_string_lossy().as_bytes()]);
+
+        let diff = DiffContext::with_reader(StoreReader::new([&store]))
+            .diff_sources(&s1, &s2, 1)
+            .unwrap()
+            .unwrap();
+
+        assert!(diff.added.is_empty(), "expected name-match, not addition");
+        assert!(diff.removed.is_empty(), "expected name-match, not removal");
+        assert_eq!(diff.common.len(), 1, "expected one content diff");
+        match &diff.common[0].diff {
+            TextDiff::Text { old, new, .. } => {
+                assert!(old.starts_with(b"echo old"));
+                assert!(new.starts_with(b"echo new"));
+            }
+            _ => panic!("expected text diff"),
+        }
+    }
+
+    #[test]
+    fn added_sources_are_told_apart_as_dirs_and_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = tmp.path().join("store");
+        let vendor = store.join("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-vendor");
+        let patch = store.join("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-fix.patch");
+        let gone = store.join("cccccccccccccccccccccccccccccccc-old.patch");
+        std::fs::create_dir_all(vendor.join("src")).unwrap();
+        std::fs::write(vendor.join("src/lib.rs"), "fn x() {}").unwrap();
+        std::fs::write(vendor.join("Cargo.toml"), "[package]").unwrap();
+        std::fs::write(&patch, "--- a\n+++ b\n").unwrap();
+        let path = |p: &std::path::Path| p.to_string_lossy().as_bytes().to_vec();
+
+        let s1 = path_set([path(&gone)]);
+        let s2 = path_set([path(&vendor), path(&patch)]);
+        let diff = DiffContext::with_reader(StoreReader::new([&store]))
+            .diff_sources(&s1, &s2, 1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(diff.removed, [path(&gone)].into());
+        assert_eq!(
+            diff.kinds,
+            [
+                (
+                    path(&vendor),
+                    SourceKind::Dir {
+                        files: 2,
+                        size: 18,
+                        truncated: false,
+                    }
+                ),
+                (path(&patch), SourceKind::File { size: 12 }),
+            ]
+            .into()
+        );
+
+        let preliminary = DiffContext::with_reader(StoreReader::new([&store]))
+            .skip_content_reads(true)
+            .diff_sources(&s1, &s2, 1)
+            .unwrap()
+            .unwrap();
+        assert!(preliminary.kinds.is_empty());
+    }
+
+    #[test]
+    fn skip_content_reads_pairs_sources_without_reading() {
+        // Neither file exists; with reads enabled they would degrade to
+        // added/removed.
+        let s1 = path_set([b"/nix/store/aaaa-script.sh"]);
+        let s2 = path_set([b"/nix/store/bbbb-script.sh"]);
+
+        let diff = ctx()
+            .skip_content_reads(true)
+            .diff_sources(&s1, &s2, 1)
+            .unwrap()
+            .unwrap();
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        assert_eq!(
+            diff.common,
+            vec![SourceDiff {
+                path: b"script.sh".to_vec(),
+                diff: TextDiff::NotCompared,
+            }]
+        );
+
+        let diff = ctx().diff_sources(&s1, &s2, 1).unwrap().unwrap();
+        assert!(diff.common.is_empty());
+        assert_eq!(diff.added.len(), 1);
+    }
+
+    /// `n` pairs of same-named sources in a temporary store; every third
+    /// pair is identical and every fifth new file is missing.
+    fn source_fixture(n: usize) -> (tempfile::TempDir, BTreeSet<ByteStr>, BTreeSet<ByteStr>) {
+        let tmp = tempfile::tempdir().unwrap();
+        let (mut s1, mut s2) = (BTreeSet::new(), BTreeSet::new());
+        for i in 0..n {
+            let p1 = tmp.path().join(format!("aaaa-file{i:03}.sh"));
+            let p2 = tmp.path().join(format!("bbbb-file{i:03}.sh"));
+            std::fs::write(&p1, format!("echo {i}\n")).unwrap();
+            if i % 5 != 0 {
+                let new = if i % 3 == 0 { i } else { i + 1 };
+                std::fs::write(&p2, format!("echo {new}\n")).unwrap();
+            }
+            s1.insert(ByteStr::from(p1.to_string_lossy().as_bytes()));
+            s2.insert(ByteStr::from(p2.to_string_lossy().as_bytes()));
+        }
+        (tmp, s1, s2)
+    }
+
+    #[test]
+    fn parallel_source_reads_match_sequential() {
+        let (tmp, s1, s2) = source_fixture(60);
+        let diff = |jobs| {
+            DiffContext::with_reader(StoreReader::new([tmp.path()]))
+                .diff_sources(&s1, &s2, jobs)
+                .unwrap()
+                .unwrap()
+        };
+        let sequential = diff(1);
+        assert_eq!(sequential.removed.len(), 12, "missing files degrade");
+        assert_eq!(sequential.common.len(), 32);
+        for jobs in [2, 7, 64] {
+            assert_eq!(diff(jobs), sequential, "jobs = {jobs}");
+        }
+    }
+
+    #[test]
+    fn source_reads_stop_at_the_budget() {
+        // Each file is 7 bytes. 42 bytes hold the two differing pairs
+        // and, briefly, the identical one.
+        let (tmp, s1, s2) = source_fixture(4);
+        let diff = DiffContext::with_reader(StoreReader::new([tmp.path()]))
+            .read_budget(42)
+            .diff_sources(&s1, &s2, 1)
+            .unwrap()
+            .unwrap();
+        let kinds: Vec<_> = diff
+            .common
+            .iter()
+            .map(|s| matches!(s.diff, TextDiff::NotCompared))
+            .collect();
+        // Pair 0 is unreadable and pair 3 identical; neither keeps its bytes.
+        assert_eq!(kinds, [false, false]);
+
+        let diff = DiffContext::with_reader(StoreReader::new([tmp.path()]))
+            .read_budget(20)
+            .diff_sources(&s1, &s2, 1)
+            .unwrap()
+            .unwrap();
+        assert!(matches!(diff.common[0].diff, TextDiff::Text { .. }));
+        assert_eq!(diff.common[1].diff, TextDiff::NotCompared);
+    }
+
+    #[test]
+    fn diff_sources_does_not_read_outside_the_store() {
+        // A crafted drv listing files outside the store must not get their
+        // contents into the report; they degrade to added/removed paths.
+        let tmp = tempfile::tempdir().unwrap();
+        let store = tmp.path().join("store");
+        let etc = tmp.path().join("etc");
+        std::fs::create_dir_all(&store).unwrap();
+        std::fs::create_dir_all(&etc).unwrap();
+        let p1 = etc.join("aaaa-hostname");
+        let p2 = etc.join("bbbb-hostname");
+        std::fs::write(&p1, b"secret-host-1\n").unwrap();
+        std::fs::write(&p2, b"secret-host-2\n").unwrap();
+
+        let s1 = path_set([p1.to_string_lossy().as_bytes()]);
+        let s2 = path_set([p2.to_string_lossy().as_bytes()]);
+        let diff = DiffContext::with_reader(StoreReader::new([&store]))
+            .diff_sources(&s1, &s2, 1)
+            .unwrap()
+            .unwrap();
+        assert!(diff.common.is_empty(), "read a file outside the store");
+        assert_eq!(
+            diff.removed,
+            [p1.to_string_lossy().as_bytes().to_vec()].into()
+        );
+        assert_eq!(
+            diff.added,
+            [p2.to_string_lossy().as_bytes().to_vec()].into()
+        );
+    }
+
+    #[test]
+    fn diff_inputs_handles_duplicate_names() {
+        // Two input derivations can share the same name with different hashes
+        // (e.g., two "source.drv" inputs). The name-based matching must not
+        // silently drop one of them.
+        let mut inputs1: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>> = BTreeMap::new();
+        inputs1.insert(
+            b"/nix/store/aaaa-source.drv".to_vec(),
+            [b"out".to_vec()].into(),
+        );
+        inputs1.insert(
+            b"/nix/store/bbbb-source.drv".to_vec(),
+            [b"out".to_vec()].into(),
+        );
+
+        // Second derivation has the same two inputs, unchanged
+        let inputs2 = inputs1.clone();
+
+        let diff = ctx().diff_inputs(&inputs1, &inputs2).unwrap();
+        // Identical inputs → no diff. With the bug, one input is dropped from
+        // each map and the survivor is compared against itself, still yielding
         // None — so also assert we account for both paths when they differ:
         assert!(diff.is_none());
 
@@ -535,6 +1917,1234 @@ mod tests {
         assert!(diff.changed.is_empty());
     }
 
+    #[test]
+    fn diff_inputs_pairs_versions_of_the_same_package() {
+        let inputs = |paths: &[&str]| -> BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>> {
+            paths
+                .iter()
+                .map(|p| {
+                    (
+                        format!("/nix/store/{p}").into_bytes(),
+                        [b"out".to_vec()].into(),
+                    )
+                })
+                .collect()
+        };
+        let inputs1 = inputs(&[
+            "aaaa-hello-2.12.drv",
+            "bbbb-openssl-3.0.13.drv",
+            "cccc-python3.11-requests-2.31.0.drv",
+            "dddd-zlib-1.2.drv",
+            "eeee-zlib-1.3.drv",
+        ]);
+        let inputs2 = inputs(&[
+            "ffff-hello-2.10.drv",
+            "gggg-openssl-3.3.1.drv",
+            "hhhh-python3.11-requests-2.32.3.drv",
+            "iiii-zlib-1.3.1.drv",
+            "jjjj-ripgrep-14.1.0.drv",
+        ]);
+
+        let diff = ctx().diff_inputs(&inputs1, &inputs2).unwrap().unwrap();
+        let versions: Vec<_> = diff
+            .changed
+            .iter()
+            .map(|input| {
+                let version = input.version.as_ref().unwrap();
+                (
+                    String::from_utf8_lossy(&input.path).into_owned(),
+                    String::from_utf8_lossy(&version.old).into_owned(),
+                    String::from_utf8_lossy(&version.new).into_owned(),
+                    version.downgrade,
+                )
+            })
+            .collect();
+        let owned = |path: &str, old: &str, new: &str, downgrade| {
+            (
+                path.to_string(),
+                old.to_string(),
+                new.to_string(),
+                downgrade,
+            )
+        };
+        assert_eq!(
+            versions,
+            [
+                owned("hello-2.10.drv", "2.12", "2.10", true),
+                owned("openssl-3.3.1.drv", "3.0.13", "3.3.1", false),
+                owned("python3.11-requests-2.32.3.drv", "2.31.0", "2.32.3", false),
+            ]
+        );
+        // Two old zlibs: which one became 1.3.1 is anyone's guess.
+        let paths = |set: &BTreeSet<DerivationPath>| -> Vec<String> {
+            set.iter()
+                .map(|p| String::from_utf8_lossy(p.get_name()).into_owned())
+                .collect()
+        };
+        assert_eq!(paths(&diff.removed), ["zlib-1.2.drv", "zlib-1.3.drv"]);
+        assert_eq!(paths(&diff.added), ["ripgrep-14.1.0.drv", "zlib-1.3.1.drv"]);
+    }
+
+    #[test]
+    fn diff_inputs_tells_new_outputs_from_new_dependencies() {
+        let inputs = |entries: &[(&str, &[&str])]| -> BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>> {
+            entries
+                .iter()
+                .map(|(path, outputs)| {
+                    (
+                        format!("/nix/store/{path}").into_bytes(),
+                        outputs.iter().map(|o| o.as_bytes().to_vec()).collect(),
+                    )
+                })
+                .collect()
+        };
+        let outputs = |names: &[&str]| -> BTreeSet<Vec<u8>> {
+            names.iter().map(|o| o.as_bytes().to_vec()).collect()
+        };
+        let names = |set: &BTreeSet<DerivationPath>| -> Vec<String> {
+            set.iter()
+                .map(|p| String::from_utf8_lossy(p.get_name()).into_owned())
+                .collect()
+        };
+        let inputs1 = inputs(&[
+            ("aaaa-curl.drv", &["out"]),
+            ("bbbb-libxml2.drv", &["out"]),
+            ("cccc-libxml2.drv", &["dev"]),
+            ("dddd-openssl-3.0.13.drv", &["out"]),
+            ("eeee-perl.drv", &["out"]),
+            ("ffff-zlib.drv", &["out"]),
+        ]);
+        let inputs2 = inputs(&[
+            // zlib's dev output, from another .drv: output usage changed.
+            ("ffff-zlib.drv", &["out"]),
+            ("gggg-zlib.drv", &["dev"]),
+            // libxml2's dev output no longer used.
+            ("bbbb-libxml2.drv", &["out"]),
+            // A second curl for the same output: a new dependency.
+            ("aaaa-curl.drv", &["out"]),
+            ("hhhh-curl.drv", &["out"]),
+            // Renamed by a version bump.
+            ("iiii-openssl-3.3.1.drv", &["out"]),
+            // New and dropped outright (perl).
+            ("jjjj-sqlite.drv", &["out"]),
+        ]);
+
+        let diff = ctx().diff_inputs(&inputs1, &inputs2).unwrap().unwrap();
+        assert_eq!(names(&diff.added), ["curl.drv", "sqlite.drv"]);
+        assert_eq!(names(&diff.removed), ["perl.drv"]);
+        let usage: Vec<_> = diff
+            .changed
+            .iter()
+            .filter_map(|input| Some((input.path.as_slice(), input.outputs.clone()?)))
+            .collect();
+        assert_eq!(
+            usage,
+            [
+                (
+                    &b"libxml2.drv"[..],
+                    OutputSetDiff {
+                        added: outputs(&[]),
+                        removed: outputs(&["dev"]),
+                        kept: outputs(&["out"]),
+                    }
+                ),
+                (
+                    &b"zlib.drv"[..],
+                    OutputSetDiff {
+                        added: outputs(&["dev"]),
+                        removed: outputs(&[]),
+                        kept: outputs(&["out"]),
+                    }
+                ),
+            ]
+        );
+        let version = diff
+            .changed
+            .iter()
+            .find_map(|i| i.version.as_ref())
+            .unwrap();
+        assert_eq!(
+            (&version.old[..], &version.new[..]),
+            (&b"3.0.13"[..], &b"3.3.1"[..])
+        );
+    }
+
+    fn output(hash_algorithm: &[u8], hash: &[u8]) -> BTreeMap<Vec<u8>, Output> {
+        let opt = |b: &[u8]| (!b.is_empty()).then(|| b.to_vec());
+        [(
+            b"out".to_vec(),
+            Output {
+                path: b"/nix/store/aaaa-src".to_vec(),
+                hash_algorithm: opt(hash_algorithm),
+                hash: opt(hash),
+            },
+        )]
+        .into()
+    }
+
+    fn fixed_output_transition(diff: &OutputsDiff) -> Option<FixedOutputTransition> {
+        match diff {
+            OutputsDiff::Changed(diffs) => match &diffs[0].diff {
+                OutputDetailDiff::Changed { fixed_output, .. } => *fixed_output,
+                other => panic!("expected changed output, got {other:?}"),
+            },
+            other => panic!("expected changed outputs, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn diff_outputs_detects_fixed_output_transitions() {
+        let regular = output(b"", b"");
+        let fixed = output(b"sha256", b"abcd");
+
+        let became = ctx().diff_outputs(&regular, &fixed);
+        assert_eq!(
+            fixed_output_transition(&became),
+            Some(FixedOutputTransition::BecameFixedOutput)
+        );
+        let no_longer = ctx().diff_outputs(&fixed, &regular);
+        assert_eq!(
+            fixed_output_transition(&no_longer),
+            Some(FixedOutputTransition::NoLongerFixedOutput)
+        );
+
+        // A new hash value (e.g. a src bump) or a floating CA output is not
+        // a transition.
+        let rehashed = ctx().diff_outputs(&fixed, &output(b"sha256", b"ef01"));
+        assert_eq!(fixed_output_transition(&rehashed), None);
+        let floating = ctx().diff_outputs(&regular, &output(b"r:sha256", b""));
+        assert_eq!(fixed_output_transition(&floating), None);
+    }
+
+    #[test]
+    fn floating_outputs_are_compared_by_hash_algorithm() {
+        let floating = |algo: &[u8]| {
+            let mut outputs = output(algo, b"");
+            outputs.get_mut(b"out".as_slice()).unwrap().path.clear();
+            outputs
+        };
+        assert!(matches!(
+            ctx().diff_outputs(&floating(b"r:sha256"), &floating(b"r:sha256")),
+            OutputsDiff::Identical
+        ));
+        let OutputsDiff::Changed(diffs) =
+            ctx().diff_outputs(&floating(b"r:sha256"), &floating(b"text:sha256"))
+        else {
+            panic!("floating outputs with other methods are the same");
+        };
+        let OutputDetailDiff::Changed {
+            path, hash_algo, ..
+        } = &diffs[0].diff
+        else {
+            panic!("{diffs:?}");
+        };
+        assert!(path.is_none());
+        assert!(hash_algo.is_some());
+
+        // Becoming floating leaves no path to diff against.
+        let OutputsDiff::Changed(diffs) =
+            ctx().diff_outputs(&output(b"", b""), &floating(b"r:sha256"))
+        else {
+            panic!("an output that became floating is the same");
+        };
+        let OutputDetailDiff::Changed { path, new, .. } = &diffs[0].diff else {
+            panic!("{diffs:?}");
+        };
+        assert!(path.is_none());
+        assert_eq!(new.shown_path(), b"(content-addressed, floating)");
+    }
+
+    #[test]
+    fn diff_outputs_compares_hashes_by_digest() {
+        let hex = b"5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+        let sri = b"sha256-WJG1tSLV3whtD/CxEPvZ0hu0/HFjrzTQgoai6Eb2vgM=";
+        let OutputsDiff::Changed(diffs) =
+            ctx().diff_outputs(&output(b"r:sha256", hex), &output(b"r:sha256", sri))
+        else {
+            panic!("the hash text differs");
+        };
+        assert!(matches!(
+            diffs[0].diff,
+            OutputDetailDiff::Changed {
+                hash: None,
+                hash_algo: None,
+                ..
+            }
+        ));
+        let other = b"0000000000000000000000000000000000000000000000000000";
+        let OutputsDiff::Changed(diffs) =
+            ctx().diff_outputs(&output(b"r:sha256", hex), &output(b"r:sha256", other))
+        else {
+            panic!("the hash differs");
+        };
+        assert!(matches!(
+            diffs[0].diff,
+            OutputDetailDiff::Changed { hash: Some(_), .. }
+        ));
+    }
+
+    #[test]
+    fn has_category_finds_nested_fixed_output_transitions() {
+        let drv = |outputs| Derivation {
+            outputs,
+            input_sources: Default::default(),
+            input_derivations: Default::default(),
+            platform: Vec::new(),
+            builder: Vec::new(),
+            args: Vec::new(),
+            env: Default::default(),
+        };
+        let inner = ctx()
+            .diff_derivations(
+                b"a",
+                b"b",
+                &drv(output(b"", b"")),
+                &drv(output(b"sha256", b"ab")),
+            )
+            .unwrap();
+        let mut outer = ctx()
+            .diff_derivations(b"c", b"d", &drv(output(b"", b"")), &drv(output(b"", b"")))
+            .unwrap();
+        assert!(!outer.has_category(ChangeCategory::FixedOutputTransition));
+
+        outer.inputs = Some(InputsDiff {
+            added: Default::default(),
+            removed: Default::default(),
+            changed: vec![InputDiff {
+                path: b"src.drv".to_vec(),
+                outputs: None,
+                derivation: Some(Box::new(inner)),
+                unavailable: None,
+                version: None,
+            }],
+            pruned: Vec::new(),
+            rebuilt: Vec::new(),
+            not_compared: Vec::new(),
+            timed_out: false,
+            depth_limited: false,
+        });
+        assert!(outer.has_category(ChangeCategory::FixedOutputTransition));
+    }
+
+    #[test]
+    fn unreadable_input_is_diffed_against_empty_derivation() {
+        // Partial GC: the old dep.drv is still in the store, the new one is
+        // gone. We should still report what the old one contained.
+        let tmp = tempfile::tempdir().unwrap();
+        let store = tmp.path().join("store");
+        std::fs::create_dir_all(&store).unwrap();
+        let store = store.to_str().unwrap();
+        let old_path = format!("{store}/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-dep.drv");
+        let new_path = format!("{store}/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-dep.drv");
+        std::fs::write(
+            &old_path,
+            format!(
+                r#"Derive([("out","{store}/cccccccccccccccccccccccccccccccc-dep","","")],[],[],"x86_64-linux","/bin/sh",[],[("name","dep"),("version","1.0")])"#
+            ),
+        )
+        .unwrap();
+
+        let inputs1: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>> =
+            [(old_path.into_bytes(), [b"out".to_vec()].into())].into();
+        let inputs2: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>> =
+            [(new_path.into_bytes(), [b"out".to_vec()].into())].into();
+        let diff = ctx().diff_inputs(&inputs1, &inputs2).unwrap().unwrap();
+
+        assert_eq!(diff.changed.len(), 1);
+        let input = &diff.changed[0];
+        assert_eq!(input.unavailable, Some(Side::New));
+        let env = input.derivation.as_ref().unwrap().env.as_ref().unwrap();
+        assert_eq!(
+            env.get(&b"version"[..]),
+            Some(&Some(EnvVarDiff::Removed(b"1.0".to_vec())))
+        );
+
+        // Swapping the sides flips the label.
+        let diff = ctx().diff_inputs(&inputs2, &inputs1).unwrap().unwrap();
+        assert_eq!(diff.changed[0].unavailable, Some(Side::Old));
+
+        // Neither side readable: nothing to recurse into.
+        std::fs::remove_file(std::str::from_utf8(inputs1.keys().next().unwrap()).unwrap()).unwrap();
+        let diff = ctx().diff_inputs(&inputs1, &inputs2).unwrap().unwrap();
+        assert_eq!(diff.changed[0].unavailable, None);
+        assert!(diff.changed[0].derivation.is_none());
+    }
+
+    #[test]
+    fn requisites_are_checked_against_the_closure() {
+        let lib = Derivation {
+            outputs: [(
+                b"out".to_vec(),
+                Output {
+                    path: b"/nix/store/aaaa-openssl".to_vec(),
+                    hash_algorithm: None,
+                    hash: None,
+                },
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let root = Derivation {
+            input_derivations: BTreeMap::from([(
+                ByteStr::from(b"openssl.drv".as_slice()),
+                [b"out".to_vec()].into(),
+            )]),
+            ..Default::default()
+        };
+        let load = |p: &[u8]| (p == b"openssl.drv").then(|| lib.clone());
+        let new_closure = crate::closure::closure_paths_with(&root, load);
+        let old_closure = HashSet::new();
+
+        let diff = requisites_diff(
+            Some(b"out /nix/store/cccc-glibc"),
+            Some(b"out /nix/store/aaaa-openssl /nix/store/bbbb-curl"),
+            &old_closure,
+            &new_closure,
+        )
+        .unwrap();
+        let found: Vec<_> = diff
+            .added
+            .iter()
+            .map(|r| (r.entry.as_slice(), r.in_closure))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (&b"/nix/store/aaaa-openssl"[..], Some(true)),
+                (&b"/nix/store/bbbb-curl"[..], Some(false)),
+            ]
+        );
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.removed[0].in_closure, Some(false));
+
+        // Reordering is not a change; output names are not store paths.
+        assert!(requisites_diff(Some(b"a  b"), Some(b"b a"), &old_closure, &new_closure).is_none());
+        let diff = requisites_diff(None, Some(b"dev"), &old_closure, &new_closure).unwrap();
+        assert_eq!(diff.added[0].in_closure, None);
+    }
+
+    #[test]
+    fn annotates_changed_requisite_vars_only() {
+        let drv = |value: &[u8]| Derivation {
+            env: BTreeMap::from([
+                (
+                    ByteStr::from(b"allowedRequisites".as_slice()),
+                    value.to_vec(),
+                ),
+                (ByteStr::from(b"version".as_slice()), value.to_vec()),
+            ]),
+            ..Default::default()
+        };
+        let diff = ctx()
+            .diff_derivations(b"a", b"b", &drv(b"/nix/store/x"), &drv(b"/nix/store/y"))
+            .unwrap();
+        let env = diff.env.unwrap();
+        assert!(matches!(
+            env[&b"allowedRequisites"[..]],
+            Some(EnvVarDiff::Requisites(_))
+        ));
+        assert!(matches!(env[&b"version"[..]], Some(EnvVarDiff::Changed(_))));
+    }
+
+    #[test]
+    fn identical_input_files_are_parsed_once() {
+        // Four paired inputs, two of which have byte-identical .drv files
+        // under different paths (as when comparing two stores).
+        let tmp = tempfile::tempdir().unwrap();
+        let store = tmp.path().join("store");
+        std::fs::create_dir_all(&store).unwrap();
+        let store = store.to_str().unwrap();
+        let drv = |name: &str, version: &str| {
+            format!(
+                r#"Derive([("out","{store}/cccccccccccccccccccccccccccccccc-{name}","","")],[],[],"x86_64-linux","/bin/sh",[],[("name","{name}"),("version","{version}")])"#
+            )
+        };
+        let mut inputs1: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>> = BTreeMap::new();
+        let mut inputs2 = inputs1.clone();
+        for (i, same) in [true, false, true, false].into_iter().enumerate() {
+            let name = format!("dep{i}");
+            let p1 = format!("{store}/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-{name}.drv");
+            let p2 = format!("{store}/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-{name}.drv");
+            std::fs::write(&p1, drv(&name, "1")).unwrap();
+            std::fs::write(&p2, drv(&name, if same { "1" } else { "2" })).unwrap();
+            inputs1.insert(p1.into_bytes(), [b"out".to_vec()].into());
+            inputs2.insert(p2.into_bytes(), [b"out".to_vec()].into());
+        }
+
+        let mut ctx = ctx();
+        let diff = ctx.diff_inputs(&inputs1, &inputs2).unwrap().unwrap();
+        assert_eq!(diff.changed.len(), 4);
+        assert_eq!(
+            ctx.counters(),
+            DiffCounters {
+                parsed: 6,
+                parses_skipped: 2,
+                identical: 2,
+                shared: 0,
+            }
+        );
+        let identical = diff.changed[0].derivation.as_ref().unwrap();
+        assert!(identical.env.is_none() && identical.outputs == OutputsDiff::Identical);
+        assert!(diff.changed[1].derivation.as_ref().unwrap().env.is_some());
+    }
+
+    #[test]
+    fn comparison_stops_at_max_derivations() {
+        // Four changed inputs, each with a changed input of its own.
+        let tmp = tempfile::tempdir().unwrap();
+        let store = tmp.path().join("store");
+        std::fs::create_dir_all(&store).unwrap();
+        let store = store.to_str().unwrap();
+        let write = |hash: char, name: &str, version: &str, input: Option<&str>| {
+            let path = format!("{store}/{}-{name}.drv", hash.to_string().repeat(32));
+            let input = input.map_or(String::new(), |p| format!(r#"("{p}",["out"])"#));
+            std::fs::write(
+                &path,
+                format!(
+                    r#"Derive([("out","{store}/{}-{name}","","")],[{input}],[],"x86_64-linux","/bin/sh",[],[("name","{name}"),("version","{version}")])"#,
+                    hash.to_string().repeat(32),
+                ),
+            )
+            .unwrap();
+            path
+        };
+        let mut roots = [Derivation::default(), Derivation::default()];
+        for i in 0..4 {
+            for (side, (hash, version)) in [('a', "1"), ('b', "2")].into_iter().enumerate() {
+                let sub = write(hash, &format!("sub{i}"), version, None);
+                let dep = write(hash, &format!("dep{i}"), version, Some(&sub));
+                roots[side]
+                    .input_derivations
+                    .insert(dep.into_bytes().into(), [b"out".to_vec()].into());
+            }
+        }
+        let diff = |limit: usize| {
+            let mut ctx = DiffContext::new().max_derivations(limit);
+            let diff = (ctx.diff_derivations(b"root1", b"root2", &roots[0], &roots[1])).unwrap();
+            diff.inputs.unwrap()
+        };
+        let names = |inputs: &[InputDiff]| -> Vec<String> {
+            let names = inputs
+                .iter()
+                .map(|i| String::from_utf8_lossy(&i.path).into_owned());
+            names.collect()
+        };
+
+        let full = diff(DEFAULT_MAX_DERIVATIONS);
+        assert!(full.not_compared.is_empty());
+        assert_eq!(full.changed.len(), 4);
+
+        // dep0, its sub0 and dep1 fit in six; sub1, dep2 and dep3 don't.
+        let capped = diff(6);
+        assert_eq!(names(&capped.changed), ["dep0.drv", "dep1.drv"]);
+        assert_eq!(names(&capped.not_compared), ["dep2.drv", "dep3.drv"]);
+        assert_eq!(capped.changed[0], full.changed[0]);
+        let dep1 = capped.changed[1].derivation.as_ref().unwrap();
+        assert_eq!(dep1.env, full.changed[1].derivation.as_ref().unwrap().env);
+        let dep1_inputs = dep1.inputs.as_ref().unwrap();
+        assert!(dep1_inputs.changed.is_empty());
+        assert_eq!(names(&dep1_inputs.not_compared), ["sub1.drv"]);
+    }
+
+    #[test]
+    fn comparison_stops_at_max_depth() {
+        // root -> dep -> sub, where sub's .drv files are not derivations:
+        // reading them is a wasted parse.
+        let tmp = tempfile::tempdir().unwrap();
+        let store = tmp.path().to_str().unwrap();
+        let mut roots = [Derivation::default(), Derivation::default()];
+        for (side, (hash, version)) in [('a', "1"), ('b', "2")].into_iter().enumerate() {
+            let hash = hash.to_string().repeat(32);
+            let sub = format!("{store}/{hash}-sub.drv");
+            std::fs::write(&sub, format!("not a derivation {version}")).unwrap();
+            let dep = format!("{store}/{hash}-dep-{version}.drv");
+            std::fs::write(
+                &dep,
+                format!(
+                    r#"Derive([("out","{store}/{hash}-dep-{version}","","")],[("{sub}",["out"])],[],"x86_64-linux","/bin/sh",[],[("name","dep-{version}")])"#
+                ),
+            )
+            .unwrap();
+            roots[side]
+                .input_derivations
+                .insert(dep.into_bytes().into(), [b"out".to_vec()].into());
+        }
+        let diff = |ctx: &mut DiffContext| {
+            let diff = (ctx.diff_derivations(b"root1", b"root2", &roots[0], &roots[1])).unwrap();
+            diff.inputs.unwrap()
+        };
+
+        let mut ctx = DiffContext::new();
+        let full = diff(&mut ctx);
+        assert_eq!(ctx.counters().parsed, 4);
+        assert!(!full.depth_limited);
+
+        // The top level alone: dep is listed, with its version change.
+        let mut ctx = DiffContext::new().max_depth(0);
+        let top = diff(&mut ctx);
+        assert_eq!(ctx.counters().parsed, 0);
+        assert!(top.depth_limited);
+        assert_eq!(top.changed.len(), 1);
+        assert_eq!(top.changed[0].derivation, None);
+        let version = top.changed[0].version.as_ref().unwrap();
+        assert_eq!((&version.old[..], &version.new[..]), (&b"1"[..], &b"2"[..]));
+
+        // One level down: dep is compared, sub is not even read.
+        let mut ctx = DiffContext::new().max_depth(1);
+        let one = diff(&mut ctx);
+        assert_eq!(ctx.counters().parsed, 2);
+        assert!(!one.depth_limited);
+        let dep = one.changed[0].derivation.as_ref().unwrap();
+        let dep_inputs = dep.inputs.as_ref().unwrap();
+        assert!(dep_inputs.depth_limited);
+        assert_eq!(dep_inputs.changed.len(), 1);
+        assert_eq!(dep_inputs.changed[0].derivation, None);
+        assert_eq!(dep_inputs.changed[0].unavailable, None);
+        assert_eq!(
+            dep.env,
+            full.changed[0].derivation.as_ref().unwrap().env,
+            "the levels compared are compared in full"
+        );
+    }
+
+    #[test]
+    fn parallel_comparisons_match_sequential() {
+        // Six changed libraries sharing a changed input, each with one of
+        // its own; one library is missing on the new side. The shared one
+        // is compared under the first library only, whichever thread
+        // compares which pair.
+        let tmp = tempfile::tempdir().unwrap();
+        let store = tmp.path().to_str().unwrap();
+        let mut roots = [Derivation::default(), Derivation::default()];
+        for (side, (hash, version)) in [('a', "1"), ('b', "2")].into_iter().enumerate() {
+            let hash = hash.to_string().repeat(32);
+            let drv = |name: &str, inputs: &[&str]| {
+                let inputs: Vec<_> = inputs
+                    .iter()
+                    .map(|p| format!(r#"("{p}",["out"])"#))
+                    .collect();
+                format!(
+                    r#"Derive([("out","{store}/{hash}-{name}","","")],[{}],[],"x86_64-linux","/bin/sh",[],[("name","{name}"),("version","{version}")])"#,
+                    inputs.join(",")
+                )
+            };
+            let common = format!("{store}/{hash}-common.drv");
+            std::fs::write(&common, drv("common", &[])).unwrap();
+            for i in 0..6 {
+                let sub = format!("{store}/{hash}-sub{i}.drv");
+                std::fs::write(&sub, drv(&format!("sub{i}"), &[])).unwrap();
+                let lib = format!("{store}/{hash}-lib{i}.drv");
+                if side == 0 || i != 3 {
+                    std::fs::write(&lib, drv(&format!("lib{i}"), &[&common, &sub])).unwrap();
+                }
+                roots[side]
+                    .input_derivations
+                    .insert(lib.into_bytes().into(), [b"out".to_vec()].into());
+            }
+        }
+        let diff = |ctx: DiffContext| {
+            let mut ctx = ctx;
+            let diff = ctx.diff_derivations(b"root1", b"root2", &roots[0], &roots[1]);
+            (diff.unwrap(), ctx.counters())
+        };
+
+        let (sequential, counters) = diff(DiffContext::new().jobs(1));
+        assert_eq!(counters.parsed, 23);
+        let inputs = sequential.inputs.as_ref().unwrap();
+        assert_eq!(inputs.changed.len(), 6);
+        assert_eq!(inputs.changed[3].unavailable, Some(Side::New));
+        let common = |lib: usize| {
+            let lib = inputs.changed[lib].derivation.as_ref().unwrap();
+            let inputs = &lib.inputs.as_ref().unwrap().changed;
+            inputs[0].derivation.as_ref().unwrap().outputs.clone()
+        };
+        assert_ne!(common(0), OutputsDiff::AlreadyCompared);
+        assert_eq!(common(1), OutputsDiff::AlreadyCompared);
+        for jobs in [2, 8] {
+            assert_eq!(
+                diff(DiffContext::new().jobs(jobs)),
+                (sequential.clone(), counters)
+            );
+            // Loading ahead doesn't change which inputs fit in the limit.
+            let limited = |jobs| diff(DiffContext::new().jobs(jobs).max_derivations(10)).0;
+            assert_eq!(limited(jobs), limited(1), "jobs = {jobs}");
+        }
+    }
+
+    #[test]
+    fn deep_dependency_chains_do_not_overflow_the_stack() {
+        // Each derivation the only input of the one before, changed all
+        // the way down. Compared, summarized, rendered or dropped by
+        // recursion, every level took a few KiB of stack, and a debug
+        // build ran out of 8 MiB long before the bottom; all of it gets
+        // 1 MiB here.
+        const DEPTH: usize = 10_000;
+        let path =
+            |side: char, level: usize| format!("/nix/store/{side}{level:031}-pkg{level}.drv");
+        let chain = |side: char, version: &str| {
+            (0..DEPTH)
+                .map(|level| {
+                    let mut drv = Derivation::default();
+                    drv.env
+                        .insert(b"version".as_slice().into(), version.as_bytes().to_vec());
+                    if level + 1 < DEPTH {
+                        drv.input_derivations.insert(
+                            path(side, level + 1).into_bytes().into(),
+                            [b"out".to_vec()].into(),
+                        );
+                    }
+                    (path(side, level).into_bytes(), drv)
+                })
+                .collect::<Vec<_>>()
+        };
+        let (old, new) = (chain('a', "1"), chain('b', "2"));
+        let roots = [old[0].clone(), new[0].clone()];
+        let cache = DrvCache::in_memory(old.into_iter().chain(new));
+
+        /// Counts the changed inputs rendered.
+        struct Bullets(usize);
+        impl std::io::Write for Bullets {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                let bullet = "• ".as_bytes();
+                self.0 += buf.windows(bullet.len()).filter(|w| *w == bullet).count();
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let run = move || {
+            let diff = DiffContext::new()
+                .cache(cache)
+                .diff_derivations(&roots[0].0, &roots[1].0, &roots[0].1, &roots[1].1)
+                .unwrap()
+                .collapse_rebuilds();
+            let summary = crate::summary::DiffSummary::of(&diff);
+            let stats = crate::summary::DiffStats::of(&diff);
+            // Matching repeated bodies holds every level's whole body, a
+            // cost quadratic in the depth rather than a stack, so each
+            // level is written out as it comes instead.
+            let renderer = crate::render::Renderer::new(RenderOptions {
+                color_mode: ColorMode::Never,
+                dedup_diffs: false,
+                ..RenderOptions::default()
+            });
+            let mut bullets = Bullets(0);
+            let differs = renderer
+                .write_diff(&mut bullets, &diff, &roots[0].0, &roots[1].0)
+                .unwrap();
+            drop(diff);
+            (
+                summary.derivations,
+                stats.inputs_changed,
+                differs,
+                bullets.0,
+            )
+        };
+        let (derivations, inputs_changed, differs, bullets) = std::thread::Builder::new()
+            .stack_size(1 << 20)
+            .spawn(run)
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(derivations, DEPTH);
+        assert_eq!(inputs_changed, DEPTH - 1);
+        assert!(differs);
+        assert_eq!(bullets, DEPTH - 1);
+    }
+
+    #[test]
+    fn cancellation_stops_the_traversal() {
+        // The old side's source is a FIFO: the diff blocks reading it until
+        // the hook below, which cancels before handing over its contents.
+        let tmp = tempfile::tempdir().unwrap();
+        let store = tmp.path().to_str().unwrap();
+        let fifo = format!("{store}/{}-script.sh", "a".repeat(32));
+        let path = std::ffi::CString::new(fifo.clone()).unwrap();
+        // SAFETY: `path` is a valid NUL-terminated string.
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+        let script = format!("{store}/{}-script.sh", "b".repeat(32));
+        std::fs::write(&script, "echo new\n").unwrap();
+        let mut roots = [Derivation::default(), Derivation::default()];
+        for (side, (hash, source)) in [('a', &fifo), ('b', &script)].into_iter().enumerate() {
+            let dep = format!("{store}/{}-dep.drv", hash.to_string().repeat(32));
+            std::fs::write(
+                &dep,
+                format!(r#"Derive([("out","{store}/{hash}-dep","","")],[],[],"x86_64-linux","/bin/sh",[],[("name","dep")])"#),
+            )
+            .unwrap();
+            roots[side]
+                .input_derivations
+                .insert(dep.into_bytes().into(), [b"out".to_vec()].into());
+            roots[side].input_sources.insert(source.as_bytes().into());
+        }
+
+        let token = CancellationToken::new();
+        let hook = {
+            let token = token.clone();
+            std::thread::spawn(move || {
+                // Opening blocks until the diff opens the FIFO to read it.
+                let mut writer = std::fs::OpenOptions::new().write(true).open(fifo).unwrap();
+                token.cancel();
+                std::io::Write::write_all(&mut writer, b"echo old\n").unwrap();
+            })
+        };
+        let mut ctx = DiffContext::with_reader(StoreReader::new([tmp.path()]))
+            .cancellation(token)
+            .jobs(1);
+        let err = ctx
+            .diff_derivations(b"root1", b"root2", &roots[0], &roots[1])
+            .unwrap_err();
+        hook.join().unwrap();
+        assert!(err.is::<crate::cancel::Cancelled>(), "{err:#}");
+        // The walk loaded the input before any source was read.
+        assert_eq!(ctx.counters().parsed, 2);
+
+        // A token cancelled up front stops the diff before anything else.
+        let token = CancellationToken::new();
+        token.cancel();
+        let err = DiffContext::new()
+            .cancellation(token)
+            .diff_derivations(b"root1", b"root2", &roots[0], &roots[1])
+            .unwrap_err();
+        assert!(err.is::<crate::cancel::Cancelled>(), "{err:#}");
+    }
+
+    #[test]
+    fn relocated_stores_are_recursed_into_separately() {
+        // Two copied stores, each holding only its own side's dependency
+        // and sources under the logical /nix/store.
+        let tmp = tempfile::tempdir().unwrap();
+        let dirs = ["a", "b"].map(|side| {
+            let dir = tmp.path().join(side);
+            std::fs::create_dir_all(&dir).unwrap();
+            dir
+        });
+        let dep = |hash: &str| format!("/nix/store/{}-dep.drv", hash.repeat(32));
+        for (dir, (hash, version)) in dirs.iter().zip([("a", "1.0"), ("b", "2.0")]) {
+            let src = format!("/nix/store/{}-build.sh", hash.repeat(32));
+            let drv = format!(
+                r#"Derive([("out","/nix/store/{}-dep","","")],[],["{src}"],"x86_64-linux","/bin/sh",[],[("name","dep"),("version","{version}")])"#,
+                "c".repeat(32)
+            );
+            std::fs::write(dir.join(&dep(hash)["/nix/store/".len()..]), drv).unwrap();
+            std::fs::write(
+                dir.join(&src["/nix/store/".len()..]),
+                format!("echo {version}\n"),
+            )
+            .unwrap();
+        }
+        let inputs1: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>> =
+            [(dep("a").into_bytes(), [b"out".to_vec()].into())].into();
+        let inputs2: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>> =
+            [(dep("b").into_bytes(), [b"out".to_vec()].into())].into();
+
+        let reader = |dir| StoreReader::new(["/nix/store"]).relocated("/nix/store", dir);
+        let diff = DiffContext::with_readers(reader(&dirs[0]), reader(&dirs[1]))
+            .diff_inputs(&inputs1, &inputs2)
+            .unwrap()
+            .unwrap();
+        let input = &diff.changed[0];
+        assert_eq!(input.unavailable, None);
+        let derivation = input.derivation.as_ref().unwrap();
+        let sources = derivation.sources.as_ref().unwrap();
+        match &sources.common[0].diff {
+            TextDiff::Text { old, new, .. } => {
+                assert_eq!(old, b"echo 1.0\n");
+                assert_eq!(new, b"echo 2.0\n");
+            }
+            other => panic!("expected text diff, got {other:?}"),
+        }
+
+        // Reading both sides from one of the copies finds only half.
+        let diff = DiffContext::with_reader(reader(&dirs[0]))
+            .diff_inputs(&inputs1, &inputs2)
+            .unwrap()
+            .unwrap();
+        assert_eq!(diff.changed[0].unavailable, Some(Side::New));
+    }
+
+    #[test]
+    fn shared_inputs_are_parsed_once_across_sides_and_passes() {
+        // Nine of the ten inputs are the same on both sides; the tenth
+        // changed. Everything depends on `base`.
+        let tmp = tempfile::tempdir().unwrap();
+        let store = tmp.path().join("store");
+        std::fs::create_dir_all(&store).unwrap();
+        let store = store.to_str().unwrap();
+        let write = |hash: char, name: &str, inputs: &[&str]| {
+            let path = format!("{store}/{}-{name}.drv", hash.to_string().repeat(32));
+            let inputs: Vec<_> = inputs
+                .iter()
+                .map(|p| format!(r#"("{p}",["out"])"#))
+                .collect();
+            std::fs::write(
+                &path,
+                format!(
+                    r#"Derive([("out","{store}/{}-{name}","","")],[{}],[],"x86_64-linux","/bin/sh",[],[("name","{name}")])"#,
+                    hash.to_string().repeat(32),
+                    inputs.join(",")
+                ),
+            )
+            .unwrap();
+            path
+        };
+        let base = write('0', "base", &[]);
+        let shared: Vec<_> = (1..=9)
+            .map(|i| {
+                write(
+                    char::from_digit(i, 10).unwrap(),
+                    &format!("lib{i}"),
+                    &[&base],
+                )
+            })
+            .collect();
+        let app1 = write('a', "app", &[&base, &shared[0]]);
+        let app2 = write('b', "app", &[&base, &shared[0], &shared[1]]);
+        let root = |app: &str, disallowed: &str| {
+            let mut drv = Derivation::default();
+            for path in shared.iter().map(String::as_str).chain([app]) {
+                drv.input_derivations
+                    .insert(path.as_bytes().to_vec().into(), [b"out".to_vec()].into());
+            }
+            drv.env.insert(
+                b"disallowedReferences".as_slice().into(),
+                disallowed.as_bytes().to_vec(),
+            );
+            drv
+        };
+        let (root1, root2) = (root(&app1, "a"), root(&app2, "b"));
+
+        let cache = DrvCache::new();
+        let sizes =
+            [&root1, &root2].map(|root| crate::closure::closure_size_with(root, |p| cache.load(p)));
+        assert_eq!(sizes, [12, 12]);
+        for _pass in 0..2 {
+            let mut ctx = DiffContext::new().cache(cache.clone());
+            let diff = ctx
+                .diff_derivations(b"root1", b"root2", &root1, &root2)
+                .unwrap();
+            assert_eq!(diff.inputs.unwrap().changed.len(), 1);
+            // The nine shared roots' inputs, plus base and lib1 under app.
+            assert_eq!(ctx.counters().shared, 11);
+        }
+        // base, lib1-9 and both apps.
+        assert_eq!(cache.stats().parsed, 12);
+    }
+
+    #[test]
+    fn diff_name_separates_pname_and_version() {
+        let env = |pairs: &[(&str, &str)]| -> BTreeMap<ByteStr, Vec<u8>> {
+            pairs
+                .iter()
+                .map(|(k, v)| (ByteStr::from(k.as_bytes()), v.as_bytes().to_vec()))
+                .collect()
+        };
+
+        let bump = diff_name(
+            &env(&[("name", "hello-2.12")]),
+            &env(&[("name", "hello-2.12.1")]),
+        )
+        .unwrap();
+        assert_eq!(bump.pname, None);
+        assert_eq!(
+            bump.version,
+            Some(VersionDiff {
+                old: b"2.12".to_vec(),
+                new: b"2.12.1".to_vec(),
+                downgrade: false,
+            })
+        );
+        assert!(bump.covers_name);
+
+        let down = diff_name(&env(&[("name", "foo-1.10")]), &env(&[("name", "foo-1.9")])).unwrap();
+        assert!(down.version.unwrap().downgrade);
+
+        // pname/version env vars win over parsing `name`.
+        let renamed = diff_name(
+            &env(&[("name", "x"), ("pname", "hello"), ("version", "1.0")]),
+            &env(&[
+                ("name", "y"),
+                ("pname", "hello-wayland"),
+                ("version", "1.0"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(renamed.version, None);
+        assert_eq!(renamed.pname.unwrap().new, b"hello-wayland");
+        assert!(!renamed.covers_name);
+
+        assert_eq!(
+            diff_name(
+                &env(&[("name", "hello-1.0")]),
+                &env(&[("name", "hello-1.0")])
+            ),
+            None
+        );
+        assert_eq!(diff_name(&env(&[]), &env(&[("name", "hello-1.0")])), None);
+    }
+
+    /// A registration-info blob (path, hash, size, deriver, references)
+    /// for store paths `range`.
+    fn registration(range: std::ops::Range<usize>) -> Vec<u8> {
+        let mut blob = String::new();
+        for i in range {
+            let path = format!("/nix/store/{i:032}-pkg{i}");
+            blob.push_str(&format!("{path}\nsha256:{i:052}\n1024\n\n1\n{path}\n"));
+        }
+        blob.into_bytes()
+    }
+
+    #[test]
+    fn closure_info_env_vars_diff_as_path_sets() {
+        let mut drv1 = Derivation::default();
+        let mut drv2 = Derivation::default();
+        drv1.env
+            .insert(b"closureInfo".as_slice().into(), registration(0..40));
+        drv2.env
+            .insert(b"closureInfo".as_slice().into(), registration(3..42));
+        // Few paths, but reference graph vars are always path sets.
+        drv1.env.insert(
+            b"exportReferencesGraph".as_slice().into(),
+            format!("closure /nix/store/{:032}-a", 1).into_bytes(),
+        );
+        drv2.env.insert(
+            b"exportReferencesGraph".as_slice().into(),
+            format!("closure /nix/store/{:032}-b", 1).into_bytes(),
+        );
+        // A handful of paths in ordinary text stays a text diff.
+        drv1.env
+            .insert(b"script".as_slice().into(), registration(0..2));
+        drv2.env
+            .insert(b"script".as_slice().into(), registration(0..3));
+
+        let diff = ctx().diff_derivations(b"a", b"b", &drv1, &drv2).unwrap();
+        let env = diff.env.unwrap();
+        let Some(EnvVarDiff::ClosureInfo(info)) = &env[&b"closureInfo"[..]] else {
+            panic!("expected closure info diff: {env:?}");
+        };
+        assert_eq!(info.removed.len(), 3);
+        assert_eq!(info.added.len(), 2);
+        assert!(info.added.is_sorted() && info.removed.is_sorted());
+        assert_eq!(
+            info.removed[0],
+            format!("/nix/store/{:032}-pkg0", 0).into_bytes()
+        );
+        let Some(EnvVarDiff::ClosureInfo(graph)) = &env[&b"exportReferencesGraph"[..]] else {
+            panic!("expected closure info diff: {env:?}");
+        };
+        assert_eq!((graph.added.len(), graph.removed.len()), (1, 1));
+        assert!(matches!(env[&b"script"[..]], Some(EnvVarDiff::Changed(_))));
+    }
+
+    #[test]
+    fn store_path_lists_diff_path_by_path() {
+        let list = |paths: &[(u32, &str)]| {
+            paths
+                .iter()
+                .map(|(hash, name)| format!("/nix/store/{hash:032}-{name}"))
+                .collect::<Vec<_>>()
+                .join(" ")
+                .into_bytes()
+        };
+        let mut drv1 = Derivation::default();
+        let mut drv2 = Derivation::default();
+        let inputs1 = list(&[(1, "zlib-1.3"), (2, "libressl-3.8"), (3, "bash-5.2")]);
+        let inputs2 = list(&[(1, "zlib-1.3"), (4, "openssl-3.0"), (5, "bash-5.2")]);
+        for (drv, inputs) in [(&mut drv1, inputs1), (&mut drv2, inputs2)] {
+            drv.env
+                .insert(b"buildInputs".as_slice().into(), inputs.clone());
+            drv.env.insert(b"rawInputs".as_slice().into(), inputs);
+        }
+        // Only hashes changed.
+        drv1.env.insert(
+            b"nativeBuildInputs".as_slice().into(),
+            list(&[(1, "cmake"), (2, "ninja")]),
+        );
+        drv2.env.insert(
+            b"nativeBuildInputs".as_slice().into(),
+            list(&[(3, "cmake"), (2, "ninja")]),
+        );
+        // A single path reads fine as a string.
+        drv1.env
+            .insert(b"src".as_slice().into(), list(&[(1, "hello-1.0.tar.gz")]));
+        drv2.env
+            .insert(b"src".as_slice().into(), list(&[(2, "hello-1.1.tar.gz")]));
+
+        let diff = ctx().diff_derivations(b"a", b"b", &drv1, &drv2).unwrap();
+        let env = diff.env.unwrap();
+        let Some(EnvVarDiff::StorePaths(inputs)) = &env[&b"buildInputs"[..]] else {
+            panic!("expected a store path list diff: {env:?}");
+        };
+        assert_eq!(inputs.removed, [list(&[(2, "libressl-3.8")])]);
+        assert_eq!(inputs.added, [list(&[(4, "openssl-3.0")])]);
+        assert_eq!(
+            inputs.rebuilt,
+            [StringDiff {
+                old: list(&[(3, "bash-5.2")]),
+                new: list(&[(5, "bash-5.2")]),
+            }]
+        );
+        let native = env[&b"nativeBuildInputs"[..]].as_ref().unwrap();
+        assert!(matches!(native, EnvVarDiff::StorePaths(_)));
+        assert!(native.store_paths_only());
+        assert!(matches!(env[&b"src"[..]], Some(EnvVarDiff::Changed(_))));
+
+        let diff = ctx()
+            .raw_path_lists(true)
+            .diff_derivations(b"a", b"b", &drv1, &drv2)
+            .unwrap();
+        let env = diff.env.unwrap();
+        assert!(matches!(
+            env[&b"buildInputs"[..]],
+            Some(EnvVarDiff::Changed(_))
+        ));
+    }
+
+    #[test]
+    fn env_vars_moved_to_a_new_name_are_renames() {
+        let flags = b"--with-openssl=/nix/store/00000000000000000000000000000000-openssl";
+        let mut drv1 = Derivation::default();
+        let mut drv2 = Derivation::default();
+        drv1.env
+            .insert(b"configureFlags".as_slice().into(), flags.to_vec());
+        drv2.env
+            .insert(b"configureFlagsArray".as_slice().into(), flags.to_vec());
+        // Same value but for a store path hash.
+        drv1.env.insert(
+            b"NIX_LDFLAGS".as_slice().into(),
+            format!("-L/nix/store/{:032}-zlib/lib", 1).into_bytes(),
+        );
+        drv2.env.insert(
+            b"LDFLAGS".as_slice().into(),
+            format!("-L/nix/store/{:032}-zlib/lib", 2).into_bytes(),
+        );
+        // Nearly the same value is a genuine change.
+        drv1.env
+            .insert(b"oldPhase".as_slice().into(), b"make install".to_vec());
+        drv2.env.insert(
+            b"newPhase".as_slice().into(),
+            b"make install-strip".to_vec(),
+        );
+        // Ambiguous: two removed vars held the value.
+        for key in ["a", "b"] {
+            drv1.env
+                .insert(key.as_bytes().into(), b"shared value".to_vec());
+        }
+        drv2.env
+            .insert(b"c".as_slice().into(), b"shared value".to_vec());
+        // Too short to tell from coincidence.
+        drv1.env.insert(b"doCheck".as_slice().into(), b"1".to_vec());
+        drv2.env
+            .insert(b"dontStrip".as_slice().into(), b"1".to_vec());
+
+        let diff = ctx().diff_derivations(b"a", b"b", &drv1, &drv2).unwrap();
+        let env = diff.env.unwrap();
+        assert_eq!(
+            env[&b"configureFlagsArray"[..]],
+            Some(EnvVarDiff::Renamed(RenamedVar {
+                from: b"configureFlags".to_vec(),
+                old: flags.to_vec(),
+                new: flags.to_vec(),
+            }))
+        );
+        assert!(!env.contains_key(&b"configureFlags"[..]));
+        let Some(EnvVarDiff::Renamed(ldflags)) = &env[&b"LDFLAGS"[..]] else {
+            panic!("expected a rename: {env:?}");
+        };
+        assert_eq!(ldflags.from, b"NIX_LDFLAGS");
+        assert_ne!(ldflags.old, ldflags.new);
+        for (key, added) in [
+            ("oldPhase", false),
+            ("newPhase", true),
+            ("a", false),
+            ("b", false),
+            ("c", true),
+            ("doCheck", false),
+            ("dontStrip", true),
+        ] {
+            let change = env[key.as_bytes()].as_ref().unwrap();
+            if added {
+                assert!(matches!(change, EnvVarDiff::Added(_)), "{key}: {change:?}");
+            } else {
+                assert!(
+                    matches!(change, EnvVarDiff::Removed(_)),
+                    "{key}: {change:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ignored_env_vars_are_counted_not_shown() {
+        let mut drv1 = Derivation::default();
+        let mut drv2 = Derivation::default();
+        for (key, old, new) in [
+            ("NIX_BUILD_CORES", "4", "8"),
+            ("NIX_LDFLAGS", "-lz", "-lz"),
+            ("SOURCE_DATE_EPOCH", "1", "2"),
+            ("version", "1.0", "1.1"),
+        ] {
+            drv1.env.insert(key.as_bytes().into(), old.into());
+            drv2.env.insert(key.as_bytes().into(), new.into());
+        }
+        drv2.env
+            .insert(b"NIX_ENFORCE_PURITY".as_slice().into(), b"1".to_vec());
+        drv2.env
+            .insert(b"__impure".as_slice().into(), b"1".to_vec());
+
+        let mut filter = EnvFilter::default();
+        filter.add_pattern("^NIX_").unwrap();
+        filter.add_pattern("EPOCH").unwrap();
+        let diff = ctx()
+            .ignore_env(filter)
+            .diff_derivations(b"a", b"b", &drv1, &drv2)
+            .unwrap();
+        let keys: Vec<_> = diff.env.unwrap().into_keys().collect();
+        assert_eq!(keys, [b"version"]);
+        // NIX_LDFLAGS is equal, so it is not counted.
+        assert_eq!(diff.ignored_env, 3);
+        // Sandbox flags are not env vars here.
+        assert_eq!(diff.sandbox.unwrap().len(), 1);
+
+        let filter = EnvFilter {
+            skip_all: true,
+            ..Default::default()
+        };
+        let diff = ctx()
+            .ignore_env(filter)
+            .diff_derivations(b"a", b"b", &drv1, &drv2)
+            .unwrap();
+        assert_eq!((diff.env, diff.ignored_env), (None, 4));
+    }
+
+    #[test]
+    fn closure_info_source_files_diff_as_path_sets() {
+        let tmp = tempfile::tempdir().unwrap();
+        let p1 = tmp.path().join("aaaa-registration");
+        let p2 = tmp.path().join("bbbb-registration");
+        std::fs::write(&p1, registration(0..50)).unwrap();
+        std::fs::write(&p2, registration(0..51)).unwrap();
+        let s1 = path_set([p1.to_string_lossy().as_bytes()]);
+        let s2 = path_set([p2.to_string_lossy().as_bytes()]);
+
+        let diff = DiffContext::with_reader(StoreReader::new([tmp.path()]))
+            .diff_sources(&s1, &s2, 1)
+            .unwrap()
+            .unwrap();
+        match &diff.common[0].diff {
+            TextDiff::ClosureInfo(info) => {
+                assert_eq!(
+                    info.added,
+                    [format!("/nix/store/{:032}-pkg50", 50).into_bytes()]
+                );
+                assert!(info.removed.is_empty());
+            }
+            other => panic!("expected closure info diff, got {other:?}"),
+        }
+    }
+
     #[test]
     fn diff_arguments_preserves_positional_index() {
         // Only argument at index 1 differs. The diff must record index 1,
@@ -543,9 +3153,202 @@ mod tests {
         let args2 = vec![b"a".to_vec(), b"X".to_vec(), b"c".to_vec()];
 
         let diffs = ctx().diff_arguments(&args1, &args2).unwrap();
+        assert_eq!(
+            diffs,
+            [ArgumentDiff::Changed {
+                old_index: 1,
+                new_index: 1,
+                diff: StringDiff {
+                    old: b"b".to_vec(),
+                    new: b"X".to_vec(),
+                },
+                store_paths_only: false,
+            }]
+        );
+
+        // An appended argument has no old position.
+        let args3 = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec()];
+        let diffs = ctx().diff_arguments(&args1, &args3).unwrap();
+        assert_eq!(
+            diffs,
+            [ArgumentDiff::Added {
+                index: 3,
+                value: b"d".to_vec(),
+            }]
+        );
+    }
+
+    fn args(items: &[&str]) -> Vec<Vec<u8>> {
+        items.iter().map(|s| s.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn diff_arguments_aligns_around_insertions_and_removals() {
+        let builder = "/nix/store/0c9z6vw9aqk8sw6j6d1x2lxnh2wrrxj0-builder.sh";
+        let rebuilt = "/nix/store/1a6kqbnvc04rhs8zmbmmhz3x4zjvnmcc-builder.sh";
+        // A flag inserted at the front doesn't shift the rest, even when
+        // they changed store paths.
+        let diffs = ctx()
+            .diff_arguments(
+                &args(&[builder, "--fast"]),
+                &args(&["-e", rebuilt, "--fast"]),
+            )
+            .unwrap();
+        assert_eq!(
+            diffs,
+            [
+                ArgumentDiff::Added {
+                    index: 0,
+                    value: b"-e".to_vec(),
+                },
+                ArgumentDiff::Changed {
+                    old_index: 0,
+                    new_index: 1,
+                    diff: StringDiff {
+                        old: builder.as_bytes().to_vec(),
+                        new: rebuilt.as_bytes().to_vec(),
+                    },
+                    store_paths_only: true,
+                },
+            ]
+        );
+
+        // Removed from the middle.
+        let diffs = ctx()
+            .diff_arguments(
+                &args(&["-e", "-x", "build.sh", "--fast"]),
+                &args(&["-e", "build.sh", "--fast"]),
+            )
+            .unwrap();
+        assert_eq!(
+            diffs,
+            [ArgumentDiff::Removed {
+                index: 1,
+                value: b"-x".to_vec(),
+            }]
+        );
+
+        // A replaced run is paired in order, the rest added.
+        let diffs = ctx()
+            .diff_arguments(&args(&["-e", "-O2", "x"]), &args(&["-e", "-O3", "-g", "x"]))
+            .unwrap();
+        assert_eq!(diffs.len(), 2, "{diffs:?}");
+        assert_eq!(diffs[0].indices(), (Some(1), Some(1)));
+        assert_eq!(diffs[1].indices(), (None, Some(2)));
+    }
+
+    #[test]
+    fn diff_arguments_keeps_a_modified_script_in_place() {
+        let old = "set -e\nmake\nmake install\n";
+        let new = "set -eu\nmake -j4\nmake install\n";
+        let diffs = ctx()
+            .diff_arguments(&args(&["-c", old]), &args(&["-x", "-c", new]))
+            .unwrap();
+        assert_eq!(diffs.len(), 2, "{diffs:?}");
+        assert_eq!(
+            diffs[0],
+            ArgumentDiff::Added {
+                index: 0,
+                value: b"-x".to_vec(),
+            }
+        );
+        match &diffs[1] {
+            ArgumentDiff::Changed {
+                old_index: 1,
+                new_index: 2,
+                diff,
+                store_paths_only: false,
+            } => assert_eq!(
+                (&diff.old[..], &diff.new[..]),
+                (old.as_bytes(), new.as_bytes())
+            ),
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn normalize_store_paths_masks_hashes_only() {
+        let a = b"ln -s /nix/store/0c9z6vw9aqk8sw6j6d1x2lxnh2wrrxj0-dep/bin $out";
+        let b = b"ln -s /nix/store/1a6kqbnvc04rhs8zmbmmhz3x4zjvnmcc-dep/bin $out";
+        assert_eq!(normalize_store_paths(a), normalize_store_paths(b));
+        assert_eq!(
+            &*normalize_store_paths(a),
+            b"ln -s /nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-dep/bin $out"
+        );
+        // Not preceded by a slash, or not base32: left alone.
+        let plain = b"x0c9z6vw9aqk8sw6j6d1x2lxnh2wrrxj0-dep /eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-x";
+        assert!(matches!(normalize_store_paths(plain), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn diff_arguments_flags_store_path_only_changes() {
+        let script = |hash: &str, msg: &str| {
+            format!("mkdir $out\nln -s /nix/store/{hash}-dep/bin $out/bin\necho {msg}\n")
+                .into_bytes()
+        };
+        let hash1 = "0c9z6vw9aqk8sw6j6d1x2lxnh2wrrxj0";
+        let hash2 = "1a6kqbnvc04rhs8zmbmmhz3x4zjvnmcc";
+        let args1 = vec![b"-c".to_vec(), script(hash1, "hi")];
+
+        let diffs = ctx()
+            .diff_arguments(&args1, &[b"-c".to_vec(), script(hash2, "hi")])
+            .unwrap();
         assert_eq!(diffs.len(), 1);
-        assert_eq!(diffs[0].index, 1);
-        assert_eq!(diffs[0].diff.old, b"b");
-        assert_eq!(diffs[0].diff.new, b"X");
+        assert!(diffs[0].store_paths_only());
+
+        let diffs = ctx()
+            .diff_arguments(&args1, &[b"-c".to_vec(), script(hash2, "bye")])
+            .unwrap();
+        assert!(!diffs[0].store_paths_only());
+    }
+
+    #[test]
+    fn large_env_maps_and_multibyte_values() {
+        // Past 64 KiB, with a multi-byte character across the boundary.
+        let big = |tail: &str| {
+            let mut value = "a".repeat(65535);
+            value.push('€');
+            value.push_str(&"ü".repeat(40_000));
+            value.push_str(tail);
+            value.into_bytes()
+        };
+        let env = |n: usize, edit: &str| -> BTreeMap<ByteStr, Vec<u8>> {
+            let mut env: BTreeMap<_, _> = (0..n)
+                .map(|i| {
+                    let value = if i % 1000 == 0 {
+                        format!("{i}{edit}")
+                    } else {
+                        format!("{i}")
+                    };
+                    (
+                        ByteStr::from(format!("VAR_{i:05}").into_bytes()),
+                        value.into_bytes(),
+                    )
+                })
+                .collect();
+            env.insert(b"big".as_slice().into(), big(edit));
+            env
+        };
+        let drv1 = Derivation {
+            env: env(50_000, ""),
+            ..Default::default()
+        };
+        let mut drv2 = Derivation {
+            env: env(50_000, "-é"),
+            ..Default::default()
+        };
+        drv2.env
+            .insert(b"VAR_99999".as_slice().into(), "ü".repeat(100).into_bytes());
+        let diff = ctx().diff_derivations(b"a", b"b", &drv1, &drv2).unwrap();
+        let env = diff.env.unwrap();
+        assert_eq!(env.len(), 50 + 1 + 1);
+        match &env[&b"big"[..]] {
+            Some(EnvVarDiff::Changed(change)) => {
+                assert!(change.new.ends_with("-é".as_bytes()));
+                assert!(change.old.len() > 65536);
+            }
+            other => panic!("{other:?}"),
+        }
+        assert!(matches!(env[&b"VAR_99999"[..]], Some(EnvVarDiff::Added(_))));
     }
 }
@@ -0,0 +1,188 @@
+//! Hashes compared by their digest rather than how they are written. Nix
+//! accepts a hash as base16, nix-base32 or base64, bare or prefixed with
+//! the algorithm (`sha256:…`), or in SRI form (`sha256-…=`), so moving a
+//! fetcher from `sha256 = "0abc…"` to `hash = "sha256-…"` changes the
+//! text but not what the output must hash to.
+
+use crate::types::Output;
+use crate::types::byte_strings::decode_base64;
+use harmonia_utils_base_encoding::base32;
+
+/// The hash algorithms fixed outputs use, with their digest sizes in
+/// bytes; no two have the same size.
+const ALGORITHMS: &[(&str, usize)] = &[("md5", 16), ("sha1", 20), ("sha256", 32), ("sha512", 64)];
+
+/// A decoded hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digest {
+    pub algorithm: &'static str,
+    pub bytes: Vec<u8>,
+}
+
+impl Digest {
+    /// Decode `text`, a hash in any of the forms Nix accepts. `algorithm`
+    /// is what the hash is said to be elsewhere, like `outputHashAlgo` or
+    /// the `r:sha256` of an output; it is needed for bare hashes, unless
+    /// their length tells.
+    pub fn parse(text: &[u8], algorithm: Option<&[u8]>) -> Option<Self> {
+        let text = std::str::from_utf8(text).ok()?;
+        // `r:sha256` and `text:sha256` say how the output is hashed, too.
+        let hint = match algorithm.and_then(|algorithm| algorithm.rsplit(|&b| b == b':').next()) {
+            None | Some(b"") => None,
+            Some(name) => Some(known(std::str::from_utf8(name).ok()?)?.0),
+        };
+        let agrees = |algorithm: &&(&'static str, usize)| hint.is_none_or(|h| h == algorithm.0);
+        if let Some((name, base64)) = text.split_once('-') {
+            let algorithm = known(name).filter(agrees)?;
+            let bytes = decode_base64(base64).filter(|b| b.len() == algorithm.1)?;
+            return Some(Self::new(algorithm, bytes));
+        }
+        if let Some((name, encoded)) = text.split_once(':') {
+            let algorithm = known(name).filter(agrees)?;
+            return Some(Self::new(algorithm, decode(encoded, algorithm.1)?));
+        }
+        // A bare hash: try every algorithm it may be, and only take an
+        // answer that is the only one.
+        let mut found = ALGORITHMS
+            .iter()
+            .filter(|algorithm| agrees(algorithm))
+            .filter_map(|algorithm| Some(Self::new(algorithm, decode(text, algorithm.1)?)));
+        let digest = found.next()?;
+        found.next().is_none().then_some(digest)
+    }
+
+    fn new(algorithm: &(&'static str, usize), bytes: Vec<u8>) -> Self {
+        Self {
+            algorithm: algorithm.0,
+            bytes,
+        }
+    }
+}
+
+fn known(name: &str) -> Option<&'static (&'static str, usize)> {
+    ALGORITHMS.iter().find(|(known, _)| *known == name)
+}
+
+/// The `size` bytes `text` encodes, with the encoding told by its length.
+fn decode(text: &str, size: usize) -> Option<Vec<u8>> {
+    let bytes = if text.len() == size * 2 {
+        from_hex(text)?
+    } else if text.len() == (size * 8).div_ceil(5) {
+        let mut bytes = vec![0; base32::decode_len(text.len())];
+        base32::decode_mut(text.as_bytes(), &mut bytes).ok()?;
+        bytes
+    } else if text.len() == size.div_ceil(3) * 4 {
+        decode_base64(text)?
+    } else {
+        return None;
+    };
+    (bytes.len() == size).then_some(bytes)
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Whether two hashes, each with the algorithm it is said to be, have the
+/// same digest; equal text always does.
+pub fn same_digest(
+    old: &[u8],
+    old_algorithm: Option<&[u8]>,
+    new: &[u8],
+    new_algorithm: Option<&[u8]>,
+) -> bool {
+    old == new
+        || Digest::parse(old, old_algorithm)
+            .is_some_and(|old| Digest::parse(new, new_algorithm) == Some(old))
+}
+
+/// Whether the fixed outputs `old` and `new` must hash to the same digest.
+pub fn same_output_hash(old: &Output, new: &Output) -> bool {
+    match (&old.hash, &new.hash) {
+        (Some(old_hash), Some(new_hash)) => same_digest(
+            old_hash,
+            old.hash_algorithm.as_deref(),
+            new_hash,
+            new.hash_algorithm.as_deref(),
+        ),
+        (old_hash, new_hash) => old_hash == new_hash,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `nix hash convert` of the sha256 of "hello\n", in each encoding.
+    const HEX: &str = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+    const BASE32: &str = "00xyyr3fi8l6hb839bv3f7yb86yjv7xi1cgh1xnhipym4asvb4aq";
+    const SRI: &str = "sha256-WJG1tSLV3whtD/CxEPvZ0hu0/HFjrzTQgoai6Eb2vgM=";
+
+    #[test]
+    fn encodings_of_one_digest_are_the_same() {
+        let digest = Digest::parse(HEX.as_bytes(), Some(b"sha256")).unwrap();
+        assert_eq!(digest.algorithm, "sha256");
+        assert_eq!(digest.bytes, from_hex(HEX).unwrap());
+        for text in [
+            BASE32.to_string(),
+            SRI.to_string(),
+            format!("sha256:{BASE32}"),
+            format!("sha256:{HEX}"),
+            SRI["sha256-".len()..].to_string(),
+        ] {
+            for algorithm in [
+                Some(b"sha256".as_slice()),
+                Some(b"r:sha256"),
+                Some(b""),
+                None,
+            ] {
+                assert_eq!(
+                    Digest::parse(text.as_bytes(), algorithm).as_ref(),
+                    Some(&digest),
+                    "{text} {algorithm:?}"
+                );
+            }
+        }
+        assert!(same_digest(
+            BASE32.as_bytes(),
+            Some(b"sha256"),
+            SRI.as_bytes(),
+            Some(b"")
+        ));
+    }
+
+    #[test]
+    fn different_digests_differ() {
+        let other = "0000000000000000000000000000000000000000000000000000";
+        assert!(!same_digest(
+            BASE32.as_bytes(),
+            Some(b"sha256"),
+            other.as_bytes(),
+            Some(b"sha256")
+        ));
+        // The same bytes as another algorithm's digest are not the same hash.
+        let sha512 = format!("sha512:{HEX}{HEX}");
+        assert_eq!(Digest::parse(sha512.as_bytes(), Some(b"sha256")), None);
+        assert_eq!(Digest::parse(SRI.as_bytes(), Some(b"sha1")), None);
+        // Hashes that do not decode are only equal to themselves.
+        assert!(same_digest(b"impure", None, b"impure", None));
+        assert!(!same_digest(b"zzz", None, b"yyy", None));
+        assert_eq!(Digest::parse(b"sha256-not base64", None), None);
+        assert_eq!(Digest::parse(&HEX.as_bytes()[1..], Some(b"sha256")), None);
+        // 32 characters are an md5 in base16 or a sha1 in nix-base32.
+        let ambiguous = "0123456789abcdf0123456789abcdf01";
+        assert_eq!(Digest::parse(ambiguous.as_bytes(), None), None);
+        assert_eq!(
+            Digest::parse(ambiguous.as_bytes(), Some(b"md5"))
+                .unwrap()
+                .algorithm,
+            "md5"
+        );
+    }
+}
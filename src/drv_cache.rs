@@ -0,0 +1,247 @@
+//! Parsed .drv files by store path, shared across a whole run.
+//!
+//! Most input derivations are identical on both sides of a comparison, and
+//! the closure counts, the requisite checks and the diff itself all walk
+//! them. Going through one cache means each .drv file is parsed once no
+//! matter which side or pass gets to it first, and the store paths and env
+//! keys of all of them are stored once.
+
+use crate::intern::Interner;
+use crate::types::Derivation;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A handle to the cache; clones share the same entries.
+#[derive(Clone, Default)]
+pub struct DrvCache {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// By file path, which is the store path unless the store was
+    /// relocated. `None` for files that could not be read or parsed, so
+    /// they aren't retried.
+    entries: HashMap<Vec<u8>, Option<Arc<Derivation>>>,
+    /// The input paths and env keys of the parsed derivations.
+    strings: Interner,
+    /// Treat paths not in `entries` as missing instead of reading them.
+    in_memory: bool,
+    stats: CacheStats,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// .drv files parsed.
+    pub parsed: usize,
+    /// Parses avoided because two paths had byte-identical contents.
+    pub identical_files: usize,
+}
+
+impl DrvCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A cache holding exactly `derivations` that never reads the file
+    /// system, for closures loaded from elsewhere (e.g. JSON dumps). Any
+    /// other path is unavailable.
+    pub fn in_memory(derivations: impl IntoIterator<Item = (Vec<u8>, Derivation)>) -> Self {
+        let mut strings = Interner::new();
+        let entries = derivations
+            .into_iter()
+            .map(|(path, mut drv)| {
+                strings.share_derivation(&mut drv);
+                (path, Some(Arc::new(drv)))
+            })
+            .collect();
+        let inner = Inner {
+            entries,
+            strings,
+            in_memory: true,
+            stats: CacheStats::default(),
+        };
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.lock().stats
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // Entries are inserted whole, so a panic elsewhere can't leave
+        // one half-written.
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The derivation at `path`, parsed on first use. `None` if it can't
+    /// be read or parsed.
+    pub fn load(&self, path: &[u8]) -> Option<Arc<Derivation>> {
+        self.load_at(path, path)
+    }
+
+    /// The derivation at store path `path`, read from `file` (see
+    /// [`StoreReader::locate`](crate::store_reader::StoreReader::locate)).
+    pub fn load_at(&self, path: &[u8], file: &[u8]) -> Option<Arc<Derivation>> {
+        {
+            let inner = self.lock();
+            if let Some(drv) = inner.entries.get(file) {
+                return drv.clone();
+            }
+            if inner.in_memory {
+                return None;
+            }
+        }
+        let drv = read(file).and_then(|contents| self.parse(path, &contents));
+        self.insert(file, drv.clone());
+        drv
+    }
+
+    /// Both sides of a changed input. Uncached files with byte-identical
+    /// contents (as when comparing two stores) are parsed only once.
+    pub fn load_pair(&self, path1: &[u8], path2: &[u8]) -> [Option<Arc<Derivation>>; 2] {
+        self.load_pair_at([path1, path2], [path1, path2])
+    }
+
+    /// Like [`load_pair`](Self::load_pair), reading each side's store path
+    /// from the matching file.
+    pub fn load_pair_at(
+        &self,
+        [path1, path2]: [&[u8]; 2],
+        [file1, file2]: [&[u8]; 2],
+    ) -> [Option<Arc<Derivation>>; 2] {
+        let uncached = {
+            let inner = self.lock();
+            !inner.in_memory
+                && !inner.entries.contains_key(file1)
+                && !inner.entries.contains_key(file2)
+        };
+        if !uncached {
+            return [self.load_at(path1, file1), self.load_at(path2, file2)];
+        }
+        let (raw1, raw2) = (read(file1), read(file2));
+        match (&raw1, &raw2) {
+            (Some(c1), Some(c2)) if c1 == c2 => {
+                let drv = self.parse(path1, c1);
+                self.lock().stats.identical_files += 1;
+                self.insert(file1, drv.clone());
+                self.insert(file2, drv.clone());
+                [drv.clone(), drv]
+            }
+            _ => {
+                let drv1 = raw1.and_then(|c| self.parse(path1, &c));
+                let drv2 = raw2.and_then(|c| self.parse(path2, &c));
+                self.insert(file1, drv1.clone());
+                self.insert(file2, drv2.clone());
+                [drv1, drv2]
+            }
+        }
+    }
+
+    fn parse(&self, path: &[u8], contents: &[u8]) -> Option<Arc<Derivation>> {
+        self.lock().stats.parsed += 1;
+        let path = std::str::from_utf8(path).ok()?;
+        let mut drv = crate::parser::parse_derivation_bytes(path, contents).ok()?;
+        self.lock().strings.share_derivation(&mut drv);
+        Some(Arc::new(drv))
+    }
+
+    fn insert(&self, path: &[u8], drv: Option<Arc<Derivation>>) {
+        self.lock().entries.insert(path.to_vec(), drv);
+    }
+}
+
+fn read(path: &[u8]) -> Option<Vec<u8>> {
+    std::fs::read(std::str::from_utf8(path).ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_each_path_once() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = tmp.path().to_str().unwrap();
+        let drv = format!(
+            r#"Derive([("out","{store}/cccccccccccccccccccccccccccccccc-foo","","")],[],[],"x86_64-linux","/bin/sh",[],[("name","foo")])"#
+        );
+        let path1 = format!("{store}/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-foo.drv");
+        let path2 = format!("{store}/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-foo.drv");
+        let missing = format!("{store}/dddddddddddddddddddddddddddddddd-gone.drv");
+        std::fs::write(&path1, &drv).unwrap();
+        std::fs::write(&path2, &drv).unwrap();
+
+        let cache = DrvCache::new();
+        let [drv1, drv2] = cache.load_pair(path1.as_bytes(), path2.as_bytes());
+        assert!(Arc::ptr_eq(drv1.as_ref().unwrap(), drv2.as_ref().unwrap()));
+        assert!(Arc::ptr_eq(
+            &cache.clone().load(path1.as_bytes()).unwrap(),
+            drv1.as_ref().unwrap()
+        ));
+        assert!(cache.load(missing.as_bytes()).is_none());
+        assert!(cache.load(missing.as_bytes()).is_none());
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                parsed: 1,
+                identical_files: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn in_memory_cache_never_reads_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let on_disk = tmp.path().join("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-foo.drv");
+        std::fs::write(
+            &on_disk,
+            r#"Derive([("out","/nix/store/cccccccccccccccccccccccccccccccc-foo","","")],[],[],"x86_64-linux","/bin/sh",[],[])"#,
+        )
+        .unwrap();
+        let on_disk = on_disk.to_str().unwrap().as_bytes();
+
+        let cache =
+            DrvCache::in_memory([(b"/nix/store/x-bar.drv".to_vec(), Derivation::default())]);
+        assert!(cache.load(b"/nix/store/x-bar.drv").is_some());
+        assert!(cache.load(on_disk).is_none());
+        let [known, unknown] = cache.load_pair(b"/nix/store/x-bar.drv", on_disk);
+        assert!(known.is_some() && unknown.is_none());
+        assert_eq!(cache.stats(), CacheStats::default());
+    }
+
+    #[test]
+    fn parsed_derivations_share_paths_and_env_keys() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = tmp.path().to_str().unwrap();
+        let write = |hash: char, version: &str| {
+            let path = format!("{store}/{}-foo.drv", hash.to_string().repeat(32));
+            std::fs::write(
+                &path,
+                format!(
+                    r#"Derive([("out","{store}/cccccccccccccccccccccccccccccccc-foo-{version}","","")],[("{store}/dddddddddddddddddddddddddddddddd-bar.drv",["out"])],["{store}/ffffffffffffffffffffffffffffffff-src"],"x86_64-linux","/bin/sh",[],[("name","foo-{version}")])"#
+                ),
+            )
+            .unwrap();
+            path
+        };
+        let (path1, path2) = (write('a', "1"), write('b', "2"));
+
+        let cache = DrvCache::new();
+        let [drv1, drv2] = cache.load_pair(path1.as_bytes(), path2.as_bytes());
+        let (drv1, drv2) = (drv1.unwrap(), drv2.unwrap());
+        assert_ne!(drv1, drv2);
+        let shared = |drv: &Derivation| {
+            [
+                Arc::clone(drv.input_derivations.first_key_value().unwrap().0),
+                Arc::clone(drv.input_sources.first().unwrap()),
+                Arc::clone(drv.env.first_key_value().unwrap().0),
+            ]
+        };
+        for (a, b) in shared(&drv1).iter().zip(&shared(&drv2)) {
+            assert!(Arc::ptr_eq(a, b), "{a:?}");
+        }
+    }
+}
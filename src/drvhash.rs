@@ -0,0 +1,518 @@
+//! Recompute derivation output paths, for `--verify`.
+//!
+//! Nix derives the output paths of an input-addressed derivation from the
+//! derivation itself ("hash derivation modulo"): every input derivation is
+//! replaced by its own such hash, output paths are blanked, and the ATerm
+//! serialization is hashed. Fixed-output derivations hash to their content
+//! hash instead, which is what keeps a changed fetcher from rebuilding
+//! everything downstream. A .drv file whose recorded output paths don't
+//! match the recomputed ones has been corrupted or tampered with.
+
+use crate::digest::Digest;
+use crate::parser;
+use crate::types::{BStr, ByteStr, Derivation, Output};
+use anyhow::{Context, Result, anyhow};
+use harmonia_store_core::store_path::{StoreDir, StorePathName};
+use harmonia_utils_hash::Sha256;
+use harmonia_utils_hash::fmt::CommonHash;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// A recorded output path that does not match the derivation's contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub output: Vec<u8>,
+    pub recorded: Vec<u8>,
+    pub expected: Vec<u8>,
+}
+
+type Loader<'a> = Box<dyn FnMut(&[u8]) -> Result<Derivation> + 'a>;
+
+pub struct Verifier<'a> {
+    load: Loader<'a>,
+    /// Hashes of input derivations by path; `None` for derivations whose
+    /// outputs are not input-addressed.
+    memo: HashMap<ByteStr, Option<Sha256>>,
+}
+
+impl Default for Verifier<'_> {
+    /// Read input derivations from the local store.
+    fn default() -> Self {
+        Self::new(|path| {
+            let path = String::from_utf8_lossy(path);
+            parser::parse_derivation(&path)
+        })
+    }
+}
+
+impl<'a> Verifier<'a> {
+    /// Read input derivations with `load`.
+    pub fn new(load: impl FnMut(&[u8]) -> Result<Derivation> + 'a) -> Self {
+        Self {
+            load: Box::new(load),
+            memo: HashMap::new(),
+        }
+    }
+
+    /// Check the output paths recorded in `drv`, which was read from
+    /// `drv_path`. Outputs without a path (content-addressed, impure or
+    /// deferred) are not checked.
+    pub fn verify(&mut self, drv_path: &[u8], drv: &Derivation) -> Result<Vec<Mismatch>> {
+        let (store_dir, name) = split_drv_path(drv_path)?;
+        let mut mismatches = Vec::new();
+        let mut check = |output: &[u8], recorded: &[u8], expected: Vec<u8>| {
+            if recorded != expected {
+                mismatches.push(Mismatch {
+                    output: output.to_vec(),
+                    recorded: recorded.to_vec(),
+                    expected,
+                });
+            }
+        };
+
+        if let Some(out) = fixed_output(drv) {
+            if let Some(expected) = fixed_output_path(out, &store_dir, name)? {
+                check(b"out", &out.path, expected);
+            }
+            return Ok(mismatches);
+        }
+        let Some(hash) = self.hash_modulo(drv, true)? else {
+            return Ok(mismatches);
+        };
+        for (output, out) in &drv.outputs {
+            let mut path_name = name.to_vec();
+            if output != b"out" {
+                path_name.push(b'-');
+                path_name.extend_from_slice(output);
+            }
+            let ty = format!("output:{}", BStr(output));
+            check(
+                output,
+                &out.path,
+                store_path(&store_dir, &ty, hash, &path_name)?,
+            );
+        }
+        Ok(mismatches)
+    }
+
+    /// Nix's `hashDerivationModulo`. `None` if the derivation or one of its
+    /// inputs has outputs whose paths aren't known before building.
+    fn hash_modulo(&mut self, drv: &Derivation, mask_outputs: bool) -> Result<Option<Sha256>> {
+        if let Some(out) = fixed_output(drv) {
+            let mut s = b"fixed:out:".to_vec();
+            for part in [
+                out.hash_algorithm.as_deref().unwrap_or_default(),
+                out.hash.as_deref().unwrap_or_default(),
+                &out.path,
+            ] {
+                s.extend_from_slice(part);
+                s.push(b':');
+            }
+            s.pop();
+            return Ok(Some(Sha256::digest(&s)));
+        }
+        if drv.outputs.values().any(|o| o.path.is_empty()) {
+            return Ok(None);
+        }
+
+        let mut inputs: BTreeMap<ByteStr, BTreeSet<Vec<u8>>> = BTreeMap::new();
+        for (path, outputs) in &drv.input_derivations {
+            let hash = match self.memo.get(path) {
+                Some(hash) => *hash,
+                None => {
+                    let input = (self.load)(path)
+                        .with_context(|| format!("Failed to read input {}", BStr(path)))?;
+                    let hash = self.hash_modulo(&input, false)?;
+                    self.memo.insert(path.clone(), hash);
+                    hash
+                }
+            };
+            let Some(hash) = hash else {
+                return Ok(None);
+            };
+            inputs
+                .entry(ByteStr::from(
+                    hash.as_base16().as_bare().to_string().into_bytes(),
+                ))
+                .or_default()
+                .extend(outputs.iter().cloned());
+        }
+        Ok(Some(Sha256::digest(unparse(drv, mask_outputs, &inputs))))
+    }
+}
+
+/// The single `out` output of a fixed-output derivation.
+fn fixed_output(drv: &Derivation) -> Option<&Output> {
+    match drv.outputs.get(&b"out"[..]) {
+        Some(out) if drv.outputs.len() == 1 && out.is_fixed_output() => Some(out),
+        _ => None,
+    }
+}
+
+/// Where the output of a fixed-output derivation must live, from its
+/// hash. `None` for hash kinds this doesn't know.
+fn fixed_output_path(out: &Output, store_dir: &StoreDir, name: &[u8]) -> Result<Option<Vec<u8>>> {
+    let (Some(algo), Some(hash)) = (out.hash_algorithm.as_deref(), out.hash.as_deref()) else {
+        return Ok(None);
+    };
+    if algo == b"r:sha256" {
+        let Some(digest) = Digest::parse(hash, Some(algo)) else {
+            return Ok(None);
+        };
+        let hash = Sha256::from_slice(&digest.bytes)?;
+        return store_path(store_dir, "source", hash, name).map(Some);
+    }
+    if algo.starts_with(b"text:") {
+        return Ok(None);
+    }
+    let mut s = b"fixed:out:".to_vec();
+    for part in [algo, hash, b""] {
+        s.extend_from_slice(part);
+        s.push(b':');
+    }
+    s.pop();
+    store_path(store_dir, "output:out", Sha256::digest(&s), name).map(Some)
+}
+
+/// Store directory and derivation name of `/nix/store/<hash>-<name>.drv`.
+fn split_drv_path(path: &[u8]) -> Result<(StoreDir, &[u8])> {
+    let unrecognized = || anyhow!("Not a derivation path: {}", BStr(path));
+    let slash = path
+        .iter()
+        .rposition(|&b| b == b'/')
+        .ok_or_else(unrecognized)?;
+    let file = &path[slash + 1..];
+    let name = file
+        .get(33..)
+        .and_then(|n| n.strip_suffix(b".drv"))
+        .filter(|_| file[32] == b'-')
+        .ok_or_else(unrecognized)?;
+    let store_dir = parser::store_dir_from_drv_path(&String::from_utf8_lossy(path))?;
+    Ok((store_dir, name))
+}
+
+/// Nix's `makeStorePath` for an output named `name`, whose path is
+/// fingerprinted as `ty` with `hash`.
+fn store_path(store_dir: &StoreDir, ty: &str, hash: Sha256, name: &[u8]) -> Result<Vec<u8>> {
+    let name: StorePathName = std::str::from_utf8(name)?
+        .parse()
+        .map_err(|e| anyhow!("invalid output name {}: {e}", BStr(name)))?;
+    let path = store_dir.make_store_path(ty, hash, name);
+    Ok(store_dir.display(&path).to_string().into_bytes())
+}
+
+/// The ATerm serialization of `drv`, with input derivations replaced by
+/// `inputs` and, if `mask_outputs` is set, output paths blanked.
+pub fn unparse(
+    drv: &Derivation,
+    mask_outputs: bool,
+    inputs: &BTreeMap<ByteStr, BTreeSet<Vec<u8>>>,
+) -> Vec<u8> {
+    let dynamic = inputs
+        .values()
+        .flatten()
+        .any(|output| output.contains(&b'^'));
+    let mut s = if dynamic {
+        b"DrvWithVersion(\"xp-dyn-drv\",[".to_vec()
+    } else {
+        b"Derive([".to_vec()
+    };
+    for (i, (name, out)) in drv.outputs.iter().enumerate() {
+        if i > 0 {
+            s.push(b',');
+        }
+        s.push(b'(');
+        let path = if mask_outputs { &b""[..] } else { &out.path };
+        let algo = out.hash_algorithm.as_deref().unwrap_or_default();
+        let hash = out.hash.as_deref().unwrap_or_default();
+        write_list(&mut s, [&name[..], path, algo, hash], write_string);
+        s.push(b')');
+    }
+    s.extend_from_slice(b"],[");
+    for (i, (path, outputs)) in inputs.iter().enumerate() {
+        if i > 0 {
+            s.push(b',');
+        }
+        s.push(b'(');
+        write_string(&mut s, path);
+        s.push(b',');
+        write_input_outputs(&mut s, outputs.iter().map(Vec::as_slice));
+        s.push(b')');
+    }
+    s.extend_from_slice(b"],[");
+    write_list(
+        &mut s,
+        drv.input_sources.iter().map(|path| &path[..]),
+        write_string,
+    );
+    s.extend_from_slice(b"],");
+    write_string(&mut s, &drv.platform);
+    s.push(b',');
+    write_string(&mut s, &drv.builder);
+    s.extend_from_slice(b",[");
+    write_list(&mut s, drv.args.iter().map(Vec::as_slice), write_string);
+    s.extend_from_slice(b"],[");
+    write_list(&mut s, &drv.env, |s, (key, value)| {
+        let masked = mask_outputs && drv.outputs.contains_key(&key[..]);
+        s.push(b'(');
+        write_string(s, key);
+        s.push(b',');
+        write_string(s, if masked { b"" } else { value });
+        s.push(b')');
+    });
+    s.extend_from_slice(b"])");
+    s
+}
+
+/// Write the outputs used of an input derivation. `out^lib` is output
+/// `lib` of the derivation that output `out` is (`dynamic-derivations`),
+/// which nests as `([...],[("out",["lib"])])`.
+fn write_input_outputs<'a>(s: &mut Vec<u8>, outputs: impl IntoIterator<Item = &'a [u8]>) {
+    let mut plain = Vec::new();
+    let mut dynamic: BTreeMap<&[u8], Vec<&[u8]>> = BTreeMap::new();
+    for output in outputs {
+        match output.iter().position(|&b| b == b'^') {
+            Some(at) => dynamic
+                .entry(&output[..at])
+                .or_default()
+                .push(&output[at + 1..]),
+            None => plain.push(output),
+        }
+    }
+    if dynamic.is_empty() {
+        s.push(b'[');
+        write_list(s, plain, write_string);
+        s.push(b']');
+        return;
+    }
+    s.extend_from_slice(b"([");
+    write_list(s, plain, write_string);
+    s.extend_from_slice(b"],[");
+    write_list(s, dynamic, |s, (name, outputs)| {
+        s.push(b'(');
+        write_string(s, name);
+        s.push(b',');
+        write_input_outputs(s, outputs);
+        s.push(b')');
+    });
+    s.extend_from_slice(b"])");
+}
+
+fn write_list<T>(
+    s: &mut Vec<u8>,
+    items: impl IntoIterator<Item = T>,
+    write: impl Fn(&mut Vec<u8>, T),
+) {
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            s.push(b',');
+        }
+        write(s, item);
+    }
+}
+
+fn write_string(s: &mut Vec<u8>, value: &[u8]) {
+    s.push(b'"');
+    for &b in value {
+        match b {
+            b'"' => s.extend_from_slice(b"\\\""),
+            b'\\' => s.extend_from_slice(b"\\\\"),
+            b'\n' => s.extend_from_slice(b"\\n"),
+            b'\r' => s.extend_from_slice(b"\\r"),
+            b'\t' => s.extend_from_slice(b"\\t"),
+            _ => s.push(b),
+        }
+    }
+    s.push(b'"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FOD_HASH: &str = "1b4c4a7a1a1d3b8a4b1e5a6d7c8f9e0a1b2c3d4e5f60718293a4b5c6d7e8f901";
+    const FOD_DRV: &str = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-src.tar.gz.drv";
+    const DEP_DRV: &str = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-dep-1.0.drv";
+    const TOP_DRV: &str = "/nix/store/cccccccccccccccccccccccccccccccc-hello-2.12.drv";
+    const BUILDER: &str = "/nix/store/0c9z6vw9aqk8sw6j6d1x2lxnh2wrrxj0-builder.sh";
+
+    // Output paths start out as placeholders; `stamped` records the ones
+    // they hash to. tests/verify_test.rs checks those against Nix.
+
+    fn output(path: &str) -> Output {
+        Output {
+            path: path.as_bytes().to_vec(),
+            hash_algorithm: None,
+            hash: None,
+        }
+    }
+
+    fn bytes_map<const N: usize>(pairs: [(&str, &str); N]) -> BTreeMap<ByteStr, Vec<u8>> {
+        pairs
+            .into_iter()
+            .map(|(k, v)| (ByteStr::from(k.as_bytes()), v.as_bytes().to_vec()))
+            .collect()
+    }
+
+    fn fod() -> Derivation {
+        let path = "/nix/store/11111111111111111111111111111111-src.tar.gz";
+        Derivation {
+            outputs: [(
+                b"out".to_vec(),
+                Output {
+                    hash_algorithm: Some(b"r:sha256".to_vec()),
+                    hash: Some(FOD_HASH.as_bytes().to_vec()),
+                    ..output(path)
+                },
+            )]
+            .into(),
+            platform: b"builtin".to_vec(),
+            builder: b"builtin:fetchurl".to_vec(),
+            env: bytes_map([
+                ("name", "src.tar.gz"),
+                ("out", path),
+                ("url", "https://example.org/src.tar.gz"),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    fn dep() -> Derivation {
+        let out = "/nix/store/22222222222222222222222222222222-dep-1.0";
+        let dev = "/nix/store/33333333333333333333333333333333-dep-1.0-dev";
+        Derivation {
+            outputs: [
+                (b"out".to_vec(), output(out)),
+                (b"dev".to_vec(), output(dev)),
+            ]
+            .into(),
+            input_sources: BTreeSet::from([ByteStr::from(BUILDER.as_bytes())]),
+            platform: b"x86_64-linux".to_vec(),
+            builder: b"/bin/sh".to_vec(),
+            args: vec![b"-e".to_vec(), BUILDER.as_bytes().to_vec()],
+            env: bytes_map([
+                ("name", "dep-1.0"),
+                ("out", out),
+                ("dev", dev),
+                ("msg", "say \"hi\"\n\tthere\\"),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    fn top() -> Derivation {
+        let out = "/nix/store/44444444444444444444444444444444-hello-2.12";
+        Derivation {
+            outputs: [(b"out".to_vec(), output(out))].into(),
+            input_derivations: BTreeMap::from([
+                (ByteStr::from(FOD_DRV.as_bytes()), [b"out".to_vec()].into()),
+                (
+                    ByteStr::from(DEP_DRV.as_bytes()),
+                    [b"dev".to_vec(), b"out".to_vec()].into(),
+                ),
+            ]),
+            platform: b"x86_64-linux".to_vec(),
+            builder: b"/bin/sh".to_vec(),
+            args: vec![b"-c".to_vec(), b"echo".to_vec()],
+            env: bytes_map([("name", "hello-2.12"), ("out", "")]),
+            ..Default::default()
+        }
+    }
+
+    fn verifier(inputs: Vec<(&'static str, Derivation)>) -> Verifier<'static> {
+        Verifier::new(move |path| {
+            inputs
+                .iter()
+                .find(|(p, _)| p.as_bytes() == path)
+                .map(|(_, drv)| drv.clone())
+                .ok_or_else(|| anyhow::anyhow!("no such derivation"))
+        })
+    }
+
+    /// `drv` with the output paths it hashes to recorded, in its outputs
+    /// and env, the way Nix writes a .drv file.
+    fn stamp(inputs: &[(&'static str, Derivation)], path: &str, mut drv: Derivation) -> Derivation {
+        let mismatches = verifier(inputs.to_vec())
+            .verify(path.as_bytes(), &drv)
+            .unwrap();
+        for m in mismatches {
+            drv.outputs.get_mut(&m.output).unwrap().path = m.expected.clone();
+            drv.env.insert(m.output.into(), m.expected);
+        }
+        drv
+    }
+
+    /// The fixtures, each stamped with its output paths.
+    fn stamped() -> Vec<(&'static str, Derivation)> {
+        let mut done = Vec::new();
+        for (path, drv) in [(FOD_DRV, fod()), (DEP_DRV, dep()), (TOP_DRV, top())] {
+            let drv = stamp(&done, path, drv);
+            done.push((path, drv));
+        }
+        done
+    }
+
+    #[test]
+    fn reports_tampered_derivations() {
+        let [(_, fod), (_, dep), (_, top)] = <[_; 3]>::try_from(stamped()).unwrap();
+        let mut v = verifier(vec![(FOD_DRV, fod.clone()), (DEP_DRV, dep.clone())]);
+        assert_eq!(v.verify(TOP_DRV.as_bytes(), &top).unwrap(), []);
+
+        // A changed build script in an input changes every output below it.
+        let mut evil_dep = dep.clone();
+        evil_dep.args[0] = b"-ex".to_vec();
+        let mut v = verifier(vec![(FOD_DRV, fod.clone()), (DEP_DRV, evil_dep.clone())]);
+        let mismatches = v.verify(TOP_DRV.as_bytes(), &top).unwrap();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].output, b"out");
+        assert_ne!(mismatches[0].recorded, mismatches[0].expected);
+        assert_eq!(v.verify(DEP_DRV.as_bytes(), &evil_dep).unwrap().len(), 2);
+
+        // A fixed-output input may change how it fetches without changing
+        // anything downstream, as long as the hash stays.
+        let mut mirror = fod.clone();
+        mirror.env.insert(
+            b"url".as_slice().into(),
+            b"https://mirror.example.org/src.tar.gz".to_vec(),
+        );
+        let mut v = verifier(vec![(FOD_DRV, mirror), (DEP_DRV, dep)]);
+        assert_eq!(v.verify(TOP_DRV.as_bytes(), &top).unwrap(), []);
+
+        // A recorded fixed-output path that doesn't match its hash.
+        let mut fake = fod;
+        fake.outputs.get_mut(&b"out"[..]).unwrap().hash = Some([b'0'; 64].to_vec());
+        assert_eq!(
+            verifier(vec![])
+                .verify(FOD_DRV.as_bytes(), &fake)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn skips_outputs_without_paths() {
+        let mut floating = top();
+        floating.outputs = [(
+            b"out".to_vec(),
+            Output {
+                hash_algorithm: Some(b"r:sha256".to_vec()),
+                ..output("")
+            },
+        )]
+        .into();
+        let drv_path = b"/nix/store/dddddddddddddddddddddddddddddddd-hello-2.12.drv";
+        assert_eq!(verifier(vec![]).verify(drv_path, &floating).unwrap(), []);
+        assert!(split_drv_path(b"hello.drv").is_err());
+    }
+
+    #[test]
+    fn missing_input_is_an_error() {
+        let err = verifier(vec![])
+            .verify(TOP_DRV.as_bytes(), &top())
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("Failed to read input"),
+            "{err:#}"
+        );
+    }
+}
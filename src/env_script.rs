@@ -0,0 +1,169 @@
+//! `--format env-script`: the environment delta as shell statements.
+//!
+//! Sourcing the script inside `nix-shell` of the old derivation gives an
+//! approximation of the new build environment. Only values that survive a
+//! round trip through a single-quoted shell word on one line are emitted;
+//! everything else is listed in a comment so nothing is silently dropped.
+
+use crate::types::{
+    ClosureInfoDiff, DerivationDiff, EnvVarDiff, RenamedVar, RequisitesDiff, StorePathsDiff,
+    StringDiff,
+};
+
+/// Quote `value` as a single shell word. Inside single quotes nothing is
+/// special except `'` itself, which is written as `'\''`.
+pub fn shell_quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('\'');
+    for c in value.chars() {
+        if c == '\'' {
+            quoted.push_str("'\\''");
+        } else {
+            quoted.push(c);
+        }
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// Whether `name` can be assigned with `export`.
+fn is_shell_identifier(name: &[u8]) -> bool {
+    name.first()
+        .is_some_and(|c| c.is_ascii_alphabetic() || *c == b'_')
+        && name.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'_')
+}
+
+/// Emit `export`/`unset` statements for the top-level environment changes.
+pub fn render_env_script(diff: &DerivationDiff) -> Vec<u8> {
+    let mut statements = String::new();
+    let mut skipped = Vec::new();
+    for (key, change) in diff.env.iter().flatten() {
+        let Some(change) = change else { continue };
+        let name = String::from_utf8_lossy(key);
+        if !is_shell_identifier(key) {
+            skipped.push(format!("{name} (not a shell variable name)"));
+            continue;
+        }
+        let new = match change {
+            EnvVarDiff::Removed(_)
+            | EnvVarDiff::Requisites(RequisitesDiff { new: None, .. })
+            | EnvVarDiff::ClosureInfo(ClosureInfoDiff { new: None, .. }) => {
+                statements.push_str(&format!("unset {name}\n"));
+                continue;
+            }
+            EnvVarDiff::Renamed(RenamedVar { from, new, .. }) => {
+                // The old name is only unset if it is a shell variable at
+                // all; then the new one is exported like any other.
+                if is_shell_identifier(from) {
+                    statements.push_str(&format!("unset {}\n", String::from_utf8_lossy(from)));
+                }
+                new
+            }
+            EnvVarDiff::Added(new)
+            | EnvVarDiff::Changed(StringDiff { new, .. })
+            | EnvVarDiff::Requisites(RequisitesDiff { new: Some(new), .. })
+            | EnvVarDiff::ClosureInfo(ClosureInfoDiff { new: Some(new), .. })
+            | EnvVarDiff::StorePaths(StorePathsDiff { new, .. }) => new,
+        };
+        match std::str::from_utf8(new) {
+            // No shell variable can hold a NUL.
+            Ok(value) if value.contains('\0') => skipped.push(format!("{name} (contains NUL)")),
+            Ok(value) if !value.contains('\n') => {
+                statements.push_str(&format!("export {name}={}\n", shell_quote(value)));
+            }
+            Ok(_) => skipped.push(format!("{name} (multi-line value)")),
+            Err(_) => skipped.push(format!("{name} (not valid UTF-8)")),
+        }
+    }
+
+    let mut out = String::from("# Environment changes from the old to the new derivation.\n");
+    for reason in &skipped {
+        out.push_str(&format!("# skipped: {reason}\n"));
+    }
+    out.push_str(&statements);
+    out.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::*;
+
+    #[test]
+    fn shell_quote_cases() {
+        let cases = [
+            ("", "''"),
+            ("plain", "'plain'"),
+            ("with space", "'with space'"),
+            ("it's", "'it'\\''s'"),
+            ("''", "''\\'''\\'''"),
+            ("$HOME ${x} $(id) `id`", "'$HOME ${x} $(id) `id`'"),
+            ("back\\slash", "'back\\slash'"),
+            ("\"double\"", "'\"double\"'"),
+            ("glob * ? [a]", "'glob * ? [a]'"),
+            ("semi; rm -rf /", "'semi; rm -rf /'"),
+            ("tab\there", "'tab\there'"),
+            ("ünïcödé", "'ünïcödé'"),
+        ];
+        for (input, want) in cases {
+            assert_eq!(shell_quote(input), want, "shell_quote({input:?})");
+        }
+    }
+
+    #[test]
+    fn shell_quote_round_trips_through_sh() {
+        for value in ["it's", "$PATH", "a'b'c", "`echo hi`", "\\n", "!"] {
+            let out = std::process::Command::new("sh")
+                .args(["-c", &format!("printf %s {}", shell_quote(value))])
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8(out.stdout).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn renders_exports_unsets_and_skips() {
+        let mut env = EnvironmentDiff::new();
+        env.insert(
+            b"version".to_vec(),
+            Some(EnvVarDiff::Changed(StringDiff {
+                old: b"1".to_vec(),
+                new: b"2'".to_vec(),
+            })),
+        );
+        env.insert(b"gone".to_vec(), Some(EnvVarDiff::Removed(b"x".to_vec())));
+        env.insert(
+            b"script".to_vec(),
+            Some(EnvVarDiff::Added(b"a\nb".to_vec())),
+        );
+        env.insert(b"bin".to_vec(), Some(EnvVarDiff::Added(vec![0xff])));
+        env.insert(b"has-dash".to_vec(), Some(EnvVarDiff::Added(b"1".to_vec())));
+        env.insert(
+            b"configureFlagsArray".to_vec(),
+            Some(EnvVarDiff::Renamed(RenamedVar {
+                from: b"configureFlags".to_vec(),
+                old: b"--with-ssl".to_vec(),
+                new: b"--with-ssl".to_vec(),
+            })),
+        );
+        let diff = DerivationDiff::new(Derivation::default(), Derivation::default()).env(env);
+
+        let out = String::from_utf8(render_env_script(&diff)).unwrap();
+        assert!(out.contains("export version='2'\\'''\n"), "{out}");
+        assert!(out.contains("unset gone\n"), "{out}");
+        assert!(
+            out.contains("unset configureFlags\nexport configureFlagsArray='--with-ssl'\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("# skipped: script (multi-line value)"),
+            "{out}"
+        );
+        assert!(out.contains("# skipped: bin (not valid UTF-8)"), "{out}");
+        assert!(
+            out.contains("# skipped: has-dash (not a shell variable name)"),
+            "{out}"
+        );
+        assert!(!out.contains("export script"), "{out}");
+    }
+}
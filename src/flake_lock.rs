@@ -0,0 +1,157 @@
+//! The direct inputs of a flake as its `flake.lock` pins them, and how
+//! they changed between two flakes.
+//!
+//! Only the inputs of the root node are compared: a transitive input only
+//! matters through the direct input that brought it in, and that one's
+//! pin changes with it. Inputs that `follows` another one have no pin of
+//! their own and are left out.
+
+use crate::types::{FlakeInputChange, LockedInput};
+use anyhow::{Context, Result, anyhow};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Direct inputs by name.
+pub type FlakeLock = BTreeMap<String, LockedInput>;
+
+/// The direct inputs of a parsed `flake.lock` (version 5 or newer).
+pub fn parse_lock(lock: &Value) -> Result<FlakeLock> {
+    let nodes = lock
+        .get("nodes")
+        .and_then(Value::as_object)
+        .ok_or_else(|| anyhow!("No nodes in flake lock"))?;
+    let root = lock.get("root").and_then(Value::as_str).unwrap_or("root");
+    let Some(inputs) = nodes
+        .get(root)
+        .and_then(|node| node.get("inputs"))
+        .and_then(Value::as_object)
+    else {
+        return Ok(FlakeLock::new());
+    };
+    let mut locked = FlakeLock::new();
+    for (name, target) in inputs {
+        // Followed inputs are a path of input names, not a node.
+        let Some(node_name) = target.as_str() else {
+            continue;
+        };
+        let node = nodes
+            .get(node_name)
+            .and_then(|node| node.get("locked"))
+            .with_context(|| format!("Flake lock input {name} has no locked node {node_name}"))?;
+        locked.insert(name.clone(), locked_input(node));
+    }
+    Ok(locked)
+}
+
+fn locked_input(locked: &Value) -> LockedInput {
+    let field = |key: &str| locked.get(key).and_then(Value::as_str);
+    let source = match (field("type"), field("owner"), field("repo")) {
+        (Some(kind @ ("github" | "gitlab" | "sourcehut")), Some(owner), Some(repo)) => {
+            format!("{kind}:{owner}/{repo}")
+        }
+        _ => field("url")
+            .or(field("path"))
+            .or(field("type"))
+            .unwrap_or("unknown")
+            .to_string(),
+    };
+    LockedInput {
+        source,
+        rev: field("rev").map(str::to_string),
+        nar_hash: field("narHash").map(str::to_string),
+        last_modified: locked.get("lastModified").and_then(Value::as_u64),
+    }
+}
+
+/// The inputs added, removed or pinned differently in `new`, by name.
+pub fn diff_locks(old: &FlakeLock, new: &FlakeLock) -> Vec<FlakeInputChange> {
+    let mut names: Vec<&String> = old.keys().chain(new.keys()).collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let (old, new) = (old.get(name), new.get(name));
+            (old != new).then(|| FlakeInputChange {
+                name: name.clone(),
+                old: old.cloned(),
+                new: new.cloned(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(text: &str) -> FlakeLock {
+        parse_lock(&serde_json::from_str(text).unwrap()).unwrap()
+    }
+
+    fn v1() -> FlakeLock {
+        fixture(include_str!("../tests/fixtures/flake-lock/v1.lock"))
+    }
+
+    fn v2() -> FlakeLock {
+        fixture(include_str!("../tests/fixtures/flake-lock/v2.lock"))
+    }
+
+    #[test]
+    fn direct_inputs_are_read_from_the_root_node() {
+        let lock = v1();
+        let names: Vec<_> = lock.keys().collect();
+        assert_eq!(names, ["flake-utils", "home-manager", "nixpkgs"]);
+        assert_eq!(
+            lock["nixpkgs"],
+            LockedInput {
+                source: "github:NixOS/nixpkgs".to_string(),
+                rev: Some("57d6973abba7ea108bac64ae7629e7431e0199b6".to_string()),
+                nar_hash: Some("sha256-9YrUjdztqi4Gz8n3mBuqvCkMo4ojrA6nASwyIKWMpus=".to_string()),
+                last_modified: Some(1718160348),
+            }
+        );
+    }
+
+    #[test]
+    fn followed_inputs_and_other_sources() {
+        let lock = fixture(
+            r#"{"nodes":{
+                "root":{"inputs":{"a":"a","b":["a"],"c":"c"}},
+                "a":{"locked":{"type":"path","path":"/src/a","narHash":"sha256-a"}},
+                "c":{"locked":{"type":"git","url":"https://example.org/c.git","rev":"abc"}}
+            },"root":"root","version":7}"#,
+        );
+        let names: Vec<_> = lock.keys().collect();
+        assert_eq!(names, ["a", "c"]);
+        assert_eq!(lock["a"].source, "/src/a");
+        assert_eq!(lock["c"].source, "https://example.org/c.git");
+
+        assert!(fixture(r#"{"nodes":{"root":{}},"root":"root"}"#).is_empty());
+        let err =
+            parse_lock(&serde_json::json!({"nodes":{"root":{"inputs":{"a":"gone"}}}})).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Flake lock input a has no locked node gone"
+        );
+    }
+
+    #[test]
+    fn changes_are_listed_by_name() {
+        let (old, new) = (v1(), v2());
+        let changes = diff_locks(&old, &new);
+        let summary: Vec<_> = changes
+            .iter()
+            .map(|c| (c.name.as_str(), c.old.is_some(), c.new.is_some()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("home-manager", true, false),
+                ("nixpkgs", true, true),
+                ("treefmt-nix", false, true),
+            ]
+        );
+        assert!(diff_locks(&old, &old).is_empty());
+    }
+}
@@ -0,0 +1,117 @@
+//! Shell-style wildcards for selecting env vars, inputs and the like by
+//! name: `*` matches any run of bytes, `?` any one byte and `[...]` one of
+//! a set (`[a-z]`, or `[!0-9]` for its complement). Names are not paths,
+//! so `*` also matches `/`.
+
+/// Whether `pattern` matches all of `text`.
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last `*`: its pattern position and the text
+    // position it currently extends to.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            Some(b'[') => {
+                if let Some((matched, len)) = match_class(&pattern[p..], text[t]) {
+                    if matched {
+                        p += len;
+                        t += 1;
+                        continue;
+                    }
+                } else if text[t] == b'[' {
+                    // An unclosed `[` is literal.
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+            }
+            Some(&c) if c == text[t] => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            _ => {}
+        }
+        // Mismatch: let the last `*` swallow one more byte, if there is one.
+        match star {
+            Some((star_p, star_t)) => {
+                star = Some((star_p, star_t + 1));
+                p = star_p + 1;
+                t = star_t + 1;
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Match `c` against the class at the start of `pattern` (which begins
+/// with `[`). Returns whether it matched and the class's length, or `None`
+/// if the class is never closed.
+fn match_class(pattern: &[u8], c: u8) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negated = matches!(pattern.get(i), Some(b'!' | b'^'));
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let &start = pattern.get(i)?;
+        // A `]` right after the opening bracket is a member.
+        if start == b']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+        if pattern.get(i + 1) == Some(&b'-') && pattern.get(i + 2).is_some_and(|&e| e != b']') {
+            matched |= (start..=pattern[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            matched |= start == c;
+            i += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        let matches = |pattern: &str, text: &str| glob_match(pattern.as_bytes(), text.as_bytes());
+        assert!(matches("builder", "builder"));
+        assert!(!matches("builder", "builderArgs"));
+        assert!(matches("*Phase", "configurePhase"));
+        assert!(matches("*Phase", "Phase"));
+        assert!(!matches("*Phase", "phases"));
+        assert!(matches("pre*Hook*", "preConfigureHooks"));
+        assert!(matches("NIX_?FLAGS", "NIX_CFLAGS"));
+        assert!(!matches("NIX_?FLAGS", "NIX_LDFLAGS"));
+        assert!(matches("*", ""));
+        assert!(matches("a*b*c", "a-b-b-c"));
+        assert!(!matches("a*b*c", "a-b-b-d"));
+    }
+
+    #[test]
+    fn classes() {
+        let matches = |pattern: &str, text: &str| glob_match(pattern.as_bytes(), text.as_bytes());
+        assert!(matches("python3[0-9]*", "python311"));
+        assert!(!matches("python3[0-9]*", "python3-minimal"));
+        assert!(matches("x[!a-z]", "x1"));
+        assert!(!matches("x[!a-z]", "xq"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("a[b", "a[b"));
+        assert!(matches("[a-]", "-"));
+    }
+}
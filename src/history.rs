@@ -0,0 +1,299 @@
+//! Record of past comparisons, for `--history` and `--rerun`.
+//!
+//! Every run appends one JSON line to `$XDG_STATE_HOME/nix-diff/history.jsonl`
+//! with the resolved .drv paths, so a comparison of two flake outputs can be
+//! repeated later without evaluating anything. Appends from concurrent runs
+//! are serialized with `flock(2)` on a lock file next to the history, and
+//! the file is rotated once it grows past a size cap.
+
+use anyhow::{Context, Result, anyhow};
+use nix_diff::summary::DiffSummary;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Rotate the history once it reaches this size (1 MiB).
+pub const DEFAULT_MAX_BYTES: u64 = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The inputs as given on the command line.
+    pub inputs: [String; 2],
+    /// The .drv files the inputs resolved to.
+    pub drv_paths: [String; 2],
+    /// Command-line options other than the inputs.
+    pub options: Vec<String>,
+    pub summary: DiffSummary,
+}
+
+impl HistoryEntry {
+    pub fn new(
+        inputs: [String; 2],
+        drv_paths: [String; 2],
+        options: Vec<String>,
+        summary: DiffSummary,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Self {
+            timestamp,
+            inputs,
+            drv_paths,
+            options,
+            summary,
+        }
+    }
+
+    /// Command line repeating this comparison on the recorded .drv files
+    /// (or URLs). `extra` options come after the recorded ones, so they win.
+    pub fn rerun_args(&self, program: &str, extra: &[String]) -> Vec<String> {
+        let mut args = vec![program.to_string()];
+        args.extend(self.options.iter().cloned());
+        args.extend(extra.iter().cloned());
+        let kinds = self.drv_paths.clone().map(|p| {
+            if nix_diff::remote::is_drv_url(&p) {
+                "url"
+            } else {
+                "drv"
+            }
+        });
+        let kinds = if kinds[0] == kinds[1] {
+            kinds[0].to_string()
+        } else {
+            kinds.join(",")
+        };
+        args.extend(["--input-type".to_string(), kinds]);
+        args.extend(self.drv_paths.iter().cloned());
+        args
+    }
+}
+
+/// Where the history lives: `$XDG_STATE_HOME/nix-diff/history.jsonl`,
+/// falling back to `~/.local/state`. `None` if neither is set.
+pub fn default_path() -> Option<PathBuf> {
+    let state = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".local/state")))?;
+    Some(state.join("nix-diff").join("history.jsonl"))
+}
+
+pub struct History {
+    path: PathBuf,
+    max_bytes: u64,
+}
+
+impl History {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+
+    /// Rotate at `max_bytes` instead; the command line always uses
+    /// [`DEFAULT_MAX_BYTES`].
+    #[cfg(test)]
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// The previous file, kept after rotation.
+    fn rotated_path(&self) -> PathBuf {
+        self.path.with_extension("1.jsonl")
+    }
+
+    /// Hold an exclusive lock on the history until the returned file is
+    /// dropped.
+    fn lock(&self) -> Result<File> {
+        let lock_path = self.path.with_extension("lock");
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .with_context(|| format!("Failed to open {}", lock_path.display()))?;
+        // SAFETY: flock(2) only takes a file descriptor we own.
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to lock {}", lock_path.display()));
+        }
+        Ok(lock)
+    }
+
+    pub fn append(&self, entry: &HistoryEntry) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let _lock = self.lock()?;
+        if fs::metadata(&self.path).is_ok_and(|m| m.len() >= self.max_bytes) {
+            fs::rename(&self.path, self.rotated_path())
+                .with_context(|| format!("Failed to rotate {}", self.path.display()))?;
+        }
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut f| f.write_all(&line))
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+
+    /// All recorded entries, oldest first. Lines that don't parse (say,
+    /// from a newer version) are skipped.
+    pub fn entries(&self) -> Result<Vec<HistoryEntry>> {
+        let mut entries = Vec::new();
+        for path in [self.rotated_path(), self.path.clone()] {
+            let contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+                }
+            };
+            entries.extend(
+                contents
+                    .lines()
+                    .filter_map(|line| serde_json::from_str(line).ok()),
+            );
+        }
+        Ok(entries)
+    }
+
+    /// Entry `n`, counting from 1 for the most recent.
+    pub fn nth_recent(&self, n: usize) -> Result<HistoryEntry> {
+        let mut entries = self.entries()?;
+        let len = entries.len();
+        if n == 0 || n > len {
+            return Err(anyhow!(
+                "No history entry {n} ({len} recorded in {})",
+                self.path.display()
+            ));
+        }
+        Ok(entries.swap_remove(len - n))
+    }
+}
+
+/// "5m ago" style age of a timestamp.
+pub fn format_age(timestamp: u64, now: u64) -> String {
+    let secs = now.saturating_sub(timestamp);
+    match secs {
+        0..60 => format!("{secs}s ago"),
+        60..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(n: u64) -> HistoryEntry {
+        HistoryEntry {
+            timestamp: n,
+            inputs: [format!("old{n}"), format!("new{n}")],
+            drv_paths: [
+                format!("/nix/store/a{n}.drv"),
+                format!("/nix/store/b{n}.drv"),
+            ],
+            options: vec!["--depth".into(), "1".into()],
+            summary: DiffSummary {
+                derivations: 1,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn appends_and_lists_most_recent_first() {
+        let tmp = tempfile::tempdir().unwrap();
+        let history = History::new(tmp.path().join("state/nix-diff/history.jsonl"));
+        assert_eq!(history.entries().unwrap(), []);
+        for n in 1..=3 {
+            history.append(&entry(n)).unwrap();
+        }
+        assert_eq!(history.entries().unwrap(), [entry(1), entry(2), entry(3)]);
+        assert_eq!(history.nth_recent(1).unwrap(), entry(3));
+        assert_eq!(history.nth_recent(3).unwrap(), entry(1));
+        assert!(history.nth_recent(0).is_err());
+        assert!(history.nth_recent(4).is_err());
+    }
+
+    #[test]
+    fn rotates_past_the_size_cap() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("history.jsonl");
+        let history = History::new(&path).max_bytes(1);
+        for n in 1..=3 {
+            history.append(&entry(n)).unwrap();
+        }
+        // Each append found the file over the cap and rotated it away.
+        assert_eq!(history.entries().unwrap(), [entry(2), entry(3)]);
+        assert!(tmp.path().join("history.1.jsonl").exists());
+    }
+
+    #[test]
+    fn concurrent_appends_keep_lines_intact() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("history.jsonl");
+        std::thread::scope(|scope| {
+            for t in 0..8 {
+                let path = &path;
+                scope.spawn(move || {
+                    let history = History::new(path);
+                    for n in 0..25 {
+                        history.append(&entry(t * 100 + n)).unwrap();
+                    }
+                });
+            }
+        });
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 200);
+        assert_eq!(History::new(&path).entries().unwrap().len(), 200);
+    }
+
+    #[test]
+    fn rerun_uses_recorded_drv_paths_and_options() {
+        let args = entry(7).rerun_args("nix-diff", &["--color".into(), "never".into()]);
+        assert_eq!(
+            args,
+            [
+                "nix-diff",
+                "--depth",
+                "1",
+                "--color",
+                "never",
+                "--input-type",
+                "drv",
+                "/nix/store/a7.drv",
+                "/nix/store/b7.drv"
+            ]
+        );
+    }
+
+    #[test]
+    fn rerun_keeps_urls_as_urls() {
+        let mut entry = entry(1);
+        entry.drv_paths[1] = "https://cache.example/nix/store/b-x.drv".into();
+        let args = entry.rerun_args("nix-diff", &[]);
+        assert_eq!(args[3..6], ["--input-type", "drv,url", "/nix/store/a1.drv"]);
+    }
+
+    #[test]
+    fn ages() {
+        assert_eq!(format_age(100, 130), "30s ago");
+        assert_eq!(format_age(0, 7200), "2h ago");
+        assert_eq!(format_age(0, 3 * 86400 + 5), "3d ago");
+        assert_eq!(format_age(10, 5), "0s ago");
+    }
+}
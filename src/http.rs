@@ -0,0 +1,73 @@
+//! Minimal HTTP access for talking to binary caches.
+//!
+//! The real client lives behind the `http` cargo feature so the default
+//! build stays free of a TLS stack. Everything that needs the network goes
+//! through [`HttpClient`], which keeps it testable without a server.
+
+use anyhow::Result;
+#[cfg(not(feature = "http"))]
+use anyhow::bail;
+
+/// Upper bound for any single response body. Narinfos are tiny and even
+/// huge NixOS .drv files stay well below this.
+pub const MAX_BODY_SIZE: u64 = 64 * 1024 * 1024;
+
+pub trait HttpClient {
+    /// GET `url`. Returns `Ok(None)` for a 404 so callers can tell "not in
+    /// the cache" apart from a failing cache.
+    fn get(&self, url: &str) -> Result<Option<Vec<u8>>>;
+}
+
+#[cfg(feature = "http")]
+pub struct UreqClient {
+    agent: ureq::Agent,
+}
+
+#[cfg(feature = "http")]
+impl UreqClient {
+    pub fn new() -> Self {
+        Self {
+            agent: ureq::Agent::new_with_defaults(),
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+impl Default for UreqClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "http")]
+impl HttpClient for UreqClient {
+    fn get(&self, url: &str) -> Result<Option<Vec<u8>>> {
+        use anyhow::Context;
+
+        match self.agent.get(url).call() {
+            Ok(mut resp) => {
+                let body = resp
+                    .body_mut()
+                    .with_config()
+                    .limit(MAX_BODY_SIZE)
+                    .read_to_vec()
+                    .with_context(|| format!("Failed to read response body from {url}"))?;
+                Ok(Some(body))
+            }
+            Err(ureq::Error::StatusCode(404)) => Ok(None),
+            Err(e) => Err(anyhow::Error::new(e).context(format!("GET {url} failed"))),
+        }
+    }
+}
+
+/// The client used by the CLI, or an error explaining how to get one.
+#[cfg(feature = "http")]
+pub fn default_client() -> Result<Box<dyn HttpClient>> {
+    Ok(Box::new(UreqClient::new()))
+}
+
+/// The client used by the CLI, or an error explaining how to get one.
+#[cfg(not(feature = "http"))]
+pub fn default_client() -> Result<Box<dyn HttpClient>> {
+    bail!("nix-diff was built without HTTP support; rebuild with `--features http`")
+}
@@ -0,0 +1,145 @@
+//! Line-based hunks for text diffs.
+//!
+//! `similar` yields one change per line, so a 500-line added script would be
+//! 500 separately colored lines. We coalesce adjacent lines with the same
+//! tag into a [`DiffBlock`], which lets the renderer emit one color span
+//! per block while the start offsets keep per-line numbers recoverable.
+
+use similar::{ChangeTag, TextDiff as SimilarTextDiff};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineTag {
+    Context,
+    Removed,
+    Added,
+}
+
+impl From<ChangeTag> for LineTag {
+    fn from(tag: ChangeTag) -> Self {
+        match tag {
+            ChangeTag::Equal => LineTag::Context,
+            ChangeTag::Delete => LineTag::Removed,
+            ChangeTag::Insert => LineTag::Added,
+        }
+    }
+}
+
+/// A run of consecutive lines sharing the same tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffBlock {
+    pub tag: LineTag,
+    /// 0-based line index in the old text where this block starts (for
+    /// added blocks: where the lines are inserted).
+    pub old_start: usize,
+    /// 0-based line index in the new text where this block starts.
+    pub new_start: usize,
+    /// Lines without their trailing newline.
+    pub lines: Vec<Vec<u8>>,
+}
+
+impl DiffBlock {
+    /// Per-line view with 0-based old/new line indices; `None` on the side
+    /// a line does not exist in.
+    pub fn iter_lines(&self) -> impl Iterator<Item = (Option<usize>, Option<usize>, &[u8])> {
+        self.lines.iter().enumerate().map(move |(i, line)| {
+            let old = (self.tag != LineTag::Added).then_some(self.old_start + i);
+            let new = (self.tag != LineTag::Removed).then_some(self.new_start + i);
+            (old, new, line.as_slice())
+        })
+    }
+}
+
+/// A group of changes with up to `context_lines` of context on each side.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+    pub blocks: Vec<DiffBlock>,
+}
+
+/// Diff `old` and `new` line by line and group the result into hunks.
+pub fn build_hunks(old: &[u8], new: &[u8], context_lines: usize) -> Vec<Hunk> {
+    let diff = SimilarTextDiff::from_lines(old, new);
+    diff.grouped_ops(context_lines)
+        .iter()
+        .map(|group| {
+            let Some(first) = group.first() else {
+                return Hunk { blocks: Vec::new() };
+            };
+            let mut old_pos = first.old_range().start;
+            let mut new_pos = first.new_range().start;
+            let mut blocks: Vec<DiffBlock> = Vec::new();
+            for op in group {
+                for change in diff.iter_changes(op) {
+                    let tag = LineTag::from(change.tag());
+                    let value = change.value();
+                    let line = value.strip_suffix(b"\n").unwrap_or(value).to_vec();
+                    match blocks.last_mut() {
+                        Some(block) if block.tag == tag => block.lines.push(line),
+                        _ => blocks.push(DiffBlock {
+                            tag,
+                            old_start: old_pos,
+                            new_start: new_pos,
+                            lines: vec![line],
+                        }),
+                    }
+                    if tag != LineTag::Added {
+                        old_pos += 1;
+                    }
+                    if tag != LineTag::Removed {
+                        new_pos += 1;
+                    }
+                }
+            }
+            Hunk { blocks }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesces_adjacent_lines_with_same_tag() {
+        let old = b"a\nb\nc\n";
+        let new = b"a\nx\ny\nz\nc\n";
+        let hunks = build_hunks(old, new, 1);
+        assert_eq!(hunks.len(), 1);
+        let tags: Vec<_> = hunks[0]
+            .blocks
+            .iter()
+            .map(|b| (b.tag, b.lines.len()))
+            .collect();
+        assert_eq!(
+            tags,
+            vec![
+                (LineTag::Context, 1),
+                (LineTag::Removed, 1),
+                (LineTag::Added, 3),
+                (LineTag::Context, 1),
+            ]
+        );
+        assert_eq!(hunks[0].blocks[2].lines[2], b"z");
+    }
+
+    #[test]
+    fn blocks_keep_per_line_numbers() {
+        let old = b"1\n2\n3\n4\n5\n6\n7\n8\n";
+        let new = b"1\n2\nnew\n3\n4\n5\n6\n7\n";
+        let hunks = build_hunks(old, new, 0);
+        assert_eq!(hunks.len(), 2, "distant changes form separate hunks");
+
+        let added = &hunks[0].blocks[0];
+        assert_eq!(added.tag, LineTag::Added);
+        assert_eq!(
+            added.iter_lines().collect::<Vec<_>>(),
+            vec![(None, Some(2), &b"new"[..])]
+        );
+
+        let removed = &hunks[1].blocks[0];
+        assert_eq!(removed.tag, LineTag::Removed);
+        assert_eq!(
+            removed.iter_lines().collect::<Vec<_>>(),
+            vec![(Some(7), None, &b"8"[..])]
+        );
+    }
+}
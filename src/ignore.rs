@@ -0,0 +1,348 @@
+//! Env vars, inputs, arguments and sources to leave out of a comparison,
+//! given with `--ignore` or in a `.nixdiffignore` file.
+//!
+//! An ignore file lists glob patterns (see [`crate::glob`]), one per line,
+//! under the section they apply to:
+//!
+//! ```text
+//! # Rebuilt on every run
+//! [env]
+//! SOURCE_DATE_EPOCH
+//! NIX_*_CORES
+//!
+//! [inputs]
+//! bootstrap-tools*.drv
+//! ```
+//!
+//! Env patterns match var names and args patterns whole arguments. Input
+//! and source patterns match the name of the store path without its hash,
+//! such as `bash-5.2.drv` or `fix-cross.patch`. A line that is just a
+//! word in brackets starts a section; other lines starting with `[`, like
+//! `[A-Z]*`, are patterns. Lines starting with `#` and blank lines are
+//! skipped.
+
+use crate::glob::glob_match;
+use crate::types::Derivation;
+use anyhow::{Context, Result, anyhow, bail};
+use regex::bytes::Regex;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The file looked for in the current directory and the ones above it.
+pub const FILE_NAME: &str = ".nixdiffignore";
+
+/// What a pattern applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Env,
+    Inputs,
+    Args,
+    Sources,
+}
+
+impl FromStr for Section {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "env" => Ok(Self::Env),
+            "inputs" => Ok(Self::Inputs),
+            "args" => Ok(Self::Args),
+            "sources" => Ok(Self::Sources),
+            _ => Err(format!(
+                "Unknown ignore section: {s} (expected env, inputs, args or sources)"
+            )),
+        }
+    }
+}
+
+/// Glob patterns per section. Rules from several places are merged with
+/// [`extend`](Self::extend); a thing is ignored if any pattern matches.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IgnoreRules {
+    pub env: Vec<String>,
+    pub inputs: Vec<String>,
+    pub args: Vec<String>,
+    pub sources: Vec<String>,
+}
+
+impl IgnoreRules {
+    /// Read the ignore file at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read ignore file {}", path.display()))?;
+        Self::parse(&text, &path.display().to_string())
+    }
+
+    /// Parse ignore file `text`; errors name the line as `origin:line`.
+    pub fn parse(text: &str, origin: &str) -> Result<Self> {
+        let mut rules = Self::default();
+        let mut section = None;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let header = line
+                .strip_prefix('[')
+                .and_then(|l| l.strip_suffix(']'))
+                .filter(|name| !name.is_empty() && name.bytes().all(|c| c.is_ascii_alphabetic()));
+            if let Some(name) = header {
+                let parsed = name
+                    .parse()
+                    .map_err(|e: String| anyhow!("{origin}:{}: {e}", i + 1))?;
+                section = Some(parsed);
+                continue;
+            }
+            let Some(section) = section else {
+                bail!(
+                    "{origin}:{}: pattern {line} comes before any section ([env], [inputs], \
+                     [args] or [sources])",
+                    i + 1
+                );
+            };
+            rules.add(section, line);
+        }
+        Ok(rules)
+    }
+
+    /// Parse an `--ignore` value, `SECTION:PATTERN`.
+    pub fn parse_option(&mut self, value: &str) -> Result<()> {
+        let (section, pattern) = value
+            .split_once(':')
+            .filter(|(_, pattern)| !pattern.is_empty())
+            .ok_or_else(|| anyhow!("Invalid --ignore: {value} (expected SECTION:PATTERN)"))?;
+        self.add(section.parse().map_err(|e: String| anyhow!(e))?, pattern);
+        Ok(())
+    }
+
+    pub fn add(&mut self, section: Section, pattern: &str) {
+        let patterns = match section {
+            Section::Env => &mut self.env,
+            Section::Inputs => &mut self.inputs,
+            Section::Args => &mut self.args,
+            Section::Sources => &mut self.sources,
+        };
+        patterns.push(pattern.to_string());
+    }
+
+    /// Add the patterns of `other`.
+    pub fn extend(&mut self, other: Self) {
+        self.env.extend(other.env);
+        self.inputs.extend(other.inputs);
+        self.args.extend(other.args);
+        self.sources.extend(other.sources);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.env.is_empty()
+            && self.inputs.is_empty()
+            && self.args.is_empty()
+            && self.sources.is_empty()
+    }
+
+    /// `drv` without the env vars, inputs, arguments and sources these
+    /// rules ignore.
+    pub fn strip<'a>(&self, drv: &'a Derivation) -> Cow<'a, Derivation> {
+        if self.is_empty() {
+            return Cow::Borrowed(drv);
+        }
+        let mut drv = drv.clone();
+        drv.env.retain(|key, _| !any_match(&self.env, key));
+        drv.input_derivations
+            .retain(|path, _| !any_match(&self.inputs, store_name(path)));
+        drv.args.retain(|arg| !any_match(&self.args, arg));
+        drv.input_sources
+            .retain(|path| !any_match(&self.sources, store_name(path)));
+        Cow::Owned(drv)
+    }
+}
+
+/// Env vars left out of the Environment section, unlike [`IgnoreRules`]
+/// only after the comparison, so that they can be counted: `--ignore-env`
+/// regexes and `--skip-env`.
+#[derive(Debug, Clone, Default)]
+pub struct EnvFilter {
+    /// Regexes searched for in var names; `^...$` matches a whole name.
+    pub patterns: Vec<Regex>,
+    /// Leave every var out.
+    pub skip_all: bool,
+}
+
+impl EnvFilter {
+    /// Add an `--ignore-env` regex.
+    pub fn add_pattern(&mut self, pattern: &str) -> Result<()> {
+        let regex = Regex::new(pattern)
+            .with_context(|| format!("Invalid --ignore-env regex: {pattern}"))?;
+        self.patterns.push(regex);
+        Ok(())
+    }
+
+    pub fn ignores(&self, key: &[u8]) -> bool {
+        self.skip_all || self.patterns.iter().any(|regex| regex.is_match(key))
+    }
+}
+
+/// The nearest [`FILE_NAME`] in `dir` or a directory above it.
+pub fn discover(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(FILE_NAME))
+        .find(|path| path.is_file())
+}
+
+fn any_match(patterns: &[String], text: &[u8]) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| glob_match(pattern.as_bytes(), text))
+}
+
+/// `/nix/store/<hash>-name` -> `name`.
+fn store_name(path: &[u8]) -> &[u8] {
+    let file = path.rsplit(|&b| b == b'/').next().unwrap_or(path);
+    match file.iter().position(|&b| b == b'-') {
+        Some(dash) => &file[dash + 1..],
+        None => file,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = "\
+# Changes on every run
+[env]
+SOURCE_DATE_EPOCH
+  NIX_*_CORES
+
+[inputs]
+bootstrap-tools*.drv
+[args]
+--jobs=*
+[sources]
+[A-Z]*.patch
+";
+
+    #[test]
+    fn sections_collect_their_patterns() {
+        let rules = IgnoreRules::parse(FILE, ".nixdiffignore").unwrap();
+        assert_eq!(rules.env, ["SOURCE_DATE_EPOCH", "NIX_*_CORES"]);
+        assert_eq!(rules.inputs, ["bootstrap-tools*.drv"]);
+        assert_eq!(rules.args, ["--jobs=*"]);
+        assert_eq!(rules.sources, ["[A-Z]*.patch"]);
+        assert!(IgnoreRules::parse("# nothing\n\n", "f").unwrap().is_empty());
+    }
+
+    #[test]
+    fn errors_name_the_file_and_line() {
+        let err = IgnoreRules::parse("[env]\nA\n[vars]\nB\n", "/src/.nixdiffignore").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "/src/.nixdiffignore:3: Unknown ignore section: vars (expected env, inputs, args or sources)"
+        );
+        let err = IgnoreRules::parse("# top\nSOURCE_DATE_EPOCH\n", "f").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "f:2: pattern SOURCE_DATE_EPOCH comes before any section ([env], [inputs], [args] or [sources])"
+        );
+    }
+
+    #[test]
+    fn options_add_to_file_rules() {
+        let mut rules = IgnoreRules::parse("[env]\nA\n", "f").unwrap();
+        rules.parse_option("env:B*").unwrap();
+        rules.parse_option("sources:x:y").unwrap();
+        assert_eq!(rules.env, ["A", "B*"]);
+        assert_eq!(rules.sources, ["x:y"]);
+        for bad in ["env", "env:", "vars:A"] {
+            assert!(rules.parse_option(bad).is_err(), "{bad}");
+        }
+
+        let mut merged = IgnoreRules::default();
+        merged.extend(rules.clone());
+        assert_eq!(merged, rules);
+    }
+
+    #[test]
+    fn strip_removes_what_matches() {
+        let mut drv = Derivation::default();
+        for (key, value) in [
+            ("SOURCE_DATE_EPOCH", "1"),
+            ("NIX_BUILD_CORES", "8"),
+            ("pname", "hello"),
+        ] {
+            drv.env.insert(key.as_bytes().into(), value.into());
+        }
+        drv.input_derivations.insert(
+            b"/nix/store/aaaa-bootstrap-tools.drv".to_vec().into(),
+            [b"out".to_vec()].into(),
+        );
+        drv.input_derivations.insert(
+            b"/nix/store/bbbb-bash-5.2.drv".to_vec().into(),
+            [b"out".to_vec()].into(),
+        );
+        drv.args = vec![b"-e".to_vec(), b"--jobs=8".to_vec()];
+        drv.input_sources
+            .insert(b"/nix/store/cccc-Fix.patch".as_slice().into());
+        drv.input_sources
+            .insert(b"/nix/store/dddd-build.sh".as_slice().into());
+
+        let rules = IgnoreRules::parse(FILE, "f").unwrap();
+        let stripped = rules.strip(&drv);
+        let keys: Vec<&[u8]> = stripped.env.keys().map(|key| &key[..]).collect();
+        assert_eq!(keys, [b"pname"]);
+        let inputs: Vec<&[u8]> = stripped
+            .input_derivations
+            .keys()
+            .map(|path| &path[..])
+            .collect();
+        assert_eq!(inputs, [b"/nix/store/bbbb-bash-5.2.drv"]);
+        assert_eq!(stripped.args, [b"-e"]);
+        let sources: Vec<&[u8]> = stripped
+            .input_sources
+            .iter()
+            .map(|path| &path[..])
+            .collect();
+        assert_eq!(sources, [b"/nix/store/dddd-build.sh"]);
+
+        assert!(matches!(
+            IgnoreRules::default().strip(&drv),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn env_filter_searches_names_with_regexes() {
+        let mut filter = EnvFilter::default();
+        assert!(!filter.ignores(b"NIX_BUILD_CORES"));
+        filter.add_pattern("^NIX_").unwrap();
+        filter.add_pattern("^SOURCE_DATE_EPOCH$").unwrap();
+        assert!(filter.ignores(b"NIX_BUILD_CORES"));
+        assert!(filter.ignores(b"SOURCE_DATE_EPOCH"));
+        assert!(!filter.ignores(b"MY_SOURCE_DATE_EPOCH"));
+        assert!(!filter.ignores(b"pname"));
+
+        let err = filter.add_pattern("NIX_(").unwrap_err();
+        assert_eq!(err.to_string(), "Invalid --ignore-env regex: NIX_(");
+        assert_eq!(filter.patterns.len(), 2);
+
+        filter.skip_all = true;
+        assert!(filter.ignores(b"pname"));
+    }
+
+    #[test]
+    fn the_nearest_file_is_found() {
+        let root = tempfile::TempDir::new().unwrap();
+        let nested = root.path().join("a/b");
+        std::fs::create_dir_all(&nested).unwrap();
+        assert_eq!(discover(&nested), None);
+        std::fs::write(root.path().join(FILE_NAME), "").unwrap();
+        assert_eq!(discover(&nested), Some(root.path().join(FILE_NAME)));
+        std::fs::write(root.path().join("a").join(FILE_NAME), "").unwrap();
+        assert_eq!(
+            discover(&nested),
+            Some(root.path().join("a").join(FILE_NAME))
+        );
+    }
+}
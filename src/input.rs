@@ -0,0 +1,284 @@
+//! Decide what kind of thing a command-line argument refers to.
+//!
+//! Guessing from substrings (`#` means flake, `.nix` means file) misfires
+//! for flake refs without an attribute (`nixpkgs`) and for paths that
+//! happen to contain `#`. Instead we try each kind in a fixed order and
+//! only accept a flake ref once it passes a syntax check.
+
+use crate::types::Derivation;
+use anyhow::{Result, anyhow, bail};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputKind {
+    /// A .drv file on disk.
+    Drv,
+    /// A store path whose deriver we query.
+    StorePath,
+    /// A .nix file (or directory) for nix-instantiate.
+    NixFile,
+    /// A flake reference, optionally with `#attr`.
+    Flake,
+    /// An http(s) URL of a .drv file.
+    Url,
+    /// A file with `nix derivation show` output.
+    Json,
+}
+
+impl FromStr for InputKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "drv" => Ok(InputKind::Drv),
+            "store" => Ok(InputKind::StorePath),
+            "nix" => Ok(InputKind::NixFile),
+            "flake" => Ok(InputKind::Flake),
+            "url" => Ok(InputKind::Url),
+            "json" => Ok(InputKind::Json),
+            _ => bail!("Invalid input type: {s} (expected drv, store, nix, flake, url, or json)"),
+        }
+    }
+}
+
+impl fmt::Display for InputKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InputKind::Drv => "drv",
+            InputKind::StorePath => "store",
+            InputKind::NixFile => "nix",
+            InputKind::Flake => "flake",
+            InputKind::Url => "url",
+            InputKind::Json => "json",
+        })
+    }
+}
+
+/// The store directory paths are expected under, honoring `NIX_STORE_DIR`.
+pub fn store_dir() -> String {
+    std::env::var("NIX_STORE_DIR").unwrap_or_else(|_| "/nix/store".to_string())
+}
+
+/// The store directory `drv`'s own paths are in: that of its first output
+/// path, or if no output has a path yet (floating content-addressed
+/// outputs), of its first input derivation or source.
+pub fn embedded_store_dir(drv: &Derivation) -> Option<String> {
+    let outputs = drv.outputs.values().map(|o| o.path.as_slice());
+    let inputs = drv.input_derivations.keys().chain(&drv.input_sources);
+    let path = outputs
+        .chain(inputs.map(|path| &path[..]))
+        .find(|p| !p.is_empty())?;
+    let slash = path.iter().rposition(|&b| b == b'/').filter(|&i| i > 0)?;
+    Some(String::from_utf8_lossy(&path[..slash]).into_owned())
+}
+
+/// Whether store directories `a` and `b` are the same, allowing for a
+/// trailing slash or a symlink in between.
+pub fn same_store_dir(a: &str, b: &str) -> bool {
+    let trim = |d: &str| Path::new(d.trim_end_matches('/')).to_path_buf();
+    let (a, b) = (trim(a), trim(b));
+    a == b
+        || a.canonicalize()
+            .is_ok_and(|a| b.canonicalize().is_ok_and(|b| a == b))
+}
+
+/// Classify `input`, trying in order: a .drv URL, an existing .drv file,
+/// an existing .json file, an existing .nix file or directory, a path
+/// inside `store_dir`, and a syntactically valid flake reference. The error lists why each kind was
+/// rejected.
+pub fn classify(input: &str, store_dir: &str) -> Result<InputKind> {
+    // Checked first: `https://` is a valid flake reference type too.
+    if crate::remote::is_drv_url(input) {
+        return Ok(InputKind::Url);
+    }
+    let path = Path::new(input);
+    if input.ends_with(".drv") && path.is_file() {
+        return Ok(InputKind::Drv);
+    }
+    if input.ends_with(".json") && path.is_file() {
+        return Ok(InputKind::Json);
+    }
+    if input.ends_with(".nix") && path.exists() {
+        return Ok(InputKind::NixFile);
+    }
+    // `./result` symlinks point into the store, so look at the target too.
+    let in_store = |p: &Path| p.starts_with(store_dir) && p != Path::new(store_dir);
+    if in_store(path) || path.canonicalize().is_ok_and(|p| in_store(&p)) {
+        return Ok(InputKind::StorePath);
+    }
+    match validate_flake_ref(input) {
+        Ok(()) => Ok(InputKind::Flake),
+        Err(flake_err) => Err(anyhow!(
+            "Cannot determine what {input:?} is (override with --input-type): \
+             not an existing .drv file; \
+             not an existing .nix file; \
+             not inside the store directory {store_dir}; \
+             not a flake reference ({flake_err})"
+        )),
+    }
+}
+
+/// Check flake reference syntax without evaluating anything.
+fn validate_flake_ref(input: &str) -> std::result::Result<(), String> {
+    let (base, attr) = match input.split_once('#') {
+        Some((base, attr)) => (base, Some(attr)),
+        None => (input, None),
+    };
+    if attr == Some("") {
+        return Err("empty attribute path after '#'".to_string());
+    }
+    if base.is_empty() {
+        return Err("empty flake reference".to_string());
+    }
+
+    if base.starts_with('.') || base.starts_with('/') {
+        // Without an attribute there is nothing to tell a flake directory
+        // apart from a typo, so insist that it exists.
+        return if attr.is_some() || Path::new(base).is_dir() {
+            Ok(())
+        } else {
+            Err(format!("{base} is not a directory"))
+        };
+    }
+
+    if let Some((scheme, rest)) = base.split_once(':') {
+        let known = matches!(
+            scheme,
+            "path"
+                | "github"
+                | "gitlab"
+                | "sourcehut"
+                | "flake"
+                | "git"
+                | "hg"
+                | "tarball"
+                | "file"
+                | "http"
+                | "https"
+        ) || ["git+", "hg+", "file+", "tarball+"]
+            .iter()
+            .any(|p| scheme.starts_with(p));
+        if !known {
+            return Err(format!("unknown flake reference type '{scheme}:'"));
+        }
+        if rest.is_empty() {
+            return Err(format!("nothing after '{scheme}:'"));
+        }
+        return Ok(());
+    }
+
+    // Indirect reference through the registry: `id[/ref-or-rev[/rev]]`.
+    let mut parts = base.split('/');
+    let id = parts.next().unwrap_or_default();
+    let valid_id = id.starts_with(|c: char| c.is_ascii_alphabetic())
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid_id {
+        return Err(format!("'{id}' is not a valid flake id"));
+    }
+    let rest: Vec<_> = parts.collect();
+    if rest.len() > 2 || rest.iter().any(|p| p.is_empty()) {
+        return Err(format!("malformed ref/rev in '{base}'"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_tricky_inputs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = tmp.path().join("store");
+        let odd = tmp.path().join("dir#with#hashes");
+        std::fs::create_dir_all(&store).unwrap();
+        std::fs::create_dir_all(&odd).unwrap();
+        let drv = store.join("aaaa-hello.drv");
+        let out = store.join("aaaa-hello");
+        let nix = odd.join("default.nix");
+        let json = tmp.path().join("hello.json");
+        std::fs::write(&drv, "").unwrap();
+        std::fs::write(&out, "").unwrap();
+        std::fs::write(&nix, "").unwrap();
+        std::fs::write(&json, "").unwrap();
+        let result = tmp.path().join("result");
+        std::os::unix::fs::symlink(&out, &result).unwrap();
+        let s = |p: &Path| p.to_str().unwrap().to_string();
+        let store_dir = s(&store);
+
+        let cases: Vec<(String, Option<InputKind>)> = vec![
+            (s(&drv), Some(InputKind::Drv)),
+            (s(&nix), Some(InputKind::NixFile)),
+            (s(&json), Some(InputKind::Json)),
+            (s(&out), Some(InputKind::StorePath)),
+            (s(&result), Some(InputKind::StorePath)),
+            // Missing .drv inside the store is still worth a deriver query.
+            (
+                format!("{store_dir}/bbbb-gone.drv"),
+                Some(InputKind::StorePath),
+            ),
+            (
+                "https://cache.example/nix/store/aaaa-hello.drv".into(),
+                Some(InputKind::Url),
+            ),
+            (
+                "https://example.com/nixpkgs.tar.gz".into(),
+                Some(InputKind::Flake),
+            ),
+            ("nixpkgs".into(), Some(InputKind::Flake)),
+            ("nixpkgs#hello".into(), Some(InputKind::Flake)),
+            ("nixpkgs/nixos-25.11#hello".into(), Some(InputKind::Flake)),
+            ("github:NixOS/nixpkgs#hello".into(), Some(InputKind::Flake)),
+            (
+                "git+https://example.com/repo".into(),
+                Some(InputKind::Flake),
+            ),
+            (
+                ".#packages.x86_64-linux.default".into(),
+                Some(InputKind::Flake),
+            ),
+            (s(tmp.path()), Some(InputKind::Flake)),
+            ("missing.nix".into(), None),
+            ("missing.json".into(), None),
+            ("nixpkgs#".into(), None),
+            ("bogus:thing".into(), None),
+            ("./missing-dir".into(), None),
+            ("".into(), None),
+        ];
+        for (input, want) in cases {
+            let got = classify(&input, &store_dir).ok();
+            assert_eq!(got, want, "classify({input:?})");
+        }
+    }
+
+    #[test]
+    fn finds_the_store_dir_a_derivation_was_made_for() {
+        let mut drv = Derivation::default();
+        assert_eq!(embedded_store_dir(&drv), None);
+        drv.input_sources
+            .insert(b"/tmp/store/aaaa-builder.sh".as_slice().into());
+        assert_eq!(embedded_store_dir(&drv).as_deref(), Some("/tmp/store"));
+        // Outputs win, but only once they have a path.
+        drv.outputs.insert(b"out".to_vec(), Default::default());
+        assert_eq!(embedded_store_dir(&drv).as_deref(), Some("/tmp/store"));
+        drv.outputs.get_mut(b"out".as_slice()).unwrap().path = b"/nix/store/bbbb-hello".to_vec();
+        assert_eq!(embedded_store_dir(&drv).as_deref(), Some("/nix/store"));
+
+        assert!(same_store_dir("/nix/store", "/nix/store/"));
+        assert!(!same_store_dir("/nix/store", "/tmp/store"));
+    }
+
+    #[test]
+    fn error_lists_every_attempted_kind() {
+        let err = classify("missing.nix", "/nix/store")
+            .unwrap_err()
+            .to_string();
+        for needle in [".drv", ".nix", "/nix/store", "flake", "--input-type"] {
+            assert!(err.contains(needle), "{needle:?} missing from: {err}");
+        }
+    }
+}
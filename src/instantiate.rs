@@ -1,70 +1,166 @@
+//! Everything that runs Nix: evaluating `.nix` files and flakes to .drv
+//! files, and asking the store for the deriver of an output. The rest of
+//! the crate only reads files, stores and caches.
+//!
+//! Children are spawned through [`crate::interrupt::output`], so the
+//! command line's Ctrl-C and `--timeout` handling kills them.
+
 use anyhow::{Context, Result, anyhow, bail};
 use std::fs;
 use std::path::Path;
 use std::process::Command;
+use std::sync::OnceLock;
 use tempfile::TempDir;
 
+use crate::console;
+use crate::flake_lock::{FlakeLock, parse_lock};
+use crate::input::InputKind;
+use crate::interrupt;
 use crate::parser::parse_derivation;
 use crate::types::Derivation;
+use crate::version::compare_versions;
 
-/// Instantiate a .nix file, flake, or expression and parse the resulting .drv file
-pub fn instantiate_and_parse(input: &str) -> Result<Derivation> {
-    let temp_dir = TempDir::new().context("Failed to create temporary directory")?;
-    let gcroot_path = temp_dir.path().join("result");
-
-    let drv_path = if input.contains('#') {
-        // Treat as flake reference if it contains #
-        instantiate_flake(input, &gcroot_path)?
-    } else if input.ends_with(".nix") {
-        // Treat as regular Nix file
-        instantiate_file(input, &gcroot_path)?
-    } else {
-        // Try as store path first
-        return Err(anyhow!(
-            "Input must be a .drv file, .nix file, or flake reference"
-        ));
-    };
+/// The oldest Nix that takes `--extra-experimental-features` and has
+/// `nix flake metadata`.
+pub const MIN_NIX_VERSION: &str = "2.4";
+
+/// The experimental features every Nix call is run with.
+const EXPERIMENTAL_FEATURES: &str = "nix-command flakes";
+
+/// Instantiate a .nix file or flake reference and parse the resulting .drv file
+pub fn instantiate_and_parse(input: &str, kind: InputKind) -> Result<Derivation> {
+    instantiate_to_drv(input, kind).map(|(_, drv)| drv)
+}
+
+/// Like [`instantiate_and_parse`], but also return the path of the .drv file.
+pub fn instantiate_to_drv(input: &str, kind: InputKind) -> Result<(String, Derivation)> {
+    match kind {
+        InputKind::Flake => instantiate_locked_flake(&lock_flake(input)?),
+        InputKind::NixFile => with_gcroot(|gcroot_path| instantiate_file(input, gcroot_path)),
+        InputKind::Drv | InputKind::StorePath | InputKind::Url | InputKind::Json => {
+            bail!("{input} is a {kind} input and needs no instantiation")
+        }
+    }
+}
 
-    // Parse the resulting .drv file
-    parse_derivation(&drv_path)
+/// What `nix --version` prints, e.g. `nix (Nix) 2.24.9`, or `None` if it
+/// can't be run. Nix is asked once per process.
+pub fn nix_version() -> Option<String> {
+    static VERSION: OnceLock<Option<String>> = OnceLock::new();
+    VERSION
+        .get_or_init(|| {
+            let output = interrupt::output(Command::new("nix").arg("--version")).ok()?;
+            let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            (output.status.success() && !version.is_empty()).then_some(version)
+        })
+        .clone()
 }
 
-/// Instantiate a flake reference
-fn instantiate_flake(flake_ref: &str, gcroot_path: &Path) -> Result<String> {
-    // Extract attribute from flake reference
+/// A flake reference pinned to the store path and hash that
+/// `nix flake metadata` reported, so that evaluating it is pure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockedFlake {
+    /// `path:/nix/store/<hash>-source?narHash=sha256-…`
+    pub locked_ref: String,
+    /// The attribute path to instantiate.
+    pub attr: String,
+    /// Its direct inputs, or `None` if its lock couldn't be read.
+    pub inputs: Option<FlakeLock>,
+}
+
+impl LockedFlake {
+    fn expression(&self) -> String {
+        format!("(builtins.getFlake \"{}\").{}", self.locked_ref, self.attr)
+    }
+}
+
+/// Lock a flake reference without evaluating it. Fetches the flake if it
+/// isn't in the store yet.
+pub fn lock_flake(flake_ref: &str) -> Result<LockedFlake> {
+    // Extract attribute from flake reference; without one, use the
+    // default package like `nix build` does.
     let (flake_path, attr) = flake_ref
         .split_once('#')
-        .ok_or_else(|| anyhow!("Invalid flake reference: missing #"))?;
+        .unwrap_or((flake_ref, "packages.${builtins.currentSystem}.default"));
 
     // First get flake metadata to resolve to store path and narHash
-    let metadata_output = Command::new("nix")
-        .args([
-            "--extra-experimental-features",
-            "nix-command flakes",
-            "flake",
-            "metadata",
-            "--json",
-            flake_path,
-        ])
-        .output()
-        .context("Failed to run nix flake metadata")?;
+    let metadata_output = interrupt::output(Command::new("nix").args([
+        "--extra-experimental-features",
+        EXPERIMENTAL_FEATURES,
+        "flake",
+        "metadata",
+        "--json",
+        flake_path,
+    ]))
+    .context("Failed to run nix flake metadata")?;
 
     if !metadata_output.status.success() {
-        bail!(
-            "nix flake metadata failed: {}",
-            String::from_utf8_lossy(&metadata_output.stderr)
-        );
+        return Err(nix_failed("nix flake metadata", &metadata_output.stderr));
     }
 
     let metadata_str = String::from_utf8(metadata_output.stdout)
         .context("Failed to parse metadata output as UTF-8")?;
 
     let (store_path, nar_hash) = extract_flake_fields(&metadata_str)?;
+    let inputs = flake_inputs(&metadata_str, &store_path);
 
-    // Create expression to evaluate the flake with narHash for pure evaluation
-    let expression = format!("(builtins.getFlake \"path:{store_path}?narHash={nar_hash}\").{attr}");
+    Ok(LockedFlake {
+        locked_ref: format!("path:{store_path}?narHash={nar_hash}"),
+        attr: attr.to_string(),
+        inputs,
+    })
+}
 
-    instantiate_expression(&expression, gcroot_path)
+/// Instantiate the attribute of a locked flake.
+pub fn instantiate_locked_flake(flake: &LockedFlake) -> Result<(String, Derivation)> {
+    with_gcroot(|gcroot_path| instantiate_expression(&flake.expression(), gcroot_path))
+}
+
+/// The .drv file that built `store_path`, asking `nix-store --query
+/// --deriver` unless it already is one.
+pub fn get_derivation_path(store_path: &str) -> Result<String> {
+    // If it's already a .drv file, return it
+    if store_path.ends_with(".drv") {
+        return Ok(store_path.to_string());
+    }
+
+    // Otherwise, query the derivation
+    let output = interrupt::output(
+        Command::new("nix-store")
+            .arg("--query")
+            .arg("--deriver")
+            .arg(store_path),
+    )
+    .with_context(|| format!("Failed to run nix-store --query --deriver for path: {store_path}"))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Failed to query derivation for {}: {}",
+            store_path,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let drv_path = String::from_utf8(output.stdout)?.trim().to_string();
+
+    if drv_path == "unknown-deriver" {
+        return Err(anyhow!(
+            "No deriver known for {store_path}. \
+             The derivation may have been garbage-collected or the path was \
+             added directly (e.g., via nix-store --add)."
+        ));
+    }
+
+    Ok(drv_path)
+}
+
+/// Run `instantiate` with a temporary GC root for its result, then parse
+/// the .drv file it returns.
+fn with_gcroot(instantiate: impl FnOnce(&Path) -> Result<String>) -> Result<(String, Derivation)> {
+    let temp_dir = TempDir::new().context("Failed to create temporary directory")?;
+    let drv_path = instantiate(&temp_dir.path().join("result"))?;
+    let drv = parse_derivation(&drv_path)?;
+    Ok((drv_path, drv))
 }
 
 #[derive(serde::Deserialize)]
@@ -79,6 +175,54 @@ struct FlakeLocked {
     nar_hash: Option<String>,
 }
 
+/// The error for `command` exiting with `stderr`. Nix's complaint about
+/// disabled experimental features is replaced by a hint, as nix-diff
+/// already asks for them.
+fn nix_failed(command: &str, stderr: &[u8]) -> anyhow::Error {
+    let stderr = String::from_utf8_lossy(stderr);
+    if refuses_experimental_features(&stderr) {
+        anyhow!(experimental_features_hint(
+            command,
+            nix_version().as_deref(),
+            &stderr
+        ))
+    } else {
+        anyhow!("{command} failed: {stderr}")
+    }
+}
+
+/// Whether Nix rejected the experimental features: a Nix that knows them
+/// but has them disabled, or one too old for the flag.
+fn refuses_experimental_features(stderr: &str) -> bool {
+    (stderr.contains("experimental Nix feature") && stderr.contains("is disabled"))
+        || stderr.contains("'--extra-experimental-features'")
+}
+
+/// Why `command` failed on the features, given what `nix --version`
+/// printed.
+fn experimental_features_hint(command: &str, nix_version: Option<&str>, stderr: &str) -> String {
+    let number = nix_version.and_then(|v| v.split_whitespace().last());
+    let cause = match number {
+        Some(n) if compare_versions(n.as_bytes(), MIN_NIX_VERSION.as_bytes()).is_lt() => {
+            format!("Nix {n} is too old for that; nix-diff needs Nix {MIN_NIX_VERSION} or newer")
+        }
+        Some(n) => format!(
+            "Nix {n} refused them, probably because the daemon or nix.conf forbids them; \
+             nix-diff needs Nix {MIN_NIX_VERSION} or newer with {EXPERIMENTAL_FEATURES} allowed"
+        ),
+        None => format!(
+            "`nix --version` didn't report a version; \
+             nix-diff needs Nix {MIN_NIX_VERSION} or newer"
+        ),
+    };
+    format!(
+        "{command} failed: the experimental features {EXPERIMENTAL_FEATURES} are not available. \
+         nix-diff passes --extra-experimental-features \"{EXPERIMENTAL_FEATURES}\" itself, \
+         but {cause}.\nNix said: {}",
+        stderr.trim_end()
+    )
+}
+
 /// Safely extract `path` and `locked.narHash` from flake metadata JSON.
 fn extract_flake_fields(json: &str) -> Result<(String, String)> {
     let metadata: FlakeMetadata =
@@ -96,6 +240,25 @@ fn extract_flake_fields(json: &str) -> Result<(String, String)> {
     Ok((store_path, nar_hash))
 }
 
+/// The direct inputs from the `locks` in flake metadata JSON, or from the
+/// `flake.lock` in `store_path` for a Nix that doesn't report them. A
+/// flake without a lock has no inputs; one whose lock can't be parsed
+/// gets `None`, as the inputs are only shown alongside the diff.
+fn flake_inputs(json: &str, store_path: &str) -> Option<FlakeLock> {
+    let metadata: serde_json::Value = serde_json::from_str(json).ok()?;
+    let lock = match metadata.get("locks") {
+        Some(locks) => locks.clone(),
+        None => match std::fs::read_to_string(Path::new(store_path).join("flake.lock")) {
+            Ok(text) => serde_json::from_str(&text).ok()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Some(FlakeLock::new());
+            }
+            Err(_) => return None,
+        },
+    };
+    parse_lock(&lock).ok()
+}
+
 /// Instantiate a Nix expression
 fn instantiate_expression(expr: &str, gcroot_path: &Path) -> Result<String> {
     let mut cmd = Command::new("nix-instantiate");
@@ -112,15 +275,12 @@ fn instantiate_file(file_path: &str, gcroot_path: &Path) -> Result<String> {
 
 /// Common function to instantiate and process nix-instantiate output
 fn run_nix_instantiate(mut cmd: Command, gcroot_path: &Path) -> Result<String> {
-    cmd.args(["--extra-experimental-features", "nix-command flakes"]);
+    cmd.args(["--extra-experimental-features", EXPERIMENTAL_FEATURES]);
     cmd.args(["--add-root", &gcroot_path.to_string_lossy(), "--indirect"]);
-    let output = cmd.output().context("Failed to run nix-instantiate")?;
+    let output = interrupt::output(&mut cmd).context("Failed to run nix-instantiate")?;
 
     if !output.status.success() {
-        bail!(
-            "nix-instantiate failed: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
+        return Err(nix_failed("nix-instantiate", &output.stderr));
     }
 
     // nix-instantiate may print multiple paths (one per line) when the
@@ -133,9 +293,9 @@ fn run_nix_instantiate(mut cmd: Command, gcroot_path: &Path) -> Result<String> {
         .ok_or_else(|| anyhow!("nix-instantiate produced no output"))?
         .to_string();
     if lines.next().is_some() {
-        eprintln!(
-            "warning: nix-instantiate produced multiple derivations, using the first: {gcroot_result}"
-        );
+        console::warn(format_args!(
+            "nix-instantiate produced multiple derivations, using the first: {gcroot_result}"
+        ));
     }
 
     // Read the symlink to get the actual .drv path
@@ -178,6 +338,102 @@ mod tests {
         assert!(err.to_string().contains("path"));
     }
 
+    #[test]
+    fn locked_flakes_evaluate_the_pinned_source() {
+        let flake = LockedFlake {
+            locked_ref: "path:/nix/store/x-source?narHash=sha256-abc".to_string(),
+            attr: "packages.x86_64-linux.hello".to_string(),
+            inputs: None,
+        };
+        assert_eq!(
+            flake.expression(),
+            "(builtins.getFlake \"path:/nix/store/x-source?narHash=sha256-abc\").packages.x86_64-linux.hello"
+        );
+    }
+
+    #[test]
+    fn flake_inputs_come_from_metadata_or_the_lock_file() {
+        let lock = include_str!("../tests/fixtures/flake-lock/v1.lock");
+        let json = format!(r#"{{"path":"/nonexistent","locks":{lock}}}"#);
+        let inputs = flake_inputs(&json, "/nonexistent").unwrap();
+        assert!(inputs.contains_key("nixpkgs"));
+
+        let source = TempDir::new().unwrap();
+        let path = source.path().to_str().unwrap();
+        let json = format!(r#"{{"path":"{path}"}}"#);
+        assert_eq!(flake_inputs(&json, path), Some(FlakeLock::new()));
+        fs::write(source.path().join("flake.lock"), lock).unwrap();
+        assert_eq!(flake_inputs(&json, path), Some(inputs));
+        fs::write(source.path().join("flake.lock"), "{").unwrap();
+        assert_eq!(flake_inputs(&json, path), None);
+    }
+
+    const DISABLED: &str = "error: experimental Nix feature 'flakes' is disabled; \
+        use '--extra-experimental-features flakes' to override\n";
+    const UNRECOGNISED: &str = "error: unrecognised flag '--extra-experimental-features'\n\
+        Try 'nix --help' for more information.\n";
+
+    #[test]
+    fn experimental_feature_errors_are_recognised() {
+        assert!(refuses_experimental_features(DISABLED));
+        assert!(refuses_experimental_features(UNRECOGNISED));
+        assert!(refuses_experimental_features(
+            "error: unknown flag '--extra-experimental-features'"
+        ));
+        assert!(!refuses_experimental_features(
+            "error: getting status of '/x/flake.nix': No such file or directory"
+        ));
+        let err = nix_failed(
+            "nix flake metadata",
+            b"error: cannot find flake 'flake:nope'\n",
+        );
+        assert_eq!(
+            err.to_string(),
+            "nix flake metadata failed: error: cannot find flake 'flake:nope'\n"
+        );
+    }
+
+    #[test]
+    fn old_nix_is_told_to_upgrade() {
+        let hint = experimental_features_hint(
+            "nix flake metadata",
+            Some("nix (Nix) 2.3.16"),
+            UNRECOGNISED,
+        );
+        assert!(
+            hint.starts_with("nix flake metadata failed: the experimental features"),
+            "{hint}"
+        );
+        assert!(
+            hint.contains("passes --extra-experimental-features \"nix-command flakes\" itself"),
+            "{hint}"
+        );
+        assert!(
+            hint.contains("Nix 2.3.16 is too old for that; nix-diff needs Nix 2.4 or newer"),
+            "{hint}"
+        );
+        assert!(
+            hint.ends_with("Nix said: error: unrecognised flag '--extra-experimental-features'\nTry 'nix --help' for more information."),
+            "{hint}"
+        );
+    }
+
+    #[test]
+    fn recent_nix_points_at_the_configuration() {
+        let hint =
+            experimental_features_hint("nix-instantiate", Some("nix (Nix) 2.24.9"), DISABLED);
+        assert!(
+            hint.contains("Nix 2.24.9 refused them, probably because the daemon or nix.conf"),
+            "{hint}"
+        );
+        assert!(!hint.contains("too old"), "{hint}");
+        let hint = experimental_features_hint("nix-instantiate", None, DISABLED);
+        assert!(
+            hint.contains("`nix --version` didn't report a version"),
+            "{hint}"
+        );
+    }
+
     #[test]
     fn flake_metadata_happy_path() {
         let json = r#"{"path":"/nix/store/x","locked":{"narHash":"sha256-abc"}}"#;
@@ -0,0 +1,130 @@
+//! Byte strings stored once per run. A system closure names the same
+//! few thousand store paths over and over, from every derivation that
+//! depends on them; parsed derivations share one copy of each path and
+//! env key, and the diff's own bookkeeping refers to them by a small id.
+
+use crate::types::{ByteStr, Derivation};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The id of a string in an [`Interner`]. Equal ids are equal strings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Id(u32);
+
+/// Every string interned so far, each stored once.
+#[derive(Debug, Default)]
+pub struct Interner {
+    ids: HashMap<ByteStr, Id>,
+    strings: Vec<ByteStr>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The id of `bytes`, storing a copy the first time it is seen.
+    pub fn intern(&mut self, bytes: &[u8]) -> Id {
+        if let Some(&id) = self.ids.get(bytes) {
+            return id;
+        }
+        let id = Id(u32::try_from(self.strings.len()).expect("more than 2^32 interned strings"));
+        let bytes = ByteStr::from(bytes);
+        self.strings.push(Arc::clone(&bytes));
+        self.ids.insert(bytes, id);
+        id
+    }
+
+    /// The stored copy of `bytes`, storing one the first time it is seen.
+    pub fn share(&mut self, bytes: &[u8]) -> ByteStr {
+        let id = self.intern(bytes);
+        Arc::clone(self.resolve(id))
+    }
+
+    /// Replace the input paths and env keys of `drv` with the stored
+    /// copies, so they are shared with every derivation that names them.
+    pub fn share_derivation(&mut self, drv: &mut Derivation) {
+        drv.input_sources = std::mem::take(&mut drv.input_sources)
+            .into_iter()
+            .map(|path| self.share(&path))
+            .collect();
+        drv.input_derivations = std::mem::take(&mut drv.input_derivations)
+            .into_iter()
+            .map(|(path, outputs)| (self.share(&path), outputs))
+            .collect();
+        drv.env = std::mem::take(&mut drv.env)
+            .into_iter()
+            .map(|(key, value)| (self.share(&key), value))
+            .collect();
+    }
+
+    /// The id of `bytes` if it was interned, without storing it.
+    pub fn get(&self, bytes: &[u8]) -> Option<Id> {
+        self.ids.get(bytes).copied()
+    }
+
+    /// The string `id` stands for.
+    pub fn resolve(&self, id: Id) -> &ByteStr {
+        &self.strings[id.0 as usize]
+    }
+
+    /// How many different strings were interned.
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_strings_share_one_copy() {
+        let mut interner = Interner::new();
+        let a = interner.intern(b"/nix/store/aaaa-glibc.drv");
+        let b = interner.intern(b"/nix/store/bbbb-gcc.drv");
+        assert_ne!(a, b);
+        assert_eq!(interner.intern(b"/nix/store/aaaa-glibc.drv"), a);
+        assert_eq!(interner.len(), 2);
+        assert_eq!(&interner.resolve(b)[..], b"/nix/store/bbbb-gcc.drv");
+        assert_eq!(interner.get(b"/nix/store/aaaa-glibc.drv"), Some(a));
+        assert_eq!(interner.get(b"/nix/store/cccc-zlib.drv"), None);
+        assert_eq!(interner.len(), 2);
+    }
+
+    #[test]
+    fn derivations_share_their_paths_and_env_keys() {
+        let drv = || {
+            let mut drv = Derivation::default();
+            drv.input_sources
+                .insert(ByteStr::from(b"/nix/store/aaaa-src".as_slice()));
+            drv.input_derivations.insert(
+                ByteStr::from(b"/nix/store/bbbb-gcc.drv".as_slice()),
+                [b"out".to_vec()].into(),
+            );
+            drv.env
+                .insert(ByteStr::from(b"name".as_slice()), b"hello".to_vec());
+            drv
+        };
+        let (mut a, mut b) = (drv(), drv());
+        let mut interner = Interner::new();
+        interner.share_derivation(&mut a);
+        interner.share_derivation(&mut b);
+        assert_eq!(a, drv());
+        assert_eq!(interner.len(), 3);
+        let first = |drv: &Derivation| {
+            [
+                Arc::clone(drv.input_sources.first().unwrap()),
+                Arc::clone(drv.input_derivations.first_key_value().unwrap().0),
+                Arc::clone(drv.env.first_key_value().unwrap().0),
+            ]
+        };
+        for (a, b) in first(&a).iter().zip(&first(&b)) {
+            assert!(Arc::ptr_eq(a, b));
+        }
+    }
+}
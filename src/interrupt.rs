@@ -0,0 +1,378 @@
+//! Cleanup on Ctrl-C / SIGTERM.
+//!
+//! Evaluating a flake can take minutes and users routinely give up with
+//! Ctrl-C. The terminal delivers SIGINT to the whole foreground process
+//! group, but SIGTERM (e.g. from a CI timeout) only reaches us, which would
+//! leave nix-instantiate running as an orphan. We therefore keep track of
+//! every child we spawn and kill them ourselves before exiting.
+//!
+//! `--timeout` is enforced by whoever waits on a child: [`output`] polls
+//! it with `try_wait` and kills it once the deadline has passed, so the
+//! wait fails, while the diff itself stops comparing inputs (see
+//! [`crate::diff::DiffContext::deadline`]) and reports what it has.
+//!
+//! Programs embedding the diff stop it with a [`CancellationToken`]
+//! instead (see [`crate::diff::DiffContext::cancellation`]); the command
+//! line cancels [`CANCEL`] on Ctrl-C, which every spawn checks as well.
+//!
+//! Everything here is process-global and meant for the command line; the
+//! library only spawns through [`output`].
+
+use crate::cancel::{CancellationToken, Cancelled};
+use anyhow::{Context, Result, anyhow};
+use std::collections::HashMap;
+use std::io::{self, IsTerminal, Read};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Exit status after an interrupt, following the shell's 128 + SIGINT convention.
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// Exit status when `--timeout` ran out, as with timeout(1).
+pub const EXIT_TIMED_OUT: i32 = 124;
+
+/// The `--timeout` and its deadline, once [`start_timeout`] was called.
+static TIMEOUT: OnceLock<(Duration, Instant)> = OnceLock::new();
+
+/// How often a running child is checked for having exited, and the
+/// deadline and [`CANCEL`] for having passed.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Children spawned through [`output`], killed by the interrupt handler.
+pub static CHILDREN: LazyLock<ChildRegistry> = LazyLock::new(ChildRegistry::default);
+
+/// Cancelled by the interrupt handler; children are not started through
+/// [`output`] once it is.
+pub static CANCEL: LazyLock<CancellationToken> = LazyLock::new(CancellationToken::new);
+
+/// Something that must be torn down when the user interrupts us.
+pub trait Killable: Send {
+    fn kill(&mut self) -> io::Result<()>;
+}
+
+/// A running child process, shared with the thread polling it so the
+/// handler thread can kill it. Both take the lock, and [`Child::kill`]
+/// does nothing once `try_wait` has reaped the child, so a pid that may
+/// have been reused is never signalled.
+struct SharedChild(Arc<Mutex<Child>>);
+
+impl SharedChild {
+    fn lock(&self) -> MutexGuard<'_, Child> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Killable for SharedChild {
+    fn kill(&mut self) -> io::Result<()> {
+        self.lock().kill()
+    }
+}
+
+#[derive(Default)]
+pub struct ChildRegistry {
+    next_id: AtomicU64,
+    children: Mutex<HashMap<u64, Box<dyn Killable>>>,
+}
+
+/// Keeps a child registered for as long as it is alive; dropping it
+/// removes the entry again.
+pub struct Registration<'a> {
+    registry: &'a ChildRegistry,
+    id: u64,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.registry.lock().remove(&self.id);
+    }
+}
+
+impl ChildRegistry {
+    pub fn register(&self, child: Box<dyn Killable>) -> Registration<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.lock().insert(id, child);
+        Registration { registry: self, id }
+    }
+
+    /// Kill every registered child and forget about them.
+    /// Returns how many children were signalled successfully.
+    pub fn kill_all(&self) -> usize {
+        self.lock()
+            .drain()
+            .filter_map(|(_, mut child)| child.kill().ok())
+            .count()
+    }
+
+    /// [`Command::output`], with the child registered here while it runs.
+    /// Past the `--timeout` deadline, children are not started, and those
+    /// killed by it fail with [`io::ErrorKind::TimedOut`]. Once [`CANCEL`]
+    /// is cancelled, they are not started or are killed alike.
+    pub fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+        if timed_out() {
+            return Err(timeout_error());
+        }
+        if CANCEL.is_cancelled() {
+            return Err(cancelled_error());
+        }
+        let output = self.output_until(cmd, || timed_out() || CANCEL.is_cancelled())?;
+        if !output.status.success() {
+            if timed_out() {
+                return Err(timeout_error());
+            }
+            if CANCEL.is_cancelled() {
+                return Err(cancelled_error());
+            }
+        }
+        Ok(output)
+    }
+
+    /// Run `cmd` to completion, polling it with `try_wait` and killing it
+    /// once `stop` returns true. Its output is read on threads of their
+    /// own, so a child filling a pipe can't stall the polling.
+    fn output_until(&self, cmd: &mut Command, stop: impl Fn() -> bool) -> io::Result<Output> {
+        let mut child = cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let (stdout, stderr) = (child.stdout.take(), child.stderr.take());
+        let child = SharedChild(Arc::new(Mutex::new(child)));
+        let _registration = self.register(Box::new(SharedChild(child.0.clone())));
+        std::thread::scope(|scope| {
+            let stdout = scope.spawn(|| read_pipe(stdout));
+            let stderr = scope.spawn(|| read_pipe(stderr));
+            let status = wait_until(&child, stop);
+            if status.is_err() {
+                // The readers only finish once the child is gone.
+                let _ = child.lock().kill();
+            }
+            let (stdout, stderr) = (join(stdout), join(stderr));
+            Ok(Output {
+                status: status?,
+                stdout: stdout?,
+                stderr: stderr?,
+            })
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, Box<dyn Killable>>> {
+        // A panic while holding the lock cannot leave the map inconsistent,
+        // and the interrupt handler must never give up on cleanup.
+        self.children.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Poll `child` until it exits, killing it once `stop` returns true.
+fn wait_until(child: &SharedChild, stop: impl Fn() -> bool) -> io::Result<ExitStatus> {
+    let mut killed = false;
+    loop {
+        if let Some(status) = child.lock().try_wait()? {
+            return Ok(status);
+        }
+        if !killed && stop() {
+            child.lock().kill()?;
+            killed = true;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn join<T>(thread: std::thread::ScopedJoinHandle<'_, T>) -> T {
+    thread
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+fn read_pipe(pipe: Option<impl Read>) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    if let Some(mut pipe) = pipe {
+        pipe.read_to_end(&mut buf)?;
+    }
+    Ok(buf)
+}
+
+/// Drop-in replacement for [`Command::output`] that registers the child in
+/// [`CHILDREN`] so an interrupt kills it instead of leaving it behind.
+pub fn output(cmd: &mut Command) -> io::Result<Output> {
+    CHILDREN.output(cmd)
+}
+
+/// Start the `--timeout` clock: once `timeout` has passed, [`timed_out`]
+/// is true and [`output`] kills the children it waits on. Returns the
+/// deadline, for the diff, or `None` if it is too far off to ever be
+/// reached.
+pub fn start_timeout(timeout: Duration) -> Option<Instant> {
+    let deadline = Instant::now().checked_add(timeout)?;
+    Some(TIMEOUT.get_or_init(|| (timeout, deadline)).1)
+}
+
+/// Whether the `--timeout` deadline has passed.
+pub fn timed_out() -> bool {
+    TIMEOUT
+        .get()
+        .is_some_and(|&(_, deadline)| Instant::now() >= deadline)
+}
+
+fn cancelled_error() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, Cancelled)
+}
+
+fn timeout_error() -> io::Error {
+    let timeout = TIMEOUT
+        .get()
+        .map(|&(timeout, _)| timeout)
+        .unwrap_or_default();
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("timed out after {} (--timeout)", format_duration(timeout)),
+    )
+}
+
+/// Parse a `--timeout` such as `90`, `90s`, `500ms`, `2m` or `1h`; a bare
+/// number is seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let invalid = || anyhow!("Invalid duration: {s} (expected e.g. 90s, 500ms, 2m or 1h)");
+    let n: u64 = number.parse().map_err(|_| invalid())?;
+    let seconds = |factor: u64| n.checked_mul(factor).map(Duration::from_secs);
+    match unit {
+        "ms" => Some(Duration::from_millis(n)),
+        "" | "s" => seconds(1),
+        "m" => seconds(60),
+        "h" => seconds(3600),
+        _ => None,
+    }
+    .ok_or_else(invalid)
+}
+
+/// `duration` the way [`parse_duration`] reads it, e.g. `120s`.
+pub fn format_duration(duration: Duration) -> String {
+    if duration.subsec_millis() == 0 {
+        format!("{}s", duration.as_secs())
+    } else {
+        format!("{}ms", duration.as_millis())
+    }
+}
+
+/// Install the SIGINT/SIGTERM handler: cancel [`CANCEL`], kill in-flight
+/// children, reset any half-written colored line, and exit with
+/// [`EXIT_INTERRUPTED`].
+pub fn install_handler() -> Result<()> {
+    let reset_terminal = io::stdout().is_terminal();
+    ctrlc::set_handler(move || {
+        CANCEL.cancel();
+        CHILDREN.kill_all();
+        if reset_terminal {
+            // Write to the fd directly: the main thread may hold the stdout
+            // lock while blocked on a full pipe.
+            let reset = b"\x1b[0m\n";
+            // SAFETY: the buffer is valid for its length; errors are ignored
+            // because we are about to exit anyway.
+            unsafe { libc::write(libc::STDOUT_FILENO, reset.as_ptr().cast(), reset.len()) };
+        }
+        std::process::exit(EXIT_INTERRUPTED);
+    })
+    .context("Failed to install interrupt handler")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    struct MockChild(Arc<AtomicBool>);
+
+    impl Killable for MockChild {
+        fn kill(&mut self) -> io::Result<()> {
+            self.0.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn mock() -> (Box<dyn Killable>, Arc<AtomicBool>) {
+        let killed = Arc::new(AtomicBool::new(false));
+        (Box::new(MockChild(killed.clone())), killed)
+    }
+
+    #[test]
+    fn kill_all_kills_live_children_only() {
+        // A child whose registration was dropped has already been reaped;
+        // killing it again could hit a recycled pid.
+        let registry = ChildRegistry::default();
+        let (a, a_killed) = mock();
+        let (b, b_killed) = mock();
+        let _a = registry.register(a);
+        drop(registry.register(b));
+
+        assert_eq!(registry.len(), 1);
+        assert_eq!(registry.kill_all(), 1);
+        assert!(a_killed.load(Ordering::SeqCst));
+        assert!(!b_killed.load(Ordering::SeqCst));
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn durations_parse_with_units() {
+        for (input, want) in [
+            ("90", Duration::from_secs(90)),
+            ("90s", Duration::from_secs(90)),
+            ("500ms", Duration::from_millis(500)),
+            ("2m", Duration::from_secs(120)),
+            ("1h", Duration::from_secs(3600)),
+        ] {
+            assert_eq!(parse_duration(input).unwrap(), want, "{input}");
+        }
+        for input in ["", "s", "-1s", "1.5s", "2 m", "3d", "99999999999999999999h"] {
+            assert!(parse_duration(input).is_err(), "{input}");
+        }
+        assert_eq!(format_duration(Duration::from_secs(120)), "120s");
+        assert_eq!(format_duration(Duration::from_millis(1500)), "1500ms");
+    }
+
+    #[test]
+    fn output_deregisters_finished_children() {
+        // A registry of its own: other tests spawn through CHILDREN.
+        let registry = ChildRegistry::default();
+        let out = registry
+            .output(Command::new("sh").args(["-c", "echo hi"]))
+            .unwrap();
+        assert_eq!(out.stdout, b"hi\n");
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn output_kills_the_child_once_stopped() {
+        let registry = ChildRegistry::default();
+        let start = Instant::now();
+        let out = registry
+            .output_until(Command::new("sleep").arg("10"), || {
+                start.elapsed() > Duration::from_millis(50)
+            })
+            .unwrap();
+        assert!(!out.status.success());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn reaped_children_are_not_signalled() {
+        // Once try_wait has reaped it, the pid may belong to another
+        // process; killing the child must not reach that one.
+        let mut child = SharedChild(Arc::new(Mutex::new(Command::new("true").spawn().unwrap())));
+        while child.lock().try_wait().unwrap().is_none() {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        assert!(child.kill().is_ok());
+    }
+}
@@ -0,0 +1,96 @@
+//! How a comparison was run, echoed into `--format json` and `--bundle`
+//! output so a shared report can be reproduced.
+//!
+//! Paths below the home directory are written as `~/…`, so the block
+//! names no user. `--no-invocation-echo` leaves it out altogether.
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// The options and inputs of one run, and the tools that served it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Invocation {
+    /// nix-diff's version.
+    pub version: String,
+    /// What `nix --version` printed, if Nix is installed.
+    pub nix_version: Option<String>,
+    /// The inputs as given on the command line.
+    pub inputs: [String; 2],
+    /// The .drv files the inputs resolved to.
+    pub drv_paths: [String; 2],
+    /// Command-line options other than the inputs, short flags spelled
+    /// out (see [`long_option`]).
+    pub options: Vec<String>,
+    /// `NIX_STORE_DIR`, or the default.
+    pub store_dir: String,
+}
+
+impl Invocation {
+    /// The invocation of this process, with `nix_version` looked up.
+    pub fn new(inputs: [String; 2], drv_paths: [String; 2], options: &[String]) -> Self {
+        let home = std::env::var_os("HOME");
+        let home = home.as_deref().map(Path::new);
+        let redact = |s: &str| redact_home(s, home);
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            nix_version: nix_diff::instantiate::nix_version(),
+            inputs: inputs.map(|s| redact(&s)),
+            drv_paths: drv_paths.map(|s| redact(&s)),
+            options: options.iter().map(|s| redact(s)).collect(),
+            store_dir: redact(&nix_diff::input::store_dir()),
+        }
+    }
+}
+
+/// The long form of the option `flag` (`--jobs` for `-j`), so the same
+/// settings are always recorded the same way.
+pub fn long_option(flag: &str) -> &str {
+    match flag {
+        "-j" => "--jobs",
+        "-v" => "--verbose",
+        _ => flag,
+    }
+}
+
+/// `s` with a leading `home` written as `~`.
+fn redact_home(s: &str, home: Option<&Path>) -> String {
+    let Some(home) = home.and_then(Path::to_str).map(|h| h.trim_end_matches('/')) else {
+        return s.to_string();
+    };
+    if home.is_empty() {
+        return s.to_string();
+    }
+    match s.strip_prefix(home) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("~{rest}"),
+        _ => s.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_flags_are_spelled_out() {
+        assert_eq!(long_option("-j"), "--jobs");
+        assert_eq!(long_option("-v"), "--verbose");
+        assert_eq!(long_option("--color"), "--color");
+    }
+
+    #[test]
+    fn home_is_redacted() {
+        let home = Some(Path::new("/home/alice/"));
+        assert_eq!(
+            redact_home("/home/alice/src/default.nix", home),
+            "~/src/default.nix"
+        );
+        assert_eq!(redact_home("/home/alice", home), "~");
+        assert_eq!(
+            redact_home("/home/alicexyz/a.nix", home),
+            "/home/alicexyz/a.nix"
+        );
+        assert_eq!(redact_home("./default.nix", home), "./default.nix");
+        assert_eq!(redact_home("/nix/store/a.drv", None), "/nix/store/a.drv");
+        assert_eq!(redact_home("/x", Some(Path::new("/"))), "/x");
+    }
+}
@@ -0,0 +1,279 @@
+//! `--format json`: the whole diff tree as JSON, for CI dashboards and
+//! other tools.
+//!
+//! The shape is that of the serialized diff types: the top-level
+//! `DerivationDiff` with both derivations and every changed section, and
+//! the `InputDiff`s below it with their own `derivation` diffs, however
+//! deep. Maps keyed by byte strings are lists of `[key, value]` pairs.
+//! Byte strings are JSON strings, or `{"lossy": ..., "base64": ...}` when
+//! they are not UTF-8 or contain NUL. Changed outputs stay in name order, with an `order`
+//! field giving their place in the text output (`out` first), and
+//! `output_names` lists the outputs `--output-name` kept, if any.
+//! `flake_inputs` has the old and new pin of each changed flake input
+//! when both sides are flakes. `sandbox` has the old and new value of
+//! each sandbox flag that differs, `null` where it is unset; these flags
+//! are not in `env`, and neither are env vars `--ignore-env` or
+//! `--skip-env` left out; `ignored_env` counts those that differ.
+//! `fetch` does the same for what a fixed-output derivation fetches, by
+//! `key`, with `stale_hash` set when the source changed but the output
+//! hash did not, and `same_digest` on attributes where only the encoding
+//! of the hash changed.
+//! `structured_attrs` lists the attributes in `__json` that differ, by
+//! `path`, when both sides have structured attrs; `__json` is then not in
+//! `env`. An input paired with another version of the same package has a
+//! `version` with the old and new version. Changed inputs past
+//! `--max-derivations` or `--timeout` are in `not_compared` rather than
+//! `changed`, with `timed_out` set for the latter; a top-level
+//! `timed_out: true` says the diff is incomplete because of it. Past
+//! `--compare-depth`, changed inputs are in `changed` without a
+//! `derivation`, and the `inputs` they are in have `depth_limited: true`. A top-level
+//! `stats` object has the totals `--stat` prints. With `--truncate-json`,
+//! `env` keeps only the biggest `--max-env-entries` changes and
+//! `env_truncated` names the rest. An `invocation`
+//! block records how the comparison was run, unless
+//! `--no-invocation-echo` is given.
+
+use crate::glob::glob_match;
+use crate::summary::{DiffStats, DiffSummary};
+use crate::types::{
+    DerivationDiff, EnvVarDiff, EnvironmentDiff, OutputsDiff, compare_output_names,
+};
+use serde_json::Value;
+
+/// `diff` as pretty-printed JSON, ending in a newline.
+///
+/// Env vars that `--env-detail` (the globs in `env_detail`) would only
+/// summarize in the text output are still there in full, with
+/// `"detail": "summarized"` next to their change. Past
+/// `max_env_entries`, if given, only the biggest changes are kept, as in
+/// the text output. `invocation`, if given, goes into the top-level
+/// object as is; the command line puts how it was run there.
+pub fn render_json(
+    diff: &DerivationDiff,
+    env_detail: &[String],
+    max_env_entries: Option<usize>,
+    invocation: Option<&Value>,
+) -> serde_json::Result<Vec<u8>> {
+    let mut value = serde_json::to_value(diff)?;
+    annotate(diff, &mut value, env_detail, max_env_entries);
+    if DiffSummary::of(diff).timed_out {
+        value["timed_out"] = true.into();
+    }
+    value["stats"] = serde_json::to_value(DiffStats::of(diff))?;
+    if let Some(invocation) = invocation {
+        value["invocation"] = invocation.clone();
+    }
+    let mut json = serde_json::to_vec_pretty(&value)?;
+    json.push(b'\n');
+    Ok(json)
+}
+
+/// Add the output order, mark the summarized env vars and cut the env
+/// down to `max_env_entries` in `value`, the serialized form of `diff`,
+/// and in the inputs below it.
+fn annotate(
+    diff: &DerivationDiff,
+    value: &mut Value,
+    env_detail: &[String],
+    max_env_entries: Option<usize>,
+) {
+    let in_detail = |key: &[u8]| {
+        env_detail
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), key))
+    };
+    if let OutputsDiff::Changed(outputs) = &diff.outputs {
+        for (output, output_value) in outputs
+            .iter()
+            .zip(elements(&mut value["outputs"]["Changed"]))
+        {
+            let order = outputs
+                .iter()
+                .filter(|other| compare_output_names(&other.name, &output.name).is_lt())
+                .count();
+            output_value["order"] = order.into();
+        }
+    }
+    // Indexing a missing section would add it, hence the checks.
+    if let Some(env) = diff.env.as_ref().filter(|_| !env_detail.is_empty()) {
+        // Pairs come out in map order.
+        for ((key, var), pair) in env.iter().zip(elements(&mut value["env"])) {
+            let summarized = !matches!(var, None | Some(EnvVarDiff::Renamed(_))) && !in_detail(key);
+            if let Some(change) = pair[1].as_object_mut().filter(|_| summarized) {
+                change.insert("detail".into(), "summarized".into());
+            }
+        }
+    }
+    if let Some((env, max)) = diff.env.as_ref().zip(max_env_entries) {
+        truncate_env(env, value, max);
+    }
+    if let Some(inputs) = &diff.inputs {
+        let changed = elements(&mut value["inputs"]["changed"]);
+        for (input, input_value) in inputs.changed.iter().zip(changed) {
+            if let Some(derivation) = &input.derivation {
+                annotate(
+                    derivation,
+                    &mut input_value["derivation"],
+                    env_detail,
+                    max_env_entries,
+                );
+            }
+        }
+    }
+}
+
+/// Keep the `max` biggest changes of `env` in `value["env"]`, in map
+/// order, and name the rest in `value["env_truncated"]`.
+fn truncate_env(env: &EnvironmentDiff, value: &mut Value, max: usize) {
+    if env.len() <= max {
+        return;
+    }
+    // Biggest first, ties in map order, like the text output.
+    let sizes: Vec<usize> = env
+        .values()
+        .map(|var| var.as_ref().map_or(0, EnvVarDiff::changed_bytes))
+        .collect();
+    let mut by_size: Vec<usize> = (0..env.len()).collect();
+    by_size.sort_by_key(|&i| std::cmp::Reverse(sizes[i]));
+    let mut kept = vec![false; env.len()];
+    for &i in &by_size[..max] {
+        kept[i] = true;
+    }
+    let (mut shown, mut hidden) = (Vec::new(), Vec::new());
+    for (pair, kept) in elements(&mut value["env"]).zip(kept) {
+        let pair = pair.take();
+        if kept {
+            shown.push(pair);
+        } else {
+            hidden.push(pair[0].clone());
+        }
+    }
+    value["env"] = shown.into();
+    value["env_truncated"] = hidden.into();
+}
+
+fn elements(value: &mut Value) -> impl Iterator<Item = &mut Value> {
+    value.as_array_mut().into_iter().flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{
+        Derivation, InputDiff, InputsDiff, Output, OutputDetailDiff, OutputDiff, StringDiff,
+    };
+
+    fn env_diff(keys: &[&str]) -> DerivationDiff {
+        let env: EnvironmentDiff = keys
+            .iter()
+            .map(|key| {
+                let change = EnvVarDiff::Changed(StringDiff {
+                    old: b"1".to_vec(),
+                    new: b"2".to_vec(),
+                });
+                (key.as_bytes().to_vec(), Some(change))
+            })
+            .collect();
+        DerivationDiff::new(Derivation::default(), Derivation::default()).env(env)
+    }
+
+    #[test]
+    fn vars_left_out_of_env_detail_are_marked() {
+        let inner = env_diff(&["buildPhase", "meta"]);
+        let diff = env_diff(&["NIX_CFLAGS", "patches"]).inputs(InputsDiff {
+            changed: vec![InputDiff {
+                path: b"/nix/store/a-dep.drv".to_vec(),
+                outputs: None,
+                derivation: Some(Box::new(inner)),
+                unavailable: None,
+                version: None,
+            }],
+            pruned: Vec::new(),
+            rebuilt: Vec::new(),
+            not_compared: Vec::new(),
+            timed_out: false,
+            depth_limited: false,
+            ..Default::default()
+        });
+        let detail = ["*Phase".to_string(), "patches".to_string()];
+        let value: Value =
+            serde_json::from_slice(&render_json(&diff, &detail, None, None).unwrap()).unwrap();
+        let marked = |env: &Value| -> Vec<String> {
+            env.as_array()
+                .unwrap()
+                .iter()
+                .filter(|pair| pair[1]["detail"] == "summarized")
+                .map(|pair| pair[0].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(marked(&value["env"]), ["NIX_CFLAGS"]);
+        let inner = &value["inputs"]["changed"][0]["derivation"];
+        assert_eq!(marked(&inner["env"]), ["meta"]);
+        assert_eq!(inner["env"][1][1]["Changed"]["new"], "2");
+
+        let value: Value =
+            serde_json::from_slice(&render_json(&diff, &[], None, None).unwrap()).unwrap();
+        assert!(marked(&value["env"]).is_empty());
+    }
+
+    #[test]
+    fn truncated_env_keeps_the_biggest_changes() {
+        // b is the biggest change, then d; a, c and e are the same size.
+        let mut diff = env_diff(&["a", "b", "c", "d", "e"]);
+        let env = diff.env.as_mut().unwrap();
+        for (key, new) in [("b", "22222"), ("d", "222")] {
+            env.insert(
+                key.as_bytes().to_vec(),
+                Some(EnvVarDiff::Changed(StringDiff {
+                    old: b"1".to_vec(),
+                    new: new.as_bytes().to_vec(),
+                })),
+            );
+        }
+        let keys = |env: &Value| -> Vec<String> {
+            env.as_array()
+                .unwrap()
+                .iter()
+                .map(|key| key.as_str().unwrap_or_else(|| key[0].as_str().unwrap()))
+                .map(str::to_string)
+                .collect()
+        };
+
+        let value: Value =
+            serde_json::from_slice(&render_json(&diff, &[], Some(3), None).unwrap()).unwrap();
+        assert_eq!(keys(&value["env"]), ["a", "b", "d"]);
+        assert_eq!(keys(&value["env_truncated"]), ["c", "e"]);
+
+        let value: Value =
+            serde_json::from_slice(&render_json(&diff, &[], Some(5), None).unwrap()).unwrap();
+        assert_eq!(keys(&value["env"]).len(), 5);
+        assert!(value.get("env_truncated").is_none());
+    }
+
+    #[test]
+    fn outputs_keep_name_order_and_say_where_they_are_listed() {
+        let outputs = ["debug", "dev", "out"]
+            .iter()
+            .map(|name| OutputDiff {
+                name: name.as_bytes().to_vec(),
+                diff: OutputDetailDiff::Added(Output::default()),
+            })
+            .collect();
+        let diff = DerivationDiff::new(Derivation::default(), Derivation::default())
+            .outputs(OutputsDiff::Changed(outputs));
+        let value: Value =
+            serde_json::from_slice(&render_json(&diff, &[], None, None).unwrap()).unwrap();
+        let listed: Vec<_> = value["outputs"]["Changed"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|output| {
+                (
+                    output["name"].as_str().unwrap(),
+                    output["order"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(listed, [("debug", 2), ("dev", 1), ("out", 0)]);
+    }
+}
@@ -0,0 +1,277 @@
+//! Closures dumped by `nix derivation show --recursive`.
+//!
+//! The dump is one JSON object with an entry per derivation in the closure,
+//! keyed by .drv path. Two dumps, say from different machines, hold
+//! everything needed to diff the derivation trees without a Nix store;
+//! only source contents are missing.
+
+use crate::types::{ByteStr, Derivation, Output};
+use anyhow::{Context, Result, anyhow, bail};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+/// All derivations of one dump, by .drv path.
+pub type Closure = BTreeMap<String, Derivation>;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonDerivation {
+    #[serde(default)]
+    args: Vec<String>,
+    builder: String,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    #[serde(default)]
+    input_drvs: BTreeMap<String, JsonInputDrv>,
+    #[serde(default)]
+    input_srcs: Vec<String>,
+    outputs: BTreeMap<String, JsonOutput>,
+    system: String,
+}
+
+/// Older Nix lists the consumed outputs directly, newer Nix wraps them
+/// next to `dynamicOutputs`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonInputDrv {
+    Outputs(BTreeSet<String>),
+    Detailed(JsonInputOutputs),
+}
+
+/// `dynamicOutputs` are the outputs used of the derivations that outputs
+/// of the input are, by output.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonInputOutputs {
+    outputs: BTreeSet<String>,
+    #[serde(default)]
+    dynamic_outputs: BTreeMap<String, JsonInputOutputs>,
+}
+
+impl JsonInputOutputs {
+    /// The outputs as the ATerm parser names them, `out^lib` for output
+    /// `lib` of the derivation that output `out` is.
+    fn flatten(self, prefix: &str, names: &mut BTreeSet<Vec<u8>>) {
+        names.extend(
+            self.outputs
+                .into_iter()
+                .map(|name| format!("{prefix}{name}").into_bytes()),
+        );
+        for (output, nested) in self.dynamic_outputs {
+            nested.flatten(&format!("{prefix}{output}^"), names);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonOutput {
+    path: Option<String>,
+    /// `r:sha256` in older Nix; newer Nix moves the `r:` into `method`.
+    hash_algo: Option<String>,
+    method: Option<String>,
+    hash: Option<String>,
+    #[serde(default)]
+    impure: bool,
+}
+
+/// Parse a `nix derivation show --recursive` dump. Paths that newer Nix
+/// prints relative to the store get `store_dir` prepended.
+pub fn parse_closure(json: &str, store_dir: &str) -> Result<Closure> {
+    let entries: BTreeMap<String, JsonDerivation> =
+        serde_json::from_str(json).context("not a `nix derivation show` dump")?;
+    let absolute = |path: String| {
+        if path.starts_with('/') {
+            path
+        } else {
+            format!("{store_dir}/{path}")
+        }
+    };
+    Ok(entries
+        .into_iter()
+        .map(|(path, drv)| (absolute(path), convert(drv, &absolute)))
+        .collect())
+}
+
+fn convert(drv: JsonDerivation, absolute: &impl Fn(String) -> String) -> Derivation {
+    let outputs = drv
+        .outputs
+        .into_iter()
+        .map(|(name, out)| {
+            let hash_algorithm = out.hash_algo.map(|algo| match out.method.as_deref() {
+                Some("nar") => format!("r:{algo}"),
+                Some(method @ ("text" | "git")) => format!("{method}:{algo}"),
+                _ => algo,
+            });
+            let hash = if out.impure {
+                Some("impure".to_string())
+            } else {
+                out.hash
+            };
+            let output = Output {
+                path: out.path.map(absolute).unwrap_or_default().into_bytes(),
+                hash_algorithm: hash_algorithm.map(String::into_bytes),
+                hash: hash.map(String::into_bytes),
+            };
+            (name.into_bytes(), output)
+        })
+        .collect();
+    let input_derivations = drv
+        .input_drvs
+        .into_iter()
+        .map(|(path, input)| {
+            let outputs = match input {
+                JsonInputDrv::Outputs(outputs) => {
+                    outputs.into_iter().map(String::into_bytes).collect()
+                }
+                JsonInputDrv::Detailed(detailed) => {
+                    let mut outputs = BTreeSet::new();
+                    detailed.flatten("", &mut outputs);
+                    outputs
+                }
+            };
+            (ByteStr::from(absolute(path).into_bytes()), outputs)
+        })
+        .collect();
+    Derivation {
+        outputs,
+        input_sources: drv
+            .input_srcs
+            .into_iter()
+            .map(|p| ByteStr::from(absolute(p).into_bytes()))
+            .collect(),
+        input_derivations,
+        platform: drv.system.into_bytes(),
+        builder: drv.builder.into_bytes(),
+        args: drv.args.into_iter().map(String::into_bytes).collect(),
+        env: drv
+            .env
+            .into_iter()
+            .map(|(k, v)| (ByteStr::from(k.into_bytes()), v.into_bytes()))
+            .collect(),
+    }
+}
+
+/// The derivation the dump was made for: the first of `candidates` in it,
+/// or else the one entry no other entry depends on.
+pub fn find_root<'a>(closure: &'a Closure, candidates: &[String]) -> Result<&'a str> {
+    if !candidates.is_empty() {
+        return candidates
+            .iter()
+            .find_map(|c| closure.get_key_value(c).map(|(path, _)| path.as_str()))
+            .ok_or_else(|| anyhow!("none of the --root paths is in the dump"));
+    }
+    let referenced: HashSet<&[u8]> = closure
+        .values()
+        .flat_map(|drv| drv.input_derivations.keys().map(|path| &path[..]))
+        .collect();
+    let roots: Vec<&str> = closure
+        .keys()
+        .map(String::as_str)
+        .filter(|path| !referenced.contains(path.as_bytes()))
+        .collect();
+    match roots[..] {
+        [root] => Ok(root),
+        [] => bail!("the dump is empty"),
+        _ => bail!(
+            "the dump has {} top-level derivations ({}); pick one with --root",
+            roots.len(),
+            roots.join(", ")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DUMP: &str = r#"{
+      "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-app.drv": {
+        "args": ["-e", "build.sh"],
+        "builder": "/bin/sh",
+        "env": {"name": "app", "out": "/nix/store/cccccccccccccccccccccccccccccccc-app"},
+        "inputDrvs": {
+          "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-lib.drv": {"dynamicOutputs": {}, "outputs": ["dev", "out"]}
+        },
+        "inputSrcs": ["dddddddddddddddddddddddddddddddd-build.sh"],
+        "outputs": {"out": {"path": "/nix/store/cccccccccccccccccccccccccccccccc-app"}},
+        "system": "x86_64-linux"
+      },
+      "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-lib.drv": {
+        "args": [],
+        "builder": "/bin/sh",
+        "env": {"name": "lib"},
+        "inputDrvs": {},
+        "inputSrcs": [],
+        "outputs": {
+          "out": {"path": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-lib", "method": "nar", "hashAlgo": "sha256", "hash": "00"},
+          "dev": {"path": "/nix/store/ffffffffffffffffffffffffffffffff-lib-dev", "hashAlgo": "r:sha256", "hash": "00"}
+        },
+        "system": "x86_64-linux"
+      }
+    }"#;
+
+    #[test]
+    fn parses_both_input_formats_and_relative_paths() {
+        let closure = parse_closure(DUMP, "/nix/store").unwrap();
+        let app = &closure["/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-app.drv"];
+        assert_eq!(
+            app.input_derivations[&b"/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-lib.drv"[..]],
+            [b"dev".to_vec(), b"out".to_vec()].into()
+        );
+        assert!(
+            app.input_sources
+                .contains(&b"/nix/store/dddddddddddddddddddddddddddddddd-build.sh"[..])
+        );
+        let lib = &closure["/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-lib.drv"];
+        for output in lib.outputs.values() {
+            assert_eq!(output.hash_algorithm.as_deref(), Some(&b"r:sha256"[..]));
+        }
+    }
+
+    #[test]
+    fn dynamic_outputs_are_named_like_in_aterm() {
+        let json = r#"{
+          "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-app.drv": {
+            "builder": "/bin/sh",
+            "inputDrvs": {
+              "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-gen.drv": {
+                "dynamicOutputs": {"out": {"dynamicOutputs": {}, "outputs": ["bin", "out"]}},
+                "outputs": []
+              }
+            },
+            "outputs": {"out": {"method": "nar", "hashAlgo": "sha256"}},
+            "system": "x86_64-linux"
+          }
+        }"#;
+        let closure = parse_closure(json, "/nix/store").unwrap();
+        let app = &closure["/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-app.drv"];
+        assert_eq!(
+            app.input_derivations[&b"/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-gen.drv"[..]],
+            [b"out^bin".to_vec(), b"out^out".to_vec()].into()
+        );
+        assert_eq!(
+            app.outputs[&b"out"[..]].shown_path(),
+            b"(content-addressed, floating)"
+        );
+    }
+
+    #[test]
+    fn root_is_the_entry_nothing_depends_on() {
+        let closure = parse_closure(DUMP, "/nix/store").unwrap();
+        assert_eq!(
+            find_root(&closure, &[]).unwrap(),
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-app.drv"
+        );
+        let lib = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-lib.drv".to_string();
+        assert_eq!(
+            find_root(&closure, &["/elsewhere.drv".to_string(), lib.clone()]).unwrap(),
+            lib
+        );
+
+        let mut two_roots = closure.clone();
+        two_roots.insert("/nix/store/x-other.drv".to_string(), Derivation::default());
+        let err = find_root(&two_roots, &[]).unwrap_err().to_string();
+        assert!(err.contains("--root"), "{err}");
+    }
+}
@@ -1,5 +1,103 @@
+//! Explain why two Nix derivations differ.
+//!
+//! [`diff_files`] compares two .drv files with the default settings, and
+//! [`diff_paths`] with those of a [`DiffContext`], such as the stores to
+//! read from or a [`CancellationToken`]. A `DiffContext` also diffs
+//! derivations parsed elsewhere, e.g. with [`parse_derivation_contents`].
+//! The resulting [`DerivationDiff`] prints like the command line, or
+//! serializes with serde. None of this runs Nix: evaluating `.nix` files
+//! and flakes, and asking the store for the deriver of an output, is left
+//! to [`instantiate`], which needs `nix-instantiate`, `nix-store` and
+//! `nix` on the `PATH`.
+//!
+//! # Example
+//!
+//! Two versions of a derivation, parsed from ATerm strings and diffed in
+//! memory:
+//!
+//! ```
+//! use nix_diff::{Derivation, DiffContext};
+//!
+//! let drv = |hash: &str, cflags: &str, dep: &str| {
+//!     let out = format!("/nix/store/{}-hello", hash.repeat(32));
+//!     let dep = format!("/nix/store/{}-zlib.drv", dep.repeat(32));
+//!     Derivation::parse(&format!(
+//!         r#"Derive([("out","{out}","","")],[("{dep}",["out"])],[],"x86_64-linux","/bin/sh",["-c","make"],[("CFLAGS","{cflags}"),("name","hello"),("out","{out}")])"#
+//!     ))
+//! };
+//! let old = drv("a", "-O2", "c")?;
+//! let new = drv("b", "-O3", "d")?;
+//!
+//! // zlib's .drv files are not in a store here, so it is only listed as
+//! // a changed input.
+//! let diff = DiffContext::new().diff_derivations(b"old.drv", b"new.drv", &old, &new)?;
+//! assert!(diff.has_changes());
+//!
+//! // `Display` renders like `nix-diff --color never`.
+//! let text = diff.to_string();
+//! assert!(text.contains("CFLAGS:\n"), "{text}");
+//!
+//! // As `--format json` prints it.
+//! let json: serde_json::Value =
+//!     serde_json::from_slice(&nix_diff::json::render_json(&diff, &[], None, None)?)?;
+//! assert_eq!(json["env"][0][0], "CFLAGS");
+//!
+//! // Count the changed env vars across the whole tree.
+//! let mut env_vars = 0;
+//! diff.walk(|_path, drv| env_vars += drv.env.as_ref().map_or(0, |env| env.len()));
+//! assert_eq!(env_vars, 2); // CFLAGS and out
+//! # anyhow::Ok(())
+//! ```
+
+pub mod binary_cache;
+pub mod cancel;
+pub mod closure;
+pub mod console;
+pub mod describe;
 pub mod diff;
-pub mod instantiate;
+pub mod digest;
+pub mod drv_cache;
+pub mod drvhash;
+pub mod env_script;
+pub mod flake_lock;
+pub mod glob;
+pub mod hunk;
+pub mod ignore;
+pub mod input;
+pub mod intern;
+pub mod json;
+pub mod json_closure;
+pub mod ndjson;
 pub mod parser;
+pub mod profile;
+pub mod redact;
+pub mod remote;
 pub mod render;
+pub mod sanitize;
+pub mod shell;
+pub mod store_reader;
+pub mod structured_attrs;
+pub mod summary;
+#[cfg(feature = "test-support")]
+pub mod testing;
+pub mod textwidth;
 pub mod types;
+pub mod version;
+
+// Running Nix. Everything else reads .drv files, stores and caches as
+// they are, without spawning a process.
+pub mod instantiate;
+
+// Process-wide state and plumbing of the command line, public only so
+// the binary can reach it.
+#[doc(hidden)]
+pub mod http;
+#[doc(hidden)]
+pub mod interrupt;
+
+pub use cancel::{CancellationToken, Cancelled};
+pub use diff::{DiffContext, diff_files, diff_paths};
+pub use parser::{
+    ParseError, parse_derivation, parse_derivation_contents, parse_derivation_string,
+};
+pub use types::{Derivation, DerivationDiff, RenderOptions};
@@ -0,0 +1,316 @@
+//! `--format ndjson`: the diff tree as a stream of newline-delimited JSON
+//! events, for diffs too big to handle as one JSON document.
+//!
+//! Each line is an object with an `event` naming what it reports and a
+//! `path` listing the input derivations, by name, from the top level down
+//! to the one it is about; `[]` is the top level. The events of an input
+//! follow its `input_changed` event. Values have the shape they have in
+//! `--format json` (see [`crate::json`]).
+//!
+//! Version 1 of the vocabulary:
+//!
+//! - `start`: the first line, with `version`, the `old` and `new` labels,
+//!   the `closure_size` of both sides unless `--no-closure-count` is
+//!   given and, unless `--no-invocation-echo` is given, the `invocation`
+//! - `sandbox_flag_changed`: `key`, `old` and `new`, `null` when unset
+//! - `fetch_attr_changed`: one of the attributes saying what a
+//!   fixed-output derivation fetches, with `key`, `old`, `new` and
+//!   `same_digest`, true when only the encoding of the hash changed
+//! - `stale_hash`: the source of a fixed-output derivation changed but
+//!   its output hash did not
+//! - `name_changed`: `pname` and `version`, `null` when equal
+//! - `output_changed`: `name` and `change`, in text output order
+//! - `platform_changed`, `builder_changed`: `change`
+//! - `arg_changed`: `change`
+//! - `source_added`, `source_removed`: `path` and what `kind` it is,
+//!   if known; `source_changed`: `path` and `change`
+//! - `input_added`, `input_removed`: `path`
+//! - `input_changed`: `name`, `outputs`, `unavailable`, `version` and
+//!   `already_compared`, true when its diff was reported earlier on
+//! - `input_not_compared`: `name`, for changed inputs past
+//!   `--max-derivations` or `--timeout`
+//! - `attr_changed`: a structured attr in `__json` that differs, with
+//!   its `attr` path, like `env.CFLAGS`, and `change`
+//! - `env_changed`: `key` and `change`
+//! - `env_ignored`: the `count` of differing vars left out by
+//!   `--ignore-env` or `--skip-env`
+//! - `summary`: the last line, with `changed`, the `counts` of the
+//!   summary line, which have `timed_out: true` if `--timeout` cut the
+//!   diff short, and the `stats` that `--stat` prints
+//!
+//! New events and fields may be added without a version bump; consumers
+//! should skip what they don't know. Renaming or removing one bumps it.
+
+use crate::summary::{DiffStats, DiffSummary};
+use crate::types::byte_strings::Str;
+use crate::types::{DerivationDiff, OutputsDiff};
+use serde_json::{Value, json};
+use std::io::{self, Write};
+
+/// The version of the event vocabulary, in the `start` event.
+pub const VERSION: u32 = 1;
+
+/// Write the events of `diff` to `out`, one per line, as the tree is
+/// walked. `invocation`, if given, goes into the `start` event as is.
+pub fn write_ndjson(
+    out: &mut impl Write,
+    diff: &DerivationDiff,
+    invocation: Option<&Value>,
+) -> io::Result<()> {
+    let mut events = Events {
+        out,
+        path: Vec::new(),
+    };
+    let [old, new] = match &diff.labels {
+        Some([old, new]) => [Some(Str(old)), Some(Str(new))],
+        None => [None, None],
+    };
+    let mut start = json!({"version": VERSION, "old": old, "new": new});
+    if let Some(sizes) = diff.closure_size {
+        start["closure_size"] = serde_json::to_value(sizes)?;
+    }
+    if let Some(invocation) = invocation {
+        start["invocation"] = invocation.clone();
+    }
+    events.emit("start", start)?;
+    events.derivation(diff)?;
+    events.emit(
+        "summary",
+        json!({
+            "changed": diff.has_changes(),
+            "counts": DiffSummary::of(diff),
+            "stats": DiffStats::of(diff),
+        }),
+    )
+}
+
+struct Events<'a, W> {
+    out: &'a mut W,
+    /// Names of the inputs leading to the derivation being walked.
+    path: Vec<&'a [u8]>,
+}
+
+impl<'a, W: Write> Events<'a, W> {
+    /// Write one line: `event`, the current `path`, then `fields`.
+    fn emit(&mut self, event: &str, fields: Value) -> io::Result<()> {
+        let path: Vec<_> = self.path.iter().map(|name| Str(name)).collect();
+        let mut line = serde_json::to_vec(&json!({"event": event, "path": path}))?;
+        if let Value::Object(fields) = fields {
+            // Open the object again to append the fields after the path.
+            line.pop();
+            for (key, value) in fields {
+                line.push(b',');
+                serde_json::to_writer(&mut line, &key)?;
+                line.push(b':');
+                serde_json::to_writer(&mut line, &value)?;
+            }
+            line.push(b'}');
+        }
+        line.push(b'\n');
+        self.out.write_all(&line)
+    }
+
+    fn derivation(&mut self, diff: &'a DerivationDiff) -> io::Result<()> {
+        for flag in diff.sandbox.iter().flatten() {
+            let fields = json!({
+                "key": Str(&flag.key),
+                "old": flag.old.as_deref().map(Str),
+                "new": flag.new.as_deref().map(Str),
+            });
+            self.emit("sandbox_flag_changed", fields)?;
+        }
+        if let Some(fetch) = &diff.fetch {
+            if fetch.stale_hash {
+                self.emit("stale_hash", json!({}))?;
+            }
+            for attr in &fetch.attrs {
+                let fields = json!({
+                    "key": Str(&attr.key),
+                    "old": attr.old.as_deref().map(Str),
+                    "new": attr.new.as_deref().map(Str),
+                    "same_digest": attr.same_digest,
+                });
+                self.emit("fetch_attr_changed", fields)?;
+            }
+        }
+        if let Some(name) = &diff.name {
+            let fields = json!({"pname": name.pname, "version": name.version});
+            self.emit("name_changed", fields)?;
+        }
+        for output in diff.outputs.listed() {
+            let fields = json!({"name": Str(&output.name), "change": output.diff});
+            self.emit("output_changed", fields)?;
+        }
+        if let Some(platform) = &diff.platform {
+            self.emit("platform_changed", json!({"change": platform}))?;
+        }
+        if let Some(builder) = &diff.builder {
+            self.emit("builder_changed", json!({"change": builder}))?;
+        }
+        for arg in diff.args.iter().flatten() {
+            self.emit("arg_changed", json!({"change": arg}))?;
+        }
+        if let Some(sources) = &diff.sources {
+            for (event, paths) in [
+                ("source_added", &sources.added),
+                ("source_removed", &sources.removed),
+            ] {
+                for path in paths {
+                    let fields = json!({"path": Str(path), "kind": sources.kinds.get(path)});
+                    self.emit(event, fields)?;
+                }
+            }
+            for source in &sources.common {
+                let fields = json!({"path": Str(&source.path), "change": source.diff});
+                self.emit("source_changed", fields)?;
+            }
+        }
+        if let Some(inputs) = &diff.inputs {
+            for (event, paths) in [
+                ("input_added", &inputs.added),
+                ("input_removed", &inputs.removed),
+            ] {
+                for path in paths {
+                    self.emit(event, json!({"path": Str(&path.0)}))?;
+                }
+            }
+            let compared = inputs.changed.iter().chain(&inputs.pruned);
+            for input in compared.chain(&inputs.rebuilt) {
+                let already_compared = input
+                    .derivation
+                    .as_ref()
+                    .is_some_and(|d| matches!(d.outputs, OutputsDiff::AlreadyCompared));
+                let fields = json!({
+                    "name": Str(&input.path),
+                    "outputs": input.outputs,
+                    "unavailable": input.unavailable,
+                    "version": input.version,
+                    "already_compared": already_compared,
+                });
+                self.emit("input_changed", fields)?;
+                if let (Some(derivation), false) = (&input.derivation, already_compared) {
+                    self.path.push(&input.path);
+                    self.derivation(derivation)?;
+                    self.path.pop();
+                }
+            }
+            for input in &inputs.not_compared {
+                self.emit("input_not_compared", json!({"name": Str(&input.path)}))?;
+            }
+        }
+        for attr in diff.structured_attrs.iter().flatten() {
+            let fields = json!({"attr": attr.path, "change": attr.change});
+            self.emit("attr_changed", fields)?;
+        }
+        for (key, change) in diff.env.iter().flatten() {
+            if let Some(change) = change {
+                self.emit("env_changed", json!({"key": Str(key), "change": change}))?;
+            }
+        }
+        if diff.ignored_env > 0 {
+            self.emit("env_ignored", json!({"count": diff.ignored_env}))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Derivation, EnvVarDiff, InputDiff, InputsDiff, StringDiff};
+
+    fn events(diff: &DerivationDiff) -> Vec<Value> {
+        let mut out = Vec::new();
+        write_ndjson(&mut out, diff, None).unwrap();
+        out.split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect()
+    }
+
+    fn env_diff(key: &str) -> DerivationDiff {
+        let change = EnvVarDiff::Changed(StringDiff {
+            old: b"1".to_vec(),
+            new: b"2".to_vec(),
+        });
+        DerivationDiff::new(Derivation::default(), Derivation::default())
+            .env([(key.as_bytes().to_vec(), Some(change))].into())
+    }
+
+    #[test]
+    fn nested_events_carry_the_path_of_input_names() {
+        let zlib = env_diff("configureFlags");
+        let libpng = env_diff("patches").inputs(InputsDiff {
+            changed: vec![InputDiff {
+                path: b"zlib-1.3.drv".to_vec(),
+                outputs: None,
+                derivation: Some(Box::new(zlib)),
+                unavailable: None,
+                version: None,
+            }],
+            ..Default::default()
+        });
+        let diff = env_diff("name")
+            .inputs(InputsDiff {
+                changed: vec![InputDiff {
+                    path: b"libpng-1.6.drv".to_vec(),
+                    outputs: None,
+                    derivation: Some(Box::new(libpng)),
+                    unavailable: None,
+                    version: None,
+                }],
+                ..Default::default()
+            })
+            .labels(b"old.drv", b"new.drv");
+
+        let events = events(&diff);
+        let lines: Vec<_> = events
+            .iter()
+            .map(|event| {
+                let detail = event
+                    .get("key")
+                    .or(event.get("name"))
+                    .unwrap_or(&Value::Null);
+                format!("{} {} {detail}", event["event"], event["path"])
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                r#""start" [] null"#,
+                r#""input_changed" [] "libpng-1.6.drv""#,
+                r#""input_changed" ["libpng-1.6.drv"] "zlib-1.3.drv""#,
+                r#""env_changed" ["libpng-1.6.drv","zlib-1.3.drv"] "configureFlags""#,
+                r#""env_changed" ["libpng-1.6.drv"] "patches""#,
+                r#""env_changed" [] "name""#,
+                r#""summary" [] null"#,
+            ]
+        );
+        assert_eq!(events[0]["version"], VERSION);
+        assert_eq!(events[0]["old"], "old.drv");
+        assert_eq!(events[3]["change"]["Changed"]["new"], "2");
+        assert_eq!(events[6]["changed"], true);
+        assert_eq!(events[6]["counts"]["derivations"], 3);
+    }
+
+    #[test]
+    fn inputs_compared_earlier_are_not_walked_again() {
+        let diff =
+            DerivationDiff::new(Derivation::default(), Derivation::default()).inputs(InputsDiff {
+                changed: vec![InputDiff {
+                    path: b"dep.drv".to_vec(),
+                    outputs: None,
+                    derivation: Some(Box::new(
+                        env_diff("x").outputs(OutputsDiff::AlreadyCompared),
+                    )),
+                    unavailable: None,
+                    version: None,
+                }],
+                ..Default::default()
+            });
+        let events = events(&diff);
+        let names: Vec<_> = events.iter().map(|event| &event["event"]).collect();
+        assert_eq!(names, ["start", "input_changed", "summary"]);
+        assert_eq!(events[1]["already_compared"], true);
+    }
+}
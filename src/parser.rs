@@ -1,37 +1,593 @@
-use crate::types::{Derivation, Output};
-use anyhow::{Context, Result, anyhow};
+use crate::types::{ByteStr, Derivation, Output};
+use anyhow::{Context, Result, anyhow, bail};
 use harmonia_store_aterm::parse_derivation_aterm;
 use harmonia_store_core::derivation::{DerivationInputs, DerivationOutput};
 use harmonia_store_core::store_path::{StoreDir, StorePath, StorePathName};
 use harmonia_utils_hash::fmt::CommonHash;
+use std::borrow::Cow;
 use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 use std::fs;
+use std::io::Read;
+
+/// How every ATerm derivation starts.
+const DERIVE: &str = "Derive(";
+
+/// How derivations that use outputs of dynamic derivations start instead,
+/// followed by the format version and then the same as after `Derive(`.
+const DRV_WITH_VERSION: &str = "DrvWithVersion(";
+
+/// The format version of [`DRV_WITH_VERSION`] derivations, the only one
+/// Nix writes (with `dynamic-derivations`).
+const DYN_DRV_VERSION: &str = "xp-dyn-drv";
 
 pub fn parse_derivation(path: &str) -> Result<Derivation> {
-    let content = fs::read_to_string(path)
-        .with_context(|| format!("Failed to read derivation file: {path}"))?;
+    parse_derivation_at(path, path)
+}
+
+/// Parse the derivation with store path `path` from `file`, for stores
+/// whose files were copied out of their store directory.
+pub fn parse_derivation_at(path: &str, file: &str) -> Result<Derivation> {
+    let content =
+        fs::read(file).with_context(|| format!("Failed to read derivation file: {file}"))?;
+    parse_derivation_bytes(path, &content)
+}
+
+/// [`parse_derivation_contents`] for contents that need not be UTF-8.
+/// Nix writes env values and arguments byte for byte, so a .drv file can
+/// hold any bytes; they come out unchanged.
+pub fn parse_derivation_bytes(path: &str, content: &[u8]) -> Result<Derivation> {
+    match std::str::from_utf8(content) {
+        Ok(content) => parse_derivation_contents(path, content),
+        // The ATerm parser only takes strings, so the bytes that are not
+        // UTF-8 go through it as private-use characters.
+        Err(_) => {
+            let escaped = escape_invalid_utf8(content);
+            parse_derivation_contents(path, &escaped)
+                .map(unescape_derivation)
+                .map_err(|mut err| {
+                    // Located in the original bytes, not the escaped ones.
+                    if let Some(parse) = err.downcast_mut::<ParseError>() {
+                        let offset = unescaped_offset(&escaped, parse.offset);
+                        let expected = std::mem::take(&mut parse.expected);
+                        *parse = ParseError::new(content, offset, expected);
+                    }
+                    err
+                })
+        }
+    }
+}
 
+/// Parse derivation `content` that was obtained from somewhere other than
+/// the local file system. `path` is its store path and determines the store
+/// directory and derivation name, as in [`parse_derivation`]. A syntax
+/// error is a [`ParseError`] in the error's chain.
+pub fn parse_derivation_contents(path: &str, content: &str) -> Result<Derivation> {
     let store_dir = store_dir_from_drv_path(path)?;
     let name = extract_drv_name(path, &store_dir);
 
-    let drv = parse_derivation_aterm(&store_dir, &content, name)
-        .map_err(|e| anyhow!("Failed to parse ATerm: {e}"))?;
+    parse_aterm(&store_dir, content, name)
+        .with_context(|| format!("Failed to parse ATerm in {path}"))
+}
+
+/// Parse the `nix derivation show` output for one derivation, with its
+/// paths in `/nix/store`.
+pub fn parse_derivation_json(json: &str) -> Result<Derivation> {
+    parse_derivation_json_in(json, "/nix/store").map(|(_, drv)| drv)
+}
 
-    Ok(convert_derivation(&store_dir, drv))
+/// Parse `nix derivation show` output, returning the .drv path and the
+/// derivation. Of a `--recursive` dump, that is the derivation the others
+/// are inputs of. Paths that newer Nix prints relative to the store get
+/// `store_dir` prepended.
+pub fn parse_derivation_json_in(json: &str, store_dir: &str) -> Result<(String, Derivation)> {
+    let mut closure = crate::json_closure::parse_closure(json, store_dir)?;
+    let root = crate::json_closure::find_root(&closure, &[])?.to_string();
+    let drv = closure.remove(&root).expect("the root is in the closure");
+    Ok((root, drv))
 }
 
+/// Check that `path` can be read and starts like a derivation, without
+/// parsing the rest.
+pub fn check_derivation_header(path: &str) -> Result<()> {
+    let mut header = Vec::with_capacity(DRV_WITH_VERSION.len());
+    fs::File::open(path)
+        .and_then(|file| {
+            file.take(DRV_WITH_VERSION.len() as u64)
+                .read_to_end(&mut header)
+        })
+        .with_context(|| format!("Failed to read derivation file: {path}"))?;
+    if !header.starts_with(DERIVE.as_bytes()) && header != DRV_WITH_VERSION.as_bytes() {
+        bail!("{path} is not a derivation: it does not start with `Derive(`");
+    }
+    Ok(())
+}
+
+/// Parse a derivation in ATerm form, as found in a .drv file, without
+/// knowing its store path; see [`Derivation::parse`].
 pub fn parse_derivation_string(input: &str) -> Result<Derivation> {
     let store_dir = StoreDir::default();
     let name: StorePathName = "unknown".parse().unwrap();
 
-    let drv = parse_derivation_aterm(&store_dir, input, name)
-        .map_err(|e| anyhow!("Failed to parse ATerm: {e}"))?;
+    parse_aterm(&store_dir, input, name).context("Failed to parse ATerm")
+}
+
+fn parse_aterm(store_dir: &StoreDir, content: &str, name: StorePathName) -> Result<Derivation> {
+    let (plain, dynamic) = match split_dynamic_inputs(content)? {
+        Some((plain, dynamic)) => (Cow::Owned(plain), dynamic),
+        None => (Cow::Borrowed(content), DynamicOutputs::new()),
+    };
+    let drv = match parse_derivation_aterm(store_dir, &plain, name) {
+        Ok(drv) => drv,
+        // The ATerm parser doesn't say where it stopped, and it may have
+        // read `plain`; look for the syntax error in `content`, or pass on
+        // what the parser said if there is none.
+        Err(e) => {
+            check_syntax(content)?;
+            bail!("{e}");
+        }
+    };
+
+    let mut drv = convert_derivation(store_dir, drv);
+    for (path, outputs) in dynamic {
+        drv.input_derivations
+            .entry(ByteStr::from(path.into_bytes()))
+            .or_default()
+            .extend(outputs.into_iter().map(String::into_bytes));
+    }
+    Ok(drv)
+}
+
+/// Outputs of dynamic derivations, by the input derivation whose outputs
+/// they are built by.
+type DynamicOutputs = BTreeMap<String, BTreeSet<String>>;
+
+/// For a [`DRV_WITH_VERSION`] derivation, the same derivation as a plain
+/// `Derive(...)` without the outputs of dynamic derivations it uses, and
+/// those outputs per input derivation. Output `lib` of the derivation
+/// that output `out` of an input is comes out as `out^lib`, as in the
+/// path `/nix/store/...-foo.drv^out^lib`.
+fn split_dynamic_inputs(content: &str) -> Result<Option<(String, DynamicOutputs)>> {
+    if !content.starts_with(DRV_WITH_VERSION) {
+        return Ok(None);
+    }
+    let mut scanner = Scanner {
+        s: content,
+        at: DRV_WITH_VERSION.len(),
+    };
+    let version = scanner.string()?;
+    if version != DYN_DRV_VERSION {
+        bail!("unknown derivation ATerm format version `{version}`");
+    }
+    scanner.expect(",")?;
+
+    let outputs = scanner.at;
+    scanner.skip_list()?;
+    scanner.expect(",")?;
+    let mut plain = format!("{DERIVE}{}", &content[outputs..scanner.at]);
+    let mut inputs = Vec::new();
+    let mut dynamic = BTreeMap::new();
+    scanner.list(|s| {
+        s.expect("(")?;
+        let path = s.string()?;
+        s.expect(",")?;
+        let mut names = Vec::new();
+        s.input_outputs("", &mut names)?;
+        let (nested, direct): (BTreeSet<_>, BTreeSet<_>) =
+            names.into_iter().partition(|name| name.contains('^'));
+        let direct: Vec<_> = direct.iter().map(|name| format!("\"{name}\"")).collect();
+        inputs.push(format!("(\"{path}\",[{}])", direct.join(",")));
+        if !nested.is_empty() {
+            dynamic.insert(path.to_string(), nested);
+        }
+        s.expect(")")
+    })?;
+    plain.push_str(&format!("[{}]", inputs.join(",")));
+    plain.push_str(&content[scanner.at..]);
+    Ok(Some((plain, dynamic)))
+}
+
+/// Walk a whole derivation for its first syntax error: the ATerm parser
+/// doesn't say where it gave up.
+fn check_syntax(content: &str) -> Result<(), ParseError> {
+    let mut scanner = Scanner { s: content, at: 0 };
+    if content.starts_with(DRV_WITH_VERSION) {
+        scanner.expect(DRV_WITH_VERSION)?;
+        scanner.string()?;
+        scanner.expect(",")?;
+    } else {
+        scanner.expect(DERIVE)?;
+    }
+    scanner.list(|s| s.string_tuple(4))?;
+    scanner.expect(",")?;
+    scanner.list(|s| {
+        s.expect("(")?;
+        s.string()?;
+        s.expect(",")?;
+        s.input_outputs("", &mut Vec::new())?;
+        s.expect(")")
+    })?;
+    scanner.expect(",")?;
+    scanner.strings()?;
+    scanner.expect(",")?;
+    scanner.string()?;
+    scanner.expect(",")?;
+    scanner.string()?;
+    scanner.expect(",")?;
+    scanner.strings()?;
+    scanner.expect(",")?;
+    scanner.list(|s| s.string_tuple(2))?;
+    scanner.expect(")")?;
+    if scanner.at < content.len() {
+        return Err(scanner.error("end of input"));
+    }
+    Ok(())
+}
+
+/// Just enough of an ATerm reader to take a [`DRV_WITH_VERSION`]
+/// derivation apart and to find syntax errors; strings come out still
+/// escaped.
+struct Scanner<'a> {
+    s: &'a str,
+    at: usize,
+}
+
+impl<'a> Scanner<'a> {
+    /// A [`ParseError`] here, for want of `expected`.
+    fn error(&self, expected: &str) -> ParseError {
+        let mut at = self.at.min(self.s.len());
+        while !self.s.is_char_boundary(at) {
+            at -= 1;
+        }
+        ParseError::new(self.s.as_bytes(), at, expected)
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), ParseError> {
+        if !self.s[self.at..].starts_with(token) {
+            return Err(self.error(&format!("`{token}`")));
+        }
+        self.at += token.len();
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.s.as_bytes().get(self.at).copied()
+    }
+
+    fn string(&mut self) -> Result<&'a str, ParseError> {
+        self.expect("\"")?;
+        let start = self.at;
+        loop {
+            match self.peek() {
+                Some(b'"') => break,
+                Some(b'\\') => self.at += 2,
+                Some(_) => self.at += 1,
+                None => return Err(self.error("`\"` to end the string")),
+            }
+        }
+        self.at += 1;
+        Ok(&self.s[start..self.at - 1])
+    }
+
+    /// A list `[...]` of items that `item` reads, separated by `,`.
+    fn list(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> Result<(), ParseError>,
+    ) -> Result<(), ParseError> {
+        self.expect("[")?;
+        if self.peek() == Some(b']') {
+            self.at += 1;
+            return Ok(());
+        }
+        loop {
+            item(self)?;
+            match self.peek() {
+                Some(b',') => self.at += 1,
+                Some(b']') => {
+                    self.at += 1;
+                    return Ok(());
+                }
+                _ => return Err(self.error("`,` or `]`")),
+            }
+        }
+    }
+
+    fn skip_list(&mut self) -> Result<(), ParseError> {
+        // Anything else would be skipped a byte at a time, possibly into
+        // the middle of a character.
+        if self.peek() != Some(b'[') {
+            return Err(self.error("`[`"));
+        }
+        let mut depth = 0;
+        loop {
+            match self.peek() {
+                Some(b'"') => {
+                    self.string()?;
+                    continue;
+                }
+                Some(b'[' | b'(') => depth += 1,
+                Some(b']' | b')') => depth -= 1,
+                Some(_) => {}
+                None => return Err(self.error("`]` to end the list")),
+            }
+            self.at += 1;
+            if depth == 0 {
+                return Ok(());
+            }
+        }
+    }
 
-    Ok(convert_derivation(&store_dir, drv))
+    fn strings(&mut self) -> Result<Vec<&'a str>, ParseError> {
+        let mut strings = Vec::new();
+        self.list(|s| {
+            strings.push(s.string()?);
+            Ok(())
+        })?;
+        Ok(strings)
+    }
+
+    /// `n` strings in parentheses, like the `(name,path,algo,hash)` of an
+    /// output.
+    fn string_tuple(&mut self, n: usize) -> Result<(), ParseError> {
+        self.expect("(")?;
+        for i in 0..n {
+            if i > 0 {
+                self.expect(",")?;
+            }
+            self.string()?;
+        }
+        self.expect(")")
+    }
+
+    /// The outputs used of an input derivation, `["out"]`, or with those
+    /// of dynamic derivations, `(["out"],[("out",["lib"])])`, each
+    /// prefixed with `prefix`.
+    fn input_outputs(&mut self, prefix: &str, names: &mut Vec<String>) -> Result<(), ParseError> {
+        let nested = self.peek() == Some(b'(');
+        if nested {
+            self.expect("(")?;
+        }
+        names.extend(self.strings()?.iter().map(|name| format!("{prefix}{name}")));
+        if !nested {
+            return Ok(());
+        }
+        self.expect(",")?;
+        self.list(|s| {
+            s.expect("(")?;
+            let output = s.string()?;
+            s.expect(",")?;
+            s.input_outputs(&format!("{prefix}{output}^"), names)?;
+            s.expect(")")
+        })?;
+        self.expect(")")
+    }
+}
+
+/// Characters of a line on either side of a [`ParseError`] in its
+/// excerpt.
+const EXCERPT_CONTEXT: usize = 30;
+
+/// A syntax error in a derivation's ATerm. The errors of
+/// [`parse_derivation`] and friends have one in their chain when the
+/// input is malformed; get it with `err.downcast_ref::<ParseError>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Byte offset of the error in the input.
+    pub offset: usize,
+    /// Line of `offset`, from 1.
+    pub line: usize,
+    /// Column of `offset` in characters, from 1. A byte that is not
+    /// UTF-8 counts as one.
+    pub column: usize,
+    /// What should have been at `offset`, e.g. `` `,` or `]` ``.
+    pub expected: String,
+    /// The line around `offset`, cut to [`EXCERPT_CONTEXT`] characters
+    /// either side of it, with control characters and bytes that are not
+    /// UTF-8 shown as `�`.
+    pub excerpt: String,
+    /// What was at `offset` instead, as the message puts it.
+    found: String,
+    /// Columns of `excerpt` before `offset`, for the caret.
+    caret: usize,
+}
+
+impl ParseError {
+    fn new(input: &[u8], offset: usize, expected: impl Into<String>) -> Self {
+        let line_start = input[..offset]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        let line_end = input[offset..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(input.len(), |i| offset + i);
+        let before = characters(&input[line_start..offset]);
+        let after = characters(&input[offset..line_end]);
+
+        let found = match characters(&input[offset..input.len().min(offset + 4)]).first() {
+            None => "end of input".to_string(),
+            Some(Ok(c)) if c.is_control() => format!("`{}`", c.escape_debug()),
+            Some(Ok(c)) => format!("`{c}`"),
+            Some(Err(b)) => format!("byte 0x{b:02x}"),
+        };
+        let shown = |c: &Result<char, u8>| match c {
+            Ok(c) if !c.is_control() => *c,
+            _ => '\u{fffd}',
+        };
+        let skip = before.len().saturating_sub(EXCERPT_CONTEXT);
+        let mut excerpt = String::from(if skip > 0 { "…" } else { "" });
+        excerpt.extend(before[skip..].iter().map(shown));
+        let caret = crate::textwidth::width(&excerpt);
+        excerpt.extend(after.iter().take(EXCERPT_CONTEXT).map(shown));
+        if after.len() > EXCERPT_CONTEXT {
+            excerpt.push('…');
+        }
+
+        Self {
+            offset,
+            line: input[..line_start].iter().filter(|&&b| b == b'\n').count() + 1,
+            column: before.len() + 1,
+            expected: expected.into(),
+            excerpt,
+            found,
+            caret,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "expected {}, found {} at line {}, column {} (byte {}):\n  {}\n  {}^",
+            self.expected,
+            self.found,
+            self.line,
+            self.column,
+            self.offset,
+            self.excerpt,
+            " ".repeat(self.caret)
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// The characters of `bytes`, with each byte that is not UTF-8 on its
+/// own.
+fn characters(bytes: &[u8]) -> Vec<Result<char, u8>> {
+    let mut characters = Vec::new();
+    for chunk in bytes.utf8_chunks() {
+        characters.extend(chunk.valid().chars().map(Ok));
+        characters.extend(chunk.invalid().iter().copied().map(Err));
+    }
+    characters
+}
+
+impl Derivation {
+    /// Parse a derivation in ATerm form, as found in a .drv file under
+    /// `/nix/store`.
+    ///
+    /// ```
+    /// use nix_diff::Derivation;
+    ///
+    /// let drv = Derivation::parse(
+    ///     r#"Derive([("out","/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello","","")],[],[],"x86_64-linux","/bin/sh",["-c","echo hello > $out"],[("name","hello"),("out","/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello")])"#,
+    /// )?;
+    /// assert_eq!(drv.platform, b"x86_64-linux");
+    /// assert_eq!(drv.env[b"name".as_slice()], b"hello");
+    /// # anyhow::Ok(())
+    /// ```
+    pub fn parse(aterm: &str) -> Result<Self> {
+        parse_derivation_string(aterm)
+    }
+
+    /// Serialize back to ATerm, byte for byte as Nix writes .drv files:
+    /// outputs, inputs and env sorted, and strings escaped the way the
+    /// parser unescapes them.
+    ///
+    /// ```
+    /// use nix_diff::Derivation;
+    ///
+    /// let aterm = r#"Derive([("out","/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello","","")],[],[],"x86_64-linux","/bin/sh",["-c","printf 'hello\n' > $out"],[("name","hello"),("out","/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello")])"#;
+    /// let mut drv = Derivation::parse(aterm)?;
+    /// assert_eq!(drv.to_aterm(), aterm.as_bytes());
+    ///
+    /// drv.env.insert(b"doCheck".as_slice().into(), b"1".to_vec());
+    /// let written = String::from_utf8(drv.to_aterm())?;
+    /// assert!(written.contains(r#"[("doCheck","1"),("name","hello")"#));
+    /// # anyhow::Ok(())
+    /// ```
+    pub fn to_aterm(&self) -> Vec<u8> {
+        crate::drvhash::unparse(self, false, &self.input_derivations)
+    }
+}
+
+/// Bytes that are not UTF-8 are never ASCII, and go through the parser as
+/// the last 128 code points, U+10FF80 to U+10FFFF.
+const ESCAPE_BASE: u32 = 0x10FF00;
+
+fn escape_invalid_utf8(content: &[u8]) -> String {
+    let escape = |escaped: &mut String, bytes: &[u8]| {
+        escaped.extend(
+            bytes
+                .iter()
+                .filter_map(|&b| char::from_u32(ESCAPE_BASE + u32::from(b))),
+        );
+    };
+    let mut escaped = String::with_capacity(content.len() + 16);
+    for chunk in content.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            // Characters in the escape range are escaped too, so that
+            // unescaping gives them back.
+            if u32::from(c) >= ESCAPE_BASE + 0x80 {
+                escape(&mut escaped, c.encode_utf8(&mut [0; 4]).as_bytes());
+            } else {
+                escaped.push(c);
+            }
+        }
+        escape(&mut escaped, chunk.invalid());
+    }
+    escaped
+}
+
+/// The offset in the original bytes of `offset` into `escaped`, each
+/// escape having stood for one byte.
+fn unescaped_offset(escaped: &str, offset: usize) -> usize {
+    escaped[..offset]
+        .chars()
+        .map(|c| {
+            if u32::from(c) >= ESCAPE_BASE + 0x80 {
+                1
+            } else {
+                c.len_utf8()
+            }
+        })
+        .sum()
+}
+
+/// Undo [`escape_invalid_utf8`] in a parsed string.
+fn unescape_bytes(bytes: Vec<u8>) -> Vec<u8> {
+    // U+10FF80..U+10FFFF is F4 8F BE/BF xx in UTF-8.
+    if !bytes.windows(2).any(|w| w == [0xf4, 0x8f]) {
+        return bytes;
+    }
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i..] {
+            [0xf4, 0x8f, b2 @ (0xbe | 0xbf), b3, ..] => {
+                out.push(((b2 & 0x3f) << 6) | (b3 & 0x3f));
+                i += 4;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Undo [`escape_invalid_utf8`] where the parser passes strings through.
+/// Store paths and output names are checked by the parser, so they can't
+/// have held such bytes.
+fn unescape_derivation(drv: Derivation) -> Derivation {
+    Derivation {
+        platform: unescape_bytes(drv.platform),
+        builder: unescape_bytes(drv.builder),
+        args: drv.args.into_iter().map(unescape_bytes).collect(),
+        env: drv
+            .env
+            .into_iter()
+            .map(|(k, v)| (ByteStr::from(unescape_bytes(k.to_vec())), unescape_bytes(v)))
+            .collect(),
+        ..drv
+    }
 }
 
 /// Infer the store directory from a .drv path like `/nix/store/hash-name.drv` → `/nix/store`.
-fn store_dir_from_drv_path(path: &str) -> Result<StoreDir> {
+pub(crate) fn store_dir_from_drv_path(path: &str) -> Result<StoreDir> {
     let p = std::path::Path::new(path);
     let parent = p
         .parent()
@@ -67,11 +623,11 @@ fn convert_derivation(
     let outputs = convert_outputs(store_dir, &drv);
     let inputs = DerivationInputs::from(&drv.inputs);
 
-    let input_derivations: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>> = inputs
+    let input_derivations: BTreeMap<ByteStr, BTreeSet<Vec<u8>>> = inputs
         .drvs
         .iter()
         .map(|(sp, oi)| {
-            let path = store_dir.display(sp).to_string().into_bytes();
+            let path = ByteStr::from(store_dir.display(sp).to_string().into_bytes());
             let outs = oi
                 .outputs
                 .iter()
@@ -81,10 +637,10 @@ fn convert_derivation(
         })
         .collect();
 
-    let input_sources: BTreeSet<Vec<u8>> = inputs
+    let input_sources: BTreeSet<ByteStr> = inputs
         .srcs
         .iter()
-        .map(|sp| store_dir.display(sp).to_string().into_bytes())
+        .map(|sp| ByteStr::from(store_dir.display(sp).to_string().into_bytes()))
         .collect();
 
     let platform = drv.platform.to_vec();
@@ -93,7 +649,7 @@ fn convert_derivation(
     let env = drv
         .env
         .iter()
-        .map(|(k, v)| (k.to_vec(), v.to_vec()))
+        .map(|(k, v)| (ByteStr::from(&k[..]), v.to_vec()))
         .collect();
 
     Derivation {
@@ -155,46 +711,10 @@ fn convert_outputs(
         .collect()
 }
 
-pub fn get_derivation_path(store_path: &str) -> Result<String> {
-    // If it's already a .drv file, return it
-    if store_path.ends_with(".drv") {
-        return Ok(store_path.to_string());
-    }
-
-    // Otherwise, query the derivation
-    let output = std::process::Command::new("nix-store")
-        .arg("--query")
-        .arg("--deriver")
-        .arg(store_path)
-        .output()
-        .with_context(|| {
-            format!("Failed to run nix-store --query --deriver for path: {store_path}")
-        })?;
-
-    if !output.status.success() {
-        return Err(anyhow!(
-            "Failed to query derivation for {}: {}",
-            store_path,
-            String::from_utf8_lossy(&output.stderr)
-        ));
-    }
-
-    let drv_path = String::from_utf8(output.stdout)?.trim().to_string();
-
-    if drv_path == "unknown-deriver" {
-        return Err(anyhow!(
-            "No deriver known for {store_path}. \
-             The derivation may have been garbage-collected or the path was \
-             added directly (e.g., via nix-store --add)."
-        ));
-    }
-
-    Ok(drv_path)
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::OutputKind;
 
     #[test]
     fn test_parse_simple_derivation() {
@@ -204,4 +724,261 @@ mod tests {
         assert_eq!(result.platform, b"/bin/bash");
         assert_eq!(result.args, vec![b"-c".to_vec(), b"echo hello".to_vec()]);
     }
+
+    #[test]
+    fn json_parses_like_aterm() {
+        let aterm = r#"Derive([("out","/nix/store/cccccccccccccccccccccccccccccccc-app","","")],[("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-lib.drv",["dev","out"])],["/nix/store/dddddddddddddddddddddddddddddddd-build.sh"],"x86_64-linux","/bin/sh",["-e","build.sh"],[("name","app"),("out","/nix/store/cccccccccccccccccccccccccccccccc-app")])"#;
+        // `nix derivation show` before Nix 2.19.
+        let old = r#"{"/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-app.drv": {
+            "args": ["-e", "build.sh"],
+            "builder": "/bin/sh",
+            "env": {"name": "app", "out": "/nix/store/cccccccccccccccccccccccccccccccc-app"},
+            "inputDrvs": {"/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-lib.drv": ["dev", "out"]},
+            "inputSrcs": ["/nix/store/dddddddddddddddddddddddddddddddd-build.sh"],
+            "outputs": {"out": {"path": "/nix/store/cccccccccccccccccccccccccccccccc-app"}},
+            "system": "x86_64-linux"
+        }}"#;
+        // Newer Nix, with `dynamicOutputs` and paths relative to the store.
+        let new = r#"{"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-app.drv": {
+            "args": ["-e", "build.sh"],
+            "builder": "/bin/sh",
+            "env": {"name": "app", "out": "/nix/store/cccccccccccccccccccccccccccccccc-app"},
+            "inputDrvs": {
+                "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-lib.drv": {"dynamicOutputs": {}, "outputs": ["dev", "out"]}
+            },
+            "inputSrcs": ["dddddddddddddddddddddddddddddddd-build.sh"],
+            "name": "app",
+            "outputs": {"out": {"path": "cccccccccccccccccccccccccccccccc-app"}},
+            "system": "x86_64-linux",
+            "version": 3
+        }}"#;
+        let expected = parse_derivation_string(aterm).unwrap();
+        assert_eq!(parse_derivation_json(old).unwrap(), expected);
+        assert_eq!(parse_derivation_json(new).unwrap(), expected);
+        let (path, _) = parse_derivation_json_in(new, "/nix/store").unwrap();
+        assert_eq!(path, "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-app.drv");
+        assert!(parse_derivation_json("{}").is_err());
+        assert!(parse_derivation_json(aterm).is_err());
+    }
+
+    #[test]
+    fn malformed_derivations_are_errors_not_panics() {
+        // Multi-byte characters next to the syntax, so that truncating or
+        // mismatching at a byte offset would split one.
+        let drv = r#"Derive([("out","/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-test","","")],[],[],"x86_64-linux","/bin/sh",["-c","échø ✓"],[("name","tést"),("ü","🚀")])"#;
+        parse_derivation_string(drv).unwrap();
+        for (i, _) in drv.char_indices() {
+            assert!(parse_derivation_string(&drv[..i]).is_err(), "{}", &drv[..i]);
+        }
+        for input in [
+            "",
+            "Derivé(",
+            "Derive(é",
+            "Derive([(\"out\"é",
+            "Derive([(\"out\",\"/nix/store/é\",\"\",\"\")],[],[],\"\",\"\",[],[])",
+            "Derive([(\"out\",\"\",\"r:sha256\",\"ééé\")],[],[],\"\",\"\",[],[])",
+            &format!("Derive({}", "[".repeat(100_000)),
+            "DrvWithVersion(\"xp-dyn-drv\",é",
+            "DrvWithVersion(\"xp-dyn-drv\",]é",
+        ] {
+            assert!(parse_derivation_string(input).is_err(), "{input}");
+        }
+        // The store directory and name come from the path.
+        for path in ["", "/", "é.drv", "/nix/store/é-x.drv"] {
+            let _ = parse_derivation_contents(path, drv);
+        }
+        assert!(parse_derivation_contents("", drv).is_err());
+    }
+
+    #[test]
+    fn syntax_errors_say_where_they_are() {
+        let message = |input: &str| {
+            let err = parse_derivation_string(input).unwrap_err();
+            err.downcast_ref::<ParseError>()
+                .unwrap_or_else(|| panic!("{err:#}"))
+                .to_string()
+        };
+        // A missing comma, far enough in to cut the excerpt.
+        assert_eq!(
+            message(
+                r#"Derive([("out","/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-test","","")][],[],"x86_64-linux","/bin/sh",[],[])"#
+            ),
+            "\
+expected `,`, found `[` at line 1, column 74 (byte 73):
+  …aaaaaaaaaaaaaaaa-test\",\"\",\"\")][],[],\"x86_64-linux\",\"/bin/sh\"…
+                                 ^"
+        );
+        assert_eq!(
+            message(r#"Derive([("out","/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-test"#),
+            "\
+expected `\"` to end the string, found end of input at line 1, column 65 (byte 64):
+  …aaaaaaaaaaaaaaaaaaaaaaaaa-test
+                                 ^"
+        );
+        assert_eq!(
+            message("<html>\n<body>"),
+            "\
+expected `Derive(`, found `<` at line 1, column 1 (byte 0):
+  <html>
+  ^"
+        );
+        // Lines only break inside strings.
+        assert_eq!(
+            message("Derive([(\"out\",\"/p\nq\",\"\" \"\")])"),
+            "\
+expected `,`, found ` ` at line 2, column 6 (byte 24):
+  q\",\"\" \"\")])
+       ^"
+        );
+
+        // The path wraps the error.
+        let path = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-test.drv";
+        let err = parse_derivation_bytes(path, b"Derive([(\"out\xff\" x").unwrap_err();
+        assert_eq!(err.to_string(), format!("Failed to parse ATerm in {path}"));
+        // Located in the file's bytes, not in the escaped string.
+        let parse = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!((parse.offset, parse.line, parse.column), (15, 1, 16));
+        assert_eq!(
+            parse.to_string(),
+            "\
+expected `,`, found ` ` at line 1, column 16 (byte 15):
+  Derive([(\"out\u{fffd}\" x
+                 ^"
+        );
+    }
+
+    /// A value of at least `len` bytes where a multi-byte character
+    /// straddles every 64 KiB boundary.
+    fn straddling_utf8(len: usize) -> String {
+        let mut value = String::new();
+        for c in ['é', '€', '🚀'].into_iter().cycle() {
+            let boundary = (value.len() / 65536 + 1) * 65536;
+            value.extend(std::iter::repeat_n('a', boundary - value.len() - 1));
+            value.push(c);
+            if value.len() >= len {
+                return value;
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn large_derivations_round_trip() {
+        let big = straddling_utf8(300_000);
+        assert!(!big.is_char_boundary(65536) && !big.is_char_boundary(131072));
+        // Runs of escapes and plain bytes of every length around the
+        // boundaries, so both sides of an escape land on them.
+        let mut escapes = String::new();
+        for run in 0..400 {
+            escapes.push_str(&"\\\"\n\t\r".repeat(run % 7));
+            escapes.push_str(&"x".repeat(run));
+        }
+        assert!(escapes.len() > 65536);
+        let mut env: BTreeMap<ByteStr, Vec<u8>> = (0..20_000)
+            .map(|i| {
+                (
+                    ByteStr::from(format!("VAR_{i:05}").into_bytes()),
+                    format!("{i}").into_bytes(),
+                )
+            })
+            .collect();
+        env.insert(b"big".as_slice().into(), big.clone().into_bytes());
+        env.insert(b"escapes".as_slice().into(), escapes.clone().into_bytes());
+        let out = b"/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-test".to_vec();
+        env.insert(b"out".as_slice().into(), out.clone());
+        let drv = Derivation {
+            outputs: BTreeMap::from([(
+                b"out".to_vec(),
+                Output {
+                    path: out,
+                    hash_algorithm: None,
+                    hash: None,
+                },
+            )]),
+            platform: b"armv7l-linux".to_vec(),
+            builder: b"/bin/sh".to_vec(),
+            args: vec![big.into_bytes(), escapes.into_bytes()],
+            env,
+            ..Default::default()
+        };
+        let aterm = drv.to_aterm();
+        assert!(aterm.len() > 1 << 20);
+        let parsed = parse_derivation_string(std::str::from_utf8(&aterm).unwrap()).unwrap();
+        assert_eq!(parsed, drv);
+        // Cut inside the big value and in the middle of an escape.
+        let text = std::str::from_utf8(&aterm).unwrap();
+        let escape = text.find("\\\"").unwrap() + 1;
+        for cut in [65536 + 1, escape, text.len() - 1] {
+            let cut = (0..=cut).rev().find(|&i| text.is_char_boundary(i)).unwrap();
+            assert!(parse_derivation_string(&text[..cut]).is_err(), "{cut}");
+        }
+    }
+
+    #[test]
+    fn outputs_of_dynamic_derivations_are_named_by_path() {
+        let drv = r#"DrvWithVersion("xp-dyn-drv",[("out","","r:sha256","")],[("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-gen.drv",(["out"],[("out",(["dev"],[("lib",["out"])]))])),("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-gen.drv",([],[("out",["out"])])),("/nix/store/cccccccccccccccccccccccccccccccc-lib.drv",["out"])],[],"x86_64-linux","/bin/sh",[],[])"#;
+        let parsed = parse_derivation_string(drv).unwrap();
+        let outputs = |path: &str| -> Vec<_> {
+            parsed.input_derivations[path.as_bytes()]
+                .iter()
+                .map(|o| String::from_utf8_lossy(o).into_owned())
+                .collect()
+        };
+        assert_eq!(
+            outputs("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-gen.drv"),
+            ["out", "out^dev", "out^lib^out"]
+        );
+        assert_eq!(
+            outputs("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-gen.drv"),
+            ["out^out"]
+        );
+        assert_eq!(
+            outputs("/nix/store/cccccccccccccccccccccccccccccccc-lib.drv"),
+            ["out"]
+        );
+        assert_eq!(
+            parsed.outputs[b"out".as_slice()].kind(),
+            OutputKind::Floating
+        );
+        assert_eq!(parsed.to_aterm(), drv.as_bytes());
+
+        let err = parse_derivation_string(&drv.replace("xp-dyn-drv", "v2")).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("unknown derivation ATerm format version `v2`"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn header_check_reads_only_the_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        // Truncated, but the header is fine.
+        fs::write(path("ok.drv"), "Derive([(\"out\"").unwrap();
+        check_derivation_header(&path("ok.drv")).unwrap();
+        fs::write(path("dyn.drv"), "DrvWithVersion(\"xp-dyn-drv\",[").unwrap();
+        check_derivation_header(&path("dyn.drv")).unwrap();
+
+        fs::write(path("html.drv"), "<html>").unwrap();
+        let err = check_derivation_header(&path("html.drv")).unwrap_err();
+        assert!(err.to_string().contains("is not a derivation"), "{err}");
+
+        let err = check_derivation_header(&path("gone.drv")).unwrap_err();
+        assert!(err.to_string().contains("Failed to read"), "{err}");
+    }
+
+    #[test]
+    fn bytes_that_are_not_utf8_are_kept() {
+        let mut drv = br#"Derive([("out","/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-test","","")],[],[],"x86_64-linux","/bin/sh",["-c","echo "#.to_vec();
+        // A Latin-1 e-acute, a stray continuation byte, and a character
+        // that looks like an escaped byte when encoded.
+        drv.extend_from_slice(b"caf\xe9\x80 \xf4\x8f\xbf\xbf\"],[(\"x\xff\",\"\xfe\")])");
+        let path = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-test.drv";
+        let parsed = parse_derivation_bytes(path, &drv).unwrap();
+        assert_eq!(parsed.args[1], b"echo caf\xe9\x80 \xf4\x8f\xbf\xbf");
+        assert_eq!(
+            parsed.env,
+            BTreeMap::from([(ByteStr::from(b"x\xff".as_slice()), b"\xfe".to_vec())])
+        );
+    }
 }
@@ -0,0 +1,295 @@
+//! `--profile-diff`: compare two user environment generations by the
+//! packages installed in them.
+//!
+//! `nix profile` records them in `manifest.json`, `nix-env` in
+//! `manifest.nix`. Only the installed store paths matter here; package
+//! names and versions come from their names, as `builtins.parseDrvName`
+//! would split them.
+
+use crate::version::{compare_versions, parse_drv_name};
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    pub path: String,
+}
+
+impl Package {
+    /// The package `full_name` (`hello-2.12`) installed as `path`.
+    fn new(full_name: &str, path: &str) -> Self {
+        let (name, version) = parse_drv_name(full_name.as_bytes());
+        Self {
+            name: String::from_utf8_lossy(name).into_owned(),
+            version: String::from_utf8_lossy(version).into_owned(),
+            path: path.to_string(),
+        }
+    }
+}
+
+/// A package present in both generations under different store paths.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageChange {
+    pub old: Package,
+    pub new: Package,
+}
+
+impl PackageChange {
+    /// `Equal` for a rebuild of the same version.
+    pub fn direction(&self) -> Ordering {
+        compare_versions(self.old.version.as_bytes(), self.new.version.as_bytes())
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ProfileDiff {
+    pub added: Vec<Package>,
+    pub removed: Vec<Package>,
+    pub changed: Vec<PackageChange>,
+}
+
+impl ProfileDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// The manifest of the profile at `path`: a profile directory or link, or
+/// the manifest file itself.
+pub fn find_manifest(path: &Path) -> Result<PathBuf> {
+    if path.is_file() {
+        return Ok(path.to_path_buf());
+    }
+    ["manifest.json", "manifest.nix"]
+        .iter()
+        .map(|name| path.join(name))
+        .find(|manifest| manifest.is_file())
+        .ok_or_else(|| {
+            anyhow!(
+                "{} is not a profile: it has no manifest.json or manifest.nix",
+                path.display()
+            )
+        })
+}
+
+/// The packages of the profile at `path`.
+pub fn load_profile(path: &Path) -> Result<Vec<Package>> {
+    let manifest = find_manifest(path)?;
+    let contents = std::fs::read_to_string(&manifest)
+        .with_context(|| format!("Failed to read {}", manifest.display()))?;
+    let paths = if manifest.extension().is_some_and(|ext| ext == "json") {
+        json_store_paths(&contents)
+            .with_context(|| format!("Failed to parse {}", manifest.display()))?
+    } else {
+        nix_store_paths(&contents)
+    };
+    Ok(packages(&paths))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonElement {
+    #[serde(default)]
+    store_paths: Vec<String>,
+}
+
+/// `elements` is a list up to manifest version 2 and an object keyed by
+/// element name from version 3 on.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonElements {
+    List(Vec<JsonElement>),
+    Named(BTreeMap<String, JsonElement>),
+}
+
+#[derive(Deserialize)]
+struct JsonManifest {
+    elements: JsonElements,
+}
+
+fn json_store_paths(contents: &str) -> Result<BTreeSet<String>> {
+    let manifest: JsonManifest = serde_json::from_str(contents)?;
+    let elements: Vec<JsonElement> = match manifest.elements {
+        JsonElements::List(elements) => elements,
+        JsonElements::Named(elements) => elements.into_values().collect(),
+    };
+    Ok(elements.into_iter().flat_map(|e| e.store_paths).collect())
+}
+
+/// The `outPath`s in a `manifest.nix`. The file is a Nix expression, but
+/// `nix-env` always writes it the same way, so scanning for
+/// `outPath = "…"` is enough and tolerates whatever else is in `meta`.
+fn nix_store_paths(contents: &str) -> BTreeSet<String> {
+    let mut paths = BTreeSet::new();
+    let mut rest = contents;
+    while let Some(pos) = rest.find("outPath") {
+        rest = &rest[pos + "outPath".len()..];
+        let value = rest.trim_start().strip_prefix('=').map(str::trim_start);
+        if let Some(path) = value
+            .and_then(|v| v.strip_prefix('"'))
+            .and_then(|v| v.split_once('"'))
+            .map(|(path, _)| path)
+        {
+            paths.insert(path.to_string());
+        }
+    }
+    paths
+}
+
+/// One package per installed derivation. The outputs of a multi-output
+/// package (`git-2.42.0` and `git-2.42.0-doc`, or `jq-1.7-bin` and
+/// `jq-1.7-man`) are one package, named without the output.
+fn packages(paths: &BTreeSet<String>) -> Vec<Package> {
+    let mut uses: BTreeMap<&str, usize> = BTreeMap::new();
+    for path in paths {
+        let name = store_path_name(path);
+        *uses.entry(name).or_default() += 1;
+        if let Some(base) = output_of(name) {
+            *uses.entry(base).or_default() += 1;
+        }
+    }
+    let mut by_base: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for path in paths {
+        let name = store_path_name(path);
+        let base = output_of(name)
+            .filter(|base| uses[base] > 1)
+            .unwrap_or(name);
+        by_base.entry(base).or_default().push(path);
+    }
+    by_base
+        .into_iter()
+        .map(|(base, paths)| {
+            // Show the path without an output suffix if there is one.
+            let path = paths
+                .iter()
+                .find(|p| store_path_name(p) == base)
+                .unwrap_or(&paths[0]);
+            Package::new(base, path)
+        })
+        .collect()
+}
+
+/// `git-2.42.0` from `git-2.42.0-doc`, if the name ends in what could be an
+/// output name.
+fn output_of(name: &str) -> Option<&str> {
+    name.rsplit_once('-')
+        .filter(|(_, output)| !output.is_empty() && output.bytes().all(|c| c.is_ascii_lowercase()))
+        .map(|(base, _)| base)
+}
+
+/// `hello-2.12` from `/nix/store/<hash>-hello-2.12`.
+fn store_path_name(path: &str) -> &str {
+    let file = path.rsplit('/').next().unwrap_or(path);
+    file.split_once('-').map_or(file, |(_, name)| name)
+}
+
+/// Pair the packages of two generations by name.
+pub fn diff_profiles(old: Vec<Package>, new: Vec<Package>) -> ProfileDiff {
+    let by_name = |packages: Vec<Package>| {
+        let mut map: BTreeMap<String, Vec<Package>> = BTreeMap::new();
+        for package in packages {
+            map.entry(package.name.clone()).or_default().push(package);
+        }
+        map
+    };
+    let (mut old, mut new) = (by_name(old), by_name(new));
+    let mut diff = ProfileDiff::default();
+    let names: BTreeSet<String> = old.keys().chain(new.keys()).cloned().collect();
+    for name in names {
+        let mut olds = old.remove(&name).unwrap_or_default();
+        let mut news = new.remove(&name).unwrap_or_default();
+        let unchanged: BTreeSet<String> = olds
+            .iter()
+            .filter(|o| news.iter().any(|n| n.path == o.path))
+            .map(|o| o.path.clone())
+            .collect();
+        olds.retain(|o| !unchanged.contains(&o.path));
+        news.retain(|n| !unchanged.contains(&n.path));
+        // Several packages of one name are only paired if unambiguous.
+        match (&olds[..], &news[..]) {
+            ([old], [new]) => diff.changed.push(PackageChange {
+                old: old.clone(),
+                new: new.clone(),
+            }),
+            _ => {
+                diff.removed.extend(olds);
+                diff.added.extend(news);
+            }
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NIX_ENV_OLD: &str = include_str!("../tests/fixtures/profiles/nix-env-41/manifest.nix");
+    const NIX_ENV_NEW: &str = include_str!("../tests/fixtures/profiles/nix-env-42/manifest.nix");
+    const PROFILE_OLD: &str = include_str!("../tests/fixtures/profiles/profile-1/manifest.json");
+    const PROFILE_NEW: &str = include_str!("../tests/fixtures/profiles/profile-2/manifest.json");
+
+    /// `name version` of each package, sorted.
+    fn names(packages: &[Package]) -> Vec<String> {
+        let mut names: Vec<String> = packages
+            .iter()
+            .map(|p| format!("{} {}", p.name, p.version))
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn reads_out_paths_of_nix_env_manifests() {
+        let old = packages(&nix_store_paths(NIX_ENV_OLD));
+        assert_eq!(
+            names(&old),
+            ["git 2.42.0", "hello 2.12.1", "htop 3.2.2", "ripgrep 14.0.3"]
+        );
+    }
+
+    #[test]
+    fn reads_store_paths_of_profile_manifests() {
+        let old = packages(&json_store_paths(PROFILE_OLD).unwrap());
+        assert_eq!(names(&old), ["firefox 121.0", "jq 1.7", "tmux 3.3a"]);
+        // Version 3 manifests key elements by name.
+        let new = packages(&json_store_paths(PROFILE_NEW).unwrap());
+        assert_eq!(
+            names(&new),
+            ["firefox 122.0", "jq 1.7", "neovim 0.9.5", "tmux 3.3a"]
+        );
+    }
+
+    #[test]
+    fn diffs_by_package_name() {
+        let old = packages(&nix_store_paths(NIX_ENV_OLD));
+        let new = packages(&nix_store_paths(NIX_ENV_NEW));
+        let diff = diff_profiles(old, new);
+        assert_eq!(names(&diff.added), ["fd 9.0.0"]);
+        assert_eq!(names(&diff.removed), ["htop 3.2.2"]);
+        let changes: Vec<_> = diff
+            .changed
+            .iter()
+            .map(|c| (c.new.name.as_str(), c.direction()))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                ("git", Ordering::Greater),
+                ("hello", Ordering::Equal),
+                ("ripgrep", Ordering::Less),
+            ]
+        );
+    }
+
+    #[test]
+    fn unchanged_profiles_have_an_empty_diff() {
+        let old = packages(&json_store_paths(PROFILE_OLD).unwrap());
+        assert!(diff_profiles(old.clone(), old).is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+//! .drv files served over HTTP(S), passed as
+//! `https://cache.example/nix/store/<hash>-name.drv`.
+//!
+//! Only the derivation itself is fetched. Its input derivations and sources
+//! are store paths that usually don't exist locally, so the diff compares
+//! those by path alone.
+
+use crate::http::HttpClient;
+use crate::types::Derivation;
+use anyhow::{Context, Result, anyhow};
+use std::path::Path;
+
+/// Whether `input` is an http(s) URL of a .drv file.
+pub fn is_drv_url(input: &str) -> bool {
+    (input.starts_with("https://") || input.starts_with("http://"))
+        && url_path(input).ends_with(".drv")
+}
+
+/// The path part of `url`, without query or fragment.
+fn url_path(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let path = rest.find('/').map_or("", |slash| &rest[slash..]);
+    path.split(['?', '#']).next().unwrap_or(path)
+}
+
+/// The store path a .drv URL stands for: the URL's path if it looks like
+/// one (`/nix/store/<hash>-x.drv`), otherwise its file name under
+/// `store_dir`. The parser takes the derivation name from it.
+pub fn store_path(url: &str, store_dir: &str) -> Result<String> {
+    let path = url_path(url);
+    let file = path.rsplit('/').next().unwrap_or_default();
+    if file.len() <= ".drv".len() {
+        return Err(anyhow!("{url} does not name a .drv file"));
+    }
+    if Path::new(path).parent() == Some(Path::new(store_dir)) {
+        Ok(path.to_string())
+    } else {
+        Ok(format!("{store_dir}/{file}"))
+    }
+}
+
+/// Download and parse the derivation at `url`. HTTP failures and parse
+/// failures are reported differently, since only the former are worth
+/// retrying.
+pub fn fetch_derivation(
+    client: &dyn HttpClient,
+    url: &str,
+    store_dir: &str,
+) -> Result<(String, Derivation)> {
+    let path = store_path(url, store_dir)?;
+    let body = client
+        .get(url)
+        .with_context(|| format!("HTTP request for {url} failed"))?
+        .ok_or_else(|| anyhow!("HTTP request for {url} failed: 404 Not Found"))?;
+    let drv = crate::parser::parse_derivation_bytes(&path, &body)
+        .with_context(|| format!("{url} is not a derivation"))?;
+    Ok((path, drv))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct MockClient(HashMap<&'static str, Result<Vec<u8>, &'static str>>);
+
+    impl HttpClient for MockClient {
+        fn get(&self, url: &str) -> Result<Option<Vec<u8>>> {
+            match self.0.get(url) {
+                Some(Ok(body)) => Ok(Some(body.clone())),
+                Some(Err(e)) => Err(anyhow!("{e}")),
+                None => Ok(None),
+            }
+        }
+    }
+
+    const DRV: &str = r#"Derive([("out","/nix/store/cccccccccccccccccccccccccccccccc-hello","","")],[],[],"x86_64-linux","/bin/sh",[],[("name","hello")])"#;
+    const URL: &str = "https://cache.example/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello.drv";
+
+    #[test]
+    fn recognizes_drv_urls() {
+        assert!(is_drv_url(URL));
+        assert!(is_drv_url("http://host/x-hello.drv?token=1"));
+        assert!(!is_drv_url("https://host/x-hello.drv.narinfo"));
+        assert!(!is_drv_url(
+            "https://github.com/NixOS/nixpkgs/archive/master.tar.gz"
+        ));
+        assert!(!is_drv_url("/nix/store/x-hello.drv"));
+    }
+
+    #[test]
+    fn maps_urls_to_store_paths() {
+        assert_eq!(
+            store_path(URL, "/nix/store").unwrap(),
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello.drv"
+        );
+        assert_eq!(
+            store_path("https://host/drvs/bbbb-x.drv?sig=1", "/nix/store").unwrap(),
+            "/nix/store/bbbb-x.drv"
+        );
+        assert!(store_path("https://host/.drv", "/nix/store").is_err());
+    }
+
+    #[test]
+    fn fetches_and_parses() {
+        let client = MockClient(HashMap::from([(URL, Ok(DRV.as_bytes().to_vec()))]));
+        let (path, drv) = fetch_derivation(&client, URL, "/nix/store").unwrap();
+        assert!(path.ends_with("-hello.drv"));
+        assert_eq!(drv.env[&b"name"[..]], b"hello");
+    }
+
+    #[test]
+    fn tells_http_failures_from_parse_failures() {
+        let client = MockClient(HashMap::from([
+            ("https://host/bad-x.drv", Ok(b"<html>oops</html>".to_vec())),
+            ("https://host/down-x.drv", Err("http status: 503")),
+        ]));
+        let err = |url| {
+            format!(
+                "{:#}",
+                fetch_derivation(&client, url, "/nix/store").unwrap_err()
+            )
+        };
+
+        let missing = err("https://host/gone-x.drv");
+        assert!(
+            missing.contains("HTTP request") && missing.contains("404"),
+            "{missing}"
+        );
+        let down = err("https://host/down-x.drv");
+        assert!(
+            down.contains("HTTP request") && down.contains("503"),
+            "{down}"
+        );
+        let bad = err("https://host/bad-x.drv");
+        assert!(bad.contains("is not a derivation"), "{bad}");
+        assert!(!bad.contains("HTTP"), "{bad}");
+    }
+}
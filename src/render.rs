@@ -1,6 +1,22 @@
+use crate::console::{Console, FixedConsole, StdoutConsole};
+use crate::diff::normalize_store_paths;
+use crate::glob::glob_match;
+use crate::hunk::{LineTag, build_hunks};
+use crate::profile::ProfileDiff;
+use crate::sanitize;
+use crate::shell;
+use crate::summary::{DiffStats, DiffSummary};
+use crate::textwidth;
 use crate::types::*;
-use similar::{ChangeTag, TextDiff as SimilarTextDiff};
-use std::io::{self, IsTerminal, Write};
+use crate::version;
+use similar::{ChangeTag, DiffOp, TextDiff as SimilarTextDiff};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::io::{self, Write};
 
 const RED: &[u8] = b"\x1b[31m";
 const GREEN: &[u8] = b"\x1b[32m";
@@ -10,8 +26,29 @@ const BOLD: &[u8] = b"\x1b[1m";
 const DIM: &[u8] = b"\x1b[2m";
 const REVERSE: &[u8] = b"\x1b[7m";
 const NOREVERSE: &[u8] = b"\x1b[27m";
+const BOLD_UNDERLINE: &[u8] = b"\x1b[1;4m";
+const NO_BOLD_UNDERLINE: &[u8] = b"\x1b[22;24m";
 const RESET: &[u8] = b"\x1b[0m";
 
+/// Longest single-line value, in bytes, that gets a character-level diff.
+/// Past it the changed spans are too scattered to help.
+const CHAR_DIFF_MAX_LEN: usize = 256;
+/// Unchanged runs shorter than this between two changed spans count as
+/// changed, so two unrelated hashes read as one span rather than confetti.
+const CHAR_DIFF_MIN_EQUAL: usize = 3;
+/// Hash characters kept to tell apart store paths with the same name.
+const SHORT_HASH_LEN: usize = 7;
+
+/// Kind of change a line describes, spelled out as a textual tag in
+/// accessible mode so no information is carried by color alone.
+#[derive(Clone, Copy)]
+enum Mark {
+    Added,
+    Removed,
+    Changed,
+    Context,
+}
+
 macro_rules! extend {
     ($output:expr, $($data:expr),+ $(,)?) => {
         $(
@@ -26,18 +63,49 @@ pub struct Renderer {
     verbose: bool,
     input_list_limit: usize,
     max_depth: Option<usize>,
+    max_env_entries: usize,
     inline_highlight: bool,
+    inline_threshold: u8,
+    /// Emphasize changed characters of short single-line values; unlike
+    /// `inline_highlight` this also works without color, using brackets.
+    char_highlight: bool,
+    accessible: bool,
+    show_unchanged_env: bool,
+    show_derived_changes: bool,
+    hyperlinks: bool,
+    summary_position: SummaryPosition,
+    /// Print the summary line and the closing totals.
+    summary: bool,
+    /// Terminal width to wrap the summary at, if output is a terminal.
+    width: Option<usize>,
+    expand_closure_info: bool,
+    dedup_diffs: bool,
+    sort_entries: EntryOrder,
+    relative_sources: bool,
+    env_detail: Vec<String>,
+    sections: SectionFilter,
+    /// Input diff bodies printed so far in this render, with their
+    /// indentation taken off, and the input they were printed for.
+    printed_bodies: RefCell<HashMap<Vec<u8>, Vec<u8>>>,
 }
 
 impl Renderer {
+    /// A renderer for the process's stdout.
     pub fn new(opts: RenderOptions) -> Self {
+        Self::with_console(opts, &StdoutConsole)
+    }
+
+    /// A renderer whose automatic choices (color, hyperlinks, wrapping)
+    /// follow `console`.
+    pub fn with_console(opts: RenderOptions, console: &dyn Console) -> Self {
+        let is_terminal = console.is_terminal();
         // Per https://no-color.org/, only a non-empty NO_COLOR disables color.
         let no_color = std::env::var("NO_COLOR").is_ok_and(|v| !v.is_empty());
         let use_color = !no_color
             && match opts.color_mode {
                 ColorMode::Always => true,
                 ColorMode::Never => false,
-                ColorMode::Auto => io::stdout().is_terminal(),
+                ColorMode::Auto => is_terminal,
             };
         Renderer {
             use_color,
@@ -45,31 +113,471 @@ impl Renderer {
             verbose: opts.verbose,
             input_list_limit: opts.input_list_limit,
             max_depth: opts.max_depth,
+            max_env_entries: opts.max_env_entries,
             // Inline highlighting relies on reverse-video ANSI escapes;
-            // without color it would just print the same text twice.
-            inline_highlight: opts.inline_highlight && use_color,
+            // without color it would just print the same text twice, unless
+            // accessible mode marks the changed words with brackets.
+            inline_highlight: opts.inline_highlight && (use_color || opts.accessible),
+            inline_threshold: opts.inline_threshold,
+            char_highlight: opts.inline_highlight,
+            accessible: opts.accessible,
+            show_unchanged_env: opts.show_unchanged_env,
+            show_derived_changes: opts.show_derived_changes || opts.verbose,
+            hyperlinks: match opts.hyperlinks {
+                HyperlinkMode::Always => true,
+                HyperlinkMode::Never => false,
+                HyperlinkMode::Auto => {
+                    is_terminal && std::env::var("TERM").is_ok_and(|term| term != "dumb")
+                }
+            },
+            summary_position: opts.summary_position,
+            summary: opts.summary,
+            width: console.width(),
+            expand_closure_info: opts.expand_closure_info,
+            dedup_diffs: opts.dedup_diffs,
+            sort_entries: opts.sort_entries,
+            relative_sources: opts.relative_sources,
+            env_detail: opts.env_detail,
+            sections: opts.sections,
+            printed_bodies: RefCell::default(),
         }
     }
 
     /// Render the diff to stdout.
     /// Returns `true` if the derivations differ, `false` if identical.
     pub fn render(&self, diff: &DerivationDiff, path1: &[u8], path2: &[u8]) -> io::Result<bool> {
-        let mut stdout = io::stdout();
-        let mut header = Vec::new();
-        extend!(header, self.red(), b"--- ", path1, self.reset(), b"\n");
-        extend!(header, self.green(), b"+++ ", path2, self.reset(), b"\n");
-        let output = self.format_derivation_diff(diff, 0, 0);
-        let differs = !output.is_empty();
+        self.write_diff(
+            &mut io::BufWriter::new(io::stdout().lock()),
+            diff,
+            path1,
+            path2,
+        )
+    }
+
+    pub fn write_diff<W: Write>(
+        &self,
+        out: &mut W,
+        diff: &DerivationDiff,
+        path1: &[u8],
+        path2: &[u8],
+    ) -> io::Result<bool> {
+        // Decided on the diff, not on what is rendered: changes the
+        // rendering hides (output paths only) still make the derivations
+        // differ.
+        let differs = diff.has_changes();
         if differs {
-            stdout.write_all(&header)?;
-            stdout.write_all(&output)?;
+            let summary = self.format_summary(&DiffSummary::of(diff));
+            out.write_all(&self.format_header(diff, path1, path2))?;
+            if self.summary && self.summary_position.top() {
+                out.write_all(&summary)?;
+            }
+            self.write_derivation_diff(&mut Sections::new(out), diff, 0, 0)?;
+            out.write_all(&self.format_totals(diff))?;
+            if self.summary && self.summary_position.bottom() {
+                out.write_all(&summary)?;
+            }
         } else {
-            stdout.write_all(b"The derivations are identical.\n")?;
+            out.write_all(&self.format_resolution(diff))?;
+            out.write_all(&self.format_flake_inputs(diff))?;
+            out.write_all(b"The derivations are identical.\n")?;
         }
-        stdout.flush()?;
+        out.flush()?;
         Ok(differs)
     }
 
+    /// Write just the body of `section` of the top-level diff, for
+    /// `--section`: no header, summary or section title, and the body not
+    /// indented below a title. Nothing is written if the section is
+    /// unchanged. Returns whether the derivations differ at all.
+    pub fn write_section_body<W: Write>(
+        &self,
+        out: &mut W,
+        diff: &DerivationDiff,
+        section: DiffSection,
+    ) -> io::Result<bool> {
+        let output = self.format_derivation_diff(&diff.only_section(section), 0, 0);
+        let mut title = Vec::new();
+        self.write_section(&mut title, section_title(section), 0);
+        let lines: Vec<&[u8]> = output
+            .split_inclusive(|&b| b == b'\n')
+            .filter(|line| *line != title.as_slice())
+            .collect();
+        // Changed inputs start at the margin with a bullet; every other
+        // body sits two columns in.
+        let indented = lines.iter().all(|line| line.starts_with(b"  "));
+        for line in lines {
+            out.write_all(if indented { &line[2..] } else { line })?;
+        }
+        out.flush()?;
+        Ok(diff.has_changes())
+    }
+
+    /// Write the totals of `diff` for `--stat`, and nothing else; the
+    /// line says so when there are none. Returns whether the derivations
+    /// differ.
+    pub fn write_stat<W: Write>(&self, out: &mut W, diff: &DerivationDiff) -> io::Result<bool> {
+        out.write_all(&self.format_counts("Totals:", &DiffStats::of(diff).parts()))?;
+        out.flush()?;
+        Ok(diff.has_changes())
+    }
+
+    /// Write a `--brief` listing: one line per input derivation added,
+    /// removed or changed anywhere in the tree, sorted by name, with the
+    /// versions it went between. Returns whether the derivations differ.
+    pub fn write_brief<W: Write>(&self, out: &mut W, diff: &DerivationDiff) -> io::Result<bool> {
+        let mut output = Vec::new();
+        for change in diff.input_changes() {
+            let (mark, color, sign) = match (&change.old, &change.new) {
+                (None, _) => (Mark::Added, self.green(), b"+ "),
+                (_, None) => (Mark::Removed, self.red(), b"- "),
+                _ => (Mark::Changed, self.yellow(), b"~ "),
+            };
+            extend!(output, self.mark(mark), color, sign, &change.name);
+            match (&change.old, &change.new) {
+                (Some(old), Some(new)) if old != new => {
+                    extend!(
+                        output,
+                        self.reset(),
+                        b" ",
+                        self.red(),
+                        old,
+                        self.reset(),
+                        self.arrow(),
+                        self.green(),
+                        new,
+                        self.reset()
+                    );
+                    if version::compare_versions(new, old) == Ordering::Less {
+                        extend!(
+                            output,
+                            self.bold(),
+                            self.yellow(),
+                            b" (downgrade)",
+                            self.reset()
+                        );
+                    }
+                }
+                (Some(version), _) | (_, Some(version)) => {
+                    if !version.is_empty() {
+                        extend!(output, b" ", version);
+                    }
+                    extend!(output, self.reset());
+                }
+                (None, None) => unreachable!("an input change has a side"),
+            }
+            output.push(b'\n');
+        }
+        out.write_all(&output)?;
+        out.flush()?;
+        Ok(diff.has_changes())
+    }
+
+    /// Write a `--profile-diff` comparison: one line per added, removed or
+    /// changed package, names aligned in a column. Returns whether the
+    /// profiles differ.
+    pub fn write_profile_diff<W: Write>(
+        &self,
+        out: &mut W,
+        diff: &ProfileDiff,
+        path1: &[u8],
+        path2: &[u8],
+    ) -> io::Result<bool> {
+        if diff.is_empty() {
+            out.write_all(b"The profiles have the same packages.\n")?;
+            out.flush()?;
+            return Ok(false);
+        }
+        let mut output = Vec::new();
+        extend!(
+            output,
+            self.red(),
+            b"--- ",
+            &self.link(path1),
+            self.reset(),
+            b"\n"
+        );
+        extend!(
+            output,
+            self.green(),
+            b"+++ ",
+            &self.link(path2),
+            self.reset(),
+            b"\n"
+        );
+
+        let count = |n: usize, what: &str| (n > 0).then(|| format!("{n} {what}"));
+        let direction = |ordering| {
+            diff.changed
+                .iter()
+                .filter(|c| c.direction() == ordering)
+                .count()
+        };
+        let parts: Vec<String> = [
+            count(diff.added.len(), "added"),
+            count(diff.removed.len(), "removed"),
+            count(direction(Ordering::Less), "upgraded"),
+            count(direction(Ordering::Greater), "downgraded"),
+            count(direction(Ordering::Equal), "rebuilt"),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        extend!(
+            output,
+            self.bold(),
+            b"Packages:",
+            self.reset(),
+            b" ",
+            parts.join(", ").as_bytes(),
+            b"\n"
+        );
+
+        let width = diff
+            .added
+            .iter()
+            .chain(&diff.removed)
+            .chain(diff.changed.iter().map(|c| &c.new))
+            .map(|p| textwidth::width(&p.name))
+            .max()
+            .unwrap_or(0);
+        let name = |output: &mut Vec<u8>, name: &str| {
+            extend!(output, textwidth::pad(name, width + 2).as_bytes());
+        };
+        for (packages, mark, color, sign) in [
+            (&diff.added, Mark::Added, self.green(), b"+ "),
+            (&diff.removed, Mark::Removed, self.red(), b"- "),
+        ] {
+            for package in packages {
+                self.write_indent(&mut output, 2);
+                extend!(output, self.mark(mark), color, sign);
+                name(&mut output, &package.name);
+                extend!(output, package.version.as_bytes(), self.reset(), b"\n");
+            }
+        }
+        for change in &diff.changed {
+            self.write_indent(&mut output, 2);
+            extend!(
+                output,
+                self.mark(Mark::Changed),
+                self.yellow(),
+                b"~ ",
+                self.reset()
+            );
+            name(&mut output, &change.new.name);
+            match change.direction() {
+                Ordering::Equal => {
+                    extend!(
+                        output,
+                        change.new.version.as_bytes(),
+                        self.dim(),
+                        b" (rebuilt)",
+                        self.reset()
+                    );
+                }
+                direction => {
+                    extend!(
+                        output,
+                        self.red(),
+                        change.old.version.as_bytes(),
+                        self.reset(),
+                        self.arrow(),
+                        self.green(),
+                        change.new.version.as_bytes(),
+                        self.reset()
+                    );
+                    if direction == Ordering::Greater {
+                        extend!(
+                            output,
+                            self.bold(),
+                            self.yellow(),
+                            b" (downgrade)",
+                            self.reset()
+                        );
+                    }
+                }
+            }
+            output.push(b'\n');
+        }
+        out.write_all(&output)?;
+        out.flush()?;
+        Ok(true)
+    }
+
+    /// The summary line.
+    fn format_summary(&self, summary: &DiffSummary) -> Vec<u8> {
+        self.format_counts("Summary:", &summary.parts())
+    }
+
+    /// The line of totals closing the report, if there are any.
+    fn format_totals(&self, diff: &DerivationDiff) -> Vec<u8> {
+        let stats = DiffStats::of(diff);
+        if !self.summary || stats.is_empty() {
+            return Vec::new();
+        }
+        self.format_counts("Totals:", &stats.parts())
+    }
+
+    /// `label` and comma-separated `parts` on one line. On a terminal too
+    /// narrow for it, the phrases continue on lines aligned after the
+    /// label.
+    fn format_counts(&self, label: &str, parts: &[String]) -> Vec<u8> {
+        let mut output = Vec::new();
+        extend!(output, self.bold(), label.as_bytes(), self.reset(), b" ");
+        let mut column = label.len() + 1;
+        for (i, part) in parts.iter().enumerate() {
+            let sep = if i + 1 < parts.len() { "," } else { "" };
+            let len = textwidth::width(part) + sep.len();
+            if i > 0 {
+                if self.width.is_some_and(|w| column + 1 + len > w) {
+                    output.push(b'\n');
+                    self.write_indent(&mut output, label.len() + 1);
+                    column = label.len() + 1;
+                } else {
+                    output.push(b' ');
+                    column += 1;
+                }
+            }
+            extend!(output, part.as_bytes(), sep.as_bytes());
+            column += len;
+        }
+        output.push(b'\n');
+        output
+    }
+
+    fn format_header(&self, diff: &DerivationDiff, path1: &[u8], path2: &[u8]) -> Vec<u8> {
+        let mut header = Vec::new();
+        extend!(
+            header,
+            self.red(),
+            b"--- ",
+            &self.link(path1),
+            self.reset(),
+            b"\n"
+        );
+        extend!(
+            header,
+            self.green(),
+            b"+++ ",
+            &self.link(path2),
+            self.reset(),
+            b"\n"
+        );
+        if let Some(sizes) = diff.closure_size {
+            extend!(
+                header,
+                self.dim(),
+                b"closure size: ",
+                group_thousands(sizes.old).as_bytes(),
+                self.arrow(),
+                group_thousands(sizes.new).as_bytes(),
+                b" derivations",
+                self.reset(),
+                b"\n"
+            );
+        }
+        header.extend(self.format_resolution(diff));
+        header.extend(self.format_flake_inputs(diff));
+        header
+    }
+
+    /// The flake inputs pinned differently, one per line: the old and new
+    /// pin of a changed input, and the pin of one that was added or
+    /// removed.
+    fn format_flake_inputs(&self, diff: &DerivationDiff) -> Vec<u8> {
+        let mut out = Vec::new();
+        let Some(changes) = diff.flake_inputs.as_ref().filter(|c| !c.is_empty()) else {
+            return out;
+        };
+        extend!(out, self.dim(), b"flake inputs:", self.reset(), b"\n");
+        for change in changes {
+            let name = change.name.as_bytes();
+            match (&change.old, &change.new) {
+                (Some(old), Some(new)) => {
+                    let (old_pin, new_pin) = if old.source == new.source {
+                        (short_pin(old), short_pin(new))
+                    } else {
+                        (
+                            format!("{} {}", old.source, short_pin(old)),
+                            format!("{} {}", new.source, short_pin(new)),
+                        )
+                    };
+                    extend!(
+                        out,
+                        b"  ",
+                        name,
+                        b": ",
+                        self.red(),
+                        old_pin.as_bytes(),
+                        self.reset(),
+                        self.arrow(),
+                        self.green(),
+                        new_pin.as_bytes(),
+                        self.reset()
+                    );
+                    if let (Some(old), Some(new)) = (old.last_modified, new.last_modified) {
+                        extend!(out, b" (", relative_age(old, new).as_bytes(), b")");
+                    }
+                    out.push(b'\n');
+                }
+                (old, new) => {
+                    let (color, sign, input) = match (old, new) {
+                        (Some(old), _) => (self.red(), b"- ", old),
+                        (_, Some(new)) => (self.green(), b"+ ", new),
+                        (None, None) => continue,
+                    };
+                    extend!(
+                        out,
+                        color,
+                        b"  ",
+                        sign,
+                        name,
+                        b": ",
+                        input.source.as_bytes(),
+                        b" ",
+                        short_pin(input).as_bytes(),
+                        self.reset(),
+                        b"\n"
+                    );
+                }
+            }
+        }
+        out
+    }
+
+    /// How each input was resolved, as a note if the comparison looks
+    /// unintended and dimmed otherwise.
+    fn format_resolution(&self, diff: &DerivationDiff) -> Vec<u8> {
+        let mut out = Vec::new();
+        let Some(resolution) = &diff.resolution else {
+            return out;
+        };
+        let (color, heading): (&[u8], &[u8]) = if resolution.mismatched {
+            (
+                self.yellow(),
+                b"Note: the inputs are of different kinds and their derivations share no \
+                  outputs; they were resolved as:",
+            )
+        } else {
+            (self.dim(), b"resolved as:")
+        };
+        extend!(out, color, heading, self.reset(), b"\n");
+        let arrow = String::from_utf8_lossy(self.arrow());
+        for (side, steps) in [("old", &resolution.old), ("new", &resolution.new)] {
+            extend!(
+                out,
+                color,
+                b"  ",
+                side.as_bytes(),
+                b": ",
+                steps.join(&arrow).as_bytes(),
+                self.reset(),
+                b"\n"
+            );
+        }
+        out
+    }
+
+    /// `diff` rendered whole, for callers that need all of it at once.
     fn format_derivation_diff(
         &self,
         diff: &DerivationDiff,
@@ -77,7 +585,62 @@ impl Renderer {
         depth: usize,
     ) -> Vec<u8> {
         let mut output = Vec::new();
+        self.write_derivation_diff(&mut Sections::new(&mut output), diff, indent, depth)
+            .expect("writing to a Vec can't fail");
+        output
+    }
+
+    /// Render `diff` to `output`, each section written out as soon as it
+    /// is formatted. Changed inputs are rendered depth first from a stack
+    /// of [`Level`]s rather than by recursion, so a chain of them
+    /// thousands deep doesn't run out of stack.
+    fn write_derivation_diff(
+        &self,
+        output: &mut Sections,
+        diff: &DerivationDiff,
+        indent: usize,
+        depth: usize,
+    ) -> io::Result<()> {
+        if depth == 0 {
+            self.printed_bodies.borrow_mut().clear();
+        }
+        let mut levels = vec![self.open_level(output, diff, indent, depth, None)?];
+        while let Some(level) = levels.last_mut() {
+            let Some(input) = level.changed.next() else {
+                let level = levels.pop().expect("the loop checked there is one");
+                self.close_level(output, level)?;
+                continue;
+            };
+            let (indent, depth) = (level.indent, level.depth + 1);
+            let Some(drv_diff) = self.write_changed_input(output, input, indent, depth) else {
+                output.flush()?;
+                continue;
+            };
+            // Unless --no-dedup-diffs, a repeat is only known once the
+            // whole body is.
+            let apart = self.dedup_diffs.then_some(input.path.as_slice());
+            if apart.is_some() {
+                output.begin_apart();
+            } else {
+                output.flush()?;
+            }
+            levels.push(self.open_level(output, drv_diff, indent + 2, depth, apart)?);
+        }
+        Ok(())
+    }
 
+    /// Start rendering `diff`: everything before its changed inputs.
+    /// `apart` is the input `diff` is for, if its body is to be matched
+    /// against those printed before.
+    fn open_level<'a>(
+        &self,
+        output: &mut Sections,
+        diff: &'a DerivationDiff,
+        indent: usize,
+        depth: usize,
+        apart: Option<&'a [u8]>,
+    ) -> io::Result<Level<'a>> {
+        let start = output.rendered();
         let DerivationDiff {
             outputs,
             platform,
@@ -85,166 +648,721 @@ impl Renderer {
             args,
             sources,
             inputs,
-            env,
+            name,
+            sandbox,
+            fetch,
             ..
         } = diff;
 
+        let already = matches!(outputs, OutputsDiff::AlreadyCompared);
+        let shows = |section| !already && self.sections.shows(section);
+        // Sandbox flags come first, whatever else changed.
+        if let (Some(flags), true) = (sandbox, shows(DiffSection::Sandbox)) {
+            self.format_sandbox_diff(output, flags, indent);
+        }
+        if let (Some(fetch), true) = (fetch, shows(DiffSection::Fetch)) {
+            self.format_fetch_diff(output, fetch, indent);
+        }
+        // Spelled out, as the raw builder and argument diffs below hide it.
+        let shell = shows(DiffSection::Builder) || shows(DiffSection::Args);
+        if let (Some(change), true) = (shell::shell_change(diff), shell) {
+            self.write_indent(output, indent);
+            extend!(
+                output,
+                self.mark(Mark::Changed),
+                self.bold(),
+                self.yellow(),
+                format!("builder shell changed: {change}").as_bytes(),
+                self.reset(),
+                b"\n"
+            );
+        }
+        if let (Some(name), true) = (name, shows(DiffSection::Name)) {
+            self.format_name_diff(output, name, indent);
+        }
+
         match outputs {
-            OutputsDiff::Changed(output_diffs) => {
+            OutputsDiff::Changed(_) if shows(DiffSection::Outputs) => {
                 // By default, hide output-path-only changes: if two derivations
                 // differ at all, their output paths differ by construction.
                 // Showing them just adds noise. We still show additions,
                 // removals, and hash/algorithm changes (FOD hash updates).
-                let interesting: Vec<_> = if self.verbose {
-                    output_diffs.iter().collect()
-                } else {
-                    output_diffs
-                        .iter()
-                        .filter(|d| !is_path_only_change(&d.diff))
-                        .collect()
-                };
+                let interesting: Vec<_> = outputs
+                    .listed()
+                    .into_iter()
+                    .filter(|d| self.verbose || !is_path_only_change(&d.diff))
+                    .collect();
                 if !interesting.is_empty() {
-                    self.write_section(&mut output, b"Outputs", indent);
+                    self.write_section(output, b"Outputs", indent);
                     for out_diff in interesting {
-                        self.format_output_diff(&mut output, out_diff, indent + 2);
+                        self.format_output_diff(output, out_diff, indent + 2);
                     }
                 }
             }
-            // AlreadyCompared is handled in format_inputs_diff so it can
+            // AlreadyCompared is handled in write_changed_input so it can
             // be collapsed onto the same line as the • header.
-            OutputsDiff::AlreadyCompared => return output,
-            OutputsDiff::Identical => {}
+            OutputsDiff::AlreadyCompared => {
+                return Ok(Level {
+                    diff,
+                    indent,
+                    depth,
+                    start,
+                    inputs: None,
+                    changed: Default::default(),
+                    apart,
+                });
+            }
+            OutputsDiff::Changed(_) | OutputsDiff::Identical => {}
         }
 
-        if let Some(plat_diff) = platform {
-            self.write_section(&mut output, b"Platform", indent);
-            self.format_string_diff(&mut output, plat_diff, indent + 2);
+        if let (Some(plat_diff), true) = (platform, shows(DiffSection::Platform)) {
+            self.write_section(output, b"Platform", indent);
+            self.format_string_diff(output, plat_diff, indent + 2);
         }
 
-        if let Some(builder_diff) = builder {
-            self.write_section(&mut output, b"Builder", indent);
-            self.format_string_diff(&mut output, builder_diff, indent + 2);
+        if let (Some(builder_diff), true) = (builder, shows(DiffSection::Builder)) {
+            self.write_section(output, b"Builder", indent);
+            self.format_string_diff(output, builder_diff, indent + 2);
         }
 
-        if let Some(arg_diffs) = args {
-            self.write_section(&mut output, b"Arguments", indent);
-            for arg_diff in arg_diffs {
-                self.write_indent(&mut output, indent + 2);
-                extend!(
+        if let (Some(arg_diffs), true) = (args, shows(DiffSection::Args)) {
+            self.format_arguments_diff(
+                output,
+                arg_diffs,
+                [&diff.original.args, &diff.new.args],
+                indent,
+            );
+        }
+
+        if let (Some(src_diff), true) = (sources, shows(DiffSection::Sources)) {
+            self.format_sources_diff(output, src_diff, indent);
+        }
+
+        let inputs = inputs.as_ref().filter(|_| shows(DiffSection::Inputs));
+        if let Some(inputs) = inputs {
+            output.flush()?;
+            // Only show section header if there are simple additions/removals
+            if !inputs.added.is_empty() || !inputs.removed.is_empty() {
+                self.write_section(output, b"Input derivations", indent);
+                self.write_path_list(
                     output,
-                    b"Argument ",
-                    arg_diff.index.to_string().as_bytes(),
-                    b":\n"
+                    inputs.removed.iter().map(|p| &p.0),
+                    Mark::Removed,
+                    indent + 2,
+                );
+                self.write_path_list(
+                    output,
+                    inputs.added.iter().map(|p| &p.0),
+                    Mark::Added,
+                    indent + 2,
                 );
-                // For multi-line arguments (like scripts), show them as a text diff
-                let StringDiff { old, new } = &arg_diff.diff;
-                if old.contains(&b'\n') || new.contains(&b'\n') {
-                    self.format_text_diff(&mut output, old, new, indent + 4);
-                } else {
-                    self.format_string_diff(&mut output, &arg_diff.diff, indent + 4);
-                }
             }
         }
+        Ok(Level {
+            diff,
+            indent,
+            depth,
+            start,
+            inputs,
+            changed: inputs
+                .map(|inputs| inputs.changed.iter())
+                .unwrap_or_default(),
+            apart,
+        })
+    }
 
-        if let Some(src_diff) = sources {
-            self.format_sources_diff(&mut output, src_diff, indent);
+    /// The rest of `level` once its changed inputs are rendered, then
+    /// its body if it was rendered apart.
+    fn close_level(&self, output: &mut Sections, level: Level) -> io::Result<()> {
+        let Level {
+            diff,
+            indent,
+            start,
+            inputs,
+            apart,
+            ..
+        } = level;
+        let DerivationDiff {
+            env,
+            name,
+            structured_attrs,
+            ignored_env,
+            ..
+        } = diff;
+        let already = matches!(diff.outputs, OutputsDiff::AlreadyCompared);
+        let shows = |section| !already && self.sections.shows(section);
+        if let Some(inputs) = inputs {
+            self.write_inputs_tail(output, inputs, indent);
         }
 
-        if let Some(inp_diff) = inputs {
-            self.format_inputs_diff(&mut output, inp_diff, indent, depth);
+        if let (Some(attrs), true) = (structured_attrs, shows(DiffSection::Attrs)) {
+            self.format_structured_attrs(output, attrs, indent);
         }
 
-        if let Some(env_diffs) = env {
+        if shows(DiffSection::Env) && (env.is_some() || self.show_unchanged_env || *ignored_env > 0)
+        {
             // Filter env vars that merely mirror output paths (e.g. $out,
             // $dev) — they duplicate the Outputs section.
-            let output_names: std::collections::HashSet<_> = diff
+            let output_names: std::collections::HashSet<&[u8]> = diff
                 .original
                 .outputs
                 .keys()
                 .chain(diff.new.outputs.keys())
+                .map(Vec::as_slice)
                 .collect();
-            let interesting: Vec<_> = env_diffs
+            let mut entries: Vec<(&[u8], Option<&EnvVarDiff>)> = env
                 .iter()
-                .filter_map(|(k, v)| v.as_ref().map(|d| (k, d)))
+                .flatten()
+                .filter_map(|(k, v)| v.as_ref().map(|d| (k.as_slice(), Some(d))))
                 .filter(|(k, _)| {
                     self.verbose
                         || (!output_names.contains(k)
                             // `builder` duplicates the Builder section.
-                            && k.as_slice() != b"builder")
+                            && *k != b"builder"
+                            && !name.as_ref().is_some_and(|n| covered_by_name(n, k)))
                 })
                 .collect();
-            if !interesting.is_empty() {
-                self.write_section(&mut output, b"Environment", indent);
-                for (key, var_diff) in interesting {
-                    self.write_indent(&mut output, indent + 2);
-                    extend!(output, key, b":\n");
-                    self.format_env_var_diff(&mut output, var_diff, indent + 4);
-                }
+            // Vars naming rebuilt inputs change whenever anything upstream
+            // does; they are only named, below the other changes.
+            let mut derived = Vec::new();
+            if !self.show_derived_changes {
+                entries.retain(|&(key, var_diff)| {
+                    let store_paths_only = var_diff.is_some_and(EnvVarDiff::store_paths_only);
+                    if store_paths_only {
+                        derived.push(key);
+                    }
+                    !store_paths_only
+                });
             }
-        }
-
-        output
-    }
-
-    fn format_output_diff(&self, output: &mut Vec<u8>, diff: &OutputDiff, indent: usize) {
-        self.write_indent(output, indent);
-        extend!(output, b"Output '", &diff.name, b"':\n");
-
-        match &diff.diff {
-            OutputDetailDiff::Added(out) => {
-                self.write_indent(output, indent + 2);
-                extend!(
-                    output,
-                    self.green(),
-                    b"+ Added: ",
-                    &out.path,
-                    self.reset(),
-                    b"\n"
-                );
+            // Past the cap, show the biggest changes; the rest are only
+            // named.
+            let mut hidden = Vec::new();
+            if self.max_env_entries > 0 && entries.len() > self.max_env_entries {
+                entries.sort_by_key(|(_, d)| {
+                    std::cmp::Reverse(d.map_or(0, EnvVarDiff::changed_bytes))
+                });
+                hidden = entries.split_off(self.max_env_entries);
+                hidden.sort_by_key(|(k, _)| *k);
             }
-            OutputDetailDiff::Removed(out) => {
-                self.write_indent(output, indent + 2);
-                extend!(
-                    output,
-                    self.red(),
-                    b"- Removed: ",
-                    &out.path,
-                    self.reset(),
-                    b"\n"
+            // Unchanged vars are context for a derivation that differs, so
+            // they only appear alongside some other change, and not at all
+            // once changes had to be cut.
+            if self.show_unchanged_env
+                && hidden.is_empty()
+                && (output.rendered() > start || !entries.is_empty() || !derived.is_empty())
+            {
+                entries.extend(
+                    diff.new
+                        .env
+                        .iter()
+                        .filter(|(k, v)| diff.original.env.get(*k) == Some(v))
+                        .map(|(k, _)| (&k[..], None)),
                 );
+                entries.sort_by_key(|(k, _)| *k);
             }
-            OutputDetailDiff::Changed {
-                path,
-                hash_algo,
-                hash,
-                ..
-            } => {
-                if let Some(path_diff) = path {
+            self.sort_by_size(&mut entries, |(_, d)| {
+                d.map_or(0, EnvVarDiff::changed_bytes)
+            });
+            if !entries.is_empty() || !hidden.is_empty() || !derived.is_empty() || *ignored_env > 0
+            {
+                self.write_section(output, b"Environment", indent);
+                for (key, var_diff) in entries {
                     self.write_indent(output, indent + 2);
-                    extend!(output, b"Path:\n");
-                    self.format_string_diff(output, path_diff, indent + 4);
+                    if let Some(EnvVarDiff::Renamed(renamed)) = var_diff {
+                        self.format_renamed_var(output, key, renamed);
+                        continue;
+                    }
+                    if var_diff.is_some() && !self.env_in_detail(key) {
+                        let sizes =
+                            [&diff.original, &diff.new].map(|d| d.env.get(key).map(Vec::len));
+                        self.format_summarized_var(output, key, sizes);
+                        continue;
+                    }
+                    extend!(output, key, b":");
+                    let similarity = match var_diff {
+                        Some(EnvVarDiff::Changed(str_diff)) if is_multiline(str_diff) => {
+                            Some(str_diff.similarity())
+                        }
+                        _ => None,
+                    };
+                    if let Some(similarity) = similarity {
+                        self.write_similarity(output, similarity);
+                    }
+                    output.push(b'\n');
+                    match var_diff {
+                        Some(var_diff) => self.format_env_var_diff(output, var_diff, indent + 4),
+                        None => self.format_unchanged_value(output, &diff.new.env[key], indent + 4),
+                    }
                 }
-                if let Some(algo_diff) = hash_algo {
+                if !hidden.is_empty() {
                     self.write_indent(output, indent + 2);
-                    extend!(output, b"Hash algorithm:\n");
-                    self.format_string_diff(output, algo_diff, indent + 4);
+                    extend!(
+                        output,
+                        self.dim(),
+                        b"... and ",
+                        hidden.len().to_string().as_bytes(),
+                        b" more (use --max-env-entries 0 to show all): ",
+                        &hidden
+                            .iter()
+                            .map(|(k, _)| *k)
+                            .collect::<Vec<_>>()
+                            .join(&b", "[..]),
+                        self.reset(),
+                        b"\n"
+                    );
                 }
-                if let Some(hash_diff) = hash {
+                if !derived.is_empty() {
+                    let count = match derived.len() {
+                        1 => "1 variable".to_string(),
+                        n => format!("{n} variables"),
+                    };
                     self.write_indent(output, indent + 2);
-                    extend!(output, b"Hash:\n");
-                    self.format_string_diff(output, hash_diff, indent + 4);
+                    extend!(
+                        output,
+                        self.mark(Mark::Changed),
+                        self.dim(),
+                        count.as_bytes(),
+                        b" changed only due to input path updates: ",
+                        &derived.join(&b", "[..]),
+                        self.reset(),
+                        b"\n"
+                    );
+                }
+                if *ignored_env > 0 {
+                    let count = match ignored_env {
+                        1 => "1 environment variable".to_string(),
+                        n => format!("{n} environment variables"),
+                    };
+                    self.write_indent(output, indent + 2);
+                    extend!(
+                        output,
+                        self.dim(),
+                        count.as_bytes(),
+                        b" ignored",
+                        self.reset(),
+                        b"\n"
+                    );
                 }
             }
         }
-    }
 
-    fn format_string_diff(&self, output: &mut Vec<u8>, diff: &StringDiff, indent: usize) {
-        let StringDiff { old, new } = diff;
-        if self.inline_highlight {
-            // Single-line pair: run a word-level diff once and highlight only
-            // the changed segments on each side. This makes store-path hash
-            // changes and version bumps immediately visible.
-            let old_toks = tokenize_path(old);
+        output.flush()?;
+        if let Some(input) = apart {
+            let body = output.end_apart();
+            match self.first_with_body(&body, indent, input) {
+                Some(first) => {
+                    self.write_indent(output, indent);
+                    extend!(
+                        output,
+                        self.dim(),
+                        b"(same changes as ",
+                        &first,
+                        b" above)",
+                        self.reset(),
+                        b"\n"
+                    );
+                }
+                None => output.extend_from_slice(&body),
+            }
+        }
+        output.flush()
+    }
+
+    fn format_name_diff(&self, output: &mut Vec<u8>, diff: &NameDiff, indent: usize) {
+        self.write_section(output, b"Name", indent);
+        let none: &[u8] = b"(none)";
+        let or_none = |s: &'_ [u8]| if s.is_empty() { none } else { s }.to_vec();
+        if let Some(StringDiff { old, new }) = &diff.pname {
+            self.write_indent(output, indent + 2);
+            extend!(
+                output,
+                self.mark(Mark::Changed),
+                b"pname: ",
+                self.red(),
+                old,
+                self.reset(),
+                self.arrow(),
+                self.green(),
+                new,
+                self.reset(),
+                b"\n"
+            );
+        }
+        if let Some(version) = &diff.version {
+            self.write_indent(output, indent + 2);
+            extend!(
+                output,
+                self.mark(Mark::Changed),
+                b"version: ",
+                self.red(),
+                &or_none(&version.old),
+                self.reset(),
+                self.arrow(),
+                self.green(),
+                &or_none(&version.new),
+                self.reset()
+            );
+            if version.downgrade {
+                extend!(
+                    output,
+                    self.bold(),
+                    self.yellow(),
+                    b" (downgrade)",
+                    self.reset()
+                );
+            }
+            output.push(b'\n');
+        }
+    }
+
+    fn format_structured_attrs(
+        &self,
+        output: &mut Vec<u8>,
+        attrs: &[StructuredAttrDiff],
+        indent: usize,
+    ) {
+        self.write_section(output, section_title(DiffSection::Attrs), indent);
+        for attr in attrs {
+            self.write_indent(output, indent + 2);
+            extend!(output, attr.path.as_bytes(), b":\n");
+            self.format_env_var_diff(output, &attr.change, indent + 4);
+        }
+    }
+
+    fn format_fetch_diff(&self, output: &mut Vec<u8>, fetch: &FetchDiff, indent: usize) {
+        self.write_section(output, section_title(DiffSection::Fetch), indent);
+        if fetch.stale_hash {
+            self.write_indent(output, indent + 2);
+            extend!(
+                output,
+                self.mark(Mark::Changed),
+                self.bold(),
+                self.red(),
+                b"warning: the source changed but the output hash did not; \
+                  the hash was probably not updated",
+                self.reset(),
+                b"\n"
+            );
+        }
+        for attr in &fetch.attrs {
+            let [old, new] = [&attr.old, &attr.new].map(|value| match value.as_deref() {
+                None => Cow::Borrowed(b"unset".as_slice()),
+                Some(b"") => Cow::Borrowed(b"\"\"".as_slice()),
+                Some(value) => sanitize::escape_unusual(value),
+            });
+            self.write_indent(output, indent + 2);
+            if attr.same_digest {
+                extend!(
+                    output,
+                    self.mark(Mark::Context),
+                    self.dim(),
+                    &attr.key,
+                    b": ",
+                    &old,
+                    self.arrow(),
+                    &new,
+                    b" (same digest)",
+                    self.reset(),
+                    b"\n"
+                );
+                continue;
+            }
+            extend!(
+                output,
+                self.mark(Mark::Changed),
+                self.bold(),
+                self.yellow(),
+                &attr.key,
+                self.reset(),
+                b": ",
+                self.red(),
+                &old,
+                self.reset(),
+                self.arrow(),
+                self.green(),
+                &new,
+                self.reset(),
+                b"\n"
+            );
+        }
+    }
+
+    fn format_sandbox_diff(&self, output: &mut Vec<u8>, flags: &[SandboxFlagDiff], indent: usize) {
+        self.write_section(output, section_title(DiffSection::Sandbox), indent);
+        for flag in flags {
+            let [old, new] = flag.shown_values();
+            self.write_indent(output, indent + 2);
+            extend!(
+                output,
+                self.mark(Mark::Changed),
+                self.bold(),
+                self.yellow(),
+                &flag.key,
+                self.reset(),
+                b": ",
+                self.red(),
+                old,
+                self.reset(),
+                self.arrow(),
+                self.green(),
+                new,
+                self.reset(),
+                b"\n"
+            );
+        }
+    }
+
+    fn format_output_diff(&self, output: &mut Vec<u8>, diff: &OutputDiff, indent: usize) {
+        self.write_indent(output, indent);
+        extend!(output, b"Output '", &diff.name, b"':\n");
+
+        match &diff.diff {
+            OutputDetailDiff::Added(out) => {
+                self.write_indent(output, indent + 2);
+                extend!(
+                    output,
+                    self.mark(Mark::Added),
+                    self.green(),
+                    b"+ Added: ",
+                    &self.link(out.shown_path()),
+                    self.reset(),
+                    b"\n"
+                );
+            }
+            OutputDetailDiff::Removed(out) => {
+                self.write_indent(output, indent + 2);
+                extend!(
+                    output,
+                    self.mark(Mark::Removed),
+                    self.red(),
+                    b"- Removed: ",
+                    &self.link(out.shown_path()),
+                    self.reset(),
+                    b"\n"
+                );
+            }
+            OutputDetailDiff::Changed {
+                old,
+                new,
+                path,
+                hash_algo,
+                hash,
+                fixed_output,
+            } => {
+                if let Some(transition) = fixed_output {
+                    let sentence: &[u8] = match transition {
+                        FixedOutputTransition::BecameFixedOutput => {
+                            b"Became a fixed-output derivation (may access the network; output pinned by hash)"
+                        }
+                        FixedOutputTransition::NoLongerFixedOutput => {
+                            b"No longer a fixed-output derivation (now built without network access)"
+                        }
+                    };
+                    self.write_indent(output, indent + 2);
+                    extend!(
+                        output,
+                        self.mark(Mark::Changed),
+                        self.bold(),
+                        self.yellow(),
+                        sentence,
+                        self.reset(),
+                        b"\n"
+                    );
+                }
+                if let Some(path_diff) = path {
+                    self.write_indent(output, indent + 2);
+                    extend!(output, b"Path:\n");
+                    self.format_string_diff(output, path_diff, indent + 4);
+                } else if old.kind() != new.kind() {
+                    // One side has no path yet, so there is nothing to diff.
+                    self.write_indent(output, indent + 2);
+                    extend!(
+                        output,
+                        self.mark(Mark::Changed),
+                        b"Path: ",
+                        self.red(),
+                        &self.link(old.shown_path()),
+                        self.reset(),
+                        self.arrow(),
+                        self.green(),
+                        &self.link(new.shown_path()),
+                        self.reset(),
+                        b"\n"
+                    );
+                }
+                if let Some(algo_diff) = hash_algo {
+                    self.write_indent(output, indent + 2);
+                    extend!(output, b"Hash algorithm:\n");
+                    self.format_string_diff(output, algo_diff, indent + 4);
+                }
+                if let Some(hash_diff) = hash {
+                    self.write_indent(output, indent + 2);
+                    extend!(output, b"Hash:\n");
+                    self.format_string_diff(output, hash_diff, indent + 4);
+                } else if let (Some(old_hash), Some(new_hash)) = (&old.hash, &new.hash) {
+                    if old_hash != new_hash {
+                        self.write_indent(output, indent + 2);
+                        extend!(
+                            output,
+                            self.mark(Mark::Context),
+                            self.dim(),
+                            b"Hash: ",
+                            old_hash,
+                            self.arrow(),
+                            new_hash,
+                            b" (same digest)",
+                            self.reset(),
+                            b"\n"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// `args` are the full old and new argument lists, for positions and
+    /// neighboring arguments.
+    fn format_arguments_diff(
+        &self,
+        output: &mut Vec<u8>,
+        diffs: &[ArgumentDiff],
+        args: [&[Vec<u8>]; 2],
+        indent: usize,
+    ) {
+        self.write_section(output, b"Arguments", indent);
+        // A `-c` script that only references rebuilt dependencies changes
+        // whenever anything upstream does; say so instead of showing it.
+        if !self.verbose && diffs.iter().all(ArgumentDiff::store_paths_only) {
+            self.write_indent(output, indent + 2);
+            extend!(
+                output,
+                self.mark(Mark::Changed),
+                self.dim(),
+                b"arguments differ only in store paths",
+                self.reset(),
+                b"\n"
+            );
+            return;
+        }
+        let mut diffs: Vec<_> = diffs.iter().collect();
+        self.sort_by_size(&mut diffs, |d| d.changed_bytes());
+        for arg_diff in diffs {
+            self.write_indent(output, indent + 2);
+            extend!(
+                output,
+                b"Argument ",
+                argument_position(arg_diff, args).as_bytes(),
+                b":"
+            );
+            // An argument that only exists on one side: show its neighbors
+            // in that list so it can be found in the real command line.
+            let (list, index, mark) = match arg_diff {
+                ArgumentDiff::Added { index, .. } => (args[1], *index, Mark::Added),
+                ArgumentDiff::Removed { index, .. } => (args[0], *index, Mark::Removed),
+                ArgumentDiff::Changed {
+                    diff,
+                    store_paths_only,
+                    ..
+                } => {
+                    self.format_argument_change(output, diff, *store_paths_only, indent);
+                    continue;
+                }
+            };
+            output.push(b'\n');
+            self.format_argument_with_neighbors(output, list, index, mark, indent + 4);
+        }
+    }
+
+    /// The rest of an `Argument …:` line for an argument on both sides.
+    fn format_argument_change(
+        &self,
+        output: &mut Vec<u8>,
+        diff: &StringDiff,
+        store_paths_only: bool,
+        indent: usize,
+    ) {
+        if store_paths_only && !self.verbose {
+            extend!(
+                output,
+                b" ",
+                self.dim(),
+                b"differs only in store paths",
+                self.reset(),
+                b"\n"
+            );
+            return;
+        }
+        // For multi-line arguments (like scripts), show them as a text diff
+        let StringDiff { old, new } = diff;
+        if old.contains(&b'\n') || new.contains(&b'\n') {
+            let similarity = diff.similarity();
+            self.write_similarity(output, similarity);
+            output.push(b'\n');
+            self.format_normalized_text_diff(output, old, new, similarity, indent + 4);
+        } else {
+            output.push(b'\n');
+            self.format_string_diff(output, diff, indent + 4);
+        }
+    }
+
+    /// Write `list[i]` as added or removed, between the arguments around
+    /// it as context.
+    fn format_argument_with_neighbors(
+        &self,
+        output: &mut Vec<u8>,
+        list: &[Vec<u8>],
+        i: usize,
+        mark: Mark,
+        indent: usize,
+    ) {
+        let (color, sign) = match mark {
+            Mark::Removed => (self.red(), b"- "),
+            _ => (self.green(), b"+ "),
+        };
+        let context = |output: &mut Vec<u8>, arg: Option<&Vec<u8>>| {
+            if let Some(arg) = arg {
+                self.write_indent(output, indent);
+                extend!(
+                    output,
+                    self.mark(Mark::Context),
+                    self.dim(),
+                    b"  ",
+                    arg,
+                    self.reset(),
+                    b"\n"
+                );
+            }
+        };
+        context(output, i.checked_sub(1).and_then(|p| list.get(p)));
+        self.write_indent(output, indent);
+        extend!(
+            output,
+            self.mark(mark),
+            color,
+            sign,
+            &list[i],
+            self.reset(),
+            b"\n"
+        );
+        context(output, list.get(i + 1));
+    }
+
+    fn format_string_diff(&self, output: &mut Vec<u8>, diff: &StringDiff, indent: usize) {
+        let StringDiff { old, new } = diff;
+        let short_line = |s: &[u8]| s.len() <= CHAR_DIFF_MAX_LEN && !s.contains(&b'\n');
+        if self.char_highlight && short_line(old) && short_line(new) {
+            let old_chars = split_chars(old);
+            let new_chars = split_chars(new);
+            let ops =
+                similar::capture_diff_slices(similar::Algorithm::Myers, &old_chars, &new_chars);
+            let (old_changed, new_changed) = changed_chars(&ops, old_chars.len(), new_chars.len());
+            self.write_char_line(output, indent, &old_chars, &old_changed, true);
+            self.write_char_line(output, indent, &new_chars, &new_changed, false);
+        } else if self.inline_highlight {
+            // Single-line pair: run a word-level diff once and highlight only
+            // the changed segments on each side. This makes store-path hash
+            // changes and version bumps immediately visible.
+            let old_toks = tokenize_path(old);
             let new_toks = tokenize_path(new);
             let ops = similar::capture_diff_slices(similar::Algorithm::Myers, &old_toks, &new_toks);
             self.write_inline_line(
@@ -269,9 +1387,25 @@ impl Renderer {
             );
         } else {
             self.write_indent(output, indent);
-            extend!(output, self.red(), b"- ", old, self.reset(), b"\n");
+            extend!(
+                output,
+                self.mark(Mark::Removed),
+                self.red(),
+                b"- ",
+                old,
+                self.reset(),
+                b"\n"
+            );
             self.write_indent(output, indent);
-            extend!(output, self.green(), b"+ ", new, self.reset(), b"\n");
+            extend!(
+                output,
+                self.mark(Mark::Added),
+                self.green(),
+                b"+ ",
+                new,
+                self.reset(),
+                b"\n"
+            );
         }
     }
 
@@ -291,7 +1425,8 @@ impl Renderer {
         is_old: bool,
     ) {
         self.write_indent(output, indent);
-        extend!(output, color, sign);
+        let mark = if is_old { Mark::Removed } else { Mark::Added };
+        extend!(output, self.mark(mark), color, sign);
         // Track reverse-video state so adjacent emphasized tokens share a
         // single REVERSE/NOREVERSE pair instead of wrapping each token.
         let mut in_rev = false;
@@ -307,14 +1442,53 @@ impl Renderer {
                 }
                 let emph = change.tag() != ChangeTag::Equal;
                 if emph != in_rev {
-                    output.extend_from_slice(if emph { REVERSE } else { NOREVERSE });
+                    output.extend_from_slice(if emph {
+                        self.emphasis_start(is_old)
+                    } else {
+                        self.emphasis_end(is_old)
+                    });
                     in_rev = emph;
                 }
                 output.extend_from_slice(change.value());
             }
         }
         if in_rev {
-            output.extend_from_slice(NOREVERSE);
+            output.extend_from_slice(self.emphasis_end(is_old));
+        }
+        extend!(output, self.reset(), b"\n");
+    }
+
+    /// Write one side of a short old/new pair with its changed characters
+    /// emphasized.
+    fn write_char_line(
+        &self,
+        output: &mut Vec<u8>,
+        indent: usize,
+        chars: &[&[u8]],
+        changed: &[bool],
+        is_old: bool,
+    ) {
+        let (color, sign, mark): (&[u8], &[u8], _) = if is_old {
+            (self.red(), b"- ", Mark::Removed)
+        } else {
+            (self.green(), b"+ ", Mark::Added)
+        };
+        self.write_indent(output, indent);
+        extend!(output, self.mark(mark), color, sign);
+        let mut emphasized = false;
+        for (c, &is_changed) in chars.iter().zip(changed) {
+            if is_changed != emphasized {
+                output.extend_from_slice(if is_changed {
+                    self.char_emphasis_start(is_old)
+                } else {
+                    self.char_emphasis_end(is_old)
+                });
+                emphasized = is_changed;
+            }
+            output.extend_from_slice(c);
+        }
+        if emphasized {
+            output.extend_from_slice(self.char_emphasis_end(is_old));
         }
         extend!(output, self.reset(), b"\n");
     }
@@ -324,29 +1498,57 @@ impl Renderer {
             added,
             removed,
             common,
+            kinds,
         } = diff;
         self.write_section(output, b"Sources", indent);
 
-        for path in removed {
+        // With --relative-sources, added and removed sources go by name
+        // and their full paths are listed once at the end.
+        let paths: Vec<&[u8]> = removed.iter().chain(added).map(Vec::as_slice).collect();
+        let names = if self.relative_sources {
+            short_names(&paths)
+        } else {
+            Vec::new()
+        };
+        let sides = removed
+            .iter()
+            .map(|_| (Mark::Removed, self.red(), b"- "))
+            .chain(added.iter().map(|_| (Mark::Added, self.green(), b"+ ")));
+        for (i, (mark, color, sign)) in sides.enumerate() {
+            let shown = match names.get(i) {
+                Some(name) => Cow::Borrowed(name.as_slice()),
+                None => self.link(paths[i]),
+            };
             self.write_indent(output, indent + 2);
-            extend!(output, self.red(), b"- ", path, self.reset(), b"\n");
-        }
-
-        for path in added {
-            self.write_indent(output, indent + 2);
-            extend!(output, self.green(), b"+ ", path, self.reset(), b"\n");
+            extend!(output, self.mark(mark), color, sign, &shown, self.reset());
+            if let Some(kind) = kinds.get(paths[i]) {
+                extend!(
+                    output,
+                    self.dim(),
+                    b"  ",
+                    describe_source_kind(kind).as_bytes(),
+                    self.reset()
+                );
+            }
+            output.push(b'\n');
         }
 
+        let mut common: Vec<_> = common.iter().collect();
+        self.sort_by_size(&mut common, |d| d.diff.changed_bytes());
         for src_diff in common {
             self.write_indent(output, indent + 2);
             extend!(
                 output,
+                self.mark(Mark::Changed),
                 self.yellow(),
                 b"~ ",
                 &src_diff.path,
-                self.reset(),
-                b"\n"
+                self.reset()
             );
+            if let TextDiff::Text { similarity, .. } = src_diff.diff {
+                self.write_similarity(output, similarity);
+            }
+            output.push(b'\n');
             match &src_diff.diff {
                 TextDiff::Binary => {
                     self.write_indent(output, indent + 4);
@@ -358,199 +1560,1034 @@ impl Renderer {
                         b"\n"
                     );
                 }
-                TextDiff::Text { old, new } => {
-                    self.format_text_diff(output, old, new, indent + 4);
+                TextDiff::Text {
+                    old,
+                    new,
+                    similarity,
+                } => {
+                    self.format_text_diff(output, old, new, *similarity, indent + 4);
+                }
+                TextDiff::ClosureInfo(info) => {
+                    self.format_closure_info(output, info, indent + 4);
+                }
+                TextDiff::NotCompared => {
+                    self.write_indent(output, indent + 4);
+                    extend!(
+                        output,
+                        self.dim(),
+                        b"(contents not compared)",
+                        self.reset(),
+                        b"\n"
+                    );
                 }
             }
         }
+
+        if !names.is_empty() {
+            self.write_indent(output, indent + 2);
+            extend!(output, self.dim(), b"Full paths:", self.reset(), b"\n");
+            for (name, path) in names.iter().zip(&paths) {
+                self.write_indent(output, indent + 4);
+                extend!(
+                    output,
+                    self.dim(),
+                    name,
+                    b": ",
+                    &self.link(path),
+                    self.reset(),
+                    b"\n"
+                );
+            }
+        }
     }
 
-    fn format_inputs_diff(
+    /// Write the bullet header of a changed input and what it says about
+    /// the outputs used. Returns the input's own diff if it is to be
+    /// rendered below, at `depth`.
+    fn write_changed_input<'a>(
         &self,
         output: &mut Vec<u8>,
-        diff: &InputsDiff,
+        inp_diff: &'a InputDiff,
         indent: usize,
         depth: usize,
-    ) {
+    ) -> Option<&'a DerivationDiff> {
+        let already = matches!(
+            inp_diff.derivation.as_deref(),
+            Some(DerivationDiff {
+                outputs: OutputsDiff::AlreadyCompared,
+                ..
+            })
+        );
+        self.write_indent(output, indent);
+        match &inp_diff.version {
+            Some(version) => {
+                let (pname, _) = version::parse_drv_path(&inp_diff.path);
+                extend!(
+                    output,
+                    self.mark(Mark::Changed),
+                    self.bold(),
+                    self.cyan(),
+                    self.bullet(),
+                    pname,
+                    b":",
+                    self.reset(),
+                    b" ",
+                    self.red(),
+                    &version.old,
+                    self.reset(),
+                    self.arrow(),
+                    self.green(),
+                    &version.new,
+                    self.reset()
+                );
+                if version.downgrade {
+                    extend!(
+                        output,
+                        self.bold(),
+                        self.yellow(),
+                        b" (downgrade)",
+                        self.reset()
+                    );
+                }
+            }
+            None => {
+                extend!(
+                    output,
+                    self.mark(Mark::Changed),
+                    self.bold(),
+                    self.cyan(),
+                    self.bullet(),
+                    &inp_diff.path,
+                    self.reset()
+                );
+            }
+        }
+        if already {
+            extend!(output, self.dim(), b" (already compared)", self.reset());
+        }
+        output.push(b'\n');
+        if let Some(side) = inp_diff.unavailable {
+            let note: &[u8] = match side {
+                Side::Old => b"(old derivation unavailable, showing the new one as added)",
+                Side::New => b"(new derivation unavailable, showing the old one as removed)",
+            };
+            self.write_indent(output, indent + 2);
+            extend!(output, self.yellow(), note, self.reset(), b"\n");
+        }
+
+        // Consumed-output changes are independent of the nested derivation
+        // diff: they describe which outputs the *parent* consumes from this
+        // input. Show them regardless of whether we also have a drv diff.
+        if let Some(out_diff) = &inp_diff.outputs {
+            let (name, _) = version::parse_drv_path(&inp_diff.path);
+            let own_outputs = inp_diff.derivation.as_deref().map(|d| &d.outputs);
+            self.format_output_set_diff(output, out_diff, name, own_outputs, indent + 2);
+        }
+        let drv_diff = inp_diff.derivation.as_deref().filter(|_| !already)?;
+        if self.max_depth.is_some_and(|d| depth > d) {
+            self.write_indent(output, indent + 2);
+            extend!(
+                output,
+                self.dim(),
+                b"(depth limit reached, use --depth to show more)",
+                self.reset(),
+                b"\n"
+            );
+            return None;
+        }
+        Some(drv_diff)
+    }
+
+    /// What follows the changed inputs: the ones collapsed, pruned or
+    /// not compared.
+    fn write_inputs_tail(&self, output: &mut Vec<u8>, diff: &InputsDiff, indent: usize) {
         let InputsDiff {
-            added,
-            removed,
-            changed,
+            pruned,
+            rebuilt,
+            not_compared,
+            timed_out,
+            depth_limited,
+            ..
         } = diff;
 
-        // Only show section header if there are simple additions/removals
-        if !added.is_empty() || !removed.is_empty() {
-            self.write_section(output, b"Input derivations", indent);
-            self.write_path_list(
+        if *depth_limited {
+            self.write_indent(output, indent);
+            extend!(
                 output,
-                removed.iter().map(|p| &p.0),
-                b"- ",
-                self.red(),
-                indent + 2,
+                self.dim(),
+                b"(changed inputs above not compared, --compare-depth reached)",
+                self.reset(),
+                b"\n"
             );
-            self.write_path_list(
+        }
+        if !rebuilt.is_empty() {
+            let count = match rebuilt.len() {
+                1 => "1 input derivation".to_string(),
+                n => format!("{n} input derivations"),
+            };
+            self.write_indent(output, indent);
+            extend!(
                 output,
-                added.iter().map(|p| &p.0),
-                b"+ ",
-                self.green(),
-                indent + 2,
+                self.mark(Mark::Changed),
+                self.dim(),
+                count.as_bytes(),
+                b" rebuilt only because of upstream changes (use --no-collapse to show them)",
+                self.reset(),
+                b"\n"
             );
         }
+        if !pruned.is_empty() {
+            self.format_pruned_inputs(output, pruned, indent);
+        }
+        if !not_compared.is_empty() {
+            self.format_not_compared_inputs(output, not_compared, *timed_out, indent);
+        }
+    }
 
-        // Show changed derivations with a compact • bullet header.
-        for inp_diff in changed {
-            let already = matches!(
-                inp_diff.derivation.as_deref(),
-                Some(DerivationDiff {
-                    outputs: OutputsDiff::AlreadyCompared,
-                    ..
-                })
+    /// The changed inputs past `--max-derivations` or `--timeout`, by
+    /// name only.
+    fn format_not_compared_inputs(
+        &self,
+        output: &mut Vec<u8>,
+        inputs: &[InputDiff],
+        timed_out: bool,
+        indent: usize,
+    ) {
+        let count = match inputs.len() {
+            1 => "1 changed input".to_string(),
+            n => format!("{n} changed inputs"),
+        };
+        self.write_indent(output, indent);
+        extend!(
+            output,
+            self.yellow(),
+            count.as_bytes(),
+            if timed_out {
+                &b" not compared (--timeout reached)"[..]
+            } else {
+                b" not compared (--max-derivations reached)"
+            },
+            self.reset(),
+            b"\n"
+        );
+        for input in inputs.iter().take(self.input_list_limit) {
+            self.write_indent(output, indent + 2);
+            extend!(
+                output,
+                self.dim(),
+                self.bullet(),
+                &self.link(&input.path),
+                self.reset(),
+                b"\n"
             );
-            self.write_indent(output, indent);
+        }
+        if inputs.len() > self.input_list_limit {
+            self.write_indent(output, indent + 2);
             extend!(
                 output,
-                self.bold(),
-                self.cyan(),
-                b"\xe2\x80\xa2 ",
-                &inp_diff.path,
-                self.reset()
+                self.dim(),
+                b"... and ",
+                (inputs.len() - self.input_list_limit)
+                    .to_string()
+                    .as_bytes(),
+                b" more",
+                self.reset(),
+                b"\n"
+            );
+        }
+    }
+
+    /// The changed inputs `--budget` left unexpanded: how many there are,
+    /// then the ones with the most changed derivations below them.
+    fn format_pruned_inputs(&self, output: &mut Vec<u8>, pruned: &[InputDiff], indent: usize) {
+        let mut sizes: Vec<_> = pruned
+            .iter()
+            .map(|input| {
+                let size = input
+                    .derivation
+                    .as_deref()
+                    .map_or(0, DerivationDiff::changed_derivations);
+                (&input.path, size)
+            })
+            .collect();
+        // Stable, so equal sizes stay in name order.
+        sizes.sort_by_key(|&(_, size)| std::cmp::Reverse(size));
+        let total: usize = sizes.iter().map(|&(_, size)| size).sum();
+        let inputs = match pruned.len() {
+            1 => "1 more changed input".to_string(),
+            n => format!("{n} more changed inputs"),
+        };
+        let derivations = match total {
+            1 => "1 changed derivation".to_string(),
+            n => format!("{n} changed derivations"),
+        };
+        self.write_indent(output, indent);
+        extend!(
+            output,
+            self.dim(),
+            b"(budget reached: ",
+            inputs.as_bytes(),
+            b" with ",
+            derivations.as_bytes(),
+            b", use --budget to show more)",
+            self.reset(),
+            b"\n"
+        );
+        for &(path, size) in sizes.iter().take(self.input_list_limit) {
+            self.write_indent(output, indent + 2);
+            extend!(
+                output,
+                self.dim(),
+                self.bullet(),
+                &self.link(path),
+                b" (",
+                size.to_string().as_bytes(),
+                b")",
+                self.reset(),
+                b"\n"
+            );
+        }
+        if sizes.len() > self.input_list_limit {
+            self.write_indent(output, indent + 2);
+            extend!(
+                output,
+                self.dim(),
+                b"... and ",
+                (sizes.len() - self.input_list_limit).to_string().as_bytes(),
+                b" more",
+                self.reset(),
+                b"\n"
             );
-            if already {
-                extend!(output, self.dim(), b" (already compared)", self.reset());
+        }
+    }
+
+    /// With `--sort-entries size`, put the biggest changes first; ties
+    /// keep their order.
+    fn sort_by_size<T>(&self, entries: &mut [T], size: impl Fn(&T) -> usize) {
+        if self.sort_entries == EntryOrder::Size {
+            entries.sort_by_cached_key(|entry| std::cmp::Reverse(size(entry)));
+        }
+    }
+
+    /// The input an identical `body` was already printed for, if any;
+    /// otherwise remember `body` as printed for `input`. Bodies match
+    /// regardless of how deeply they are nested.
+    fn first_with_body(&self, body: &[u8], indent: usize, input: &[u8]) -> Option<Vec<u8>> {
+        if body.is_empty() {
+            return None;
+        }
+        let mut dedented = Vec::with_capacity(body.len());
+        for line in body.split_inclusive(|&b| b == b'\n') {
+            let spaces = line.iter().take(indent).take_while(|&&b| b == b' ').count();
+            dedented.extend_from_slice(&line[spaces..]);
+        }
+        let mut printed = self.printed_bodies.borrow_mut();
+        match printed.entry(dedented) {
+            Entry::Occupied(first) => Some(first.get().clone()),
+            Entry::Vacant(entry) => {
+                entry.insert(input.to_vec());
+                None
             }
-            output.push(b'\n');
+        }
+    }
 
-            // Consumed-output changes are independent of the nested derivation
-            // diff: they describe which outputs the *parent* consumes from this
-            // input. Show them regardless of whether we also have a drv diff.
-            if let Some(out_diff) = &inp_diff.outputs {
-                self.write_indent(output, indent + 2);
-                extend!(output, b"Consumed outputs:\n");
-                self.format_output_set_diff(output, out_diff, indent + 4);
+    /// Which outputs of input `name` the derivation uses now. Starting to
+    /// use an output the input had all along looks the same in the input
+    /// list as using one it just gained, so `own_outputs`, the input's own
+    /// outputs diff, tells the two apart.
+    fn format_output_set_diff(
+        &self,
+        output: &mut Vec<u8>,
+        diff: &OutputSetDiff,
+        name: &[u8],
+        own_outputs: Option<&OutputsDiff>,
+        indent: usize,
+    ) {
+        let OutputSetDiff {
+            added,
+            removed,
+            kept,
+        } = diff;
+        self.write_indent(output, indent);
+        extend!(output, self.mark(Mark::Changed), b"This derivation ");
+        if added.is_empty() {
+            extend!(
+                output,
+                b"no longer uses ",
+                &self.output_names(removed, self.red()),
+                b" of ",
+                name
+            );
+        } else {
+            extend!(
+                output,
+                b"now uses ",
+                &self.output_names(added, self.green()),
+                b" of ",
+                name
+            );
+            if !removed.is_empty() {
+                extend!(output, b" instead of ", &self.quoted(removed, self.red()));
             }
-            if let (Some(drv_diff), false) = (&inp_diff.derivation, already) {
-                if self.max_depth.is_some_and(|d| depth + 1 > d) {
-                    self.write_indent(output, indent + 2);
-                    extend!(
-                        output,
-                        self.dim(),
-                        b"(depth limit reached, use --depth to show more)",
-                        self.reset(),
-                        b"\n"
-                    );
-                } else {
-                    let sub = self.format_derivation_diff(drv_diff, indent + 2, depth + 1);
-                    extend!(output, &sub);
+        }
+        if !kept.is_empty() {
+            let previously: &[u8] = if removed.is_empty() {
+                b" (previously only "
+            } else {
+                b" (still uses "
+            };
+            extend!(output, previously, &self.quoted(kept, b""), b")");
+        }
+        output.push(b'\n');
+
+        let (mut gained, mut dropped) = (BTreeSet::new(), BTreeSet::new());
+        if let Some(OutputsDiff::Changed(diffs)) = own_outputs {
+            for diff in diffs {
+                match diff.diff {
+                    OutputDetailDiff::Added(_) if added.contains(&diff.name) => {
+                        gained.insert(diff.name.clone());
+                    }
+                    OutputDetailDiff::Removed(_) if removed.contains(&diff.name) => {
+                        dropped.insert(diff.name.clone());
+                    }
+                    _ => {}
                 }
             }
         }
+        if gained.is_empty() && dropped.is_empty() {
+            return;
+        }
+        self.write_indent(output, indent);
+        extend!(output, self.mark(Mark::Changed), name, b" itself ");
+        if !gained.is_empty() {
+            extend!(
+                output,
+                b"gained ",
+                &self.output_names(&gained, self.green())
+            );
+        }
+        if !dropped.is_empty() {
+            if !gained.is_empty() {
+                extend!(output, b" and ");
+            }
+            extend!(
+                output,
+                b"dropped ",
+                &self.output_names(&dropped, self.red())
+            );
+        }
+        output.push(b'\n');
     }
 
-    fn format_output_set_diff(&self, output: &mut Vec<u8>, diff: &OutputSetDiff, indent: usize) {
-        let OutputSetDiff { added, removed } = diff;
-        for out in removed {
-            self.write_indent(output, indent);
-            extend!(output, self.red(), b"- ", out, self.reset(), b"\n");
+    /// `the 'dev' output`, `the 'dev' and 'lib' outputs`.
+    fn output_names(&self, names: &BTreeSet<Vec<u8>>, color: &[u8]) -> Vec<u8> {
+        let mut text = b"the ".to_vec();
+        extend!(text, &self.quoted(names, color));
+        extend!(
+            text,
+            if names.len() == 1 {
+                b" output".as_slice()
+            } else {
+                b" outputs"
+            }
+        );
+        text
+    }
+
+    /// `'a'`, `'a' and 'b'`, `'a', 'b' and 'c'`.
+    fn quoted(&self, names: &BTreeSet<Vec<u8>>, color: &[u8]) -> Vec<u8> {
+        let mut text = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            if i + 1 == names.len() && i > 0 {
+                extend!(text, b" and ");
+            } else if i > 0 {
+                extend!(text, b", ");
+            }
+            extend!(text, color, b"'", name, b"'");
+            if !color.is_empty() {
+                extend!(text, self.reset());
+            }
         }
-        for out in added {
-            self.write_indent(output, indent);
-            extend!(output, self.green(), b"+ ", out, self.reset(), b"\n");
+        text
+    }
+
+    /// `renamed: OLD → NEW (value unchanged)`, on one line in place of the
+    /// key and its diff.
+    fn format_renamed_var(&self, output: &mut Vec<u8>, key: &[u8], renamed: &RenamedVar) {
+        let note: &[u8] = if renamed.old == renamed.new {
+            b" (value unchanged)\n"
+        } else {
+            b" (value unchanged but for store path hashes)\n"
+        };
+        extend!(
+            output,
+            self.mark(Mark::Changed),
+            b"renamed: ",
+            self.red(),
+            &renamed.from,
+            self.reset(),
+            self.arrow(),
+            self.green(),
+            key,
+            self.reset(),
+            self.dim(),
+            note,
+            self.reset()
+        );
+    }
+
+    /// Whether `--env-detail` asks for the full value diff of `key`.
+    fn env_in_detail(&self, key: &[u8]) -> bool {
+        self.env_detail.is_empty()
+            || self
+                .env_detail
+                .iter()
+                .any(|pattern| glob_match(pattern.as_bytes(), key))
+    }
+
+    /// `KEY: changed (1,204 → 1,190 bytes)` for a var left out of
+    /// `--env-detail`, given its value sizes on both sides.
+    fn format_summarized_var(&self, output: &mut Vec<u8>, key: &[u8], sizes: [Option<usize>; 2]) {
+        extend!(output, self.mark(Mark::Changed), key, b": ", self.yellow());
+        let (what, sizes) = match sizes {
+            [Some(old), Some(new)] => {
+                let arrow = String::from_utf8_lossy(self.arrow()).into_owned();
+                let sizes = format!("{}{arrow}{}", group_thousands(old), group_thousands(new));
+                ("changed", Some(sizes))
+            }
+            [None, Some(new)] => ("added", Some(group_thousands(new))),
+            [Some(old), None] => ("removed", Some(group_thousands(old))),
+            [None, None] => ("changed", None),
+        };
+        extend!(output, what.as_bytes(), self.reset());
+        if let Some(sizes) = sizes {
+            let unit: &[u8] = if sizes == "1" { b" byte)" } else { b" bytes)" };
+            extend!(output, self.dim(), b" (", sizes.as_bytes(), unit);
         }
+        extend!(output, self.reset(), b"\n");
     }
 
     fn format_env_var_diff(&self, output: &mut Vec<u8>, diff: &EnvVarDiff, indent: usize) {
+        if let Some(escaped) = sanitize::escape_env_var_diff(diff) {
+            return self.format_env_var_diff(output, &escaped, indent);
+        }
         match diff {
             EnvVarDiff::Added(value) => {
                 self.write_indent(output, indent);
-                extend!(output, self.green(), b"+ ", value, self.reset(), b"\n");
+                extend!(
+                    output,
+                    self.mark(Mark::Added),
+                    self.green(),
+                    b"+ ",
+                    value,
+                    self.reset(),
+                    b"\n"
+                );
             }
             EnvVarDiff::Removed(value) => {
                 self.write_indent(output, indent);
-                extend!(output, self.red(), b"- ", value, self.reset(), b"\n");
+                extend!(
+                    output,
+                    self.mark(Mark::Removed),
+                    self.red(),
+                    b"- ",
+                    value,
+                    self.reset(),
+                    b"\n"
+                );
             }
             EnvVarDiff::Changed(str_diff) => {
                 let StringDiff { old, new } = str_diff;
                 // For multi-line environment variables, show them as a text diff
-                if old.contains(&b'\n') || new.contains(&b'\n') {
-                    self.format_text_diff(output, old, new, indent);
+                if is_multiline(str_diff) {
+                    self.format_text_diff(output, old, new, str_diff.similarity(), indent);
                 } else {
                     self.format_string_diff(output, str_diff, indent);
                 }
             }
-        }
-    }
-
-    /// Render a multi-line text diff with context trimming. When inline
-    /// highlighting is enabled, changed words within changed lines are
-    /// reverse-video'd (delta-style), making it obvious *what* in the line
-    /// changed — particularly useful for store-path hash changes.
-    fn format_text_diff(&self, output: &mut Vec<u8>, old: &[u8], new: &[u8], indent: usize) {
-        let diff = SimilarTextDiff::from_lines(old, new);
-
-        for (idx, group) in diff.grouped_ops(self.context_lines).iter().enumerate() {
-            if idx > 0 {
-                self.write_indent(output, indent);
-                extend!(output, b"...\n");
-            }
-            for op in group {
-                if self.inline_highlight {
-                    for change in diff.iter_inline_changes(op) {
-                        let (color, sign): (&[u8], &[u8]) = match change.tag() {
-                            ChangeTag::Delete => (self.red(), b"- "),
-                            ChangeTag::Insert => (self.green(), b"+ "),
-                            ChangeTag::Equal => (b"", b"  "),
-                        };
+            EnvVarDiff::ClosureInfo(info) => self.format_closure_info(output, info, indent),
+            EnvVarDiff::StorePaths(paths) => self.format_store_paths(output, paths, indent),
+            EnvVarDiff::Requisites(req_diff) => {
+                let sides = [
+                    (&req_diff.removed, Mark::Removed, &b"old"[..]),
+                    (&req_diff.added, Mark::Added, &b"new"[..]),
+                ];
+                for (entries, mark, side) in sides {
+                    let (color, sign): (&[u8], &[u8]) = match mark {
+                        Mark::Removed => (self.red(), b"- "),
+                        _ => (self.green(), b"+ "),
+                    };
+                    for req in entries {
                         self.write_indent(output, indent);
-                        extend!(output, color, sign);
-                        for (emphasized, value) in change.iter_strings_lossy() {
-                            let bytes = value.as_bytes();
-                            // Strip trailing newline so reset comes before \n
-                            // (avoids color bleed in some pagers).
-                            let body = bytes.strip_suffix(b"\n").unwrap_or(bytes);
-                            if emphasized {
-                                extend!(output, REVERSE, body, NOREVERSE);
-                            } else {
-                                output.extend_from_slice(body);
-                            }
+                        extend!(
+                            output,
+                            self.mark(mark),
+                            color,
+                            sign,
+                            &self.link(&req.entry),
+                            self.reset()
+                        );
+                        if let Some(in_closure) = req.in_closure {
+                            let not: &[u8] = if in_closure { b"" } else { b"not " };
+                            extend!(
+                                output,
+                                self.dim(),
+                                b" (",
+                                not,
+                                b"in ",
+                                side,
+                                b" closure)",
+                                self.reset()
+                            );
                         }
-                        extend!(output, self.reset(), b"\n");
-                    }
-                } else {
-                    for change in diff.iter_changes(op) {
-                        let (color, sign): (&[u8], &[u8]) = match change.tag() {
-                            ChangeTag::Delete => (self.red(), b"- "),
-                            ChangeTag::Insert => (self.green(), b"+ "),
-                            ChangeTag::Equal => (b"", b"  "),
-                        };
-                        self.write_indent(output, indent);
-                        let val = change.value();
-                        let body = val.strip_suffix(b"\n").unwrap_or(val);
-                        extend!(output, color, sign, body, self.reset(), b"\n");
+                        output.push(b'\n');
                     }
                 }
             }
+            // The Environment section shows renames on the key's line;
+            // here only the value is left to show.
+            EnvVarDiff::Renamed(RenamedVar { old, new, .. }) => {
+                if old != new {
+                    let diff = StringDiff {
+                        old: old.clone(),
+                        new: new.clone(),
+                    };
+                    self.format_string_diff(output, &diff, indent);
+                }
+            }
         }
     }
 
-    /// Write a list of store paths, truncating to `input_list_limit` entries
-    /// and summarizing the remainder. Large add/remove lists (e.g., after a
-    /// stdenv bump) otherwise dominate the output without adding insight.
-    fn write_path_list<'a, I>(
-        &self,
-        output: &mut Vec<u8>,
-        paths: I,
-        sign: &[u8],
-        color: &[u8],
-        indent: usize,
-    ) where
-        I: Iterator<Item = &'a Vec<u8>>,
-    {
-        let mut shown = 0;
+    /// A count line plus the added/removed paths, capped at
+    /// `input_list_limit` unless `--expand-closure-info` is given.
+    fn format_closure_info(&self, output: &mut Vec<u8>, info: &ClosureInfoDiff, indent: usize) {
+        let paths = |n: usize| if n == 1 { "path" } else { "paths" };
+        self.write_indent(output, indent);
+        extend!(
+            output,
+            self.mark(Mark::Changed),
+            self.yellow(),
+            format!(
+                "registration info: +{} {}, -{} {}",
+                info.added.len(),
+                paths(info.added.len()),
+                info.removed.len(),
+                paths(info.removed.len())
+            )
+            .as_bytes(),
+            self.reset(),
+            b"\n"
+        );
+        let expand = self.verbose || self.expand_closure_info;
+        for (list, mark) in [(&info.removed, Mark::Removed), (&info.added, Mark::Added)] {
+            self.write_capped_path_list(
+                output,
+                list.iter(),
+                mark,
+                indent,
+                expand,
+                b"--expand-closure-info",
+            );
+        }
+    }
+
+    /// One line per removed, added and rebuilt path of a store path list.
+    fn format_store_paths(&self, output: &mut Vec<u8>, diff: &StorePathsDiff, indent: usize) {
+        for (paths, mark) in [(&diff.removed, Mark::Removed), (&diff.added, Mark::Added)] {
+            let (color, sign): (&[u8], &[u8]) = match mark {
+                Mark::Removed => (self.red(), b"- "),
+                _ => (self.green(), b"+ "),
+            };
+            for path in paths {
+                self.write_indent(output, indent);
+                extend!(
+                    output,
+                    self.mark(mark),
+                    color,
+                    sign,
+                    &self.link(path),
+                    self.reset(),
+                    b"\n"
+                );
+            }
+        }
+        for rebuilt in &diff.rebuilt {
+            self.write_indent(output, indent);
+            extend!(
+                output,
+                self.mark(Mark::Changed),
+                self.yellow(),
+                b"~ ",
+                &self.link(&rebuilt.new),
+                self.reset(),
+                self.dim(),
+                b" (rebuilt)",
+                self.reset(),
+                b"\n"
+            );
+        }
+    }
+
+    /// Show an unchanged value as dim context. Multi-line values are cut
+    /// to their first line unless `--verbose` is given.
+    fn format_unchanged_value(&self, output: &mut Vec<u8>, value: &[u8], indent: usize) {
+        let escaped = sanitize::escape_unusual(value);
+        let value: &[u8] = &escaped;
+        let mut lines = value
+            .strip_suffix(b"\n")
+            .unwrap_or(value)
+            .split(|&b| b == b'\n');
+        let shown: Vec<_> = if self.verbose {
+            lines.by_ref().collect()
+        } else {
+            lines.next().into_iter().collect()
+        };
+        for line in shown {
+            self.write_indent(output, indent);
+            extend!(
+                output,
+                self.mark(Mark::Context),
+                self.dim(),
+                b"  ",
+                line,
+                self.reset(),
+                b"\n"
+            );
+        }
+        let hidden = lines.count();
+        if hidden > 0 {
+            self.write_indent(output, indent);
+            extend!(
+                output,
+                self.dim(),
+                b"  ... and ",
+                hidden.to_string().as_bytes(),
+                b" more lines (use --verbose to show all)",
+                self.reset(),
+                b"\n"
+            );
+        }
+    }
+
+    /// Whether to highlight changed words in texts `similarity` percent
+    /// similar.
+    fn inline_for(&self, similarity: u8) -> bool {
+        self.inline_highlight && similarity >= self.inline_threshold
+    }
+
+    /// ` (97% similar)` after the name of a changed text.
+    fn write_similarity(&self, output: &mut Vec<u8>, similarity: u8) {
+        extend!(
+            output,
+            b" ",
+            self.dim(),
+            format!("({similarity}% similar)").as_bytes(),
+            self.reset()
+        );
+    }
+
+    /// Render a multi-line text diff with context trimming. When inline
+    /// highlighting is enabled, changed words within changed lines are
+    /// reverse-video'd (delta-style), making it obvious *what* in the line
+    /// changed — particularly useful for store-path hash changes. Texts
+    /// below the similarity threshold are diffed by line only.
+    fn format_text_diff(
+        &self,
+        output: &mut Vec<u8>,
+        old: &[u8],
+        new: &[u8],
+        similarity: u8,
+        indent: usize,
+    ) {
+        if self.inline_for(similarity) {
+            self.format_inline_text_diff(output, old, new, indent);
+            return;
+        }
+
+        for (idx, hunk) in build_hunks(old, new, self.context_lines).iter().enumerate() {
+            if idx > 0 {
+                self.write_indent(output, indent);
+                extend!(output, b"...\n");
+            }
+            for block in &hunk.blocks {
+                self.write_line_block(output, block.tag, &block.lines, indent);
+            }
+        }
+    }
+
+    /// Write consecutive lines sharing a tag. One color span per block
+    /// rather than per line keeps large added/removed scripts from
+    /// drowning in escape sequences; the reset still precedes the final
+    /// newline. A trailing newline on each line is dropped.
+    fn write_line_block<L: AsRef<[u8]>>(
+        &self,
+        output: &mut Vec<u8>,
+        tag: LineTag,
+        lines: &[L],
+        indent: usize,
+    ) {
+        let (color, sign, mark): (&[u8], &[u8], _) = match tag {
+            LineTag::Removed => (self.red(), b"- ", Mark::Removed),
+            LineTag::Added => (self.green(), b"+ ", Mark::Added),
+            LineTag::Context => (b"", b"  ", Mark::Context),
+        };
+        for (i, line) in lines.iter().enumerate() {
+            let line = line.as_ref();
+            self.write_indent(output, indent);
+            output.extend_from_slice(self.mark(mark));
+            if i == 0 {
+                output.extend_from_slice(color);
+            }
+            extend!(output, sign, line.strip_suffix(b"\n").unwrap_or(line));
+            if i == lines.len() - 1 && !color.is_empty() {
+                output.extend_from_slice(self.reset());
+            }
+            output.push(b'\n');
+        }
+    }
+
+    /// Like [`format_text_diff`](Self::format_text_diff), but lines that
+    /// only differ in store path hashes don't count as changes: they are
+    /// shown once, dimmed and marked `~`, so the real edits stand out.
+    fn format_normalized_text_diff(
+        &self,
+        output: &mut Vec<u8>,
+        old: &[u8],
+        new: &[u8],
+        similarity: u8,
+        indent: usize,
+    ) {
+        let inline = self.inline_for(similarity);
+        let old_lines: Vec<&[u8]> = old.split_inclusive(|&b| b == b'\n').collect();
+        let new_lines: Vec<&[u8]> = new.split_inclusive(|&b| b == b'\n').collect();
+        let normalize = |lines: &[&[u8]]| -> Vec<Vec<u8>> {
+            lines
+                .iter()
+                .map(|l| normalize_store_paths(l).into_owned())
+                .collect()
+        };
+        let (old_norm, new_norm) = (normalize(&old_lines), normalize(&new_lines));
+
+        // Split equal runs where the original lines differ into their own
+        // ops, so they get context around them like any other change.
+        let mut ops = Vec::new();
+        for op in similar::capture_diff_slices(similar::Algorithm::Myers, &old_norm, &new_norm) {
+            let DiffOp::Equal {
+                old_index,
+                new_index,
+                len,
+            } = op
+            else {
+                ops.push(op);
+                continue;
+            };
+            let same = |i: usize| old_lines[old_index + i] == new_lines[new_index + i];
+            let mut start = 0;
+            while start < len {
+                let end = (start..len)
+                    .find(|&i| same(i) != same(start))
+                    .unwrap_or(len);
+                ops.push(if same(start) {
+                    DiffOp::Equal {
+                        old_index: old_index + start,
+                        new_index: new_index + start,
+                        len: end - start,
+                    }
+                } else {
+                    DiffOp::Replace {
+                        old_index: old_index + start,
+                        old_len: end - start,
+                        new_index: new_index + start,
+                        new_len: end - start,
+                    }
+                });
+                start = end;
+            }
+        }
+
+        for (idx, group) in similar::group_diff_ops(ops, self.context_lines)
+            .iter()
+            .enumerate()
+        {
+            if idx > 0 {
+                self.write_indent(output, indent);
+                extend!(output, b"...\n");
+            }
+            for op in group {
+                let (old_range, new_range) = (op.old_range(), op.new_range());
+                let old_block = &old_lines[old_range.clone()];
+                let new_block = &new_lines[new_range.clone()];
+                if let (DiffOp::Equal { .. }, false) = (op, inline) {
+                    self.write_line_block(output, LineTag::Context, new_block, indent);
+                } else if let DiffOp::Equal { .. } = op {
+                    // Match the inline renderer, which resets after every line.
+                    for line in new_block {
+                        self.write_indent(output, indent);
+                        extend!(
+                            output,
+                            self.mark(Mark::Context),
+                            b"  ",
+                            line.strip_suffix(b"\n").unwrap_or(line),
+                            self.reset(),
+                            b"\n"
+                        );
+                    }
+                } else if old_norm[old_range] == new_norm[new_range] {
+                    for line in new_block {
+                        self.write_indent(output, indent);
+                        extend!(
+                            output,
+                            self.mark(Mark::Changed),
+                            self.dim(),
+                            b"~ ",
+                            line.strip_suffix(b"\n").unwrap_or(line),
+                            self.reset(),
+                            b"\n"
+                        );
+                    }
+                } else if inline {
+                    let (old_text, new_text) = (old_block.concat(), new_block.concat());
+                    let diff = SimilarTextDiff::from_lines(&old_text[..], &new_text[..]);
+                    for op in diff.ops() {
+                        for change in diff.iter_inline_changes(op) {
+                            self.write_inline_change(output, &change, indent);
+                        }
+                    }
+                } else {
+                    self.write_line_block(output, LineTag::Removed, old_block, indent);
+                    self.write_line_block(output, LineTag::Added, new_block, indent);
+                }
+            }
+        }
+    }
+
+    fn format_inline_text_diff(&self, output: &mut Vec<u8>, old: &[u8], new: &[u8], indent: usize) {
+        let diff = SimilarTextDiff::from_lines(old, new);
+
+        for (idx, group) in diff.grouped_ops(self.context_lines).iter().enumerate() {
+            if idx > 0 {
+                self.write_indent(output, indent);
+                extend!(output, b"...\n");
+            }
+            for op in group {
+                for change in diff.iter_inline_changes(op) {
+                    self.write_inline_change(output, &change, indent);
+                }
+            }
+        }
+    }
+
+    fn write_inline_change(
+        &self,
+        output: &mut Vec<u8>,
+        change: &similar::InlineChange<'_, [u8]>,
+        indent: usize,
+    ) {
+        let (color, sign, mark): (&[u8], &[u8], _) = match change.tag() {
+            ChangeTag::Delete => (self.red(), b"- ", Mark::Removed),
+            ChangeTag::Insert => (self.green(), b"+ ", Mark::Added),
+            ChangeTag::Equal => (b"", b"  ", Mark::Context),
+        };
+        let is_old = change.tag() == ChangeTag::Delete;
+        self.write_indent(output, indent);
+        extend!(output, self.mark(mark), color, sign);
+        for (emphasized, value) in change.iter_strings_lossy() {
+            let bytes = value.as_bytes();
+            // Strip trailing newline so reset comes before \n
+            // (avoids color bleed in some pagers).
+            let body = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+            if emphasized {
+                extend!(
+                    output,
+                    self.emphasis_start(is_old),
+                    body,
+                    self.emphasis_end(is_old)
+                );
+            } else {
+                output.extend_from_slice(body);
+            }
+        }
+        extend!(output, self.reset(), b"\n");
+    }
+
+    /// Write a list of input derivations, truncating to `input_list_limit`
+    /// entries and summarizing the remainder. Large add/remove lists (e.g.,
+    /// after a stdenv bump) otherwise dominate the output without adding
+    /// insight. Each is shown as package name and version, or by its full
+    /// path with `--verbose`.
+    fn write_path_list<'a, I>(&self, output: &mut Vec<u8>, paths: I, mark: Mark, indent: usize)
+    where
+        I: Iterator<Item = &'a Vec<u8>>,
+    {
+        let label = |path: &[u8]| -> Vec<u8> {
+            if self.verbose {
+                return path.to_vec();
+            }
+            match version::parse_drv_path(path) {
+                (pname, b"") => pname.to_vec(),
+                (pname, version) => [pname, b" ", version].concat(),
+            }
+        };
+        self.write_labelled_path_list(
+            output,
+            paths,
+            label,
+            mark,
+            indent,
+            self.verbose,
+            b"--verbose",
+        );
+    }
+
+    /// List `paths`, stopping after `input_list_limit` unless `expand`;
+    /// the rest are counted with a hint to pass `flag`.
+    fn write_capped_path_list<'a, I>(
+        &self,
+        output: &mut Vec<u8>,
+        paths: I,
+        mark: Mark,
+        indent: usize,
+        expand: bool,
+        flag: &[u8],
+    ) where
+        I: Iterator<Item = &'a Vec<u8>>,
+    {
+        self.write_labelled_path_list(output, paths, <[u8]>::to_vec, mark, indent, expand, flag);
+    }
+
+    /// [`Self::write_capped_path_list`], showing each path as `label`
+    /// gives it.
+    #[allow(clippy::too_many_arguments)]
+    fn write_labelled_path_list<'a, I>(
+        &self,
+        output: &mut Vec<u8>,
+        paths: I,
+        label: impl Fn(&[u8]) -> Vec<u8>,
+        mark: Mark,
+        indent: usize,
+        expand: bool,
+        flag: &[u8],
+    ) where
+        I: Iterator<Item = &'a Vec<u8>>,
+    {
+        let (sign, color): (&[u8], &[u8]) = match mark {
+            Mark::Removed => (b"- ", self.red()),
+            _ => (b"+ ", self.green()),
+        };
+        let mut shown = 0;
         let mut hidden = 0;
         for path in paths {
-            if self.verbose || shown < self.input_list_limit {
+            if expand || shown < self.input_list_limit {
                 self.write_indent(output, indent);
-                extend!(output, color, sign, path, self.reset(), b"\n");
+                extend!(
+                    output,
+                    self.mark(mark),
+                    color,
+                    sign,
+                    &self.link_text(path, &label(path)),
+                    self.reset(),
+                    b"\n"
+                );
                 shown += 1;
             } else {
                 hidden += 1;
@@ -564,7 +2601,9 @@ impl Renderer {
                 sign,
                 b"... and ",
                 hidden.to_string().as_bytes(),
-                b" more (use --verbose to show all)",
+                b" more (use ",
+                flag,
+                b" to show all)",
                 self.reset(),
                 b"\n"
             );
@@ -582,6 +2621,103 @@ impl Renderer {
         }
     }
 
+    /// Wrap an absolute path in an OSC 8 hyperlink to `file://<path>` so
+    /// terminals make it clickable. The link is closed right after the
+    /// path, so nothing that follows can end up inside it.
+    fn link<'a>(&self, path: &'a [u8]) -> Cow<'a, [u8]> {
+        self.link_text(path, path)
+    }
+
+    /// `text` linked to `path`, like [`Self::link`].
+    fn link_text<'a>(&self, path: &[u8], text: &'a [u8]) -> Cow<'a, [u8]> {
+        if !self.hyperlinks || !path.starts_with(b"/") {
+            return Cow::Borrowed(text);
+        }
+        let mut linked = Vec::with_capacity(path.len() * 2 + 24);
+        linked.extend_from_slice(b"\x1b]8;;file://");
+        for &b in path {
+            if b.is_ascii_alphanumeric() || matches!(b, b'/' | b'-' | b'.' | b'_' | b'~') {
+                linked.push(b);
+            } else {
+                linked.extend_from_slice(format!("%{b:02X}").as_bytes());
+            }
+        }
+        extend!(linked, b"\x1b\\", text, b"\x1b]8;;\x1b\\");
+        Cow::Owned(linked)
+    }
+
+    fn mark(&self, mark: Mark) -> &'static [u8] {
+        if !self.accessible {
+            return b"";
+        }
+        match mark {
+            Mark::Added => b"[added] ",
+            Mark::Removed => b"[removed] ",
+            Mark::Changed => b"[changed] ",
+            Mark::Context => b"[context] ",
+        }
+    }
+
+    /// Opening marker for a changed word: reverse video, plus git
+    /// word-diff style brackets in accessible mode.
+    fn emphasis_start(&self, is_old: bool) -> &'static [u8] {
+        match (self.accessible, self.use_color, is_old) {
+            (false, _, _) => REVERSE,
+            (true, true, true) => b"[-\x1b[7m",
+            (true, true, false) => b"{+\x1b[7m",
+            (true, false, true) => b"[-",
+            (true, false, false) => b"{+",
+        }
+    }
+
+    fn emphasis_end(&self, is_old: bool) -> &'static [u8] {
+        match (self.accessible, self.use_color, is_old) {
+            (false, _, _) => NOREVERSE,
+            (true, true, true) => b"\x1b[27m-]",
+            (true, true, false) => b"\x1b[27m+}",
+            (true, false, true) => b"-]",
+            (true, false, false) => b"+}",
+        }
+    }
+
+    /// Opening marker for changed characters: bold and underlined with
+    /// color, `[-`/`{+` brackets without it or in accessible mode.
+    fn char_emphasis_start(&self, is_old: bool) -> &'static [u8] {
+        match (self.use_color, self.accessible, is_old) {
+            (true, false, _) => BOLD_UNDERLINE,
+            (true, true, true) => b"[-\x1b[1;4m",
+            (true, true, false) => b"{+\x1b[1;4m",
+            (false, _, true) => b"[-",
+            (false, _, false) => b"{+",
+        }
+    }
+
+    fn char_emphasis_end(&self, is_old: bool) -> &'static [u8] {
+        match (self.use_color, self.accessible, is_old) {
+            (true, false, _) => NO_BOLD_UNDERLINE,
+            (true, true, true) => b"\x1b[22;24m-]",
+            (true, true, false) => b"\x1b[22;24m+}",
+            (false, _, true) => b"-]",
+            (false, _, false) => b"+}",
+        }
+    }
+
+    fn bullet(&self) -> &'static [u8] {
+        if self.accessible {
+            b"* "
+        } else {
+            b"\xe2\x80\xa2 "
+        }
+    }
+
+    fn arrow(&self) -> &'static [u8] {
+        if self.accessible {
+            b" -> "
+        } else {
+            b" \xe2\x86\x92 "
+        }
+    }
+
     fn red(&self) -> &[u8] {
         if self.use_color { RED } else { b"" }
     }
@@ -605,79 +2741,476 @@ impl Renderer {
     }
 }
 
-/// Split on path/version separators so store-path hashes and version
-/// components become individual diff tokens. `similar::from_words` splits
-/// only on whitespace, which treats an entire store path as one token.
-fn tokenize_path(s: &[u8]) -> Vec<&[u8]> {
-    let mut toks = Vec::new();
-    let mut start = 0;
-    for (i, &b) in s.iter().enumerate() {
-        if matches!(b, b'/' | b'-' | b'.' | b'_' | b':' | b' ' | b'\t') {
-            if start < i {
-                toks.push(&s[start..i]);
-            }
-            toks.push(&s[i..i + 1]);
-            start = i + 1;
+/// A derivation [`Renderer::write_derivation_diff`] has started on,
+/// with the changed inputs it has yet to render.
+struct Level<'a> {
+    diff: &'a DerivationDiff,
+    indent: usize,
+    depth: usize,
+    /// [`Sections::rendered`] when it was started.
+    start: usize,
+    /// Its inputs, if that section is shown.
+    inputs: Option<&'a InputsDiff>,
+    changed: std::slice::Iter<'a, InputDiff>,
+    /// The input it is the diff of, when its body is rendered apart to
+    /// be matched against the bodies printed before.
+    apart: Option<&'a [u8]>,
+}
+
+/// Rendered output on its way to a writer. Sections are formatted into
+/// the buffer this derefs to and written out by [`flush`](Self::flush)
+/// once complete, so a big diff is never held in memory whole.
+struct Sections<'a> {
+    out: &'a mut dyn Write,
+    buf: Vec<u8>,
+    written: usize,
+    /// What was formatted before each body being rendered apart, see
+    /// [`begin_apart`](Self::begin_apart), and its total length.
+    held: Vec<Vec<u8>>,
+    held_len: usize,
+}
+
+impl<'a> Sections<'a> {
+    fn new(out: &'a mut dyn Write) -> Self {
+        Self {
+            out,
+            buf: Vec::new(),
+            written: 0,
+            held: Vec::new(),
+            held_len: 0,
         }
     }
-    if start < s.len() {
-        toks.push(&s[start..]);
+
+    /// Bytes rendered so far, written out or not.
+    fn rendered(&self) -> usize {
+        self.written + self.held_len + self.buf.len()
     }
-    toks
-}
 
-/// An output change that only touches the store path (not hash/algo) is a
-/// mechanical consequence of any other change and carries no information.
-fn is_path_only_change(d: &OutputDetailDiff) -> bool {
-    matches!(
-        d,
-        OutputDetailDiff::Changed {
-            hash_algo: None,
-            hash: None,
-            ..
+    /// Write out what has been formatted, unless a body is being
+    /// rendered apart.
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.held.is_empty() {
+            return Ok(());
         }
-    )
-}
+        self.out.write_all(&self.buf)?;
+        self.written += self.buf.len();
+        self.buf.clear();
+        Ok(())
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Render what follows on its own, held back until
+    /// [`end_apart`](Self::end_apart) hands it over.
+    fn begin_apart(&mut self) {
+        self.held_len += self.buf.len();
+        self.held.push(std::mem::take(&mut self.buf));
+    }
 
-    fn empty_drv() -> Derivation {
-        Derivation {
-            outputs: Default::default(),
-            input_sources: Default::default(),
-            input_derivations: Default::default(),
-            platform: Vec::new(),
-            builder: Vec::new(),
-            args: Vec::new(),
-            env: Default::default(),
-        }
+    /// What was rendered since the matching [`begin_apart`](Self::begin_apart).
+    fn end_apart(&mut self) -> Vec<u8> {
+        let before = self.held.pop().expect("a body is being rendered apart");
+        self.held_len -= before.len();
+        std::mem::replace(&mut self.buf, before)
     }
+}
 
-    #[test]
+impl std::ops::Deref for Sections<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl std::ops::DerefMut for Sections<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+/// The diff as `--color never` prints it, header and summary included.
+/// The sides are named by [`DerivationDiff::labels`], or `old` and `new`.
+impl fmt::Display for DerivationDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let opts = RenderOptions {
+            color_mode: ColorMode::Never,
+            hyperlinks: HyperlinkMode::Never,
+            ..RenderOptions::default()
+        };
+        self.write_rendered(f, opts)
+    }
+}
+
+impl DerivationDiff {
+    /// The diff rendered with `opts`, as if to a pipe: `ColorMode::Auto`
+    /// means no color, `ColorMode::Always` gives ANSI escapes.
+    pub fn to_colored_string(&self, opts: &RenderOptions) -> String {
+        struct Rendered<'a>(&'a DerivationDiff, &'a RenderOptions);
+        impl fmt::Display for Rendered<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.write_rendered(f, self.1.clone())
+            }
+        }
+        Rendered(self, opts).to_string()
+    }
+
+    fn write_rendered(&self, f: &mut fmt::Formatter<'_>, opts: RenderOptions) -> fmt::Result {
+        let renderer = Renderer::with_console(opts, &FixedConsole::default());
+        let [old, new] = match &self.labels {
+            Some([old, new]) => [&old[..], &new[..]],
+            None => [&b"old"[..], &b"new"[..]],
+        };
+        let mut out = FmtWriter {
+            f,
+            pending: Vec::new(),
+        };
+        renderer
+            .write_diff(&mut out, self, old, new)
+            .map_err(|_| fmt::Error)?;
+        out.finish()
+    }
+}
+
+/// Passes rendered bytes on to a formatter, with invalid UTF-8 replaced
+/// as by `String::from_utf8_lossy`. A character split across writes is
+/// held back until the rest of it arrives.
+struct FmtWriter<'a, 'b> {
+    f: &'a mut fmt::Formatter<'b>,
+    pending: Vec<u8>,
+}
+
+impl FmtWriter<'_, '_> {
+    /// Replace a character left incomplete at the end.
+    fn finish(self) -> fmt::Result {
+        if self.pending.is_empty() {
+            Ok(())
+        } else {
+            self.f.write_str("\u{FFFD}")
+        }
+    }
+}
+
+impl Write for FmtWriter<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        let mut start = 0;
+        while start < self.pending.len() {
+            let rest = &self.pending[start..];
+            let (valid, invalid) = match std::str::from_utf8(rest) {
+                Ok(_) => (rest.len(), None),
+                Err(e) => (e.valid_up_to(), Some(e.error_len())),
+            };
+            // `valid_up_to` guarantees this part is UTF-8.
+            let text = std::str::from_utf8(&rest[..valid]).unwrap_or_default();
+            self.f.write_str(text).map_err(io::Error::other)?;
+            start += valid;
+            match invalid {
+                None => {}
+                Some(Some(len)) => {
+                    self.f.write_str("\u{FFFD}").map_err(io::Error::other)?;
+                    start += len;
+                }
+                // Incomplete at the end: wait for the rest.
+                Some(None) => break,
+            }
+        }
+        self.pending.drain(..start);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `dir (123 files, 4.5 MiB)` or `file (2.1 KiB)`.
+fn describe_source_kind(kind: &SourceKind) -> String {
+    match *kind {
+        SourceKind::File { size } => format!("file ({})", human_size(size)),
+        SourceKind::Dir {
+            files,
+            size,
+            truncated,
+        } => {
+            let over = if truncated { "over " } else { "" };
+            let noun = if files == 1 { "file" } else { "files" };
+            let files = group_thousands(usize::try_from(files).unwrap_or(usize::MAX));
+            format!("dir ({over}{files} {noun}, {over}{})", human_size(size))
+        }
+    }
+}
+
+/// `512 bytes`, `2.1 KiB`, `4.5 MiB` and so on, in powers of 1024.
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    match bytes {
+        1 => return "1 byte".to_string(),
+        0..1024 => return format!("{bytes} bytes"),
+        _ => {}
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// The first 7 characters of the locked revision, or of the NAR hash for
+/// inputs without one, such as paths and tarballs.
+fn short_pin(input: &LockedInput) -> String {
+    match (&input.rev, &input.nar_hash) {
+        (Some(rev), _) => rev.chars().take(7).collect(),
+        (None, Some(hash)) => {
+            let hash = hash.strip_prefix("sha256-").unwrap_or(hash);
+            hash.chars().take(7).collect()
+        }
+        (None, None) => "unlocked".to_string(),
+    }
+}
+
+/// How much newer the `new` timestamp is than `old`, e.g. "3 weeks newer"
+/// or "2 days older", in the largest unit that fits at least once.
+fn relative_age(old: u64, new: u64) -> String {
+    const UNITS: [(u64, &str); 6] = [
+        (365 * 24 * 3600, "year"),
+        (30 * 24 * 3600, "month"),
+        (7 * 24 * 3600, "week"),
+        (24 * 3600, "day"),
+        (3600, "hour"),
+        (60, "minute"),
+    ];
+    let (seconds, direction) = if new >= old {
+        (new - old, "newer")
+    } else {
+        (old - new, "older")
+    };
+    if seconds < 60 {
+        return "same age".to_string();
+    }
+    let (size, unit) = UNITS
+        .into_iter()
+        .find(|&(size, _)| seconds >= size)
+        .unwrap_or(UNITS[5]);
+    let count = seconds / size;
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{count} {unit}{plural} {direction}")
+}
+
+/// `1234567` → `"1,234,567"`.
+fn group_thousands(n: usize) -> String {
+    let digits = n.to_string();
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Whether either side of `diff` spans several lines, which is then
+/// shown as a text diff.
+fn is_multiline(diff: &StringDiff) -> bool {
+    diff.old.contains(&b'\n') || diff.new.contains(&b'\n')
+}
+
+/// Where an argument sits, counting from 1: "2 of 3", "2 (old) / 3 (new)
+/// of 4", or "4 (new) of 3 → 4" when the list grew.
+fn argument_position(arg: &ArgumentDiff, [old, new]: [&[Vec<u8>]; 2]) -> String {
+    let position = match arg.indices() {
+        (Some(o), Some(n)) if o == n => (o + 1).to_string(),
+        (Some(o), Some(n)) => format!("{} (old) / {} (new)", o + 1, n + 1),
+        (Some(o), None) => format!("{} (old)", o + 1),
+        (None, Some(n)) => format!("{} (new)", n + 1),
+        (None, None) => "?".to_string(),
+    };
+    if old.len() == new.len() {
+        format!("{position} of {}", new.len())
+    } else {
+        format!("{position} of {} → {}", old.len(), new.len())
+    }
+}
+
+/// Split on path/version separators so store-path hashes and version
+/// components become individual diff tokens. `similar::from_words` splits
+/// only on whitespace, which treats an entire store path as one token.
+fn tokenize_path(s: &[u8]) -> Vec<&[u8]> {
+    let mut toks = Vec::new();
+    let mut start = 0;
+    for (i, &b) in s.iter().enumerate() {
+        if matches!(b, b'/' | b'-' | b'.' | b'_' | b':' | b' ' | b'\t') {
+            if start < i {
+                toks.push(&s[start..i]);
+            }
+            toks.push(&s[i..i + 1]);
+            start = i + 1;
+        }
+    }
+    if start < s.len() {
+        toks.push(&s[start..]);
+    }
+    toks
+}
+
+/// `s` split into characters, or into bytes if it isn't UTF-8.
+fn split_chars(s: &[u8]) -> Vec<&[u8]> {
+    match std::str::from_utf8(s) {
+        Ok(text) => text
+            .char_indices()
+            .map(|(i, c)| &s[i..i + c.len_utf8()])
+            .collect(),
+        Err(_) => s.chunks(1).collect(),
+    }
+}
+
+/// Which characters on each side of a character diff are changed, with
+/// short unchanged runs between changes folded in.
+fn changed_chars(ops: &[DiffOp], old_len: usize, new_len: usize) -> (Vec<bool>, Vec<bool>) {
+    let mut old = vec![false; old_len];
+    let mut new = vec![false; new_len];
+    for op in ops {
+        let (_, old_range, new_range) = op.as_tag_tuple();
+        if !matches!(op, DiffOp::Equal { .. }) {
+            old[old_range].fill(true);
+            new[new_range].fill(true);
+        }
+    }
+    fold_short_gaps(&mut old);
+    fold_short_gaps(&mut new);
+    (old, new)
+}
+
+fn fold_short_gaps(changed: &mut [bool]) {
+    let mut i = 0;
+    while i < changed.len() {
+        if changed[i] {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < changed.len() && !changed[i] {
+            i += 1;
+        }
+        if start > 0 && i < changed.len() && i - start < CHAR_DIFF_MIN_EQUAL {
+            changed[start..i].fill(true);
+        }
+    }
+}
+
+/// The title `section` is written under.
+fn section_title(section: DiffSection) -> &'static [u8] {
+    match section {
+        DiffSection::Sandbox => b"Sandbox/purity flags",
+        DiffSection::Fetch => b"Fixed-output fetch",
+        DiffSection::Name => b"Name",
+        DiffSection::Outputs => b"Outputs",
+        DiffSection::Platform => b"Platform",
+        DiffSection::Builder => b"Builder",
+        DiffSection::Args => b"Arguments",
+        DiffSection::Sources => b"Sources",
+        DiffSection::Inputs => b"Input derivations",
+        DiffSection::Attrs => b"Attributes (structured)",
+        DiffSection::Env => b"Environment",
+    }
+}
+
+/// Names to show for store paths: the part after the hash, plus a short
+/// hash for names that several of `paths` share.
+fn short_names(paths: &[&[u8]]) -> Vec<Vec<u8>> {
+    let split = |path: &[u8]| -> (usize, usize) {
+        let file = path.rsplit(|&b| b == b'/').next().unwrap_or(path);
+        let start = path.len() - file.len();
+        if file.len() > 33 && file[32] == b'-' {
+            (start, start + 33)
+        } else {
+            (start, start)
+        }
+    };
+    let mut counts: HashMap<&[u8], usize> = HashMap::new();
+    for path in paths {
+        let (_, name) = split(path);
+        *counts.entry(&path[name..]).or_default() += 1;
+    }
+    paths
+        .iter()
+        .map(|path| {
+            let (hash, name) = split(path);
+            let (hash, name) = (&path[hash..name], &path[name..]);
+            if counts[name] > 1 && !hash.is_empty() {
+                [name, b" (", &hash[..SHORT_HASH_LEN], b")"].concat()
+            } else {
+                name.to_vec()
+            }
+        })
+        .collect()
+}
+
+/// An output change that only touches the store path (not hash/algo) is a
+/// mechanical consequence of any other change and carries no information.
+/// Env vars whose change the Name section already shows.
+fn covered_by_name(name: &NameDiff, key: &[u8]) -> bool {
+    match key {
+        b"pname" | b"version" => true,
+        b"name" => name.covers_name,
+        _ => false,
+    }
+}
+
+fn is_path_only_change(d: &OutputDetailDiff) -> bool {
+    match d {
+        OutputDetailDiff::Changed {
+            old,
+            new,
+            hash_algo: None,
+            hash: None,
+            ..
+        } => {
+            // A hash written differently is still worth a note.
+            old.hash == new.hash
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::FixedConsole;
+    use std::collections::BTreeSet;
+
+    fn format_inputs(renderer: &Renderer, inputs: &InputsDiff) -> String {
+        let diff = DerivationDiff::new(empty_drv(), empty_drv()).inputs(inputs.clone());
+        String::from_utf8(renderer.format_derivation_diff(&diff, 0, 0)).unwrap()
+    }
+
+    fn empty_drv() -> Derivation {
+        Derivation {
+            outputs: Default::default(),
+            input_sources: Default::default(),
+            input_derivations: Default::default(),
+            platform: Vec::new(),
+            builder: Vec::new(),
+            args: Vec::new(),
+            env: Default::default(),
+        }
+    }
+
+    #[test]
     fn input_diff_shows_both_outputs_and_derivation() {
         // InputDiff.outputs describes which outputs are *consumed from* the
         // input (e.g., ["out"] -> ["out","dev"]). This is independent of the
         // nested derivation diff and must be shown even when both are set.
         let renderer = Renderer::new(RenderOptions {
             color_mode: ColorMode::Never,
+            inline_highlight: false,
             ..Default::default()
         });
-        let inner = DerivationDiff {
-            original: empty_drv(),
-            new: empty_drv(),
-            outputs: OutputsDiff::Identical,
-            platform: Some(StringDiff {
-                old: b"x86_64-linux".to_vec(),
-                new: b"aarch64-linux".to_vec(),
-            }),
-            builder: None,
-            args: None,
-            sources: None,
-            inputs: None,
-            env: None,
-        };
+        let inner = DerivationDiff::new(empty_drv(), empty_drv()).platform(StringDiff {
+            old: b"x86_64-linux".to_vec(),
+            new: b"aarch64-linux".to_vec(),
+        });
         let inputs = InputsDiff {
             added: Default::default(),
             removed: Default::default(),
@@ -686,179 +3219,1617 @@ mod tests {
                 outputs: Some(OutputSetDiff {
                     added: [b"dev".to_vec()].into(),
                     removed: Default::default(),
+                    kept: [b"out".to_vec()].into(),
                 }),
                 derivation: Some(Box::new(inner)),
+                unavailable: None,
+                version: None,
+            }],
+            pruned: Vec::new(),
+            rebuilt: Vec::new(),
+            not_compared: Vec::new(),
+            timed_out: false,
+            depth_limited: false,
+        };
+
+        let out = format_inputs(&renderer, &inputs);
+
+        assert!(out.contains("aarch64-linux"), "nested drv diff missing");
+        assert!(
+            out.contains("dev"),
+            "consumed-output change was swallowed:\n{out}"
+        );
+    }
+
+    #[test]
+    fn already_compared_input_is_labeled() {
+        // When the cycle detector short-circuits a nested diff, the output
+        // should say "already compared" rather than printing a dangling
+        // "X differs" header with no body.
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            ..Default::default()
+        });
+        let inner =
+            DerivationDiff::new(empty_drv(), empty_drv()).outputs(OutputsDiff::AlreadyCompared);
+        let inputs = InputsDiff {
+            added: Default::default(),
+            removed: Default::default(),
+            changed: vec![InputDiff {
+                path: b"foo.drv".to_vec(),
+                outputs: None,
+                derivation: Some(Box::new(inner)),
+                unavailable: None,
+                version: None,
             }],
+            pruned: Vec::new(),
+            rebuilt: Vec::new(),
+            not_compared: Vec::new(),
+            timed_out: false,
+            depth_limited: false,
+        };
+
+        let out = format_inputs(&renderer, &inputs);
+
+        assert!(out.contains("foo.drv"));
+        assert!(
+            out.contains("already compared"),
+            "expected 'already compared' marker, got:\n{out}"
+        );
+    }
+
+    fn drv_with_output(name: &[u8], path: &[u8]) -> Derivation {
+        let mut outputs = std::collections::BTreeMap::new();
+        outputs.insert(
+            name.to_vec(),
+            Output {
+                path: path.to_vec(),
+                hash_algorithm: None,
+                hash: None,
+            },
+        );
+        Derivation {
+            outputs,
+            ..empty_drv()
+        }
+    }
+
+    #[test]
+    fn hides_output_path_noise_by_default() {
+        // Output store paths differ whenever *anything* else differs. Showing
+        // them on every nested derivation floods the diff with zero-signal
+        // noise. The env var `$out` mirrors the same path and is equally
+        // useless. Both must be hidden unless --verbose is set.
+        let old = drv_with_output(b"out", b"/nix/store/aaa-foo");
+        let new = drv_with_output(b"out", b"/nix/store/bbb-foo");
+        let mut env = std::collections::BTreeMap::new();
+        env.insert(
+            b"out".to_vec(),
+            Some(EnvVarDiff::Changed(StringDiff {
+                old: b"/nix/store/aaa-foo".to_vec(),
+                new: b"/nix/store/bbb-foo".to_vec(),
+            })),
+        );
+        env.insert(
+            b"version".to_vec(),
+            Some(EnvVarDiff::Changed(StringDiff {
+                old: b"1".to_vec(),
+                new: b"2".to_vec(),
+            })),
+        );
+        let diff = DerivationDiff::new(old, new)
+            .outputs(OutputsDiff::Changed(vec![OutputDiff {
+                name: b"out".to_vec(),
+                diff: OutputDetailDiff::Changed {
+                    old: Output {
+                        path: b"/nix/store/aaa-foo".to_vec(),
+                        hash_algorithm: None,
+                        hash: None,
+                    },
+                    new: Box::new(Output {
+                        path: b"/nix/store/bbb-foo".to_vec(),
+                        hash_algorithm: None,
+                        hash: None,
+                    }),
+                    path: Some(StringDiff {
+                        old: b"/nix/store/aaa-foo".to_vec(),
+                        new: b"/nix/store/bbb-foo".to_vec(),
+                    }),
+                    hash_algo: None,
+                    hash: None,
+                    fixed_output: None,
+                },
+            }]))
+            .env(env);
+
+        let quiet = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            ..Default::default()
+        });
+        let out = String::from_utf8(quiet.format_derivation_diff(&diff, 0, 0)).unwrap();
+        assert!(!out.contains("Outputs"), "path-only output shown:\n{out}");
+        assert!(!out.contains("out:"), "$out env var shown:\n{out}");
+        assert!(out.contains("version"), "real env change missing:\n{out}");
+
+        let verbose = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            verbose: true,
+            ..Default::default()
+        });
+        let out = String::from_utf8(verbose.format_derivation_diff(&diff, 0, 0)).unwrap();
+        assert!(out.contains("Outputs"), "verbose should show outputs");
+        assert!(out.contains("out:"), "verbose should show $out");
+    }
+
+    #[test]
+    fn outputs_are_listed_out_first() {
+        let outputs = ["dev", "debug", "out", "bin"]
+            .iter()
+            .map(|name| OutputDiff {
+                name: name.as_bytes().to_vec(),
+                diff: OutputDetailDiff::Added(Output {
+                    path: format!("/nix/store/aaa-foo-{name}").into_bytes(),
+                    hash_algorithm: None,
+                    hash: None,
+                }),
+            })
+            .collect();
+        let diff =
+            DerivationDiff::new(empty_drv(), empty_drv()).outputs(OutputsDiff::Changed(outputs));
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            ..Default::default()
+        });
+        let out = String::from_utf8(renderer.format_derivation_diff(&diff, 0, 0)).unwrap();
+        let positions: Vec<_> = ["foo-out", "foo-bin", "foo-dev", "foo-debug"]
+            .iter()
+            .map(|path| {
+                out.find(path)
+                    .unwrap_or_else(|| panic!("{path} missing:\n{out}"))
+            })
+            .collect();
+        assert!(positions.is_sorted(), "{out}");
+    }
+
+    #[test]
+    fn shows_fod_hash_changes() {
+        // Fixed-output derivation hash changes are semantically meaningful
+        // (e.g., a src update) and must NOT be filtered as path noise.
+        let diff = OutputDetailDiff::Changed {
+            old: Output {
+                path: b"/nix/store/aaa-src".to_vec(),
+                hash_algorithm: Some(b"sha256".to_vec()),
+                hash: Some(b"old".to_vec()),
+            },
+            new: Box::new(Output {
+                path: b"/nix/store/bbb-src".to_vec(),
+                hash_algorithm: Some(b"sha256".to_vec()),
+                hash: Some(b"new".to_vec()),
+            }),
+            path: Some(StringDiff {
+                old: b"/nix/store/aaa-src".to_vec(),
+                new: b"/nix/store/bbb-src".to_vec(),
+            }),
+            hash_algo: None,
+            hash: Some(StringDiff {
+                old: b"old".to_vec(),
+                new: b"new".to_vec(),
+            }),
+            fixed_output: None,
+        };
+        assert!(!is_path_only_change(&diff));
+    }
+
+    #[test]
+    fn explains_fixed_output_transition() {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            ..Default::default()
+        });
+        let regular = Output {
+            path: b"/nix/store/aaa-src".to_vec(),
+            hash_algorithm: None,
+            hash: None,
+        };
+        let fixed = Output {
+            path: b"/nix/store/bbb-src".to_vec(),
+            hash_algorithm: Some(b"sha256".to_vec()),
+            hash: Some(b"abcd".to_vec()),
+        };
+        let diff = OutputDiff {
+            name: b"out".to_vec(),
+            diff: OutputDetailDiff::Changed {
+                old: regular,
+                new: Box::new(fixed),
+                path: None,
+                hash_algo: Some(StringDiff {
+                    old: Vec::new(),
+                    new: b"sha256".to_vec(),
+                }),
+                hash: Some(StringDiff {
+                    old: Vec::new(),
+                    new: b"abcd".to_vec(),
+                }),
+                fixed_output: Some(FixedOutputTransition::BecameFixedOutput),
+            },
+        };
+        let mut out = Vec::new();
+        renderer.format_output_diff(&mut out, &diff, 0);
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.contains("Became a fixed-output derivation"),
+            "missing explanation:\n{out}"
+        );
+    }
+
+    #[test]
+    fn header_shows_closure_sizes() {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            ..Default::default()
+        });
+        let mut diff = DerivationDiff::new(empty_drv(), empty_drv());
+        let header = renderer.format_header(&diff, b"a.drv", b"b.drv");
+        assert!(!String::from_utf8(header).unwrap().contains("closure"));
+
+        diff.closure_size = Some(ClosureSizes {
+            old: 1234,
+            new: 1241,
+        });
+        let header = String::from_utf8(renderer.format_header(&diff, b"a.drv", b"b.drv")).unwrap();
+        assert!(
+            header.contains("closure size: 1,234 → 1,241 derivations"),
+            "got:\n{header}"
+        );
+        assert_eq!(group_thousands(999), "999");
+        assert_eq!(group_thousands(1_000_000), "1,000,000");
+    }
+
+    #[test]
+    fn display_is_the_colorless_rendering() {
+        let mut env = EnvironmentDiff::new();
+        env.insert(
+            b"version".to_vec(),
+            Some(EnvVarDiff::Changed(StringDiff {
+                old: b"1.0".to_vec(),
+                new: "2.0-\u{1F680}".as_bytes().to_vec(),
+            })),
+        );
+        let diff = DerivationDiff::new(empty_drv(), empty_drv())
+            .env(env)
+            .labels(b"a.drv", b"b.drv");
+        let renderer = Renderer::with_console(
+            RenderOptions {
+                color_mode: ColorMode::Never,
+                ..Default::default()
+            },
+            &FixedConsole::default(),
+        );
+        let mut expected = Vec::new();
+        renderer
+            .write_diff(&mut expected, &diff, b"a.drv", b"b.drv")
+            .unwrap();
+        assert_eq!(diff.to_string().into_bytes(), expected);
+        assert!(diff.to_string().starts_with("--- a.drv\n+++ b.drv\n"));
+
+        let colored = diff.to_colored_string(&RenderOptions {
+            color_mode: ColorMode::Always,
+            ..Default::default()
+        });
+        assert!(colored.contains("\x1b["), "{colored}");
+        // Without labels the sides are just old and new.
+        let unlabelled = DerivationDiff {
+            labels: None,
+            ..diff
+        };
+        assert!(unlabelled.to_string().starts_with("--- old\n+++ new\n"));
+    }
+
+    #[test]
+    fn write_diff_renders_into_any_writer() {
+        let diff = DerivationDiff::new(empty_drv(), empty_drv()).platform(StringDiff {
+            old: b"x86_64-linux".to_vec(),
+            new: b"aarch64-linux".to_vec(),
+        });
+        let render = |color_mode| {
+            // A console that is not a terminal: `Auto` means no color.
+            let renderer = Renderer::with_console(
+                RenderOptions {
+                    color_mode,
+                    inline_highlight: false,
+                    ..Default::default()
+                },
+                &FixedConsole::default(),
+            );
+            let mut out = Vec::new();
+            assert!(
+                renderer
+                    .write_diff(&mut out, &diff, b"a.drv", b"b.drv")
+                    .unwrap()
+            );
+            String::from_utf8(out).unwrap()
+        };
+        let plain = "\
+--- a.drv
++++ b.drv
+Summary: 1 derivation differs
+Platform:
+  - x86_64-linux
+  + aarch64-linux
+";
+        assert_eq!(render(ColorMode::Never), plain);
+        assert_eq!(render(ColorMode::Auto), plain);
+        assert_eq!(
+            render(ColorMode::Always),
+            "\x1b[31m--- a.drv\x1b[0m\n\x1b[32m+++ b.drv\x1b[0m\n\
+             \x1b[1mSummary:\x1b[0m 1 derivation differs\n\
+             \x1b[1mPlatform:\x1b[0m\n\
+             \x20 \x1b[31m- x86_64-linux\x1b[0m\n\
+             \x20 \x1b[32m+ aarch64-linux\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn fmt_writer_keeps_split_characters_whole() {
+        struct Chunks<'a>(&'a [&'a [u8]]);
+        impl fmt::Display for Chunks<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let mut out = FmtWriter {
+                    f,
+                    pending: Vec::new(),
+                };
+                for chunk in self.0 {
+                    out.write_all(chunk).map_err(|_| fmt::Error)?;
+                }
+                out.finish()
+            }
+        }
+        let rocket = "\u{1F680}".as_bytes();
+        let chunks: &[&[u8]] = &[b"a", &rocket[..2], &rocket[2..], b"b\xffc", &rocket[..1]];
+        assert_eq!(Chunks(chunks).to_string(), "a\u{1F680}b\u{FFFD}c\u{FFFD}");
+    }
+
+    #[test]
+    fn added_sources_show_what_they_are() {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            ..Default::default()
+        });
+        let diff = SourcesDiff {
+            added: [
+                b"/nix/store/a-vendor".to_vec(),
+                b"/nix/store/b-fix.patch".to_vec(),
+            ]
+            .into(),
+            removed: [b"/nix/store/c-big".to_vec(), b"/nix/store/d-gone".to_vec()].into(),
+            kinds: [
+                (
+                    b"/nix/store/a-vendor".to_vec(),
+                    SourceKind::Dir {
+                        files: 123,
+                        size: 4_718_592,
+                        truncated: false,
+                    },
+                ),
+                (
+                    b"/nix/store/b-fix.patch".to_vec(),
+                    SourceKind::File { size: 2150 },
+                ),
+                (
+                    b"/nix/store/c-big".to_vec(),
+                    SourceKind::Dir {
+                        files: 9_990,
+                        size: 3 << 30,
+                        truncated: true,
+                    },
+                ),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        renderer.format_sources_diff(&mut out, &diff, 0);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\
+Sources:
+  - /nix/store/c-big  dir (over 9,990 files, over 3.0 GiB)
+  - /nix/store/d-gone
+  + /nix/store/a-vendor  dir (123 files, 4.5 MiB)
+  + /nix/store/b-fix.patch  file (2.1 KiB)
+"
+        );
+        assert_eq!(human_size(0), "0 bytes");
+        assert_eq!(human_size(1), "1 byte");
+        assert_eq!(human_size(1023), "1023 bytes");
+    }
+
+    #[test]
+    fn header_explains_how_inputs_were_resolved() {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            accessible: true,
+            ..Default::default()
+        });
+        let mut resolution = Resolution {
+            old: vec!["./result (store)".into(), "output out of a.drv".into()],
+            new: vec!["b.drv (drv)".into()],
+            mismatched: false,
+        };
+        let diff = DerivationDiff::new(empty_drv(), empty_drv()).resolution(resolution.clone());
+        let header = String::from_utf8(renderer.format_header(&diff, b"a.drv", b"b.drv")).unwrap();
+        assert_eq!(
+            header,
+            "--- a.drv\n+++ b.drv\nresolved as:\n  \
+             old: ./result (store) -> output out of a.drv\n  new: b.drv (drv)\n"
+        );
+
+        resolution.mismatched = true;
+        let diff = diff.resolution(resolution);
+        let header = String::from_utf8(renderer.format_header(&diff, b"a.drv", b"b.drv")).unwrap();
+        assert!(
+            header.contains("Note: the inputs are of different kinds"),
+            "{header}"
+        );
+    }
+
+    #[test]
+    fn flake_inputs_show_their_pins() {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            ..Default::default()
+        });
+        let input = |rev: &str, last_modified| LockedInput {
+            source: "github:NixOS/nixpkgs".to_string(),
+            rev: Some(rev.to_string()),
+            nar_hash: Some("sha256-x".to_string()),
+            last_modified: Some(last_modified),
+        };
+        let path = LockedInput {
+            source: "/src/local".to_string(),
+            rev: None,
+            nar_hash: Some("sha256-9YrUjdztqi4G=".to_string()),
+            last_modified: None,
+        };
+        let changes = vec![
+            FlakeInputChange {
+                name: "local".to_string(),
+                old: None,
+                new: Some(path),
+            },
+            FlakeInputChange {
+                name: "nixpkgs".to_string(),
+                old: Some(input("57d6973abba7", 1718160348)),
+                new: Some(input("e2dd4e18cc1c", 1718160348 + 21 * 24 * 3600 + 3600)),
+            },
+        ];
+        let diff = DerivationDiff::new(empty_drv(), empty_drv()).flake_inputs(changes);
+        let header = String::from_utf8(renderer.format_header(&diff, b"a.drv", b"b.drv")).unwrap();
+        assert_eq!(
+            header,
+            "--- a.drv\n+++ b.drv\nflake inputs:\n  + local: /src/local 9YrUjdz\n  \
+             nixpkgs: 57d6973 → e2dd4e1 (3 weeks newer)\n"
+        );
+
+        let unchanged = DerivationDiff::new(empty_drv(), empty_drv()).flake_inputs(Vec::new());
+        let header = renderer.format_header(&unchanged, b"a.drv", b"b.drv");
+        assert_eq!(header, b"--- a.drv\n+++ b.drv\n");
+    }
+
+    #[test]
+    fn ages_use_the_largest_unit() {
+        assert_eq!(relative_age(100, 130), "same age");
+        assert_eq!(relative_age(0, 60), "1 minute newer");
+        assert_eq!(relative_age(0, 2 * 3600 + 59), "2 hours newer");
+        assert_eq!(relative_age(40 * 24 * 3600, 0), "1 month older");
+        assert_eq!(relative_age(0, 3 * 365 * 24 * 3600), "3 years newer");
+    }
+
+    fn source_change(diff: TextDiff) -> DerivationDiff {
+        DerivationDiff::new(empty_drv(), empty_drv()).sources(SourcesDiff {
+            added: BTreeSet::new(),
+            removed: BTreeSet::new(),
+            common: vec![SourceDiff {
+                path: b"builder.sh".to_vec(),
+                diff,
+            }],
+            ..Default::default()
+        })
+    }
+
+    fn render_at(position: SummaryPosition, diff: DerivationDiff) -> String {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            summary_position: position,
+            ..Default::default()
+        });
+        let mut out = Vec::new();
+        renderer
+            .write_diff(&mut out, &diff, b"a.drv", b"b.drv")
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn summary_position_orders_sections() {
+        let detailed = || source_change(TextDiff::text(b"old\n".to_vec(), b"new\n".to_vec()));
+        let summary = "Summary: 1 derivation differs, 1 source changed\n";
+
+        let top = render_at(SummaryPosition::Top, detailed());
+        assert!(top.starts_with("--- a.drv\n+++ b.drv\n"), "{top}");
+        assert_eq!(top.matches(summary).count(), 1, "{top}");
+        assert!(top.find(summary) < top.find("Sources:"), "{top}");
+        // The totals close the details, before a summary at the bottom.
+        let totals = "Totals: 1 source changed, 1 line added, 1 line removed\n";
+        assert!(top.ends_with(totals), "{top}");
+
+        let bottom = render_at(SummaryPosition::Bottom, detailed());
+        assert!(bottom.ends_with(&format!("{totals}{summary}")), "{bottom}");
+        assert_eq!(bottom.matches("Summary:").count(), 1, "{bottom}");
+
+        let both = render_at(SummaryPosition::Both, detailed());
+        assert_eq!(both.matches(summary).count(), 2, "{both}");
+        assert!(both.find(summary) < both.find("Sources:"), "{both}");
+        assert!(both.ends_with(summary), "{both}");
+    }
+
+    fn summary() -> DiffSummary {
+        DiffSummary {
+            derivations: 12,
+            arguments: 3,
+            env_vars: 40,
+            sources_changed: 2,
+            inputs_added: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn auto_modes_follow_the_console() {
+        let color_allowed = std::env::var("NO_COLOR").map_or(true, |v| v.is_empty());
+        let old_term = std::env::var("TERM").ok();
+        let auto = || RenderOptions {
+            color_mode: ColorMode::Auto,
+            hyperlinks: HyperlinkMode::Auto,
+            ..Default::default()
+        };
+        let tty = FixedConsole {
+            terminal: true,
+            width: None,
+        };
+        let pipe = FixedConsole::default();
+
+        let renderer = Renderer::with_console(auto(), &tty);
+        assert_eq!(renderer.use_color, color_allowed);
+        assert_eq!(
+            renderer.hyperlinks,
+            old_term.as_deref().is_some_and(|t| t != "dumb")
+        );
+
+        let renderer = Renderer::with_console(auto(), &pipe);
+        assert!(!renderer.use_color);
+        assert!(!renderer.hyperlinks);
+        // Explicit modes ignore the console.
+        let renderer = Renderer::with_console(
+            RenderOptions {
+                color_mode: ColorMode::Always,
+                hyperlinks: HyperlinkMode::Always,
+                ..Default::default()
+            },
+            &pipe,
+        );
+        assert_eq!(renderer.use_color, color_allowed);
+        assert!(renderer.hyperlinks);
+    }
+
+    #[test]
+    fn summary_wraps_at_console_width() {
+        let opts = || RenderOptions {
+            color_mode: ColorMode::Never,
+            ..Default::default()
+        };
+        let line = |width| {
+            let console = FixedConsole {
+                terminal: true,
+                width,
+            };
+            String::from_utf8(Renderer::with_console(opts(), &console).format_summary(&summary()))
+                .unwrap()
+        };
+
+        let unwrapped = "Summary: 12 derivations differ, 3 arguments, 40 env vars, \
+                         2 sources changed, 1 input added\n";
+        assert_eq!(line(None), unwrapped);
+        assert_eq!(line(Some(200)), unwrapped);
+        assert_eq!(
+            line(Some(50)),
+            "Summary: 12 derivations differ, 3 arguments,\n         \
+             40 env vars, 2 sources changed,\n         1 input added\n"
+        );
+        for l in line(Some(50)).lines() {
+            assert!(l.len() <= 50, "{l:?}");
+        }
+        // A phrase longer than the width still goes out whole.
+        assert_eq!(line(Some(10)).lines().count(), 5);
+    }
+
+    #[test]
+    fn closure_info_is_a_count_line_and_capped_lists() {
+        let info = ClosureInfoDiff {
+            old: None,
+            new: None,
+            added: (0..12)
+                .map(|i| format!("/nix/store/{i:02}-new").into_bytes())
+                .collect(),
+            removed: vec![b"/nix/store/00-old".to_vec()],
+        };
+        let render = |expand_closure_info| {
+            let renderer = Renderer::new(RenderOptions {
+                color_mode: ColorMode::Never,
+                input_list_limit: 2,
+                expand_closure_info,
+                ..Default::default()
+            });
+            let mut out = Vec::new();
+            renderer.format_env_var_diff(&mut out, &EnvVarDiff::ClosureInfo(info.clone()), 0);
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            render(false),
+            "registration info: +12 paths, -1 path\n\
+             - /nix/store/00-old\n\
+             + /nix/store/00-new\n\
+             + /nix/store/01-new\n\
+             + ... and 10 more (use --expand-closure-info to show all)\n"
+        );
+        let expanded = render(true);
+        assert_eq!(expanded.lines().count(), 14, "{expanded}");
+        assert!(expanded.contains("+ /nix/store/11-new\n"), "{expanded}");
+    }
+
+    #[test]
+    fn store_path_lists_are_one_path_per_line() {
+        let diff = StorePathsDiff {
+            added: vec![b"/nix/store/2-openssl".to_vec()],
+            removed: vec![b"/nix/store/1-libressl".to_vec()],
+            rebuilt: vec![StringDiff {
+                old: b"/nix/store/3-zlib".to_vec(),
+                new: b"/nix/store/4-zlib".to_vec(),
+            }],
+            ..Default::default()
+        };
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            ..Default::default()
+        });
+        let mut out = Vec::new();
+        renderer.format_env_var_diff(&mut out, &EnvVarDiff::StorePaths(diff), 2);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "  - /nix/store/1-libressl\n  \
+             + /nix/store/2-openssl\n  \
+             ~ /nix/store/4-zlib (rebuilt)\n"
+        );
+    }
+
+    #[test]
+    fn name_section_replaces_name_env_vars() {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            ..Default::default()
+        });
+        let mut env = EnvironmentDiff::new();
+        for (key, old, new) in [("name", "hello-2.12", "hello-2.11"), ("src", "a", "b")] {
+            env.insert(
+                key.as_bytes().to_vec(),
+                Some(EnvVarDiff::Changed(StringDiff {
+                    old: old.as_bytes().to_vec(),
+                    new: new.as_bytes().to_vec(),
+                })),
+            );
+        }
+        let diff = DerivationDiff {
+            env: Some(env),
+            name: Some(NameDiff {
+                pname: None,
+                version: Some(VersionDiff {
+                    old: b"2.12".to_vec(),
+                    new: b"2.11".to_vec(),
+                    downgrade: true,
+                }),
+                covers_name: true,
+            }),
+            ..source_change(TextDiff::NotCompared)
+        };
+        let out = String::from_utf8(renderer.format_derivation_diff(&diff, 0, 0)).unwrap();
+        assert!(
+            out.starts_with("Name:\n  version: 2.12 → 2.11 (downgrade)\n"),
+            "{out}"
+        );
+        assert!(out.contains("  src:\n"), "{out}");
+        assert!(!out.contains("  name:\n"), "{out}");
+    }
+
+    #[test]
+    fn sort_entries_by_size_puts_big_changes_first() {
+        let big = "x".repeat(100);
+        let change = |old: &str, new: &str| StringDiff {
+            old: old.as_bytes().to_vec(),
+            new: new.as_bytes().to_vec(),
+        };
+        let mut env = EnvironmentDiff::new();
+        env.insert(
+            b"aTiny".to_vec(),
+            Some(EnvVarDiff::Changed(change("1", "2"))),
+        );
+        env.insert(
+            b"zBig".to_vec(),
+            Some(EnvVarDiff::Changed(change("1", &big))),
+        );
+        let source = |path: &str, new: &str| SourceDiff {
+            path: path.as_bytes().to_vec(),
+            diff: TextDiff::text(b"a\n".to_vec(), format!("{new}\n").into_bytes()),
+        };
+        let arg = |i, diff| ArgumentDiff::Changed {
+            old_index: i,
+            new_index: i,
+            diff,
+            store_paths_only: false,
+        };
+        let args = [vec![b"-a".to_vec(), b"-b".to_vec()], vec![]];
+        let mut drv = empty_drv();
+        drv.args = args[0].clone();
+        let diff = DerivationDiff::new(drv.clone(), drv)
+            .args(vec![arg(0, change("-a", "-A")), arg(1, change("-b", &big))])
+            .sources(SourcesDiff {
+                added: BTreeSet::new(),
+                removed: BTreeSet::new(),
+                common: vec![source("a.sh", "b"), source("z.sh", &big)],
+                ..Default::default()
+            })
+            .env(env);
+
+        let order = |sort_entries| {
+            let renderer = Renderer::new(RenderOptions {
+                color_mode: ColorMode::Never,
+                sort_entries,
+                ..Default::default()
+            });
+            let out = String::from_utf8(renderer.format_derivation_diff(&diff, 0, 0)).unwrap();
+            let headings = [
+                "Argument 1 ",
+                "Argument 2 ",
+                "a.sh",
+                "z.sh",
+                "aTiny:",
+                "zBig:",
+            ];
+            let mut found: Vec<_> = headings
+                .iter()
+                .map(|h| (out.find(h).unwrap_or_else(|| panic!("{h}:\n{out}")), *h))
+                .collect();
+            found.sort();
+            found.into_iter().map(|(_, h)| h).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            order(EntryOrder::Name),
+            [
+                "Argument 1 ",
+                "Argument 2 ",
+                "a.sh",
+                "z.sh",
+                "aTiny:",
+                "zBig:"
+            ]
+        );
+        assert_eq!(
+            order(EntryOrder::Size),
+            [
+                "Argument 2 ",
+                "Argument 1 ",
+                "z.sh",
+                "a.sh",
+                "zBig:",
+                "aTiny:"
+            ]
+        );
+    }
+
+    #[test]
+    fn identical_input_changes_are_printed_once() {
+        let python_change = || {
+            let mut env = EnvironmentDiff::new();
+            env.insert(
+                b"PYTHON".to_vec(),
+                Some(EnvVarDiff::Changed(StringDiff {
+                    old: b"/nix/store/aaa-python-3.11".to_vec(),
+                    new: b"/nix/store/bbb-python-3.12".to_vec(),
+                })),
+            );
+            DerivationDiff::new(empty_drv(), empty_drv()).env(env)
+        };
+        let input = |name: &str, derivation| InputDiff {
+            path: name.as_bytes().to_vec(),
+            outputs: None,
+            derivation: Some(Box::new(derivation)),
+            unavailable: None,
+            version: None,
+        };
+        let inputs = |changed| InputsDiff {
+            added: Default::default(),
+            removed: Default::default(),
+            changed,
+            pruned: Vec::new(),
+            rebuilt: Vec::new(),
+            not_compared: Vec::new(),
+            timed_out: false,
+            depth_limited: false,
+        };
+        // c.drv has the same change one level further down.
+        let x = python_change()
+            .platform(StringDiff {
+                old: b"x86_64-linux".to_vec(),
+                new: b"aarch64-linux".to_vec(),
+            })
+            .inputs(inputs(vec![input("c.drv", python_change())]));
+        let diff = DerivationDiff::new(empty_drv(), empty_drv()).inputs(inputs(vec![
+            input("a.drv", python_change()),
+            input("b.drv", python_change()),
+            input("x.drv", x),
+        ]));
+
+        let render = |dedup_diffs| {
+            let renderer = Renderer::new(RenderOptions {
+                color_mode: ColorMode::Never,
+                inline_highlight: false,
+                dedup_diffs,
+                ..Default::default()
+            });
+            String::from_utf8(renderer.format_derivation_diff(&diff, 0, 0)).unwrap()
+        };
+        assert_eq!(
+            render(true),
+            "\
+• a.drv
+  Environment:
+    PYTHON:
+      - /nix/store/aaa-python-3.11
+      + /nix/store/bbb-python-3.12
+• b.drv
+  (same changes as a.drv above)
+• x.drv
+  Platform:
+    - x86_64-linux
+    + aarch64-linux
+  • c.drv
+    (same changes as a.drv above)
+  Environment:
+    PYTHON:
+      - /nix/store/aaa-python-3.11
+      + /nix/store/bbb-python-3.12
+"
+        );
+        let full = render(false);
+        assert_eq!(full.matches("PYTHON:").count(), 4, "{full}");
+        assert!(!full.contains("same changes"), "{full}");
+
+        // Written out as it is rendered, a section at a time, with the
+        // same bytes either way.
+        for dedup_diffs in [false, true] {
+            let renderer = Renderer::new(RenderOptions {
+                color_mode: ColorMode::Never,
+                inline_highlight: false,
+                dedup_diffs,
+                ..Default::default()
+            });
+            let mut writes: Vec<Vec<u8>> = Vec::new();
+            struct Writes<'a>(&'a mut Vec<Vec<u8>>);
+            impl Write for Writes<'_> {
+                fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                    self.0.push(buf.to_vec());
+                    Ok(buf.len())
+                }
+                fn flush(&mut self) -> io::Result<()> {
+                    Ok(())
+                }
+            }
+            let mut out = Writes(&mut writes);
+            let mut sections = Sections::new(&mut out);
+            renderer
+                .write_derivation_diff(&mut sections, &diff, 0, 0)
+                .unwrap();
+            let first = String::from_utf8(writes[0].clone()).unwrap();
+            assert!(first.starts_with("• a.drv\n"), "{first}");
+            assert!(!first.contains("x.drv"), "{first}");
+            assert_eq!(
+                String::from_utf8(writes.concat()).unwrap(),
+                render(dedup_diffs)
+            );
+        }
+    }
+
+    #[test]
+    fn accessible_mode_tags_every_change_line() {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            accessible: true,
+            ..Default::default()
+        });
+        let mut env = EnvironmentDiff::new();
+        env.insert(
+            b"script".to_vec(),
+            Some(EnvVarDiff::Changed(StringDiff {
+                old: b"a\nold line\nc\n".to_vec(),
+                new: b"a\nnew line\nc\n".to_vec(),
+            })),
+        );
+        env.insert(
+            b"version".to_vec(),
+            Some(EnvVarDiff::Changed(StringDiff {
+                old: b"1.0".to_vec(),
+                new: b"2.0".to_vec(),
+            })),
+        );
+        env.insert(b"extra".to_vec(), Some(EnvVarDiff::Added(b"yes".to_vec())));
+        let inner = DerivationDiff::new(empty_drv(), empty_drv()).env(env);
+        let diff = DerivationDiff {
+            inputs: Some(InputsDiff {
+                added: Default::default(),
+                removed: [DerivationPath(b"/nix/store/aaa-old.drv".to_vec())].into(),
+                changed: vec![InputDiff {
+                    path: b"dep.drv".to_vec(),
+                    outputs: None,
+                    derivation: Some(Box::new(inner.clone())),
+                    unavailable: None,
+                    version: None,
+                }],
+                pruned: Vec::new(),
+                rebuilt: Vec::new(),
+                not_compared: Vec::new(),
+                timed_out: false,
+                depth_limited: false,
+            }),
+            env: None,
+            closure_size: Some(ClosureSizes { old: 2, new: 3 }),
+            ..inner
+        };
+
+        let mut out = renderer.format_header(&diff, b"a.drv", b"b.drv");
+        out.extend(renderer.format_derivation_diff(&diff, 0, 0));
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(
+            out,
+            "\
+--- a.drv
++++ b.drv
+closure size: 2 -> 3 derivations
+Input derivations:
+  [removed] - old
+[changed] * dep.drv
+  Environment:
+    extra:
+      [added] + yes
+    script: (87% similar)
+      [context]   a
+      [removed] - [-old-] line
+      [added] + {+new+} line
+      [context]   c
+    version:
+      [removed] - [-1-].0
+      [added] + {+2+}.0
+"
+        );
+        assert!(out.is_ascii());
+    }
+
+    #[test]
+    fn requisites_show_closure_membership() {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            ..Default::default()
+        });
+        let diff = EnvVarDiff::Requisites(RequisitesDiff {
+            old: Some(b"/nix/store/ccc-glibc".to_vec()),
+            new: Some(b"/nix/store/aaa-openssl out".to_vec()),
+            added: vec![
+                Requisite {
+                    entry: b"/nix/store/aaa-openssl".to_vec(),
+                    in_closure: Some(true),
+                },
+                Requisite {
+                    entry: b"out".to_vec(),
+                    in_closure: None,
+                },
+            ],
+            removed: vec![Requisite {
+                entry: b"/nix/store/ccc-glibc".to_vec(),
+                in_closure: Some(false),
+            }],
+        });
+        let mut out = Vec::new();
+        renderer.format_env_var_diff(&mut out, &diff, 0);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "- /nix/store/ccc-glibc (not in old closure)\n\
+             + /nix/store/aaa-openssl (in new closure)\n\
+             + out\n"
+        );
+    }
+
+    #[test]
+    fn relative_sources_go_by_name_with_paths_below() {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            relative_sources: true,
+            ..Default::default()
+        });
+        let path = |hash: char, name: &str| {
+            format!("/nix/store/{}-{name}", hash.to_string().repeat(32)).into_bytes()
+        };
+        let diff = SourcesDiff {
+            removed: [path('1', "fix-cross.patch"), path('2', "builder.sh")].into(),
+            added: [path('3', "fix-cross.patch"), path('4', "musl.patch")].into(),
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        renderer.format_sources_diff(&mut out, &diff, 0);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\
+Sources:
+  - fix-cross.patch (1111111)
+  - builder.sh
+  + fix-cross.patch (3333333)
+  + musl.patch
+  Full paths:
+    fix-cross.patch (1111111): /nix/store/11111111111111111111111111111111-fix-cross.patch
+    builder.sh: /nix/store/22222222222222222222222222222222-builder.sh
+    fix-cross.patch (3333333): /nix/store/33333333333333333333333333333333-fix-cross.patch
+    musl.patch: /nix/store/44444444444444444444444444444444-musl.patch
+"
+        );
+    }
+
+    #[test]
+    fn env_detail_summarizes_the_other_vars() {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            inline_highlight: false,
+            env_detail: vec!["*Phase".to_string(), "patches".to_string()],
+            ..Default::default()
+        });
+        let mut old = empty_drv();
+        let mut new = empty_drv();
+        let mut env = EnvironmentDiff::new();
+        let mut change = |key: &str, before: Option<&str>, after: Option<&str>| {
+            let key = key.as_bytes().to_vec();
+            let (before, after) = (before.map(str::as_bytes), after.map(str::as_bytes));
+            let var_diff = match (before, after) {
+                (Some(before), Some(after)) => EnvVarDiff::Changed(StringDiff {
+                    old: before.to_vec(),
+                    new: after.to_vec(),
+                }),
+                (None, Some(after)) => EnvVarDiff::Added(after.to_vec()),
+                (Some(before), None) => EnvVarDiff::Removed(before.to_vec()),
+                (None, None) => unreachable!(),
+            };
+            if let Some(before) = before {
+                old.env.insert(key.as_slice().into(), before.to_vec());
+            }
+            if let Some(after) = after {
+                new.env.insert(key.as_slice().into(), after.to_vec());
+            }
+            env.insert(key, Some(var_diff));
+        };
+        change("buildPhase", Some("make"), Some("make -j4"));
+        change(
+            "NIX_CFLAGS_COMPILE",
+            Some(&"-O2 ".repeat(301)),
+            Some(&"-O3 ".repeat(300)),
+        );
+        change("patches", None, Some("fix.patch"));
+        change("doCheck", Some("1"), None);
+        let diff = DerivationDiff::new(old, new).env(env);
+
+        assert_eq!(
+            String::from_utf8(renderer.format_derivation_diff(&diff, 0, 0)).unwrap(),
+            "\
+Environment:
+  NIX_CFLAGS_COMPILE: changed (1,204 → 1,200 bytes)
+  buildPhase:
+    - make
+    + make -j4
+  doCheck: removed (1 byte)
+  patches:
+    + fix.patch
+"
+        );
+    }
+
+    #[test]
+    fn renamed_env_vars_take_one_line() {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            ..Default::default()
+        });
+        let renamed = |from: &str, old: &str, new: &str| {
+            Some(EnvVarDiff::Renamed(RenamedVar {
+                from: from.as_bytes().to_vec(),
+                old: old.as_bytes().to_vec(),
+                new: new.as_bytes().to_vec(),
+            }))
+        };
+        let mut env = EnvironmentDiff::new();
+        env.insert(
+            b"configureFlagsArray".to_vec(),
+            renamed("configureFlags", "--with-ssl", "--with-ssl"),
+        );
+        env.insert(
+            b"LDFLAGS".to_vec(),
+            renamed("NIX_LDFLAGS", "-L/nix/store/a-zlib", "-L/nix/store/b-zlib"),
+        );
+        let diff = DerivationDiff::new(empty_drv(), empty_drv()).env(env);
+
+        assert_eq!(
+            String::from_utf8(renderer.format_derivation_diff(&diff, 0, 0)).unwrap(),
+            "\
+Environment:
+  renamed: NIX_LDFLAGS → LDFLAGS (value unchanged but for store path hashes)
+  renamed: configureFlags → configureFlagsArray (value unchanged)
+"
+        );
+    }
+
+    #[test]
+    fn profile_diffs_align_package_names() {
+        use crate::profile::{Package, PackageChange};
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            ..Default::default()
+        });
+        let package = |name: &str, version: &str| Package {
+            name: name.to_string(),
+            version: version.to_string(),
+            path: format!("/nix/store/x-{name}-{version}"),
+        };
+        let change = |name, old, new| PackageChange {
+            old: package(name, old),
+            new: package(name, new),
+        };
+        let diff = ProfileDiff {
+            added: vec![package("fd", "9.0.0")],
+            removed: vec![],
+            changed: vec![
+                change("git", "2.42.0", "2.41.0"),
+                change("ripgrep", "14.0.3", "14.1.0"),
+            ],
+        };
+        let mut out = Vec::new();
+        assert!(
+            renderer
+                .write_profile_diff(&mut out, &diff, b"p-1", b"p-2")
+                .unwrap()
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\
+--- p-1
++++ p-2
+Packages: 1 added, 1 upgraded, 1 downgraded
+  + fd       9.0.0
+  ~ git      2.42.0 → 2.41.0 (downgrade)
+  ~ ripgrep  14.0.3 → 14.1.0
+"
+        );
+
+        // Wide characters take two columns each.
+        let diff = ProfileDiff {
+            added: vec![package("fcitx5-中文", "5.1"), package("vim", "9.1")],
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        renderer
+            .write_profile_diff(&mut out, &diff, b"p-1", b"p-2")
+            .unwrap();
+        assert!(String::from_utf8(out).unwrap().ends_with(
+            "\
+  + fcitx5-中文  5.1
+  + vim          9.1
+"
+        ));
+
+        let mut out = Vec::new();
+        let same = ProfileDiff::default();
+        assert!(
+            !renderer
+                .write_profile_diff(&mut out, &same, b"p-1", b"p-2")
+                .unwrap()
+        );
+        assert_eq!(out, b"The profiles have the same packages.\n");
+    }
+
+    #[test]
+    fn brief_lists_each_changed_input_once() {
+        let input = |path: &str, version: Option<(&str, &str)>| InputDiff {
+            path: path.as_bytes().to_vec(),
+            outputs: None,
+            derivation: None,
+            unavailable: None,
+            version: version.map(|(old, new)| VersionDiff {
+                old: old.as_bytes().to_vec(),
+                new: new.as_bytes().to_vec(),
+                downgrade: false,
+            }),
+        };
+        let drv = |path: &str| DerivationPath(path.as_bytes().to_vec());
+        let diff = DerivationDiff::new(empty_drv(), empty_drv()).inputs(InputsDiff {
+            added: [drv("/nix/store/a-brotli-1.1.0.drv")].into(),
+            removed: [drv("/nix/store/b-bootstrap-tools.drv")].into(),
+            changed: vec![
+                input("/nix/store/c-zlib-1.3.1.drv", None),
+                input(
+                    "/nix/store/d-openssl-3.0.13.drv",
+                    Some(("3.0.14", "3.0.13")),
+                ),
+                input("/nix/store/e-curl-8.8.0.drv", Some(("8.7.1", "8.8.0"))),
+            ],
+            ..Default::default()
+        });
+
+        let brief = |opts: RenderOptions| {
+            let mut out = Vec::new();
+            assert!(Renderer::new(opts).write_brief(&mut out, &diff).unwrap());
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            brief(RenderOptions {
+                color_mode: ColorMode::Never,
+                ..Default::default()
+            }),
+            "\
+- bootstrap-tools
++ brotli 1.1.0
+~ curl 8.7.1 → 8.8.0
+~ openssl 3.0.14 → 3.0.13 (downgrade)
+~ zlib 1.3.1
+"
+        );
+        assert!(
+            brief(RenderOptions {
+                color_mode: ColorMode::Never,
+                accessible: true,
+                ..Default::default()
+            })
+            .starts_with("[removed] - bootstrap-tools\n[added] + brotli 1.1.0\n[changed] ~ curl 8.7.1 -> 8.8.0\n")
+        );
+    }
+
+    #[test]
+    fn vars_differing_only_in_store_paths_are_named_on_one_line() {
+        let changed = |old: &str, new: &str| {
+            Some(EnvVarDiff::Changed(StringDiff {
+                old: old.as_bytes().to_vec(),
+                new: new.as_bytes().to_vec(),
+            }))
+        };
+        let bash = |hash: char| format!("/nix/store/{}-bash-5.2", hash.to_string().repeat(32));
+        let env: EnvironmentDiff = [
+            (b"version".to_vec(), changed("1", "2")),
+            (b"buildInputs".to_vec(), changed(&bash('a'), &bash('b'))),
+            (
+                b"PATH".to_vec(),
+                changed(&format!("{}/bin", bash('a')), &format!("{}/bin", bash('b'))),
+            ),
+        ]
+        .into();
+        let diff = DerivationDiff::new(empty_drv(), empty_drv()).env(env);
+        let render = |opts: RenderOptions| {
+            let renderer = Renderer::new(RenderOptions {
+                color_mode: ColorMode::Never,
+                inline_highlight: false,
+                ..opts
+            });
+            String::from_utf8(renderer.format_derivation_diff(&diff, 0, 0)).unwrap()
+        };
+
+        assert_eq!(
+            render(RenderOptions::default()),
+            "\
+Environment:
+  version:
+    - 1
+    + 2
+  2 variables changed only due to input path updates: PATH, buildInputs
+"
+        );
+        for opts in [
+            RenderOptions {
+                show_derived_changes: true,
+                ..Default::default()
+            },
+            RenderOptions {
+                verbose: true,
+                ..Default::default()
+            },
+        ] {
+            let out = render(opts);
+            assert!(out.contains("  PATH:\n"), "{out}");
+            assert!(!out.contains("input path updates"), "{out}");
+        }
+    }
+
+    #[test]
+    fn show_unchanged_env_lists_context_only_for_changed_derivations() {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            inline_highlight: false,
+            show_unchanged_env: true,
+            ..Default::default()
+        });
+        let mut old = empty_drv();
+        old.env
+            .insert(b"license".as_slice().into(), b"MIT".to_vec());
+        old.env.insert(
+            b"script".as_slice().into(),
+            b"line 1\nline 2\nline 3\n".to_vec(),
+        );
+        old.env.insert(b"version".as_slice().into(), b"1".to_vec());
+        let mut new = old.clone();
+        new.env.insert(b"version".as_slice().into(), b"2".to_vec());
+        let mut env = EnvironmentDiff::new();
+        env.insert(
+            b"version".to_vec(),
+            Some(EnvVarDiff::Changed(StringDiff {
+                old: b"1".to_vec(),
+                new: b"2".to_vec(),
+            })),
+        );
+        let mut diff = DerivationDiff::new(old.clone(), new).env(env);
+
+        let out = String::from_utf8(renderer.format_derivation_diff(&diff, 0, 0)).unwrap();
+        assert_eq!(
+            out,
+            "\
+Environment:
+  license:
+      MIT
+  script:
+      line 1
+      ... and 2 more lines (use --verbose to show all)
+  version:
+    - 1
+    + 2
+"
+        );
+
+        // Nothing changed: unchanged vars alone must not make it "differ".
+        diff.env = None;
+        diff.new = old.into();
+        assert!(renderer.format_derivation_diff(&diff, 0, 0).is_empty());
+    }
+
+    #[test]
+    fn env_cap_counts_changed_vars_only() {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            show_unchanged_env: true,
+            max_env_entries: 2,
+            ..Default::default()
+        });
+        let mut old = empty_drv();
+        for key in ["a", "b", "c"] {
+            old.env.insert(key.as_bytes().into(), b"same".to_vec());
+        }
+        let changed = |n: usize| -> EnvironmentDiff {
+            (0..n)
+                .map(|i| {
+                    let diff = StringDiff {
+                        old: b"1".to_vec(),
+                        new: b"2".to_vec(),
+                    };
+                    (
+                        format!("var{i}").into_bytes(),
+                        Some(EnvVarDiff::Changed(diff)),
+                    )
+                })
+                .collect()
         };
 
-        let mut out = Vec::new();
-        renderer.format_inputs_diff(&mut out, &inputs, 0, 0);
-        let out = String::from_utf8(out).unwrap();
+        // Within the cap: both changes, with the unchanged vars as context.
+        let diff = DerivationDiff::new(old.clone(), old.clone()).env(changed(2));
+        let out = String::from_utf8(renderer.format_derivation_diff(&diff, 0, 0)).unwrap();
+        for key in ["a", "b", "c", "var0", "var1"] {
+            assert!(out.contains(&format!("  {key}:\n")), "{out}");
+        }
+        assert!(!out.contains("more (use --max-env-entries"), "{out}");
 
-        assert!(out.contains("aarch64-linux"), "nested drv diff missing");
+        // Past it: only changed vars are cut, and no context is added.
+        let diff = DerivationDiff::new(old.clone(), old).env(changed(3));
+        let out = String::from_utf8(renderer.format_derivation_diff(&diff, 0, 0)).unwrap();
         assert!(
-            out.contains("dev"),
-            "consumed-output change was swallowed:\n{out}"
+            out.contains("  ... and 1 more (use --max-env-entries 0 to show all): var"),
+            "{out}"
         );
+        assert!(!out.contains("  a:\n"), "{out}");
     }
 
     #[test]
-    fn already_compared_input_is_labeled() {
-        // When the cycle detector short-circuits a nested diff, the output
-        // should say "already compared" rather than printing a dangling
-        // "X differs" header with no body.
+    fn multi_line_env_vars_are_diffed_in_hunks() {
         let renderer = Renderer::new(RenderOptions {
             color_mode: ColorMode::Never,
+            inline_highlight: false,
+            context_lines: 1,
             ..Default::default()
         });
-        let inner = DerivationDiff {
-            original: empty_drv(),
-            new: empty_drv(),
-            outputs: OutputsDiff::AlreadyCompared,
-            platform: None,
-            builder: None,
-            args: None,
-            sources: None,
-            inputs: None,
-            env: None,
-        };
-        let inputs = InputsDiff {
-            added: Default::default(),
-            removed: Default::default(),
-            changed: vec![InputDiff {
-                path: b"foo.drv".to_vec(),
-                outputs: None,
-                derivation: Some(Box::new(inner)),
-            }],
+        let script = |changed: &[usize]| {
+            (1..=20)
+                .map(|i| match changed.contains(&i) {
+                    true => format!("step {i} --changed\n"),
+                    false => format!("step {i}\n"),
+                })
+                .collect::<String>()
+                .into_bytes()
         };
+        let mut env = EnvironmentDiff::new();
+        env.insert(
+            b"buildCommand".to_vec(),
+            Some(EnvVarDiff::Changed(StringDiff {
+                old: script(&[]),
+                new: script(&[3, 17]),
+            })),
+        );
+        let diff = DerivationDiff::new(empty_drv(), empty_drv()).env(env);
 
-        let mut out = Vec::new();
-        renderer.format_inputs_diff(&mut out, &inputs, 0, 0);
-        let out = String::from_utf8(out).unwrap();
-
-        assert!(out.contains("foo.drv"));
-        assert!(
-            out.contains("already compared"),
-            "expected 'already compared' marker, got:\n{out}"
+        // Only the changed lines and one line around each, not the whole
+        // script on one line per side.
+        let out = String::from_utf8(renderer.format_derivation_diff(&diff, 0, 0)).unwrap();
+        assert_eq!(
+            out,
+            "\
+Environment:
+  buildCommand: (97% similar)
+      step 2
+    - step 3
+    + step 3 --changed
+      step 4
+    ...
+      step 16
+    - step 17
+    + step 17 --changed
+      step 18
+"
         );
     }
 
-    fn drv_with_output(name: &[u8], path: &[u8]) -> Derivation {
-        let mut outputs = std::collections::BTreeMap::new();
-        outputs.insert(
-            name.to_vec(),
-            Output {
-                path: path.to_vec(),
-                hash_algorithm: None,
-                hash: None,
-            },
+    #[test]
+    fn env_section_shows_the_largest_changes_up_to_the_cap() {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            ..Default::default()
+        });
+        // var0000 is the smallest change, var0999 the largest.
+        let env: EnvironmentDiff = (0..1000)
+            .map(|i| {
+                let diff = StringDiff {
+                    old: b"a".to_vec(),
+                    new: vec![b'b'; i + 1],
+                };
+                (
+                    format!("var{i:04}").into_bytes(),
+                    Some(EnvVarDiff::Changed(diff)),
+                )
+            })
+            .collect();
+        let diff = DerivationDiff::new(empty_drv(), empty_drv()).env(env);
+
+        let out = String::from_utf8(renderer.format_derivation_diff(&diff, 0, 0)).unwrap();
+        let shown: Vec<_> = out
+            .lines()
+            .filter_map(|l| l.strip_prefix("  ")?.strip_suffix(':'))
+            .collect();
+        assert_eq!(shown.len(), 500);
+        assert_eq!(shown[..3], ["var0999", "var0998", "var0997"]);
+        assert_eq!(shown[499], "var0500");
+        let summary = out.lines().last().unwrap();
+        assert!(
+            summary.starts_with(
+                "  ... and 500 more (use --max-env-entries 0 to show all): var0000, var0001, "
+            ),
+            "{summary}"
         );
-        Derivation {
-            outputs,
-            ..empty_drv()
-        }
+        assert!(summary.ends_with(", var0499"), "{summary}");
     }
 
     #[test]
-    fn hides_output_path_noise_by_default() {
-        // Output store paths differ whenever *anything* else differs. Showing
-        // them on every nested derivation floods the diff with zero-signal
-        // noise. The env var `$out` mirrors the same path and is equally
-        // useless. Both must be hidden unless --verbose is set.
-        let old = drv_with_output(b"out", b"/nix/store/aaa-foo");
-        let new = drv_with_output(b"out", b"/nix/store/bbb-foo");
-        let mut env = std::collections::BTreeMap::new();
-        env.insert(
-            b"out".to_vec(),
-            Some(EnvVarDiff::Changed(StringDiff {
-                old: b"/nix/store/aaa-foo".to_vec(),
-                new: b"/nix/store/bbb-foo".to_vec(),
-            })),
+    fn hyperlinks_wrap_absolute_paths_only() {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            hyperlinks: HyperlinkMode::Always,
+            ..Default::default()
+        });
+        assert_eq!(
+            renderer.link(b"/nix/store/abc-foo bar+1.drv").as_ref(),
+            b"\x1b]8;;file:///nix/store/abc-foo%20bar%2B1.drv\x1b\\/nix/store/abc-foo bar+1.drv\x1b]8;;\x1b\\"
         );
-        env.insert(
-            b"version".to_vec(),
-            Some(EnvVarDiff::Changed(StringDiff {
-                old: b"1".to_vec(),
-                new: b"2".to_vec(),
-            })),
+        assert_eq!(
+            renderer.link(b"<instantiated from x.nix>").as_ref(),
+            b"<instantiated from x.nix>"
         );
-        let diff = DerivationDiff {
-            original: old,
-            new,
-            outputs: OutputsDiff::Changed(vec![OutputDiff {
-                name: b"out".to_vec(),
-                diff: OutputDetailDiff::Changed {
-                    old: Output {
-                        path: b"/nix/store/aaa-foo".to_vec(),
-                        hash_algorithm: None,
-                        hash: None,
-                    },
-                    new: Box::new(Output {
-                        path: b"/nix/store/bbb-foo".to_vec(),
-                        hash_algorithm: None,
-                        hash: None,
-                    }),
-                    path: Some(StringDiff {
-                        old: b"/nix/store/aaa-foo".to_vec(),
-                        new: b"/nix/store/bbb-foo".to_vec(),
-                    }),
-                    hash_algo: None,
-                    hash: None,
-                },
-            }]),
-            platform: None,
-            builder: None,
-            args: None,
-            sources: None,
-            inputs: None,
-            env: Some(env),
-        };
 
-        let quiet = Renderer::new(RenderOptions {
+        // Truncated lists still close every link they open.
+        let paths: Vec<Vec<u8>> = (0..5)
+            .map(|i| format!("/nix/store/{i}-x").into_bytes())
+            .collect();
+        let renderer = Renderer::new(RenderOptions {
             color_mode: ColorMode::Never,
+            hyperlinks: HyperlinkMode::Always,
+            input_list_limit: 2,
             ..Default::default()
         });
-        let out = String::from_utf8(quiet.format_derivation_diff(&diff, 0, 0)).unwrap();
-        assert!(!out.contains("Outputs"), "path-only output shown:\n{out}");
-        assert!(!out.contains("out:"), "$out env var shown:\n{out}");
-        assert!(out.contains("version"), "real env change missing:\n{out}");
+        let mut out = Vec::new();
+        renderer.write_path_list(&mut out, paths.iter(), Mark::Added, 0);
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.matches("\x1b]8;;file://").count(), 2);
+        assert_eq!(out.matches("\x1b]8;;\x1b\\").count(), 2);
 
-        let verbose = Renderer::new(RenderOptions {
-            color_mode: ColorMode::Never,
-            verbose: true,
+        let never = Renderer::new(RenderOptions {
+            hyperlinks: HyperlinkMode::Never,
             ..Default::default()
         });
-        let out = String::from_utf8(verbose.format_derivation_diff(&diff, 0, 0)).unwrap();
-        assert!(out.contains("Outputs"), "verbose should show outputs");
-        assert!(out.contains("out:"), "verbose should show $out");
+        assert_eq!(never.link(b"/nix/store/x").as_ref(), b"/nix/store/x");
     }
 
     #[test]
-    fn shows_fod_hash_changes() {
-        // Fixed-output derivation hash changes are semantically meaningful
-        // (e.g., a src update) and must NOT be filtered as path noise.
-        let diff = OutputDetailDiff::Changed {
-            old: Output {
-                path: b"/nix/store/aaa-src".to_vec(),
-                hash_algorithm: Some(b"sha256".to_vec()),
-                hash: Some(b"old".to_vec()),
-            },
-            new: Box::new(Output {
-                path: b"/nix/store/bbb-src".to_vec(),
-                hash_algorithm: Some(b"sha256".to_vec()),
-                hash: Some(b"new".to_vec()),
-            }),
-            path: Some(StringDiff {
-                old: b"/nix/store/aaa-src".to_vec(),
-                new: b"/nix/store/bbb-src".to_vec(),
-            }),
-            hash_algo: None,
-            hash: Some(StringDiff {
-                old: b"old".to_vec(),
-                new: b"new".to_vec(),
+    fn inputs_show_package_names_and_versions() {
+        let diff = DerivationDiff {
+            inputs: Some(InputsDiff {
+                added: [DerivationPath(
+                    b"/nix/store/aaaa-ripgrep-14.1.0.drv".to_vec(),
+                )]
+                .into(),
+                removed: [DerivationPath(b"/nix/store/bbbb-source.drv".to_vec())].into(),
+                changed: vec![InputDiff {
+                    path: b"hello-2.10.drv".to_vec(),
+                    outputs: None,
+                    derivation: None,
+                    unavailable: None,
+                    version: Some(VersionDiff {
+                        old: b"2.12".to_vec(),
+                        new: b"2.10".to_vec(),
+                        downgrade: true,
+                    }),
+                }],
+                pruned: Vec::new(),
+                rebuilt: Vec::new(),
+                not_compared: Vec::new(),
+                timed_out: false,
+                depth_limited: false,
             }),
+            ..DerivationDiff::new(empty_drv(), empty_drv())
         };
-        assert!(!is_path_only_change(&diff));
+        let render = |verbose| {
+            let renderer = Renderer::new(RenderOptions {
+                color_mode: ColorMode::Never,
+                verbose,
+                ..Default::default()
+            });
+            String::from_utf8(renderer.format_derivation_diff(&diff, 0, 0)).unwrap()
+        };
+        assert_eq!(
+            render(false),
+            "\
+Input derivations:
+  - source
+  + ripgrep 14.1.0
+• hello: 2.12 → 2.10 (downgrade)
+"
+        );
+        assert!(
+            render(true).contains("  + /nix/store/aaaa-ripgrep-14.1.0.drv\n"),
+            "{}",
+            render(true)
+        );
     }
 
     #[test]
@@ -872,7 +4843,7 @@ mod tests {
         });
         let paths: Vec<Vec<u8>> = (0..10).map(|i| format!("path{i}").into_bytes()).collect();
         let mut out = Vec::new();
-        renderer.write_path_list(&mut out, paths.iter(), b"+ ", b"", 0);
+        renderer.write_path_list(&mut out, paths.iter(), Mark::Added, 0);
         let out = String::from_utf8(out).unwrap();
         assert!(out.contains("path0"));
         assert!(out.contains("path2"));
@@ -892,7 +4863,7 @@ mod tests {
         let new = b"a\nb\nNEW\nc\nd\ne\n";
 
         let mut out = Vec::new();
-        renderer.format_text_diff(&mut out, old, new, 0);
+        renderer.format_text_diff(&mut out, old, new, 100, 0);
         let out = String::from_utf8(out).unwrap();
 
         // Leading: only "b" (1 line before change), then NEW, then only "c"
@@ -907,18 +4878,164 @@ mod tests {
         assert!(!out.contains("  e\n"));
     }
 
+    fn script_arg(hash: &str, msg: &str) -> Vec<u8> {
+        format!(
+            "mkdir -p $out\nln -s /nix/store/{hash}-dep/bin $out/bin\n\
+             cp a b\ncp c d\ncp e f\ncp g h\ncp i j\ncp k l\ncp m n\necho {msg}\n"
+        )
+        .into_bytes()
+    }
+
+    fn render_args(verbose: bool, old: Vec<u8>, new: Vec<u8>, store_paths_only: bool) -> String {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            verbose,
+            ..Default::default()
+        });
+        let lists = [
+            vec![b"-c".to_vec(), old.clone()],
+            vec![b"-c".to_vec(), new.clone()],
+        ];
+        let args = vec![ArgumentDiff::Changed {
+            old_index: 1,
+            new_index: 1,
+            diff: StringDiff { old, new },
+            store_paths_only,
+        }];
+        let mut out = Vec::new();
+        renderer.format_arguments_diff(&mut out, &args, [&lists[0], &lists[1]], 0);
+        String::from_utf8(out).unwrap()
+    }
+
+    const HASH1: &str = "0c9z6vw9aqk8sw6j6d1x2lxnh2wrrxj0";
+    const HASH2: &str = "1a6kqbnvc04rhs8zmbmmhz3x4zjvnmcc";
+
+    fn vecs(items: &[&str]) -> Vec<Vec<u8>> {
+        items.iter().map(|s| s.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn argument_positions_and_neighbors() {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            ..Default::default()
+        });
+        let old = vecs(&["-e", "build.sh", "--fast"]);
+        let new = vecs(&["-e", "build.sh", "--fast", "--debug"]);
+        let diffs = vec![ArgumentDiff::Added {
+            index: 3,
+            value: b"--debug".to_vec(),
+        }];
+        let mut out = Vec::new();
+        renderer.format_arguments_diff(&mut out, &diffs, [&old, &new], 0);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Arguments:\n  Argument 4 (new) of 3 → 4:\n      --fast\n    + --debug\n"
+        );
+
+        // Removed from the front: the following argument is the context.
+        let diffs = vec![ArgumentDiff::Removed {
+            index: 0,
+            value: b"-e".to_vec(),
+        }];
+        let new = vecs(&["build.sh", "--fast"]);
+        let mut out = Vec::new();
+        renderer.format_arguments_diff(&mut out, &diffs, [&old, &new], 0);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Arguments:\n  Argument 1 (old) of 3 → 2:\n    - -e\n      build.sh\n"
+        );
+
+        // Moved within a list of the same length.
+        let moved = ArgumentDiff::Changed {
+            old_index: 2,
+            new_index: 3,
+            diff: StringDiff {
+                old: b"a".to_vec(),
+                new: b"b".to_vec(),
+            },
+            store_paths_only: false,
+        };
+        let four = vecs(&["1", "2", "3", "4"]);
+        assert_eq!(
+            argument_position(&moved, [&four, &four]),
+            "3 (old) / 4 (new) of 4"
+        );
+    }
+
+    #[test]
+    fn store_path_only_arguments_collapse() {
+        let (old, new) = (script_arg(HASH1, "hi"), script_arg(HASH2, "hi"));
+        assert_eq!(
+            render_args(false, old.clone(), new.clone(), true),
+            "Arguments:\n  arguments differ only in store paths\n"
+        );
+        // --verbose still shows the script, with the hash change dimmed.
+        let out = render_args(true, old, new, true);
+        assert!(
+            out.contains(&format!("~ ln -s /nix/store/{HASH2}-dep/bin $out/bin\n")),
+            "{out}"
+        );
+        assert!(!out.contains("- ln"), "{out}");
+    }
+
+    #[test]
+    fn store_path_changes_are_dimmed_next_to_real_changes() {
+        let out = render_args(
+            false,
+            script_arg(HASH1, "hi"),
+            script_arg(HASH2, "bye"),
+            false,
+        );
+        assert_eq!(
+            out,
+            format!(
+                "Arguments:\n  Argument 2 of 2: (96% similar)\n      mkdir -p $out\n    \
+                 ~ ln -s /nix/store/{HASH2}-dep/bin $out/bin\n      cp a b\n      cp c d\n      \
+                 cp e f\n    ...\n      cp i j\n      cp k l\n      cp m n\n    - echo hi\n    \
+                 + echo bye\n"
+            )
+        );
+    }
+
+    #[test]
+    fn plain_text_diff_colors_each_block_once() {
+        // Without inline highlighting, a run of added lines is one color
+        // span instead of one per line.
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Always,
+            inline_highlight: false,
+            ..Default::default()
+        });
+        let old = b"keep\n";
+        let new = b"keep\none\ntwo\nthree\n";
+
+        let mut out = Vec::new();
+        renderer.format_text_diff(&mut out, old, new, 100, 0);
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(out.matches("\x1b[32m").count(), 1, "{out:?}");
+        assert_eq!(out.matches("\x1b[0m").count(), 1, "{out:?}");
+        assert!(
+            out.ends_with("+ three\x1b[0m\n"),
+            "reset must precede the final newline: {out:?}"
+        );
+    }
+
     #[test]
     fn inline_highlight_marks_changed_words() {
         // With inline highlighting on, only the changed word segments should
         // be wrapped in reverse-video, not the whole line. This lets the
         // reader spot store-path hash changes and version bumps at a glance.
+        // (Values this long are past the character-level diff.)
         let renderer = Renderer::new(RenderOptions {
             color_mode: ColorMode::Always,
             ..Default::default()
         });
+        let tail = "/lib".repeat(CHAR_DIFF_MAX_LEN);
         let diff = StringDiff {
-            old: b"/nix/store/aaa-foo-1.0".to_vec(),
-            new: b"/nix/store/bbb-foo-2.0".to_vec(),
+            old: format!("/nix/store/aaa-foo-1.0{tail}").into_bytes(),
+            new: format!("/nix/store/bbb-foo-2.0{tail}").into_bytes(),
         };
         let mut out = Vec::new();
         renderer.format_string_diff(&mut out, &diff, 0);
@@ -940,6 +5057,130 @@ mod tests {
         );
     }
 
+    #[test]
+    fn dissimilar_texts_are_diffed_by_line() {
+        let render = |inline_threshold, old: &str, new: &str| {
+            let renderer = Renderer::new(RenderOptions {
+                color_mode: ColorMode::Always,
+                inline_threshold,
+                ..Default::default()
+            });
+            let diff = TextDiff::text(old.into(), new.into());
+            let TextDiff::Text { similarity, .. } = diff else {
+                unreachable!()
+            };
+            let mut out = Vec::new();
+            renderer.format_text_diff(&mut out, old.as_bytes(), new.as_bytes(), similarity, 0);
+            (similarity, String::from_utf8(out).unwrap())
+        };
+        let script = "./configure --prefix=$out\nmake -j4\nmake install\n";
+        let tweaked = script.replace("-j4", "-j8");
+        let rewrite = "meson setup build\nninja -C build install\n";
+
+        let (similarity, out) = render(50, script, &tweaked);
+        assert!(similarity >= 50, "{similarity}");
+        assert!(out.contains("\x1b[7m"), "{out:?}");
+
+        let (similarity, out) = render(50, script, rewrite);
+        assert!(similarity < 50, "{similarity}");
+        assert!(!out.contains("\x1b[7m"), "{out:?}");
+        assert!(out.contains("- make -j4\n"), "{out:?}");
+
+        // At 100, only identical texts would get word highlighting.
+        assert!(!render(100, script, &tweaked).1.contains("\x1b[7m"));
+    }
+
+    #[test]
+    fn changed_texts_show_their_similarity() {
+        let diff = TextDiff::text(
+            b"set -e\nmake -j4\nmake install\n".to_vec(),
+            b"set -e\nmake -j8\nmake install\n".to_vec(),
+        );
+        let TextDiff::Text { similarity, .. } = diff else {
+            unreachable!()
+        };
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            ..Default::default()
+        });
+        let mut out = Vec::new();
+        renderer
+            .write_diff(&mut out, &source_change(diff), b"a.drv", b"b.drv")
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.contains(&format!("~ builder.sh ({similarity}% similar)\n")),
+            "{out}"
+        );
+    }
+
+    #[test]
+    fn short_values_get_a_character_diff() {
+        let diff = StringDiff {
+            old: b"/nix/store/abcdefgh-openssl-3.0.13".to_vec(),
+            new: b"/nix/store/abzdefgh-openssl-3.0.14".to_vec(),
+        };
+        let render = |color_mode, accessible| {
+            let renderer = Renderer::new(RenderOptions {
+                color_mode,
+                accessible,
+                ..Default::default()
+            });
+            let mut out = Vec::new();
+            renderer.format_string_diff(&mut out, &diff, 0);
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            render(ColorMode::Never, false),
+            "- /nix/store/ab[-c-]defgh-openssl-3.0.1[-3-]\n\
+             + /nix/store/ab{+z+}defgh-openssl-3.0.1{+4+}\n"
+        );
+        assert_eq!(
+            render(ColorMode::Always, false),
+            "\x1b[31m- /nix/store/ab\x1b[1;4mc\x1b[22;24mdefgh-openssl-3.0.1\x1b[1;4m3\x1b[22;24m\x1b[0m\n\
+             \x1b[32m+ /nix/store/ab\x1b[1;4mz\x1b[22;24mdefgh-openssl-3.0.1\x1b[1;4m4\x1b[22;24m\x1b[0m\n"
+        );
+        assert!(
+            render(ColorMode::Always, true).contains("ab{+\x1b[1;4mz\x1b[22;24m+}defgh"),
+            "accessible mode keeps the brackets"
+        );
+    }
+
+    #[test]
+    fn character_diff_folds_short_unchanged_runs() {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            ..Default::default()
+        });
+        let diff = StringDiff {
+            old: b"0a1b2c-foo".to_vec(),
+            new: b"9a8b7c-foo".to_vec(),
+        };
+        let mut out = Vec::new();
+        renderer.format_string_diff(&mut out, &diff, 0);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "- [-0a1b2-]c-foo\n+ {+9a8b7+}c-foo\n"
+        );
+    }
+
+    #[test]
+    fn no_inline_highlight_disables_the_character_diff() {
+        let renderer = Renderer::new(RenderOptions {
+            color_mode: ColorMode::Never,
+            inline_highlight: false,
+            ..Default::default()
+        });
+        let diff = StringDiff {
+            old: b"1.0".to_vec(),
+            new: b"1.1".to_vec(),
+        };
+        let mut out = Vec::new();
+        renderer.format_string_diff(&mut out, &diff, 0);
+        assert_eq!(String::from_utf8(out).unwrap(), "- 1.0\n+ 1.1\n");
+    }
+
     #[test]
     fn inline_highlight_disabled_without_color() {
         // Reverse-video escapes are meaningless without color; inline
@@ -0,0 +1,122 @@
+//! Env values with bytes that break the formats they are printed in. A
+//! hand-crafted derivation can put any byte in a value: NUL confuses
+//! pagers such as `less` and is refused by some JSON consumers even as
+//! `\u0000`, and other control characters move the cursor or start
+//! terminal escape sequences.
+//!
+//! The text formats show such bytes as escapes, `\0` for NUL and `\x7f`
+//! for the others. JSON keeps values with NUL exactly, in the base64 form
+//! also used for bytes that are not UTF-8. Either way, main warns which
+//! env vars had them.
+
+use crate::types::{DerivationDiff, EnvVarDiff, RenamedVar, StringDiff};
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+
+/// Whether `byte` is a control character other than tab, line feed and
+/// carriage return, which text diffs handle as whitespace and lines.
+pub fn is_unusual(byte: u8) -> bool {
+    matches!(byte, 0..=8 | 0x0b | 0x0c | 0x0e..=0x1f | 0x7f)
+}
+
+pub fn has_unusual(value: &[u8]) -> bool {
+    value.iter().copied().any(is_unusual)
+}
+
+/// `value` with its unusual bytes written as escapes.
+pub fn escape_unusual(value: &[u8]) -> Cow<'_, [u8]> {
+    if !has_unusual(value) {
+        return Cow::Borrowed(value);
+    }
+    let mut escaped = Vec::with_capacity(value.len() + 8);
+    for &byte in value {
+        match byte {
+            0 => escaped.extend_from_slice(b"\\0"),
+            _ if is_unusual(byte) => escaped.extend_from_slice(format!("\\x{byte:02x}").as_bytes()),
+            _ => escaped.push(byte),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// The values an env var change shows.
+fn values(change: &EnvVarDiff) -> Vec<&[u8]> {
+    match change {
+        EnvVarDiff::Added(value) | EnvVarDiff::Removed(value) => vec![value],
+        EnvVarDiff::Changed(StringDiff { old, new })
+        | EnvVarDiff::Renamed(RenamedVar { old, new, .. }) => vec![old, new],
+        // Store paths only.
+        EnvVarDiff::Requisites(_) | EnvVarDiff::ClosureInfo(_) | EnvVarDiff::StorePaths(_) => {
+            Vec::new()
+        }
+    }
+}
+
+/// `change` with its values escaped, if any needed to be.
+pub fn escape_env_var_diff(change: &EnvVarDiff) -> Option<EnvVarDiff> {
+    if !values(change).into_iter().any(has_unusual) {
+        return None;
+    }
+    let escape = |value: &[u8]| escape_unusual(value).into_owned();
+    Some(match change {
+        EnvVarDiff::Added(value) => EnvVarDiff::Added(escape(value)),
+        EnvVarDiff::Removed(value) => EnvVarDiff::Removed(escape(value)),
+        EnvVarDiff::Changed(StringDiff { old, new }) => EnvVarDiff::Changed(StringDiff {
+            old: escape(old),
+            new: escape(new),
+        }),
+        EnvVarDiff::Renamed(RenamedVar { from, old, new }) => EnvVarDiff::Renamed(RenamedVar {
+            from: from.clone(),
+            old: escape(old),
+            new: escape(new),
+        }),
+        _ => unreachable!("only changes with values have unusual bytes"),
+    })
+}
+
+/// The env vars anywhere in `diff` whose changed values have unusual
+/// bytes.
+pub fn keys_with_unusual_bytes(diff: &DerivationDiff) -> BTreeSet<String> {
+    let mut keys = BTreeSet::new();
+    diff.walk(|_, diff| {
+        for (key, change) in diff.env.iter().flatten() {
+            let Some(change) = change else { continue };
+            if values(change).into_iter().any(has_unusual) {
+                keys.insert(String::from_utf8_lossy(key).into_owned());
+            }
+        }
+    });
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_bytes_are_escaped() {
+        assert!(matches!(
+            escape_unusual(b"a\tb\r\n"),
+            Cow::Borrowed(b"a\tb\r\n")
+        ));
+        assert_eq!(
+            escape_unusual(b"a\0b\x7fc\x1b[31m").as_ref(),
+            b"a\\0b\\x7fc\\x1b[31m"
+        );
+        // Bytes that are not UTF-8 are left to the renderer.
+        assert_eq!(escape_unusual(b"caf\xe9").as_ref(), b"caf\xe9");
+
+        let change = EnvVarDiff::Changed(StringDiff {
+            old: b"a".to_vec(),
+            new: b"a\0".to_vec(),
+        });
+        assert_eq!(
+            escape_env_var_diff(&change),
+            Some(EnvVarDiff::Changed(StringDiff {
+                old: b"a".to_vec(),
+                new: b"a\\0".to_vec(),
+            }))
+        );
+        assert_eq!(escape_env_var_diff(&EnvVarDiff::Added(b"a".to_vec())), None);
+    }
+}
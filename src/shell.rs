@@ -0,0 +1,253 @@
+//! Which shell a builder runs and with which options. Switching from
+//! `bash -e` to `dash`, or dropping `-e`, changes how the build script
+//! behaves on errors, but the Builder and Arguments sections only show a
+//! store path and a flag changing among the others.
+
+use crate::types::{Derivation, DerivationDiff};
+use std::fmt;
+
+/// Builder basenames taken to be shells.
+const SHELLS: &[&str] = &["sh", "bash", "dash", "ash", "zsh", "ksh", "mksh"];
+
+/// How many arguments are looked at for options; the script or `-c`
+/// comes soon after them.
+const MAX_OPTION_ARGS: usize = 6;
+
+/// `set -o` names with a single-letter form, which they are shown as.
+const OPTION_LETTERS: &[(&str, char)] = &[
+    ("errexit", 'e'),
+    ("nounset", 'u'),
+    ("xtrace", 'x'),
+    ("verbose", 'v'),
+    ("noglob", 'f'),
+    ("noexec", 'n'),
+];
+
+/// A builder that is a shell, with the options it is started with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShellInvocation {
+    pub shell: String,
+    /// `-e`, `+x` or `-o pipefail`, one option each: `-eu` is split and
+    /// `-o errexit` is `-e`. Sorted, so the order they are given in does
+    /// not count.
+    pub options: Vec<String>,
+}
+
+impl ShellInvocation {
+    /// How `drv` runs its shell, unless its builder is not one.
+    pub fn of(drv: &Derivation) -> Option<Self> {
+        Self::parse(&drv.builder, &drv.args)
+    }
+
+    fn parse(builder: &[u8], args: &[Vec<u8>]) -> Option<Self> {
+        let builder = String::from_utf8_lossy(builder);
+        let shell = builder.rsplit('/').next().unwrap_or_default();
+        if !SHELLS.contains(&shell) {
+            return None;
+        }
+        let mut options = Vec::new();
+        let mut args = args
+            .iter()
+            .take(MAX_OPTION_ARGS)
+            .map(|arg| String::from_utf8_lossy(arg));
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                break;
+            }
+            // Long options such as `--posix`.
+            if arg.starts_with("--") {
+                options.push(arg.into_owned());
+                continue;
+            }
+            let Some(sign) = arg.chars().next().filter(|c| matches!(c, '-' | '+')) else {
+                break;
+            };
+            if arg.len() == 1 {
+                break;
+            }
+            // The script follows `-c`, after any other options in the same
+            // argument.
+            let mut command = false;
+            for letter in arg[1..].chars() {
+                match letter {
+                    'c' => command = true,
+                    'o' => {
+                        let Some(name) = args.next() else { break };
+                        options.push(match OPTION_LETTERS.iter().find(|(n, _)| *n == name) {
+                            Some((_, letter)) => format!("{sign}{letter}"),
+                            None => format!("{sign}o {name}"),
+                        });
+                    }
+                    _ => options.push(format!("{sign}{letter}")),
+                }
+            }
+            if command {
+                break;
+            }
+        }
+        // Single letters first, then the `-o` names, then long options.
+        options.sort_by_key(|option| {
+            let long = option.starts_with("--");
+            (long, option.contains(' '), option[1..].to_string())
+        });
+        options.dedup();
+        Some(Self {
+            shell: shell.to_string(),
+            options,
+        })
+    }
+}
+
+impl fmt::Display for ShellInvocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.shell)?;
+        for option in &self.options {
+            write!(f, " {option}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A builder shell, or its options, changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShellChange {
+    pub old: ShellInvocation,
+    pub new: ShellInvocation,
+}
+
+impl fmt::Display for ShellChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} → {}", self.old, self.new)
+    }
+}
+
+/// The shell change behind the builder and argument changes of `diff`,
+/// if both sides run a shell and it or its options differ.
+pub fn shell_change(diff: &DerivationDiff) -> Option<ShellChange> {
+    if diff.builder.is_none() && diff.args.is_none() {
+        return None;
+    }
+    let old = ShellInvocation::of(&diff.original)?;
+    let new = ShellInvocation::of(&diff.new)?;
+    (old != new).then_some(ShellChange { old, new })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invocation(builder: &str, args: &[&str]) -> Option<String> {
+        let args: Vec<_> = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+        ShellInvocation::parse(builder.as_bytes(), &args).map(|shell| shell.to_string())
+    }
+
+    fn change(old: (&str, &[&str]), new: (&str, &[&str])) -> Option<String> {
+        let drv = |(builder, args): (&str, &[&str])| Derivation {
+            builder: builder.as_bytes().to_vec(),
+            args: args.iter().map(|arg| arg.as_bytes().to_vec()).collect(),
+            ..Default::default()
+        };
+        let mut diff = DerivationDiff::new(drv(old), drv(new));
+        // Only the raw diffs being there matters here, not their content.
+        diff.args = Some(Vec::new());
+        shell_change(&diff).map(|change| change.to_string())
+    }
+
+    const SCRIPT: &str = "/nix/store/00000000000000000000000000000000-builder.sh";
+    const BASH: &str = "/nix/store/11111111111111111111111111111111-bash-5.2/bin/bash";
+    const DASH: &str = "/nix/store/22222222222222222222222222222222-dash-0.5/bin/dash";
+
+    #[test]
+    fn options_are_normalized() {
+        assert_eq!(invocation(BASH, &["-e", SCRIPT]).unwrap(), "bash -e");
+        assert_eq!(invocation(BASH, &[SCRIPT]).unwrap(), "bash");
+        for args in [
+            &["-eu", SCRIPT][..],
+            &["-u", "-e", SCRIPT],
+            &["-o", "errexit", "-o", "nounset", SCRIPT],
+            &["-eo", "nounset", SCRIPT],
+        ] {
+            assert_eq!(invocation(BASH, args).unwrap(), "bash -e -u", "{args:?}");
+        }
+        assert_eq!(
+            invocation(BASH, &["-o", "pipefail", "-e", SCRIPT]).unwrap(),
+            "bash -e -o pipefail"
+        );
+        assert_eq!(invocation("/bin/sh", &["+x", SCRIPT]).unwrap(), "sh +x");
+        assert_eq!(
+            invocation(BASH, &["--posix", "-e", SCRIPT]).unwrap(),
+            "bash -e --posix"
+        );
+        // What follows `-c` or the script is not an option to the shell.
+        assert_eq!(invocation(BASH, &["-ec", "set -u"]).unwrap(), "bash -e");
+        assert_eq!(invocation(BASH, &[SCRIPT, "-u"]).unwrap(), "bash");
+        assert_eq!(invocation(BASH, &["-e", "--", "-u"]).unwrap(), "bash -e");
+        assert_eq!(invocation("/bin/python3", &["-e"]), None);
+        assert_eq!(invocation("builtin:fetchurl", &[]), None);
+    }
+
+    #[test]
+    fn shell_transitions() {
+        assert_eq!(
+            change((BASH, &["-e", SCRIPT]), (DASH, &[SCRIPT])).unwrap(),
+            "bash -e → dash"
+        );
+        assert_eq!(
+            change((BASH, &["-e", SCRIPT]), (BASH, &[SCRIPT])).unwrap(),
+            "bash -e → bash"
+        );
+        assert_eq!(
+            change((BASH, &[SCRIPT]), (BASH, &["-eu", SCRIPT])).unwrap(),
+            "bash → bash -e -u"
+        );
+        assert_eq!(
+            change(
+                (BASH, &["-e", SCRIPT]),
+                (BASH, &["-e", "-o", "pipefail", SCRIPT])
+            )
+            .unwrap(),
+            "bash -e → bash -e -o pipefail"
+        );
+        assert_eq!(
+            change(("/bin/sh", &["-e", SCRIPT]), (BASH, &["-e", SCRIPT])).unwrap(),
+            "sh -e → bash -e"
+        );
+        assert_eq!(
+            change((BASH, &["-ex", SCRIPT]), (BASH, &["-e", SCRIPT])).unwrap(),
+            "bash -e -x → bash -e"
+        );
+    }
+
+    #[test]
+    fn no_shell_change() {
+        // A new bash or script is the same invocation.
+        let bash = "/nix/store/33333333333333333333333333333333-bash-5.3/bin/bash";
+        let script = "/nix/store/44444444444444444444444444444444-builder.sh";
+        assert_eq!(
+            change((BASH, &["-e", SCRIPT]), (bash, &["-e", script])),
+            None
+        );
+        assert_eq!(
+            change(
+                (BASH, &["-eu", SCRIPT]),
+                (BASH, &["-o", "nounset", "-e", SCRIPT])
+            ),
+            None
+        );
+        // Only shells are compared.
+        assert_eq!(
+            change((BASH, &["-e", SCRIPT]), ("/bin/python3", &[SCRIPT])),
+            None
+        );
+
+        // Without a builder or argument change there is nothing to explain.
+        let drv = |builder: &str| Derivation {
+            builder: builder.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(
+            shell_change(&DerivationDiff::new(drv(BASH), drv(DASH))),
+            None
+        );
+    }
+}
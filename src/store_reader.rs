@@ -0,0 +1,388 @@
+//! Guarded file reads for content diffs.
+//!
+//! Source paths come straight from the .drv being inspected. A crafted drv
+//! could list `/etc/shadow` as an input source and we would print its
+//! contents into a report that may well end up in public CI logs. All
+//! content reads therefore go through [`StoreReader`], which refuses paths
+//! that do not resolve to somewhere inside an allowed store directory.
+//!
+//! A reader can also stand for a store whose files were copied out of their
+//! store directory, say from another machine into `/tmp/a`: store paths are
+//! then looked up in that directory instead.
+
+use crate::console;
+use crate::types::SourceKind;
+use std::borrow::Cow;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Source contents kept in memory at most by default (1 GiB).
+pub const DEFAULT_READ_BUDGET: usize = 1 << 30;
+
+/// Directory entries [`StoreReader::source_kind`] looks at before it
+/// stops counting.
+pub const SOURCE_WALK_CAP: u64 = 10_000;
+
+#[derive(Debug, Clone)]
+pub struct StoreReader {
+    store_dirs: Vec<PathBuf>,
+    allow_outside: bool,
+    /// The store directory and where its files really are, if elsewhere.
+    relocation: Option<(String, String)>,
+}
+
+impl Default for StoreReader {
+    fn default() -> Self {
+        Self::new([crate::input::store_dir()])
+    }
+}
+
+impl StoreReader {
+    /// A reader that only reads below `store_dirs`.
+    pub fn new<P: AsRef<Path>>(store_dirs: impl IntoIterator<Item = P>) -> Self {
+        let store_dirs = store_dirs
+            .into_iter()
+            .map(|d| {
+                let d = d.as_ref();
+                // Compare canonical forms: /nix/store may itself be a symlink.
+                d.canonicalize().unwrap_or_else(|_| d.to_path_buf())
+            })
+            .collect();
+        Self {
+            store_dirs,
+            allow_outside: false,
+            relocation: None,
+        }
+    }
+
+    /// Look up paths in `store_dir` under `dir` instead, and allow reads
+    /// there (`--store-a`/`--store-b`).
+    pub fn relocated(mut self, store_dir: &str, dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref();
+        let real = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+        self.store_dirs.push(real);
+        let trim = |d: &str| d.trim_end_matches('/').to_string();
+        self.relocation = Some((trim(store_dir), trim(&dir.to_string_lossy())));
+        self
+    }
+
+    /// The file that holds store path `path`: `path` itself, unless the
+    /// store is relocated.
+    pub fn locate<'a>(&self, path: &'a [u8]) -> Cow<'a, [u8]> {
+        match &self.relocation {
+            Some((store_dir, dir)) => match strip_dir(path, store_dir.as_bytes()) {
+                Some(rest) => Cow::Owned([dir.as_bytes(), rest].concat()),
+                None => Cow::Borrowed(path),
+            },
+            None => Cow::Borrowed(path),
+        }
+    }
+
+    /// The store path that file `path` holds, the reverse of
+    /// [`locate`](Self::locate): a file in the relocated directory stands
+    /// for the same name in the store directory. Other paths are their own
+    /// store path.
+    pub fn store_path<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        let Some((store_dir, dir)) = &self.relocation else {
+            return Cow::Borrowed(path);
+        };
+        match strip_dir(path.as_bytes(), dir.as_bytes()) {
+            Some(rest) => Cow::Owned(format!("{store_dir}{}", String::from_utf8_lossy(rest))),
+            None => Cow::Borrowed(path),
+        }
+    }
+
+    /// Skip the store check entirely (`--allow-outside-store-reads`).
+    pub fn allow_outside(mut self, allow: bool) -> Self {
+        self.allow_outside = allow;
+        self
+    }
+
+    /// Whether `path` resolves to a location inside one of the store dirs.
+    pub fn is_allowed(&self, path: &Path) -> bool {
+        if self.allow_outside {
+            return true;
+        }
+        // Canonicalize so `/nix/store/x/../../etc/shadow` and symlinks
+        // pointing out of the store are caught.
+        let Ok(real) = path.canonicalize() else {
+            return false;
+        };
+        self.store_dirs
+            .iter()
+            .any(|dir| real.starts_with(dir) && real != *dir)
+    }
+
+    /// Read `path` if it is inside the store. Refused reads print a warning
+    /// and return `None`, which callers already treat as "unreadable".
+    pub fn read(&self, path: &[u8]) -> Option<Vec<u8>> {
+        let path = self.locate(path);
+        let path = self.checked_path(&path)?;
+        fs::read(path).ok()
+    }
+
+    /// Like [`read`](Self::read), but first reserve the file's size from
+    /// `budget`. Returns [`Budgeted::OverBudget`] without reading if the
+    /// budget cannot cover it.
+    pub fn read_within(&self, path: &[u8], budget: &ReadBudget) -> Budgeted {
+        let path = self.locate(path);
+        let Some(path) = self.checked_path(&path) else {
+            return Budgeted::Unreadable;
+        };
+        let Ok(len) = fs::metadata(path).map(|m| m.len()) else {
+            return Budgeted::Unreadable;
+        };
+        if !budget.reserve(usize::try_from(len).unwrap_or(usize::MAX)) {
+            return Budgeted::OverBudget;
+        }
+        match fs::read(path) {
+            Ok(contents) => Budgeted::Read(contents),
+            Err(_) => Budgeted::Unreadable,
+        }
+    }
+
+    /// Whether `path` is a file or a directory, and how much is in it.
+    /// `None` if it is outside the store or can't be looked at; unlike
+    /// reads, that is not worth a warning.
+    pub fn source_kind(&self, path: &[u8]) -> Option<SourceKind> {
+        let path = self.locate(path);
+        let path = Path::new(std::str::from_utf8(&path).ok()?);
+        if !self.is_allowed(path) {
+            return None;
+        }
+        let metadata = fs::metadata(path).ok()?;
+        if metadata.is_dir() {
+            Some(walk_dir(path, SOURCE_WALK_CAP))
+        } else {
+            Some(SourceKind::File {
+                size: metadata.len(),
+            })
+        }
+    }
+
+    fn checked_path<'a>(&self, path: &'a [u8]) -> Option<&'a Path> {
+        let path = Path::new(std::str::from_utf8(path).ok()?);
+        if !self.is_allowed(path) {
+            if path.exists() {
+                console::warn(format_args!(
+                    "not reading {} because it is outside the store (use --allow-outside-store-reads to override)",
+                    path.display()
+                ));
+            }
+            return None;
+        }
+        Some(path)
+    }
+}
+
+/// `/rest` of `path` if it is `dir/rest`.
+fn strip_dir<'a>(path: &'a [u8], dir: &[u8]) -> Option<&'a [u8]> {
+    path.strip_prefix(dir)
+        .filter(|rest| rest.len() > 1 && rest[0] == b'/')
+}
+
+/// Count the files below `dir` and add up their sizes, looking at no more
+/// than `cap` entries.
+fn walk_dir(dir: &Path, cap: u64) -> SourceKind {
+    let (mut files, mut size, mut seen) = (0, 0, 0);
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            seen += 1;
+            if seen > cap {
+                return SourceKind::Dir {
+                    files,
+                    size,
+                    truncated: true,
+                };
+            }
+            // Symlinks are counted, not followed.
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                files += 1;
+                size += metadata.len();
+            }
+        }
+    }
+    SourceKind::Dir {
+        files,
+        size,
+        truncated: false,
+    }
+}
+
+/// Result of [`StoreReader::read_within`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Budgeted {
+    Read(Vec<u8>),
+    Unreadable,
+    OverBudget,
+}
+
+/// Bytes of file contents a diff may still hold in memory. Shared by all
+/// reader threads, so a handful of huge sources cannot exhaust memory no
+/// matter how the reads are scheduled.
+#[derive(Debug)]
+pub struct ReadBudget {
+    remaining: AtomicUsize,
+    warned: AtomicBool,
+}
+
+impl Default for ReadBudget {
+    fn default() -> Self {
+        Self::new(DEFAULT_READ_BUDGET)
+    }
+}
+
+impl ReadBudget {
+    pub fn new(bytes: usize) -> Self {
+        Self {
+            remaining: AtomicUsize::new(bytes),
+            warned: AtomicBool::new(false),
+        }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.remaining.load(Ordering::Relaxed)
+    }
+
+    /// Take `bytes` out of the budget, or leave it untouched and return
+    /// `false` if not enough is left. Warns once when that first happens.
+    pub fn reserve(&self, bytes: usize) -> bool {
+        let reserved = self
+            .remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(bytes)
+            })
+            .is_ok();
+        if !reserved && !self.warned.swap(true, Ordering::Relaxed) {
+            console::warn("source read budget exhausted; remaining source files are not compared");
+        }
+        reserved
+    }
+
+    /// Return `bytes` that are no longer held, e.g. after finding two
+    /// files identical.
+    pub fn release(&self, bytes: usize) {
+        self.remaining.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_paths_outside_the_store() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = tmp.path().join("store");
+        std::fs::create_dir_all(&store).unwrap();
+        let inside = store.join("aaaa-file");
+        let outside = tmp.path().join("secret");
+        let link = store.join("bbbb-link");
+        std::fs::write(&inside, "ok").unwrap();
+        std::fs::write(&outside, "secret").unwrap();
+        std::os::unix::fs::symlink(&outside, &link).unwrap();
+        let reader = StoreReader::new([&store]);
+        let read = |p: &Path| reader.read(p.to_str().unwrap().as_bytes());
+
+        assert_eq!(read(&inside), Some(b"ok".to_vec()));
+        assert_eq!(read(&outside), None);
+        assert_eq!(read(&link), None, "symlink escaping the store");
+        assert_eq!(read(&store.join("../secret")), None, "dot-dot escape");
+        assert_eq!(read(&store), None, "the store dir itself");
+        assert_eq!(read(Path::new("/etc/hostname")), None);
+
+        let budget = ReadBudget::new(3);
+        let read_within = |p: &Path| reader.read_within(p.to_str().unwrap().as_bytes(), &budget);
+        assert_eq!(read_within(&outside), Budgeted::Unreadable);
+        assert_eq!(read_within(&inside), Budgeted::Read(b"ok".to_vec()));
+        assert_eq!(budget.remaining(), 1);
+        assert_eq!(read_within(&inside), Budgeted::OverBudget);
+        assert_eq!(budget.remaining(), 1, "a failed reservation takes nothing");
+
+        let permissive = StoreReader::new([&store]).allow_outside(true);
+        assert_eq!(
+            permissive.read(outside.to_str().unwrap().as_bytes()),
+            Some(b"secret".to_vec())
+        );
+    }
+
+    #[test]
+    fn relocated_stores_are_read_from_their_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let copy = tmp.path().join("a");
+        std::fs::create_dir_all(&copy).unwrap();
+        std::fs::write(copy.join("aaaa-file"), "copied").unwrap();
+        let copy_str = copy.to_str().unwrap();
+        let reader = StoreReader::new(["/nix/store"]).relocated("/nix/store/", &copy);
+
+        assert_eq!(
+            reader.locate(b"/nix/store/aaaa-file"),
+            format!("{copy_str}/aaaa-file").as_bytes()
+        );
+        assert_eq!(reader.locate(b"/nix/storefront"), &b"/nix/storefront"[..]);
+        assert_eq!(reader.locate(b"/etc/hostname"), &b"/etc/hostname"[..]);
+        assert_eq!(
+            reader.store_path(&format!("{copy_str}/aaaa-file")),
+            "/nix/store/aaaa-file"
+        );
+        assert_eq!(reader.store_path("/nix/store/x"), "/nix/store/x");
+
+        assert_eq!(
+            reader.read(b"/nix/store/aaaa-file"),
+            Some(b"copied".to_vec())
+        );
+        assert_eq!(reader.read(b"/nix/store/bbbb-missing"), None);
+    }
+
+    #[test]
+    fn directories_are_counted_up_to_the_cap() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = tmp.path().join("store");
+        let vendor = store.join("aaaa-vendor");
+        std::fs::create_dir_all(vendor.join("src/deep")).unwrap();
+        std::fs::write(vendor.join("Cargo.toml"), "12345").unwrap();
+        std::fs::write(vendor.join("src/lib.rs"), "123").unwrap();
+        std::fs::write(vendor.join("src/deep/mod.rs"), "1").unwrap();
+        std::os::unix::fs::symlink("/etc/hostname", vendor.join("link")).unwrap();
+        std::fs::write(store.join("bbbb-fix.patch"), "patch").unwrap();
+        let reader = StoreReader::new([&store]);
+        let kind = |p: &Path| reader.source_kind(p.to_str().unwrap().as_bytes());
+
+        let link_size = std::fs::symlink_metadata(vendor.join("link"))
+            .unwrap()
+            .len();
+        assert_eq!(
+            kind(&vendor),
+            Some(SourceKind::Dir {
+                files: 4,
+                size: 9 + link_size,
+                truncated: false,
+            })
+        );
+        assert_eq!(
+            kind(&store.join("bbbb-fix.patch")),
+            Some(SourceKind::File { size: 5 })
+        );
+        assert_eq!(kind(&store.join("cccc-gone")), None);
+        assert_eq!(kind(Path::new("/etc")), None);
+        // Entries: Cargo.toml, src, link, lib.rs, deep, mod.rs; the cap
+        // stops the walk before all files are seen.
+        let SourceKind::Dir {
+            files, truncated, ..
+        } = walk_dir(&vendor, 4)
+        else {
+            panic!("not a directory");
+        };
+        assert!(truncated && files < 4, "{files}");
+    }
+}
@@ -0,0 +1,204 @@
+//! Derivations with `__structuredAttrs = true` keep all their attributes
+//! in one `__json` env var. Comparing it as one string says nothing
+//! useful, so it is parsed and compared attribute by attribute, down
+//! into nested objects and lists.
+
+use crate::types::{EnvVarDiff, StringDiff, StructuredAttrDiff};
+use serde_json::{Map, Value};
+
+/// The env var holding the structured attributes.
+pub const JSON_KEY: &[u8] = b"__json";
+
+/// The attributes that differ between two `__json` values, or `None`
+/// when either is not a JSON object or they are the same once parsed;
+/// `__json` is then diffed like any other env var.
+pub fn diff_structured_attrs(old: &[u8], new: &[u8]) -> Option<Vec<StructuredAttrDiff>> {
+    let (Ok(Value::Object(old)), Ok(Value::Object(new))) = (
+        serde_json::from_slice::<Value>(old),
+        serde_json::from_slice::<Value>(new),
+    ) else {
+        return None;
+    };
+    let mut diffs = Vec::new();
+    diff_objects(&mut diffs, "", &old, &new);
+    (!diffs.is_empty()).then_some(diffs)
+}
+
+fn diff_values(diffs: &mut Vec<StructuredAttrDiff>, path: String, old: &Value, new: &Value) {
+    match (old, new) {
+        _ if old == new => {}
+        (Value::Object(old), Value::Object(new)) => diff_objects(diffs, &path, old, new),
+        (Value::Array(old), Value::Array(new)) => diff_arrays(diffs, &path, old, new),
+        _ => diffs.push(StructuredAttrDiff {
+            path,
+            change: EnvVarDiff::Changed(StringDiff {
+                old: shown(old),
+                new: shown(new),
+            }),
+        }),
+    }
+}
+
+fn diff_objects(
+    diffs: &mut Vec<StructuredAttrDiff>,
+    path: &str,
+    old: &Map<String, Value>,
+    new: &Map<String, Value>,
+) {
+    let mut keys: Vec<_> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        let path = attr_path(path, key);
+        match (old.get(key), new.get(key)) {
+            (Some(old), Some(new)) => diff_values(diffs, path, old, new),
+            (Some(old), None) => diffs.push(StructuredAttrDiff {
+                path,
+                change: EnvVarDiff::Removed(shown(old)),
+            }),
+            (None, Some(new)) => diffs.push(StructuredAttrDiff {
+                path,
+                change: EnvVarDiff::Added(shown(new)),
+            }),
+            (None, None) => unreachable!("key from neither object"),
+        }
+    }
+}
+
+/// Lists are aligned like arguments, so that an inserted element shows up
+/// as one addition rather than a change to every element after it.
+/// Elements are named by their index on the side they are on, the new
+/// one for changed elements.
+fn diff_arrays(diffs: &mut Vec<StructuredAttrDiff>, path: &str, old: &[Value], new: &[Value]) {
+    let element = |index: usize| format!("{path}[{index}]");
+    let removed = |diffs: &mut Vec<_>, o: usize| {
+        diffs.push(StructuredAttrDiff {
+            path: element(o),
+            change: EnvVarDiff::Removed(shown(&old[o])),
+        })
+    };
+    let added = |diffs: &mut Vec<_>, n: usize| {
+        diffs.push(StructuredAttrDiff {
+            path: element(n),
+            change: EnvVarDiff::Added(shown(&new[n])),
+        })
+    };
+    let keys = |values: &[Value]| -> Vec<String> { values.iter().map(Value::to_string).collect() };
+    let (old_keys, new_keys) = (keys(old), keys(new));
+    for op in similar::capture_diff_slices(similar::Algorithm::Myers, &old_keys, &new_keys) {
+        match op {
+            similar::DiffOp::Equal { .. } => {}
+            similar::DiffOp::Delete {
+                old_index, old_len, ..
+            } => (old_index..old_index + old_len).for_each(|o| removed(diffs, o)),
+            similar::DiffOp::Insert {
+                new_index, new_len, ..
+            } => (new_index..new_index + new_len).for_each(|n| added(diffs, n)),
+            // Replaced runs are paired up in order and compared; the
+            // longer one's tail was added or removed.
+            similar::DiffOp::Replace {
+                old_index,
+                old_len,
+                new_index,
+                new_len,
+            } => {
+                let paired = old_len.min(new_len);
+                for i in 0..paired {
+                    let (o, n) = (old_index + i, new_index + i);
+                    diff_values(diffs, element(n), &old[o], &new[n]);
+                }
+                (old_index + paired..old_index + old_len).for_each(|o| removed(diffs, o));
+                (new_index + paired..new_index + new_len).for_each(|n| added(diffs, n));
+            }
+        }
+    }
+}
+
+/// `key` below `parent`, written like a Nix attribute path: quoted unless
+/// it is a plain identifier.
+fn attr_path(parent: &str, key: &str) -> String {
+    let plain = key
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '\''));
+    let key = if plain {
+        key.to_string()
+    } else {
+        Value::from(key).to_string()
+    };
+    if parent.is_empty() {
+        key
+    } else {
+        format!("{parent}.{key}")
+    }
+}
+
+/// A value as it is shown: strings as they are, like env vars, anything
+/// else as compact JSON.
+fn shown(value: &Value) -> Vec<u8> {
+    match value {
+        Value::String(s) => s.as_bytes().to_vec(),
+        _ => value.to_string().into_bytes(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff(old: &str, new: &str) -> Vec<String> {
+        diff_structured_attrs(old.as_bytes(), new.as_bytes())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|attr| {
+                let show = |b: &[u8]| String::from_utf8_lossy(b).into_owned();
+                let change = match attr.change {
+                    EnvVarDiff::Added(v) => format!("+ {}", show(&v)),
+                    EnvVarDiff::Removed(v) => format!("- {}", show(&v)),
+                    EnvVarDiff::Changed(d) => format!("{} -> {}", show(&d.old), show(&d.new)),
+                    other => panic!("unexpected change {other:?}"),
+                };
+                format!("{}: {change}", attr.path)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nested_attributes_are_diffed_by_path() {
+        let old = r#"{
+            "outputs": ["out", "dev"],
+            "env": {"NIX_CFLAGS_COMPILE": "-O2", "cmake": {"flags": ["-DA=1", "-DB=1"]}},
+            "exportReferencesGraph": {"closure": ["/nix/store/a"]},
+            "doCheck": true
+        }"#;
+        let new = r#"{
+            "outputs": ["bin", "out", "dev"],
+            "env": {"NIX_CFLAGS_COMPILE": "-O3", "cmake": {"flags": ["-DA=2", "-DB=1", "-DC=1"]}},
+            "doCheck": false,
+            "meta with spaces": 1
+        }"#;
+        assert_eq!(
+            diff(old, new),
+            [
+                "doCheck: true -> false",
+                "env.NIX_CFLAGS_COMPILE: -O2 -> -O3",
+                "env.cmake.flags[0]: -DA=1 -> -DA=2",
+                "env.cmake.flags[2]: + -DC=1",
+                r#"exportReferencesGraph: - {"closure":["/nix/store/a"]}"#,
+                r#""meta with spaces": + 1"#,
+                "outputs[0]: + bin",
+            ]
+        );
+    }
+
+    #[test]
+    fn values_that_are_not_objects_fall_back() {
+        assert_eq!(diff_structured_attrs(b"{\"a\": 1", b"{\"a\": 2}"), None);
+        assert_eq!(diff_structured_attrs(b"[1]", b"[2]"), None);
+        // Only the formatting differs.
+        assert_eq!(diff_structured_attrs(b"{\"a\":1}", b"{ \"a\": 1 }"), None);
+    }
+}
@@ -0,0 +1,448 @@
+//! Change counts for the summary line, and the totals closing the report.
+//!
+//! Both are counted from the finished diff, so a summary printed above
+//! the details agrees with them. Counting only walks the diff tree. The
+//! totals ([`DiffStats`]) also count changed lines.
+
+use crate::types::{DerivationDiff, EnvVarDiff, TextDiff};
+use serde::{Deserialize, Serialize};
+use similar::ChangeTag;
+
+/// How many things changed, across the top-level derivation and every
+/// input derivation diffed below it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiffSummary {
+    /// Derivations with at least one change, including the top level.
+    pub derivations: usize,
+    pub arguments: usize,
+    pub sources_changed: usize,
+    pub sources_added: usize,
+    pub sources_removed: usize,
+    pub inputs_added: usize,
+    pub inputs_removed: usize,
+    pub env_vars: usize,
+    /// Attributes in `__json` that differ, for structured attrs.
+    pub structured_attrs: usize,
+    /// Sandbox and purity flags set, unset or changed.
+    pub sandbox_flags: usize,
+    /// Fixed-output derivations whose source changed but whose output
+    /// hash did not.
+    #[serde(default)]
+    pub stale_hashes: usize,
+    /// Version changes where the new version sorts lower.
+    pub downgrades: usize,
+    /// Changed inputs left uncompared by `--max-derivations` or
+    /// `--timeout`.
+    pub inputs_not_compared: usize,
+    /// Whether `--timeout` cut the comparison short, leaving inputs
+    /// uncompared.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub timed_out: bool,
+}
+
+impl DiffSummary {
+    pub fn of(diff: &DerivationDiff) -> Self {
+        let mut summary = Self::default();
+        // Inputs reached twice are counted where they were first compared.
+        diff.walk(|_, drv| summary.add(drv));
+        summary
+    }
+
+    fn add(&mut self, diff: &DerivationDiff) {
+        if !diff.has_changes() {
+            return;
+        }
+        self.derivations += 1;
+        self.arguments += diff.args.as_ref().map_or(0, Vec::len);
+        if let Some(sources) = &diff.sources {
+            self.sources_changed += sources.common.len();
+            self.sources_added += sources.added.len();
+            self.sources_removed += sources.removed.len();
+        }
+        self.env_vars += counted_env(diff).count();
+        // Fetch attributes are env vars too, shown in their own section.
+        if let Some(fetch) = &diff.fetch {
+            self.env_vars += fetch.attrs.iter().filter(|a| !a.same_digest).count();
+            self.stale_hashes += usize::from(fetch.stale_hash);
+        }
+        self.structured_attrs += diff.structured_attrs.as_ref().map_or(0, Vec::len);
+        self.sandbox_flags += diff.sandbox.as_ref().map_or(0, Vec::len);
+        if diff
+            .name
+            .as_ref()
+            .and_then(|n| n.version.as_ref())
+            .is_some_and(|v| v.downgrade)
+        {
+            self.downgrades += 1;
+        }
+        if let Some(inputs) = &diff.inputs {
+            self.inputs_added += inputs.added.len();
+            self.inputs_removed += inputs.removed.len();
+            self.inputs_not_compared += inputs.not_compared.len();
+            self.timed_out |= inputs.timed_out;
+        }
+    }
+
+    /// The counts as comma-separated phrases, leaving out zeros.
+    pub fn describe(&self) -> String {
+        self.parts().join(", ")
+    }
+
+    /// The phrases [`describe`](Self::describe) joins.
+    pub fn parts(&self) -> Vec<String> {
+        let verb = if self.derivations == 1 {
+            " differs"
+        } else {
+            " differ"
+        };
+        let mut parts = vec![count(self.derivations, "derivation", "derivations") + verb];
+        for (n, one, many) in [
+            (self.arguments, "argument", "arguments"),
+            (self.env_vars, "env var", "env vars"),
+            (self.structured_attrs, "attribute", "attributes"),
+            (self.sandbox_flags, "sandbox flag", "sandbox flags"),
+            (
+                self.stale_hashes,
+                "possibly stale hash",
+                "possibly stale hashes",
+            ),
+            (self.sources_changed, "source changed", "sources changed"),
+            (self.sources_added, "source added", "sources added"),
+            (self.sources_removed, "source removed", "sources removed"),
+            (self.inputs_added, "input added", "inputs added"),
+            (self.inputs_removed, "input removed", "inputs removed"),
+            (self.downgrades, "downgrade", "downgrades"),
+            (
+                self.inputs_not_compared,
+                "input not compared",
+                "inputs not compared",
+            ),
+        ] {
+            if n > 0 {
+                parts.push(count(n, one, many));
+            }
+        }
+        if self.timed_out {
+            parts.push("incomplete (timed out)".to_string());
+        }
+        parts
+    }
+}
+
+/// The scale of a diff, like the last line of `git diff --stat`: what
+/// was added, removed or changed, summed over the top-level derivation
+/// and every input derivation diffed below it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiffStats {
+    /// Inputs of each derivation, so an input added to two derivations
+    /// counts twice.
+    pub inputs_added: usize,
+    pub inputs_removed: usize,
+    pub inputs_changed: usize,
+    /// Env vars, leaving out those that mirror output paths or the
+    /// builder, as the summary does.
+    pub env_added: usize,
+    pub env_removed: usize,
+    pub env_changed: usize,
+    /// Sources added, removed or changed.
+    pub sources_changed: usize,
+    /// Lines added and removed in changed env vars and text sources.
+    pub lines_added: usize,
+    pub lines_removed: usize,
+}
+
+impl DiffStats {
+    pub fn of(diff: &DerivationDiff) -> Self {
+        let mut stats = Self::default();
+        diff.walk(|_, drv| stats.add(drv));
+        stats
+    }
+
+    fn add(&mut self, diff: &DerivationDiff) {
+        if let Some(inputs) = &diff.inputs {
+            self.inputs_added += inputs.added.len();
+            self.inputs_removed += inputs.removed.len();
+            self.inputs_changed += inputs.all_changed().count();
+        }
+        for (_, change) in counted_env(diff) {
+            match change {
+                EnvVarDiff::Added(_) => self.env_added += 1,
+                EnvVarDiff::Removed(_) => self.env_removed += 1,
+                EnvVarDiff::Changed(change) => {
+                    self.env_changed += 1;
+                    self.add_lines(&change.old, &change.new);
+                }
+                _ => self.env_changed += 1,
+            }
+        }
+        if let Some(sources) = &diff.sources {
+            self.sources_changed +=
+                sources.added.len() + sources.removed.len() + sources.common.len();
+            for source in &sources.common {
+                if let TextDiff::Text { old, new, .. } = &source.diff {
+                    self.add_lines(old, new);
+                }
+            }
+        }
+    }
+
+    fn add_lines(&mut self, old: &[u8], new: &[u8]) {
+        for change in similar::TextDiff::from_lines(old, new).iter_all_changes() {
+            match change.tag() {
+                ChangeTag::Insert => self.lines_added += 1,
+                ChangeTag::Delete => self.lines_removed += 1,
+                ChangeTag::Equal => {}
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The counts as comma-separated phrases, leaving out zeros.
+    pub fn describe(&self) -> String {
+        self.parts().join(", ")
+    }
+
+    /// The phrases [`describe`](Self::describe) joins.
+    pub fn parts(&self) -> Vec<String> {
+        if self.is_empty() {
+            return vec!["no inputs, env vars or sources changed".to_string()];
+        }
+        let mut parts = Vec::new();
+        for (n, one, many) in [
+            (self.inputs_added, "input added", "inputs added"),
+            (self.inputs_removed, "input removed", "inputs removed"),
+            (self.inputs_changed, "input changed", "inputs changed"),
+            (self.env_added, "env var added", "env vars added"),
+            (self.env_removed, "env var removed", "env vars removed"),
+            (self.env_changed, "env var changed", "env vars changed"),
+            (self.sources_changed, "source changed", "sources changed"),
+            (self.lines_added, "line added", "lines added"),
+            (self.lines_removed, "line removed", "lines removed"),
+        ] {
+            if n > 0 {
+                parts.push(count(n, one, many));
+            }
+        }
+        parts
+    }
+}
+
+/// The changed env vars the counts include. Like the default rendering,
+/// this leaves out vars that only mirror output paths or the builder;
+/// they change whenever anything else does.
+fn counted_env(diff: &DerivationDiff) -> impl Iterator<Item = (&Vec<u8>, &EnvVarDiff)> {
+    diff.env
+        .iter()
+        .flatten()
+        .filter_map(|(key, change)| Some((key, change.as_ref()?)))
+        .filter(|(key, _)| {
+            key.as_slice() != b"builder"
+                && !diff.original.outputs.contains_key(*key)
+                && !diff.new.outputs.contains_key(*key)
+        })
+}
+
+fn count(n: usize, one: &str, many: &str) -> String {
+    format!("{n} {}", if n == 1 { one } else { many })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::*;
+    use std::collections::BTreeSet;
+    use std::sync::Arc;
+
+    fn diff() -> DerivationDiff {
+        DerivationDiff::new(Derivation::default(), Derivation::default())
+    }
+
+    #[test]
+    fn counts_nested_changes_once() {
+        let mut env = EnvironmentDiff::new();
+        env.insert(b"a".to_vec(), Some(EnvVarDiff::Added(b"1".to_vec())));
+        env.insert(b"b".to_vec(), Some(EnvVarDiff::Removed(b"1".to_vec())));
+        env.insert(
+            b"out".to_vec(),
+            Some(EnvVarDiff::Added(b"/nix/store/z".to_vec())),
+        );
+        let mut nested = DerivationDiff {
+            env: Some(env),
+            name: Some(NameDiff {
+                pname: None,
+                version: Some(VersionDiff {
+                    old: b"2".to_vec(),
+                    new: b"1".to_vec(),
+                    downgrade: true,
+                }),
+                covers_name: true,
+            }),
+            sandbox: Some(vec![SandboxFlagDiff {
+                key: b"__noChroot".to_vec(),
+                old: None,
+                new: Some(b"1".to_vec()),
+            }]),
+            ..diff()
+        };
+        Arc::make_mut(&mut nested.new).outputs.insert(
+            b"out".to_vec(),
+            Output {
+                path: b"/nix/store/z".to_vec(),
+                hash_algorithm: None,
+                hash: None,
+            },
+        );
+        let already = DerivationDiff {
+            outputs: OutputsDiff::AlreadyCompared,
+            ..diff()
+        };
+        let input = |derivation| InputDiff {
+            path: b"dep.drv".to_vec(),
+            outputs: None,
+            derivation: Some(Box::new(derivation)),
+            unavailable: None,
+            version: None,
+        };
+        let top = DerivationDiff {
+            sources: Some(SourcesDiff {
+                added: BTreeSet::from([b"/nix/store/x-new".to_vec()]),
+                removed: BTreeSet::new(),
+                common: vec![SourceDiff {
+                    path: b"builder.sh".to_vec(),
+                    diff: TextDiff::Binary,
+                }],
+                ..Default::default()
+            }),
+            inputs: Some(InputsDiff {
+                added: BTreeSet::new(),
+                removed: BTreeSet::from([DerivationPath(b"/nix/store/y-old.drv".to_vec())]),
+                changed: vec![input(nested), input(already)],
+                pruned: Vec::new(),
+                rebuilt: Vec::new(),
+                not_compared: Vec::new(),
+                timed_out: false,
+                depth_limited: false,
+            }),
+            ..diff()
+        };
+
+        let summary = DiffSummary::of(&top);
+        assert_eq!(
+            summary,
+            DiffSummary {
+                derivations: 2,
+                sources_changed: 1,
+                sources_added: 1,
+                inputs_removed: 1,
+                env_vars: 2,
+                sandbox_flags: 1,
+                downgrades: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            summary.describe(),
+            "2 derivations differ, 2 env vars, 1 sandbox flag, 1 source changed, 1 source added, \
+             1 input removed, 1 downgrade"
+        );
+        assert_eq!(DiffSummary::of(&diff()), DiffSummary::default());
+    }
+
+    #[test]
+    fn stats_count_every_level_once() {
+        let env = |vars: Vec<(&str, EnvVarDiff)>| {
+            let env: EnvironmentDiff = vars
+                .into_iter()
+                .map(|(key, change)| (key.as_bytes().to_vec(), Some(change)))
+                .collect();
+            Some(env)
+        };
+        let changed = |old: &str, new: &str| {
+            EnvVarDiff::Changed(StringDiff {
+                old: old.as_bytes().to_vec(),
+                new: new.as_bytes().to_vec(),
+            })
+        };
+        let nested = DerivationDiff {
+            env: env(vec![("version", changed("1", "2"))]),
+            ..diff()
+        };
+        // Counted where it was compared first, not here.
+        let already = DerivationDiff {
+            outputs: OutputsDiff::AlreadyCompared,
+            env: env(vec![("extra", EnvVarDiff::Added(b"1".to_vec()))]),
+            ..diff()
+        };
+        let input = |derivation| InputDiff {
+            path: b"dep.drv".to_vec(),
+            outputs: None,
+            derivation: Some(Box::new(derivation)),
+            unavailable: None,
+            version: None,
+        };
+        let mut top = DerivationDiff {
+            env: env(vec![
+                ("a", EnvVarDiff::Added(b"1".to_vec())),
+                ("b", EnvVarDiff::Removed(b"1".to_vec())),
+                ("script", changed("x\ny\n", "x\nz\nw\n")),
+                ("builder", changed("/bin/sh", "/bin/bash")),
+            ]),
+            sources: Some(SourcesDiff {
+                added: BTreeSet::from([b"/nix/store/x-new".to_vec()]),
+                common: vec![
+                    SourceDiff {
+                        path: b"builder.sh".to_vec(),
+                        diff: TextDiff::text(b"a\n".to_vec(), b"b\n".to_vec()),
+                    },
+                    SourceDiff {
+                        path: b"logo.png".to_vec(),
+                        diff: TextDiff::Binary,
+                    },
+                ],
+                ..Default::default()
+            }),
+            inputs: Some(InputsDiff {
+                added: BTreeSet::from([DerivationPath(b"/nix/store/z-new.drv".to_vec())]),
+                removed: BTreeSet::from([DerivationPath(b"/nix/store/y-old.drv".to_vec())]),
+                changed: vec![input(nested), input(already)],
+                ..Default::default()
+            }),
+            ..diff()
+        };
+        // `a` mirrors an output and `builder` the builder; neither counts.
+        Arc::make_mut(&mut top.new)
+            .outputs
+            .insert(b"a".to_vec(), Output::default());
+
+        let stats = DiffStats::of(&top);
+        assert_eq!(
+            stats,
+            DiffStats {
+                inputs_added: 1,
+                inputs_removed: 1,
+                inputs_changed: 2,
+                env_removed: 1,
+                env_changed: 2,
+                sources_changed: 3,
+                lines_added: 4,
+                lines_removed: 3,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            stats.describe(),
+            "1 input added, 1 input removed, 2 inputs changed, 1 env var removed, \
+             2 env vars changed, 3 sources changed, 4 lines added, 3 lines removed"
+        );
+        assert!(DiffStats::of(&diff()).is_empty());
+        assert_eq!(
+            DiffStats::of(&diff()).describe(),
+            "no inputs, env vars or sources changed"
+        );
+    }
+}
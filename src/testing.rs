@@ -0,0 +1,360 @@
+//! Hermetic Nix environments for tests, here and in crates that embed
+//! nix-diff (`test-support` feature).
+//!
+//! A [`NixEnv`] is a temporary directory with a store and the state, log
+//! and cache directories Nix wants, plus the environment variables that
+//! point Nix and nix-diff at them, with substituters and the sandbox off.
+//! Pass [`NixEnv::vars`] to every `nix-instantiate` or `nix-diff` a test
+//! runs. Tests that don't need Nix itself can fill the store up front.
+
+use anyhow::{Context, Result};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+
+/// Replaced by the store directory in files put into the store, so
+/// fixtures can name store paths before the directory exists.
+pub const STORE_DIR_PLACEHOLDER: &str = "@storeDir@";
+
+/// Directories below the root that every environment gets, besides the
+/// store.
+const DIRS: &[&str] = &[
+    "var/log/nix/drvs",
+    "var/nix/profiles",
+    "state",
+    "cache",
+    "xdg-state",
+];
+
+/// Settings for a [`NixEnv`]; see [`NixEnv::builder`].
+#[derive(Debug, Clone)]
+pub struct NixEnvBuilder {
+    nix_config: Vec<String>,
+    store: PathBuf,
+    dirs: Vec<PathBuf>,
+    files: Vec<(PathBuf, Vec<u8>)>,
+}
+
+impl Default for NixEnvBuilder {
+    fn default() -> Self {
+        Self {
+            nix_config: vec![
+                "substituters =".to_string(),
+                "connect-timeout = 0".to_string(),
+                "sandbox = false".to_string(),
+            ],
+            store: PathBuf::from("store"),
+            dirs: Vec::new(),
+            files: Vec::new(),
+        }
+    }
+}
+
+impl NixEnvBuilder {
+    /// Add a line to `NIX_CONFIG`, after the defaults.
+    pub fn nix_config(mut self, line: &str) -> Self {
+        self.nix_config.push(line.to_string());
+        self
+    }
+
+    /// Put the store at `path` below the root instead of `store`.
+    pub fn store_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.store = path.into();
+        self
+    }
+
+    /// Also create the directory `path` below the root.
+    pub fn dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.dirs.push(path.into());
+        self
+    }
+
+    /// Put a file at `path` below the store, e.g. a .drv or a source.
+    /// [`STORE_DIR_PLACEHOLDER`] in `contents` becomes the store directory.
+    pub fn store_file(mut self, path: impl Into<PathBuf>, contents: impl AsRef<[u8]>) -> Self {
+        self.files.push((path.into(), contents.as_ref().to_vec()));
+        self
+    }
+
+    /// Create the directories and files.
+    pub fn build(self) -> Result<NixEnv> {
+        let root = TempDir::new().context("Failed to create a temporary directory")?;
+        let store = root.path().join(&self.store);
+        let dirs = DIRS.iter().map(PathBuf::from).chain(self.dirs);
+        for dir in std::iter::once(store.clone()).chain(dirs.map(|d| root.path().join(d))) {
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let store_dir = store.to_string_lossy().into_owned();
+        for (path, contents) in &self.files {
+            let path = store.join(path);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| format!("Failed to create {}", parent.display()))?;
+            }
+            let contents = replace(
+                contents,
+                STORE_DIR_PLACEHOLDER.as_bytes(),
+                store_dir.as_bytes(),
+            );
+            std::fs::write(&path, contents)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+
+        let below = |dir: &str| root.path().join(dir).to_string_lossy().into_owned();
+        let vars = vec![
+            ("NIX_STORE_DIR".to_string(), store_dir),
+            ("NIX_DATA_DIR".to_string(), below("share")),
+            ("NIX_LOG_DIR".to_string(), below("var/log/nix")),
+            ("NIX_STATE_DIR".to_string(), below("state")),
+            ("NIX_CONF_DIR".to_string(), below("etc")),
+            ("XDG_CACHE_HOME".to_string(), below("cache")),
+            ("XDG_STATE_HOME".to_string(), below("xdg-state")),
+            ("NIX_CONFIG".to_string(), self.nix_config.join("\n")),
+            ("_NIX_TEST_NO_SANDBOX".to_string(), "1".to_string()),
+            ("NIX_REMOTE".to_string(), String::new()),
+        ];
+        Ok(NixEnv { root, store, vars })
+    }
+}
+
+/// A temporary Nix environment, removed when dropped.
+#[derive(Debug)]
+pub struct NixEnv {
+    root: TempDir,
+    store: PathBuf,
+    vars: Vec<(String, String)>,
+}
+
+impl NixEnv {
+    /// An environment with the default layout and nothing in the store.
+    pub fn new() -> Result<Self> {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> NixEnvBuilder {
+        NixEnvBuilder::default()
+    }
+
+    /// The temporary directory everything is in.
+    pub fn root(&self) -> &Path {
+        self.root.path()
+    }
+
+    pub fn store_dir(&self) -> &Path {
+        &self.store
+    }
+
+    /// The store path `name`, e.g. `<hash>-hello-2.12.drv`.
+    pub fn store_path(&self, name: &str) -> PathBuf {
+        self.store.join(name)
+    }
+
+    /// The environment variables to run Nix and nix-diff with.
+    pub fn vars(&self) -> &[(String, String)] {
+        &self.vars
+    }
+
+    /// A command for `program` with [`Self::vars`] set.
+    pub fn command(&self, program: impl AsRef<OsStr>) -> Command {
+        let mut command = Command::new(program);
+        command.envs(self.vars.iter().map(|(k, v)| (k, v)));
+        command
+    }
+
+    /// A command for the nix-diff binary built with the running
+    /// integration test, with [`Self::vars`] set, `args`, and no colours,
+    /// history or closure count. Add the derivations to compare.
+    pub fn nix_diff(&self, args: &[&str]) -> Command {
+        let mut command = self.command(nix_diff_binary());
+        command
+            .args(["--color", "never", "--no-history", "--no-closure-count"])
+            .args(args);
+        command
+    }
+
+    /// A derivation `name` whose output is `<hash × 32>-name` and whose
+    /// only difference between versions is the `version` variable, for
+    /// [`NixEnvBuilder::store_file`]. `inputs` are .drv file names in the
+    /// same store, each with its `out` output used.
+    pub fn drv(name: &str, hash: char, version: &str, inputs: &[String]) -> String {
+        let hash = hash.to_string().repeat(32);
+        let inputs: Vec<_> = inputs
+            .iter()
+            .map(|p| format!(r#"("{STORE_DIR_PLACEHOLDER}/{p}",["out"])"#))
+            .collect();
+        let out = format!("{STORE_DIR_PLACEHOLDER}/{hash}-{name}");
+        format!(
+            r#"Derive([("out","{out}","","")],[{}],[],"x86_64-linux","/bin/sh",["-c","true"],[("name","{name}"),("out","{out}"),("version","{version}")])"#,
+            inputs.join(",")
+        )
+    }
+}
+
+/// Where cargo puts the nix-diff binary: next to the integration test
+/// running now, which is in `deps/` below it.
+fn nix_diff_binary() -> PathBuf {
+    let mut dir = std::env::current_exe().expect("the running test has a path");
+    dir.pop();
+    if dir.ends_with("deps") {
+        dir.pop();
+    }
+    dir.join(format!("nix-diff{}", std::env::consts::EXE_SUFFIX))
+}
+
+/// `haystack` with every `needle` replaced by `with`.
+fn replace(haystack: &[u8], needle: &[u8], with: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(haystack.len());
+    let mut rest = haystack;
+    while let Some(pos) = rest.windows(needle.len()).position(|w| w == needle) {
+        out.extend_from_slice(&rest[..pos]);
+        out.extend_from_slice(with);
+        rest = &rest[pos + needle.len()..];
+    }
+    out.extend_from_slice(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var<'a>(env: &'a NixEnv, name: &str) -> &'a str {
+        env.vars()
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+            .unwrap_or_else(|| panic!("{name} is not set"))
+    }
+
+    #[test]
+    fn creates_the_layout_and_points_nix_at_it() {
+        let env = NixEnv::new().unwrap();
+        assert_eq!(env.store_dir(), env.root().join("store"));
+        for dir in [
+            "store",
+            "var/log/nix/drvs",
+            "var/nix/profiles",
+            "state",
+            "cache",
+        ] {
+            assert!(env.root().join(dir).is_dir(), "{dir}");
+        }
+        assert_eq!(
+            var(&env, "NIX_STORE_DIR"),
+            env.store_dir().to_str().unwrap()
+        );
+        assert_eq!(
+            var(&env, "XDG_STATE_HOME"),
+            env.root().join("xdg-state").to_str().unwrap()
+        );
+        assert_eq!(var(&env, "NIX_REMOTE"), "");
+        // Every path the variables name is inside the environment.
+        for (name, value) in env.vars() {
+            if value.starts_with('/') {
+                assert!(Path::new(value).starts_with(env.root()), "{name}={value}");
+            }
+        }
+        assert_eq!(
+            var(&env, "NIX_CONFIG"),
+            "substituters =\nconnect-timeout = 0\nsandbox = false"
+        );
+    }
+
+    #[test]
+    fn builder_adds_config_dirs_and_store_files() {
+        let env = NixEnv::builder()
+            .nix_config("experimental-features = nix-command flakes")
+            .store_dir("nix/store")
+            .dir("home")
+            .store_file(
+                "aaaa-hello.drv",
+                "Derive([(\"out\",\"@storeDir@/bbbb-hello\",\"\",\"\")])",
+            )
+            .store_file("cccc-src/build.sh", b"echo hi\n")
+            .build()
+            .unwrap();
+        assert!(var(&env, "NIX_CONFIG").ends_with("\nexperimental-features = nix-command flakes"));
+        assert_eq!(env.store_dir(), env.root().join("nix/store"));
+        assert_eq!(
+            var(&env, "NIX_STORE_DIR"),
+            env.store_dir().to_str().unwrap()
+        );
+        assert!(env.root().join("home").is_dir());
+        assert_eq!(
+            std::fs::read_to_string(env.store_path("aaaa-hello.drv")).unwrap(),
+            format!(
+                "Derive([(\"out\",\"{}/bbbb-hello\",\"\",\"\")])",
+                env.store_dir().display()
+            )
+        );
+        assert_eq!(
+            std::fs::read(env.store_path("cccc-src/build.sh")).unwrap(),
+            b"echo hi\n"
+        );
+    }
+
+    #[test]
+    fn commands_get_the_variables() {
+        let env = NixEnv::new().unwrap();
+        let command = env.command("nix-instantiate");
+        let store = command
+            .get_envs()
+            .find(|(k, _)| *k == "NIX_STORE_DIR")
+            .and_then(|(_, v)| v);
+        assert_eq!(store, Some(env.store_dir().as_os_str()));
+    }
+
+    #[test]
+    fn drv_names_its_inputs_in_the_store() {
+        let lib = "3".repeat(32) + "-lib.drv";
+        let app = "4".repeat(32) + "-app.drv";
+        let env = NixEnv::builder()
+            .store_file(&app, NixEnv::drv("app", '5', "2", &[lib.clone()]))
+            .build()
+            .unwrap();
+        let path = env.store_path(&app);
+        let drv = crate::parser::parse_derivation(path.to_str().unwrap()).unwrap();
+        let store = env.store_dir().to_str().unwrap();
+        assert_eq!(
+            drv.outputs[&b"out"[..]].path,
+            format!("{store}/{}-app", "5".repeat(32)).into_bytes()
+        );
+        assert_eq!(drv.env[&b"version"[..]], b"2");
+        assert_eq!(
+            drv.input_derivations
+                .keys()
+                .map(|path| &path[..])
+                .collect::<Vec<_>>(),
+            [format!("{store}/{lib}").as_bytes()]
+        );
+    }
+
+    #[test]
+    fn nix_diff_runs_quietly_in_the_environment() {
+        let env = NixEnv::new().unwrap();
+        let command = env.nix_diff(&["--format", "json"]);
+        assert!(Path::new(command.get_program()).ends_with("nix-diff"));
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            [
+                "--color",
+                "never",
+                "--no-history",
+                "--no-closure-count",
+                "--format",
+                "json"
+            ]
+        );
+        assert!(command.get_envs().any(|(k, _)| k == "NIX_STORE_DIR"));
+    }
+
+    #[test]
+    fn the_environment_is_removed_when_dropped() {
+        let env = NixEnv::new().unwrap();
+        let root = env.root().to_path_buf();
+        drop(env);
+        assert!(!root.exists());
+    }
+}
@@ -0,0 +1,304 @@
+//! Display width of text in a terminal, for anything laid out in columns.
+//!
+//! Derivation and package names are mostly ASCII, but not always: CJK
+//! characters and most emoji take two columns, combining marks none. Byte
+//! or `char` counts misalign tables as soon as one of those shows up.
+//!
+//! Widths follow Unicode's East Asian Width and emoji presentation for the
+//! ranges that occur in practice; there is no full Unicode table here.
+//! Text is measured and cut by grapheme cluster, approximated as a base
+//! character with everything that attaches to it.
+
+use std::borrow::Cow;
+
+/// Characters that take no column of their own: combining marks, joiners,
+/// variation selectors, emoji modifiers and tags.
+const ZERO_WIDTH: &[(u32, u32)] = &[
+    (0x0300, 0x036F),
+    (0x0483, 0x0489),
+    (0x0591, 0x05BD),
+    (0x05BF, 0x05BF),
+    (0x05C1, 0x05C2),
+    (0x05C4, 0x05C5),
+    (0x05C7, 0x05C7),
+    (0x0610, 0x061A),
+    (0x064B, 0x065F),
+    (0x0670, 0x0670),
+    (0x06D6, 0x06DC),
+    (0x06DF, 0x06E4),
+    (0x06E7, 0x06E8),
+    (0x06EA, 0x06ED),
+    (0x0900, 0x0902),
+    (0x093A, 0x093A),
+    (0x093C, 0x093C),
+    (0x0941, 0x0948),
+    (0x094D, 0x094D),
+    (0x0951, 0x0957),
+    (0x0E31, 0x0E31),
+    (0x0E34, 0x0E3A),
+    (0x0E47, 0x0E4E),
+    (0x1AB0, 0x1AFF),
+    (0x1DC0, 0x1DFF),
+    (0x200B, 0x200F),
+    (0x202A, 0x202E),
+    (0x2060, 0x2064),
+    (0x20D0, 0x20FF),
+    (0x302A, 0x302D),
+    (0x3099, 0x309A),
+    (0xFE00, 0xFE0F),
+    (0xFE20, 0xFE2F),
+    (0xFEFF, 0xFEFF),
+    (0x1F3FB, 0x1F3FF),
+    (0xE0000, 0xE007F),
+    (0xE0100, 0xE01EF),
+];
+
+/// Characters that take two columns: East Asian wide and fullwidth
+/// characters, and emoji shown as emoji by default.
+const WIDE: &[(u32, u32)] = &[
+    (0x1100, 0x115F),
+    (0x231A, 0x231B),
+    (0x2329, 0x232A),
+    (0x23E9, 0x23EC),
+    (0x23F0, 0x23F0),
+    (0x23F3, 0x23F3),
+    (0x25FD, 0x25FE),
+    (0x2614, 0x2615),
+    (0x2648, 0x2653),
+    (0x267F, 0x267F),
+    (0x2693, 0x2693),
+    (0x26A1, 0x26A1),
+    (0x26AA, 0x26AB),
+    (0x26BD, 0x26BE),
+    (0x26C4, 0x26C5),
+    (0x26CE, 0x26CE),
+    (0x26D4, 0x26D4),
+    (0x26EA, 0x26EA),
+    (0x26F2, 0x26F3),
+    (0x26F5, 0x26F5),
+    (0x26FA, 0x26FA),
+    (0x26FD, 0x26FD),
+    (0x2705, 0x2705),
+    (0x270A, 0x270B),
+    (0x2728, 0x2728),
+    (0x274C, 0x274C),
+    (0x274E, 0x274E),
+    (0x2753, 0x2755),
+    (0x2757, 0x2757),
+    (0x2795, 0x2797),
+    (0x27B0, 0x27B0),
+    (0x27BF, 0x27BF),
+    (0x2B1B, 0x2B1C),
+    (0x2B50, 0x2B50),
+    (0x2B55, 0x2B55),
+    (0x2E80, 0x3029),
+    (0x302E, 0x303E),
+    (0x3041, 0x3098),
+    (0x309B, 0x33FF),
+    (0x3400, 0x4DBF),
+    (0x4E00, 0x9FFF),
+    (0xA000, 0xA4CF),
+    (0xA960, 0xA97F),
+    (0xAC00, 0xD7A3),
+    (0xF900, 0xFAFF),
+    (0xFE10, 0xFE19),
+    (0xFE30, 0xFE6F),
+    (0xFF00, 0xFF60),
+    (0xFFE0, 0xFFE6),
+    (0x16FE0, 0x16FE4),
+    (0x17000, 0x18AFF),
+    (0x1B000, 0x1B2FF),
+    (0x1F004, 0x1F004),
+    (0x1F0CF, 0x1F0CF),
+    (0x1F18E, 0x1F18E),
+    (0x1F191, 0x1F19A),
+    (0x1F200, 0x1F251),
+    (0x1F300, 0x1F3FA),
+    (0x1F400, 0x1F64F),
+    (0x1F680, 0x1F6FF),
+    (0x1F7E0, 0x1F7EB),
+    (0x1F90C, 0x1F9FF),
+    (0x1FA70, 0x1FAFF),
+    (0x20000, 0x2FFFD),
+    (0x30000, 0x3FFFD),
+];
+
+const ZWJ: char = '\u{200D}';
+/// Asks for emoji presentation of the preceding character.
+const VS16: char = '\u{FE0F}';
+const ELLIPSIS: char = '…';
+
+fn in_table(table: &[(u32, u32)], c: char) -> bool {
+    let c = u32::from(c);
+    table
+        .binary_search_by(|&(start, end)| {
+            if end < c {
+                std::cmp::Ordering::Less
+            } else if start > c {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        })
+        .is_ok()
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}
+
+/// Columns taken by `c` on its own.
+fn char_width(c: char) -> usize {
+    if c.is_control() || in_table(ZERO_WIDTH, c) {
+        0
+    } else if in_table(WIDE, c) {
+        2
+    } else {
+        1
+    }
+}
+
+/// Split `s` into grapheme clusters: a base character plus the marks,
+/// modifiers and variation selectors attached to it, emoji joined by ZWJ,
+/// and pairs of regional indicators (flags).
+fn graphemes(s: &str) -> impl Iterator<Item = &str> {
+    let mut rest = s;
+    std::iter::from_fn(move || {
+        let mut chars = rest.char_indices();
+        let (_, first) = chars.next()?;
+        let mut end = first.len_utf8();
+        let mut prev = first;
+        let mut flag = is_regional_indicator(first);
+        for (i, c) in chars {
+            let attaches = in_table(ZERO_WIDTH, c) && c != '\u{200B}' && c != '\u{FEFF}';
+            let pairs = flag && is_regional_indicator(c);
+            if !(attaches || prev == ZWJ || pairs) {
+                break;
+            }
+            flag = false;
+            end = i + c.len_utf8();
+            prev = c;
+        }
+        let (cluster, tail) = rest.split_at(end);
+        rest = tail;
+        Some(cluster)
+    })
+}
+
+/// Columns taken by one grapheme cluster: those of its base, or two for
+/// an emoji sequence or flag.
+fn cluster_width(cluster: &str) -> usize {
+    let mut chars = cluster.chars();
+    let Some(base) = chars.next() else {
+        return 0;
+    };
+    let rest: Vec<char> = chars.collect();
+    if is_regional_indicator(base) {
+        return if rest.is_empty() { 1 } else { 2 };
+    }
+    let width = char_width(base);
+    if width == 1 && rest.contains(&VS16) {
+        2
+    } else {
+        width
+    }
+}
+
+/// Columns `s` takes in a terminal.
+pub fn width(s: &str) -> usize {
+    graphemes(s).map(cluster_width).sum()
+}
+
+/// `s` cut to at most `max_width` columns, ending in `…` if anything was
+/// cut. Grapheme clusters are kept whole.
+pub fn truncate(s: &str, max_width: usize) -> Cow<'_, str> {
+    if width(s) <= max_width {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::new();
+    let mut used = 0;
+    for cluster in graphemes(s) {
+        let w = cluster_width(cluster);
+        if used + w + 1 > max_width {
+            break;
+        }
+        out.push_str(cluster);
+        used += w;
+    }
+    if max_width > 0 {
+        out.push(ELLIPSIS);
+    }
+    Cow::Owned(out)
+}
+
+/// `s` followed by spaces up to `target` columns.
+pub fn pad(s: &str, target: usize) -> String {
+    let fill = target.saturating_sub(width(s));
+    let mut out = String::with_capacity(s.len() + fill);
+    out.push_str(s);
+    out.extend(std::iter::repeat_n(' ', fill));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_is_one_column_per_char() {
+        assert_eq!(width(""), 0);
+        assert_eq!(width("hello-2.12.1"), 12);
+        assert_eq!(width("a\tb"), 2);
+    }
+
+    #[test]
+    fn cjk_is_two_columns() {
+        assert_eq!(width("中文输入法"), 10);
+        assert_eq!(width("fcitx5-中文-1.0"), 15);
+        assert_eq!(width("ｆｕｌｌ"), 8);
+        assert_eq!(width("한글"), 4);
+    }
+
+    #[test]
+    fn emoji_are_two_columns() {
+        assert_eq!(width("🚀"), 2);
+        assert_eq!(width("rocket-🚀"), 9);
+        // Text-default symbols turned emoji by VS16.
+        assert_eq!(width("❤\u{FE0F}"), 2);
+        assert_eq!(width("❤"), 1);
+        // Skin tones, ZWJ families and flags are one picture each.
+        assert_eq!(width("👍🏽"), 2);
+        assert_eq!(width("👨\u{200D}👩\u{200D}👧"), 2);
+        assert_eq!(width("🇩🇪🇫🇷"), 4);
+    }
+
+    #[test]
+    fn combining_marks_take_no_column() {
+        assert_eq!(width("e\u{301}"), 1);
+        assert_eq!(width("cafe\u{301}-1.0"), 8);
+        assert_eq!(width("\u{301}"), 0);
+    }
+
+    #[test]
+    fn truncation_keeps_clusters_whole() {
+        assert_eq!(truncate("hello", 5), "hello");
+        assert_eq!(truncate("hello-world", 6), "hello…");
+        // A wide character that doesn't fit before the ellipsis is dropped
+        // rather than split.
+        assert_eq!(truncate("ab中文", 4), "ab…");
+        assert_eq!(width(&truncate("ab中文", 4)), 3);
+        assert_eq!(truncate("cafe\u{301}s!", 5), "cafe\u{301}…");
+        assert_eq!(
+            truncate("👨\u{200D}👩\u{200D}👧xx", 3),
+            "👨\u{200D}👩\u{200D}👧…"
+        );
+        assert_eq!(truncate("abc", 0), "");
+    }
+
+    #[test]
+    fn padding_goes_by_columns() {
+        assert_eq!(pad("zlib", 6), "zlib  ");
+        assert_eq!(pad("中文", 6), "中文  ");
+        assert_eq!(pad("toolong", 3), "toolong");
+    }
+}
@@ -1,14 +1,20 @@
+use crate::diff::normalize_store_paths;
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use std::ptr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// A wrapper around derivation paths that sorts by derivation name instead of full path
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct DerivationPath(pub Vec<u8>);
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DerivationPath(#[serde(with = "byte_strings")] pub Vec<u8>);
 
 impl DerivationPath {
     /// Extract the derivation name from a store path
     /// e.g., "/nix/store/hash-name.drv" -> "name.drv"
-    fn get_name(&self) -> &[u8] {
+    pub(crate) fn get_name(&self) -> &[u8] {
         let path = &self.0;
         // Find the last '/' to get the filename
         if let Some(last_slash) = path.iter().rposition(|&b| b == b'/') {
@@ -42,39 +48,800 @@ impl Ord for DerivationPath {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// A shared, immutable byte string. The store paths and env keys of
+/// derivations are these, so a path named by many derivations is stored
+/// once (see [`Interner::share_derivation`](crate::intern::Interner::share_derivation)).
+pub type ByteStr = Arc<[u8]>;
+
+#[derive(Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
 pub struct Derivation {
+    #[serde(with = "pairs")]
     pub outputs: BTreeMap<Vec<u8>, Output>,
-    pub input_sources: BTreeSet<Vec<u8>>,
-    pub input_derivations: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>,
+    #[serde(with = "byte_strings")]
+    pub input_sources: BTreeSet<ByteStr>,
+    #[serde(with = "byte_strings")]
+    pub input_derivations: BTreeMap<ByteStr, BTreeSet<Vec<u8>>>,
+    #[serde(with = "byte_strings")]
     pub platform: Vec<u8>,
+    #[serde(with = "byte_strings")]
     pub builder: Vec<u8>,
+    #[serde(with = "byte_strings")]
     pub args: Vec<Vec<u8>>,
-    pub env: BTreeMap<Vec<u8>, Vec<u8>>,
+    #[serde(with = "byte_strings")]
+    pub env: BTreeMap<ByteStr, Vec<u8>>,
+}
+
+/// Bytes that print as a string, quoted with `{:?}` and bare with `{}`.
+/// Invalid UTF-8 is escaped as `\xNN` rather than replaced, so what was
+/// read can be told apart from what it was decoded to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct BStr<'a>(pub &'a [u8]);
+
+impl BStr<'_> {
+    fn write(&self, f: &mut fmt::Formatter<'_>, quoted: bool) -> fmt::Result {
+        for chunk in self.0.utf8_chunks() {
+            if quoted {
+                for c in chunk.valid().chars() {
+                    match c {
+                        '\'' => f.write_str("'")?,
+                        c => write!(f, "{}", c.escape_debug())?,
+                    }
+                }
+            } else {
+                f.write_str(chunk.valid())?;
+            }
+            for byte in chunk.invalid() {
+                write!(f, "\\x{byte:02x}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for BStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"")?;
+        self.write(f, true)?;
+        f.write_str("\"")
+    }
+}
+
+impl fmt::Display for BStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, false)
+    }
+}
+
+/// Debug-prints the byte strings in a field of the types below as
+/// [`BStr`]s instead of lists of numbers.
+struct Bytes<'a, T>(&'a T);
+
+impl fmt::Debug for Bytes<'_, Vec<u8>> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        BStr(self.0).fmt(f)
+    }
+}
+
+impl fmt::Debug for Bytes<'_, Option<Vec<u8>>> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.as_deref().map(BStr).fmt(f)
+    }
+}
+
+impl fmt::Debug for Bytes<'_, Vec<Vec<u8>>> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|b| BStr(b)))
+            .finish()
+    }
+}
+
+impl<K: AsRef<[u8]>> fmt::Debug for Bytes<'_, BTreeSet<K>> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set()
+            .entries(self.0.iter().map(|b| BStr(b.as_ref())))
+            .finish()
+    }
+}
+
+impl fmt::Debug for Bytes<'_, Output> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<K: AsRef<[u8]>, V> fmt::Debug for Bytes<'_, BTreeMap<K, V>>
+where
+    for<'a> Bytes<'a, V>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.iter().map(|(k, v)| (BStr(k.as_ref()), Bytes(v))))
+            .finish()
+    }
+}
+
+impl fmt::Debug for Derivation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Derivation")
+            .field("outputs", &Bytes(&self.outputs))
+            .field("input_sources", &Bytes(&self.input_sources))
+            .field("input_derivations", &Bytes(&self.input_derivations))
+            .field("platform", &Bytes(&self.platform))
+            .field("builder", &Bytes(&self.builder))
+            .field("args", &Bytes(&self.args))
+            .field("env", &Bytes(&self.env))
+            .finish()
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Maps keyed by byte strings, (de)serialized as lists of `[key, value]`
+/// pairs; formats like JSON only allow string keys.
+mod pairs {
+    use super::byte_strings::{Buf, Str};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<V, S>(map: &BTreeMap<Vec<u8>, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map.iter().map(|(k, v)| (Str(k), v)))
+    }
+
+    pub fn deserialize<'de, V, D>(deserializer: D) -> Result<BTreeMap<Vec<u8>, V>, D::Error>
+    where
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Ok(Vec::<(Buf, V)>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(k, v)| (k.0, v))
+            .collect())
+    }
+
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<V, S>(
+            map: &Option<BTreeMap<Vec<u8>, V>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error>
+        where
+            V: Serialize,
+            S: Serializer,
+        {
+            match map {
+                Some(map) => {
+                    serializer.serialize_some(&Vec::from_iter(map.iter().map(|(k, v)| (Str(k), v))))
+                }
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, V, D>(
+            deserializer: D,
+        ) -> Result<Option<BTreeMap<Vec<u8>, V>>, D::Error>
+        where
+            V: Deserialize<'de>,
+            D: Deserializer<'de>,
+        {
+            let pairs = Option::<Vec<(Buf, V)>>::deserialize(deserializer)?;
+            Ok(pairs.map(|pairs| pairs.into_iter().map(|(k, v)| (k.0, v)).collect()))
+        }
+    }
+}
+
+/// Byte strings (de)serialized as strings when they are UTF-8, as nearly
+/// all are, and otherwise as `{"lossy": ..., "base64": ...}`: readable
+/// text with U+FFFD for the invalid bytes, and the exact bytes. Strings
+/// with NUL take the second form too, with U+FFFD for NUL, as some JSON
+/// consumers refuse `\u0000`. Without this, serde writes `Vec<u8>` as a
+/// list of numbers.
+pub(crate) mod byte_strings {
+    use serde::de::Error;
+    use serde::ser::SerializeStruct;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::{BTreeMap, BTreeSet};
+
+    /// The containers of byte strings found in the diff types.
+    pub trait ByteStrings: Sized {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>;
+        fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>;
+    }
+
+    pub fn serialize<T: ByteStrings, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T: ByteStrings, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        T::deserialize(deserializer)
+    }
+
+    /// A byte string to serialize.
+    pub struct Str<'a>(pub &'a [u8]);
+
+    impl Serialize for Str<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match std::str::from_utf8(self.0) {
+                Ok(s) if !s.contains('\0') => serializer.serialize_str(s),
+                _ => {
+                    let lossy = String::from_utf8_lossy(self.0).replace('\0', "\u{fffd}");
+                    let mut fields = serializer.serialize_struct("Bytes", 2)?;
+                    fields.serialize_field("lossy", &lossy)?;
+                    fields.serialize_field("base64", &encode_base64(self.0))?;
+                    fields.end()
+                }
+            }
+        }
+    }
+
+    /// A deserialized byte string.
+    pub struct Buf(pub Vec<u8>);
+
+    impl<'de> Deserialize<'de> for Buf {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            #[derive(Deserialize)]
+            #[serde(untagged)]
+            enum Repr {
+                Text(String),
+                Bytes { base64: String },
+            }
+            match Repr::deserialize(deserializer)? {
+                Repr::Text(s) => Ok(Buf(s.into_bytes())),
+                Repr::Bytes { base64 } => decode_base64(&base64)
+                    .map(Buf)
+                    .ok_or_else(|| D::Error::custom(format!("invalid base64: {base64}"))),
+            }
+        }
+    }
+
+    /// A value serialized through its [`ByteStrings`] impl.
+    struct Nested<'a, T>(&'a T);
+
+    impl<T: ByteStrings> Serialize for Nested<'_, T> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            self.0.serialize(serializer)
+        }
+    }
+
+    struct NestedBuf<T>(T);
+
+    impl<'de, T: ByteStrings> Deserialize<'de> for NestedBuf<T> {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            T::deserialize(deserializer).map(NestedBuf)
+        }
+    }
+
+    fn strs<'a, C, B>(strings: C) -> impl Iterator<Item = Str<'a>>
+    where
+        C: IntoIterator<Item = &'a B>,
+        B: AsRef<[u8]> + 'a,
+    {
+        strings.into_iter().map(|s| Str(s.as_ref()))
+    }
+
+    fn bufs<'de, C, B, D>(deserializer: D) -> Result<C, D::Error>
+    where
+        C: FromIterator<B>,
+        B: From<Vec<u8>>,
+        D: Deserializer<'de>,
+    {
+        Ok(Vec::<Buf>::deserialize(deserializer)?
+            .into_iter()
+            .map(|b| b.0.into())
+            .collect())
+    }
+
+    impl ByteStrings for Vec<u8> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            Str(self).serialize(serializer)
+        }
+
+        fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            Ok(Buf::deserialize(deserializer)?.0)
+        }
+    }
+
+    impl ByteStrings for Option<Vec<u8>> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            self.as_deref().map(Str).serialize(serializer)
+        }
+
+        fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            Ok(Option::<Buf>::deserialize(deserializer)?.map(|b| b.0))
+        }
+    }
+
+    impl ByteStrings for Option<[Vec<u8>; 2]> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            self.as_ref()
+                .map(|[a, b]| [Str(a), Str(b)])
+                .serialize(serializer)
+        }
+
+        fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            Ok(Option::<[Buf; 2]>::deserialize(deserializer)?.map(|[a, b]| [a.0, b.0]))
+        }
+    }
+
+    impl ByteStrings for Vec<Vec<u8>> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(strs(self))
+        }
+
+        fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            bufs(deserializer)
+        }
+    }
+
+    impl<K: AsRef<[u8]> + From<Vec<u8>> + Ord> ByteStrings for BTreeSet<K> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(strs(self))
+        }
+
+        fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            bufs(deserializer)
+        }
+    }
+
+    /// As `[key, value]` pairs, like [`super::pairs`].
+    impl<K, V> ByteStrings for BTreeMap<K, V>
+    where
+        K: AsRef<[u8]> + From<Vec<u8>> + Ord,
+        V: ByteStrings,
+    {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(self.iter().map(|(k, v)| (Str(k.as_ref()), Nested(v))))
+        }
+
+        fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            Ok(Vec::<(Buf, NestedBuf<V>)>::deserialize(deserializer)?
+                .into_iter()
+                .map(|(k, v)| (k.0.into(), v.0))
+                .collect())
+        }
+    }
+
+    const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    /// Standard base64 with padding.
+    pub fn encode_base64(data: &[u8]) -> String {
+        let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+        for chunk in data.chunks(3) {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    out.push(BASE64[(n >> (18 - 6 * i) & 63) as usize] as char);
+                } else {
+                    out.push('=');
+                }
+            }
+        }
+        out
+    }
+
+    /// The bytes of padded base64 `text`, or `None` if it isn't.
+    pub fn decode_base64(text: &str) -> Option<Vec<u8>> {
+        if !text.len().is_multiple_of(4) {
+            return None;
+        }
+        let mut out = Vec::with_capacity(text.len() / 4 * 3);
+        for chunk in text.as_bytes().chunks(4) {
+            let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+            if padding > 2 {
+                return None;
+            }
+            let mut n = 0u32;
+            for (i, &c) in chunk[..4 - padding].iter().enumerate() {
+                let value = BASE64.iter().position(|&b| b == c)? as u32;
+                n |= value << (18 - 6 * i);
+            }
+            out.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+        }
+        Some(out)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
 pub struct Output {
+    #[serde(with = "byte_strings")]
     pub path: Vec<u8>,
+    #[serde(with = "byte_strings")]
     pub hash_algorithm: Option<Vec<u8>>,
+    #[serde(with = "byte_strings")]
     pub hash: Option<Vec<u8>>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Output")
+            .field("path", &Bytes(&self.path))
+            .field("hash_algorithm", &Bytes(&self.hash_algorithm))
+            .field("hash", &Bytes(&self.hash))
+            .finish()
+    }
+}
+
+impl Output {
+    /// Whether the output hash is known up front, which is what lets a
+    /// fixed-output derivation access the network.
+    pub fn is_fixed_output(&self) -> bool {
+        matches!(&self.hash, Some(h) if !h.is_empty() && h.as_slice() != b"impure")
+    }
+
+    /// The output path, unless it is only known once the output is built.
+    pub fn known_path(&self) -> Option<&[u8]> {
+        Some(self.path.as_slice()).filter(|path| !path.is_empty())
+    }
+
+    pub fn kind(&self) -> OutputKind {
+        let has_algorithm = matches!(&self.hash_algorithm, Some(a) if !a.is_empty());
+        if self.hash.as_deref() == Some(b"impure") {
+            OutputKind::Impure
+        } else if self.is_fixed_output() {
+            OutputKind::Fixed
+        } else if self.known_path().is_some() {
+            OutputKind::InputAddressed
+        } else if has_algorithm {
+            OutputKind::Floating
+        } else {
+            OutputKind::Deferred
+        }
+    }
+
+    /// The path, or for an output without one, what it is instead.
+    pub fn shown_path(&self) -> &[u8] {
+        self.known_path()
+            .or_else(|| self.kind().placeholder())
+            .unwrap_or_default()
+    }
+}
+
+/// How an output's path is determined. Outputs with `ca-derivations` and
+/// `impure-derivations` have their paths computed once built, so their
+/// .drv files leave the path empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputKind {
+    /// From the derivation and its inputs.
+    InputAddressed,
+    /// From the output hash, which is given up front.
+    Fixed,
+    /// From the contents, hashed with the given method and algorithm.
+    Floating,
+    /// Never the same: the output is built anew each time.
+    Impure,
+    /// From the derivation, once the content-addressed inputs it depends
+    /// on are built.
+    Deferred,
+}
+
+impl OutputKind {
+    /// What is shown instead of the path of an output that has none.
+    pub fn placeholder(self) -> Option<&'static [u8]> {
+        match self {
+            OutputKind::InputAddressed | OutputKind::Fixed => None,
+            OutputKind::Floating => Some(b"(content-addressed, floating)"),
+            OutputKind::Impure => Some(b"(impure)"),
+            OutputKind::Deferred => Some(b"(deferred)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct DerivationDiff {
-    pub original: Derivation,
-    pub new: Derivation,
+    /// The two derivations compared, shared with the parse cache rather
+    /// than copied at every level of the tree.
+    pub original: Arc<Derivation>,
+    pub new: Arc<Derivation>,
     pub outputs: OutputsDiff,
     pub platform: Option<StringDiff>,
     pub builder: Option<StringDiff>,
     pub args: Option<ArgumentsDiff>,
     pub sources: Option<SourcesDiff>,
     pub inputs: Option<InputsDiff>,
+    #[serde(with = "pairs::option")]
     pub env: Option<EnvironmentDiff>,
+    pub name: Option<NameDiff>,
+    /// Sandbox and purity attributes that differ; they are not in `env`.
+    pub sandbox: Option<Vec<SandboxFlagDiff>>,
+    /// What a fixed-output derivation fetches, where that differs; those
+    /// attributes are then not in `env`.
+    #[serde(default)]
+    pub fetch: Option<FetchDiff>,
+    /// The attributes in `__json` that differ, for derivations with
+    /// structured attrs; `__json` is then not in `env`.
+    #[serde(default)]
+    pub structured_attrs: Option<Vec<StructuredAttrDiff>>,
+    /// Env vars that differ but were left out of `env` by `--ignore-env`
+    /// or `--skip-env`.
+    #[serde(default)]
+    pub ignored_env: usize,
+    /// Closure sizes of both sides; only filled in for the top-level diff.
+    pub closure_size: Option<ClosureSizes>,
+    /// How the inputs became the two derivations; only filled in for the
+    /// top-level diff, and only when it is worth showing.
+    pub resolution: Option<Resolution>,
+    /// What to call the two sides in the header, e.g. their .drv paths;
+    /// only filled in for the top-level diff.
+    #[serde(with = "byte_strings")]
+    pub labels: Option<[Vec<u8>; 2]>,
+    /// The outputs `--output-name` restricted the comparison to; only
+    /// filled in for the top-level diff.
+    pub output_names: Option<Vec<String>>,
+    /// The flake inputs pinned differently, when both sides are flakes
+    /// whose locks could be read; only filled in for the top-level diff.
+    pub flake_inputs: Option<Vec<FlakeInputChange>>,
+}
+
+impl DerivationDiff {
+    /// A diff of `original` and `new` with identical outputs and no
+    /// changed sections; the methods below fill them in.
+    pub fn new(original: impl Into<Arc<Derivation>>, new: impl Into<Arc<Derivation>>) -> Self {
+        Self {
+            original: original.into(),
+            new: new.into(),
+            outputs: OutputsDiff::Identical,
+            platform: None,
+            builder: None,
+            args: None,
+            sources: None,
+            inputs: None,
+            env: None,
+            name: None,
+            sandbox: None,
+            fetch: None,
+            structured_attrs: None,
+            ignored_env: 0,
+            closure_size: None,
+            resolution: None,
+            labels: None,
+            output_names: None,
+            flake_inputs: None,
+        }
+    }
+
+    pub fn outputs(mut self, outputs: OutputsDiff) -> Self {
+        self.outputs = outputs;
+        self
+    }
+
+    pub fn platform(mut self, platform: StringDiff) -> Self {
+        self.platform = Some(platform);
+        self
+    }
+
+    pub fn builder(mut self, builder: StringDiff) -> Self {
+        self.builder = Some(builder);
+        self
+    }
+
+    pub fn args(mut self, args: ArgumentsDiff) -> Self {
+        self.args = Some(args);
+        self
+    }
+
+    pub fn sources(mut self, sources: SourcesDiff) -> Self {
+        self.sources = Some(sources);
+        self
+    }
+
+    pub fn inputs(mut self, inputs: InputsDiff) -> Self {
+        self.inputs = Some(inputs);
+        self
+    }
+
+    pub fn env(mut self, env: EnvironmentDiff) -> Self {
+        self.env = Some(env);
+        self
+    }
+
+    pub fn name(mut self, name: NameDiff) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    pub fn sandbox(mut self, flags: Vec<SandboxFlagDiff>) -> Self {
+        self.sandbox = Some(flags);
+        self
+    }
+
+    pub fn fetch(mut self, fetch: FetchDiff) -> Self {
+        self.fetch = Some(fetch);
+        self
+    }
+
+    pub fn structured_attrs(mut self, attrs: Vec<StructuredAttrDiff>) -> Self {
+        self.structured_attrs = Some(attrs);
+        self
+    }
+
+    pub fn closure_size(mut self, closure_size: ClosureSizes) -> Self {
+        self.closure_size = Some(closure_size);
+        self
+    }
+
+    pub fn resolution(mut self, resolution: Resolution) -> Self {
+        self.resolution = Some(resolution);
+        self
+    }
+
+    pub fn flake_inputs(mut self, changes: Vec<FlakeInputChange>) -> Self {
+        self.flake_inputs = Some(changes);
+        self
+    }
+
+    pub fn labels(mut self, old: &[u8], new: &[u8]) -> Self {
+        self.labels = Some([old.to_vec(), new.to_vec()]);
+        self
+    }
+
+    /// Leave only the outputs in `names` in the Outputs section, and drop
+    /// the env vars mirroring the paths of the other outputs. The other
+    /// sections and the input derivations are kept as they are.
+    pub fn only_outputs(mut self, names: &[String]) -> Self {
+        let selected = |name: &[u8]| names.iter().any(|n| n.as_bytes() == name);
+        if let OutputsDiff::Changed(outputs) = &mut self.outputs {
+            outputs.retain(|output| selected(&output.name));
+            if outputs.is_empty() {
+                self.outputs = OutputsDiff::Identical;
+            }
+        }
+        let others: BTreeSet<_> = (self.original.outputs.keys())
+            .chain(self.new.outputs.keys())
+            .filter(|name| !selected(name))
+            .cloned()
+            .collect();
+        if let Some(env) = &mut self.env {
+            env.retain(|key, _| !others.contains(key));
+            if env.is_empty() {
+                self.env = None;
+            }
+        }
+        self.output_names = Some(names.to_vec());
+        self
+    }
+}
+
+/// An attribute in `__json` that differs, from
+/// [`crate::structured_attrs::diff_structured_attrs`]. Values are strings
+/// as they are and anything else as compact JSON, so that `change` is
+/// added, removed or changed like an env var.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct StructuredAttrDiff {
+    /// Where the attribute is, like `env.NIX_CFLAGS_COMPILE` or
+    /// `outputs[1]`.
+    pub path: String,
+    pub change: EnvVarDiff,
+}
+
+/// One of the env attributes in [`crate::diff::SANDBOX_FLAGS`], on each
+/// side; `None` where it is not set.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SandboxFlagDiff {
+    #[serde(with = "byte_strings")]
+    pub key: Vec<u8>,
+    #[serde(with = "byte_strings")]
+    pub old: Option<Vec<u8>>,
+    #[serde(with = "byte_strings")]
+    pub new: Option<Vec<u8>>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl SandboxFlagDiff {
+    /// The old and new value for display. Nix writes `true` as `1` and
+    /// `false` as the empty string; a flag that is not set is `unset`.
+    pub fn shown_values(&self) -> [&[u8]; 2] {
+        let boolean = self.key != b"__sandboxProfile";
+        [&self.old, &self.new].map(|value| match value.as_deref() {
+            None => b"unset".as_slice(),
+            Some(b"1") if boolean => b"true",
+            Some(b"") if boolean => b"false",
+            Some(b"") => b"\"\"",
+            Some(value) => value,
+        })
+    }
+}
+
+/// The attributes in [`crate::diff::FETCH_ATTRS`] that differ between two
+/// derivations of which at least one is fixed-output.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FetchDiff {
+    pub attrs: Vec<FetchAttrDiff>,
+    /// Where it is fetched from (`url`, `urls` or `rev`) changed but the
+    /// output hash did not, which usually means the hash was not updated
+    /// and the old source is still what gets built.
+    pub stale_hash: bool,
+}
+
+/// One of the env attributes in [`crate::diff::FETCH_ATTRS`], on each
+/// side; `None` where it is not set.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FetchAttrDiff {
+    #[serde(with = "byte_strings")]
+    pub key: Vec<u8>,
+    #[serde(with = "byte_strings")]
+    pub old: Option<Vec<u8>>,
+    #[serde(with = "byte_strings")]
+    pub new: Option<Vec<u8>>,
+    /// Only how the hash is written changed, not its digest: `outputHash`
+    /// in another encoding, or `outputHashAlgo` now left to an SRI hash.
+    /// Shown, but not counted as a change.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub same_digest: bool,
+}
+
+/// The `name` env var compared as package name and version. Both come
+/// from `builtins.parseDrvName` on `name`, unless `pname`/`version` env
+/// vars are set.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct NameDiff {
+    pub pname: Option<StringDiff>,
+    pub version: Option<VersionDiff>,
+    /// `name` is just `pname-version` on both sides, so its env entry
+    /// says nothing the comparison doesn't.
+    pub covers_name: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct VersionDiff {
+    #[serde(with = "byte_strings")]
+    pub old: Vec<u8>,
+    #[serde(with = "byte_strings")]
+    pub new: Vec<u8>,
+    /// The new version sorts lower under `builtins.compareVersions`.
+    pub downgrade: bool,
+}
+
+/// Number of derivations in each side's closure, for context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ClosureSizes {
+    pub old: usize,
+    pub new: usize,
+}
+
+/// The steps from each input to the derivation compared, e.g. a store
+/// path, its deriver and the output it is.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct Resolution {
+    pub old: Vec<String>,
+    pub new: Vec<String>,
+    /// The inputs were of different kinds and the derivations have no
+    /// output in common, so the comparison is likely not the intended one.
+    pub mismatched: bool,
+}
+
+/// A direct input of a flake as its `flake.lock` pins it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LockedInput {
+    /// Where it is fetched from, e.g. `github:NixOS/nixpkgs`.
+    pub source: String,
+    pub rev: Option<String>,
+    pub nar_hash: Option<String>,
+    /// When the pinned revision was made, in seconds since the epoch.
+    pub last_modified: Option<u64>,
+}
+
+/// A direct flake input pinned differently on the two sides, or present
+/// on only one of them.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FlakeInputChange {
+    pub name: String,
+    pub old: Option<LockedInput>,
+    pub new: Option<LockedInput>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
 pub enum OutputsDiff {
+    #[default]
     Identical,
     /// The derivation pair was already compared earlier in the tree
     /// (cycle detection). Rendered as "(already compared above)".
@@ -82,13 +849,43 @@ pub enum OutputsDiff {
     Changed(Vec<OutputDiff>),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl OutputsDiff {
+    /// The changed outputs in the order they are listed, see
+    /// [`compare_output_names`].
+    pub fn listed(&self) -> Vec<&OutputDiff> {
+        let mut diffs: Vec<_> = match self {
+            OutputsDiff::Changed(diffs) => diffs.iter().collect(),
+            _ => Vec::new(),
+        };
+        diffs.sort_by(|a, b| compare_output_names(&a.name, &b.name));
+        diffs
+    }
+}
+
+/// Outputs that are listed first, in this order. Most derivations have
+/// `out`, and split packages follow this convention for the rest.
+const LEADING_OUTPUTS: &[&[u8]] = &[b"out", b"bin", b"dev", b"lib", b"man", b"doc"];
+
+/// The order outputs are listed in: `out`, `bin`, `dev`, `lib`, `man` and
+/// `doc` first, then the rest by name.
+pub fn compare_output_names(a: &[u8], b: &[u8]) -> Ordering {
+    let rank = |name: &[u8]| {
+        LEADING_OUTPUTS
+            .iter()
+            .position(|leading| *leading == name)
+            .unwrap_or(LEADING_OUTPUTS.len())
+    };
+    rank(a).cmp(&rank(b)).then_with(|| a.cmp(b))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct OutputDiff {
+    #[serde(with = "byte_strings")]
     pub name: Vec<u8>,
     pub diff: OutputDetailDiff,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum OutputDetailDiff {
     Added(Output),
     Removed(Output),
@@ -98,75 +895,1012 @@ pub enum OutputDetailDiff {
         path: Option<StringDiff>,
         hash_algo: Option<StringDiff>,
         hash: Option<StringDiff>,
+        fixed_output: Option<FixedOutputTransition>,
     },
 }
 
+/// A switch between a fixed-output derivation (network access, content
+/// pinned by hash) and a regular sandboxed one. The raw hash fields only
+/// show a string appearing or vanishing, which undersells the change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FixedOutputTransition {
+    BecameFixedOutput,
+    NoLongerFixedOutput,
+}
+
 pub type ArgumentsDiff = Vec<ArgumentDiff>;
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct ArgumentDiff {
-    pub index: usize,
-    pub diff: StringDiff,
+/// An argument that differs, with its positions (from 0) in the old and
+/// new argument lists. The lists are aligned as sequences, so an inserted
+/// flag doesn't shift the arguments after it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ArgumentDiff {
+    Added {
+        index: usize,
+        #[serde(with = "byte_strings")]
+        value: Vec<u8>,
+    },
+    Removed {
+        index: usize,
+        #[serde(with = "byte_strings")]
+        value: Vec<u8>,
+    },
+    Changed {
+        old_index: usize,
+        new_index: usize,
+        diff: StringDiff,
+        /// The two sides are equal once store path hashes are masked, as
+        /// when a `-c` script only references rebuilt dependencies.
+        store_paths_only: bool,
+    },
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
 pub struct SourcesDiff {
+    #[serde(with = "byte_strings")]
     pub added: BTreeSet<Vec<u8>>,
+    #[serde(with = "byte_strings")]
     pub removed: BTreeSet<Vec<u8>>,
     pub common: Vec<SourceDiff>,
+    /// What the added and removed sources are on disk, for those that
+    /// could be looked at.
+    #[serde(default, with = "pairs")]
+    pub kinds: BTreeMap<Vec<u8>, SourceKind>,
+}
+
+/// An added or removed source: a single file, or a directory such as a
+/// vendored tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SourceKind {
+    File {
+        size: u64,
+    },
+    /// `files` counts the files and symlinks below the directory, `size`
+    /// their sizes. With `truncated`, counting stopped early and both are
+    /// lower bounds.
+    Dir {
+        files: u64,
+        size: u64,
+        truncated: bool,
+    },
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct SourceDiff {
+    #[serde(with = "byte_strings")]
     pub path: Vec<u8>,
     pub diff: TextDiff,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
 pub struct InputsDiff {
     pub added: BTreeSet<DerivationPath>,
     pub removed: BTreeSet<DerivationPath>,
     pub changed: Vec<InputDiff>,
+    /// Changed inputs left unexpanded by [`DerivationDiff::prune`]; they
+    /// still count towards the summary. Only text output prunes, so JSON
+    /// leaves it out.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pruned: Vec<InputDiff>,
+    /// Changed inputs that were only rebuilt because something they
+    /// depend on changed, counted rather than shown; see
+    /// [`DerivationDiff::collapse_rebuilds`]. Only text output collapses
+    /// them, so JSON leaves it out.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rebuilt: Vec<InputDiff>,
+    /// Changed inputs that were not compared because the diff had loaded
+    /// as many derivations as [`crate::diff::DiffContext::max_derivations`]
+    /// allows. They have no `derivation`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub not_compared: Vec<InputDiff>,
+    /// Set when inputs are in `not_compared` because the comparison ran
+    /// past [`crate::diff::DiffContext::deadline`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub timed_out: bool,
+    /// Set when the inputs in `changed` were not compared because they
+    /// are deeper than [`crate::diff::DiffContext::max_depth`] allows;
+    /// they are listed without a `derivation`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub depth_limited: bool,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl InputsDiff {
+    /// Every changed input, whether shown, pruned, collapsed or not
+    /// compared.
+    pub fn all_changed(&self) -> impl Iterator<Item = &InputDiff> {
+        self.changed
+            .iter()
+            .chain(&self.pruned)
+            .chain(&self.rebuilt)
+            .chain(&self.not_compared)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct InputDiff {
+    #[serde(with = "byte_strings")]
     pub path: Vec<u8>,
     pub outputs: Option<OutputSetDiff>,
     pub derivation: Option<Box<DerivationDiff>>,
+    /// Set when only one side's .drv could be read (e.g. the other was
+    /// garbage-collected). `derivation` then compares the readable side
+    /// against an empty derivation.
+    pub unavailable: Option<Side>,
+    /// Set when this input was paired with another version of the same
+    /// package; `path` is then the new name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<VersionDiff>,
+}
+
+impl InputDiff {
+    /// Whether this input changed only because of changes upstream, see
+    /// [`DerivationDiff::is_rebuild_only`].
+    pub fn is_rebuild_only(&self) -> bool {
+        self.rebuild_only_if(DerivationDiff::is_rebuild_only)
+    }
+
+    /// [`is_rebuild_only`](Self::is_rebuild_only), given which diffs of
+    /// input derivations are.
+    fn rebuild_only_if(&self, rebuild_only: impl FnOnce(&DerivationDiff) -> bool) -> bool {
+        self.outputs.is_none()
+            && self.version.is_none()
+            && self.derivation.as_deref().is_some_and(rebuild_only)
+    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl Drop for InputDiff {
+    fn drop(&mut self) {
+        // Dropped as is, a chain of changed inputs thousands deep takes a
+        // few stack frames per level; take the diffs below apart here.
+        let mut below: Vec<_> = self.derivation.take().into_iter().collect();
+        while let Some(mut diff) = below.pop() {
+            let Some(inputs) = &mut diff.inputs else {
+                continue;
+            };
+            for input in inputs
+                .changed
+                .iter_mut()
+                .chain(&mut inputs.pruned)
+                .chain(&mut inputs.rebuilt)
+                .chain(&mut inputs.not_compared)
+            {
+                below.extend(input.derivation.take());
+            }
+        }
+    }
+}
+
+/// An input derivation that differs somewhere in a diff's tree, by
+/// package name; see [`DerivationDiff::input_changes`]. Sorts by name.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct InputChange {
+    pub name: Vec<u8>,
+    /// The version before, `None` if the input was added.
+    pub old: Option<Vec<u8>>,
+    /// The version after, `None` if the input was removed. Equal to `old`
+    /// when the derivation changed but its version did not.
+    pub new: Option<Vec<u8>>,
+}
+
+impl InputChange {
+    fn of(path: &[u8], old: bool, new: bool) -> Self {
+        let (name, version) = crate::version::parse_drv_path(path);
+        InputChange {
+            name: name.to_vec(),
+            old: old.then(|| version.to_vec()),
+            new: new.then(|| version.to_vec()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Side {
+    Old,
+    New,
+}
+
+/// How the outputs a derivation uses of one of its inputs changed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
 pub struct OutputSetDiff {
+    #[serde(with = "byte_strings")]
     pub added: BTreeSet<Vec<u8>>,
+    #[serde(with = "byte_strings")]
     pub removed: BTreeSet<Vec<u8>>,
+    /// The outputs used on both sides.
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeSet::is_empty",
+        with = "byte_strings"
+    )]
+    pub kept: BTreeSet<Vec<u8>>,
+}
+
+impl OutputSetDiff {
+    /// The change from using outputs `old` to using `new`, if any.
+    pub fn between(old: &BTreeSet<Vec<u8>>, new: &BTreeSet<Vec<u8>>) -> Option<Self> {
+        let diff = OutputSetDiff {
+            added: new - old,
+            removed: old - new,
+            kept: old & new,
+        };
+        (!diff.added.is_empty() || !diff.removed.is_empty()).then_some(diff)
+    }
 }
 
 pub type EnvironmentDiff = BTreeMap<Vec<u8>, Option<EnvVarDiff>>;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum EnvVarDiff {
-    Added(Vec<u8>),
-    Removed(Vec<u8>),
+    Added(#[serde(with = "byte_strings")] Vec<u8>),
+    Removed(#[serde(with = "byte_strings")] Vec<u8>),
     Changed(StringDiff),
+    /// A reference restriction (`allowedRequisites` and friends) compared
+    /// entry by entry.
+    Requisites(RequisitesDiff),
+    /// A reference graph or registration blob compared as a set of paths.
+    ClosureInfo(ClosureInfoDiff),
+    /// A list of store paths such as `buildInputs` compared path by path.
+    StorePaths(StorePathsDiff),
+    /// Unset under another name that had the same value; keyed by the new
+    /// name.
+    Renamed(RenamedVar),
+}
+
+/// An env var that moved to a new name with its value unchanged, or
+/// changed only in store path hashes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct RenamedVar {
+    /// The old name.
+    #[serde(with = "byte_strings")]
+    pub from: Vec<u8>,
+    #[serde(with = "byte_strings")]
+    pub old: Vec<u8>,
+    #[serde(with = "byte_strings")]
+    pub new: Vec<u8>,
+}
+
+/// Store paths listed in a closure/registration blob (what
+/// `exportReferencesGraph` and `closureInfo` produce), compared as sets.
+/// Such blobs run to thousands of lines, of which only membership matters.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct ClosureInfoDiff {
+    /// Raw values; `None` when an env var is unset on that side.
+    #[serde(with = "byte_strings")]
+    pub old: Option<Vec<u8>>,
+    #[serde(with = "byte_strings")]
+    pub new: Option<Vec<u8>>,
+    /// Paths only on the new side, sorted.
+    #[serde(with = "byte_strings")]
+    pub added: Vec<Vec<u8>>,
+    /// Paths only on the old side, sorted.
+    #[serde(with = "byte_strings")]
+    pub removed: Vec<Vec<u8>>,
+}
+
+/// A value made up only of whitespace-separated store paths, compared as
+/// a set. Paths that differ only in their hash are paired by name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct StorePathsDiff {
+    /// Raw values.
+    #[serde(with = "byte_strings")]
+    pub old: Vec<u8>,
+    #[serde(with = "byte_strings")]
+    pub new: Vec<u8>,
+    /// Paths only on the new side, sorted.
+    #[serde(with = "byte_strings")]
+    pub added: Vec<Vec<u8>>,
+    /// Paths only on the old side, sorted.
+    #[serde(with = "byte_strings")]
+    pub removed: Vec<Vec<u8>>,
+    /// Paths whose hash changed, old and new, sorted.
+    pub rebuilt: Vec<StringDiff>,
+}
+
+/// Entry-wise diff of a whitespace-separated reference restriction list.
+/// These are security-relevant, so each entry records whether it is part
+/// of the respective side's build-time closure.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct RequisitesDiff {
+    /// Raw values; `None` when the variable is unset on that side.
+    #[serde(with = "byte_strings")]
+    pub old: Option<Vec<u8>>,
+    #[serde(with = "byte_strings")]
+    pub new: Option<Vec<u8>>,
+    /// Entries only in the new value, checked against the new closure.
+    pub added: Vec<Requisite>,
+    /// Entries only in the old value, checked against the old closure.
+    pub removed: Vec<Requisite>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Requisite {
+    #[serde(with = "byte_strings")]
+    pub entry: Vec<u8>,
+    /// `None` for entries that are not store paths, such as output names.
+    pub in_closure: Option<bool>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
 pub struct StringDiff {
+    #[serde(with = "byte_strings")]
     pub old: Vec<u8>,
+    #[serde(with = "byte_strings")]
     pub new: Vec<u8>,
 }
 
+impl fmt::Debug for StringDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StringDiff")
+            .field("old", &Bytes(&self.old))
+            .field("new", &Bytes(&self.new))
+            .finish()
+    }
+}
+
 /// A textual diff. We store the raw old/new content rather than a
 /// pre-computed list of lines so the renderer can choose how to present
 /// it (plain line diff or delta-style inline word highlighting).
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum TextDiff {
     Binary,
-    Text { old: Vec<u8>, new: Vec<u8> },
+    Text {
+        #[serde(with = "byte_strings")]
+        old: Vec<u8>,
+        #[serde(with = "byte_strings")]
+        new: Vec<u8>,
+        /// See [`similarity`].
+        similarity: u8,
+    },
+    /// The store paths differ but the contents were not read, e.g. because
+    /// the report doesn't show sources.
+    NotCompared,
+    /// The files list store paths, registration-info style.
+    ClosureInfo(ClosureInfoDiff),
+}
+
+/// Kinds of changes that `--fail-on` can gate the exit status on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ChangeCategory {
+    FixedOutputTransition,
+    /// A sandbox or purity attribute was set, unset or changed.
+    Sandbox,
+    /// The builder switched shells or shell options, like `bash -e` to
+    /// `dash`; see [`crate::shell`].
+    ShellChange,
+}
+
+impl std::str::FromStr for ChangeCategory {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed-output-transition" => Ok(ChangeCategory::FixedOutputTransition),
+            "sandbox" => Ok(ChangeCategory::Sandbox),
+            "shell-change" => Ok(ChangeCategory::ShellChange),
+            _ => Err(format!(
+                "Invalid category: {s} (expected fixed-output-transition, sandbox or \
+                 shell-change)"
+            )),
+        }
+    }
+}
+
+/// A section of the text output, for `--section`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DiffSection {
+    Sandbox,
+    Fetch,
+    Name,
+    Outputs,
+    Platform,
+    Builder,
+    Args,
+    Sources,
+    Inputs,
+    Attrs,
+    Env,
+}
+
+impl DiffSection {
+    /// Whether the section needs source contents or input derivations
+    /// beyond the top level, which the others can skip reading.
+    pub fn reads_sources(self) -> bool {
+        matches!(self, DiffSection::Sources | DiffSection::Inputs)
+    }
+}
+
+impl std::str::FromStr for DiffSection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sandbox" => Ok(DiffSection::Sandbox),
+            "fetch" => Ok(DiffSection::Fetch),
+            "name" => Ok(DiffSection::Name),
+            "outputs" => Ok(DiffSection::Outputs),
+            "platform" => Ok(DiffSection::Platform),
+            "builder" => Ok(DiffSection::Builder),
+            "args" => Ok(DiffSection::Args),
+            "sources" => Ok(DiffSection::Sources),
+            "inputs" => Ok(DiffSection::Inputs),
+            "attrs" => Ok(DiffSection::Attrs),
+            "env" => Ok(DiffSection::Env),
+            _ => Err(format!(
+                "Invalid section: {s} (expected name, outputs, platform, builder, args, \
+                 sources, inputs, attrs, env, sandbox or fetch)"
+            )),
+        }
+    }
+}
+
+/// Which sections of the text output are printed, for `--only` and
+/// `--skip`. Applies at every level of the tree; the summary and whether
+/// the derivations differ still count every section.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SectionFilter {
+    #[default]
+    All,
+    Only(Vec<DiffSection>),
+    Skip(Vec<DiffSection>),
+}
+
+impl SectionFilter {
+    /// Print `section` as well, for `--only`.
+    pub fn only(&mut self, section: DiffSection) -> Result<(), String> {
+        match self {
+            SectionFilter::All => *self = SectionFilter::Only(vec![section]),
+            SectionFilter::Only(sections) => sections.push(section),
+            SectionFilter::Skip(_) => return Err(Self::conflict()),
+        }
+        Ok(())
+    }
+
+    /// Leave `section` out as well, for `--skip`.
+    pub fn skip(&mut self, section: DiffSection) -> Result<(), String> {
+        match self {
+            SectionFilter::All => *self = SectionFilter::Skip(vec![section]),
+            SectionFilter::Skip(sections) => sections.push(section),
+            SectionFilter::Only(_) => return Err(Self::conflict()),
+        }
+        Ok(())
+    }
+
+    fn conflict() -> String {
+        "--only and --skip can't be combined: list the sections to print with --only, \
+         or those to leave out with --skip"
+            .to_string()
+    }
+
+    /// Whether `section` is printed.
+    pub fn shows(&self, section: DiffSection) -> bool {
+        match self {
+            SectionFilter::All => true,
+            SectionFilter::Only(sections) => sections.contains(&section),
+            SectionFilter::Skip(sections) => !sections.contains(&section),
+        }
+    }
+
+    /// Whether a printed section needs source contents or input
+    /// derivations beyond the top level.
+    pub fn reads_sources(&self) -> bool {
+        [DiffSection::Sources, DiffSection::Inputs]
+            .into_iter()
+            .any(|section| self.shows(section))
+    }
+}
+
+impl DerivationDiff {
+    /// A copy of this diff with `section` as the only change.
+    pub fn only_section(&self, section: DiffSection) -> DerivationDiff {
+        let mut only = DerivationDiff::new(self.original.clone(), self.new.clone());
+        match section {
+            DiffSection::Sandbox => only.sandbox = self.sandbox.clone(),
+            DiffSection::Fetch => only.fetch = self.fetch.clone(),
+            DiffSection::Name => only.name = self.name.clone(),
+            DiffSection::Outputs => only.outputs = self.outputs.clone(),
+            DiffSection::Platform => only.platform = self.platform.clone(),
+            DiffSection::Builder => only.builder = self.builder.clone(),
+            DiffSection::Args => only.args = self.args.clone(),
+            DiffSection::Sources => only.sources = self.sources.clone(),
+            DiffSection::Inputs => only.inputs = self.inputs.clone(),
+            DiffSection::Attrs => only.structured_attrs = self.structured_attrs.clone(),
+            DiffSection::Env => {
+                only.env = self.env.clone();
+                only.ignored_env = self.ignored_env;
+            }
+        }
+        only
+    }
+
+    /// The changed derivations in this diff's tree, itself included.
+    /// Inputs compared elsewhere in the tree count there.
+    pub fn changed_derivations(&self) -> usize {
+        let mut count = 0;
+        self.walk(|_, _| count += 1);
+        count
+    }
+
+    /// Call `visit` on this diff and every input diff below it, parents
+    /// before their inputs, with the names of the inputs leading to it;
+    /// the path is empty for this one. Inputs compared elsewhere in the
+    /// tree are visited there.
+    ///
+    /// ```
+    /// # use nix_diff::{DerivationDiff, Derivation};
+    /// # let diff = DerivationDiff::new(Derivation::default(), Derivation::default());
+    /// let mut depths = Vec::new();
+    /// diff.walk(|path, _| depths.push(path.len()));
+    /// assert_eq!(depths, [0]);
+    /// ```
+    pub fn walk<'a>(&'a self, mut visit: impl FnMut(&[&'a [u8]], &'a DerivationDiff)) {
+        let compared = |drv: &&DerivationDiff| !matches!(drv.outputs, OutputsDiff::AlreadyCompared);
+        if !compared(&self) {
+            return;
+        }
+        // Depth first from a stack of the inputs left at each level, not
+        // by recursion: chains of changed inputs can be thousands deep.
+        let mut path = Vec::new();
+        visit(&path, self);
+        let mut levels = vec![self.inputs.iter().flat_map(InputsDiff::all_changed)];
+        while let Some(inputs) = levels.last_mut() {
+            let Some(input) = inputs.next() else {
+                levels.pop();
+                path.pop();
+                continue;
+            };
+            if let Some(drv) = input.derivation.as_deref().filter(compared) {
+                path.push(input.path.as_slice());
+                visit(&path, drv);
+                levels.push(drv.inputs.iter().flat_map(InputsDiff::all_changed));
+            }
+        }
+    }
+
+    /// The input derivations added, removed or changed anywhere in this
+    /// diff's tree, each listed once however many derivations share it.
+    pub fn input_changes(&self) -> BTreeSet<InputChange> {
+        let mut changes = BTreeSet::new();
+        self.walk(|_, drv| {
+            let Some(inputs) = &drv.inputs else {
+                return;
+            };
+            for path in &inputs.added {
+                changes.insert(InputChange::of(&path.0, false, true));
+            }
+            for path in &inputs.removed {
+                changes.insert(InputChange::of(&path.0, true, false));
+            }
+            for input in inputs.all_changed() {
+                let mut change = InputChange::of(&input.path, true, true);
+                if let Some(version) = &input.version {
+                    change.old = Some(version.old.clone());
+                    change.new = Some(version.new.clone());
+                }
+                changes.insert(change);
+            }
+        });
+        changes
+    }
+
+    /// Expand at most `budget` changed derivations, this one included.
+    /// Changed inputs are expanded in order, each with what is left of the
+    /// budget; once it is spent, the remaining changed inputs of each
+    /// derivation move to [`InputsDiff::pruned`].
+    pub fn prune(mut self, budget: usize) -> Self {
+        self.prune_within(budget.max(1));
+        self
+    }
+
+    /// [`prune`](Self::prune), returning the derivations kept.
+    fn prune_within(&mut self, budget: usize) -> usize {
+        let mut used = 1;
+        let Some(inputs) = &mut self.inputs else {
+            return used;
+        };
+        for mut input in std::mem::take(&mut inputs.changed) {
+            // Inputs without a derivation diff of their own cost nothing.
+            let Some(drv) = input
+                .derivation
+                .as_deref_mut()
+                .filter(|drv| drv.changed_derivations() > 0)
+            else {
+                inputs.changed.push(input);
+                continue;
+            };
+            if used < budget {
+                used += drv.prune_within(budget - used);
+                inputs.changed.push(input);
+            } else {
+                inputs.pruned.push(input);
+            }
+        }
+        used
+    }
+
+    /// Move the changed inputs that were only rebuilt because of changes
+    /// upstream (see [`is_rebuild_only`](Self::is_rebuild_only)) to
+    /// [`InputsDiff::rebuilt`], at every level.
+    pub fn collapse_rebuilds(mut self) -> Self {
+        self.collapse_within();
+        self
+    }
+
+    fn collapse_within(&mut self) {
+        let rebuild_only = self.rebuild_only_diffs();
+        let is_rebuild =
+            |input: &InputDiff| input.rebuild_only_if(|d| rebuild_only.contains(&ptr::from_ref(d)));
+        let mut below = vec![self];
+        while let Some(diff) = below.pop() {
+            let Some(inputs) = &mut diff.inputs else {
+                continue;
+            };
+            let (rebuilt, changed): (Vec<_>, Vec<_>) = std::mem::take(&mut inputs.changed)
+                .into_iter()
+                .partition(&is_rebuild);
+            inputs.changed = changed;
+            inputs.rebuilt.extend(rebuilt);
+            below.extend(
+                inputs
+                    .changed
+                    .iter_mut()
+                    .filter_map(|input| input.derivation.as_deref_mut()),
+            );
+        }
+    }
+
+    /// Whether this derivation differs only because inputs it depends on
+    /// were rebuilt: nothing but store path hashes changed here, and every
+    /// changed input was compared elsewhere in the tree or is such a
+    /// rebuild itself. Whatever caused it is shown where it was compared.
+    pub fn is_rebuild_only(&self) -> bool {
+        self.rebuild_only_diffs().contains(&ptr::from_ref(self))
+    }
+
+    /// The diffs in this tree that are [rebuilds only](Self::is_rebuild_only),
+    /// by address. Worked out from the bottom up rather than by recursion,
+    /// as chains of changed inputs can be thousands deep.
+    fn rebuild_only_diffs(&self) -> HashSet<*const DerivationDiff> {
+        // Parents before their inputs.
+        let mut order = Vec::new();
+        let mut below = vec![self];
+        while let Some(diff) = below.pop() {
+            order.push(diff);
+            let inputs = diff.inputs.iter().flat_map(InputsDiff::all_changed);
+            below.extend(inputs.filter_map(|input| input.derivation.as_deref()));
+        }
+        let mut rebuild_only = HashSet::new();
+        for diff in order.into_iter().rev() {
+            if diff.rebuild_only_here(|d| rebuild_only.contains(&ptr::from_ref(d))) {
+                rebuild_only.insert(ptr::from_ref(diff));
+            }
+        }
+        rebuild_only
+    }
+
+    /// [`is_rebuild_only`](Self::is_rebuild_only), given which diffs of
+    /// input derivations are.
+    fn rebuild_only_here(&self, rebuild_only: impl Fn(&DerivationDiff) -> bool) -> bool {
+        let outputs = match &self.outputs {
+            OutputsDiff::Identical => true,
+            OutputsDiff::Changed(diffs) => diffs.iter().all(|d| {
+                matches!(
+                    d.diff,
+                    OutputDetailDiff::Changed {
+                        hash_algo: None,
+                        hash: None,
+                        ..
+                    }
+                )
+            }),
+            OutputsDiff::AlreadyCompared => false,
+        };
+        let inputs = self.inputs.as_ref().is_none_or(|inputs| {
+            inputs.added.is_empty()
+                && inputs.removed.is_empty()
+                && inputs.all_changed().all(|input| {
+                    let already = input
+                        .derivation
+                        .as_deref()
+                        .is_some_and(|d| matches!(d.outputs, OutputsDiff::AlreadyCompared));
+                    (already && input.outputs.is_none() && input.version.is_none())
+                        || input.rebuild_only_if(&rebuild_only)
+                })
+        });
+        let builder = self.builder.as_ref().is_none_or(|builder| {
+            normalize_store_paths(&builder.old) == normalize_store_paths(&builder.new)
+        });
+        let env = self
+            .env
+            .iter()
+            .flatten()
+            .all(|(_, change)| change.as_ref().is_none_or(EnvVarDiff::store_paths_only))
+            && self
+                .structured_attrs
+                .iter()
+                .flatten()
+                .all(|attr| attr.change.store_paths_only());
+        self.has_changes()
+            && outputs
+            && inputs
+            && builder
+            && env
+            && self
+                .args
+                .iter()
+                .flatten()
+                .all(ArgumentDiff::store_paths_only)
+            && self.platform.is_none()
+            && self.sources.is_none()
+            && self.name.is_none()
+            && self.sandbox.is_none()
+            && self.fetch.is_none()
+    }
+
+    /// Whether the derivations differ at all. This is what the exit status
+    /// reports, whatever the rendering leaves out (output paths, vars that
+    /// mirror them).
+    pub fn has_changes(&self) -> bool {
+        !matches!(self.outputs, OutputsDiff::Identical)
+            || self.platform.is_some()
+            || self.builder.is_some()
+            || self.args.is_some()
+            || self.sources.is_some()
+            || self.inputs.is_some()
+            || self.env.is_some()
+            || self.name.is_some()
+            || self.sandbox.is_some()
+            || self.fetch.is_some()
+            || self.structured_attrs.is_some()
+    }
+
+    /// Whether this diff or any nested input diff contains a change of
+    /// the given category.
+    pub fn has_category(&self, category: ChangeCategory) -> bool {
+        let here = match category {
+            ChangeCategory::FixedOutputTransition => match &self.outputs {
+                OutputsDiff::Changed(diffs) => diffs.iter().any(|d| {
+                    matches!(
+                        d.diff,
+                        OutputDetailDiff::Changed {
+                            fixed_output: Some(_),
+                            ..
+                        }
+                    )
+                }),
+                _ => false,
+            },
+            ChangeCategory::Sandbox => self.sandbox.is_some(),
+            ChangeCategory::ShellChange => crate::shell::shell_change(self).is_some(),
+        };
+        let mut inputs = self.inputs.iter().flat_map(InputsDiff::all_changed);
+        here || inputs.any(|i| {
+            i.derivation
+                .as_ref()
+                .is_some_and(|d| d.has_category(category))
+        })
+    }
+}
+
+/// Bytes on the lines that differ between `old` and `new`.
+fn changed_line_bytes(old: &[u8], new: &[u8]) -> usize {
+    similar::TextDiff::from_lines(old, new)
+        .iter_all_changes()
+        .filter(|change| change.tag() != similar::ChangeTag::Equal)
+        .map(|change| change.value().len())
+        .sum()
+}
+
+/// Give up on an exact similarity after this long; the ratio is then
+/// that of a coarser diff.
+const SIMILARITY_DEADLINE: Duration = Duration::from_millis(500);
+
+/// How similar `old` and `new` are in percent: similar's `ratio()` of a
+/// diff by words, rounded down so only identical texts are 100% similar.
+/// Words rather than lines, so that a one-word edit of a one-line file is
+/// not 0% similar.
+pub fn similarity(old: &[u8], new: &[u8]) -> u8 {
+    let ratio = similar::TextDiff::configure()
+        .deadline(Instant::now() + SIMILARITY_DEADLINE)
+        .diff_words(old, new)
+        .ratio();
+    (ratio * 100.0).floor() as u8
+}
+
+impl StringDiff {
+    /// How big the change is: bytes on changed lines, so a one-word edit
+    /// of a long script weighs less than a rewrite.
+    pub fn changed_bytes(&self) -> usize {
+        changed_line_bytes(&self.old, &self.new)
+    }
+
+    /// See [`similarity`].
+    pub fn similarity(&self) -> u8 {
+        similarity(&self.old, &self.new)
+    }
+}
+
+impl ArgumentDiff {
+    /// Positions in the old and new list; `None` on the side the argument
+    /// is missing from.
+    pub fn indices(&self) -> (Option<usize>, Option<usize>) {
+        match self {
+            ArgumentDiff::Added { index, .. } => (None, Some(*index)),
+            ArgumentDiff::Removed { index, .. } => (Some(*index), None),
+            ArgumentDiff::Changed {
+                old_index,
+                new_index,
+                ..
+            } => (Some(*old_index), Some(*new_index)),
+        }
+    }
+
+    pub fn store_paths_only(&self) -> bool {
+        matches!(
+            self,
+            ArgumentDiff::Changed {
+                store_paths_only: true,
+                ..
+            }
+        )
+    }
+
+    pub fn changed_bytes(&self) -> usize {
+        match self {
+            ArgumentDiff::Added { value, .. } | ArgumentDiff::Removed { value, .. } => value.len(),
+            ArgumentDiff::Changed { diff, .. } => diff.changed_bytes(),
+        }
+    }
+}
+
+impl EnvVarDiff {
+    pub fn changed_bytes(&self) -> usize {
+        let total = |entries: &mut dyn Iterator<Item = &Vec<u8>>| entries.map(Vec::len).sum();
+        match self {
+            EnvVarDiff::Added(value) | EnvVarDiff::Removed(value) => value.len(),
+            EnvVarDiff::Changed(diff) => diff.changed_bytes(),
+            EnvVarDiff::Requisites(d) => {
+                total(&mut d.added.iter().chain(&d.removed).map(|r| &r.entry))
+            }
+            EnvVarDiff::ClosureInfo(d) => total(&mut d.added.iter().chain(&d.removed)),
+            EnvVarDiff::StorePaths(d) => total(
+                &mut d
+                    .added
+                    .iter()
+                    .chain(&d.removed)
+                    .chain(d.rebuilt.iter().map(|r| &r.new)),
+            ),
+            EnvVarDiff::Renamed(_) => 0,
+        }
+    }
+
+    /// A value that changed only in the hashes of the store paths in it,
+    /// as happens to every var naming a rebuilt input. Not one whose two
+    /// sides read the same, as `--redact` can leave them.
+    pub fn store_paths_only(&self) -> bool {
+        match self {
+            EnvVarDiff::Changed(diff) => {
+                diff.old != diff.new
+                    && normalize_store_paths(&diff.old) == normalize_store_paths(&diff.new)
+            }
+            EnvVarDiff::StorePaths(diff) => diff.added.is_empty() && diff.removed.is_empty(),
+            _ => false,
+        }
+    }
+}
+
+impl TextDiff {
+    /// A text diff of `old` and `new`, with their similarity.
+    pub fn text(old: Vec<u8>, new: Vec<u8>) -> Self {
+        let similarity = similarity(&old, &new);
+        TextDiff::Text {
+            old,
+            new,
+            similarity,
+        }
+    }
+
+    /// Zero for binary files and contents that weren't compared.
+    pub fn changed_bytes(&self) -> usize {
+        match self {
+            TextDiff::Binary | TextDiff::NotCompared => 0,
+            TextDiff::Text { old, new, .. } => changed_line_bytes(old, new),
+            TextDiff::ClosureInfo(d) => d.added.iter().chain(&d.removed).map(Vec::len).sum(),
+        }
+    }
+}
+
+/// How entries within a section are ordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum EntryOrder {
+    /// By key or position, for output that is stable across runs.
+    #[default]
+    Name,
+    /// Biggest change first.
+    Size,
+}
+
+impl std::str::FromStr for EntryOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(EntryOrder::Name),
+            "size" => Ok(EntryOrder::Size),
+            _ => Err(format!("Invalid entry order: {s} (expected name or size)")),
+        }
+    }
+}
+
+/// What the CLI prints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum OutputFormat {
+    /// The human-readable diff.
+    #[default]
+    Text,
+    /// `export`/`unset` statements reproducing the environment delta.
+    EnvScript,
+    /// Changelog bullets for a commit message.
+    CommitMsg,
+    /// The whole diff tree, for other tools.
+    Json,
+    /// The diff tree as one JSON event per line, for very large diffs.
+    Ndjson,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "env-script" => Ok(OutputFormat::EnvScript),
+            "commit-msg" => Ok(OutputFormat::CommitMsg),
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            _ => Err(format!(
+                "Invalid format: {s} (expected text, env-script, commit-msg, json or ndjson)"
+            )),
+        }
+    }
+}
+
+/// Where the text output puts the summary of change counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum SummaryPosition {
+    /// Right below the header, before the details.
+    #[default]
+    Top,
+    Bottom,
+    Both,
+}
+
+impl SummaryPosition {
+    pub fn top(self) -> bool {
+        matches!(self, SummaryPosition::Top | SummaryPosition::Both)
+    }
+
+    pub fn bottom(self) -> bool {
+        matches!(self, SummaryPosition::Bottom | SummaryPosition::Both)
+    }
+}
+
+impl std::str::FromStr for SummaryPosition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "top" => Ok(SummaryPosition::Top),
+            "bottom" => Ok(SummaryPosition::Bottom),
+            "both" => Ok(SummaryPosition::Both),
+            _ => Err(format!(
+                "Invalid summary position: {s} (expected top, bottom, or both)"
+            )),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 pub enum ColorMode {
     Always,
     #[default]
@@ -174,6 +1908,16 @@ pub enum ColorMode {
     Never,
 }
 
+/// Whether to wrap store paths in OSC 8 terminal hyperlinks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum HyperlinkMode {
+    Always,
+    /// On when stdout is a terminal and `TERM` is set and not `dumb`.
+    #[default]
+    Auto,
+    Never,
+}
+
 /// Options controlling what gets rendered.
 ///
 /// By default we hide changes that are purely mechanical consequences of
@@ -190,10 +1934,47 @@ pub struct RenderOptions {
     pub input_list_limit: usize,
     /// Maximum recursion depth into input derivations. `None` = unlimited.
     pub max_depth: Option<usize>,
+    /// Maximum env vars to show per derivation; past it, the largest
+    /// changes are shown and the rest only named. 0 shows all.
+    pub max_env_entries: usize,
     /// Highlight changed words within changed lines (delta-style).
     /// Automatically disabled when color is off since it relies on
     /// reverse-video escapes.
     pub inline_highlight: bool,
+    /// Texts less similar than this, in percent, are diffed by line even
+    /// with `inline_highlight`: the words of a rewrite have little to do
+    /// with each other.
+    pub inline_threshold: u8,
+    /// Tag every change line with `[added]`/`[removed]`/... and bracket
+    /// changed words, so nothing is conveyed by color alone. Also keeps
+    /// the output ASCII-only.
+    pub accessible: bool,
+    /// Also list env vars that are the same on both sides, as context.
+    pub show_unchanged_env: bool,
+    /// Show env vars that changed only in store path hashes in full,
+    /// instead of naming them on one line.
+    pub show_derived_changes: bool,
+    pub hyperlinks: HyperlinkMode,
+    pub summary_position: SummaryPosition,
+    /// Print the summary line, and the totals of
+    /// [`DiffStats`](crate::summary::DiffStats) after the details.
+    pub summary: bool,
+    /// List every path of a closure-info diff instead of the first
+    /// `input_list_limit`.
+    pub expand_closure_info: bool,
+    /// Print an input's changes only once when several inputs change in
+    /// exactly the same way.
+    pub dedup_diffs: bool,
+    /// Order of changed env vars, sources and arguments.
+    pub sort_entries: EntryOrder,
+    /// Show added and removed sources by name, with their full paths
+    /// listed once below.
+    pub relative_sources: bool,
+    /// Env vars to show in full, as globs; other changed vars get a one-line
+    /// size summary. Empty shows every var in full.
+    pub env_detail: Vec<String>,
+    /// The sections to print.
+    pub sections: SectionFilter,
 }
 
 impl Default for RenderOptions {
@@ -204,7 +1985,676 @@ impl Default for RenderOptions {
             verbose: false,
             input_list_limit: 10,
             max_depth: None,
+            max_env_entries: 500,
             inline_highlight: true,
+            inline_threshold: 50,
+            accessible: false,
+            show_unchanged_env: false,
+            show_derived_changes: false,
+            hyperlinks: HyperlinkMode::Auto,
+            summary_position: SummaryPosition::Top,
+            summary: true,
+            expand_closure_info: false,
+            dedup_diffs: true,
+            sort_entries: EntryOrder::Name,
+            relative_sources: false,
+            env_detail: Vec::new(),
+            sections: SectionFilter::All,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::DeserializeOwned;
+    use std::collections::HashSet;
+    use std::fmt::Debug;
+    use std::hash::Hash;
+
+    fn bytes(s: &str) -> Vec<u8> {
+        s.as_bytes().to_vec()
+    }
+
+    fn string_diff(old: &str, new: &str) -> StringDiff {
+        StringDiff {
+            old: bytes(old),
+            new: bytes(new),
+        }
+    }
+
+    fn output(path: &str) -> Output {
+        Output {
+            path: bytes(path),
+            hash_algorithm: Some(bytes("sha256")),
+            hash: Some(bytes("abc")),
+        }
+    }
+
+    /// Serialize through JSON and back, and check equal values hash alike.
+    fn check<T>(value: T)
+    where
+        T: Clone + Debug + Eq + Hash + Serialize + DeserializeOwned,
+    {
+        let json = serde_json::to_string(&value).unwrap();
+        let back: T = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, value, "{json}");
+        let set = HashSet::from([value.clone(), back]);
+        assert_eq!(set.len(), 1, "{value:?}");
+        assert!(set.contains(&value));
+    }
+
+    /// One diff touching every section, nested once.
+    fn full_diff() -> DerivationDiff {
+        let mut drv = Derivation::default();
+        drv.outputs.insert(bytes("out"), output("/nix/store/a-out"));
+        drv.input_sources.insert(bytes("/nix/store/b-src").into());
+        drv.input_derivations
+            .insert(bytes("/nix/store/c-dep.drv").into(), [bytes("out")].into());
+        drv.env.insert(bytes("name").into(), bytes("foo-1.0"));
+
+        let mut env = EnvironmentDiff::new();
+        env.insert(bytes("a"), Some(EnvVarDiff::Added(bytes("1"))));
+        env.insert(bytes("b"), Some(EnvVarDiff::Removed(bytes("1"))));
+        env.insert(bytes("c"), Some(EnvVarDiff::Changed(string_diff("1", "2"))));
+        env.insert(
+            bytes("disallowedRequisites"),
+            Some(EnvVarDiff::Requisites(RequisitesDiff {
+                old: None,
+                new: Some(bytes("/nix/store/d-gcc")),
+                added: vec![Requisite {
+                    entry: bytes("/nix/store/d-gcc"),
+                    in_closure: Some(true),
+                }],
+                removed: Vec::new(),
+            })),
+        );
+        env.insert(
+            bytes("exportReferencesGraph"),
+            Some(EnvVarDiff::ClosureInfo(ClosureInfoDiff {
+                added: vec![bytes("/nix/store/e-new")],
+                ..Default::default()
+            })),
+        );
+        env.insert(
+            bytes("configureFlagsArray"),
+            Some(EnvVarDiff::Renamed(RenamedVar {
+                from: bytes("configureFlags"),
+                old: bytes("--with-ssl"),
+                new: bytes("--with-ssl"),
+            })),
+        );
+        env.insert(bytes("unchanged"), None);
+
+        let inner = DerivationDiff::new(drv.clone(), Derivation::default())
+            .outputs(OutputsDiff::AlreadyCompared);
+        DerivationDiff::new(Derivation::default(), drv)
+            .outputs(OutputsDiff::Changed(vec![
+                OutputDiff {
+                    name: bytes("dev"),
+                    diff: OutputDetailDiff::Added(output("/nix/store/f-dev")),
+                },
+                OutputDiff {
+                    name: bytes("out"),
+                    diff: OutputDetailDiff::Changed {
+                        old: Output::default(),
+                        new: Box::new(output("/nix/store/a-out")),
+                        path: Some(string_diff("", "/nix/store/a-out")),
+                        hash_algo: None,
+                        hash: Some(string_diff("", "abc")),
+                        fixed_output: Some(FixedOutputTransition::BecameFixedOutput),
+                    },
+                },
+            ]))
+            .platform(string_diff("x86_64-linux", "aarch64-linux"))
+            .builder(string_diff("/bin/sh", "/bin/bash"))
+            .args(vec![ArgumentDiff::Added {
+                index: 0,
+                value: bytes("-e"),
+            }])
+            .sources(SourcesDiff {
+                added: [bytes("/nix/store/g-patch")].into(),
+                removed: BTreeSet::new(),
+                kinds: [(
+                    bytes("/nix/store/g-patch"),
+                    SourceKind::Dir {
+                        files: 2,
+                        size: 10,
+                        truncated: false,
+                    },
+                )]
+                .into(),
+                common: vec![
+                    SourceDiff {
+                        path: bytes("builder.sh"),
+                        diff: TextDiff::text(bytes("a"), bytes("b")),
+                    },
+                    SourceDiff {
+                        path: bytes("logo.png"),
+                        diff: TextDiff::Binary,
+                    },
+                    SourceDiff {
+                        path: bytes("big"),
+                        diff: TextDiff::NotCompared,
+                    },
+                    SourceDiff {
+                        path: bytes("closure"),
+                        diff: TextDiff::ClosureInfo(ClosureInfoDiff::default()),
+                    },
+                ],
+            })
+            .inputs(InputsDiff {
+                added: [DerivationPath(bytes("/nix/store/h-new.drv"))].into(),
+                removed: BTreeSet::new(),
+                changed: vec![InputDiff {
+                    path: bytes("dep.drv"),
+                    outputs: Some(OutputSetDiff {
+                        added: [bytes("lib")].into(),
+                        removed: BTreeSet::new(),
+                        kept: [bytes("out")].into(),
+                    }),
+                    derivation: Some(Box::new(inner)),
+                    unavailable: Some(Side::New),
+                    version: None,
+                }],
+                pruned: Vec::new(),
+                rebuilt: Vec::new(),
+                not_compared: Vec::new(),
+                timed_out: false,
+                depth_limited: false,
+            })
+            .env(env)
+            .name(NameDiff {
+                pname: Some(string_diff("foo", "bar")),
+                version: Some(VersionDiff {
+                    old: bytes("1.0"),
+                    new: bytes("0.9"),
+                    downgrade: true,
+                }),
+                covers_name: true,
+            })
+            .sandbox(vec![SandboxFlagDiff {
+                key: bytes("__noChroot"),
+                old: None,
+                new: Some(bytes("1")),
+            }])
+            .fetch(FetchDiff {
+                attrs: vec![FetchAttrDiff {
+                    key: bytes("url"),
+                    old: Some(bytes("https://example.org/a-1.tar.gz")),
+                    new: Some(bytes("https://example.org/a-2.tar.gz")),
+                    same_digest: false,
+                }],
+                stale_hash: true,
+            })
+            .structured_attrs(vec![StructuredAttrDiff {
+                path: "env.CFLAGS".into(),
+                change: EnvVarDiff::Changed(StringDiff {
+                    old: bytes("-O2"),
+                    new: bytes("-O3"),
+                }),
+            }])
+            .closure_size(ClosureSizes { old: 1, new: 2 })
+            .resolution(Resolution {
+                old: vec![
+                    "/nix/store/h-foo-man (store)".into(),
+                    "output man of h-foo.drv".into(),
+                ],
+                new: vec!["bar.drv (drv)".into()],
+                mismatched: true,
+            })
+            .labels(b"foo.drv", b"bar.drv")
+    }
+
+    #[test]
+    fn builder_fills_only_the_given_sections() {
+        let diff = DerivationDiff::new(Derivation::default(), Derivation::default())
+            .env(EnvironmentDiff::new());
+        assert_eq!(diff.outputs, OutputsDiff::Identical);
+        assert_eq!(diff.env, Some(EnvironmentDiff::new()));
+        assert!(diff.platform.is_none() && diff.inputs.is_none() && diff.closure_size.is_none());
+    }
+
+    #[test]
+    fn byte_strings_debug_print_as_escaped_strings() {
+        let bytes = b"caf\xe9 \"it's\"\n";
+        assert_eq!(format!("{:?}", BStr(bytes)), r#""caf\xe9 \"it's\"\n""#);
+        assert_eq!(BStr(bytes).to_string(), "caf\\xe9 \"it's\"\n");
+        assert_eq!(
+            format!(
+                "{:?}",
+                StringDiff {
+                    old: b"1.0".to_vec(),
+                    new: vec![0xff],
+                }
+            ),
+            r#"StringDiff { old: "1.0", new: "\xff" }"#
+        );
+        let mut drv = Derivation::default();
+        drv.env.insert(b"name".as_slice().into(), b"hello".to_vec());
+        drv.outputs.insert(b"out".to_vec(), Output::default());
+        let debug = format!("{drv:?}");
+        assert!(debug.contains(r#"env: {"name": "hello"}"#), "{debug}");
+        assert!(
+            debug.contains(r#"outputs: {"out": Output { path: "", hash_algorithm: None"#),
+            "{debug}"
+        );
+    }
+
+    #[test]
+    fn every_type_round_trips_and_hashes() {
+        let diff = full_diff();
+        check(diff.clone());
+        check(diff.original.clone());
+        check(diff.new.clone());
+        check(diff.outputs.clone());
+        check(diff.platform.clone().unwrap());
+        check(diff.args.clone().unwrap());
+        check(diff.sources.clone().unwrap());
+        check(diff.inputs.clone().unwrap());
+        check(diff.name.clone().unwrap());
+        check(diff.closure_size.unwrap());
+        check(diff.resolution.clone().unwrap());
+        check(DerivationPath(bytes("/nix/store/h-new.drv")));
+        check(output("/nix/store/a-out"));
+        check(Side::Old);
+        check(FixedOutputTransition::NoLongerFixedOutput);
+        check(ChangeCategory::FixedOutputTransition);
+        for (_, var) in diff.env.unwrap() {
+            check(var);
+        }
+        for source in diff.sources.unwrap().common {
+            check(source.diff);
+        }
+    }
+
+    #[test]
+    fn json_shape_of_every_section() {
+        insta::assert_snapshot!(serde_json::to_string_pretty(&full_diff()).unwrap());
+    }
+
+    #[test]
+    fn non_utf8_byte_strings_keep_their_bytes() {
+        let diff = StringDiff {
+            old: bytes("café"),
+            new: b"caf\xe9".to_vec(),
+        };
+        let json = serde_json::to_string(&diff).unwrap();
+        assert_eq!(
+            json,
+            r#"{"old":"café","new":{"lossy":"caf�","base64":"Y2Fm6Q=="}}"#
+        );
+        check(diff);
+        check(Derivation {
+            env: BTreeMap::from([(ByteStr::from(b"\xff".as_slice()), b"\x00\x80".to_vec())]),
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn base64_round_trips() {
+        use byte_strings::{decode_base64, encode_base64};
+        for (data, text) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(encode_base64(data.as_bytes()), text);
+            assert_eq!(decode_base64(text).unwrap(), data.as_bytes());
+        }
+        let all: Vec<u8> = (0..=255).collect();
+        assert_eq!(decode_base64(&encode_base64(&all)).unwrap(), all);
+        for invalid in ["Zg=", "Z===", "Z!==", "=Zg="] {
+            assert_eq!(decode_base64(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn empty_diffs_are_the_defaults() {
+        assert_eq!(OutputsDiff::default(), OutputsDiff::Identical);
+        assert_eq!(
+            SourcesDiff::default(),
+            SourcesDiff {
+                added: BTreeSet::new(),
+                removed: BTreeSet::new(),
+                common: Vec::new(),
+                kinds: BTreeMap::new(),
+            }
+        );
+        assert!(InputsDiff::default().changed.is_empty());
+        assert!(StringDiff::default().old.is_empty());
+        assert!(!NameDiff::default().covers_name);
+    }
+
+    #[test]
+    fn diffs_work_as_set_members() {
+        let a = full_diff();
+        let b = DerivationDiff::new(Derivation::default(), Derivation::default());
+        let set = HashSet::from([a.clone(), b.clone(), a.clone()]);
+        assert_eq!(set.len(), 2);
+        let sorted: BTreeSet<_> = [
+            DerivationPath(bytes("/nix/store/2-b.drv")),
+            DerivationPath(bytes("/nix/store/1-c.drv")),
+            DerivationPath(bytes("/nix/store/3-a.drv")),
+        ]
+        .into();
+        let names: Vec<_> = sorted.iter().map(|p| p.0.as_slice()).collect();
+        assert_eq!(
+            names,
+            [
+                &b"/nix/store/3-a.drv"[..],
+                b"/nix/store/2-b.drv",
+                b"/nix/store/1-c.drv"
+            ]
+        );
+    }
+    #[test]
+    fn similarity_is_a_percentage_of_shared_words() {
+        let script = "set -e\nconfigure --prefix=$out\nmake -j4\nmake install\n";
+        assert_eq!(similarity(script.as_bytes(), script.as_bytes()), 100);
+        // One word edited: still nearly the same script.
+        let edited = script.replace("-j4", "-j8");
+        let high = similarity(script.as_bytes(), edited.as_bytes());
+        assert!((90..100).contains(&high), "{high}");
+        // A one-line file with one word changed isn't 0% similar.
+        assert!(similarity(b"version = 1.0", b"version = 2.0") >= 60);
+        // A rewrite only shares whitespace.
+        let rewrite = "#!/usr/bin/env python\nimport sys\nsys.exit(main())\n";
+        let low = similarity(script.as_bytes(), rewrite.as_bytes());
+        assert!(low < 50, "{low}");
+        assert_eq!(similarity(b"", b"anything"), 0);
+    }
+
+    #[test]
+    fn has_changes_looks_at_the_sections_only() {
+        let empty = || DerivationDiff::new(Derivation::default(), Derivation::default());
+        // The top-level extras say nothing about the derivations differing.
+        let identical = empty()
+            .labels(b"a.drv", b"b.drv")
+            .closure_size(ClosureSizes { old: 3, new: 3 });
+        assert!(!identical.has_changes());
+        let change = StringDiff {
+            old: bytes("a"),
+            new: bytes("b"),
+        };
+        assert!(empty().platform(change.clone()).has_changes());
+        assert!(empty().outputs(OutputsDiff::AlreadyCompared).has_changes());
+        assert!(full_diff().has_changes());
+    }
+
+    #[test]
+    fn section_filters_compose_but_do_not_mix() {
+        let mut only = SectionFilter::default();
+        assert!(only.shows(DiffSection::Sources) && only.reads_sources());
+        only.only(DiffSection::Env).unwrap();
+        only.only(DiffSection::Inputs).unwrap();
+        assert!(only.shows(DiffSection::Env) && only.shows(DiffSection::Inputs));
+        assert!(!only.shows(DiffSection::Sources) && only.reads_sources());
+        assert!(only.skip(DiffSection::Sources).is_err());
+
+        let mut skip = SectionFilter::default();
+        skip.skip(DiffSection::Sources).unwrap();
+        skip.skip(DiffSection::Inputs).unwrap();
+        assert!(skip.shows(DiffSection::Env) && !skip.reads_sources());
+        assert!(skip.only(DiffSection::Env).is_err());
+    }
+
+    #[test]
+    fn only_outputs_drops_the_other_outputs_and_their_vars() {
+        let mut drv = Derivation::default();
+        for name in ["out", "man"] {
+            drv.outputs
+                .insert(bytes(name), output(&format!("/nix/store/a-{name}")));
+        }
+        let changed = |name: &str| OutputDiff {
+            name: bytes(name),
+            diff: OutputDetailDiff::Removed(output("/nix/store/a")),
+        };
+        let mut env = EnvironmentDiff::new();
+        for key in ["man", "out", "version"] {
+            env.insert(bytes(key), Some(EnvVarDiff::Changed(string_diff("1", "2"))));
+        }
+        let diff = DerivationDiff::new(drv.clone(), drv)
+            .outputs(OutputsDiff::Changed(vec![changed("man"), changed("out")]))
+            .env(env);
+
+        let out = diff.clone().only_outputs(&["out".to_string()]);
+        let names: Vec<_> = out
+            .outputs
+            .listed()
+            .iter()
+            .map(|o| o.name.clone())
+            .collect();
+        assert_eq!(names, [bytes("out")]);
+        let keys: Vec<_> = out.env.iter().flatten().map(|(k, _)| k.clone()).collect();
+        assert_eq!(keys, [bytes("out"), bytes("version")]);
+        assert_eq!(out.output_names, Some(vec!["out".to_string()]));
+
+        // A name that changed nowhere leaves the section empty.
+        let dev = diff.only_outputs(&["dev".to_string()]);
+        assert_eq!(dev.outputs, OutputsDiff::Identical);
+        assert_eq!(dev.env.unwrap().len(), 1);
+    }
+
+    fn listed_names(names: &[&str]) -> Vec<String> {
+        let diffs = names
+            .iter()
+            .map(|name| OutputDiff {
+                name: bytes(name),
+                diff: OutputDetailDiff::Added(output("/nix/store/x")),
+            })
+            .collect();
+        OutputsDiff::Changed(diffs)
+            .listed()
+            .iter()
+            .map(|d| String::from_utf8_lossy(&d.name).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn outputs_are_listed_out_first() {
+        assert_eq!(
+            listed_names(&["dev", "doc", "bin", "man", "lib", "out"]),
+            ["out", "bin", "dev", "lib", "man", "doc"]
+        );
+        // Unconventional names follow, by name.
+        assert_eq!(
+            listed_names(&["static", "debug", "out", "info", "dev"]),
+            ["out", "dev", "debug", "info", "static"]
+        );
+        assert_eq!(listed_names(&["py", "lib"]), ["lib", "py"]);
+        assert!(OutputsDiff::Identical.listed().is_empty());
+    }
+
+    /// A changed derivation with `children` as changed inputs, named
+    /// `<name>.drv`.
+    fn node(children: Vec<(&str, DerivationDiff)>) -> DerivationDiff {
+        let changed = children
+            .into_iter()
+            .map(|(name, child)| InputDiff {
+                path: bytes(&format!("{name}.drv")),
+                outputs: None,
+                derivation: Some(Box::new(child)),
+                unavailable: None,
+                version: None,
+            })
+            .collect();
+        DerivationDiff::new(Derivation::default(), Derivation::default())
+            .platform(string_diff("a", "b"))
+            .inputs(InputsDiff {
+                changed,
+                ..Default::default()
+            })
+    }
+
+    fn leaves(n: usize) -> DerivationDiff {
+        node((0..n).map(|_| ("leaf", node(Vec::new()))).collect())
+    }
+
+    /// Changed derivations still expanded after pruning.
+    fn shown(diff: &DerivationDiff) -> usize {
+        if matches!(diff.outputs, OutputsDiff::AlreadyCompared) {
+            return 0;
+        }
+        let inputs = diff.inputs.iter().flat_map(|i| &i.changed);
+        1 + inputs
+            .filter_map(|i| i.derivation.as_deref())
+            .map(shown)
+            .sum::<usize>()
+    }
+
+    fn names(inputs: &[InputDiff]) -> Vec<String> {
+        inputs
+            .iter()
+            .map(|i| String::from_utf8_lossy(&i.path).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn walk_visits_parents_before_inputs_with_their_path() {
+        let already = DerivationDiff::new(Derivation::default(), Derivation::default())
+            .outputs(OutputsDiff::AlreadyCompared);
+        let tree = node(vec![("a", leaves(1)), ("b", already), ("c", leaves(0))]);
+        let mut visited = Vec::new();
+        tree.walk(|path, _| {
+            let path: Vec<_> = path.iter().map(|p| String::from_utf8_lossy(p)).collect();
+            visited.push(path.join("/"));
+        });
+        assert_eq!(visited, ["", "a.drv", "a.drv/leaf.drv", "c.drv"]);
+    }
+
+    #[test]
+    fn input_changes_are_listed_once_by_name() {
+        let bumped = InputDiff {
+            path: bytes("/nix/store/h-openssl-3.0.14.drv"),
+            outputs: None,
+            derivation: None,
+            unavailable: None,
+            version: Some(VersionDiff {
+                old: bytes("3.0.13"),
+                new: bytes("3.0.14"),
+                downgrade: false,
+            }),
+        };
+        let mut inner = node(vec![("zlib-1.3", leaves(0))]);
+        inner.inputs.as_mut().unwrap().changed.push(bumped);
+        let mut tree = node(vec![("zlib-1.3", leaves(0)), ("curl-8.7.1", inner)]);
+        let inputs = tree.inputs.as_mut().unwrap();
+        inputs
+            .added
+            .insert(DerivationPath(bytes("/nix/store/h-brotli-1.1.0.drv")));
+        inputs
+            .removed
+            .insert(DerivationPath(bytes("/nix/store/h-aaa.drv")));
+
+        let change = |name: &str, old: Option<&str>, new: Option<&str>| InputChange {
+            name: bytes(name),
+            old: old.map(bytes),
+            new: new.map(bytes),
+        };
+        assert_eq!(
+            tree.input_changes().into_iter().collect::<Vec<_>>(),
+            [
+                change("aaa", Some(""), None),
+                change("brotli", None, Some("1.1.0")),
+                change("curl", Some("8.7.1"), Some("8.7.1")),
+                change("openssl", Some("3.0.13"), Some("3.0.14")),
+                change("zlib", Some("1.3"), Some("1.3")),
+            ]
+        );
+    }
+
+    #[test]
+    fn prune_expands_inputs_in_order_within_the_budget() {
+        let already = DerivationDiff::new(Derivation::default(), Derivation::default())
+            .outputs(OutputsDiff::AlreadyCompared);
+        let mut children = vec![("a", leaves(0)), ("b", leaves(2)), ("c", leaves(4))];
+        children.extend(["d", "e", "f", "g"].map(|name| (name, leaves(0))));
+        children.push(("h", already));
+        let wide = node(children);
+        assert_eq!(wide.changed_derivations(), 14);
+        assert_eq!(wide.clone().prune(1000), wide);
+
+        let pruned = wide.clone().prune(6);
+        assert_eq!(shown(&pruned), 6);
+        assert_eq!(pruned.changed_derivations(), 14);
+        let inputs = pruned.inputs.as_ref().unwrap();
+        // Already compared inputs cost nothing and are always kept.
+        assert_eq!(names(&inputs.changed), ["a.drv", "b.drv", "c.drv", "h.drv"]);
+        assert_eq!(names(&inputs.pruned), ["d.drv", "e.drv", "f.drv", "g.drv"]);
+        // c got what was left: itself, without its inputs.
+        let c = inputs.changed[2].derivation.as_ref().unwrap();
+        let c_inputs = c.inputs.as_ref().unwrap();
+        assert!(c_inputs.changed.is_empty());
+        assert_eq!(c_inputs.pruned.len(), 4);
+
+        for budget in [0, 1, 2, 5, 13, 14] {
+            let pruned = wide.clone().prune(budget);
+            assert_eq!(shown(&pruned), budget.clamp(1, 14), "{budget}");
+        }
+    }
+
+    #[test]
+    fn collapse_moves_inputs_that_only_changed_store_paths() {
+        let already = || {
+            DerivationDiff::new(Derivation::default(), Derivation::default())
+                .outputs(OutputsDiff::AlreadyCompared)
+        };
+        // Only the hash of the input it names changed, as does its own.
+        let rebuild = |children| {
+            let mut env = EnvironmentDiff::new();
+            env.insert(
+                bytes("buildInputs"),
+                Some(EnvVarDiff::Changed(string_diff(
+                    "/nix/store/0c9z6vw9aqk8sw6j6d1x2lxnh2wrrxj0-lib",
+                    "/nix/store/1a6kqbnvc04rhs8zmbmmhz3x4zjvnmcc-lib",
+                ))),
+            );
+            DerivationDiff {
+                platform: None,
+                env: Some(env),
+                ..node(children)
+            }
+        };
+        let mut patched = rebuild(vec![("lib", already())]);
+        patched.args = Some(vec![ArgumentDiff::Added {
+            index: 1,
+            value: bytes("--patch"),
+        }]);
+        let top = node(vec![
+            ("a", rebuild(vec![("lib", leaves(0))])),
+            ("b", rebuild(vec![("lib", already())])),
+            (
+                "c",
+                rebuild(vec![("b2", rebuild(vec![("lib", already())]))]),
+            ),
+            ("d", patched),
+        ]);
+        assert!(!top.is_rebuild_only());
+
+        let collapsed = top.clone().collapse_rebuilds();
+        let inputs = collapsed.inputs.as_ref().unwrap();
+        // a compares lib, the cause, first; d has changes of its own.
+        assert_eq!(names(&inputs.changed), ["a.drv", "d.drv"]);
+        assert_eq!(names(&inputs.rebuilt), ["b.drv", "c.drv"]);
+        assert_eq!(collapsed.changed_derivations(), top.changed_derivations());
+    }
+
+    #[test]
+    fn output_order_is_total() {
+        let names: [&[u8]; 6] = [b"out", b"doc", b"a", b"outs", b"", b"z"];
+        for a in names {
+            assert_eq!(compare_output_names(a, a), Ordering::Equal);
+            for b in names {
+                assert_eq!(
+                    compare_output_names(a, b),
+                    compare_output_names(b, a).reverse()
+                );
+            }
         }
+        assert_eq!(compare_output_names(b"doc", b"a"), Ordering::Less);
+        assert_eq!(compare_output_names(b"outs", b"out"), Ordering::Greater);
     }
 }
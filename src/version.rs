@@ -0,0 +1,198 @@
+//! Package names and versions, following Nix's `builtins.parseDrvName`
+//! and `builtins.compareVersions`.
+
+use std::cmp::Ordering;
+
+/// Split a derivation name into package name and version. The version
+/// starts after the first dash that is followed by a non-letter, so
+/// `hello-2.12` gives `("hello", "2.12")` and `hello-v1` has no version.
+pub fn parse_drv_name(name: &[u8]) -> (&[u8], &[u8]) {
+    for i in 0..name.len() {
+        if name[i] == b'-' && name.get(i + 1).is_some_and(|c| !c.is_ascii_alphabetic()) {
+            return (&name[..i], &name[i + 1..]);
+        }
+    }
+    (name, b"")
+}
+
+/// Package name and version of a derivation from its store path, or from
+/// the name after the hash: `/nix/store/<hash>-openssl-3.0.13.drv` gives
+/// `("openssl", "3.0.13")`.
+pub fn parse_drv_path(path: &[u8]) -> (&[u8], &[u8]) {
+    let name = match path.iter().rposition(|&b| b == b'/') {
+        Some(slash) => {
+            let file = &path[slash + 1..];
+            file.iter()
+                .position(|&b| b == b'-')
+                .map_or(file, |dash| &file[dash + 1..])
+        }
+        None => path,
+    };
+    parse_drv_name(name.strip_suffix(b".drv").unwrap_or(name))
+}
+
+/// Next version component after skipping `.`/`-` separators: a run of
+/// digits or a run of other characters. Empty once `v` is exhausted.
+fn next_component<'a>(v: &mut &'a [u8]) -> &'a [u8] {
+    while let [b'.' | b'-', rest @ ..] = *v {
+        *v = rest;
+    }
+    let is_digit = v.first().is_some_and(u8::is_ascii_digit);
+    let len = v
+        .iter()
+        .position(|c| {
+            if is_digit {
+                !c.is_ascii_digit()
+            } else {
+                c.is_ascii_digit() || *c == b'.' || *c == b'-'
+            }
+        })
+        .unwrap_or(v.len());
+    let (component, rest) = v.split_at(len);
+    *v = rest;
+    component
+}
+
+fn as_number(c: &[u8]) -> Option<u64> {
+    std::str::from_utf8(c).ok()?.parse().ok()
+}
+
+/// Nix's ordering of two components: numbers numerically, a missing
+/// component before a number, `pre` before anything else, letters before
+/// numbers (`2.3a < 2.3.1`), and everything else byte-wise.
+fn component_lt(c1: &[u8], c2: &[u8]) -> bool {
+    match (as_number(c1), as_number(c2)) {
+        (Some(n1), Some(n2)) => n1 < n2,
+        (_, Some(_)) if c1.is_empty() => true,
+        _ if c1 == b"pre" && c2 != b"pre" => true,
+        _ if c2 == b"pre" => false,
+        (_, Some(_)) => true,
+        (Some(_), _) => false,
+        _ => c1 < c2,
+    }
+}
+
+/// Compare two versions like `builtins.compareVersions`.
+pub fn compare_versions(v1: &[u8], v2: &[u8]) -> Ordering {
+    let (mut v1, mut v2) = (v1, v2);
+    while !v1.is_empty() || !v2.is_empty() {
+        let c1 = next_component(&mut v1);
+        let c2 = next_component(&mut v2);
+        if component_lt(c1, c2) {
+            return Ordering::Less;
+        }
+        if component_lt(c2, c1) {
+            return Ordering::Greater;
+        }
+    }
+    Ordering::Equal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_versions_matches_nix() {
+        use Ordering::*;
+        // The examples from the Nix manual and Nix's own test suite, plus
+        // edge cases around letters, dashes and pre-releases.
+        let cases: &[(&str, &str, Ordering)] = &[
+            ("1.0", "2.3", Less),
+            ("2.1", "2.3", Less),
+            ("2.3", "2.3", Equal),
+            ("2.5", "2.3", Greater),
+            ("3.1", "2.3", Greater),
+            ("2.3.1", "2.3", Greater),
+            ("2.3.1", "2.3a", Greater),
+            ("2.3pre1", "2.3", Less),
+            ("2.3pre3", "2.3pre12", Less),
+            ("2.3a", "2.3c", Less),
+            ("2.3pre1", "2.3c", Less),
+            ("2.3pre1", "2.3q", Less),
+            ("2.12", "2.12.1", Less),
+            ("1.10", "1.9", Greater),
+            ("1.0", "1.0.0", Less),
+            ("01", "1", Equal),
+            ("1-2", "1.2", Equal),
+            ("1..2", "1.2", Equal),
+            ("1.2.3", "1.2.3-rc1", Less),
+            ("1.2.3-alpha", "1.2.3-beta", Less),
+            ("1.2.3pre", "1.2.3-rc1", Less),
+            ("pre", "pre", Equal),
+            ("2024-01-05", "2023-12-31", Greater),
+            ("unstable-2024-01-01", "1.0", Less),
+            ("", "", Equal),
+            ("", "0", Less),
+            ("a", "", Greater),
+        ];
+        for (v1, v2, want) in cases {
+            assert_eq!(
+                compare_versions(v1.as_bytes(), v2.as_bytes()),
+                *want,
+                "compareVersions {v1:?} {v2:?}"
+            );
+            assert_eq!(
+                compare_versions(v2.as_bytes(), v1.as_bytes()),
+                want.reverse(),
+                "compareVersions {v2:?} {v1:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_drv_name_splits_at_first_dash_before_non_letter() {
+        let cases: &[(&str, &str, &str)] = &[
+            ("hello-2.12", "hello", "2.12"),
+            ("hello-v1", "hello-v1", ""),
+            ("hello", "hello", ""),
+            ("hello-", "hello-", ""),
+            ("nix-unstable-2.0", "nix-unstable", "2.0"),
+            ("foo-bar-1.0-rc1", "foo-bar", "1.0-rc1"),
+            (
+                "python3.11-requests-2.31.0",
+                "python3.11-requests",
+                "2.31.0",
+            ),
+            ("font-_x", "font", "_x"),
+        ];
+        for (name, pname, version) in cases {
+            assert_eq!(
+                parse_drv_name(name.as_bytes()),
+                (pname.as_bytes(), version.as_bytes()),
+                "parseDrvName {name:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_drv_path_strips_store_dir_hash_and_extension() {
+        let cases: &[(&str, &str, &str)] = &[
+            (
+                "/nix/store/0123456789abcdfghijklmnpqrsvwxyz-openssl-3.0.13.drv",
+                "openssl",
+                "3.0.13",
+            ),
+            (
+                "/nix/store/0123456789abcdfghijklmnpqrsvwxyz-python3.11-requests-2.31.0.drv",
+                "python3.11-requests",
+                "2.31.0",
+            ),
+            ("/nix/store/aaaa-source.drv", "source", ""),
+            ("/nix/store/aaaa-hello-2.12", "hello", "2.12"),
+            ("hello-2.12.drv", "hello", "2.12"),
+            (
+                "python3.11-requests-2.31.0.drv",
+                "python3.11-requests",
+                "2.31.0",
+            ),
+        ];
+        for (path, pname, version) in cases {
+            assert_eq!(
+                parse_drv_path(path.as_bytes()),
+                (pname.as_bytes(), version.as_bytes()),
+                "{path:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! `Derivation::to_aterm` writes .drv files back byte for byte: the
+//! fixtures, which are in the exact format Nix writes, and a derivation
+//! instantiated by Nix.
+
+use nix_diff::parser::parse_derivation_bytes;
+use nix_diff::testing::NixEnv;
+use std::path::PathBuf;
+use std::process::Command;
+
+fn assert_round_trips(path: &str, aterm: &[u8]) {
+    let drv = parse_derivation_bytes(path, aterm).unwrap();
+    let written = drv.to_aterm();
+    assert!(
+        written == aterm,
+        "{path} written as\n{}",
+        String::from_utf8_lossy(&written)
+    );
+    assert_eq!(
+        parse_derivation_bytes(path, &written).unwrap(),
+        drv,
+        "{path}"
+    );
+}
+
+fn replace(bytes: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut replaced = Vec::new();
+    let mut rest = bytes;
+    while let Some(at) = rest.windows(from.len()).position(|w| w == from) {
+        replaced.extend_from_slice(&rest[..at]);
+        replaced.extend_from_slice(to);
+        rest = &rest[at + from.len()..];
+    }
+    replaced.extend_from_slice(rest);
+    replaced
+}
+
+#[test]
+fn fixtures_round_trip() {
+    let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    // Escapes and bytes that are not UTF-8.
+    let cafe = std::fs::read(fixtures.join("dump-parsed/cafe.drv")).unwrap();
+    let cafe = replace(&cafe, b"@storeDir@", b"/nix/store");
+    assert_round_trips(
+        "/nix/store/11111111111111111111111111111111-cafe.drv",
+        &cafe,
+    );
+
+    // Floating content-addressed outputs, and `DrvWithVersion`.
+    for (dir, name) in [
+        ("ca-derivations", "new.drv"),
+        ("ca-derivations", "lib-new.drv"),
+        ("dyn-drv", "gen.drv"),
+        ("dyn-drv", "new.drv"),
+    ] {
+        let aterm = std::fs::read(fixtures.join(dir).join(name)).unwrap();
+        let aterm = replace(&aterm, b"@storeDir@", b"/nix/store");
+        assert_round_trips(
+            &format!("/nix/store/11111111111111111111111111111111-{name}"),
+            &aterm,
+        );
+    }
+
+    for side in ["a", "b"] {
+        for entry in std::fs::read_dir(fixtures.join("dual-store").join(side)).unwrap() {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            if name.ends_with(".drv") {
+                let aterm =
+                    std::fs::read(fixtures.join("dual-store").join(side).join(&name)).unwrap();
+                assert_round_trips(&format!("/nix/store/{name}"), &aterm);
+            }
+        }
+    }
+}
+
+#[test]
+fn instantiated_derivation_round_trips() {
+    let tests_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let nix = NixEnv::new().unwrap();
+    let output = Command::new("nix-instantiate")
+        .arg(tests_dir.join("hello-flake-v1/default.nix"))
+        .envs(nix.vars().iter().map(|(key, value)| (key, value)))
+        .output()
+        .expect("Failed to instantiate hello-flake-v1/default.nix");
+    let drv_path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let aterm = std::fs::read(&drv_path).unwrap();
+    assert_round_trips(&drv_path, &aterm);
+}
@@ -0,0 +1,145 @@
+//! `--budget`: a wide graph where every derivation changed, `lib-NN`
+//! having NN leaf inputs of its own. The derivations are put into a fake
+//! store, so this runs without Nix.
+
+use nix_diff::testing::NixEnv;
+use std::process::Output;
+
+const LIBS: usize = 12;
+
+/// The store name of a derivation on `side` (1 = old, 2 = new).
+fn drv_name(side: u8, name: &str, id: usize) -> String {
+    format!("{side}{id:031}-{name}.drv")
+}
+
+fn drv(side: u8, name: &str, inputs: &[String]) -> String {
+    let inputs: Vec<_> = inputs
+        .iter()
+        .map(|input| format!(r#"("@storeDir@/{input}",["out"])"#))
+        .collect();
+    format!(
+        r#"Derive([("out","@storeDir@/{side}{:031}-{name}","","")],[{}],[],"x86_64-linux","/bin/sh",[],[("name","{name}"),("side","{side}")])"#,
+        name.len(),
+        inputs.join(",")
+    )
+}
+
+fn nix_diff(args: &[&str]) -> Output {
+    let mut builder = NixEnv::builder();
+    for side in [1, 2] {
+        let mut libs = Vec::new();
+        for lib in 0..LIBS {
+            let mut leaves = Vec::new();
+            for leaf in 0..lib {
+                let name = format!("leaf-{lib:02}-{leaf:02}");
+                let path = drv_name(side, &name, 1000 + lib * 100 + leaf);
+                builder = builder.store_file(&path, drv(side, &name, &[]));
+                leaves.push(path);
+            }
+            let name = format!("lib-{lib:02}");
+            let path = drv_name(side, &name, lib);
+            builder = builder.store_file(&path, drv(side, &name, &leaves));
+            libs.push(path);
+        }
+        builder = builder.store_file(drv_name(side, "root", 99), drv(side, "root", &libs));
+    }
+    let env = builder.build().unwrap();
+    env.nix_diff(args)
+        .arg(env.store_path(&drv_name(1, "root", 99)))
+        .arg(env.store_path(&drv_name(2, "root", 99)))
+        .output()
+        .unwrap()
+}
+
+fn stdout(args: &[&str]) -> String {
+    let output = nix_diff(args);
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{stdout}{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    stdout
+}
+
+/// The inputs shown expanded, by name.
+fn expanded(stdout: &str) -> Vec<&str> {
+    stdout
+        .lines()
+        .filter_map(|line| line.trim_start().strip_prefix("• "))
+        .filter(|line| line.ends_with(".drv"))
+        .collect()
+}
+
+#[test]
+fn without_a_budget_everything_is_expanded() {
+    let stdout = stdout(&[]);
+    // The root, 12 libs and 0 + 1 + ... + 11 leaves.
+    assert_eq!(expanded(&stdout).len(), 1 + LIBS + 66 - 1, "{stdout}");
+    assert!(!stdout.contains("budget reached"), "{stdout}");
+}
+
+#[test]
+fn the_budget_caps_expanded_derivations() {
+    let stdout = stdout(&["--budget", "10"]);
+    // The root takes one; lib-03 gets the last three, for itself and two
+    // of its leaves.
+    assert_eq!(
+        expanded(&stdout),
+        [
+            "lib-00.drv",
+            "lib-01.drv",
+            "leaf-01-00.drv",
+            "lib-02.drv",
+            "leaf-02-00.drv",
+            "leaf-02-01.drv",
+            "lib-03.drv",
+            "leaf-03-00.drv",
+            "leaf-03-01.drv",
+        ],
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains(
+            "(budget reached: 1 more changed input with 1 changed derivation, \
+             use --budget to show more)"
+        ),
+        "{stdout}"
+    );
+    // The summary still counts everything.
+    assert!(stdout.contains("79 derivations differ"), "{stdout}");
+}
+
+#[test]
+fn the_largest_pruned_inputs_are_listed() {
+    let stdout = stdout(&["--budget", "10", "--input-list-limit", "3"]);
+    let listing = stdout
+        .lines()
+        .skip_while(|line| !line.contains("8 more changed inputs with 68 changed derivations"))
+        .skip(1)
+        .take(4)
+        .map(str::trim_start)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        listing,
+        [
+            "• lib-11.drv (12)",
+            "• lib-10.drv (11)",
+            "• lib-09.drv (10)",
+            "... and 5 more",
+        ],
+        "{stdout}"
+    );
+}
+
+#[test]
+fn the_budget_needs_text_output() {
+    for args in [
+        &["--budget", "0"][..],
+        &["--budget", "5", "--format", "json"],
+    ] {
+        let output = nix_diff(args);
+        assert_eq!(output.status.code(), Some(2), "{args:?}");
+    }
+}
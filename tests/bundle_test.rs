@@ -0,0 +1,216 @@
+//! `--bundle DIR`: the manifest must describe exactly the files written.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use harmonia_utils_hash::Sha256;
+use harmonia_utils_hash::fmt::CommonHash;
+use nix_diff::testing::NixEnv;
+
+fn nix_diff(args: &[&str], env: &[(String, String)]) -> Output {
+    let state = tempfile::tempdir().unwrap();
+    Command::new(env!("CARGO_BIN_EXE_nix-diff"))
+        .args(["--color", "never", "--no-history"])
+        .args(args)
+        .env("NIX_STORE_DIR", "/nix/store")
+        .env("XDG_STATE_HOME", state.path())
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .output()
+        .unwrap()
+}
+
+/// Every file below `dir` but `MANIFEST`, with its SHA-256 from
+/// `sha256sum`.
+fn files_and_hashes(dir: &Path) -> BTreeMap<String, String> {
+    let mut files = BTreeMap::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in std::fs::read_dir(&current).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            let name = path
+                .strip_prefix(dir)
+                .unwrap()
+                .to_string_lossy()
+                .into_owned();
+            if name != "MANIFEST" {
+                let hash = Sha256::digest(std::fs::read(&path).unwrap());
+                let hash = hash.as_base16().as_bare().to_string();
+                files.insert(name, hash);
+            }
+        }
+    }
+    files
+}
+
+/// Check `MANIFEST` against the files in `dir` and return its entries.
+fn check_manifest(dir: &Path) -> BTreeMap<String, String> {
+    let manifest = std::fs::read_to_string(dir.join("MANIFEST")).unwrap();
+    let entries: BTreeMap<String, String> = manifest
+        .lines()
+        .map(|line| {
+            let (hash, name) = line.split_once("  ").unwrap();
+            (name.to_string(), hash.to_string())
+        })
+        .collect();
+    assert_eq!(entries, files_and_hashes(dir), "{manifest}");
+    entries
+}
+
+#[test]
+fn bundles_the_hello_diff() {
+    let tests_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let nix = NixEnv::new().unwrap();
+    let env_vars = nix.vars();
+    let instantiate = |dir: &str| {
+        let output = Command::new("nix-instantiate")
+            .arg(tests_dir.join(dir).join("default.nix"))
+            .envs(env_vars.iter().map(|(k, v)| (k, v)))
+            .output()
+            .expect("Failed to run nix-instantiate");
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    };
+    let (drv1, drv2) = (instantiate("hello-flake-v1"), instantiate("hello-flake-v2"));
+
+    let out = tempfile::tempdir().unwrap();
+    let dir = out.path().join("bundle");
+    let output = nix_diff(&["--bundle", dir.to_str().unwrap(), &drv1, &drv2], env_vars);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{stderr}");
+
+    let files = check_manifest(&dir);
+    let name = |path: &str| {
+        Path::new(path)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    };
+    for (side, drv) in [("old", &drv1), ("new", &drv2)] {
+        let copy = format!("{side}/{}", name(drv));
+        assert!(files.contains_key(&copy), "{files:?}");
+        assert_eq!(
+            std::fs::read(dir.join(&copy)).unwrap(),
+            std::fs::read(drv).unwrap()
+        );
+    }
+    assert!(files.contains_key("diff.json") && files.contains_key("metadata.json"));
+    // The rendered diff is what was printed.
+    assert_eq!(
+        std::fs::read_to_string(dir.join("diff.txt")).unwrap(),
+        String::from_utf8_lossy(&output.stdout)
+    );
+    let metadata = std::fs::read_to_string(dir.join("metadata.json")).unwrap();
+    assert!(metadata.contains(env!("CARGO_PKG_VERSION")), "{metadata}");
+    assert!(!metadata.contains("--bundle"), "{metadata}");
+    assert!(
+        stderr.contains(&format!("into {}:", dir.display())),
+        "{stderr}"
+    );
+}
+
+/// Arguments that bundle the `dual-store` fixtures into `dir`, without Nix.
+fn dual_store_args(dir: &Path) -> Vec<String> {
+    let store = |side: &str| {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/dual-store")
+            .join(side)
+            .to_string_lossy()
+            .into_owned()
+    };
+    vec![
+        "--bundle".to_string(),
+        dir.to_string_lossy().into_owned(),
+        "--store-a".to_string(),
+        store("a"),
+        "--store-b".to_string(),
+        store("b"),
+        "/nix/store/55555555555555555555555555555555-app.drv".to_string(),
+        "/nix/store/cccccccccccccccccccccccccccccccc-app.drv".to_string(),
+    ]
+}
+
+fn metadata(dir: &Path) -> serde_json::Value {
+    serde_json::from_slice(&std::fs::read(dir.join("metadata.json")).unwrap()).unwrap()
+}
+
+#[test]
+fn bundles_sources_and_refuses_to_overwrite() {
+    let out = tempfile::tempdir().unwrap();
+    let dir = out.path().join("bundle");
+    let args = dual_store_args(&dir);
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let output = nix_diff(&args, &[]);
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let files = check_manifest(&dir);
+    assert_eq!(
+        files.keys().map(String::as_str).collect::<Vec<_>>(),
+        [
+            "diff.json",
+            "diff.txt",
+            "metadata.json",
+            "new/cccccccccccccccccccccccccccccccc-app.drv",
+            "old/55555555555555555555555555555555-app.drv",
+            "sources/lib.drv/patch.sh.new",
+            "sources/lib.drv/patch.sh.old",
+        ]
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.join("sources/lib.drv/patch.sh.new")).unwrap(),
+        "sed -i s/foo/baz/ lib.c\n"
+    );
+    // app → lib on the old side; app → lib → zlib on the new one.
+    let diff: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(dir.join("diff.json")).unwrap()).unwrap();
+    assert_eq!(
+        diff["closure_size"],
+        serde_json::json!({"old": 2, "new": 3})
+    );
+    let metadata = metadata(&dir);
+    assert_eq!(metadata["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(metadata["options"][0], "--color", "{metadata}");
+    assert_eq!(metadata["options"][2], "--store-a", "{metadata}");
+    assert_eq!(
+        metadata["inputs"][1], "/nix/store/cccccccccccccccccccccccccccccccc-app.drv",
+        "{metadata}"
+    );
+    assert_eq!(metadata["store_dir"], "/nix/store");
+
+    let again = nix_diff(&args, &[]);
+    let stderr = String::from_utf8_lossy(&again.stderr);
+    assert_eq!(again.status.code(), Some(2), "{stderr}");
+    assert!(stderr.contains("not empty"), "{stderr}");
+    // Checked before diffing.
+    assert!(again.stdout.is_empty());
+}
+
+#[test]
+fn no_invocation_echo_keeps_only_the_version() {
+    let out = tempfile::tempdir().unwrap();
+    let dir = out.path().join("bundle");
+    let mut args = dual_store_args(&dir);
+    args.insert(0, "--no-invocation-echo".to_string());
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let output = nix_diff(&args, &[]);
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    check_manifest(&dir);
+    assert_eq!(
+        metadata(&dir),
+        serde_json::json!({ "version": env!("CARGO_PKG_VERSION") })
+    );
+}
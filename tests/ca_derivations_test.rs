@@ -0,0 +1,114 @@
+//! Content-addressed derivations (`ca-derivations`), whose floating
+//! outputs have no path in the .drv file, and derivations using outputs of
+//! dynamic derivations (`dynamic-derivations`), which Nix writes as
+//! `DrvWithVersion`. The fixtures in `tests/fixtures/ca-derivations` and
+//! `tests/fixtures/dyn-drv` are put into a fake store, so this runs
+//! without an experimental Nix.
+
+use nix_diff::testing::NixEnv;
+use std::process::Output;
+
+fn fixture(dir: &str, name: &str) -> Vec<u8> {
+    let dir = format!("{}/tests/fixtures/{dir}", env!("CARGO_MANIFEST_DIR"));
+    std::fs::read(format!("{dir}/{name}")).unwrap()
+}
+
+fn nix_diff(env: &NixEnv, old: &str, new: &str) -> Output {
+    env.nix_diff(&[])
+        .arg(env.store_path(old))
+        .arg(env.store_path(new))
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn floating_outputs_are_not_diffed_as_empty_paths() {
+    let dir = "ca-derivations";
+    let env = NixEnv::builder()
+        .store_file(
+            "11111111111111111111111111111111-hello.drv",
+            fixture(dir, "old.drv"),
+        )
+        .store_file(
+            "22222222222222222222222222222222-hello.drv",
+            fixture(dir, "new.drv"),
+        )
+        .store_file(
+            "44444444444444444444444444444444-lib.drv",
+            fixture(dir, "lib-old.drv"),
+        )
+        .store_file(
+            "55555555555555555555555555555555-lib.drv",
+            fixture(dir, "lib-new.drv"),
+        )
+        .build()
+        .unwrap();
+    let output = nix_diff(
+        &env,
+        "11111111111111111111111111111111-hello.drv",
+        "22222222222222222222222222222222-hello.drv",
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{stdout}{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let store = env.store_dir().display();
+    assert!(
+        stdout.contains(&format!(
+            "Output 'out':\n    Path: {store}/77777777777777777777777777777777-hello → \
+             (content-addressed, floating)\n"
+        )),
+        "{stdout}"
+    );
+    assert!(!stdout.contains("Path:\n"), "{stdout}");
+
+    // Both floating: nothing about the outputs differs.
+    let output = nix_diff(
+        &env,
+        "22222222222222222222222222222222-hello.drv",
+        "22222222222222222222222222222222-hello.drv",
+    );
+    assert_eq!(output.status.code(), Some(0), "{output:?}");
+}
+
+#[test]
+fn dynamic_derivation_outputs_are_inputs() {
+    let dir = "dyn-drv";
+    let env = NixEnv::builder()
+        .store_file(
+            "11111111111111111111111111111111-wrapper.drv",
+            fixture(dir, "old.drv"),
+        )
+        .store_file(
+            "22222222222222222222222222222222-wrapper.drv",
+            fixture(dir, "new.drv"),
+        )
+        .store_file(
+            "88888888888888888888888888888888-hello.drv.drv",
+            fixture(dir, "gen.drv"),
+        )
+        .build()
+        .unwrap();
+    let output = nix_diff(
+        &env,
+        "11111111111111111111111111111111-wrapper.drv",
+        "22222222222222222222222222222222-wrapper.drv",
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{stdout}{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(
+        stdout.contains(
+            "  This derivation now uses the 'out^dev' output of hello.drv \
+             (previously only 'out' and 'out^out')\n"
+        ),
+        "{stdout}"
+    );
+}
@@ -0,0 +1,110 @@
+//! Inputs rebuilt only because of upstream changes: `app-1` to `app-4`
+//! each depend on `lib`, whose configure flags changed, and differ from
+//! their old versions in nothing but store path hashes. The derivations
+//! are put into a fake store, so this runs without Nix.
+
+use insta::assert_snapshot;
+use nix_diff::testing::NixEnv;
+
+const APPS: usize = 4;
+
+fn hash(side: u8, id: usize) -> String {
+    format!("{side}{id:031}")
+}
+
+/// A derivation `name` on `side` (1 = old, 2 = new), with its store name.
+fn drv(
+    side: u8,
+    id: usize,
+    name: &str,
+    inputs: &[(usize, &str)],
+    env: &[(&str, &str)],
+) -> (String, String) {
+    let out = format!("@storeDir@/{}-{name}", hash(side, id));
+    let input_drvs: Vec<_> = inputs
+        .iter()
+        .map(|&(id, name)| {
+            format!(
+                r#"("@storeDir@/{}-{name}.drv",["out"])"#,
+                hash(side, 100 + id)
+            )
+        })
+        .collect();
+    let mut vars = vec![("name", name), ("out", out.as_str())];
+    vars.extend_from_slice(env);
+    vars.sort();
+    let vars: Vec<_> = vars
+        .iter()
+        .map(|(key, value)| format!(r#"("{key}","{value}")"#))
+        .collect();
+    (
+        format!("{}-{name}.drv", hash(side, 100 + id)),
+        format!(
+            r#"Derive([("out","{out}","","")],[{}],[],"x86_64-linux","/bin/sh",["-c","true"],[{}])"#,
+            input_drvs.join(","),
+            vars.join(",")
+        ),
+    )
+}
+
+fn nix_diff(args: &[&str]) -> String {
+    let mut builder = NixEnv::builder();
+    let mut roots = Vec::new();
+    for side in [1, 2] {
+        let lib_out = format!("@storeDir@/{}-lib", hash(side, 0));
+        let flags = if side == 1 {
+            "--with-foo"
+        } else {
+            "--without-foo"
+        };
+        let (path, contents) = drv(side, 0, "lib", &[], &[("configureFlags", flags)]);
+        builder = builder.store_file(path, contents);
+        let names: Vec<_> = (1..=APPS).map(|app| format!("app-{app}")).collect();
+        let mut inputs = vec![(0, "lib")];
+        for (i, name) in names.iter().enumerate() {
+            let (path, contents) = drv(
+                side,
+                i + 1,
+                name,
+                &[(0, "lib")],
+                &[("buildInputs", &lib_out)],
+            );
+            builder = builder.store_file(path, contents);
+            inputs.push((i + 1, name));
+        }
+        let (path, contents) = drv(side, 99, "root", &inputs, &[]);
+        builder = builder.store_file(&path, contents);
+        roots.push(path);
+    }
+    let env = builder.build().unwrap();
+    let output = env
+        .nix_diff(args)
+        .arg(env.store_path(&roots[0]))
+        .arg(env.store_path(&roots[1]))
+        .output()
+        .unwrap();
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let store = env.store_path("").display().to_string();
+    String::from_utf8_lossy(&output.stdout).replace(store.trim_end_matches('/'), "/nix/store")
+}
+
+#[test]
+fn rebuilt_inputs_are_counted() {
+    assert_snapshot!(nix_diff(&[]));
+}
+
+#[test]
+fn no_collapse_lists_every_input() {
+    assert_snapshot!(nix_diff(&["--no-collapse"]));
+}
+
+#[test]
+fn brief_lists_rebuilt_inputs_too() {
+    // `app-<n>` parses as package `app` at version `<n>`.
+    assert_snapshot!(nix_diff(&["--brief"]));
+}
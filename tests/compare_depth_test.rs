@@ -0,0 +1,62 @@
+//! `--compare-depth`: changed inputs past the limit are listed but not
+//! compared. Runs without Nix, on derivations in a fake store whose deeper
+//! inputs are missing.
+
+use nix_diff::testing::NixEnv;
+use serde_json::Value;
+use std::process::Output;
+
+const OLD: &str = "11111111111111111111111111111111-app.drv";
+const NEW: &str = "22222222222222222222222222222222-app.drv";
+
+fn nix_diff(args: &[&str]) -> Output {
+    let mut builder = NixEnv::builder();
+    for (hash, root, root_hash, version) in [('3', OLD, '5', "1"), ('4', NEW, '6', "2")] {
+        // libfoo's own input is never written to the store.
+        let sub = format!("{}-zlib-{version}.drv", hash.to_string().repeat(32));
+        let dep = format!("{}-libfoo-{version}.drv", hash.to_string().repeat(32));
+        let libfoo = NixEnv::drv(&format!("libfoo-{version}"), hash, version, &[sub]);
+        builder = builder.store_file(&dep, libfoo);
+        builder = builder.store_file(root, NixEnv::drv("app", root_hash, version, &[dep]));
+    }
+    let env = builder.build().unwrap();
+    env.nix_diff(args)
+        .arg(env.store_path(OLD))
+        .arg(env.store_path(NEW))
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn inputs_past_compare_depth_are_listed_only() {
+    let output = nix_diff(&["--compare-depth", "0"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{stdout}{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("• libfoo: 1 → 2\n"), "{stdout}");
+    assert!(
+        stdout.contains("(changed inputs above not compared, --compare-depth reached)\n"),
+        "{stdout}"
+    );
+    assert!(stdout.contains("Summary: 1 derivation differs"), "{stdout}");
+
+    let output = nix_diff(&["--compare-depth", "0", "--format", "json"]);
+    let json: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["inputs"]["depth_limited"], true);
+    assert_eq!(json["inputs"]["changed"][0]["derivation"], Value::Null);
+
+    // One level down, libfoo is compared and zlib is listed.
+    let output = nix_diff(&["--compare-depth", "1", "--format", "json"]);
+    let json: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(json["inputs"].get("depth_limited").is_none());
+    let libfoo = &json["inputs"]["changed"][0]["derivation"];
+    assert_eq!(libfoo["inputs"]["depth_limited"], true);
+    assert_eq!(libfoo["inputs"]["changed"][0]["derivation"], Value::Null);
+
+    let output = nix_diff(&["--compare-depth", "x"]);
+    assert_eq!(output.status.code(), Some(2));
+}
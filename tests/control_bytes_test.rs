@@ -0,0 +1,68 @@
+//! Env values with NUL and DEL bytes, as a hand-crafted derivation can
+//! have: escaped in the text formats, exact in JSON, and warned about in
+//! every format. The derivations are put into a fake store, so this runs
+//! without Nix.
+
+use nix_diff::testing::NixEnv;
+use std::process::Output;
+
+const OLD: &str = "11111111111111111111111111111111-hello.drv";
+const NEW: &str = "22222222222222222222222222222222-hello.drv";
+
+fn drv(greeting: &str) -> String {
+    format!(
+        r#"Derive([("out","@storeDir@/33333333333333333333333333333333-hello","","")],[],[],"x86_64-linux","/bin/sh",["-c","true"],[("greeting","{greeting}"),("name","hello"),("out","@storeDir@/33333333333333333333333333333333-hello")])"#
+    )
+}
+
+fn nix_diff(format: &str) -> Output {
+    let env = NixEnv::builder()
+        .store_file(OLD, drv("hello"))
+        .store_file(NEW, drv("hel\0lo\x7f"))
+        .build()
+        .unwrap();
+    let output = env
+        .nix_diff(&["--format", format])
+        .arg(env.store_path(OLD))
+        .arg(env.store_path(NEW))
+        .output()
+        .unwrap();
+    assert!(
+        String::from_utf8_lossy(&output.stderr)
+            .contains("warning: the values of greeting contain NUL or other control characters"),
+        "{format}: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+#[test]
+fn control_bytes_in_env_values() {
+    for format in ["text", "commit-msg", "json", "ndjson", "env-script"] {
+        let output = nix_diff(format);
+        // JSON strings may hold DEL as it is.
+        let json = format.ends_with("json");
+        assert!(
+            !output
+                .stdout
+                .iter()
+                .any(|&b| b == 0 || (b == 0x7f && !json)),
+            "{format}: {:?}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        match format {
+            "text" => assert!(stdout.contains("+ hel{+\\0lo\\x7f+}\n"), "{stdout}"),
+            "commit-msg" => assert!(stdout.contains("hello → hel\\0lo\\x7f"), "{stdout}"),
+            "env-script" => assert!(
+                stdout.contains("# skipped: greeting (contains NUL)"),
+                "{stdout}"
+            ),
+            // Exact, and no `\u0000` for strict consumers.
+            _ => {
+                assert!(!stdout.contains("\\u0000"), "{stdout}");
+                assert!(stdout.contains(r#""aGVsAGxvfw==""#), "{stdout}");
+            }
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! The library's `Display` of a diff is what the command line prints with
+//! `--color never`, so the two can share expected output.
+
+use nix_diff::testing::NixEnv;
+
+const OLD: &str = r#"Derive([("dev","@storeDir@/11111111111111111111111111111111-zlib-1.3-dev","",""),("out","@storeDir@/22222222222222222222222222222222-zlib-1.3","","")],[],[],"x86_64-linux","/bin/sh",["-e","build.sh"],[("CFLAGS","-O2"),("name","zlib-1.3"),("out","@storeDir@/22222222222222222222222222222222-zlib-1.3")])"#;
+const NEW: &str = r#"Derive([("dev","@storeDir@/33333333333333333333333333333333-zlib-1.3.1-dev","",""),("out","@storeDir@/44444444444444444444444444444444-zlib-1.3.1","","")],[],[],"x86_64-linux","/bin/sh",["-e","build.sh","--static"],[("CFLAGS","-O2 -fPIC"),("name","zlib-1.3.1"),("out","@storeDir@/44444444444444444444444444444444-zlib-1.3.1")])"#;
+
+#[test]
+fn display_matches_the_command_line() {
+    let env = NixEnv::builder()
+        .store_file("55555555555555555555555555555555-zlib-1.3.drv", OLD)
+        .store_file("66666666666666666666666666666666-zlib-1.3.1.drv", NEW)
+        .build()
+        .unwrap();
+    let old = env.store_path("55555555555555555555555555555555-zlib-1.3.drv");
+    let new = env.store_path("66666666666666666666666666666666-zlib-1.3.1.drv");
+
+    let output = env.nix_diff(&[]).args([&old, &new]).output().unwrap();
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let diff = nix_diff::diff_files(&old, &new).unwrap();
+    assert_eq!(diff.to_string(), String::from_utf8(output.stdout).unwrap());
+    assert!(diff.to_string().contains("--static"), "{diff}");
+}
+
+/// The re-exports at the crate root are enough to diff derivations held
+/// in memory, and `diff_paths` with settings diffs files like `diff_files`.
+#[test]
+fn library_entry_points_agree() {
+    let env = NixEnv::builder()
+        .store_file("55555555555555555555555555555555-zlib-1.3.drv", OLD)
+        .store_file("66666666666666666666666666666666-zlib-1.3.1.drv", NEW)
+        .build()
+        .unwrap();
+    let old = env.store_path("55555555555555555555555555555555-zlib-1.3.drv");
+    let new = env.store_path("66666666666666666666666666666666-zlib-1.3.1.drv");
+    let from_files = nix_diff::diff_files(&old, &new).unwrap();
+
+    let options = nix_diff::DiffContext::new()
+        .skip_content_reads(true)
+        .jobs(1);
+    let configured = nix_diff::diff_paths(&old, &new, options).unwrap();
+    assert_eq!(configured, from_files);
+
+    let store = env.store_dir().to_string_lossy().into_owned();
+    let [old, new] = [old, new].map(|path| path.to_string_lossy().into_owned());
+    let parse = |path: &str, text: &str| {
+        nix_diff::parse_derivation_contents(path, &text.replace("@storeDir@", &store)).unwrap()
+    };
+    let in_memory: nix_diff::DerivationDiff = nix_diff::DiffContext::new()
+        .diff_derivations(
+            old.as_bytes(),
+            new.as_bytes(),
+            &parse(&old, OLD),
+            &parse(&new, NEW),
+        )
+        .unwrap();
+    assert_eq!(in_memory.args, from_files.args);
+    assert_eq!(in_memory.env, from_files.env);
+    assert!(in_memory.labels.is_none());
+}
@@ -0,0 +1,143 @@
+//! `--dry-run` resolves both inputs and prints the plan without diffing.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use nix_diff::testing::NixEnv;
+
+const DRV: &str = r#"Derive([("out","/nix/store/cccccccccccccccccccccccccccccccc-hello","","")],[],[],"x86_64-linux","/bin/sh",[],[("name","hello")])"#;
+
+fn nix_diff(args: &[&str], env: &[(String, String)]) -> Output {
+    let state = tempfile::tempdir().unwrap();
+    Command::new(env!("CARGO_BIN_EXE_nix-diff"))
+        .args(["--color", "never", "--dry-run"])
+        .args(args)
+        .env("NIX_STORE_DIR", "/nix/store")
+        .env("XDG_STATE_HOME", state.path())
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .output()
+        .unwrap()
+}
+
+fn plan(output: &Output) -> String {
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "{stdout}\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(!stdout.contains("Environment"), "{stdout}");
+    stdout
+}
+
+fn write(dir: &Path, name: &str, contents: &str) -> String {
+    let path = dir.join(name);
+    std::fs::write(&path, contents).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn drv_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let old = write(dir.path(), "old.drv", DRV);
+    // Only the header is checked, so a truncated file passes.
+    let new = write(dir.path(), "new.drv", &DRV[..40]);
+    let output = nix_diff(&["--depth", "1", &old, &new], &[]);
+    assert_eq!(
+        plan(&output),
+        format!(
+            "\
+Would compare:
+  old: {old} (drv)
+       {old}
+  new: {new} (drv)
+       {new}
+Store directory: /nix/store
+Options: --color never --depth 1
+"
+        )
+    );
+}
+
+#[test]
+fn store_paths_and_urls() {
+    let store = tempfile::tempdir().unwrap();
+    let store_dir = store.path().to_string_lossy().into_owned();
+    let drv = write(
+        store.path(),
+        "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello.drv",
+        DRV,
+    );
+    let url = "https://cache.example/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-hello.drv";
+    let output = nix_diff(
+        &["--input-type", "store,url", &drv, url],
+        &[("NIX_STORE_DIR".to_string(), store_dir.clone())],
+    );
+    let plan = plan(&output);
+    assert!(
+        plan.contains(&format!("  old: {drv} (store)\n       {drv}\n")),
+        "{plan}"
+    );
+    assert!(
+        plan.contains(&format!("  new: {url} (url)\n       download {url}\n")),
+        "{plan}"
+    );
+    assert!(
+        plan.contains(&format!("Store directory: {store_dir}\n")),
+        "{plan}"
+    );
+}
+
+#[test]
+fn nix_files_are_not_evaluated() {
+    let dir = tempfile::tempdir().unwrap();
+    let nix = write(dir.path(), "default.nix", "throw \"evaluated\"");
+    let drv = write(dir.path(), "hello.drv", DRV);
+    let plan = plan(&nix_diff(&[&drv, &nix], &[]));
+    assert!(
+        plan.contains(&format!(
+            "  new: {nix} (nix)\n       nix-instantiate {nix}\n"
+        )),
+        "{plan}"
+    );
+}
+
+#[test]
+fn resolution_failures_are_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let drv = write(dir.path(), "hello.drv", DRV);
+    let html = write(dir.path(), "html.drv", "<html>");
+    let missing = dir.path().join("missing.nix");
+
+    let output = nix_diff(&[&drv, &html], &[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{stderr}");
+    assert!(output.stdout.is_empty());
+    assert!(stderr.contains("is not a derivation"), "{stderr}");
+
+    let output = nix_diff(
+        &["--input-type", "drv,nix", &drv, missing.to_str().unwrap()],
+        &[],
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{stderr}");
+    assert!(stderr.contains("does not exist"), "{stderr}");
+}
+
+#[test]
+fn test_dry_run_locks_flakes() {
+    let tests_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let nix = NixEnv::new().unwrap();
+    let env_vars = nix.vars();
+    let flake = format!(
+        "path:{}#hello",
+        tests_dir.join("hello-flake-v1").to_str().unwrap()
+    );
+    let plan = plan(&nix_diff(&[&flake, &flake], env_vars));
+    assert!(
+        plan.contains(&format!("  old: {flake} (flake)\n")),
+        "{plan}"
+    );
+    assert!(plan.contains("-source?narHash=sha256-"), "{plan}");
+}
@@ -0,0 +1,78 @@
+//! `--store-a`/`--store-b` on two fixture store copies whose graphs
+//! diverge below the root: each side's inputs only exist in its own copy.
+
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+const OLD: &str = "/nix/store/55555555555555555555555555555555-app.drv";
+const NEW: &str = "/nix/store/cccccccccccccccccccccccccccccccc-app.drv";
+
+fn store(side: &str) -> String {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/dual-store")
+        .join(side)
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn nix_diff(args: &[&str]) -> Output {
+    let state = tempfile::tempdir().unwrap();
+    Command::new(env!("CARGO_BIN_EXE_nix-diff"))
+        .args(["--color", "never", "--no-history"])
+        .args(args)
+        .env("NIX_STORE_DIR", "/nix/store")
+        .env("XDG_STATE_HOME", state.path())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn recurses_into_each_side_in_its_own_store() {
+    let (a, b) = (store("a"), store("b"));
+    let output = nix_diff(&["--store-a", &a, "--store-b", &b, OLD, NEW]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{stdout}\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(
+        stdout.starts_with(&format!("--- {OLD}\n+++ {NEW}\n")),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains("closure size: 2 → 3 derivations"),
+        "{stdout}"
+    );
+    assert!(stdout.contains("• lib.drv"), "{stdout}");
+    assert!(stdout.contains("version: 1.0 → 1.1"), "{stdout}");
+    // Sources are read from the copies too.
+    assert!(
+        stdout.contains("- sed -i s/foo/bar/ lib.c\n      + sed -i s/foo/baz/ lib.c"),
+        "{stdout}"
+    );
+    assert!(stdout.contains("+ zlib 1.3\n"), "{stdout}");
+
+    // The files in the copies name the same derivations.
+    let old_file = format!("{a}/55555555555555555555555555555555-app.drv");
+    let new_file = format!("{b}/cccccccccccccccccccccccccccccccc-app.drv");
+    let by_file = nix_diff(&["--store-a", &a, "--store-b", &b, &old_file, &new_file]);
+    let by_file = String::from_utf8_lossy(&by_file.stdout);
+    assert_eq!(
+        by_file.lines().skip(2).collect::<Vec<_>>(),
+        stdout.lines().skip(2).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn one_store_for_both_sides_only_finds_half() {
+    let a = store("a");
+    let output = nix_diff(&["--store-a", &a, "--store-b", &a, OLD, NEW]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{stderr}");
+    assert!(
+        stderr.contains("cccccccccccccccccccccccccccccccc-app.drv"),
+        "{stderr}"
+    );
+}
@@ -0,0 +1,35 @@
+//! `--dump-parsed` on a derivation with bytes that are not UTF-8. The
+//! fixture in `tests/fixtures/dump-parsed` is put into a fake store, so
+//! this runs without Nix.
+
+use nix_diff::testing::NixEnv;
+
+const DRV: &str = "11111111111111111111111111111111-cafe.drv";
+
+#[test]
+fn dump_keeps_bytes_that_are_not_utf8() {
+    let fixture = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/dump-parsed/cafe.drv"
+    );
+    let env = NixEnv::builder()
+        .store_file(DRV, std::fs::read(fixture).unwrap())
+        .build()
+        .unwrap();
+    let output = env
+        .command(env!("CARGO_BIN_EXE_nix-diff"))
+        .args(["--no-history", "--dump-parsed"])
+        .arg(env.store_path(DRV))
+        .arg(env.store_path(DRV))
+        .output()
+        .unwrap();
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stdout = stdout.replace(&*env.store_dir().to_string_lossy(), "/nix/store");
+    insta::assert_snapshot!(stdout);
+}
@@ -0,0 +1,88 @@
+//! How errors look on stderr, with and without color.
+
+use std::process::{Command, Output};
+
+fn nix_diff(color: bool, args: &[&str]) -> Output {
+    let state = tempfile::tempdir().unwrap();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_nix-diff"));
+    command
+        .args(["--no-history", "--input-type", "drv", "--color", "always"])
+        .args(args)
+        .env("NIX_STORE_DIR", "/nix/store")
+        .env("XDG_STATE_HOME", state.path());
+    if color {
+        command.env_remove("NO_COLOR");
+    } else {
+        command.env("NO_COLOR", "1");
+    }
+    command.output().unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    assert_eq!(output.status.code(), Some(2));
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+#[test]
+fn missing_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("gone.drv");
+    let path = path.to_str().unwrap();
+    let output = nix_diff(false, &[path, path]);
+    assert_eq!(
+        stderr(&output),
+        format!(
+            "\
+error: failed to parse derivation: {path}
+  caused by: failed to read derivation file: {path}
+  caused by: no such file or directory (os error 2)
+"
+        )
+    );
+
+    let output = nix_diff(true, &[path, path]);
+    assert_eq!(
+        stderr(&output),
+        format!(
+            "\
+\x1b[1;31merror:\x1b[0m failed to parse derivation: {path}
+\x1b[2m  caused by: failed to read derivation file: {path}\x1b[0m
+\x1b[2m  caused by: no such file or directory (os error 2)\x1b[0m
+"
+        )
+    );
+}
+
+#[test]
+fn parse_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bad.drv");
+    std::fs::write(&path, "Derive([(\"out\"").unwrap();
+    let path = path.to_str().unwrap();
+
+    // The cause quotes where the file stops making sense, with a caret
+    // under it.
+    let plain = stderr(&nix_diff(false, &[path, path]));
+    assert_eq!(
+        plain,
+        format!(
+            "\
+error: failed to parse derivation: {path}
+  caused by: failed to parse ATerm in {path}
+  caused by: expected `,`, found end of input at line 1, column 15 (byte 14):
+               Derive([(\"out\"
+                             ^
+"
+        )
+    );
+
+    let colored = stderr(&nix_diff(true, &[path, path]));
+    let lead = " ".repeat("  caused by: ".len());
+    assert!(
+        colored.ends_with(&format!(
+            "\x1b[2m{lead}  Derive([(\"out\"\x1b[0m\n\
+             {lead}\x1b[1;31m                ^\x1b[0m\n"
+        )),
+        "{colored}"
+    );
+}
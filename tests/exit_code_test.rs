@@ -0,0 +1,71 @@
+//! The exit status follows diff(1): 0 when the derivations are identical,
+//! 1 when they differ, 2 on errors. The fixtures are put into a fake store,
+//! so this runs without Nix.
+
+use nix_diff::testing::NixEnv;
+use std::process::Output;
+
+const OUT_A: &str = "33333333333333333333333333333333";
+const OUT_B: &str = "44444444444444444444444444444444";
+
+fn drv(out: &str, version: &str) -> String {
+    format!(
+        r#"Derive([("out","@storeDir@/{out}-hello","","")],[],[],"x86_64-linux","/bin/sh",["-c","true"],[("out","@storeDir@/{out}-hello"),("version","{version}")])"#
+    )
+}
+
+fn nix_diff(old: &str, new: &str, args: &[&str]) -> Output {
+    let env = NixEnv::builder()
+        .store_file("11111111111111111111111111111111-hello.drv", old)
+        .store_file("22222222222222222222222222222222-hello.drv", new)
+        .build()
+        .unwrap();
+    env.nix_diff(args)
+        .arg(env.store_path("11111111111111111111111111111111-hello.drv"))
+        .arg(env.store_path("22222222222222222222222222222222-hello.drv"))
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn identical_derivations_exit_0() {
+    let drv = drv(OUT_A, "1.0");
+    for format in ["text", "json", "commit-msg", "env-script"] {
+        let output = nix_diff(&drv, &drv, &["--format", format]);
+        assert_eq!(output.status.code(), Some(0), "{format}: {output:?}");
+    }
+    let output = nix_diff(&drv, &drv, &[]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("The derivations are identical."),
+        "{stdout}"
+    );
+}
+
+#[test]
+fn differing_derivations_exit_1() {
+    let (old, new) = (drv(OUT_A, "1.0"), drv(OUT_B, "2.0"));
+    for format in ["text", "json", "commit-msg", "env-script"] {
+        let output = nix_diff(&old, &new, &["--format", format]);
+        assert_eq!(output.status.code(), Some(1), "{format}: {output:?}");
+    }
+}
+
+#[test]
+fn hidden_changes_still_differ() {
+    // Only the output path and the var that mirrors it change, both left
+    // out of the default rendering.
+    let output = nix_diff(&drv(OUT_A, "1.0"), &drv(OUT_B, "1.0"), &[]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{stdout}");
+    assert!(!stdout.contains("identical"), "{stdout}");
+}
+
+#[test]
+fn usage_and_parse_errors_exit_2() {
+    let drv = drv(OUT_A, "1.0");
+    let output = nix_diff(&drv, &drv, &["--format", "yaml"]);
+    assert_eq!(output.status.code(), Some(2), "{output:?}");
+    let output = nix_diff(&drv, "Derive([(", &[]);
+    assert_eq!(output.status.code(), Some(2), "{output:?}");
+}
@@ -0,0 +1,160 @@
+//! Fixed-output derivations: what they fetch and the hash it must have
+//! are shown in a section of their own, with a warning when the source
+//! changed but the hash did not. Runs without Nix: the derivations, in
+//! the shape fetchurl and fetchgit give them, are put into a fake store.
+
+use nix_diff::testing::NixEnv;
+use serde_json::Value;
+use std::process::Output;
+
+const OLD: &str = "11111111111111111111111111111111-src.drv";
+const NEW: &str = "22222222222222222222222222222222-src.drv";
+
+/// A fixed-output derivation with `hash` as its output hash and `env` on
+/// top of the usual attributes; `hash` is `None` for a regular one.
+fn drv(hash: Option<&str>, env: &[(&str, &str)]) -> String {
+    let path = "@storeDir@/33333333333333333333333333333333-src";
+    let mut vars = vec![("builder", "/bin/sh"), ("name", "src"), ("out", path)];
+    let output = match hash {
+        Some(hash) => {
+            vars.push(("outputHash", hash));
+            format!(r#"("out","{path}","r:sha256","{hash}")"#)
+        }
+        None => format!(r#"("out","{path}","","")"#),
+    };
+    vars.extend_from_slice(env);
+    vars.sort();
+    let vars: Vec<_> = vars
+        .iter()
+        .map(|(key, value)| format!(r#"("{key}","{value}")"#))
+        .collect();
+    format!(
+        r#"Derive([{output}],[],[],"x86_64-linux","/bin/sh",["-c","true"],[{}])"#,
+        vars.join(",")
+    )
+}
+
+fn nix_diff(old: String, new: String, args: &[&str]) -> Output {
+    let env = NixEnv::builder()
+        .store_file(OLD, old)
+        .store_file(NEW, new)
+        .build()
+        .unwrap();
+    env.nix_diff(args)
+        .arg(env.store_path(OLD))
+        .arg(env.store_path(NEW))
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn fetchurl_changes_are_shown_together() {
+    let old = drv(
+        Some("aaaa"),
+        &[
+            ("outputHashMode", "flat"),
+            ("urls", "https://example.org/foo-1.0.tar.gz"),
+        ],
+    );
+    let new = drv(
+        Some("bbbb"),
+        &[
+            ("outputHashMode", "flat"),
+            ("urls", "https://example.org/foo-1.1.tar.gz"),
+        ],
+    );
+    let output = nix_diff(old, new, &[]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{stdout}");
+    assert!(
+        stdout.contains(
+            "Fixed-output fetch:\n  \
+             urls: https://example.org/foo-1.0.tar.gz → https://example.org/foo-1.1.tar.gz\n  \
+             outputHash: aaaa → bbbb\n"
+        ),
+        "{stdout}"
+    );
+    assert!(!stdout.contains("warning:"), "{stdout}");
+    // They are not repeated among the other env vars.
+    assert!(!stdout.contains("Environment:"), "{stdout}");
+}
+
+#[test]
+fn fetchgit_rev_change_without_new_hash_is_flagged() {
+    let old = drv(
+        Some("aaaa"),
+        &[("rev", "v1.0"), ("url", "https://example.org/foo.git")],
+    );
+    let new = drv(
+        Some("aaaa"),
+        &[("rev", "v1.1"), ("url", "https://example.org/foo.git")],
+    );
+    let output = nix_diff(old.clone(), new.clone(), &[]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{stdout}");
+    assert!(
+        stdout.contains(
+            "Fixed-output fetch:\n  \
+             warning: the source changed but the output hash did not; \
+             the hash was probably not updated\n  \
+             rev: v1.0 → v1.1\n"
+        ),
+        "{stdout}"
+    );
+    assert!(stdout.contains("1 possibly stale hash"), "{stdout}");
+
+    let output = nix_diff(old, new, &["--format", "json"]);
+    let json: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["fetch"]["stale_hash"], true);
+    assert_eq!(json["fetch"]["attrs"][0]["key"], "rev");
+    assert_eq!(json["env"], Value::Null);
+}
+
+#[test]
+fn regular_derivations_keep_url_in_env() {
+    let old = drv(None, &[("url", "https://example.org/a")]);
+    let new = drv(None, &[("url", "https://example.org/b")]);
+    let output = nix_diff(old, new, &[]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{stdout}");
+    assert!(!stdout.contains("Fixed-output fetch"), "{stdout}");
+    assert!(stdout.contains("Environment:\n  url:\n"), "{stdout}");
+}
+
+#[test]
+fn hash_in_another_encoding_is_not_a_change() {
+    // The sha256 of "hello\n" in nix-base32 and as SRI; the output itself
+    // has it in base16, as Nix writes it.
+    let hex = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+    let base32 = "00xyyr3fi8l6hb839bv3f7yb86yjv7xi1cgh1xnhipym4asvb4aq";
+    let sri = "sha256-WJG1tSLV3whtD/CxEPvZ0hu0/HFjrzTQgoai6Eb2vgM=";
+    let with_hash = |hash: &str, algorithm: &str| {
+        drv(Some(hex), &[("outputHashAlgo", algorithm)]).replace(
+            &format!(r#"("outputHash","{hex}")"#),
+            &format!(r#"("outputHash","{hash}")"#),
+        )
+    };
+    let output = nix_diff(with_hash(base32, "sha256"), with_hash(sri, ""), &[]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains(&format!(
+            "Fixed-output fetch:\n  \
+             outputHash: {base32} → {sri} (same digest)\n  \
+             outputHashAlgo: sha256 → \"\" (same digest)\n"
+        )),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains("Summary: 1 derivation differs\n"),
+        "{stdout}"
+    );
+
+    // A new digest in the new encoding is a change.
+    let other = "sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+    let output = nix_diff(with_hash(base32, "sha256"), with_hash(other, ""), &[]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains(&format!("  outputHash: {base32} → {other}\n")),
+        "{stdout}"
+    );
+}
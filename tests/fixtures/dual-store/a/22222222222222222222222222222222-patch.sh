@@ -0,0 +1 @@
+sed -i s/foo/bar/ lib.c
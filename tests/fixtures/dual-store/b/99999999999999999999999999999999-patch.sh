@@ -0,0 +1 @@
+sed -i s/foo/baz/ lib.c
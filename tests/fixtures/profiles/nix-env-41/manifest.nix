@@ -0,0 +1 @@
+[ { meta = { available = true; broken = false; description = "A program that produces a familiar, friendly greeting"; license = { deprecated = false; free = true; fullName = "MIT License"; shortName = "mit"; spdxId = "MIT"; }; maintainers = [ { email = "someone@example.org"; github = "someone"; name = "Some One"; } ]; name = "hello-2.12.1"; outputsToInstall = [ "out" ]; position = "/nix/store/563231inw6p0fbr4ab4a9grwkczx44a6-source/pkgs/hello-2.12.1/default.nix:12"; }; name = "hello-2.12.1"; out = { outPath = "/nix/store/b1gp95v3z07f4snvcyr8r6v22h752w0w-hello-2.12.1"; }; outPath = "/nix/store/b1gp95v3z07f4snvcyr8r6v22h752w0w-hello-2.12.1"; outputs = [ "out" ]; system = "x86_64-linux"; type = "derivation"; } { meta = { available = true; broken = false; description = "A utility that combines the usability of The Silver Searcher with the raw speed of grep"; license = { deprecated = false; free = true; fullName = "MIT License"; shortName = "mit"; spdxId = "MIT"; }; maintainers = [ { email = "someone@example.org"; github = "someone"; name = "Some One"; } ]; name = "ripgrep-14.0.3"; outputsToInstall = [ "out" ]; position = "/nix/store/563231inw6p0fbr4ab4a9grwkczx44a6-source/pkgs/ripgrep-14.0.3/default.nix:12"; }; name = "ripgrep-14.0.3"; out = { outPath = "/nix/store/pl1bg1667as1ax5rj74ls9hnqwp2bmcy-ripgrep-14.0.3"; }; outPath = "/nix/store/pl1bg1667as1ax5rj74ls9hnqwp2bmcy-ripgrep-14.0.3"; outputs = [ "out" ]; system = "x86_64-linux"; type = "derivation"; } { meta = { available = true; broken = false; description = "An interactive process viewer"; license = { deprecated = false; free = true; fullName = "MIT License"; shortName = "mit"; spdxId = "MIT"; }; maintainers = [ { email = "someone@example.org"; github = "someone"; name = "Some One"; } ]; name = "htop-3.2.2"; outputsToInstall = [ "out" ]; position = "/nix/store/563231inw6p0fbr4ab4a9grwkczx44a6-source/pkgs/htop-3.2.2/default.nix:12"; }; name = "htop-3.2.2"; out = { outPath = "/nix/store/yycbim96dz84dxx1v0gv1j9b9zq9ixcn-htop-3.2.2"; }; outPath = "/nix/store/yycbim96dz84dxx1v0gv1j9b9zq9ixcn-htop-3.2.2"; outputs = [ "out" ]; system = "x86_64-linux"; type = "derivation"; } { meta = { available = true; broken = false; description = "Distributed version control system"; license = { deprecated = false; free = true; fullName = "MIT License"; shortName = "mit"; spdxId = "MIT"; }; maintainers = [ { email = "someone@example.org"; github = "someone"; name = "Some One"; } ]; name = "git-2.42.0"; outputsToInstall = [ "out" ]; position = "/nix/store/563231inw6p0fbr4ab4a9grwkczx44a6-source/pkgs/git-2.42.0/default.nix:12"; }; name = "git-2.42.0"; out = { outPath = "/nix/store/z3pn76749npzbf42kqmkcn5ay0ga2anm-git-2.42.0"; }; doc = { outPath = "/nix/store/1ha4zz8ahkv4ivjh17gvp83x8cd7pzyc-git-2.42.0-doc"; }; outPath = "/nix/store/z3pn76749npzbf42kqmkcn5ay0ga2anm-git-2.42.0"; outputs = [ "out" "doc" ]; system = "x86_64-linux"; type = "derivation"; } ]
\ No newline at end of file
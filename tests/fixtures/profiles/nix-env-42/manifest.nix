@@ -0,0 +1 @@
+[ { meta = { available = true; broken = false; description = "A program that produces a familiar, friendly greeting"; license = { deprecated = false; free = true; fullName = "MIT License"; shortName = "mit"; spdxId = "MIT"; }; maintainers = [ { email = "someone@example.org"; github = "someone"; name = "Some One"; } ]; name = "hello-2.12.1"; outputsToInstall = [ "out" ]; position = "/nix/store/563231inw6p0fbr4ab4a9grwkczx44a6-source/pkgs/hello-2.12.1/default.nix:12"; }; name = "hello-2.12.1"; out = { outPath = "/nix/store/93mgx715c1f61jldzm69mhddlxchcr2n-hello-2.12.1"; }; outPath = "/nix/store/93mgx715c1f61jldzm69mhddlxchcr2n-hello-2.12.1"; outputs = [ "out" ]; system = "x86_64-linux"; type = "derivation"; } { meta = { available = true; broken = false; description = "A utility that combines the usability of The Silver Searcher with the raw speed of grep"; license = { deprecated = false; free = true; fullName = "MIT License"; shortName = "mit"; spdxId = "MIT"; }; maintainers = [ { email = "someone@example.org"; github = "someone"; name = "Some One"; } ]; name = "ripgrep-14.1.0"; outputsToInstall = [ "out" ]; position = "/nix/store/563231inw6p0fbr4ab4a9grwkczx44a6-source/pkgs/ripgrep-14.1.0/default.nix:12"; }; name = "ripgrep-14.1.0"; out = { outPath = "/nix/store/6hayfqfr3r4sd579vxzpadf8mnkk4rwa-ripgrep-14.1.0"; }; outPath = "/nix/store/6hayfqfr3r4sd579vxzpadf8mnkk4rwa-ripgrep-14.1.0"; outputs = [ "out" ]; system = "x86_64-linux"; type = "derivation"; } { meta = { available = true; broken = false; description = "Distributed version control system"; license = { deprecated = false; free = true; fullName = "MIT License"; shortName = "mit"; spdxId = "MIT"; }; maintainers = [ { email = "someone@example.org"; github = "someone"; name = "Some One"; } ]; name = "git-2.41.0"; outputsToInstall = [ "out" ]; position = "/nix/store/563231inw6p0fbr4ab4a9grwkczx44a6-source/pkgs/git-2.41.0/default.nix:12"; }; name = "git-2.41.0"; out = { outPath = "/nix/store/rmpmxmsld32gir6a4q9b3pj84li4x0pp-git-2.41.0"; }; doc = { outPath = "/nix/store/0swac95i90zh4fqlp2mmn7qj8lr2crbf-git-2.41.0-doc"; }; outPath = "/nix/store/rmpmxmsld32gir6a4q9b3pj84li4x0pp-git-2.41.0"; outputs = [ "out" "doc" ]; system = "x86_64-linux"; type = "derivation"; } { meta = { available = true; broken = false; description = "A simple, fast and user-friendly alternative to find"; license = { deprecated = false; free = true; fullName = "MIT License"; shortName = "mit"; spdxId = "MIT"; }; maintainers = [ { email = "someone@example.org"; github = "someone"; name = "Some One"; } ]; name = "fd-9.0.0"; outputsToInstall = [ "out" ]; position = "/nix/store/563231inw6p0fbr4ab4a9grwkczx44a6-source/pkgs/fd-9.0.0/default.nix:12"; }; name = "fd-9.0.0"; out = { outPath = "/nix/store/43shr5c4i624d7d42m0j2s4jjpb32rk0-fd-9.0.0"; }; outPath = "/nix/store/43shr5c4i624d7d42m0j2s4jjpb32rk0-fd-9.0.0"; outputs = [ "out" ]; system = "x86_64-linux"; type = "derivation"; } ]
\ No newline at end of file
@@ -0,0 +1,33 @@
+#!/bin/sh
+set -eu
+export PATH=$coreutils/bin:$gnumake/bin:$gcc/bin
+source $stdenv/setup
+
+unpackPhase() {
+  tar xf $src
+  cd hello-2.12
+}
+
+patchPhase() {
+  patch -p1 < $patch1
+  sed -i 's/-Werror -Wall//' Makefile.in
+}
+
+configurePhase() {
+  ./configure --prefix=$out
+}
+
+buildPhase() {
+  make
+}
+
+checkPhase() {
+  make check
+}
+
+installPhase() {
+  make install
+  rm -rf $out/share/info $out/share/man
+}
+
+genericBuild
@@ -0,0 +1,34 @@
+#!/bin/sh
+set -e
+export PATH=$coreutils/bin:$gnumake/bin:$gcc/bin
+source $stdenv/setup
+
+unpackPhase() {
+  tar xf $src
+  cd hello-2.12
+}
+
+patchPhase() {
+  patch -p1 < $patch1
+  patch -p1 < $patch2
+  sed -i 's/-Werror//' Makefile.in
+}
+
+configurePhase() {
+  ./configure --prefix=$out
+}
+
+buildPhase() {
+  make
+}
+
+checkPhase() {
+  make check
+}
+
+installPhase() {
+  make install
+  rm -rf $out/share/info
+}
+
+genericBuild
@@ -0,0 +1,5 @@
+#!/bin/sh
+set -eu
+./configure --prefix=$out --enable-shared
+make -j4
+make install
@@ -0,0 +1,5 @@
+#!/bin/sh
+set -e
+./configure --prefix=$out
+make
+make install
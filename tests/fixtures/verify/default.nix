@@ -0,0 +1,46 @@
+# Derivations for tests/verify_test.rs, only ever instantiated: the
+# output hashes don't have to match anything built.
+rec {
+  # Fixed-output, hashed as a flat file.
+  flat = builtins.derivation {
+    name = "greeting.txt";
+    system = builtins.currentSystem;
+    builder = "/bin/sh";
+    args = [
+      "-c"
+      "echo hello > $out"
+    ];
+    outputHashMode = "flat";
+    outputHashAlgo = "sha256";
+    outputHash = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+  };
+
+  # Fixed-output, hashed as a NAR.
+  recursive = builtins.derivation {
+    name = "greeting";
+    system = builtins.currentSystem;
+    builder = "/bin/sh";
+    args = [
+      "-c"
+      "mkdir $out && echo hello > $out/greeting.txt"
+    ];
+    outputHashMode = "recursive";
+    outputHash = "sha256-WJG1tSLV3whtD/CxEPvZ0hu0/HFjrzTQgoai6Eb2vgM=";
+  };
+
+  # Input-addressed with two outputs, using both of the above.
+  consumer = builtins.derivation {
+    name = "consumer-1.0";
+    system = builtins.currentSystem;
+    builder = "/bin/sh";
+    outputs = [
+      "out"
+      "dev"
+    ];
+    args = [
+      "-c"
+      "cat ${flat} ${recursive}/greeting.txt > $out && touch $dev"
+    ];
+    message = "say \"hi\"\n\tthere\\";
+  };
+}
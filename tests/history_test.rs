@@ -0,0 +1,81 @@
+use std::path::Path;
+use std::process::{Command, Output};
+
+/// Write a minimal derivation named `name` at `version` into `store`.
+fn write_drv(store: &Path, hash: char, version: &str) -> String {
+    let store = store.to_str().unwrap();
+    let path = format!("{store}/{}-hello.drv", hash.to_string().repeat(32));
+    std::fs::write(
+        &path,
+        format!(
+            r#"Derive([("out","{store}/cccccccccccccccccccccccccccccccc-hello","","")],[],[],"x86_64-linux","/bin/sh",["-c","echo {version}"],[("name","hello"),("version","{version}")])"#
+        ),
+    )
+    .unwrap();
+    path
+}
+
+fn nix_diff(state: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_nix-diff"))
+        .args(["--color", "never", "--no-closure-count"])
+        .args(args)
+        .env("XDG_STATE_HOME", state)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn records_lists_and_reruns_comparisons() {
+    let tmp = tempfile::tempdir().unwrap();
+    let store = tmp.path().join("store");
+    std::fs::create_dir_all(&store).unwrap();
+    let state = tmp.path().join("state");
+    let old = write_drv(&store, 'a', "1.0");
+    let new = write_drv(&store, 'b', "2.0");
+
+    let first = nix_diff(&state, &["--input-type", "drv", &old, &new]);
+    assert_eq!(first.status.code(), Some(1), "{first:?}");
+    let history = state.join("nix-diff/history.jsonl");
+    assert_eq!(
+        std::fs::read_to_string(&history).unwrap().lines().count(),
+        1
+    );
+
+    // Not recorded.
+    let second = nix_diff(&state, &["--no-history", "--input-type", "drv", &new, &old]);
+    assert_eq!(second.status.code(), Some(1));
+    assert_eq!(
+        std::fs::read_to_string(&history).unwrap().lines().count(),
+        1
+    );
+
+    let list = nix_diff(&state, &["--history"]);
+    assert!(list.status.success());
+    let list = String::from_utf8(list.stdout).unwrap();
+    assert_eq!(list.lines().count(), 1, "{list}");
+    let line = list.lines().next().unwrap();
+    assert!(line.trim_start().starts_with("1 "), "{line}");
+    assert!(line.contains(&format!("{old} → {new}")), "{line}");
+    assert!(line.contains("1 derivation differs"), "{line}");
+
+    // The recorded options (--color never etc.) come back, and the output
+    // matches the original run.
+    let rerun = Command::new(env!("CARGO_BIN_EXE_nix-diff"))
+        .args(["--rerun", "1"])
+        .env("XDG_STATE_HOME", &state)
+        .output()
+        .unwrap();
+    assert_eq!(rerun.status.code(), Some(1), "{rerun:?}");
+    assert_eq!(
+        String::from_utf8(rerun.stdout).unwrap(),
+        String::from_utf8(first.stdout).unwrap()
+    );
+    assert_eq!(
+        std::fs::read_to_string(&history).unwrap().lines().count(),
+        2
+    );
+
+    let missing = nix_diff(&state, &["--rerun", "5"]);
+    assert_eq!(missing.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&missing.stderr).contains("no history entry 5"));
+}
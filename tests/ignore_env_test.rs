@@ -0,0 +1,90 @@
+//! `--ignore-env` and `--skip-env`: env vars left out of the Environment
+//! section but counted, alongside vars that genuinely changed. The
+//! derivations are put into a fake store, so this runs without Nix.
+
+use nix_diff::testing::NixEnv;
+use serde_json::Value;
+use std::process::Output;
+
+const OLD: &str = "11111111111111111111111111111111-hello.drv";
+const NEW: &str = "22222222222222222222222222222222-hello.drv";
+
+fn drv(out: &str, cores: &str, epoch: &str, version: &str) -> String {
+    format!(
+        r#"Derive([("out","@storeDir@/{out}-hello","","")],[],[],"x86_64-linux","/bin/sh",["-c","true"],[("NIX_BUILD_CORES","{cores}"),("NIX_LDFLAGS","-lz"),("SOURCE_DATE_EPOCH","{epoch}"),("out","@storeDir@/{out}-hello"),("version","{version}")])"#
+    )
+}
+
+fn nix_diff(args: &[&str]) -> Output {
+    let env = NixEnv::builder()
+        .store_file(
+            OLD,
+            drv("33333333333333333333333333333333", "4", "1", "1.0"),
+        )
+        .store_file(
+            NEW,
+            drv("44444444444444444444444444444444", "8", "2", "1.1"),
+        )
+        .build()
+        .unwrap();
+    env.nix_diff(args)
+        .arg(env.store_path(OLD))
+        .arg(env.store_path(NEW))
+        .output()
+        .unwrap()
+}
+
+fn text(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn matching_vars_are_counted_next_to_real_changes() {
+    let output = nix_diff(&["--ignore-env", "^NIX_", "--ignore-env", "EPOCH$"]);
+    let stdout = text(&output);
+    assert_eq!(output.status.code(), Some(1), "{stdout}");
+    let environment = "Environment:\n  \
+        version:\n    \
+        - 1.[-0-]\n    \
+        + 1.{+1+}\n  \
+        2 environment variables ignored\n";
+    assert!(stdout.contains(environment), "{stdout}");
+    assert!(!stdout.contains("NIX_BUILD_CORES"), "{stdout}");
+    assert!(!stdout.contains("SOURCE_DATE_EPOCH"), "{stdout}");
+
+    let output = nix_diff(&["--ignore-env", "^NIX_", "--format", "json"]);
+    let json: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let keys: Vec<_> = json["env"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|pair| pair[0].as_str().unwrap())
+        .collect();
+    assert_eq!(keys, ["SOURCE_DATE_EPOCH", "out", "version"]);
+    assert_eq!(json["ignored_env"], 1);
+}
+
+#[test]
+fn skip_env_leaves_every_var_out() {
+    let output = nix_diff(&["--skip-env"]);
+    let stdout = text(&output);
+    assert_eq!(output.status.code(), Some(1), "{stdout}");
+    // `out` differs too, but is never shown outside --verbose.
+    assert!(
+        stdout.contains("Environment:\n  4 environment variables ignored\n"),
+        "{stdout}"
+    );
+    assert!(!stdout.contains("version"), "{stdout}");
+}
+
+#[test]
+fn an_invalid_regex_is_a_usage_error() {
+    let output = nix_diff(&["--ignore-env", "NIX_("]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("invalid --ignore-env regex: NIX_("),
+        "{stderr}"
+    );
+    assert!(stderr.contains("unclosed group"), "{stderr}");
+}
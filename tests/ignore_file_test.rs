@@ -0,0 +1,105 @@
+//! Ignore rules from a `.nixdiffignore` found above the current directory,
+//! from `--ignore-file` and from `--ignore`. The fixtures are put into a
+//! fake store, so this runs without Nix.
+
+use nix_diff::testing::NixEnv;
+use serde_json::Value;
+use std::process::Output;
+
+const OLD: &str = "11111111111111111111111111111111-hello.drv";
+const NEW: &str = "22222222222222222222222222222222-hello.drv";
+
+fn drv(out: &str, epoch: &str, version: &str) -> String {
+    format!(
+        r#"Derive([("out","@storeDir@/{out}-hello","","")],[],[],"x86_64-linux","/bin/sh",["-c","true"],[("SOURCE_DATE_EPOCH","{epoch}"),("out","@storeDir@/{out}-hello"),("version","{version}")])"#
+    )
+}
+
+/// Run nix-diff in `project/sub`, below a `.nixdiffignore` holding
+/// `ignore_file` if given.
+fn nix_diff(ignore_file: Option<&str>, args: &[&str]) -> Output {
+    let env = NixEnv::builder()
+        .store_file(OLD, drv("33333333333333333333333333333333", "1", "1.0"))
+        .store_file(NEW, drv("44444444444444444444444444444444", "2", "2.0"))
+        .dir("project/sub")
+        .build()
+        .unwrap();
+    let project = env.root().join("project");
+    if let Some(contents) = ignore_file {
+        std::fs::write(project.join(".nixdiffignore"), contents).unwrap();
+    }
+    env.command(env!("CARGO_BIN_EXE_nix-diff"))
+        .current_dir(project.join("sub"))
+        .args(["--no-history", "--no-invocation-echo"])
+        .args(args)
+        .arg(env.store_path(OLD))
+        .arg(env.store_path(NEW))
+        .output()
+        .unwrap()
+}
+
+fn env_keys(ignore_file: Option<&str>, args: &[&str]) -> Vec<String> {
+    let output = nix_diff(ignore_file, &[&["--format", "json"], args].concat());
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+    let json: Value = serde_json::from_slice(&output.stdout).unwrap();
+    json["env"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|pair| pair[0].as_str().unwrap().to_string())
+        .collect()
+}
+
+const IGNORE_EPOCH: &str = "# Changes on every run\n[env]\nSOURCE_DATE_EPOCH\n";
+
+#[test]
+fn the_nearest_ignore_file_applies() {
+    assert_eq!(env_keys(None, &[]), ["SOURCE_DATE_EPOCH", "out", "version"]);
+    assert_eq!(env_keys(Some(IGNORE_EPOCH), &[]), ["out", "version"]);
+    assert_eq!(
+        env_keys(Some(IGNORE_EPOCH), &["--no-ignore-file"]),
+        ["SOURCE_DATE_EPOCH", "out", "version"]
+    );
+}
+
+#[test]
+fn command_line_patterns_are_added() {
+    assert_eq!(
+        env_keys(Some(IGNORE_EPOCH), &["--ignore", "env:vers*"]),
+        ["out"]
+    );
+    assert_eq!(
+        env_keys(None, &["--ignore", "env:version"]),
+        ["SOURCE_DATE_EPOCH", "out"]
+    );
+}
+
+#[test]
+fn an_explicit_ignore_file_replaces_the_found_one() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("rules");
+    std::fs::write(&path, "[env]\nversion\n").unwrap();
+    let path = path.to_str().unwrap();
+    assert_eq!(
+        env_keys(Some(IGNORE_EPOCH), &["--ignore-file", path]),
+        ["SOURCE_DATE_EPOCH", "out"]
+    );
+
+    let output = nix_diff(Some(IGNORE_EPOCH), &["--dry-run", "--ignore-file", path]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains(&format!("Ignore file: {path}\n")),
+        "{stdout}"
+    );
+}
+
+#[test]
+fn bad_ignore_files_name_the_line() {
+    let output = nix_diff(Some("[env]\nSOURCE_DATE_EPOCH\n\n[environment]\nx\n"), &[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{stderr}");
+    assert!(
+        stderr.contains("/project/.nixdiffignore:4: Unknown ignore section: environment"),
+        "{stderr}"
+    );
+}
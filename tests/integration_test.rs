@@ -1,8 +1,7 @@
 use std::path::PathBuf;
 use std::process::Command;
 
-mod common;
-use common::setup_nix_env;
+use nix_diff::testing::NixEnv;
 
 fn assert_diff_output(output: &str) {
     assert!(output.contains("hello-v1"));
@@ -16,12 +15,13 @@ fn assert_diff_output(output: &str) {
 #[test]
 fn test_basic_derivation_diff() {
     let tests_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
-    let (_nix_root, env_vars) = setup_nix_env();
+    let nix = NixEnv::new().unwrap();
+    let env_vars = nix.vars();
 
     // Generate derivations
     let mut cmd1 = Command::new("nix-instantiate");
     cmd1.arg(tests_dir.join("hello-flake-v1/default.nix"));
-    for (key, value) in &env_vars {
+    for (key, value) in env_vars {
         cmd1.env(key, value);
     }
     let output1 = cmd1
@@ -30,7 +30,7 @@ fn test_basic_derivation_diff() {
 
     let mut cmd2 = Command::new("nix-instantiate");
     cmd2.arg(tests_dir.join("hello-flake-v2/default.nix"));
-    for (key, value) in &env_vars {
+    for (key, value) in env_vars {
         cmd2.env(key, value);
     }
     let output2 = cmd2
@@ -43,7 +43,7 @@ fn test_basic_derivation_diff() {
     // Run nix-diff
     let mut cmd = Command::new(env!("CARGO_BIN_EXE_nix-diff"));
     cmd.args([&drv1, &drv2]).env("NO_COLOR", "1");
-    for (key, value) in &env_vars {
+    for (key, value) in env_vars {
         cmd.env(key, value);
     }
     let output = cmd.output().expect("Failed to run nix-diff");
@@ -55,7 +55,8 @@ fn test_basic_derivation_diff() {
 #[test]
 fn test_nix_file_diff() {
     let tests_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
-    let (_nix_root, env_vars) = setup_nix_env();
+    let nix = NixEnv::new().unwrap();
+    let env_vars = nix.vars();
 
     // Run nix-diff directly on .nix files
     let mut cmd = Command::new(env!("CARGO_BIN_EXE_nix-diff"));
@@ -70,7 +71,7 @@ fn test_nix_file_diff() {
             .unwrap(),
     ])
     .env("NO_COLOR", "1");
-    for (key, value) in &env_vars {
+    for (key, value) in env_vars {
         cmd.env(key, value);
     }
     let output = cmd.output().expect("Failed to run nix-diff");
@@ -82,7 +83,8 @@ fn test_nix_file_diff() {
 #[test]
 fn test_flake_diff() {
     let tests_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
-    let (_nix_root, env_vars) = setup_nix_env();
+    let nix = NixEnv::new().unwrap();
+    let env_vars = nix.vars();
 
     // Get current system
     let mut cmd = Command::new("nix");
@@ -94,7 +96,7 @@ fn test_flake_diff() {
         "--expr",
         "builtins.currentSystem",
     ]);
-    for (key, value) in &env_vars {
+    for (key, value) in env_vars {
         cmd.env(key, value);
     }
     let system_output = cmd.output().expect("Failed to get current system");
@@ -123,7 +125,7 @@ fn test_flake_diff() {
         ),
     ])
     .env("NO_COLOR", "1");
-    for (key, value) in &env_vars {
+    for (key, value) in env_vars {
         cmd.env(key, value);
     }
     let output = cmd.output().expect("Failed to run nix-diff");
@@ -141,3 +143,117 @@ fn test_flake_diff() {
 
     assert_diff_output(&stdout);
 }
+
+/// Names and nested diffs of the changed inputs in `--format json` output.
+fn changed_inputs(diff: &serde_json::Value) -> Vec<(String, serde_json::Value)> {
+    diff["inputs"]["changed"]
+        .as_array()
+        .unwrap_or_else(|| panic!("no changed inputs: {diff}"))
+        .iter()
+        .map(|input| {
+            let name = input["path"].as_str().unwrap().to_string();
+            (name, input["derivation"].clone())
+        })
+        .collect()
+}
+
+#[test]
+fn test_changed_dependencies_are_diffed() {
+    // dep1 and dep2 get new store paths between v1 and v2; they must be
+    // paired by name and compared, not listed as added and removed.
+    let tests_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let nix = NixEnv::new().unwrap();
+    let instantiate = |dir: &str| {
+        let output = nix
+            .command("nix-instantiate")
+            .arg(tests_dir.join(dir).join("default.nix"))
+            .output()
+            .expect("Failed to run nix-instantiate");
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    };
+    let (drv1, drv2) = (instantiate("hello-flake-v1"), instantiate("hello-flake-v2"));
+    let output = nix
+        .command(env!("CARGO_BIN_EXE_nix-diff"))
+        .args(["--format", "json", &drv1, &drv2])
+        .output()
+        .expect("Failed to run nix-diff");
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let diff: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(diff["inputs"]["added"], serde_json::json!([]));
+    assert_eq!(diff["inputs"]["removed"], serde_json::json!([]));
+    let changed = changed_inputs(&diff);
+    let names: Vec<&str> = changed.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["dep1.drv", "dep2.drv"]);
+    for (name, derivation) in &changed {
+        let args = derivation["args"].to_string();
+        assert!(
+            args.contains("Dependency 1 updated") || args.contains("Shared data v2"),
+            "{name}: {args}"
+        );
+    }
+}
+
+#[test]
+fn changed_inputs_are_diffed_recursively() {
+    // The same without Nix: top → lib, where lib's flags changed.
+    let drv = |name: &str, lib: Option<&str>, flags: &str| {
+        let inputs = lib.map_or(String::new(), |lib| {
+            format!(r#"("@storeDir@/{lib}",["out"])"#)
+        });
+        format!(
+            r#"Derive([("out","@storeDir@/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-{name}","","")],[{inputs}],[],"x86_64-linux","/bin/sh",[],[("flags","{flags}"),("name","{name}")])"#
+        )
+    };
+    let nix = NixEnv::builder()
+        .store_file(
+            "11111111111111111111111111111111-lib.drv",
+            drv("lib", None, "-O2"),
+        )
+        .store_file(
+            "22222222222222222222222222222222-lib.drv",
+            drv("lib", None, "-O3"),
+        )
+        .store_file(
+            "33333333333333333333333333333333-top.drv",
+            drv("top", Some("11111111111111111111111111111111-lib.drv"), ""),
+        )
+        .store_file(
+            "44444444444444444444444444444444-top.drv",
+            drv("top", Some("22222222222222222222222222222222-lib.drv"), ""),
+        )
+        .build()
+        .unwrap();
+    let output = nix
+        .command(env!("CARGO_BIN_EXE_nix-diff"))
+        .args(["--format", "json", "--no-history"])
+        .arg(nix.store_path("33333333333333333333333333333333-top.drv"))
+        .arg(nix.store_path("44444444444444444444444444444444-top.drv"))
+        .output()
+        .unwrap();
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let diff: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(diff["inputs"]["added"], serde_json::json!([]));
+    assert_eq!(diff["inputs"]["removed"], serde_json::json!([]));
+    let changed = changed_inputs(&diff);
+    assert_eq!(changed.len(), 1, "{diff}");
+    let (name, lib) = &changed[0];
+    assert_eq!(name, "lib.drv");
+    let flags = lib["env"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|pair| pair[0] == "flags")
+        .unwrap_or_else(|| panic!("{lib}"));
+    assert_eq!(flags[1]["Changed"]["old"], "-O2");
+    assert_eq!(flags[1]["Changed"]["new"], "-O3");
+}
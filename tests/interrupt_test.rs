@@ -0,0 +1,74 @@
+#![cfg(unix)]
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+fn wait_for(what: &str, mut cond: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !cond() {
+        assert!(Instant::now() < deadline, "timed out waiting for {what}");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn pid_alive(pid: &str) -> bool {
+    // A killed child lingers as a zombie until reaped, which `kill -0`
+    // still reports as alive; prefer /proc where we have it.
+    if Path::new("/proc/self").exists() {
+        return std::fs::read_to_string(format!("/proc/{pid}/stat"))
+            .is_ok_and(|stat| !stat.contains(") Z "));
+    }
+    Command::new("kill")
+        .args(["-0", pid])
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+#[test]
+fn sigterm_kills_children_and_exits_130() {
+    // SIGTERM, unlike a terminal Ctrl-C, is only delivered to nix-diff
+    // itself; a hanging nix-instantiate must not survive it.
+    let tmp = tempfile::tempdir().unwrap();
+    let pid_file = tmp.path().join("child.pid");
+    let fake = tmp.path().join("nix-instantiate");
+    std::fs::write(
+        &fake,
+        format!(
+            "#!/bin/sh\necho $$ > {}\nexec sleep 60\n",
+            pid_file.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&fake, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let path = format!(
+        "{}:{}",
+        tmp.path().display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+    let nix_file = tmp.path().join("a.nix");
+    std::fs::write(&nix_file, "{}").unwrap();
+    let mut nix_diff = Command::new(env!("CARGO_BIN_EXE_nix-diff"))
+        .args([&nix_file, &nix_file])
+        .env("PATH", path)
+        .spawn()
+        .unwrap();
+
+    wait_for("fake nix-instantiate", || {
+        std::fs::read_to_string(&pid_file).is_ok_and(|s| s.ends_with('\n'))
+    });
+    let child_pid = std::fs::read_to_string(&pid_file).unwrap();
+    let child_pid = child_pid.trim();
+    assert!(pid_alive(child_pid));
+
+    Command::new("kill")
+        .args(["-TERM", &nix_diff.id().to_string()])
+        .status()
+        .unwrap();
+    let status = nix_diff.wait().unwrap();
+
+    assert_eq!(status.code(), Some(130));
+    wait_for("child to be killed", || !pid_alive(child_pid));
+}
@@ -0,0 +1,87 @@
+//! `--from-json-closure` on fixture dumps of a three-derivation closure
+//! (viewer → libpng → zlib); runs without Nix.
+
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn fixture(name: &str) -> String {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/json-closure")
+        .join(name)
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn nix_diff(args: &[&str]) -> Output {
+    let state = tempfile::tempdir().unwrap();
+    Command::new(env!("CARGO_BIN_EXE_nix-diff"))
+        .args(["--color", "never", "--no-history", "--from-json-closure"])
+        .args(args)
+        .env("NIX_STORE_DIR", "/nix/store")
+        .env("XDG_STATE_HOME", state.path())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn diffs_the_whole_closure_offline() {
+    let output = nix_diff(&[&fixture("old.json"), &fixture("new.json")]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{stdout}\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(
+        stdout.starts_with(
+            "--- /nix/store/55555555555555555555555555555555-viewer-2.0.drv\n\
+             +++ /nix/store/66666666666666666666666666666666-viewer-2.0.drv\n"
+        ),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains("closure size: 3 → 3 derivations"),
+        "{stdout}"
+    );
+    // The change is two levels down, in zlib.
+    assert!(stdout.contains("• libpng-1.6.43.drv"), "{stdout}");
+    assert!(stdout.contains("  • zlib-1.3.drv"), "{stdout}");
+    assert!(
+        stdout.contains("configureFlags:\n        + --static"),
+        "{stdout}"
+    );
+    // Sources are not in the dumps.
+    assert!(stdout.contains("(contents not compared)"), "{stdout}");
+}
+
+#[test]
+fn root_can_be_given_explicitly() {
+    let libpng_old = "/nix/store/33333333333333333333333333333333-libpng-1.6.43.drv";
+    let libpng_new = "/nix/store/44444444444444444444444444444444-libpng-1.6.43.drv";
+    let output = nix_diff(&[
+        "--root",
+        libpng_old,
+        "--root",
+        libpng_new,
+        &fixture("old.json"),
+        &fixture("new.json"),
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{stdout}");
+    assert!(
+        stdout.starts_with(&format!("--- {libpng_old}\n+++ {libpng_new}\n")),
+        "{stdout}"
+    );
+    assert!(!stdout.contains("viewer"), "{stdout}");
+
+    let output = nix_diff(&[
+        "--root",
+        "/nix/store/x-missing.drv",
+        &fixture("old.json"),
+        &fixture("new.json"),
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{stderr}");
+    assert!(stderr.contains("none of the --root paths"), "{stderr}");
+}
@@ -0,0 +1,67 @@
+//! `nix derivation show` output for one derivation as an input, compared
+//! with a .drv file. The derivations are put into a fake store, so this
+//! runs without Nix.
+
+use nix_diff::testing::NixEnv;
+
+const DRV: &str = "11111111111111111111111111111111-hello.drv";
+const LIB: &str = "22222222222222222222222222222222-lib.drv";
+
+fn drv(greeting: &str) -> String {
+    format!(
+        r#"Derive([("out","@storeDir@/33333333333333333333333333333333-hello","","")],[("@storeDir@/{LIB}",["out"])],[],"x86_64-linux","/bin/sh",["-c","true"],[("greeting","{greeting}"),("name","hello"),("out","@storeDir@/33333333333333333333333333333333-hello")])"#
+    )
+}
+
+/// As newer Nix prints it, with store paths relative to the store.
+const JSON: &str = r#"{
+  "11111111111111111111111111111111-hello.drv": {
+    "args": ["-c", "true"],
+    "builder": "/bin/sh",
+    "env": {"greeting": "Hello, world!", "name": "hello", "out": "@storeDir@/33333333333333333333333333333333-hello"},
+    "inputDrvs": {"22222222222222222222222222222222-lib.drv": {"dynamicOutputs": {}, "outputs": ["out"]}},
+    "inputSrcs": [],
+    "name": "hello",
+    "outputs": {"out": {"path": "33333333333333333333333333333333-hello"}},
+    "system": "x86_64-linux",
+    "version": 3
+  }
+}"#;
+
+#[test]
+fn json_is_compared_with_a_drv_file() {
+    let env = NixEnv::builder()
+        .store_file(DRV, drv("Hello, world!"))
+        .store_file(
+            LIB,
+            r#"Derive([("out","@storeDir@/44444444444444444444444444444444-lib","","")],[],[],"x86_64-linux","/bin/sh",[],[])"#,
+        )
+        .build()
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let json = dir.path().join("hello.json");
+    let store_dir = env.store_dir().to_string_lossy().into_owned();
+    std::fs::write(&json, JSON.replace("@storeDir@", &store_dir)).unwrap();
+    let nix_diff = |args: &[&std::ffi::OsStr]| env.nix_diff(&[]).args(args).output().unwrap();
+
+    // The same derivation either way.
+    let output = nix_diff(&[json.as_os_str(), env.store_path(DRV).as_os_str()]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "{stdout}{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let changed = env.store_path("55555555555555555555555555555555-hello.drv");
+    std::fs::write(&changed, drv("Hi!").replace("@storeDir@", &store_dir)).unwrap();
+    let output = nix_diff(&[json.as_os_str(), changed.as_os_str()]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{stdout}");
+    assert!(
+        stdout.starts_with(&format!("--- {}\n", json.display())),
+        "{stdout}"
+    );
+    assert!(stdout.contains("greeting:\n"), "{stdout}");
+}
@@ -0,0 +1,192 @@
+//! `--format json` on the `--from-json-closure` fixtures, so the shape is
+//! pinned down without Nix.
+
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn fixture(name: &str) -> String {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/json-closure")
+        .join(name)
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn nix_diff(args: &[&str]) -> Output {
+    nix_diff_of(&fixture("old.json"), &fixture("new.json"), args)
+}
+
+fn nix_diff_of(old: &str, new: &str, args: &[&str]) -> Output {
+    let state = tempfile::tempdir().unwrap();
+    Command::new(env!("CARGO_BIN_EXE_nix-diff"))
+        .args(["--no-history", "--from-json-closure", "--format", "json"])
+        .args(args)
+        .args([old, new])
+        .env("NIX_STORE_DIR", "/nix/store")
+        .env("XDG_STATE_HOME", state.path())
+        .env("HOME", env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn json_diff_of_a_closure() {
+    // The invocation names this checkout and the local Nix.
+    let output = nix_diff(&["--no-invocation-echo"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{stdout}\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    insta::assert_snapshot!(stdout);
+}
+
+#[test]
+fn nested_inputs_and_summarized_vars() {
+    let output = nix_diff(&["--env-detail", "*Phase"]);
+    let diff: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    // viewer → libpng → zlib, which gained configureFlags.
+    let libpng = &diff["inputs"]["changed"][0]["derivation"];
+    let zlib = &libpng["inputs"]["changed"][0]["derivation"];
+    let env = zlib["env"].as_array().unwrap();
+    let flags = env
+        .iter()
+        .find(|pair| pair[0] == "configureFlags")
+        .unwrap_or_else(|| panic!("{}", zlib["env"]));
+    assert_eq!(flags[1]["detail"], "summarized", "{flags}");
+    assert_eq!(flags[1]["Added"], "--static", "{flags}");
+}
+
+#[test]
+fn ndjson_events_of_a_closure() {
+    let output = nix_diff(&["--format", "ndjson", "--no-invocation-echo"]);
+    assert_eq!(output.status.code(), Some(1));
+    let events: Vec<serde_json::Value> = output
+        .stdout
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice(line).unwrap())
+        .collect();
+    assert_eq!(events[0]["event"], "start");
+    assert_eq!(events[0]["version"], 1);
+    let summary = events.last().unwrap();
+    assert_eq!(summary["event"], "summary");
+    assert_eq!(summary["stats"]["env_added"], 2, "{summary}");
+    // viewer → libpng → zlib, which gained configureFlags.
+    let flags = events
+        .iter()
+        .find(|event| event["event"] == "env_changed" && event["key"] == "configureFlags")
+        .unwrap();
+    let path = flags["path"].as_array().unwrap();
+    assert_eq!(path.len(), 2, "{flags}");
+    assert!(path[1].as_str().unwrap().starts_with("zlib"), "{flags}");
+    assert_eq!(flags["change"]["Added"], "--static", "{flags}");
+}
+
+#[test]
+fn invocation_block() {
+    let output = nix_diff(&["-j", "2", "--env-detail", "*Phase"]);
+    let diff: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let invocation = &diff["invocation"];
+    assert_eq!(invocation["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(
+        invocation["options"],
+        serde_json::json!([
+            "--from-json-closure",
+            "--format",
+            "json",
+            "--jobs",
+            "2",
+            "--env-detail",
+            "*Phase"
+        ])
+    );
+    // HOME is the checkout, so the fixtures are below it.
+    assert_eq!(
+        invocation["inputs"],
+        serde_json::json!([
+            "~/tests/fixtures/json-closure/old.json",
+            "~/tests/fixtures/json-closure/new.json"
+        ])
+    );
+    assert_eq!(invocation["store_dir"], "/nix/store");
+    assert!(
+        invocation["nix_version"].is_null() || invocation["nix_version"].is_string(),
+        "{invocation}"
+    );
+
+    let output = nix_diff(&["--no-invocation-echo"]);
+    let diff: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(diff.get("invocation").is_none());
+}
+
+#[test]
+fn truncate_json_applies_max_env_entries() {
+    let zlib_env = |output: &Output, field: &str| -> Vec<serde_json::Value> {
+        let diff: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        let libpng = &diff["inputs"]["changed"][0]["derivation"];
+        let zlib = &libpng["inputs"]["changed"][0]["derivation"];
+        zlib[field].as_array().cloned().unwrap_or_default()
+    };
+    let full = zlib_env(&nix_diff(&["--max-env-entries", "1"]), "env");
+    assert!(full.len() > 1, "{full:?}");
+
+    let output = nix_diff(&["--max-env-entries", "1", "--truncate-json"]);
+    assert_eq!(zlib_env(&output, "env").len(), 1);
+    assert_eq!(zlib_env(&output, "env_truncated").len(), full.len() - 1);
+
+    // 0 is no cap at all.
+    let output = nix_diff(&["--max-env-entries", "0", "--truncate-json"]);
+    assert_eq!(zlib_env(&output, "env"), full);
+
+    let output = nix_diff(&["--format", "ndjson", "--truncate-json"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("--truncate-json only applies to"),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn closure_sizes_of_both_sides() {
+    // The new viewer also depends on bzip2, a fourth derivation.
+    let mut new: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(fixture("new.json")).unwrap()).unwrap();
+    let bzip2 = "/nix/store/77777777777777777777777777777777-bzip2-1.0.8.drv";
+    let mut drv = new["/nix/store/22222222222222222222222222222222-zlib-1.3.drv"].clone();
+    drv["name"] = "bzip2-1.0.8".into();
+    drv["env"] = serde_json::json!({"name": "bzip2-1.0.8"});
+    drv["outputs"]["out"]["path"] =
+        "/nix/store/jjjjjjjjjjjjjjjjjjjjjjjjjjjjjjjj-bzip2-1.0.8".into();
+    new[bzip2] = drv;
+    new["/nix/store/66666666666666666666666666666666-viewer-2.0.drv"]["inputDrvs"][bzip2] =
+        serde_json::json!({"dynamicOutputs": {}, "outputs": ["out"]});
+    let dir = tempfile::tempdir().unwrap();
+    let new_json = dir.path().join("new.json");
+    std::fs::write(&new_json, new.to_string()).unwrap();
+    let new_json = new_json.to_str().unwrap();
+
+    let output = nix_diff_of(&fixture("old.json"), new_json, &[]);
+    let diff: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        diff["closure_size"],
+        serde_json::json!({"old": 3, "new": 4}),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let output = nix_diff_of(&fixture("old.json"), new_json, &["--format", "ndjson"]);
+    let start: serde_json::Value =
+        serde_json::from_slice(output.stdout.split(|&b| b == b'\n').next().unwrap()).unwrap();
+    assert_eq!(
+        start["closure_size"],
+        serde_json::json!({"old": 3, "new": 4})
+    );
+
+    let output = nix_diff_of(&fixture("old.json"), new_json, &["--no-closure-count"]);
+    let diff: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(diff["closure_size"].is_null());
+}
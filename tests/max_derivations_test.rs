@@ -0,0 +1,73 @@
+//! `--max-derivations`: past the cap, changed inputs are named but not
+//! compared, with a warning. The derivations are put into a fake store,
+//! so this runs without Nix.
+
+use nix_diff::testing::NixEnv;
+use serde_json::Value;
+
+const OLD: &str = "11111111111111111111111111111111-app.drv";
+const NEW: &str = "22222222222222222222222222222222-app.drv";
+const DEPS: [&str; 3] = ["libfoo", "libbar", "libbaz"];
+
+fn store() -> NixEnv {
+    let mut builder = NixEnv::builder();
+    for (hash, version) in [('3', "1"), ('4', "2")] {
+        let mut deps = Vec::new();
+        for name in DEPS {
+            let file = format!("{}-{name}.drv", hash.to_string().repeat(32));
+            builder = builder.store_file(&file, NixEnv::drv(name, hash, version, &[]));
+            deps.push(file);
+        }
+        let (root, root_hash) = if version == "1" {
+            (OLD, '5')
+        } else {
+            (NEW, '6')
+        };
+        builder = builder.store_file(root, NixEnv::drv("app", root_hash, version, &deps));
+    }
+    builder.build().unwrap()
+}
+
+#[test]
+fn inputs_past_the_cap_are_listed_with_a_warning() {
+    let env = store();
+    let (old, new) = (env.store_path(OLD), env.store_path(NEW));
+    let output = env
+        .nix_diff(&["--max-derivations", "2"])
+        .args([&old, &new])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{stdout}{stderr}");
+    assert!(
+        stderr.contains(
+            "warning: stopped comparing after loading 2 input derivations \
+             (--max-derivations); 2 changed input(s) were not compared"
+        ),
+        "{stderr}"
+    );
+    assert!(stdout.contains("2 inputs not compared"), "{stdout}");
+    let listed = "2 changed inputs not compared (--max-derivations reached)\n  \
+        • libbaz.drv\n  \
+        • libfoo.drv\n";
+    assert!(stdout.contains(listed), "{stdout}");
+
+    // The input compared within the cap is the same as without one.
+    let json = |args: &[&str]| -> Value {
+        let output = env
+            .nix_diff(&["--format", "json"])
+            .args(args)
+            .args([&old, &new])
+            .output()
+            .unwrap();
+        serde_json::from_slice(&output.stdout).unwrap()
+    };
+    let (capped, full) = (json(&["--max-derivations", "2"]), json(&[]));
+    assert_eq!(capped["inputs"]["changed"][0], full["inputs"]["changed"][0]);
+    assert_eq!(
+        capped["inputs"]["not_compared"].as_array().unwrap().len(),
+        2
+    );
+    assert!(full["inputs"].get("not_compared").is_none());
+}
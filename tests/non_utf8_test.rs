@@ -0,0 +1,60 @@
+//! Derivations with env values that are not UTF-8, as `builtins.toFile`
+//! can make, are compared byte for byte. Uses the `--dump-parsed` fixture
+//! in a fake store, so this runs without Nix.
+
+use nix_diff::testing::NixEnv;
+
+const OLD: &str = "11111111111111111111111111111111-cafe.drv";
+const NEW: &str = "22222222222222222222222222222222-cafe.drv";
+
+#[test]
+fn env_values_that_are_not_utf8_are_diffed() {
+    let fixture = std::fs::read(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/dump-parsed/cafe.drv"
+    ))
+    .unwrap();
+    // Latin-1 "é" in the greeting becomes "è"; the name keeps its "é".
+    let greeting = b"caf\xe9 \\\"ol\xe9";
+    let at = fixture
+        .windows(greeting.len())
+        .position(|w| w == greeting)
+        .unwrap();
+    let mut changed = fixture.clone();
+    changed[at + 3] = 0xe8;
+    let env = NixEnv::builder()
+        .store_file(OLD, fixture)
+        .store_file(NEW, changed)
+        .build()
+        .unwrap();
+    let nix_diff = |new: &str| {
+        env.nix_diff(&[])
+            .arg(env.store_path(OLD))
+            .arg(env.store_path(new))
+            .output()
+            .unwrap()
+    };
+
+    let output = nix_diff(OLD);
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let output = nix_diff(NEW);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{stdout}{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("Environment:\n  greeting: "), "{stdout}");
+    assert!(!stdout.contains("  name:"), "{stdout}");
+    // The values are written out as they are, not lossily decoded.
+    let has = |line: &[u8]| output.stdout.windows(line.len()).any(|w| w == line);
+    assert!(has(b"    - caf\xe9 \"ol\xe9\"\n"), "{stdout}");
+    assert!(has(b"    + caf\xe8 \"ol\xe9\"\n"), "{stdout}");
+}
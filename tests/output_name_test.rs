@@ -0,0 +1,124 @@
+//! `--output-name`: a multi-output derivation that gained a `doc` output,
+//! compared with and without restricting the diff to `out`. The fixtures
+//! are put into a fake store, so this runs without Nix.
+
+use nix_diff::testing::NixEnv;
+use serde_json::Value;
+use std::process::Output;
+
+const OLD: &str = "11111111111111111111111111111111-hello.drv";
+const NEW: &str = "22222222222222222222222222222222-hello.drv";
+
+/// A derivation with an output per `(name, hash)` and the env vars that
+/// mirror them.
+fn drv(outputs: &[(&str, &str)]) -> String {
+    let path = |name: &str, hash: &str| format!("@storeDir@/{hash}-hello-{name}");
+    let decls: Vec<_> = outputs
+        .iter()
+        .map(|(name, hash)| format!(r#"("{name}","{}","","")"#, path(name, hash)))
+        .collect();
+    let names: Vec<_> = outputs.iter().map(|(name, _)| *name).collect();
+    let mut env: Vec<_> = outputs
+        .iter()
+        .map(|(name, hash)| (name.to_string(), path(name, hash)))
+        .chain([("outputs".to_string(), names.join(" "))])
+        .collect();
+    env.sort();
+    let env: Vec<_> = env
+        .iter()
+        .map(|(key, value)| format!(r#"("{key}","{value}")"#))
+        .collect();
+    format!(
+        r#"Derive([{}],[],[],"x86_64-linux","/bin/sh",["-c","true"],[{}])"#,
+        decls.join(","),
+        env.join(",")
+    )
+}
+
+fn nix_diff(args: &[&str]) -> Output {
+    let old = drv(&[
+        ("man", "33333333333333333333333333333333"),
+        ("out", "44444444444444444444444444444444"),
+    ]);
+    let new = drv(&[
+        ("doc", "55555555555555555555555555555555"),
+        ("man", "66666666666666666666666666666666"),
+        ("out", "77777777777777777777777777777777"),
+    ]);
+    let env = NixEnv::builder()
+        .store_file(OLD, old)
+        .store_file(NEW, new)
+        .build()
+        .unwrap();
+    env.nix_diff(args)
+        .arg(env.store_path(OLD))
+        .arg(env.store_path(NEW))
+        .output()
+        .unwrap()
+}
+
+fn json(args: &[&str]) -> Value {
+    let output = nix_diff(&[&["--format", "json", "--no-invocation-echo"], args].concat());
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+fn output_names(json: &Value) -> Vec<&str> {
+    json["outputs"]["Changed"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|output| output["name"].as_str().unwrap())
+        .collect()
+}
+
+fn env_keys(json: &Value) -> Vec<&str> {
+    json["env"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|pair| pair[0].as_str().unwrap())
+        .collect()
+}
+
+#[test]
+fn every_output_is_compared_by_default() {
+    let json = json(&[]);
+    assert_eq!(output_names(&json), ["doc", "man", "out"]);
+    assert_eq!(env_keys(&json), ["doc", "man", "out", "outputs"]);
+    assert!(json["output_names"].is_null(), "{json}");
+}
+
+#[test]
+fn output_name_restricts_outputs_and_their_vars() {
+    let json = json(&["--output-name", "out"]);
+    assert_eq!(output_names(&json), ["out"]);
+    // The list of outputs is not the path of one, so it stays.
+    assert_eq!(env_keys(&json), ["out", "outputs"]);
+    assert_eq!(json["output_names"], serde_json::json!(["out"]));
+
+    let json = self::json(&["--output-name", "doc", "--output-name", "out"]);
+    assert_eq!(output_names(&json), ["doc", "out"]);
+    assert_eq!(env_keys(&json), ["doc", "out", "outputs"]);
+}
+
+#[test]
+fn text_output_lists_only_the_selected_outputs() {
+    let output = nix_diff(&["--verbose", "--output-name", "man"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{stdout}");
+    assert!(stdout.contains("-man"), "{stdout}");
+    assert!(!stdout.contains("-doc"), "{stdout}");
+    assert!(!stdout.contains("hello-out"), "{stdout}");
+}
+
+#[test]
+fn unknown_output_names_are_errors() {
+    let output = nix_diff(&["--output-name", "dev"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{stderr}");
+    assert!(
+        stderr.contains("neither derivation has an output called dev"),
+        "{stderr}"
+    );
+}
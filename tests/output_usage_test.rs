@@ -0,0 +1,71 @@
+//! Changes to the outputs a derivation uses of an input: starting to use
+//! an output the input always had, and switching to an output the input
+//! only just gained. The derivations are put into a fake store, so this
+//! runs without Nix.
+
+use insta::assert_snapshot;
+use nix_diff::testing::NixEnv;
+
+const OLD: &str = "11111111111111111111111111111111-app.drv";
+const NEW: &str = "22222222222222222222222222222222-app.drv";
+/// With `doc` and `out` outputs.
+const LIB: &str = "33333333333333333333333333333333-lib.drv";
+/// With `dev` and `out` outputs instead.
+const LIB_WITH_DEV: &str = "44444444444444444444444444444444-lib.drv";
+
+fn lib(hash: char, outputs: &[&str]) -> String {
+    let hash = hash.to_string().repeat(32);
+    let outputs: Vec<_> = outputs
+        .iter()
+        .map(|name| format!(r#"("{name}","@storeDir@/{hash}-lib-{name}","","")"#))
+        .collect();
+    format!(
+        r#"Derive([{}],[],[],"x86_64-linux","/bin/sh",["-c","true"],[("name","lib")])"#,
+        outputs.join(",")
+    )
+}
+
+fn app(lib: &str, used: &[&str]) -> String {
+    let used: Vec<_> = used.iter().map(|name| format!(r#""{name}""#)).collect();
+    format!(
+        r#"Derive([("out","@storeDir@/55555555555555555555555555555555-app","","")],[("@storeDir@/{lib}",[{}])],[],"x86_64-linux","/bin/sh",["-c","true"],[("name","app"),("out","@storeDir@/55555555555555555555555555555555-app")])"#,
+        used.join(",")
+    )
+}
+
+fn nix_diff(old: String, new: String) -> String {
+    let env = NixEnv::builder()
+        .store_file(OLD, old)
+        .store_file(NEW, new)
+        .store_file(LIB, lib('6', &["doc", "out"]))
+        .store_file(LIB_WITH_DEV, lib('7', &["dev", "out"]))
+        .build()
+        .unwrap();
+    let output = env
+        .nix_diff(&[])
+        .arg(env.store_path(OLD))
+        .arg(env.store_path(NEW))
+        .output()
+        .unwrap();
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let store = env.store_dir().display().to_string();
+    String::from_utf8_lossy(&output.stdout).replace(&store, "/nix/store")
+}
+
+#[test]
+fn another_output_of_the_same_input_is_used() {
+    assert_snapshot!(nix_diff(app(LIB, &["out"]), app(LIB, &["doc", "out"])));
+}
+
+#[test]
+fn the_input_gained_the_output_now_used() {
+    assert_snapshot!(nix_diff(
+        app(LIB, &["doc", "out"]),
+        app(LIB_WITH_DEV, &["dev", "out"])
+    ));
+}
@@ -0,0 +1,79 @@
+//! `--profile-diff` on checked-in `nix profile` and `nix-env` manifests.
+
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn fixture(name: &str) -> String {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/profiles")
+        .join(name)
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn nix_diff(args: &[&str]) -> Output {
+    let state = tempfile::tempdir().unwrap();
+    Command::new(env!("CARGO_BIN_EXE_nix-diff"))
+        .args(["--color", "never", "--no-history", "--profile-diff"])
+        .args(args)
+        .env("XDG_STATE_HOME", state.path())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn diffs_nix_env_generations() {
+    let (old, new) = (fixture("nix-env-41"), fixture("nix-env-42"));
+    let output = nix_diff(&[&old, &new]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{stdout}\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        stdout,
+        format!(
+            "\
+--- {old}
++++ {new}
+Packages: 1 added, 1 removed, 1 upgraded, 1 downgraded, 1 rebuilt
+  + fd       9.0.0
+  - htop     3.2.2
+  ~ git      2.42.0 → 2.41.0 (downgrade)
+  ~ hello    2.12.1 (rebuilt)
+  ~ ripgrep  14.0.3 → 14.1.0
+"
+        )
+    );
+}
+
+#[test]
+fn diffs_nix_profile_manifests() {
+    let output = nix_diff(&[
+        &fixture("profile-1/manifest.json"),
+        &fixture("profile-2/manifest.json"),
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{stdout}");
+    assert!(stdout.contains("+ neovim   0.9.5\n"), "{stdout}");
+    assert!(stdout.contains("~ firefox  121.0 → 122.0\n"), "{stdout}");
+    // jq's outputs are one package, and unchanged.
+    assert!(!stdout.contains("jq"), "{stdout}");
+
+    let same = nix_diff(&[&fixture("profile-1"), &fixture("profile-1")]);
+    assert_eq!(same.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&same.stdout),
+        "The profiles have the same packages.\n"
+    );
+}
+
+#[test]
+fn rejects_directories_without_a_manifest() {
+    let output = nix_diff(&[&fixture("."), &fixture("profile-1")]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{stderr}");
+    assert!(stderr.contains("is not a profile"), "{stderr}");
+}
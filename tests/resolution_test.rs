@@ -0,0 +1,196 @@
+//! How inputs of different kinds are resolved, and the note when they
+//! resolve to unrelated derivations. `nix-store`, `nix-instantiate` and
+//! `nix` are replaced by scripts that resolve everything to the fixture
+//! derivations below.
+#![cfg(unix)]
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Command, Output};
+
+const HELLO: &str = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.12.drv";
+const HELLO_OUT: &str = "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-hello-2.12";
+const HELLO_MAN: &str = "cccccccccccccccccccccccccccccccc-hello-2.12-man";
+const WORLD: &str = "dddddddddddddddddddddddddddddddd-world-1.0.drv";
+const WORLD_OUT: &str = "eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-world-1.0";
+
+struct Env {
+    dir: tempfile::TempDir,
+}
+
+impl Env {
+    fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let env = Self { dir };
+        let store = env.store();
+        std::fs::create_dir_all(store.join(HELLO_OUT)).unwrap();
+        std::fs::create_dir_all(store.join(HELLO_MAN)).unwrap();
+        let s = |name: &str| store.join(name).to_string_lossy().into_owned();
+        std::fs::write(
+            store.join(HELLO),
+            format!(
+                r#"Derive([("man","{}","",""),("out","{}","","")],[],[],"x86_64-linux","/bin/sh",[],[("name","hello-2.12")])"#,
+                s(HELLO_MAN),
+                s(HELLO_OUT)
+            ),
+        )
+        .unwrap();
+        std::fs::write(
+            store.join(WORLD),
+            format!(
+                r#"Derive([("out","{}","","")],[],[],"x86_64-linux","/bin/sh",[],[("name","world-1.0")])"#,
+                s(WORLD_OUT)
+            ),
+        )
+        .unwrap();
+        std::fs::write(env.dir.path().join("world.nix"), "{}").unwrap();
+
+        // Every store path's deriver is hello; everything evaluates to world.
+        let bin = env.dir.path().join("bin");
+        std::fs::create_dir(&bin).unwrap();
+        env.script(&bin, "nix-store", &format!("echo {}", s(HELLO)));
+        env.script(
+            &bin,
+            "nix-instantiate",
+            &format!(
+                "while [ \"$1\" != --add-root ]; do shift; done\nln -s {} \"$2\"\necho \"$2\"",
+                s(WORLD)
+            ),
+        );
+        env.script(
+            &bin,
+            "nix",
+            &format!(
+                r#"echo '{{"path":"{}","locked":{{"narHash":"sha256-AAAA"}}}}'"#,
+                s("ffffffffffffffffffffffffffffffff-source")
+            ),
+        );
+        env
+    }
+
+    fn script(&self, bin: &Path, name: &str, body: &str) {
+        let path = bin.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    fn store(&self) -> std::path::PathBuf {
+        self.dir.path().join("store")
+    }
+
+    fn path(&self, name: &str) -> String {
+        self.store().join(name).to_string_lossy().into_owned()
+    }
+
+    fn nix_diff(&self, args: &[&str]) -> (Output, String) {
+        let path = format!(
+            "{}:{}",
+            self.dir.path().join("bin").display(),
+            std::env::var("PATH").unwrap_or_default()
+        );
+        let output = Command::new(env!("CARGO_BIN_EXE_nix-diff"))
+            .args(["--color", "never", "--no-history", "--no-closure-count"])
+            .args(args)
+            .env("NIX_STORE_DIR", self.store())
+            .env("PATH", path)
+            .env("XDG_STATE_HOME", self.dir.path())
+            .current_dir(self.dir.path())
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        assert!(
+            matches!(output.status.code(), Some(0 | 1)),
+            "{stdout}\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        (output, stdout)
+    }
+}
+
+const NOTE: &str = "Note: the inputs are of different kinds and their derivations share no \
+                    outputs; they were resolved as:\n";
+
+#[test]
+fn store_path_and_drv() {
+    let env = Env::new();
+    std::os::unix::fs::symlink(env.path(HELLO_MAN), env.dir.path().join("result-man")).unwrap();
+    let (_, stdout) = env.nix_diff(&["./result-man", &env.path(WORLD)]);
+    assert!(
+        stdout.contains(&format!(
+            "{NOTE}  old: ./result-man (store) → {} → output man of {}\n  new: {} (drv)\n",
+            env.path(HELLO_MAN),
+            env.path(HELLO),
+            env.path(WORLD)
+        )),
+        "{stdout}"
+    );
+}
+
+#[test]
+fn store_path_and_nix_file() {
+    let env = Env::new();
+    let (_, stdout) = env.nix_diff(&[&env.path(HELLO_OUT), "world.nix"]);
+    assert!(
+        stdout.contains(&format!(
+            "{NOTE}  old: {} (store) → output out of {}\n  new: world.nix (nix) → {}\n",
+            env.path(HELLO_OUT),
+            env.path(HELLO),
+            env.path(WORLD)
+        )),
+        "{stdout}"
+    );
+}
+
+#[test]
+fn drv_and_flake() {
+    let env = Env::new();
+    let (_, stdout) = env.nix_diff(&[&env.path(HELLO), "github:example/world#world"]);
+    assert!(
+        stdout.contains(&format!(
+            "{NOTE}  old: {} (drv)\n  new: github:example/world#world (flake) → \
+             path:{}?narHash=sha256-AAAA#world → {}\n",
+            env.path(HELLO),
+            env.path("ffffffffffffffffffffffffffffffff-source"),
+            env.path(WORLD)
+        )),
+        "{stdout}"
+    );
+}
+
+#[test]
+fn related_or_same_kind_inputs_get_no_note() {
+    let env = Env::new();
+    // The store path is an output of the .drv: nothing to explain.
+    let (output, stdout) = env.nix_diff(&[&env.path(HELLO_MAN), &env.path(HELLO)]);
+    assert_eq!(output.status.code(), Some(0), "{stdout}");
+    assert_eq!(stdout, "The derivations are identical.\n");
+    // Two .drv files are compared as given.
+    let (output, stdout) = env.nix_diff(&[&env.path(HELLO), &env.path(WORLD)]);
+    assert_eq!(output.status.code(), Some(1), "{stdout}");
+    assert!(!stdout.contains("resolved as"), "{stdout}");
+}
+
+#[test]
+fn trace_resolution_always_shows_the_chain() {
+    let env = Env::new();
+    let (_, stdout) = env.nix_diff(&["--trace-resolution", &env.path(HELLO_MAN), &env.path(HELLO)]);
+    assert_eq!(
+        stdout,
+        format!(
+            "resolved as:\n  old: {} (store) → output man of {}\n  new: {} (drv)\n\
+             The derivations are identical.\n",
+            env.path(HELLO_MAN),
+            env.path(HELLO),
+            env.path(HELLO)
+        )
+    );
+    let (_, stdout) = env.nix_diff(&["--trace-resolution", &env.path(HELLO), &env.path(WORLD)]);
+    assert!(
+        stdout.contains(&format!(
+            "resolved as:\n  old: {} (drv)\n  new: {} (drv)\n",
+            env.path(HELLO),
+            env.path(WORLD)
+        )),
+        "{stdout}"
+    );
+}
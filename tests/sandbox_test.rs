@@ -0,0 +1,98 @@
+//! Sandbox and purity flags: a fetcher that turns off the sandbox with
+//! `__noChroot` and `allowSubstitutes = false`. The fixtures in
+//! `tests/fixtures/sandbox` are put into a fake store, so this runs
+//! without Nix.
+
+use nix_diff::testing::NixEnv;
+use serde_json::Value;
+use std::process::Output;
+
+const OLD: &str = "11111111111111111111111111111111-fetch-1.0.drv";
+const NEW: &str = "22222222222222222222222222222222-fetch-1.0.drv";
+
+fn fixture(name: &str) -> Vec<u8> {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/sandbox");
+    std::fs::read(format!("{dir}/{name}")).unwrap()
+}
+
+fn nix_diff(old: &str, new: &str, args: &[&str]) -> Output {
+    let env = NixEnv::builder()
+        .store_file(OLD, fixture("old.drv"))
+        .store_file(NEW, fixture("new.drv"))
+        .build()
+        .unwrap();
+    env.nix_diff(args)
+        .arg(env.store_path(old))
+        .arg(env.store_path(new))
+        .output()
+        .unwrap()
+}
+
+fn text(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn flags_are_listed_before_the_other_sections() {
+    let output = nix_diff(OLD, NEW, &[]);
+    let stdout = text(&output);
+    assert_eq!(output.status.code(), Some(1), "{stdout}");
+    let flags = "Sandbox/purity flags:\n  \
+        __noChroot: unset → true\n  \
+        allowSubstitutes: unset → false\n";
+    assert!(stdout.contains(flags), "{stdout}");
+    let environment = stdout.find("Environment:").unwrap();
+    assert!(stdout.find(flags).unwrap() < environment, "{stdout}");
+    // Only in their own section.
+    assert!(!stdout[environment..].contains("__noChroot"), "{stdout}");
+    assert!(stdout[environment..].contains("url:"), "{stdout}");
+    assert!(stdout.contains("2 sandbox flags"), "{stdout}");
+
+    let section = nix_diff(OLD, NEW, &["--section", "sandbox"]);
+    assert_eq!(
+        text(&section),
+        "__noChroot: unset → true\nallowSubstitutes: unset → false\n"
+    );
+}
+
+#[test]
+fn fail_on_sandbox() {
+    let fail_on = ["--fail-on", "sandbox"];
+    assert_eq!(nix_diff(OLD, NEW, &fail_on).status.code(), Some(1));
+    // Dropping the flags is a change to them too.
+    assert_eq!(nix_diff(NEW, OLD, &fail_on).status.code(), Some(1));
+    assert_eq!(
+        nix_diff(OLD, NEW, &["--fail-on", "fixed-output-transition"])
+            .status
+            .code(),
+        Some(0)
+    );
+    let output = nix_diff(OLD, NEW, &["--fail-on", "sandboxing"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        String::from_utf8_lossy(&output.stderr)
+            .contains("expected fixed-output-transition or sandbox"),
+        "{output:?}"
+    );
+}
+
+#[test]
+fn json_has_the_flags_outside_env() {
+    let output = nix_diff(OLD, NEW, &["--format", "json", "--no-invocation-echo"]);
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+    let json: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        json["sandbox"],
+        serde_json::json!([
+            {"key": "__noChroot", "old": null, "new": "1"},
+            {"key": "allowSubstitutes", "old": null, "new": ""},
+        ])
+    );
+    let env_keys: Vec<_> = json["env"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|pair| pair[0].as_str().unwrap())
+        .collect();
+    assert!(!env_keys.contains(&"__noChroot"), "{env_keys:?}");
+}
@@ -0,0 +1,137 @@
+//! `--section`: one section of the top-level diff, without header,
+//! summary or title. The fixtures are put into a fake store, so this runs
+//! without Nix.
+
+use nix_diff::testing::NixEnv;
+use std::process::Output;
+
+const OLD: &str = "11111111111111111111111111111111-hello.drv";
+const NEW: &str = "22222222222222222222222222222222-hello.drv";
+
+fn drv(out: &str, src: &str, cflags: &str) -> String {
+    format!(
+        r#"Derive([("out","@storeDir@/{out}-hello","","")],[],["@storeDir@/{src}-build.sh"],"x86_64-linux","/bin/sh",["-e","@storeDir@/{src}-build.sh"],[("CFLAGS","{cflags}"),("name","hello"),("out","@storeDir@/{out}-hello")])"#
+    )
+}
+
+fn nix_diff(new_cflags: &str, args: &[&str]) -> Output {
+    let env = NixEnv::builder()
+        .store_file(
+            OLD,
+            drv(
+                "33333333333333333333333333333333",
+                "55555555555555555555555555555555",
+                "-O2",
+            ),
+        )
+        .store_file(
+            NEW,
+            drv(
+                "44444444444444444444444444444444",
+                "66666666666666666666666666666666",
+                new_cflags,
+            ),
+        )
+        .store_file("55555555555555555555555555555555-build.sh", "make\n")
+        .store_file("66666666666666666666666666666666-build.sh", "make -j4\n")
+        .build()
+        .unwrap();
+    env.nix_diff(args)
+        .arg(env.store_path(OLD))
+        .arg(env.store_path(NEW))
+        .output()
+        .unwrap()
+}
+
+fn section(new_cflags: &str, name: &str) -> (Option<i32>, String) {
+    let output = nix_diff(new_cflags, &["--section", name]);
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    (output.status.code(), stdout)
+}
+
+#[test]
+fn only_the_requested_section_is_printed() {
+    assert_eq!(
+        section("-O2 -fPIC", "env"),
+        (Some(1), "CFLAGS:\n  - -O2\n  + -O2{+ -fPIC+}\n".to_string())
+    );
+    assert_eq!(
+        section("-O2 -fPIC", "sources"),
+        (
+            Some(1),
+            "~ build.sh (66% similar)\n  - make\n  + make -j4\n".to_string()
+        )
+    );
+}
+
+#[test]
+fn unchanged_sections_print_nothing_but_still_differ() {
+    // Only the script changed: the env section is empty, yet the
+    // derivations differ.
+    assert_eq!(section("-O2", "env"), (Some(1), String::new()));
+    assert_eq!(section("-O2", "platform"), (Some(1), String::new()));
+}
+
+#[test]
+fn unknown_sections_list_the_valid_ones() {
+    let output = nix_diff("-O2", &["--section", "environment"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{stderr}");
+    assert!(
+        stderr.contains(
+            "invalid section: environment (expected name, outputs, platform, builder, args, \
+             sources, inputs, attrs, env, sandbox or fetch)"
+        ),
+        "{stderr}"
+    );
+    let output = nix_diff("-O2", &["--section", "env", "--format", "json"]);
+    assert_eq!(output.status.code(), Some(2), "{output:?}");
+}
+
+#[test]
+fn only_and_skip_filter_the_report() {
+    let output = nix_diff("-O2 -fPIC", &["--only", "env"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{stdout}");
+    assert!(stdout.contains("Environment:\n  CFLAGS:\n"), "{stdout}");
+    assert!(!stdout.contains("Sources:"), "{stdout}");
+
+    // Only the script changed: nothing is printed between the summary
+    // and the totals, yet the derivations differ.
+    let output = nix_diff("-O2", &["--skip", "sources", "--skip", "args"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{stdout}");
+    assert!(
+        stdout.lines().nth(2).unwrap().starts_with("Summary: "),
+        "{stdout}"
+    );
+    assert!(
+        stdout.lines().nth(3).unwrap().starts_with("Totals: "),
+        "{stdout}"
+    );
+    assert_eq!(stdout.lines().count(), 4, "{stdout}");
+}
+
+#[test]
+fn only_and_skip_do_not_mix() {
+    for args in [
+        &["--only", "env", "--skip", "sources"][..],
+        &["--skip", "sources", "--only", "env"],
+    ] {
+        let output = nix_diff("-O2", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(2), "{stderr}");
+        assert!(
+            stderr.contains("--only and --skip can't be combined"),
+            "{stderr}"
+        );
+    }
+    let output = nix_diff("-O2", &["--only", "env", "--section", "env"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{stderr}");
+    assert!(stderr.contains("combined with --section"), "{stderr}");
+    let output = nix_diff("-O2", &["--only", "environment"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{stderr}");
+    assert!(stderr.contains("invalid section: environment"), "{stderr}");
+}
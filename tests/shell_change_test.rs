@@ -0,0 +1,59 @@
+//! A builder switching shells or shell options is pointed out above the
+//! raw builder and argument diffs, and `--fail-on shell-change` gates the
+//! exit status on it. Runs without Nix, on derivations in a fake store.
+
+use nix_diff::testing::NixEnv;
+use std::process::Output;
+
+const OLD: &str = "11111111111111111111111111111111-app.drv";
+const NEW: &str = "22222222222222222222222222222222-app.drv";
+
+fn drv(builder: &str, args: &[&str]) -> String {
+    let args: Vec<_> = args.iter().map(|arg| format!(r#""{arg}""#)).collect();
+    format!(
+        r#"Derive([("out","@storeDir@/33333333333333333333333333333333-app","","")],[],[],"x86_64-linux","{builder}",[{}],[("builder","{builder}"),("name","app"),("out","@storeDir@/33333333333333333333333333333333-app")])"#,
+        args.join(",")
+    )
+}
+
+fn nix_diff(old: String, new: String, args: &[&str]) -> Output {
+    let env = NixEnv::builder()
+        .store_file(OLD, old)
+        .store_file(NEW, new)
+        .build()
+        .unwrap();
+    env.nix_diff(args)
+        .arg(env.store_path(OLD))
+        .arg(env.store_path(NEW))
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn shell_change_is_pointed_out() {
+    let old = drv("/bin/bash", &["-e", "/build.sh"]);
+    let new = drv("/bin/dash", &["/build.sh"]);
+    let output = nix_diff(old.clone(), new.clone(), &[]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{stdout}");
+    assert!(
+        stdout.contains("\nbuilder shell changed: bash -e → dash\n"),
+        "{stdout}"
+    );
+    // The raw diffs are still there.
+    assert!(stdout.contains("Builder:"), "{stdout}");
+    assert!(stdout.contains("Arguments:"), "{stdout}");
+
+    let output = nix_diff(old, new, &["--fail-on", "shell-change"]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn other_argument_changes_do_not_count() {
+    let old = drv("/bin/bash", &["-e", "/build.sh"]);
+    let new = drv("/bin/bash", &["-e", "/build2.sh"]);
+    let output = nix_diff(old, new, &["--fail-on", "shell-change"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(0), "{stdout}");
+    assert!(!stdout.contains("builder shell changed"), "{stdout}");
+}
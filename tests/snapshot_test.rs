@@ -2,8 +2,7 @@ use insta::assert_snapshot;
 use std::path::PathBuf;
 use std::process::Command;
 
-mod common;
-use common::setup_nix_env;
+use nix_diff::testing::NixEnv;
 
 // Normalize store paths and hashes for consistent snapshots
 fn normalize_nix_output(output: &str, store_dir: &str) -> String {
@@ -12,19 +11,35 @@ fn normalize_nix_output(output: &str, store_dir: &str) -> String {
 
     // Replace all hashes with "HASH"
     let re = regex::Regex::new(r"/nix/store/[a-z0-9]{32}-").unwrap();
-    re.replace_all(&normalized, "/nix/store/HASH-").to_string()
+    let normalized = re.replace_all(&normalized, "/nix/store/HASH-").to_string();
+
+    // builtins.currentSystem shows up in unchanged env listings
+    normalized.replace(&current_system(), "SYSTEM")
+}
+
+fn current_system() -> String {
+    let os = match std::env::consts::OS {
+        "macos" => "darwin",
+        os => os,
+    };
+    format!("{}-{os}", std::env::consts::ARCH)
 }
 
 fn run_nix_diff(file1: &str, file2: &str) -> String {
+    run_nix_diff_with_args(file1, file2, &[])
+}
+
+fn run_nix_diff_with_args(file1: &str, file2: &str, extra_args: &[&str]) -> String {
     let tests_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
-    let (nix_root, env_vars) = setup_nix_env();
-    let nix_store_dir = nix_root.path().join("store").to_string_lossy().to_string();
+    let nix = NixEnv::new().unwrap();
+    let env_vars = nix.vars();
+    let nix_store_dir = nix.store_dir().to_string_lossy().to_string();
 
     // Generate derivations
     let mut cmd1 = Command::new("nix-instantiate");
     cmd1.args(["--extra-experimental-features", "nix-command flakes"])
         .arg(tests_dir.join(file1));
-    for (key, value) in &env_vars {
+    for (key, value) in env_vars {
         cmd1.env(key, value);
     }
     let output1 = cmd1
@@ -34,7 +49,7 @@ fn run_nix_diff(file1: &str, file2: &str) -> String {
     let mut cmd2 = Command::new("nix-instantiate");
     cmd2.args(["--extra-experimental-features", "nix-command flakes"])
         .arg(tests_dir.join(file2));
-    for (key, value) in &env_vars {
+    for (key, value) in env_vars {
         cmd2.env(key, value);
     }
     let output2 = cmd2
@@ -46,8 +61,11 @@ fn run_nix_diff(file1: &str, file2: &str) -> String {
 
     // Run nix-diff with NO_COLOR to get consistent output
     let mut cmd = Command::new(env!("CARGO_BIN_EXE_nix-diff"));
-    cmd.args([&drv1, &drv2]).env("NO_COLOR", "1");
-    for (key, value) in &env_vars {
+    cmd.arg("--no-closure-count")
+        .args(extra_args)
+        .args([&drv1, &drv2])
+        .env("NO_COLOR", "1");
+    for (key, value) in env_vars {
         cmd.env(key, value);
     }
     let output = cmd.output().expect("Failed to run nix-diff");
@@ -70,6 +88,88 @@ fn test_hello_diff_snapshot() {
     assert_snapshot!(output);
 }
 
+#[test]
+fn test_hello_diff_accessible() {
+    let output = run_nix_diff_with_args(
+        "hello-flake-v1/default.nix",
+        "hello-flake-v2/default.nix",
+        &["--accessible", "--no-inline-highlight"],
+    );
+    assert_snapshot!(output);
+}
+
+#[test]
+fn test_hello_diff_show_unchanged_env() {
+    let output = run_nix_diff_with_args(
+        "hello-flake-v1/default.nix",
+        "hello-flake-v2/default.nix",
+        &["--show-unchanged", "env"],
+    );
+    assert_snapshot!(output);
+}
+
+#[test]
+fn test_hello_diff_only_env_and_inputs() {
+    // Env changes at every level; the inputs' argument changes are left
+    // out, so they are only listed.
+    let output = run_nix_diff_with_args(
+        "hello-flake-v1/default.nix",
+        "hello-flake-v2/default.nix",
+        &["--only", "env", "--only", "inputs"],
+    );
+    assert_snapshot!(output);
+}
+
+#[test]
+fn test_hello_diff_skip_args() {
+    let output = run_nix_diff_with_args(
+        "hello-flake-v1/default.nix",
+        "hello-flake-v2/default.nix",
+        &["--skip", "args"],
+    );
+    assert_snapshot!(output);
+}
+
+#[test]
+fn test_hello_diff_brief() {
+    let output = run_nix_diff_with_args(
+        "hello-flake-v1/default.nix",
+        "hello-flake-v2/default.nix",
+        &["--brief"],
+    );
+    assert_snapshot!(output);
+}
+
+#[test]
+fn test_hello_diff_stat() {
+    let output = run_nix_diff_with_args(
+        "hello-flake-v1/default.nix",
+        "hello-flake-v2/default.nix",
+        &["--stat"],
+    );
+    assert_snapshot!(output);
+}
+
+#[test]
+fn test_hello_commit_msg() {
+    let output = run_nix_diff_with_args(
+        "hello-flake-v1/default.nix",
+        "hello-flake-v2/default.nix",
+        &["--format", "commit-msg"],
+    );
+    assert_snapshot!(output);
+}
+
+#[test]
+fn test_hello_ndjson() {
+    let output = run_nix_diff_with_args(
+        "hello-flake-v1/default.nix",
+        "hello-flake-v2/default.nix",
+        &["--format", "ndjson", "--no-invocation-echo"],
+    );
+    assert_snapshot!(output);
+}
+
 #[test]
 fn test_identical_derivations() {
     let output = run_nix_diff("hello-flake-v1/default.nix", "hello-flake-v1/default.nix");
@@ -79,14 +179,15 @@ fn test_identical_derivations() {
 #[test]
 fn test_hello_diff_with_context() {
     let tests_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
-    let (nix_root, env_vars) = setup_nix_env();
-    let nix_store_dir = nix_root.path().join("store").to_string_lossy().to_string();
+    let nix = NixEnv::new().unwrap();
+    let env_vars = nix.vars();
+    let nix_store_dir = nix.store_dir().to_string_lossy().to_string();
 
     // Generate derivations
     let mut cmd1 = Command::new("nix-instantiate");
     cmd1.args(["--extra-experimental-features", "nix-command flakes"])
         .arg(tests_dir.join("hello-flake-v1/default.nix"));
-    for (key, value) in &env_vars {
+    for (key, value) in env_vars {
         cmd1.env(key, value);
     }
     let output1 = cmd1
@@ -96,7 +197,7 @@ fn test_hello_diff_with_context() {
     let mut cmd2 = Command::new("nix-instantiate");
     cmd2.args(["--extra-experimental-features", "nix-command flakes"])
         .arg(tests_dir.join("hello-flake-v2/default.nix"));
-    for (key, value) in &env_vars {
+    for (key, value) in env_vars {
         cmd2.env(key, value);
     }
     let output2 = cmd2
@@ -108,9 +209,9 @@ fn test_hello_diff_with_context() {
 
     // Run with different context settings
     let mut cmd = Command::new(env!("CARGO_BIN_EXE_nix-diff"));
-    cmd.args(["--context", "5", &drv1, &drv2])
+    cmd.args(["--no-closure-count", "--context", "5", &drv1, &drv2])
         .env("NO_COLOR", "1");
-    for (key, value) in &env_vars {
+    for (key, value) in env_vars {
         cmd.env(key, value);
     }
     let output = cmd.output().expect("Failed to run nix-diff");
@@ -124,19 +225,21 @@ fn test_hello_diff_with_context() {
 
 #[test]
 fn test_inline_highlight_snapshot() {
-    // Inline word-level highlighting is the default when color is on.
-    // Snapshot the ANSI output so we can see exactly which segments get
-    // reverse-video'd. Escapes are rendered as readable tokens so the
+    // Inline highlighting is the default when color is on: short values
+    // get a character-level diff in bold and underline, longer lines a
+    // word-level one in reverse video. Snapshot the ANSI output so we can
+    // see exactly which segments get emphasized. Escapes are rendered as readable tokens so the
     // snapshot is reviewable in plain text.
     let tests_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
-    let (nix_root, env_vars) = setup_nix_env();
-    let nix_store_dir = nix_root.path().join("store").to_string_lossy().to_string();
+    let nix = NixEnv::new().unwrap();
+    let env_vars = nix.vars();
+    let nix_store_dir = nix.store_dir().to_string_lossy().to_string();
 
     let instantiate = |f: &str| {
         let mut cmd = Command::new("nix-instantiate");
         cmd.args(["--extra-experimental-features", "nix-command flakes"])
             .arg(tests_dir.join(f));
-        for (k, v) in &env_vars {
+        for (k, v) in env_vars {
             cmd.env(k, v);
         }
         String::from_utf8_lossy(&cmd.output().unwrap().stdout)
@@ -147,9 +250,9 @@ fn test_inline_highlight_snapshot() {
     let drv2 = instantiate("hello-flake-v2/default.nix");
 
     let mut cmd = Command::new(env!("CARGO_BIN_EXE_nix-diff"));
-    cmd.args(["--color", "always", &drv1, &drv2]);
+    cmd.args(["--no-closure-count", "--color", "always", &drv1, &drv2]);
     cmd.env_remove("NO_COLOR");
-    for (k, v) in &env_vars {
+    for (k, v) in env_vars {
         cmd.env(k, v);
     }
     let output = cmd.output().expect("Failed to run nix-diff");
@@ -163,6 +266,8 @@ fn test_inline_highlight_snapshot() {
     let readable = normalized
         .replace("\x1b[7m", "<rev>")
         .replace("\x1b[27m", "</rev>")
+        .replace("\x1b[1;4m", "<bu>")
+        .replace("\x1b[22;24m", "</bu>")
         .replace("\x1b[31m", "<red>")
         .replace("\x1b[32m", "<grn>")
         .replace("\x1b[33m", "<yel>")
@@ -177,3 +282,38 @@ fn test_inline_highlight_snapshot() {
     );
     assert_snapshot!(readable);
 }
+
+#[test]
+fn test_hello_diff_hyperlinks() {
+    // Every store path is wrapped in an OSC 8 hyperlink. ESC is shown as
+    // `\e` so the snapshot pins the exact link bytes.
+    let output = run_nix_diff_with_args(
+        "hello-flake-v1/default.nix",
+        "hello-flake-v2/default.nix",
+        &["--hyperlinks", "always", "--color", "never"],
+    );
+    assert!(
+        output.contains("\x1b]8;;file:///nix/store/HASH-"),
+        "expected OSC 8 hyperlinks around store paths"
+    );
+    let readable = output.replace('\x1b', "\\e");
+    assert_snapshot!(readable);
+}
+
+#[test]
+fn test_hello_diff_is_the_same_for_any_jobs() {
+    // Input .drv files are loaded and the derivation pairs compared on
+    // `--jobs` threads; the output must not depend on the scheduling.
+    let run = |jobs: &str| {
+        run_nix_diff_with_args(
+            "hello-flake-v1/default.nix",
+            "hello-flake-v2/default.nix",
+            &["--jobs", jobs],
+        )
+    };
+    let sequential = run("1");
+    assert!(sequential.contains("• dep1.drv"), "{sequential}");
+    for jobs in ["2", "8"] {
+        assert_eq!(run(jobs), sequential, "--jobs {jobs}");
+    }
+}
@@ -0,0 +1,107 @@
+//! A builder script that changes between two instantiations of the same
+//! package: the sources are paired by name and their contents diffed in
+//! hunks with `--context` lines around each change. The fixtures in
+//! `tests/fixtures/source-diff` are put into a fake store, so this runs
+//! without Nix.
+
+use nix_diff::testing::NixEnv;
+
+const OLD: &str = "55555555555555555555555555555555-hello-2.12.drv";
+const NEW: &str = "66666666666666666666666666666666-hello-2.12.drv";
+
+fn fixture(name: &str) -> Vec<u8> {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/source-diff");
+    std::fs::read(format!("{dir}/{name}")).unwrap()
+}
+
+fn nix_diff(args: &[&str]) -> String {
+    nix_diff_scripts(["builder-old.sh", "builder-new.sh"], args)
+}
+
+/// Diff the derivations with `scripts` as the old and new builder.
+fn nix_diff_scripts(scripts: [&str; 2], args: &[&str]) -> String {
+    let env = NixEnv::builder()
+        .store_file(OLD, fixture("old.drv"))
+        .store_file(NEW, fixture("new.drv"))
+        .store_file(
+            "11111111111111111111111111111111-builder.sh",
+            fixture(scripts[0]),
+        )
+        .store_file(
+            "22222222222222222222222222222222-builder.sh",
+            fixture(scripts[1]),
+        )
+        .build()
+        .unwrap();
+    let output = env
+        .command(env!("CARGO_BIN_EXE_nix-diff"))
+        .args(["--no-history", "--no-closure-count"])
+        .args(args)
+        .arg(env.store_path(OLD))
+        .arg(env.store_path(NEW))
+        .output()
+        .unwrap();
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).replace(
+        &env.store_dir().to_string_lossy().into_owned(),
+        "/nix/store",
+    )
+}
+
+#[test]
+fn changed_builder_script() {
+    let stdout = nix_diff(&["--color", "never"]);
+    insta::assert_snapshot!(stdout);
+}
+
+#[test]
+fn changed_builder_script_highlights_words() {
+    let stdout = nix_diff(&["--color", "always"]);
+    assert!(stdout.contains("~ builder.sh"), "{stdout}");
+    assert!(
+        stdout.contains("+ ./configure --prefix=$out\x1b[7m --enable-shared\x1b[27m"),
+        "{stdout}"
+    );
+    assert!(stdout.contains("- set \x1b[7m-e\x1b[27m"), "{stdout}");
+}
+
+#[test]
+fn stat_counts_the_changed_lines() {
+    let totals = "Totals: 1 source changed, 3 lines added, 3 lines removed\n";
+    assert_eq!(nix_diff(&["--color", "never", "--stat"]), totals);
+    let stdout = nix_diff(&["--color", "never"]);
+    assert!(stdout.ends_with(totals), "{stdout}");
+    let stdout = nix_diff(&["--color", "never", "--no-summary"]);
+    assert!(!stdout.contains("Summary:"), "{stdout}");
+    assert!(!stdout.contains("Totals:"), "{stdout}");
+    assert!(stdout.contains("~ builder.sh"), "{stdout}");
+}
+
+/// Changes near the top, in the middle and at the bottom of a longer
+/// script: hunks get `--context` lines on both sides and `...` between
+/// them, and merge when their context touches.
+#[test]
+fn hunks_follow_the_context_setting() {
+    let scripts = ["builder-long-old.sh", "builder-long-new.sh"];
+    for context in ["0", "1", "100"] {
+        let stdout = nix_diff_scripts(scripts, &["--color", "never", "--context", context]);
+        insta::assert_snapshot!(format!("long_script_context_{context}"), stdout);
+    }
+    // The line diff, for texts too different for inline highlighting.
+    let stdout = nix_diff_scripts(
+        scripts,
+        &[
+            "--color",
+            "never",
+            "--no-inline-highlight",
+            "--context",
+            "2",
+        ],
+    );
+    insta::assert_snapshot!("long_script_line_diff_context_2", stdout);
+}
@@ -0,0 +1,83 @@
+//! Derivations made for another store directory than `NIX_STORE_DIR`:
+//! nix-diff warns and reads from the directory the derivations name,
+//! unless `--strict-store` is given.
+
+use nix_diff::testing::NixEnv;
+use std::process::Output;
+
+const OLD: &str = "11111111111111111111111111111111-hello.drv";
+const NEW: &str = "22222222222222222222222222222222-hello.drv";
+
+fn drv(out: &str, source: &str) -> String {
+    format!(
+        r#"Derive([("out","@storeDir@/{out}-hello","","")],[],["@storeDir@/{source}-build.sh"],"x86_64-linux","/bin/sh",["@storeDir@/{source}-build.sh"],[("name","hello")])"#
+    )
+}
+
+fn nix_diff(args: &[&str]) -> Output {
+    let env = NixEnv::builder()
+        .store_file(
+            OLD,
+            drv(
+                "33333333333333333333333333333333",
+                "55555555555555555555555555555555",
+            ),
+        )
+        .store_file(
+            NEW,
+            drv(
+                "44444444444444444444444444444444",
+                "66666666666666666666666666666666",
+            ),
+        )
+        .store_file("55555555555555555555555555555555-build.sh", "echo old\n")
+        .store_file("66666666666666666666666666666666-build.sh", "echo new\n")
+        .dir("elsewhere")
+        .build()
+        .unwrap();
+    let output = env
+        .command(env!("CARGO_BIN_EXE_nix-diff"))
+        .args(["--color", "never", "--no-history"])
+        .args(args)
+        .arg(env.store_path(OLD))
+        .arg(env.store_path(NEW))
+        .env("NIX_STORE_DIR", env.root().join("elsewhere"))
+        .output()
+        .unwrap();
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+#[test]
+fn falls_back_to_the_store_dir_of_the_derivations() {
+    let output = nix_diff(&[]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("warning: NIX_STORE_DIR is ") && stderr.contains("/elsewhere but "),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("/store; reading them from there"),
+        "{stderr}"
+    );
+    assert!(!stderr.contains("outside"), "{stderr}");
+    assert!(
+        stdout.contains("- echo old") && stdout.contains("+ echo new"),
+        "{stdout}"
+    );
+}
+
+#[test]
+fn strict_store_only_warns() {
+    let output = nix_diff(&["--strict-store"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("(--strict-store)"), "{stderr}");
+    assert!(!stdout.contains("echo new"), "{stdout}");
+}
@@ -0,0 +1,103 @@
+//! Derivations with `__structuredAttrs`, whose attributes are all in the
+//! `__json` env var, compared attribute by attribute. The derivations are
+//! put into a fake store, so this runs without Nix.
+
+use nix_diff::testing::NixEnv;
+use serde_json::Value;
+use std::process::Output;
+
+const OLD: &str = "11111111111111111111111111111111-hello.drv";
+const NEW: &str = "22222222222222222222222222222222-hello.drv";
+
+fn drv(out: &str, json: &str) -> String {
+    let json = json.replace('\\', "\\\\").replace('"', "\\\"");
+    format!(
+        r#"Derive([("out","@storeDir@/{out}-hello","","")],[],[],"x86_64-linux","/bin/sh",["-c","true"],[("__json","{json}"),("out","@storeDir@/{out}-hello")])"#
+    )
+}
+
+fn nix_diff(old_json: &str, new_json: &str, args: &[&str]) -> Output {
+    let env = NixEnv::builder()
+        .store_file(OLD, drv("33333333333333333333333333333333", old_json))
+        .store_file(NEW, drv("44444444444444444444444444444444", new_json))
+        .build()
+        .unwrap();
+    env.nix_diff(args)
+        .arg(env.store_path(OLD))
+        .arg(env.store_path(NEW))
+        .output()
+        .unwrap()
+}
+
+const OLD_JSON: &str = r#"{
+  "name": "hello",
+  "outputs": ["out", "dev"],
+  "env": {
+    "NIX_CFLAGS_COMPILE": "-O2",
+    "cmake": {"flags": ["-DUSE_A=ON"], "generator": {"name": "Ninja", "jobs": 4}}
+  },
+  "exportReferencesGraph": {"closure": ["/nix/store/aaaa-dep"]}
+}"#;
+
+const NEW_JSON: &str = r#"{
+  "name": "hello",
+  "outputs": ["out", "dev", "man"],
+  "env": {
+    "NIX_CFLAGS_COMPILE": "-O3",
+    "cmake": {"flags": ["-DUSE_A=ON", "-DUSE_B=ON"], "generator": {"name": "Ninja", "jobs": 8}}
+  },
+  "doCheck": true
+}"#;
+
+#[test]
+fn json_env_is_diffed_attribute_by_attribute() {
+    let output = nix_diff(OLD_JSON, NEW_JSON, &[]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{stdout}");
+    let attrs = "Attributes (structured):\n  \
+        doCheck:\n    \
+        + true\n  \
+        env.NIX_CFLAGS_COMPILE:\n    \
+        - -O[-2-]\n    \
+        + -O{+3+}\n  \
+        env.cmake.flags[1]:\n    \
+        + -DUSE_B=ON\n  \
+        env.cmake.generator.jobs:\n    \
+        - [-4-]\n    \
+        + {+8+}\n  \
+        exportReferencesGraph:\n    \
+        - {\"closure\":[\"/nix/store/aaaa-dep\"]}\n  \
+        outputs[2]:\n    \
+        + man\n";
+    assert!(stdout.contains(attrs), "{stdout}");
+    assert!(!stdout.contains("__json"), "{stdout}");
+
+    let output = nix_diff(OLD_JSON, NEW_JSON, &["--format", "json"]);
+    let json: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let paths: Vec<_> = json["structured_attrs"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|attr| attr["path"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        paths,
+        [
+            "doCheck",
+            "env.NIX_CFLAGS_COMPILE",
+            "env.cmake.flags[1]",
+            "env.cmake.generator.jobs",
+            "exportReferencesGraph",
+            "outputs[2]",
+        ]
+    );
+}
+
+#[test]
+fn json_that_does_not_parse_is_diffed_as_a_string() {
+    let output = nix_diff(r#"{"name": "hello""#, r#"{"name": "hello2"}"#, &[]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{stdout}");
+    assert!(!stdout.contains("Attributes (structured)"), "{stdout}");
+    assert!(stdout.contains("Environment:\n  __json:\n"), "{stdout}");
+}
@@ -0,0 +1,111 @@
+#![cfg(unix)]
+//! `--timeout`: past the deadline, changed inputs are listed but not
+//! compared and the partial diff is printed, or a hanging Nix is killed.
+//! Runs without Nix: the derivations are put into a fake store, and Nix
+//! is a script that never finishes.
+
+use nix_diff::testing::NixEnv;
+use serde_json::Value;
+use std::os::unix::fs::PermissionsExt;
+use std::time::{Duration, Instant};
+
+const OLD: &str = "11111111111111111111111111111111-app.drv";
+const NEW: &str = "22222222222222222222222222222222-app.drv";
+
+fn store() -> NixEnv {
+    let mut builder = NixEnv::builder();
+    for (hash, root, root_hash, version) in [('3', OLD, '5', "1"), ('4', NEW, '6', "2")] {
+        let mut deps = Vec::new();
+        for name in ["libfoo", "libbar"] {
+            let file = format!("{}-{name}.drv", hash.to_string().repeat(32));
+            builder = builder.store_file(&file, NixEnv::drv(name, hash, version, &[]));
+            deps.push(file);
+        }
+        builder = builder.store_file(root, NixEnv::drv("app", root_hash, version, &deps));
+    }
+    builder.build().unwrap()
+}
+
+#[test]
+fn partial_diff_is_printed_when_time_runs_out() {
+    let env = store();
+    let nix_diff = |args: &[&str]| {
+        env.nix_diff(args)
+            .arg(env.store_path(OLD))
+            .arg(env.store_path(NEW))
+            .output()
+            .unwrap()
+    };
+    // Out of time from the start: only the top level is compared.
+    let output = nix_diff(&["--timeout", "0s"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        output.status.code(),
+        Some(124),
+        "{stdout}{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(
+        stdout.contains("2 inputs not compared, incomplete (timed out)"),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains("2 changed inputs not compared (--timeout reached)\n"),
+        "{stdout}"
+    );
+    assert!(stdout.contains("version:"), "{stdout}");
+    assert!(
+        stdout.ends_with("\nanalysis incomplete (timed out after 0s)\n"),
+        "{stdout}"
+    );
+
+    let output = nix_diff(&["--timeout", "0s", "--format", "json"]);
+    assert_eq!(output.status.code(), Some(124));
+    let json: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["timed_out"], true);
+    assert_eq!(json["inputs"]["timed_out"], true);
+    assert_eq!(json["inputs"]["not_compared"].as_array().unwrap().len(), 2);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("warning: analysis incomplete (timed out after 0s)"),
+        "{stderr}"
+    );
+
+    // With time to spare, it is a plain diff.
+    let output = nix_diff(&["--timeout", "1h", "--format", "json"]);
+    assert_eq!(output.status.code(), Some(1));
+    let json: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(json.get("timed_out").is_none());
+    assert_eq!(json["inputs"]["changed"].as_array().unwrap().len(), 2);
+}
+
+#[test]
+fn hanging_instantiation_is_killed() {
+    let tmp = tempfile::tempdir().unwrap();
+    let fake = tmp.path().join("nix-instantiate");
+    std::fs::write(&fake, "#!/bin/sh\nexec sleep 60\n").unwrap();
+    std::fs::set_permissions(&fake, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!(
+        "{}:{}",
+        tmp.path().display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+    let nix_file = tmp.path().join("a.nix");
+    std::fs::write(&nix_file, "{}").unwrap();
+
+    let start = Instant::now();
+    let output = NixEnv::new()
+        .unwrap()
+        .nix_diff(&["--timeout", "1s"])
+        .args([&nix_file, &nix_file])
+        .env("PATH", path)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(124), "{stderr}");
+    assert!(start.elapsed() < Duration::from_secs(30), "{stderr}");
+    assert!(
+        stderr.contains("timed out after 1s (--timeout)"),
+        "{stderr}"
+    );
+}
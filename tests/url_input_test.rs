@@ -0,0 +1,93 @@
+//! .drv inputs given as URLs, served by a tiny local HTTP server.
+#![cfg(feature = "http")]
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::process::{Command, Output};
+
+fn drv(version: &str) -> String {
+    format!(
+        r#"Derive([("out","/nix/store/cccccccccccccccccccccccccccccccc-hello","","")],[("/nix/store/dddddddddddddddddddddddddddddddd-dep-{version}.drv",["out"])],[],"x86_64-linux","/bin/sh",[],[("name","hello"),("version","{version}")])"#
+    )
+}
+
+/// Serve `files` by request path until the test process exits; anything
+/// else is a 404. Returns the base URL.
+fn serve(files: HashMap<&'static str, String>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = String::new();
+            BufReader::new(&stream).read_line(&mut request).unwrap();
+            let path = request.split_whitespace().nth(1).unwrap_or_default();
+            let (status, body) = match files.get(path) {
+                Some(body) => ("200 OK", body.as_str()),
+                None => ("404 Not Found", ""),
+            };
+            let _ = write!(
+                stream,
+                "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+        }
+    });
+    base
+}
+
+fn nix_diff(args: &[&str]) -> Output {
+    let state = tempfile::tempdir().unwrap();
+    Command::new(env!("CARGO_BIN_EXE_nix-diff"))
+        .args(["--color", "never", "--no-closure-count", "--no-history"])
+        .args(args)
+        .env("NIX_STORE_DIR", "/nix/store")
+        .env("XDG_STATE_HOME", state.path())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn diffs_derivations_fetched_over_http() {
+    let base = serve(HashMap::from([
+        (
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello.drv",
+            drv("1.0"),
+        ),
+        (
+            "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-hello.drv",
+            drv("2.0"),
+        ),
+        ("/garbage-hello.drv", "<html>not a drv</html>".to_string()),
+    ]));
+    let old = format!("{base}/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello.drv");
+    let new = format!("{base}/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-hello.drv");
+
+    let output = nix_diff(&[&old, &new]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{stdout}\n{stderr}");
+    assert!(
+        stdout.starts_with(&format!("--- {old}\n+++ {new}\n")),
+        "{stdout}"
+    );
+    assert!(stdout.contains("version: 1.0 → 2.0"), "{stdout}");
+    // The input derivations aren't available, so only their versions show.
+    assert!(stdout.contains("• dep: 1.0 → 2.0\n"), "{stdout}");
+    assert!(stderr.contains("fetched over HTTP"), "{stderr}");
+
+    let missing = nix_diff(&[&format!("{base}/nix/store/gone-hello.drv"), &new]);
+    let stderr = String::from_utf8_lossy(&missing.stderr);
+    assert_eq!(missing.status.code(), Some(2), "{stderr}");
+    assert!(
+        stderr.contains("HTTP request") && stderr.contains("404"),
+        "{stderr}"
+    );
+
+    let garbage = nix_diff(&[&format!("{base}/garbage-hello.drv"), &new]);
+    let stderr = String::from_utf8_lossy(&garbage.stderr);
+    assert_eq!(garbage.status.code(), Some(2), "{stderr}");
+    assert!(stderr.contains("is not a derivation"), "{stderr}");
+    assert!(!stderr.contains("HTTP request"), "{stderr}");
+}
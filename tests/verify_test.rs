@@ -0,0 +1,81 @@
+//! `--verify` against derivations written by Nix: the output paths
+//! `nix-instantiate` records are what `hashDerivationModulo` must give.
+
+use std::path::PathBuf;
+
+use nix_diff::drvhash::Verifier;
+use nix_diff::parser::parse_derivation;
+use nix_diff::testing::NixEnv;
+
+fn instantiate(nix: &NixEnv, file: &str, attr: Option<&str>) -> String {
+    let tests_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let mut command = nix.command("nix-instantiate");
+    command.arg(tests_dir.join(file));
+    if let Some(attr) = attr {
+        command.args(["-A", attr]);
+    }
+    let output = command
+        .output()
+        .unwrap_or_else(|_| panic!("Failed to instantiate {file}"));
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+fn assert_verifies(drv_path: &str) {
+    let drv = parse_derivation(drv_path).unwrap();
+    assert_eq!(
+        Verifier::default()
+            .verify(drv_path.as_bytes(), &drv)
+            .unwrap(),
+        [],
+        "{drv_path}"
+    );
+}
+
+#[test]
+fn recomputes_the_paths_nix_recorded() {
+    let nix = NixEnv::new().unwrap();
+    // Input-addressed, with input derivations.
+    assert_verifies(&instantiate(&nix, "hello-flake-v1/default.nix", None));
+    for attr in ["flat", "recursive", "consumer"] {
+        assert_verifies(&instantiate(
+            &nix,
+            "fixtures/verify/default.nix",
+            Some(attr),
+        ));
+    }
+}
+
+#[test]
+fn tampered_derivation_is_reported() {
+    let nix = NixEnv::new().unwrap();
+    let drv_path = instantiate(&nix, "fixtures/verify/default.nix", Some("consumer"));
+    let aterm = std::fs::read_to_string(&drv_path).unwrap();
+    let tampered = nix.store_path("00000000000000000000000000000000-consumer-1.0.drv");
+    std::fs::write(&tampered, aterm.replace("say", "run")).unwrap();
+
+    let output = nix
+        .nix_diff(&["--verify"])
+        .arg(&drv_path)
+        .arg(&tampered)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(
+        stderr.matches("may be corrupted or tampered with").count(),
+        2,
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains(&format!("warning: {}: output out", tampered.display())),
+        "{stderr}"
+    );
+    assert!(
+        !stderr.contains(&format!("warning: {drv_path}")),
+        "{stderr}"
+    );
+}